@@ -2,6 +2,8 @@
 #include <errno.h>
 #include <fcntl.h>
 #include <limits.h>
+#include <poll.h>
+#include <signal.h>
 #include <stdbool.h>
 #include <stdint.h>
 #include <stdio.h>
@@ -11,7 +13,10 @@
 #include <time.h>
 #include <unistd.h>
 
+#include <arpa/inet.h>
 #include <net/if.h>
+#include <net/route.h>
+#include <netinet/in.h>
 #include <sys/ioctl.h>
 #include <sys/mount.h>
 #include <sys/resource.h>
@@ -32,6 +37,11 @@
 
 #define KRUN_EXIT_CODE_IOCTL 0x7602
 #define KRUN_REMOVE_ROOT_DIR_IOCTL 0x7603
+#define KRUN_EXIT_STATUS_IOCTL 0x7604
+#define KRUN_BOOT_STAGE_IOCTL 0x7605
+
+#define KRUN_BOOT_STAGE_INIT 1
+#define KRUN_BOOT_STAGE_WORKLOAD 2
 
 #define KRUN_MAGIC "KRUN"
 #define KRUN_FOOTER_LEN 12
@@ -50,6 +60,281 @@ static char *snp_get_luks_passphrase(char *, char *, char *, int *);
 
 char DEFAULT_KRUN_INIT[] = "/bin/sh";
 
+/*
+ * Opens a copy of the /etc file at `path` to write, keeping what's in it if
+ * `keep` is set. The root filesystem may be shared with other VMs, so a file
+ * that's already there is copied to /dev/shm and later mounted over instead of
+ * being changed, and `tmp` gets the copy's path. If there's no file yet, it's
+ * created in place and `tmp` is left empty.
+ */
+static int etc_file_open(const char *path, char *tmp, bool keep)
+{
+    char buf[4096];
+    ssize_t len;
+    int fd, src;
+
+    if (access(path, F_OK) < 0) {
+        tmp[0] = '\0';
+        if (mkdir("/etc", 0755) < 0 && errno != EEXIST) {
+            return -1;
+        }
+        return open(path, O_WRONLY | O_CREAT | O_TRUNC | O_CLOEXEC, 0644);
+    }
+
+    fd = mkstemp(tmp);
+    if (fd < 0) {
+        return -1;
+    }
+    fchmod(fd, 0644);
+
+    if (keep) {
+        src = open(path, O_RDONLY | O_CLOEXEC);
+        if (src < 0) {
+            goto err;
+        }
+        while ((len = read(src, buf, sizeof(buf))) > 0) {
+            if (write(fd, buf, len) != len) {
+                len = -1;
+                break;
+            }
+        }
+        close(src);
+        if (len < 0) {
+            goto err;
+        }
+    }
+
+    return fd;
+
+err:
+    close(fd);
+    unlink(tmp);
+    return -1;
+}
+
+/* Finishes a file opened with etc_file_open(), mounting the copy if any. */
+static int etc_file_close(const char *path, const char *tmp, int fd)
+{
+    int ret = 0;
+
+    close(fd);
+    if (tmp[0] != '\0') {
+        ret = mount(tmp, path, NULL, MS_BIND, NULL);
+        /* The mount keeps the copy around. */
+        unlink(tmp);
+    }
+
+    return ret;
+}
+
+/*
+ * Writes the hostname and host entries the VM was given to /etc/hostname and
+ * /etc/hosts. `hosts` has `ip=name` pairs separated by commas.
+ */
+static void setup_etc_hosts(const char *hostname, const char *hosts)
+{
+    char tmp[] = "/dev/shm/.krun-etc-XXXXXX";
+    char *entries, *entry, *name, *saveptr;
+    int fd;
+
+    if (hostname) {
+        fd = etc_file_open("/etc/hostname", tmp, false);
+        if (fd < 0 || dprintf(fd, "%s\n", hostname) < 0 ||
+            etc_file_close("/etc/hostname", tmp, fd) < 0) {
+            perror("Couldn't write /etc/hostname");
+        }
+        strcpy(tmp, "/dev/shm/.krun-etc-XXXXXX");
+    }
+
+    fd = etc_file_open("/etc/hosts", tmp, true);
+    if (fd < 0) {
+        perror("Couldn't write /etc/hosts");
+        return;
+    }
+    if (tmp[0] == '\0') {
+        dprintf(fd, "127.0.0.1\tlocalhost\n::1\tlocalhost\n");
+    }
+    if (hostname) {
+        dprintf(fd, "127.0.1.1\t%s\n", hostname);
+    }
+    if (hosts) {
+        entries = strdup(hosts);
+        for (entry = strtok_r(entries, ",", &saveptr); entry;
+             entry = strtok_r(NULL, ",", &saveptr)) {
+            name = strchr(entry, '=');
+            if (name) {
+                *name++ = '\0';
+                dprintf(fd, "%s\t%s\n", entry, name);
+            }
+        }
+        free(entries);
+    }
+    if (etc_file_close("/etc/hosts", tmp, fd) < 0) {
+        perror("Couldn't write /etc/hosts");
+    }
+}
+
+/*
+ * Writes /etc/resolv.conf with the DNS servers and search domains the VM was
+ * given, both separated by commas.
+ */
+static void setup_resolv_conf(const char *nameservers, const char *search)
+{
+    char tmp[] = "/dev/shm/.krun-etc-XXXXXX";
+    char *servers, *server, *domains, *c, *saveptr;
+    int fd;
+
+    fd = etc_file_open("/etc/resolv.conf", tmp, false);
+    if (fd < 0) {
+        perror("Couldn't write /etc/resolv.conf");
+        return;
+    }
+    servers = strdup(nameservers);
+    for (server = strtok_r(servers, ",", &saveptr); server;
+         server = strtok_r(NULL, ",", &saveptr)) {
+        dprintf(fd, "nameserver %s\n", server);
+    }
+    free(servers);
+    if (search) {
+        domains = strdup(search);
+        for (c = domains; *c; c++) {
+            if (*c == ',') {
+                *c = ' ';
+            }
+        }
+        dprintf(fd, "search %s\n", domains);
+        free(domains);
+    }
+    if (etc_file_close("/etc/resolv.conf", tmp, fd) < 0) {
+        perror("Couldn't write /etc/resolv.conf");
+    }
+}
+
+/* The kernel's in6_ifreq, which glibc doesn't define. */
+struct krun_in6_ifreq {
+    struct in6_addr addr;
+    uint32_t prefixlen;
+    int ifindex;
+};
+
+/*
+ * Gives `ifname` the address `addr` with a `prefix` long mask, brings it up
+ * and routes everything else through `gateway`.
+ */
+static int set_static_ip(const char *ifname, const char *addr, int prefix,
+                         const char *gateway)
+{
+    struct sockaddr_in *sin;
+    struct krun_in6_ifreq ifr6;
+    struct in6_rtmsg rt6;
+    struct rtentry rt;
+    struct ifreq ifr;
+    int fd, ret, saved_errno;
+
+    memset(&ifr, 0, sizeof ifr);
+    strncpy(ifr.ifr_name, ifname, IFNAMSIZ - 1);
+
+    if (!strchr(addr, ':')) {
+        fd = socket(AF_INET, SOCK_DGRAM, 0);
+        if (fd < 0) {
+            return -1;
+        }
+        sin = (struct sockaddr_in *)&ifr.ifr_addr;
+        sin->sin_family = AF_INET;
+        ret = inet_pton(AF_INET, addr, &sin->sin_addr) == 1 ? 0 : -1;
+        if (ret == 0) {
+            ret = ioctl(fd, SIOCSIFADDR, &ifr);
+        }
+        if (ret == 0) {
+            sin = (struct sockaddr_in *)&ifr.ifr_netmask;
+            sin->sin_family = AF_INET;
+            sin->sin_addr.s_addr =
+                htonl(prefix ? 0xffffffffu << (32 - prefix) : 0);
+            ret = ioctl(fd, SIOCSIFNETMASK, &ifr);
+        }
+    } else {
+        fd = socket(AF_INET6, SOCK_DGRAM, 0);
+        if (fd < 0) {
+            return -1;
+        }
+        memset(&ifr6, 0, sizeof ifr6);
+        ret = ioctl(fd, SIOCGIFINDEX, &ifr);
+        if (ret == 0) {
+            ifr6.ifindex = ifr.ifr_ifindex;
+            ifr6.prefixlen = prefix;
+            ret = inet_pton(AF_INET6, addr, &ifr6.addr) == 1 ? 0 : -1;
+        }
+        if (ret == 0) {
+            ret = ioctl(fd, SIOCSIFADDR, &ifr6);
+        }
+    }
+
+    if (ret == 0) {
+        ret = ioctl(fd, SIOCGIFFLAGS, &ifr);
+    }
+    if (ret == 0) {
+        ifr.ifr_flags |= IFF_UP;
+        ret = ioctl(fd, SIOCSIFFLAGS, &ifr);
+    }
+
+    if (ret == 0 && !strchr(addr, ':')) {
+        memset(&rt, 0, sizeof rt);
+        rt.rt_dst.sa_family = AF_INET;
+        rt.rt_genmask.sa_family = AF_INET;
+        sin = (struct sockaddr_in *)&rt.rt_gateway;
+        sin->sin_family = AF_INET;
+        ret = inet_pton(AF_INET, gateway, &sin->sin_addr) == 1 ? 0 : -1;
+        rt.rt_flags = RTF_UP | RTF_GATEWAY;
+        rt.rt_dev = (char *)ifname;
+        if (ret == 0) {
+            ret = ioctl(fd, SIOCADDRT, &rt);
+        }
+    } else if (ret == 0) {
+        memset(&rt6, 0, sizeof rt6);
+        ret = inet_pton(AF_INET6, gateway, &rt6.rtmsg_gateway) == 1 ? 0 : -1;
+        rt6.rtmsg_flags = RTF_UP | RTF_GATEWAY;
+        rt6.rtmsg_metric = 1;
+        rt6.rtmsg_ifindex = ifr6.ifindex;
+        if (ret == 0) {
+            ret = ioctl(fd, SIOCADDRT, &rt6);
+        }
+    }
+
+    saved_errno = errno;
+    close(fd);
+    errno = saved_errno;
+    return ret;
+}
+
+/*
+ * Configures the static addresses the VM was given, as
+ * `iface,ip/prefix,gateway` separated by semicolons.
+ */
+static void setup_static_ips(const char *ips)
+{
+    char *entries, *entry, *addr, *prefix, *gateway, *saveptr;
+
+    entries = strdup(ips);
+    for (entry = strtok_r(entries, ";", &saveptr); entry;
+         entry = strtok_r(NULL, ";", &saveptr)) {
+        addr = strchr(entry, ',');
+        gateway = addr ? strchr(addr + 1, ',') : NULL;
+        prefix = addr ? strchr(addr + 1, '/') : NULL;
+        if (!gateway || !prefix || prefix > gateway) {
+            printf("Invalid static IP configuration: %s\n", entry);
+            continue;
+        }
+        *addr++ = '\0';
+        *prefix++ = '\0';
+        *gateway++ = '\0';
+        if (set_static_ip(entry, addr, atoi(prefix), gateway) < 0) {
+            printf("Couldn't set %s/%s via %s on %s: %s\n", addr, prefix,
+                   gateway, entry, strerror(errno));
+        }
+    }
+    free(entries);
+}
+
 static void set_rlimits(const char *rlimits)
 {
     unsigned long long int lim_id, lim_cur, lim_max;
@@ -959,6 +1244,253 @@ int setup_redirects()
     return 0;
 }
 
+/* Where init copies what's read from `src`, for KRUN_TTY. */
+struct krun_relay {
+    int src;
+    int dst;
+    char last;
+};
+
+static int relay_signal_pipe[2] = {-1, -1};
+
+static void relay_signal(int sig)
+{
+    int saved_errno = errno;
+    char c = sig;
+
+    write(relay_signal_pipe[1], &c, 1);
+    errno = saved_errno;
+}
+
+/* Gives the pseudo-terminal `master` the size of init's own terminal. */
+static void copy_winsize(int master)
+{
+    struct winsize ws;
+    int fd;
+
+    for (fd = STDIN_FILENO; fd <= STDERR_FILENO; fd++) {
+        if (ioctl(fd, TIOCGWINSZ, &ws) == 0) {
+            ioctl(master, TIOCSWINSZ, &ws);
+            return;
+        }
+    }
+}
+
+/*
+ * Sets up the workload's stdio for KRUN_TTY: a pseudo-terminal if `force` is
+ * set, or else pipes in place of whichever of init's own stdio are terminals.
+ * `fds` gets what the workload's stdio is to be, `relays` what init then
+ * copies to and from it, and `master` the pseudo-terminal's master end or -1.
+ * Returns the number of relays, or -1 on failure.
+ */
+static int setup_workload_stdio(bool force, int fds[3],
+                                struct krun_relay relays[3], int *master)
+{
+    struct sigaction sa;
+    struct termios tio;
+    char slave[32];
+    int fd, n, unlock = 0, pipefd[2];
+
+    *master = -1;
+    memset(relays, 0, 3 * sizeof *relays);
+
+    if (pipe(relay_signal_pipe) < 0) {
+        return -1;
+    }
+    for (n = 0; n < 2; n++) {
+        fcntl(relay_signal_pipe[n], F_SETFD, FD_CLOEXEC);
+        fcntl(relay_signal_pipe[n], F_SETFL, O_NONBLOCK);
+    }
+    memset(&sa, 0, sizeof sa);
+    sa.sa_handler = relay_signal;
+    sigaction(SIGCHLD, &sa, NULL);
+    sigaction(SIGINT, &sa, NULL);
+    sigaction(SIGPIPE, &sa, NULL);
+    sigaction(SIGWINCH, &sa, NULL);
+
+    if (force) {
+        *master = open("/dev/ptmx", O_RDWR | O_NOCTTY | O_CLOEXEC);
+        if (*master < 0 || ioctl(*master, TIOCSPTLCK, &unlock) < 0 ||
+            ioctl(*master, TIOCGPTN, &n) < 0) {
+            return -1;
+        }
+        snprintf(slave, sizeof slave, "/dev/pts/%d", n);
+        fds[0] = open(slave, O_RDWR | O_NOCTTY);
+        if (fds[0] < 0) {
+            return -1;
+        }
+        fds[1] = fds[2] = fds[0];
+        copy_winsize(*master);
+
+        // The pseudo-terminal does the line editing and output processing
+        // now, so init's own terminal mustn't do them again.
+        for (fd = STDIN_FILENO; fd <= STDERR_FILENO; fd++) {
+            if (tcgetattr(fd, &tio) < 0) {
+                continue;
+            }
+            if (fd == STDIN_FILENO) {
+                cfmakeraw(&tio);
+            } else {
+                tio.c_oflag &= ~OPOST;
+            }
+            tcsetattr(fd, TCSANOW, &tio);
+        }
+
+        relays[0].src = STDIN_FILENO;
+        relays[0].dst = *master;
+        relays[1].src = *master;
+        relays[1].dst = STDOUT_FILENO;
+        return 2;
+    }
+
+    n = 0;
+    for (fd = STDIN_FILENO; fd <= STDERR_FILENO; fd++) {
+        fds[fd] = fd;
+        if (!isatty(fd)) {
+            continue;
+        }
+        if (pipe(pipefd) < 0) {
+            return -1;
+        }
+        if (fd == STDIN_FILENO) {
+            fds[fd] = pipefd[0];
+            relays[n].src = fd;
+            relays[n].dst = pipefd[1];
+        } else {
+            fds[fd] = pipefd[1];
+            relays[n].src = pipefd[0];
+            relays[n].dst = fd;
+        }
+        fcntl(fds[fd] == pipefd[0] ? pipefd[1] : pipefd[0], F_SETFD,
+              FD_CLOEXEC);
+        n++;
+    }
+    return n;
+}
+
+/*
+ * Makes the workload the leader of a new session with `fds` as its stdio,
+ * and their terminal, if any, as its controlling terminal.
+ */
+static void enter_workload_stdio(const int fds[3])
+{
+    int fd;
+
+    setsid();
+    ioctl(fds[0], TIOCSCTTY, 0);
+    for (fd = STDIN_FILENO; fd <= STDERR_FILENO; fd++) {
+        if (fds[fd] != fd) {
+            dup2(fds[fd], fd);
+        }
+    }
+    for (fd = STDIN_FILENO; fd <= STDERR_FILENO; fd++) {
+        if (fds[fd] > STDERR_FILENO) {
+            close(fds[fd]);
+        }
+    }
+}
+
+/*
+ * Copies what `relay` reads to where it goes. Returns false once there's
+ * nothing more to copy.
+ */
+static bool relay_copy(struct krun_relay *relay)
+{
+    char buf[4096];
+    ssize_t len, done, ret;
+
+    len = read(relay->src, buf, sizeof buf);
+    if (len <= 0) {
+        return false;
+    }
+    relay->last = buf[len - 1];
+    for (done = 0; done < len; done += ret) {
+        ret = write(relay->dst, buf + done, len - done);
+        if (ret < 0 && errno == EINTR) {
+            ret = 0;
+        } else if (ret < 0) {
+            return false;
+        }
+    }
+    return true;
+}
+
+/*
+ * Passes on the end of init's stdin. A pseudo-terminal's only way to tell of
+ * it is its EOF character, which has to end a line of its own.
+ */
+static void relay_eof(struct krun_relay *relay, int master)
+{
+    struct termios tio;
+
+    if (relay->dst != master) {
+        close(relay->dst);
+        return;
+    }
+    if (tcgetattr(master, &tio) < 0 || !(tio.c_lflag & ICANON)) {
+        return;
+    }
+    if (relay->last != '\0' && relay->last != '\n') {
+        write(master, &tio.c_cc[VEOF], 1);
+    }
+    write(master, &tio.c_cc[VEOF], 1);
+}
+
+/*
+ * Copies between init's stdio and the workload's until `child` exits, passing
+ * on SIGINT and the terminal's size, then reaps it into `status`.
+ */
+static void relay_stdio(struct krun_relay *relays, int n, int master,
+                        pid_t child, int *status)
+{
+    struct pollfd pfds[4];
+    char sig;
+    int i;
+
+    for (;;) {
+        pfds[0].fd = relay_signal_pipe[0];
+        pfds[0].events = POLLIN;
+        for (i = 0; i < n; i++) {
+            pfds[i + 1].fd = relays[i].src;
+            pfds[i + 1].events = POLLIN;
+        }
+        if (poll(pfds, n + 1, -1) < 0) {
+            continue;
+        }
+
+        while (read(relay_signal_pipe[0], &sig, 1) == 1) {
+            if (sig == SIGINT) {
+                kill(-child, SIGINT);
+            } else if (sig == SIGWINCH && master >= 0) {
+                copy_winsize(master);
+            } else if (sig == SIGCHLD &&
+                       waitpid(child, status, WNOHANG) == child) {
+                // Flush what the workload wrote before it exited.
+                for (i = 0; i < n; i++) {
+                    if (relays[i].src > STDIN_FILENO) {
+                        fcntl(relays[i].src, F_SETFL, O_NONBLOCK);
+                        while (relay_copy(&relays[i]))
+                            ;
+                    }
+                }
+                return;
+            }
+        }
+
+        for (i = 0; i < n; i++) {
+            if (pfds[i + 1].fd < 0 || !pfds[i + 1].revents) {
+                continue;
+            }
+            if (!relay_copy(&relays[i])) {
+                if (relays[i].src == STDIN_FILENO) {
+                    relay_eof(&relays[i], master);
+                }
+                relays[i].src = -1;
+            }
+        }
+    }
+}
+
 int is_virtiofs(const char *path)
 {
     struct statfs fs;
@@ -972,7 +1504,12 @@ int is_virtiofs(const char *path)
     return (fs.f_type == 0x65735546) ? 1 : 0;
 }
 
-void set_exit_code(int code)
+/*
+ * Reports the workload's wait status to the host, so it can tell a workload
+ * killed by a signal from one that exited with the same code. Hosts that don't
+ * know about it only get the exit code, with 128 added to the signal.
+ */
+static void report_exit(int wstatus, int code)
 {
     int fd;
     int ret;
@@ -995,7 +1532,13 @@ void set_exit_code(int code)
         return;
     }
 
-    ret = ioctl(fd, KRUN_EXIT_CODE_IOCTL, code);
+    ret = -1;
+    if (wstatus >= 0) {
+        ret = ioctl(fd, KRUN_EXIT_STATUS_IOCTL, wstatus);
+    }
+    if (ret < 0) {
+        ret = ioctl(fd, KRUN_EXIT_CODE_IOCTL, code);
+    }
     if (ret < 0) {
         perror("Error using the ioctl to set the exit code");
     }
@@ -1003,6 +1546,40 @@ void set_exit_code(int code)
     close(fd);
 }
 
+/*
+ * Tells the host which boot stage init reached, for its boot timings. Hosts
+ * that don't know about it reject the ioctl, which is fine.
+ */
+static void report_boot_stage(int stage)
+{
+    int fd;
+
+    if (is_virtiofs("/") <= 0) {
+        return;
+    }
+
+    fd = open("/", O_RDONLY);
+    if (fd < 0) {
+        return;
+    }
+    ioctl(fd, KRUN_BOOT_STAGE_IOCTL, stage);
+    close(fd);
+}
+
+void set_exit_code(int code)
+{
+    report_exit(-1, code);
+}
+
+void set_exit_status(int wstatus)
+{
+    if (WIFEXITED(wstatus)) {
+        report_exit(wstatus, WEXITSTATUS(wstatus));
+    } else if (WIFSIGNALED(wstatus)) {
+        report_exit(wstatus, WTERMSIG(wstatus) + 128);
+    }
+}
+
 int try_mount(const char *source, const char *target, const char *fstype,
               unsigned long mountflags, const void *data)
 {
@@ -1048,6 +1625,16 @@ int main(int argc, char **argv)
     bool init_pid1 = false;
     char localhost[] = "localhost\0";
     char *hostname;
+    char *krun_hostname;
+    char *krun_hosts;
+    char *krun_nameservers;
+    char *krun_ips;
+    char *krun_tty;
+    struct krun_relay relays[3];
+    int workload_fds[3];
+    int nrelays;
+    int master = -1;
+    int i;
     char *krun_home;
     char *krun_term;
     char *krun_init;
@@ -1059,6 +1646,8 @@ int main(int argc, char **argv)
     char *rlimits;
     char **config_argv, **exec_argv;
 
+    report_boot_stage(KRUN_BOOT_STAGE_INIT);
+
 #ifdef TDX
     if (mkdir("/tmp", 0755) < 0 && errno != EEXIST) {
         perror("mkdir(/tmp)");
@@ -1150,6 +1739,11 @@ int main(int argc, char **argv)
         close(sockfd);
     }
 
+    krun_ips = getenv("KRUN_IPS");
+    if (krun_ips) {
+        setup_static_ips(krun_ips);
+    }
+
     config_argv = NULL;
     config_workdir = NULL;
 
@@ -1165,6 +1759,11 @@ int main(int argc, char **argv)
         setenv("TERM", krun_term, 1);
     }
 
+    krun_hostname = getenv("KRUN_HOSTNAME");
+    if (krun_hostname) {
+        setenv("HOSTNAME", krun_hostname, 1);
+    }
+
     hostname = getenv("HOSTNAME");
     if (hostname) {
         sethostname(hostname, strlen(hostname));
@@ -1172,6 +1771,16 @@ int main(int argc, char **argv)
         sethostname(&localhost[0], strlen(localhost));
     }
 
+    krun_hosts = getenv("KRUN_HOSTS");
+    if (krun_hostname || krun_hosts) {
+        setup_etc_hosts(krun_hostname, krun_hosts);
+    }
+
+    krun_nameservers = getenv("KRUN_NAMESERVERS");
+    if (krun_nameservers) {
+        setup_resolv_conf(krun_nameservers, getenv("KRUN_DNS_SEARCH"));
+    }
+
     rlimits = getenv("KRUN_RLIMITS");
     if (rlimits) {
         set_rlimits(rlimits);
@@ -1205,10 +1814,33 @@ int main(int argc, char **argv)
     }
 #endif
 
-    if (init_pid1) {
+    // Anything other than "force" or "never" leaves the workload's stdio as
+    // the console gives it. Either of those needs init to relay the stdio,
+    // so it can't also hand PID 1 over to the workload.
+    krun_tty = getenv("KRUN_TTY");
+    if (krun_tty && strcmp(krun_tty, "force") != 0 &&
+        strcmp(krun_tty, "never") != 0) {
+        krun_tty = NULL;
+    }
+
+    if (init_pid1 && !krun_tty) {
         goto exec_init;
     }
 
+    if (krun_tty) {
+        if (setup_redirects() < 0) {
+            set_exit_code(125);
+            exit(125);
+        }
+        nrelays = setup_workload_stdio(strcmp(krun_tty, "force") == 0,
+                                       workload_fds, relays, &master);
+        if (nrelays < 0) {
+            perror("Couldn't set up the workload's stdio");
+            set_exit_code(125);
+            exit(125);
+        }
+    }
+
     // We need to fork ourselves, because pid 1 cannot doesn't receive SIGINT
     // signal
     int child = fork();
@@ -1219,9 +1851,12 @@ int main(int argc, char **argv)
     }
     if (child == 0) { // child
     exec_init:
-        if (setup_redirects() < 0) {
+        if (krun_tty) {
+            enter_workload_stdio(workload_fds);
+        } else if (setup_redirects() < 0) {
             exit(125);
         }
+        report_boot_stage(KRUN_BOOT_STAGE_WORKLOAD);
         if (execvp(exec_argv[0], exec_argv) < 0) {
             saved_errno = errno;
             printf("Couldn't execute '%s' inside the vm: %s\n", exec_argv[0],
@@ -1233,6 +1868,14 @@ int main(int argc, char **argv)
                 exit(126);
             }
         }
+    } else if (krun_tty) { // parent
+        for (i = 0; i < 3; i++) {
+            if (workload_fds[i] > STDERR_FILENO) {
+                close(workload_fds[i]);
+            }
+        }
+        relay_stdio(relays, nrelays, master, child, &status);
+        set_exit_status(status);
     } else { // parent
         // Wait until the workload's entrypoint has exited, ignoring any other
         // children.
@@ -1240,13 +1883,9 @@ int main(int argc, char **argv)
             // Not the first child, ignore it.
         };
 
-        // The workload's entrypoint has exited, record its exit code and exit
+        // The workload's entrypoint has exited, record how and exit
         // ourselves.
-        if (WIFEXITED(status)) {
-            set_exit_code(WEXITSTATUS(status));
-        } else if (WIFSIGNALED(status)) {
-            set_exit_code(WTERMSIG(status) + 128);
-        }
+        set_exit_status(status);
     }
 
     return 0;
@@ -1,14 +1,15 @@
 use std::cmp;
-use std::convert::TryInto;
-use std::io::Write;
+use std::io::{self, Write};
+use std::sync::Arc;
 
 use utils::eventfd::EventFd;
-use vm_memory::{ByteValued, GuestMemory, GuestMemoryMmap};
+use vm_memory::{ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap};
 
 use super::super::{
     ActivateError, ActivateResult, BalloonError, DeviceQueue, DeviceState, QueueConfig,
     VirtioDevice,
 };
+use super::metrics::BalloonMetrics;
 use super::{defs, defs::uapi};
 use crate::virtio::InterruptTransport;
 
@@ -23,11 +24,28 @@ pub(crate) const PHQ_INDEX: usize = 3;
 // Free page reporting queue.
 pub(crate) const FRQ_INDEX: usize = 4;
 
-// Supported features.
+// Supported features, apart from free page reporting, which can be turned off.
 pub(crate) const AVAIL_FEATURES: u64 = (1 << uapi::VIRTIO_F_VERSION_1 as u64)
     | (1 << uapi::VIRTIO_BALLOON_F_STATS_VQ as u64)
-    | (1 << uapi::VIRTIO_BALLOON_F_FREE_PAGE_HINT as u64)
-    | (1 << uapi::VIRTIO_BALLOON_F_REPORTING as u64);
+    | (1 << uapi::VIRTIO_BALLOON_F_FREE_PAGE_HINT as u64);
+
+/// Gives the host memory at a host address back to the host, like `madvise(MADV_DONTNEED)`.
+pub(crate) type Discard = Box<dyn FnMut(*mut u8, usize) -> io::Result<()> + Send>;
+
+fn madvise_dontneed(addr: *mut u8, len: usize) -> io::Result<()> {
+    // Safe because the range is guest memory, which the guest said it no longer uses and which
+    // reads back as zeroes afterwards.
+    let ret = unsafe { libc::madvise(addr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn host_page_size() -> usize {
+    // Safe because sysconf has no side effects.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed)]
@@ -52,21 +70,36 @@ pub struct Balloon {
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
     config: VirtioBalloonConfig,
+    metrics: Arc<BalloonMetrics>,
+    pub(crate) discard: Discard,
 }
 
 impl Balloon {
-    pub fn new() -> super::Result<Balloon> {
+    /// Creates a balloon that offers the guest free page reporting if `free_page_reporting` is
+    /// set, counting the reported pages in `metrics`.
+    pub fn new(free_page_reporting: bool, metrics: Arc<BalloonMetrics>) -> super::Result<Balloon> {
+        let mut avail_features = AVAIL_FEATURES;
+        if free_page_reporting {
+            avail_features |= 1 << uapi::VIRTIO_BALLOON_F_REPORTING as u64;
+        }
+
         Ok(Balloon {
             queues: None,
-            avail_features: AVAIL_FEATURES,
+            avail_features,
             acked_features: 0,
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
                 .map_err(BalloonError::EventFd)?,
             device_state: DeviceState::Inactive,
             config: VirtioBalloonConfig::default(),
+            metrics,
+            discard: Box::new(madvise_dontneed),
         })
     }
 
+    pub fn metrics(&self) -> &Arc<BalloonMetrics> {
+        &self.metrics
+    }
+
     pub fn id(&self) -> &str {
         defs::BALLOON_DEV_ID
     }
@@ -88,18 +121,19 @@ impl Balloon {
         while let Some(head) = queues[FRQ_INDEX].queue.pop(mem) {
             let index = head.index;
             for desc in head.into_iter() {
-                let host_addr = mem.get_host_address(desc.addr).unwrap();
+                let len = desc.len as usize;
+                self.metrics.reported(len);
+                let Some((addr, len)) = host_pages(mem, desc.addr, len) else {
+                    continue;
+                };
                 debug!(
-                    "balloon: should release guest_addr={:?} host_addr={:p} len={}",
-                    desc.addr, host_addr, desc.len
+                    "balloon: releasing guest_addr={:?} host_addr={:p} len={}",
+                    desc.addr, addr, len
                 );
-                unsafe {
-                    libc::madvise(
-                        host_addr as *mut libc::c_void,
-                        desc.len.try_into().unwrap(),
-                        libc::MADV_DONTNEED,
-                    )
-                };
+                match (self.discard)(addr, len) {
+                    Ok(()) => self.metrics.reclaimed(len),
+                    Err(e) => warn!("balloon: can't release {len} bytes at {addr:p}: {e}"),
+                }
             }
 
             have_used = true;
@@ -112,6 +146,26 @@ impl Balloon {
     }
 }
 
+// Returns the host pages fully inside the `len` bytes of guest memory at `addr`, if there are any.
+// The range must be within a single region of guest memory.
+fn host_pages(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> Option<(*mut u8, usize)> {
+    let slice = match mem.get_slice(addr, len) {
+        Ok(slice) => slice,
+        Err(e) => {
+            warn!("balloon: ignoring report of {len} bytes at {addr:?}: {e}");
+            return None;
+        }
+    };
+    let page_size = host_page_size();
+    let start = slice.ptr_guard_mut().as_ptr() as usize;
+    let first = start.next_multiple_of(page_size);
+    let end = (start + len) / page_size * page_size;
+    if end <= first {
+        return None;
+    }
+    Some((first as *mut u8, end - first))
+}
+
 impl VirtioDevice for Balloon {
     fn avail_features(&self) -> u64 {
         self.avail_features
@@ -189,3 +243,82 @@ impl VirtioDevice for Balloon {
         self.device_state.is_activated()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use utils::eventfd::EFD_NONBLOCK;
+
+    use super::super::metrics::BalloonMetricsSnapshot;
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::queue::tests::VirtQueue;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+    const MEM_SIZE: u64 = 0x100000;
+
+    #[test]
+    fn free_page_report() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE as usize)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let evt = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let queues = (0..defs::NUM_QUEUES)
+            .map(|_| DeviceQueue::new(vq.create_queue(), evt.clone()))
+            .collect();
+        let interrupt = InterruptTransport::new(DummyIrqChip::new().into(), "balloon".into());
+
+        let metrics = Arc::new(BalloonMetrics::new());
+        let mut balloon = Balloon::new(true, metrics.clone()).unwrap();
+        assert_ne!(
+            balloon.avail_features() & (1 << uapi::VIRTIO_BALLOON_F_REPORTING),
+            0
+        );
+        let discarded = Arc::new(Mutex::new(Vec::new()));
+        let calls = discarded.clone();
+        balloon.discard = Box::new(move |addr, len| {
+            calls.lock().unwrap().push((addr as usize, len));
+            Ok(())
+        });
+        balloon.queues = Some(queues);
+        balloon.device_state = DeviceState::Activated(mem.clone(), interrupt.unwrap());
+
+        // One report of three ranges: 128 KiB of whole pages, a range running past the end of
+        // guest memory and a range within a single page.
+        vq.dtable[0].set(0x10000, 0x20000, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(
+            MEM_SIZE - 0x10000,
+            0x20000,
+            VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+            2,
+        );
+        vq.dtable[2].set(0x40100, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        assert!(balloon.process_frq());
+        assert_eq!(vq.used.idx.get(), 1);
+
+        let base = mem.get_host_address(GuestAddress(0)).unwrap() as usize;
+        assert_eq!(*discarded.lock().unwrap(), vec![(base + 0x10000, 0x20000)]);
+        assert_eq!(
+            metrics.snapshot(),
+            BalloonMetricsSnapshot {
+                reports: 3,
+                reported_bytes: 0x40100,
+                reclaimed_bytes: 0x20000,
+            }
+        );
+    }
+
+    #[test]
+    fn free_page_reporting_disabled() {
+        let balloon = Balloon::new(false, Arc::new(BalloonMetrics::new())).unwrap();
+        assert_eq!(
+            balloon.avail_features() & (1 << uapi::VIRTIO_BALLOON_F_REPORTING),
+            0
+        );
+    }
+}
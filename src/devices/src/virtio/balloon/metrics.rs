@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Free page reporting counters of a balloon. Every counter is a relaxed atomic, so they can be
+/// read from any thread while the device keeps going.
+#[derive(Debug, Default)]
+pub struct BalloonMetrics {
+    reports: AtomicU64,
+    reported_bytes: AtomicU64,
+    reclaimed_bytes: AtomicU64,
+}
+
+/// The values of a `BalloonMetrics` at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BalloonMetricsSnapshot {
+    /// Free page ranges the guest reported.
+    pub reports: u64,
+    /// Bytes in the ranges the guest reported.
+    pub reported_bytes: u64,
+    /// Bytes of guest memory given back to the host. Less than `reported_bytes` when ranges fall
+    /// outside guest memory or only partly cover host pages.
+    pub reclaimed_bytes: u64,
+}
+
+impl BalloonMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> BalloonMetricsSnapshot {
+        BalloonMetricsSnapshot {
+            reports: self.reports.load(Ordering::Relaxed),
+            reported_bytes: self.reported_bytes.load(Ordering::Relaxed),
+            reclaimed_bytes: self.reclaimed_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reported(&self, bytes: usize) {
+        self.reports.fetch_add(1, Ordering::Relaxed);
+        self.reported_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reclaimed(&self, bytes: usize) {
+        self.reclaimed_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
@@ -1,8 +1,10 @@
 mod device;
 mod event_handler;
+mod metrics;
 
 pub use self::defs::uapi::VIRTIO_ID_BALLOON as TYPE_BALLOON;
 pub use self::device::Balloon;
+pub use self::metrics::{BalloonMetrics, BalloonMetricsSnapshot};
 
 mod defs {
     use super::super::QueueConfig;
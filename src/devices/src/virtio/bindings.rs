@@ -8,7 +8,13 @@ pub const LINUX_ENOTEMPTY: libc::c_int = 39;
 
 pub const LINUX_O_APPEND: libc::c_int = 1024;
 pub const LINUX_O_CLOEXEC: libc::c_int = 0x80000;
+#[cfg(target_arch = "aarch64")]
+pub const LINUX_O_DIRECT: libc::c_int = 0x10000;
+#[cfg(not(target_arch = "aarch64"))]
 pub const LINUX_O_DIRECT: libc::c_int = 0x4000;
+#[cfg(target_arch = "aarch64")]
+pub const LINUX_O_DIRECTORY: libc::c_int = 0x4000;
+#[cfg(not(target_arch = "aarch64"))]
 pub const LINUX_O_DIRECTORY: libc::c_int = 0x10000;
 pub const LINUX_O_LARGEFILE: libc::c_int = 0;
 pub const LINUX_O_NOFOLLOW: libc::c_int = 0x20000;
@@ -22,10 +28,20 @@ pub const LINUX_O_RSYNC: libc::c_int = 1052672;
 pub const LINUX_O_DSYNC: libc::c_int = 4096;
 pub const LINUX_O_ASYNC: libc::c_int = 0x2000;
 
+pub const LINUX_FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const LINUX_FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const LINUX_FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
 pub const LINUX_RENAME_NOREPLACE: libc::c_int = 1 << 0;
 pub const LINUX_RENAME_EXCHANGE: libc::c_int = 1 << 1;
 pub const LINUX_RENAME_WHITEOUT: libc::c_int = 1 << 2;
 
+pub const LINUX_SEEK_SET: libc::c_int = 0;
+pub const LINUX_SEEK_CUR: libc::c_int = 1;
+pub const LINUX_SEEK_END: libc::c_int = 2;
+pub const LINUX_SEEK_DATA: libc::c_int = 3;
+pub const LINUX_SEEK_HOLE: libc::c_int = 4;
+
 pub const LINUX_XATTR_CREATE: libc::c_int = 1;
 pub const LINUX_XATTR_REPLACE: libc::c_int = 2;
 
@@ -18,9 +18,11 @@ use std::result;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 
+use crossbeam_channel::{bounded, unbounded, Sender};
 use imago::{
-    file::File as ImagoFile, qcow2::Qcow2, raw::Raw, vmdk::Vmdk, DynStorage, FormatDriverBuilder,
-    PermissiveImplicitOpenGate, Storage, StorageOpenOptions, SyncFormatAccess,
+    file::File as ImagoFile, format::PreallocateMode, qcow2::Qcow2, raw::Raw, vmdk::Vmdk,
+    DynStorage, FormatDriverBuilder, PermissiveImplicitOpenGate, Storage, StorageOpenOptions,
+    SyncFormatAccess,
 };
 use log::{error, warn};
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
@@ -29,7 +31,10 @@ use virtio_bindings::{
 };
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
-use super::worker::BlockWorker;
+use super::metrics::BlockMetrics;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use super::uring::UringDisk;
+use super::worker::{BlockWorker, ResizeRequest};
 use super::{
     super::{ActivateResult, DeviceQueue, DeviceState, QueueConfig, VirtioDevice, TYPE_BLOCK},
     Error, NUM_QUEUES, QUEUE_CONFIG, SECTOR_SHIFT, SECTOR_SIZE,
@@ -37,6 +42,7 @@ use super::{
 
 use crate::virtio::{
     block::{ImageType, SyncMode},
+    rate_limiter::{RateLimit, RateLimiter, Throttle},
     ActivateError, InterruptTransport,
 };
 
@@ -51,6 +57,9 @@ pub enum CacheType {
     /// flush requests coming from the guest will be performed using
     /// `fsync`.
     Writeback,
+    /// Writes are synced to the host disk before they are completed, so
+    /// no flushing mechanic is advertised to the guest driver.
+    Writethrough,
 }
 
 impl CacheType {
@@ -71,6 +80,7 @@ pub(crate) struct DiskProperties {
     pub(crate) file: Arc<Mutex<SyncFormatAccess<Box<dyn DynStorage>>>>,
     nsectors: u64,
     image_id: Vec<u8>,
+    read_only: bool,
 }
 
 impl DiskProperties {
@@ -78,6 +88,7 @@ impl DiskProperties {
         disk_image: Arc<Mutex<SyncFormatAccess<Box<dyn DynStorage>>>>,
         disk_image_id: Vec<u8>,
         cache_type: CacheType,
+        read_only: bool,
     ) -> io::Result<Self> {
         let disk_size = disk_image.lock().unwrap().size();
 
@@ -95,6 +106,7 @@ impl DiskProperties {
             nsectors: disk_size >> SECTOR_SHIFT,
             image_id: disk_image_id,
             file: disk_image,
+            read_only,
         })
     }
 
@@ -106,6 +118,21 @@ impl DiskProperties {
         &self.image_id
     }
 
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Resizes the image to `new_size` bytes. Growing it keeps the data past the old end that the
+    /// host may have put there already.
+    pub fn resize(&mut self, new_size: u64) -> io::Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .resize(new_size, PreallocateMode::None)?;
+        self.nsectors = new_size >> SECTOR_SHIFT;
+        Ok(())
+    }
+
     fn build_device_id(disk_file: &File) -> result::Result<String, Error> {
         let blk_metadata = disk_file.metadata().map_err(Error::GetFileMetadata)?;
         // This is how kvmtool does it.
@@ -138,12 +165,19 @@ impl DiskProperties {
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    /// Forces any cached data out and syncs it to the physical media on the host.
+    pub fn sync(&self) -> io::Result<()> {
+        let diskfile = self.file.lock().unwrap();
+        diskfile.flush()?;
+        diskfile.sync()
+    }
 }
 
 impl Drop for DiskProperties {
     fn drop(&mut self) {
         match self.cache_type {
-            CacheType::Writeback => {
+            CacheType::Writeback | CacheType::Writethrough => {
                 // flush() first to force any cached data out.
                 if self.file.lock().unwrap().flush().is_err() {
                     error!("Failed to flush block data on drop.");
@@ -209,6 +243,13 @@ pub struct Block {
     disk_image_id: Vec<u8>,
     worker_thread: Option<JoinHandle<()>>,
     worker_stopfd: EventFd,
+    worker_resize_evt: EventFd,
+    worker_resize_tx: Option<Sender<ResizeRequest>>,
+    metrics: Arc<BlockMetrics>,
+    rate_limiter: Arc<RateLimiter>,
+    // Raw image for the worker to do I/O on through io_uring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring_file: Option<File>,
 
     // Virtio fields.
     pub(crate) avail_features: u64,
@@ -244,15 +285,27 @@ impl Block {
             .open(PathBuf::from(&disk_image_path))?;
 
         let disk_image_id = DiskProperties::build_disk_image_id(&disk_image);
+        // Direct I/O needs aligned buffers, which imago takes care of.
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let uring_file = (disk_image_format == ImageType::Raw && !direct_io).then_some(disk_image);
 
         let file_opts = StorageOpenOptions::new()
             .write(!is_disk_read_only)
-            .filename(disk_image_path)
+            .filename(disk_image_path.clone())
             .direct(direct_io);
 
         #[cfg(target_os = "macos")]
         let file_opts = file_opts.relaxed_sync(sync_mode == SyncMode::Relaxed);
-        let file = ImagoFile::open_sync(file_opts)?;
+        let file = ImagoFile::open_sync(file_opts).map_err(|e| {
+            if direct_io && e.raw_os_error() == Some(libc::EINVAL) {
+                io::Error::new(
+                    e.kind(),
+                    format!("the filesystem of {disk_image_path} doesn't support direct I/O"),
+                )
+            } else {
+                e
+            }
+        })?;
         let discard_alignment = file.discard_align();
 
         let disk_image = match disk_image_format {
@@ -283,22 +336,27 @@ impl Block {
 
         let disk_image = Arc::new(Mutex::new(disk_image));
 
-        let disk_properties =
-            DiskProperties::new(disk_image.clone(), disk_image_id.clone(), cache_type)?;
+        let disk_properties = DiskProperties::new(
+            disk_image.clone(),
+            disk_image_id.clone(),
+            cache_type,
+            is_disk_read_only,
+        )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
             | (1u64 << VIRTIO_BLK_F_SEG_MAX)
-            | (1u64 << VIRTIO_BLK_F_DISCARD)
-            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES)
             | (1u64 << VIRTIO_RING_F_EVENT_IDX);
 
-        if sync_mode != SyncMode::None {
+        // Without the flush feature the guest treats the disk as having a writethrough cache.
+        if sync_mode != SyncMode::None && cache_type != CacheType::Writethrough {
             avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
         }
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
-        };
+        } else {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        }
 
         let config = VirtioBlkConfig {
             capacity: disk_properties.nsectors(),
@@ -327,6 +385,12 @@ impl Block {
             device_state: DeviceState::Inactive,
             worker_thread: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK)?,
+            worker_resize_evt: EventFd::new(EFD_NONBLOCK)?,
+            worker_resize_tx: None,
+            metrics: Arc::new(BlockMetrics::new()),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring_file,
         })
     }
 
@@ -344,6 +408,94 @@ impl Block {
     pub fn is_read_only(&self) -> bool {
         self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0
     }
+
+    /// The I/O counters of this block device, which keep counting across resets.
+    pub fn metrics(&self) -> Arc<BlockMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The ceiling on this block device's requests and bytes per second.
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limiter.limit()
+    }
+
+    /// Caps the rate of this block device's I/O, taking effect right away if it's running.
+    /// Requests over the limit wait on the queue.
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.rate_limiter.set_limit(limit);
+    }
+
+    /// Resizes the disk to `new_size` bytes, which must be a multiple of the sector size, and
+    /// lets the guest know. Shrinking drops the data past the new end, so it's refused unless
+    /// `force` is set.
+    ///
+    /// While the device is running, the resize happens between two batches of requests.
+    pub fn resize(&mut self, new_size: u64, force: bool) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "can't resize a read-only disk",
+            ));
+        }
+        if !new_size.is_multiple_of(SECTOR_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("disk size {new_size} is not a multiple of {SECTOR_SIZE}"),
+            ));
+        }
+        let capacity = self.config.capacity;
+        if new_size >> SECTOR_SHIFT < capacity && !force {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shrinking a disk needs to be forced",
+            ));
+        }
+
+        match &self.worker_resize_tx {
+            Some(resize_tx) => {
+                let (reply, result) = bounded(1);
+                resize_tx
+                    .send(ResizeRequest { new_size, reply })
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+                self.worker_resize_evt.write(1)?;
+                result
+                    .recv()
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))??;
+            }
+            None => match self.disk.as_mut() {
+                Some(disk) => disk.resize(new_size)?,
+                // The worker's disk is recreated with the new size on activation.
+                None => self
+                    .disk_image
+                    .lock()
+                    .unwrap()
+                    .resize(new_size, PreallocateMode::None)?,
+            },
+        }
+
+        self.config.capacity = new_size >> SECTOR_SHIFT;
+        if let DeviceState::Activated(_, interrupt) = &self.device_state {
+            interrupt.signal_config_change();
+        }
+        Ok(())
+    }
+
+    // Sets up io_uring for the worker if the image can use it, falling back to doing the I/O on
+    // the worker thread if the host doesn't let us.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn with_uring(&self, mut worker: BlockWorker, mem: &GuestMemoryMmap) -> BlockWorker {
+        if let Some(file) = &self.uring_file {
+            let dsync = self.cache_type == CacheType::Writethrough;
+            match file
+                .try_clone()
+                .and_then(|file| UringDisk::new(file, super::QUEUE_SIZE as u32, mem, dsync))
+            {
+                Ok(uring) => worker.set_uring(uring),
+                Err(e) => warn!("io_uring unavailable, using synchronous disk I/O: {e}"),
+            }
+        }
+        worker
+    }
 }
 
 impl VirtioDevice for Block {
@@ -414,24 +566,38 @@ impl VirtioDevice for Block {
                 Arc::clone(&self.disk_image),
                 self.disk_image_id.clone(),
                 self.cache_type,
+                self.is_read_only(),
             )
             .map_err(|_| ActivateError::BadActivate)?,
         };
 
+        let throttle = Throttle::new(Arc::clone(&self.rate_limiter)).map_err(|e| {
+            error!("Failed to create the rate limiter timer: {e:?}");
+            ActivateError::BadActivate
+        })?;
+        let (resize_tx, resize_rx) = unbounded();
         let worker = BlockWorker::new(
             blk_q,
             interrupt.clone(),
             mem.clone(),
             disk,
             self.worker_stopfd.try_clone().unwrap(),
+            self.worker_resize_evt.try_clone().unwrap(),
+            resize_rx,
+            Arc::clone(&self.metrics),
+            throttle,
         );
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let worker = self.with_uring(worker, &mem);
         self.worker_thread = Some(worker.run());
+        self.worker_resize_tx = Some(resize_tx);
 
         self.device_state = DeviceState::Activated(mem, interrupt);
         Ok(())
     }
 
     fn reset(&mut self) -> bool {
+        self.worker_resize_tx = None;
         if let Some(worker) = self.worker_thread.take() {
             let _ = self.worker_stopfd.write(1);
             if let Err(e) = worker.join() {
@@ -442,3 +608,134 @@ impl VirtioDevice for Block {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use vm_memory::{Bytes, GuestAddress};
+
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::VIRTIO_MMIO_INT_CONFIG;
+
+    const MIB: u64 = 1 << 20;
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+    fn capacity(block: &Block) -> u64 {
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        u64::from_le_bytes(capacity)
+    }
+
+    fn open(path: &std::path::Path, read_only: bool) -> Block {
+        Block::new(
+            "vda".to_string(),
+            None,
+            CacheType::Writeback,
+            path.to_str().unwrap().to_string(),
+            ImageType::Raw,
+            read_only,
+            false,
+            SyncMode::Full,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn resize() {
+        let dir = std::env::temp_dir().join(format!("krun-blk-resize-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("disk.raw");
+        fs::File::create(&path).unwrap().set_len(MIB).unwrap();
+        let file_len = || fs::metadata(&path).unwrap().len();
+
+        let mut block = open(&path, false);
+        block.resize(2 * MIB, false).unwrap();
+        assert_eq!(capacity(&block), 2 * MIB / 512);
+        assert_eq!(file_len(), 2 * MIB);
+
+        let err = block.resize(MIB, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(file_len(), 2 * MIB);
+        assert_eq!(
+            block.resize(MIB + 1, true).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        block.resize(MIB, true).unwrap();
+        assert_eq!(capacity(&block), MIB / 512);
+        assert_eq!(file_len(), MIB);
+
+        // Once running, the worker picks up the new size, including what the host grew the image
+        // to on its own.
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let evt = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let interrupt =
+            InterruptTransport::new(DummyIrqChip::new().into(), "block".into()).unwrap();
+        block
+            .activate(
+                mem.clone(),
+                interrupt.clone(),
+                vec![DeviceQueue::new(vq.create_queue(), evt.clone())],
+            )
+            .unwrap();
+
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(4 * MIB)
+            .unwrap();
+        block.resize(4 * MIB, false).unwrap();
+        assert_eq!(capacity(&block), 4 * MIB / 512);
+        assert_ne!(
+            interrupt.status().load(std::sync::atomic::Ordering::SeqCst)
+                & VIRTIO_MMIO_INT_CONFIG as usize,
+            0
+        );
+
+        // Write the last sector of the grown disk.
+        let (header, data, status) = (0x1000, 0x2000, 0x3000);
+        mem.write_slice(&VIRTIO_BLK_T_OUT.to_le_bytes(), GuestAddress(header))
+            .unwrap();
+        mem.write_slice(&(4 * MIB / 512 - 1).to_le_bytes(), GuestAddress(header + 8))
+            .unwrap();
+        mem.write_slice(&[0x5a; 512], GuestAddress(data)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(status)).unwrap();
+        vq.dtable[0].set(header, 16, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(data, 512, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(status, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        evt.write(1).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while vq.used.idx.get() != 1 {
+            assert!(Instant::now() < deadline, "request not completed in time");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert!(block.reset());
+        assert_eq!(
+            fs::read(&path).unwrap()[(4 * MIB - 512) as usize..],
+            [0x5a; 512]
+        );
+
+        let mut block = open(&path, true);
+        assert_eq!(
+            block.resize(8 * MIB, false).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
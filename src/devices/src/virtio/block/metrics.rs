@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use virtio_bindings::virtio_blk::*;
+
+/// I/O counters of a disk, updated as requests complete. Every counter is a relaxed atomic, so
+/// they can be read from any thread while the worker keeps going.
+#[derive(Debug, Default)]
+pub struct BlockMetrics {
+    reads: AtomicU64,
+    bytes_read: AtomicU64,
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+    flushes: AtomicU64,
+    errors: AtomicU64,
+    inflight: AtomicU64,
+    max_inflight: AtomicU64,
+    interval_max_inflight: AtomicU64,
+    last: Mutex<BlockMetricsSnapshot>,
+}
+
+/// The values of a `BlockMetrics` at one point in time, or how much they grew between two points.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockMetricsSnapshot {
+    /// Completed read requests.
+    pub reads: u64,
+    /// Bytes returned by read requests.
+    pub bytes_read: u64,
+    /// Completed write, discard and write zeroes requests.
+    pub writes: u64,
+    /// Bytes stored by write requests.
+    pub bytes_written: u64,
+    /// Completed flush requests.
+    pub flushes: u64,
+    /// Requests that failed.
+    pub errors: u64,
+    /// The most requests the device has been working on at once.
+    pub max_queue_depth: u64,
+}
+
+impl BlockMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> BlockMetricsSnapshot {
+        BlockMetricsSnapshot {
+            reads: self.reads.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            max_queue_depth: self.max_inflight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns how much the counters grew since the previous call, along with the most requests
+    /// in flight at once in that time.
+    pub fn interval(&self) -> BlockMetricsSnapshot {
+        let mut last = self.last.lock().unwrap();
+        let now = self.snapshot();
+        let depth = self
+            .interval_max_inflight
+            .swap(self.inflight.load(Ordering::Relaxed), Ordering::Relaxed);
+        let delta = BlockMetricsSnapshot {
+            reads: now.reads - last.reads,
+            bytes_read: now.bytes_read - last.bytes_read,
+            writes: now.writes - last.writes,
+            bytes_written: now.bytes_written - last.bytes_written,
+            flushes: now.flushes - last.flushes,
+            errors: now.errors - last.errors,
+            max_queue_depth: depth,
+        };
+        *last = now;
+        delta
+    }
+
+    /// Counts a request the device took from the queue.
+    pub(crate) fn start(&self) {
+        let inflight = self.inflight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_inflight.fetch_max(inflight, Ordering::Relaxed);
+        self.interval_max_inflight
+            .fetch_max(inflight, Ordering::Relaxed);
+    }
+
+    /// Counts a completed request that transferred `bytes` if it succeeded.
+    pub(crate) fn complete(&self, request_type: u32, bytes: usize, ok: bool) {
+        self.inflight.fetch_sub(1, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        match request_type {
+            VIRTIO_BLK_T_IN => {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            VIRTIO_BLK_T_OUT => {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                self.bytes_written
+                    .fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.flushes.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
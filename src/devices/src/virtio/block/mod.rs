@@ -2,9 +2,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod device;
+mod metrics;
+mod slot;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod worker;
 
 pub use self::device::{Block, CacheType};
+pub use self::metrics::{BlockMetrics, BlockMetricsSnapshot};
+pub use self::slot::BlockSlot;
 
 use vm_memory::GuestMemoryError;
 
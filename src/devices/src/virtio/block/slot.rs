@@ -0,0 +1,270 @@
+use std::io;
+
+use log::warn;
+use vm_memory::GuestMemoryMmap;
+
+use super::super::{
+    ActivateError, ActivateResult, DeviceQueue, InterruptTransport, QueueConfig, VirtioDevice,
+};
+use super::{Block, QUEUE_CONFIG};
+
+/// A virtio-blk device that disks can be plugged into and taken out of while the VM runs.
+///
+/// While empty, the device reports an ID of 0, which guest drivers take for a placeholder and
+/// skip. Once a disk is plugged in, the guest finds it by probing the device again, such as by
+/// binding it to the `virtio-mmio` driver through sysfs.
+pub struct BlockSlot {
+    id: String,
+    block: Option<Block>,
+}
+
+impl BlockSlot {
+    /// Creates an empty slot, whose disk shows up in the guest as `id`.
+    pub fn new(id: String) -> Self {
+        BlockSlot { id, block: None }
+    }
+
+    /// Provides the ID of the disks plugged into this slot.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Whether there's no disk in this slot.
+    pub fn is_vacant(&self) -> bool {
+        self.block.is_none()
+    }
+
+    /// The disk in this slot, if any.
+    pub fn block(&self) -> Option<&Block> {
+        self.block.as_ref()
+    }
+
+    /// Plugs `block` into this slot. Fails if there's a disk in it already.
+    pub fn plug(&mut self, block: Block) -> io::Result<()> {
+        if self.block.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{}: slot already has a disk", self.id),
+            ));
+        }
+        self.block = Some(block);
+        Ok(())
+    }
+
+    /// Takes the disk out of this slot, after the requests the guest has in flight complete.
+    /// The disk is flushed to the host as it's dropped.
+    ///
+    /// Fails with `EBUSY` while the guest's driver is using the disk, unless `force` is set, in
+    /// which case the guest's further requests go unanswered.
+    pub fn unplug(&mut self, force: bool) -> io::Result<()> {
+        let Some(block) = self.block.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: slot has no disk", self.id),
+            ));
+        };
+        if block.is_activated() && !force {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        // Stopping the worker waits for the requests in flight.
+        block.reset();
+        self.block = None;
+        Ok(())
+    }
+}
+
+impl VirtioDevice for BlockSlot {
+    fn device_type(&self) -> u32 {
+        self.block.as_ref().map_or(0, Block::device_type)
+    }
+
+    fn device_name(&self) -> &str {
+        "block"
+    }
+
+    fn queue_config(&self) -> &[QueueConfig] {
+        &QUEUE_CONFIG
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.block.as_ref().map_or(0, Block::avail_features)
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.block.as_ref().map_or(0, Block::acked_features)
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        if let Some(block) = self.block.as_mut() {
+            block.set_acked_features(acked_features);
+        }
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        match &self.block {
+            Some(block) => block.read_config(offset, data),
+            None => data.fill(0),
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if let Some(block) = self.block.as_mut() {
+            block.write_config(offset, data);
+        }
+    }
+
+    fn is_activated(&self) -> bool {
+        self.block.as_ref().is_some_and(Block::is_activated)
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: InterruptTransport,
+        queues: Vec<DeviceQueue>,
+    ) -> ActivateResult {
+        match self.block.as_mut() {
+            Some(block) => block.activate(mem, interrupt, queues),
+            None => {
+                warn!("{}: activating an empty disk slot", self.id);
+                Err(ActivateError::BadActivate)
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        self.block.as_mut().is_none_or(Block::reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use utils::eventfd::{EventFd, EFD_NONBLOCK};
+    use virtio_bindings::virtio_blk::{VIRTIO_BLK_S_OK, VIRTIO_BLK_T_OUT};
+    use vm_memory::{Bytes, GuestAddress};
+
+    use crate::bus::BusDevice;
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::block::{ImageType, SyncMode};
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::{CacheType, MmioTransport, TYPE_BLOCK};
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+    #[test]
+    fn hotplug_disk() {
+        let dir = std::env::temp_dir().join(format!("krun-blk-slot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("scratch.raw");
+        fs::File::create(&path).unwrap().set_len(1 << 20).unwrap();
+
+        let mut slot = BlockSlot::new("vdb".to_string());
+        assert_eq!(slot.device_type(), 0);
+        assert_eq!(
+            slot.unplug(false).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let block = || {
+            Block::new(
+                "vdb".to_string(),
+                None,
+                CacheType::Writeback,
+                path.to_str().unwrap().to_string(),
+                ImageType::Raw,
+                false,
+                false,
+                SyncMode::Full,
+            )
+            .unwrap()
+        };
+        slot.plug(block()).unwrap();
+        assert_eq!(slot.device_type(), TYPE_BLOCK);
+        assert_eq!(
+            slot.plug(block()).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let evt = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let interrupt = InterruptTransport::new(DummyIrqChip::new().into(), "vdb".into()).unwrap();
+        slot.activate(
+            mem.clone(),
+            interrupt,
+            vec![DeviceQueue::new(vq.create_queue(), evt.clone())],
+        )
+        .unwrap();
+
+        // Write the second sector, as the guest would when formatting the disk.
+        let (header, data, status) = (0x1000, 0x2000, 0x3000);
+        mem.write_slice(&VIRTIO_BLK_T_OUT.to_le_bytes(), GuestAddress(header))
+            .unwrap();
+        mem.write_slice(&1u64.to_le_bytes(), GuestAddress(header + 8))
+            .unwrap();
+        mem.write_slice(&[0xa5; 512], GuestAddress(data)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(status)).unwrap();
+        vq.dtable[0].set(header, 16, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(data, 512, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(status, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        evt.write(1).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while vq.used.idx.get() != 1 {
+            assert!(Instant::now() < deadline, "request not completed in time");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        assert_eq!(
+            slot.unplug(false).unwrap_err().raw_os_error(),
+            Some(libc::EBUSY)
+        );
+        slot.unplug(true).unwrap();
+        assert!(slot.is_vacant());
+        assert_eq!(slot.device_type(), 0);
+
+        let image = fs::read(&path).unwrap();
+        assert_eq!(image.len(), 1 << 20);
+        assert_eq!(image[512..1024], [0xa5; 512]);
+        assert!(image[..512].iter().chain(&image[1024..]).all(|&b| b == 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn activating_a_vacant_slot_fails_the_device() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut transport = MmioTransport::new(
+            mem,
+            DummyIrqChip::new().into(),
+            Arc::new(Mutex::new(BlockSlot::new("vdb".to_string()))),
+        )
+        .unwrap();
+
+        // Walk the device through initialization like a guest that doesn't skip placeholders.
+        let mut status = 0u32;
+        for bit in [1, 2, 8, 4] {
+            status |= bit;
+            transport.write(0, 0x70, &status.to_le_bytes());
+        }
+
+        let mut data = [0; 4];
+        transport.read(0, 0x70, &mut data);
+        assert_ne!(u32::from_le_bytes(data) & 0x80, 0);
+        assert!(!transport.locked_device().is_activated());
+    }
+}
@@ -0,0 +1,331 @@
+// Submits the guest's reads, writes and flushes of raw images to io_uring, so that many of them
+// can be in flight at once and complete out of order.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Result};
+use std::os::fd::{AsRawFd, RawFd};
+
+use io_uring::{opcode, types, IoUring};
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use virtio_bindings::virtio_blk::*;
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion, VolatileSlice};
+
+use crate::virtio::file_traits::FileReadWriteAtVolatile;
+
+// Registered buffers can't be larger than this.
+const MAX_FIXED_BUFFER: usize = 1 << 30;
+
+/// Collects the host addresses of the first `limit` bytes of guest buffers that a `Reader` or
+/// `Writer` would copy to or from, instead of doing any I/O.
+pub(crate) struct IovecCollector {
+    iovecs: RefCell<Vec<libc::iovec>>,
+    limit: usize,
+}
+
+impl IovecCollector {
+    pub fn new(limit: usize) -> Self {
+        IovecCollector {
+            iovecs: RefCell::new(Vec::new()),
+            limit,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<libc::iovec> {
+        self.iovecs.into_inner()
+    }
+
+    // The last buffer may be longer than what is left to transfer, so it gets cut short.
+    fn collect(&self, bufs: &[VolatileSlice]) -> Result<usize> {
+        let mut iovecs = self.iovecs.borrow_mut();
+        let mut len = 0;
+        for buf in bufs {
+            let iov_len = buf.len().min(self.limit - len);
+            if iov_len == 0 {
+                break;
+            }
+            iovecs.push(libc::iovec {
+                iov_base: buf.ptr_guard_mut().as_ptr() as *mut libc::c_void,
+                iov_len,
+            });
+            len += iov_len;
+        }
+        Ok(len)
+    }
+}
+
+impl FileReadWriteAtVolatile for IovecCollector {
+    fn read_at_volatile(&self, slice: VolatileSlice, _offset: u64) -> Result<usize> {
+        self.collect(&[slice])
+    }
+
+    fn read_vectored_at_volatile(&self, bufs: &[VolatileSlice], _offset: u64) -> Result<usize> {
+        self.collect(bufs)
+    }
+
+    fn write_at_volatile(&self, slice: VolatileSlice, _offset: u64) -> Result<usize> {
+        self.collect(&[slice])
+    }
+
+    fn write_vectored_at_volatile(&self, bufs: &[VolatileSlice], _offset: u64) -> Result<usize> {
+        self.collect(bufs)
+    }
+}
+
+/// A request submitted to the ring, to be completed once the kernel is done with it.
+struct InFlight {
+    head_index: u16,
+    request_type: u32,
+    // Where the virtio status goes, in guest memory.
+    status: *mut u8,
+    // Bytes the request is expected to transfer, checked against the result.
+    expected: usize,
+    used_len: u32,
+    // Referenced by the submission until it completes.
+    _iovecs: Vec<libc::iovec>,
+}
+
+/// A request the kernel is done with.
+pub(crate) struct Completion {
+    pub head_index: u16,
+    pub request_type: u32,
+    pub used_len: u32,
+    // Bytes transferred, if the request succeeded.
+    pub bytes: usize,
+    pub ok: bool,
+}
+
+/// A request the guest can read or write through io_uring.
+pub(crate) enum UringRequest {
+    Read(u64),
+    Write(u64),
+    Flush,
+}
+
+pub(crate) struct UringDisk {
+    ring: IoUring,
+    file: File,
+    eventfd: EventFd,
+    // Host address and length of the guest memory regions registered as fixed buffers.
+    fixed: Vec<(usize, usize)>,
+    inflight: Vec<Option<InFlight>>,
+    free: Vec<usize>,
+    dsync: bool,
+}
+
+// Safe because the raw pointers only refer to guest memory, which outlives the worker that owns
+// this.
+unsafe impl Send for UringDisk {}
+
+impl UringDisk {
+    /// Sets up a ring for up to `entries` requests on `file`. Writes are issued with `RWF_DSYNC`
+    /// if `dsync` is set, so that they reach the disk before they complete.
+    pub fn new(file: File, entries: u32, mem: &GuestMemoryMmap, dsync: bool) -> Result<Self> {
+        let ring = IoUring::new(entries)?;
+        let eventfd = EventFd::new(EFD_NONBLOCK)?;
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        let regions: Vec<_> = mem
+            .iter()
+            .map(|r| (r.as_ptr() as usize, r.len() as usize))
+            .collect();
+        let fixed = if regions.iter().all(|&(_, len)| len <= MAX_FIXED_BUFFER) {
+            let iovecs: Vec<_> = regions
+                .iter()
+                .map(|&(addr, len)| libc::iovec {
+                    iov_base: addr as *mut libc::c_void,
+                    iov_len: len,
+                })
+                .collect();
+            // Safe because guest memory stays mapped for as long as the ring exists.
+            match unsafe { ring.submitter().register_buffers(&iovecs) } {
+                Ok(()) => regions,
+                Err(e) => {
+                    debug!("not using registered buffers for disk I/O: {e}");
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
+        Ok(UringDisk {
+            ring,
+            file,
+            eventfd,
+            fixed,
+            inflight: (0..entries).map(|_| None).collect(),
+            free: (0..entries as usize).rev().collect(),
+            dsync,
+        })
+    }
+
+    /// The eventfd that is signalled when requests complete.
+    pub fn event_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.free.len() == self.inflight.len()
+    }
+
+    /// Queues `request` for the descriptor chain at `head_index`, transferring `iovecs` and
+    /// writing the virtio status to `status` on completion. Nothing reaches the kernel until the
+    /// next `submit`.
+    pub fn push(
+        &mut self,
+        head_index: u16,
+        request: UringRequest,
+        iovecs: Vec<libc::iovec>,
+        status: *mut u8,
+        used_len: u32,
+    ) -> Result<()> {
+        let slot = self
+            .free
+            .pop()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBUSY))?;
+        let fd = types::Fd(self.file.as_raw_fd());
+        let expected = iovecs.iter().map(|iov| iov.iov_len).sum();
+        let rw_flags = if self.dsync { libc::RWF_DSYNC } else { 0 };
+        let request_type = match request {
+            UringRequest::Read(_) => VIRTIO_BLK_T_IN,
+            UringRequest::Write(_) => VIRTIO_BLK_T_OUT,
+            UringRequest::Flush => VIRTIO_BLK_T_FLUSH,
+        };
+
+        let entry = match (request, self.fixed_index(&iovecs)) {
+            (UringRequest::Read(offset), Some(index)) => opcode::ReadFixed::new(
+                fd,
+                iovecs[0].iov_base as *mut u8,
+                iovecs[0].iov_len as u32,
+                index,
+            )
+            .offset(offset)
+            .build(),
+            (UringRequest::Read(offset), None) => {
+                opcode::Readv::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(offset)
+                    .build()
+            }
+            (UringRequest::Write(offset), Some(index)) => opcode::WriteFixed::new(
+                fd,
+                iovecs[0].iov_base as *const u8,
+                iovecs[0].iov_len as u32,
+                index,
+            )
+            .offset(offset)
+            .rw_flags(rw_flags)
+            .build(),
+            (UringRequest::Write(offset), None) => {
+                opcode::Writev::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(offset)
+                    .rw_flags(rw_flags)
+                    .build()
+            }
+            (UringRequest::Flush, _) => opcode::Fsync::new(fd)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build(),
+        }
+        .user_data(slot as u64);
+
+        // Safe because the buffers are guest memory and the iovecs are kept in `inflight` until
+        // the request completes.
+        if unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.free.push(slot);
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        self.inflight[slot] = Some(InFlight {
+            head_index,
+            request_type,
+            status,
+            expected,
+            used_len,
+            _iovecs: iovecs,
+        });
+        Ok(())
+    }
+
+    /// Hands the queued requests to the kernel.
+    pub fn submit(&mut self) -> Result<()> {
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Completes the requests the kernel is done with, writing their status and calling
+    /// `complete` for each of them, in completion order.
+    pub fn reap<F>(&mut self, mut complete: F)
+    where
+        F: FnMut(Completion),
+    {
+        let _ = self.eventfd.read();
+        let completed: Vec<_> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect();
+        for (slot, result) in completed {
+            let Some(req) = self.inflight.get_mut(slot).and_then(Option::take) else {
+                error!("unexpected io_uring completion for slot {slot}");
+                continue;
+            };
+            self.free.push(slot);
+
+            // Partial transfers are only possible if the image shrank under us.
+            let status = if result < 0 {
+                error!(
+                    "error processing request: {}",
+                    io::Error::from_raw_os_error(-result)
+                );
+                VIRTIO_BLK_S_IOERR
+            } else if result as usize != req.expected {
+                error!("short disk transfer: {result} of {} bytes", req.expected);
+                VIRTIO_BLK_S_IOERR
+            } else {
+                VIRTIO_BLK_S_OK
+            };
+            // Safe because the status byte is in guest memory, which the guest gave us for this
+            // request and which stays mapped.
+            unsafe { std::ptr::write_volatile(req.status, status as u8) };
+            let ok = status == VIRTIO_BLK_S_OK;
+            complete(Completion {
+                head_index: req.head_index,
+                request_type: req.request_type,
+                used_len: if ok { req.used_len } else { 0 },
+                bytes: if ok { req.expected } else { 0 },
+                ok,
+            });
+        }
+    }
+
+    /// Blocks until every request in flight has completed.
+    pub fn drain<F>(&mut self, mut complete: F)
+    where
+        F: FnMut(Completion),
+    {
+        while !self.is_idle() {
+            if let Err(e) = self.ring.submit_and_wait(1) {
+                if e.raw_os_error() != Some(libc::EINTR) {
+                    error!("failed to wait for disk requests: {e}");
+                    return;
+                }
+            }
+            self.reap(&mut complete);
+        }
+    }
+
+    // Returns the registered buffer holding all of a single-buffer request, if any.
+    fn fixed_index(&self, iovecs: &[libc::iovec]) -> Option<u16> {
+        let [iov] = iovecs else {
+            return None;
+        };
+        let start = iov.iov_base as usize;
+        self.fixed
+            .iter()
+            .position(|&(addr, len)| start >= addr && start + iov.iov_len <= addr + len)
+            .map(|i| i as u16)
+    }
+}
@@ -2,11 +2,19 @@ use crate::virtio::descriptor_utils::{Reader, Writer};
 
 use super::super::DeviceQueue;
 use super::device::{CacheType, DiskProperties};
+use super::metrics::BlockMetrics;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use super::uring::{Completion, IovecCollector, UringDisk, UringRequest};
 
+use crate::virtio::queue::DescriptorChain;
+use crate::virtio::rate_limiter::Throttle;
 use crate::virtio::InterruptTransport;
+use crossbeam_channel::{Receiver, Sender};
 use std::io::{self, Write};
+use std::mem::size_of;
 use std::os::fd::AsRawFd;
 use std::result;
+use std::sync::Arc;
 use std::thread;
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
@@ -23,7 +31,23 @@ pub enum RequestError {
     ReadingFromDescriptor(io::Error),
     WritingToDescriptor(io::Error),
     WritingZeroes(io::Error),
+    Submitting(io::Error),
+    OutOfRange,
+    ReadOnly,
     UnknownRequest,
+    UnsupportedFlags(u32),
+}
+
+impl RequestError {
+    /// The virtio status reported to the driver for a request that failed with this error.
+    fn status(&self) -> u8 {
+        match self {
+            RequestError::UnknownRequest | RequestError::UnsupportedFlags(_) => {
+                VIRTIO_BLK_S_UNSUPP as u8
+            }
+            _ => VIRTIO_BLK_S_IOERR as u8,
+        }
+    }
 }
 
 /// The request header represents the mandatory fields of each block device request.
@@ -55,21 +79,48 @@ pub struct DiscardWriteData {
 // Safe because DiscardWriteData only contains plain data.
 unsafe impl ByteValued for DiscardWriteData {}
 
+// Returns true for the requests that change the contents of the disk.
+fn is_write(request_type: u32) -> bool {
+    matches!(
+        request_type,
+        VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+    )
+}
+
+/// A request to change the size of the disk, answered once it's done.
+pub(crate) struct ResizeRequest {
+    pub new_size: u64,
+    pub reply: Sender<io::Result<()>>,
+}
+
 pub struct BlockWorker {
     device_queue: DeviceQueue,
     interrupt: InterruptTransport,
     mem: GuestMemoryMmap,
     disk: DiskProperties,
     stop_fd: EventFd,
+    resize_evt: EventFd,
+    resize_rx: Receiver<ResizeRequest>,
+    metrics: Arc<BlockMetrics>,
+    throttle: Throttle,
+    // Whether a request was put back on the queue until the throttle's timer fires.
+    throttled: bool,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<UringDisk>,
 }
 
 impl BlockWorker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device_queue: DeviceQueue,
         interrupt: InterruptTransport,
         mem: GuestMemoryMmap,
         disk: DiskProperties,
         stop_fd: EventFd,
+        resize_evt: EventFd,
+        resize_rx: Receiver<ResizeRequest>,
+        metrics: Arc<BlockMetrics>,
+        throttle: Throttle,
     ) -> Self {
         Self {
             device_queue,
@@ -77,9 +128,22 @@ impl BlockWorker {
             mem,
             disk,
             stop_fd,
+            resize_evt,
+            resize_rx,
+            metrics,
+            throttle,
+            throttled: false,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring: None,
         }
     }
 
+    /// Submits reads, writes and flushes to `uring` instead of doing them on the worker thread.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn set_uring(&mut self, uring: UringDisk) {
+        self.uring = Some(uring);
+    }
+
     pub fn run(self) -> thread::JoinHandle<()> {
         thread::Builder::new()
             .name("block worker".into())
@@ -90,6 +154,8 @@ impl BlockWorker {
     fn work(mut self) {
         let virtq_ev_fd = self.device_queue.event.as_raw_fd();
         let stop_ev_fd = self.stop_fd.as_raw_fd();
+        let resize_ev_fd = self.resize_evt.as_raw_fd();
+        let throttle_fd = self.throttle.as_raw_fd();
 
         let epoll = Epoll::new().unwrap();
 
@@ -105,6 +171,29 @@ impl BlockWorker {
             &EpollEvent::new(EventSet::IN, stop_ev_fd as u64),
         );
 
+        let _ = epoll.ctl(
+            ControlOperation::Add,
+            resize_ev_fd,
+            &EpollEvent::new(EventSet::IN, resize_ev_fd as u64),
+        );
+
+        let _ = epoll.ctl(
+            ControlOperation::Add,
+            throttle_fd,
+            &EpollEvent::new(EventSet::IN, throttle_fd as u64),
+        );
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let uring_ev_fd = self.uring.as_ref().map_or(-1, UringDisk::event_fd);
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if uring_ev_fd >= 0 {
+            let _ = epoll.ctl(
+                ControlOperation::Add,
+                uring_ev_fd,
+                &EpollEvent::new(EventSet::IN, uring_ev_fd as u64),
+            );
+        }
+
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
             match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
@@ -116,8 +205,24 @@ impl BlockWorker {
                             EventSet::IN if source == virtq_ev_fd => {
                                 self.process_queue_event();
                             }
+                            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                            EventSet::IN if source == uring_ev_fd => {
+                                self.process_uring_completions();
+                                // The queue may have been left alone while the ring was full.
+                                self.process_virtio_queues();
+                            }
+                            EventSet::IN if source == resize_ev_fd => {
+                                self.process_resize_event();
+                            }
+                            EventSet::IN if source == throttle_fd => {
+                                self.process_throttle_event();
+                            }
                             EventSet::IN if source == stop_ev_fd => {
                                 debug!("stopping worker thread");
+                                // The guest buffers of requests in flight must not be reused
+                                // before the kernel is done with them.
+                                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                                self.drain_uring();
                                 let _ = self.stop_fd.read();
                                 return;
                             }
@@ -144,6 +249,24 @@ impl BlockWorker {
         }
     }
 
+    // Resizes are only done here, between two batches of requests, so that no request sees the
+    // size of the disk change under it.
+    fn process_resize_event(&mut self) {
+        let _ = self.resize_evt.read();
+        for request in self.resize_rx.try_iter().collect::<Vec<_>>() {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            self.drain_uring();
+            let _ = request.reply.send(self.disk.resize(request.new_size));
+        }
+    }
+
+    // The rate limit left requests on the queue, and there are tokens for them now.
+    fn process_throttle_event(&mut self) {
+        self.throttle.clear_timer();
+        self.throttled = false;
+        self.process_virtio_queues();
+    }
+
     /// Process device virtio queue(s).
     fn process_virtio_queues(&mut self) {
         let mem = self.mem.clone();
@@ -152,6 +275,10 @@ impl BlockWorker {
 
             self.process_queue(&mem);
 
+            // Notifications stay off until the throttle's timer resumes the queue.
+            if self.throttled {
+                break;
+            }
             if !self.device_queue.queue.enable_notification(&mem).unwrap() {
                 break;
             }
@@ -159,7 +286,11 @@ impl BlockWorker {
     }
 
     fn process_queue(&mut self, mem: &GuestMemoryMmap) {
-        while let Some(head) = self.device_queue.queue.pop(mem) {
+        // With a writethrough cache, writes are only completed once the whole batch is synced.
+        let writethrough = self.disk.cache_type() == CacheType::Writethrough;
+        let mut unsynced = Vec::new();
+
+        while let Some(head) = self.next_request(mem) {
             let mut reader = match Reader::new(mem, head.clone()) {
                 Ok(r) => r,
                 Err(e) => {
@@ -181,32 +312,222 @@ impl BlockWorker {
                     continue;
                 }
             };
+            let request_type = request_header.request_type;
+            self.metrics.start();
+
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            if self.uses_uring(request_type) {
+                if let Err(e) =
+                    self.push_uring(head.index, request_header, &mut reader, &mut writer)
+                {
+                    error!("error processing request: {e:?}");
+                    self.complete_request(
+                        mem,
+                        head.index,
+                        &mut writer,
+                        request_type,
+                        e.status(),
+                        0,
+                    );
+                }
+                continue;
+            }
 
             let (status, len): (u8, usize) =
                 match self.process_request(request_header, &mut reader, &mut writer) {
+                    Ok(l) if writethrough && is_write(request_type) => {
+                        unsynced.push((head.index, writer, request_type, l));
+                        continue;
+                    }
                     Ok(l) => (VIRTIO_BLK_S_OK.try_into().unwrap(), l),
                     Err(e) => {
                         error!("error processing request: {e:?}");
-                        (VIRTIO_BLK_S_IOERR.try_into().unwrap(), 0)
+                        (e.status(), 0)
                     }
                 };
 
-            if let Err(e) = writer.write_obj(status) {
-                error!("Failed to write virtio block status: {e:?}")
+            self.complete_request(mem, head.index, &mut writer, request_type, status, len);
+        }
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(uring) = self.uring.as_mut() {
+            if let Err(e) = uring.submit() {
+                error!("failed to submit disk requests: {e:?}");
             }
+        }
 
-            if let Err(e) = self
-                .device_queue
-                .queue
-                .add_used(mem, head.index, len as u32)
-            {
-                error!("failed to add used elements to the queue: {e:?}");
+        if unsynced.is_empty() {
+            return;
+        }
+        let status = match self.disk.sync() {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                error!("error syncing written data: {e:?}");
+                VIRTIO_BLK_S_IOERR
+            }
+        };
+        for (index, mut writer, request_type, len) in unsynced {
+            self.complete_request(mem, index, &mut writer, request_type, status as u8, len);
+        }
+    }
+
+    // Takes the next request off the queue, unless there's no room to submit it or the rate
+    // limit holds it back.
+    fn next_request<'a>(&mut self, mem: &'a GuestMemoryMmap) -> Option<DescriptorChain<'a>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.uring.as_ref().is_some_and(UringDisk::is_full) {
+            return None;
+        }
+        let head = self.device_queue.queue.pop(mem)?;
+        // The data is whatever the chain holds past the header and before the status byte.
+        let chain_len: u64 = head
+            .clone()
+            .into_iter()
+            .map(|desc| u64::from(desc.len))
+            .sum();
+        let data_len = chain_len.saturating_sub(size_of::<RequestHeader>() as u64 + 1);
+        if !self.throttle.consume(1, data_len) {
+            self.device_queue.queue.undo_pop();
+            self.throttled = true;
+            return None;
+        }
+        Some(head)
+    }
+
+    fn complete_request(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        writer: &mut Writer,
+        request_type: u32,
+        status: u8,
+        len: usize,
+    ) {
+        if let Err(e) = writer.write_obj(status) {
+            error!("Failed to write virtio block status: {e:?}")
+        }
+        self.metrics
+            .complete(request_type, len, status == VIRTIO_BLK_S_OK as u8);
+
+        if let Err(e) = self.device_queue.queue.add_used(mem, index, len as u32) {
+            error!("failed to add used elements to the queue: {e:?}");
+        }
+
+        self.signal_used_queue();
+    }
+
+    // Returns true if requests of this type go through io_uring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn uses_uring(&self, request_type: u32) -> bool {
+        self.uring.is_some()
+            && match request_type {
+                VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => true,
+                VIRTIO_BLK_T_FLUSH => self.disk.cache_type() != CacheType::Unsafe,
+                _ => false,
             }
+    }
 
-            if self.device_queue.queue.needs_notification(mem).unwrap() {
-                if let Err(e) = self.interrupt.try_signal_used_queue() {
-                    error!("error signalling queue: {e:?}");
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn push_uring(
+        &mut self,
+        head_index: u16,
+        request_header: RequestHeader,
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> result::Result<(), RequestError> {
+        let offset = request_header.sector * 512;
+        let (request, iovecs, used_len) = match request_header.request_type {
+            VIRTIO_BLK_T_IN => {
+                let data_len = writer.available_bytes() - 1;
+                if !data_len.is_multiple_of(512) {
+                    return Err(RequestError::InvalidDataLength);
                 }
+                self.check_range(request_header.sector, data_len as u64 / 512)?;
+                let iovecs = IovecCollector::new(data_len);
+                writer
+                    .write_from_at(&iovecs, data_len, 0)
+                    .map_err(RequestError::WritingToDescriptor)?;
+                (UringRequest::Read(offset), iovecs.into_inner(), data_len)
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.disk.is_read_only() {
+                    return Err(RequestError::ReadOnly);
+                }
+                let data_len = reader.available_bytes();
+                if !data_len.is_multiple_of(512) {
+                    return Err(RequestError::InvalidDataLength);
+                }
+                self.check_range(request_header.sector, data_len as u64 / 512)?;
+                let iovecs = IovecCollector::new(data_len);
+                reader
+                    .read_to_at(&iovecs, data_len, 0)
+                    .map_err(RequestError::ReadingFromDescriptor)?;
+                (UringRequest::Write(offset), iovecs.into_inner(), 0)
+            }
+            _ => (UringRequest::Flush, Vec::new(), 0),
+        };
+
+        let status = IovecCollector::new(1);
+        writer
+            .write_from_at(&status, 1, 0)
+            .map_err(RequestError::WritingToDescriptor)?;
+        let Some(status) = status
+            .into_inner()
+            .first()
+            .map(|iov| iov.iov_base as *mut u8)
+        else {
+            return Err(RequestError::InvalidDataLength);
+        };
+
+        let uring = self.uring.as_mut().unwrap();
+        uring
+            .push(head_index, request, iovecs, status, used_len as u32)
+            .map_err(RequestError::Submitting)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn process_uring_completions(&mut self) {
+        let Some(uring) = self.uring.as_mut() else {
+            return;
+        };
+        let queue = &mut self.device_queue.queue;
+        let mem = &self.mem;
+        let metrics = &self.metrics;
+        uring.reap(|c: Completion| {
+            metrics.complete(c.request_type, c.bytes, c.ok);
+            if let Err(e) = queue.add_used(mem, c.head_index, c.used_len) {
+                error!("failed to add used elements to the queue: {e:?}");
+            }
+        });
+        self.signal_used_queue();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn drain_uring(&mut self) {
+        let Some(uring) = self.uring.as_mut() else {
+            return;
+        };
+        let queue = &mut self.device_queue.queue;
+        let mem = &self.mem;
+        let metrics = &self.metrics;
+        uring.drain(|c: Completion| {
+            metrics.complete(c.request_type, c.bytes, c.ok);
+            if let Err(e) = queue.add_used(mem, c.head_index, c.used_len) {
+                error!("failed to add used elements to the queue: {e:?}");
+            }
+        });
+        self.signal_used_queue();
+    }
+
+    fn signal_used_queue(&mut self) {
+        if self
+            .device_queue
+            .queue
+            .needs_notification(&self.mem)
+            .unwrap()
+        {
+            if let Err(e) = self.interrupt.try_signal_used_queue() {
+                error!("error signalling queue: {e:?}");
             }
         }
     }
@@ -223,26 +544,29 @@ impl BlockWorker {
                 if !data_len.is_multiple_of(512) {
                     Err(RequestError::InvalidDataLength)
                 } else {
+                    self.check_range(request_header.sector, data_len as u64 / 512)?;
                     writer
                         .write_from_at(&self.disk, data_len, request_header.sector * 512)
                         .map_err(RequestError::WritingToDescriptor)
                 }
             }
             VIRTIO_BLK_T_OUT => {
+                if self.disk.is_read_only() {
+                    return Err(RequestError::ReadOnly);
+                }
                 let data_len = reader.available_bytes();
                 if !data_len.is_multiple_of(512) {
                     Err(RequestError::InvalidDataLength)
                 } else {
+                    self.check_range(request_header.sector, data_len as u64 / 512)?;
                     reader
                         .read_to_at(&self.disk, data_len, request_header.sector * 512)
                         .map_err(RequestError::ReadingFromDescriptor)
                 }
             }
             VIRTIO_BLK_T_FLUSH => match self.disk.cache_type() {
-                CacheType::Writeback => {
-                    let diskfile = self.disk.file.lock().unwrap();
-                    diskfile.flush().map_err(RequestError::FlushingToDisk)?;
-                    diskfile.sync().map_err(RequestError::FlushingToDisk)?;
+                CacheType::Writeback | CacheType::Writethrough => {
+                    self.disk.sync().map_err(RequestError::FlushingToDisk)?;
                     Ok(0)
                 }
                 CacheType::Unsafe => Ok(0),
@@ -259,50 +583,709 @@ impl BlockWorker {
                     Ok(disk_id.len())
                 }
             }
-            VIRTIO_BLK_T_DISCARD => {
-                let discard_write_data: DiscardWriteData = reader
-                    .read_obj()
-                    .map_err(RequestError::ReadingFromDescriptor)?;
-                self.disk
-                    .file
-                    .lock()
-                    .unwrap()
-                    .discard_to_any(
-                        discard_write_data.sector * 512,
-                        discard_write_data.num_sectors as u64 * 512,
-                    )
-                    .map_err(RequestError::Discarding)?;
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                if self.disk.is_read_only() {
+                    return Err(RequestError::ReadOnly);
+                }
+                // The driver may send as many segments as advertised in the config space.
+                while reader.available_bytes() >= size_of::<DiscardWriteData>() {
+                    let segment: DiscardWriteData = reader
+                        .read_obj()
+                        .map_err(RequestError::ReadingFromDescriptor)?;
+                    self.discard_or_zero(request_header.request_type, segment)?;
+                }
                 Ok(0)
             }
-            VIRTIO_BLK_T_WRITE_ZEROES => {
-                let discard_write_data: DiscardWriteData = reader
-                    .read_obj()
-                    .map_err(RequestError::ReadingFromDescriptor)?;
-                let unmap = (discard_write_data.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP) != 0;
-                if unmap {
-                    self.disk
-                        .file
-                        .lock()
-                        .unwrap()
-                        .discard_to_zero(
-                            discard_write_data.sector * 512,
-                            discard_write_data.num_sectors as u64 * 512,
-                        )
-                        .map_err(RequestError::DiscardingToZero)?;
+            _ => Err(RequestError::UnknownRequest),
+        }
+    }
+
+    // Checks that `num_sectors` starting at `sector` fit in the disk, returning the byte offset
+    // and length of the range.
+    fn check_range(
+        &self,
+        sector: u64,
+        num_sectors: u64,
+    ) -> result::Result<(u64, u64), RequestError> {
+        match sector.checked_add(num_sectors) {
+            Some(end) if end <= self.disk.nsectors() => Ok((sector * 512, num_sectors * 512)),
+            _ => Err(RequestError::OutOfRange),
+        }
+    }
+
+    fn discard_or_zero(
+        &mut self,
+        request_type: u32,
+        segment: DiscardWriteData,
+    ) -> result::Result<(), RequestError> {
+        // Discard requests take no flags, and UNMAP is the only one defined for write zeroes.
+        let known_flags = match request_type {
+            VIRTIO_BLK_T_WRITE_ZEROES => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            _ => 0,
+        };
+        if segment.flags & !known_flags != 0 {
+            return Err(RequestError::UnsupportedFlags(segment.flags));
+        }
+        let (offset, len) = self.check_range(segment.sector, segment.num_sectors as u64)?;
+
+        let mut file = self.disk.file.lock().unwrap();
+        if request_type == VIRTIO_BLK_T_DISCARD {
+            file.discard_to_any(offset, len)
+                .map_err(RequestError::Discarding)
+        } else if segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+            file.discard_to_zero(offset, len)
+                .map_err(RequestError::DiscardingToZero)
+        } else {
+            file.write_zeroes(offset, len)
+                .map_err(RequestError::WritingZeroes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use imago::{
+        file::File as ImagoFile, raw::Raw, DynStorage, Storage, StorageOpenOptions,
+        SyncFormatAccess,
+    };
+    use utils::eventfd::EFD_NONBLOCK;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::block::BlockMetricsSnapshot;
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::queue::Queue;
+    use crate::virtio::rate_limiter::{RateLimit, RateLimiter};
+
+    const REQUEST_ADDR: u64 = 0x1000;
+    const DISK_SIZE: u64 = 4 << 20;
+
+    fn open_disk(
+        path: &Path,
+        read_only: bool,
+        cache_type: CacheType,
+        direct_io: bool,
+    ) -> DiskProperties {
+        let opts = StorageOpenOptions::new()
+            .write(!read_only)
+            .filename(path.to_str().unwrap())
+            .direct(direct_io);
+        let file = ImagoFile::open_sync(opts).unwrap();
+        let raw = Raw::<Box<dyn DynStorage>>::open_image_sync(Box::new(file), !read_only).unwrap();
+        let image = Arc::new(Mutex::new(SyncFormatAccess::new(raw).unwrap()));
+        DiskProperties::new(image, Vec::new(), cache_type, read_only).unwrap()
+    }
+
+    fn new_worker(disk: DiskProperties, queue: Queue, mem: &GuestMemoryMmap) -> BlockWorker {
+        let queue = DeviceQueue::new(queue, Arc::new(EventFd::new(EFD_NONBLOCK).unwrap()));
+        let interrupt =
+            InterruptTransport::new(DummyIrqChip::new().into(), "block".into()).unwrap();
+        let stop_fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let resize_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (_, resize_rx) = crossbeam_channel::unbounded();
+        BlockWorker::new(
+            queue,
+            interrupt,
+            mem.clone(),
+            disk,
+            stop_fd,
+            resize_evt,
+            resize_rx,
+            Arc::new(BlockMetrics::new()),
+            Throttle::new(Arc::new(RateLimiter::default())).unwrap(),
+        )
+    }
+
+    fn open_worker(path: &Path, read_only: bool) -> (BlockWorker, GuestMemoryMmap) {
+        let disk = open_disk(path, read_only, CacheType::Writeback, false);
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        (new_worker(disk, Queue::new(16), &mem), mem)
+    }
+
+    // Sends a request carrying `payload` after its header, returning the virtio status the driver
+    // would see.
+    fn request(
+        worker: &mut BlockWorker,
+        mem: &GuestMemoryMmap,
+        header: RequestHeader,
+        payload: &[u8],
+    ) -> u8 {
+        mem.write_obj(header, GuestAddress(REQUEST_ADDR)).unwrap();
+        mem.write_slice(
+            payload,
+            GuestAddress(REQUEST_ADDR + size_of::<RequestHeader>() as u64),
+        )
+        .unwrap();
+        let chain = create_descriptor_chain(
+            mem,
+            GuestAddress(0),
+            GuestAddress(REQUEST_ADDR),
+            vec![
+                (
+                    DescriptorType::Readable,
+                    (size_of::<RequestHeader>() + payload.len()) as u32,
+                ),
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .unwrap();
+        let mut reader = Reader::new(mem, chain.clone()).unwrap();
+        let mut writer = Writer::new(mem, chain).unwrap();
+        let header: RequestHeader = reader.read_obj().unwrap();
+        match worker.process_request(header, &mut reader, &mut writer) {
+            Ok(_) => VIRTIO_BLK_S_OK as u8,
+            Err(e) => e.status(),
+        }
+    }
+
+    fn header(request_type: u32, sector: u64) -> RequestHeader {
+        RequestHeader {
+            request_type,
+            sector,
+            ..Default::default()
+        }
+    }
+
+    fn segment(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteData {
+        DiscardWriteData {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+
+    #[test]
+    fn discard_frees_blocks() {
+        let dir = std::env::temp_dir().join(format!("krun-blk-discard-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("disk.raw");
+        let image = fs::File::create(&path).unwrap();
+        image.set_len(DISK_SIZE).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&image, &vec![0xaa; 1 << 20], 0).unwrap();
+        image.sync_all().unwrap();
+        let blocks = || std::os::unix::fs::MetadataExt::blocks(&fs::metadata(&path).unwrap());
+        let written = blocks();
+        assert!(written >= (1 << 20) / 512);
+
+        let (mut worker, mem) = open_worker(&path, false);
+        let nsectors = DISK_SIZE / 512;
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_DISCARD, 0),
+            segment(0, 2048, 0).as_slice(),
+        );
+        assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+        assert!(
+            blocks() < written,
+            "discard left {} of {written} blocks",
+            blocks()
+        );
+
+        // Ranges past the end of the disk and unknown flags are refused.
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_WRITE_ZEROES, 0),
+            segment(nsectors - 1, 2, 0).as_slice(),
+        );
+        assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_DISCARD, 0),
+            segment(u64::MAX, 2, 0).as_slice(),
+        );
+        assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_DISCARD, 0),
+            segment(0, 8, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP).as_slice(),
+        );
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP as u8);
+        let status = request(&mut worker, &mem, header(0xff, 0), &[]);
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP as u8);
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_OUT, nsectors),
+            &[0; 512],
+        );
+        assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_WRITE_ZEROES, 0),
+            segment(nsectors - 8, 8, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP).as_slice(),
+        );
+        assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+
+        // Nothing that changes the disk goes through on a read-only one.
+        drop(worker);
+        let (mut worker, mem) = open_worker(&path, true);
+        let status = request(
+            &mut worker,
+            &mem,
+            header(VIRTIO_BLK_T_DISCARD, 0),
+            segment(0, 8, 0).as_slice(),
+        );
+        assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+        let status = request(&mut worker, &mem, header(VIRTIO_BLK_T_OUT, 0), &[0; 512]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+
+        drop(worker);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_modes_keep_completed_writes() {
+        const REQUESTS: u64 = 4;
+        const DATA_LEN: u32 = 4096;
+        const SLOT_SIZE: u64 = 0x2000;
+        const STATUS_OFFSET: u64 = 0x1800;
+        const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+        const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+        let dir = std::env::temp_dir().join(format!("krun-blk-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        for (cache_type, direct_io) in [
+            (CacheType::Writeback, false),
+            (CacheType::Writethrough, false),
+            (CacheType::Writethrough, true),
+        ] {
+            let path = dir.join(format!("{cache_type:?}-{direct_io}.raw"));
+            fs::File::create(&path).unwrap().set_len(DISK_SIZE).unwrap();
+
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            let disk = open_disk(&path, false, cache_type, direct_io);
+            let mut worker = new_worker(disk, vq.create_queue(), &mem);
+
+            // The data right after the header is misaligned for direct I/O on purpose.
+            for i in 0..REQUESTS {
+                let base = REQUEST_ADDR + i * SLOT_SIZE;
+                let desc = i as u16 * 3;
+                mem.write_obj(header(VIRTIO_BLK_T_OUT, i * 8), GuestAddress(base))
+                    .unwrap();
+                let data = base + size_of::<RequestHeader>() as u64;
+                mem.write_slice(&[i as u8 + 1; DATA_LEN as usize], GuestAddress(data))
+                    .unwrap();
+                vq.dtable[desc as usize].set(
+                    base,
+                    size_of::<RequestHeader>() as u32,
+                    VIRTQ_DESC_F_NEXT,
+                    desc + 1,
+                );
+                vq.dtable[desc as usize + 1].set(data, DATA_LEN, VIRTQ_DESC_F_NEXT, desc + 2);
+                vq.dtable[desc as usize + 2].set(base + STATUS_OFFSET, 1, VIRTQ_DESC_F_WRITE, 0);
+                vq.avail.ring[i as usize].set(desc);
+            }
+            vq.avail.idx.set(REQUESTS as u16);
+            worker.process_queue(&mem);
+            assert_eq!(vq.used.idx.get(), REQUESTS as u16);
+
+            // Crash before the guest gets to flush: the worker never syncs on drop either.
+            std::mem::forget(worker);
+            let contents = fs::read(&path).unwrap();
+            for i in 0..REQUESTS {
+                let status: u8 = mem
+                    .read_obj(GuestAddress(REQUEST_ADDR + i * SLOT_SIZE + STATUS_OFFSET))
+                    .unwrap();
+                assert_eq!(status, VIRTIO_BLK_S_OK as u8, "{cache_type:?}");
+                let start = (i * 8 * 512) as usize;
+                assert!(
+                    contents[start..start + DATA_LEN as usize]
+                        .iter()
+                        .all(|&b| b == i as u8 + 1),
+                    "{cache_type:?} direct_io={direct_io}: request {i} lost"
+                );
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stats_count_completed_io() {
+        const DATA_LEN: u32 = 4096;
+        const STATUS_ADDR: u64 = 0x3000;
+        const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+        const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+        let dir = std::env::temp_dir().join(format!("krun-blk-stats-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("disk.raw");
+        fs::File::create(&path).unwrap().set_len(DISK_SIZE).unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let disk = open_disk(&path, false, CacheType::Writeback, false);
+        let mut worker = new_worker(disk, vq.create_queue(), &mem);
+        let metrics = Arc::clone(&worker.metrics);
+
+        // Runs one request moving `DATA_LEN` bytes, or none for a flush.
+        let mut run = |request_type: u32, sector: u64| {
+            mem.write_obj(header(request_type, sector), GuestAddress(REQUEST_ADDR))
+                .unwrap();
+            let data = REQUEST_ADDR + size_of::<RequestHeader>() as u64;
+            let mut desc = 1;
+            vq.dtable[0].set(
+                REQUEST_ADDR,
+                size_of::<RequestHeader>() as u32,
+                VIRTQ_DESC_F_NEXT,
+                1,
+            );
+            if request_type != VIRTIO_BLK_T_FLUSH {
+                let flags = if request_type == VIRTIO_BLK_T_IN {
+                    VIRTQ_DESC_F_WRITE
+                } else {
+                    0
+                };
+                vq.dtable[1].set(data, DATA_LEN, flags | VIRTQ_DESC_F_NEXT, 2);
+                desc = 2;
+            }
+            vq.dtable[desc].set(STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0);
+            let avail = vq.avail.idx.get();
+            vq.avail.ring[(avail % 16) as usize].set(0);
+            vq.avail.idx.set(avail + 1);
+            worker.process_queue(&mem);
+            assert_eq!(vq.used.idx.get(), avail + 1);
+        };
+
+        for i in 0..3 {
+            run(VIRTIO_BLK_T_OUT, i * 8);
+        }
+        run(VIRTIO_BLK_T_FLUSH, 0);
+        let first = metrics.interval();
+        assert_eq!(
+            first,
+            BlockMetricsSnapshot {
+                writes: 3,
+                bytes_written: 3 * DATA_LEN as u64,
+                flushes: 1,
+                max_queue_depth: 1,
+                ..Default::default()
+            }
+        );
+
+        for i in 0..2 {
+            run(VIRTIO_BLK_T_IN, i * 8);
+        }
+        // Past the end of the disk.
+        run(VIRTIO_BLK_T_IN, DISK_SIZE / 512);
+        let second = metrics.interval();
+        assert_eq!(
+            second,
+            BlockMetricsSnapshot {
+                reads: 2,
+                bytes_read: 2 * DATA_LEN as u64,
+                errors: 1,
+                max_queue_depth: 1,
+                ..Default::default()
+            }
+        );
+
+        let total = metrics.snapshot();
+        assert_eq!(total.reads, 2);
+        assert_eq!(total.writes, 3);
+        assert_eq!(total.bytes_written, 3 * DATA_LEN as u64);
+        assert_eq!(total.errors, 1);
+        assert_eq!(metrics.interval(), BlockMetricsSnapshot::default());
+
+        drop(worker);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rate_limit_caps_throughput() {
+        const DATA_LEN: u32 = 4096;
+        const REQUESTS: u16 = 17;
+        const BYTES_PER_SEC: u64 = 256 << 10;
+        const STATUS_ADDR: u64 = 0x3000;
+        const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+        const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+        let dir = std::env::temp_dir().join(format!("krun-blk-limit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("disk.raw");
+        fs::File::create(&path).unwrap().set_len(DISK_SIZE).unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let disk = open_disk(&path, false, CacheType::Writeback, false);
+        let mut worker = new_worker(disk, vq.create_queue(), &mem);
+        let limiter = Arc::new(RateLimiter::new(RateLimit {
+            bytes_per_sec: BYTES_PER_SEC,
+            bytes_burst: DATA_LEN.into(),
+            ..Default::default()
+        }));
+        worker.throttle = Throttle::new(limiter).unwrap();
+
+        // Every request but the first waits for the bucket to refill with its data.
+        let start = std::time::Instant::now();
+        for i in 0..REQUESTS {
+            mem.write_obj(
+                header(VIRTIO_BLK_T_OUT, i as u64 * 8),
+                GuestAddress(REQUEST_ADDR),
+            )
+            .unwrap();
+            let data = REQUEST_ADDR + size_of::<RequestHeader>() as u64;
+            vq.dtable[0].set(
+                REQUEST_ADDR,
+                size_of::<RequestHeader>() as u32,
+                VIRTQ_DESC_F_NEXT,
+                1,
+            );
+            vq.dtable[1].set(data, DATA_LEN, VIRTQ_DESC_F_NEXT, 2);
+            vq.dtable[2].set(STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[(i % 16) as usize].set(0);
+            vq.avail.idx.set(i + 1);
+
+            worker.process_virtio_queues();
+            while worker.throttled {
+                worker.process_throttle_event();
+            }
+            assert_eq!(vq.used.idx.get(), i + 1);
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let expected = f64::from(REQUESTS - 1) * f64::from(DATA_LEN) / BYTES_PER_SEC as f64;
+        assert!(
+            elapsed >= expected * 0.95 && elapsed < expected * 2.0,
+            "took {elapsed}s, expected {expected}s"
+        );
+        assert_eq!(worker.metrics.snapshot().writes, u64::from(REQUESTS));
+
+        drop(worker);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Random reads and writes in flight together through io_uring, checked against a copy of
+    // what the disk should hold.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    mod uring {
+        use super::*;
+
+        use std::time::{Duration, Instant};
+
+        use rand::{rng, Rng};
+
+        use crate::virtio::block::uring::UringDisk;
+
+        const RING_SIZE: u16 = 64;
+        const BATCH: u64 = 16;
+        // Each request owns a slot of the disk, so the ones in flight together never overlap.
+        const DISK_SLOT: u64 = 16 * 512;
+        const BUFFERS: u64 = 0x10000;
+        const SLOT_SIZE: u64 = 0x4000;
+        const DATA_OFFSET: u64 = 0x100;
+        const STATUS_OFFSET: u64 = 0x3000;
+        const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+        const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+        struct Disk<'a> {
+            vq: VirtQueue<'a>,
+            mem: &'a GuestMemoryMmap,
+            worker: BlockWorker,
+            shadow: Vec<u8>,
+            // Guest address and expected contents of the reads in flight.
+            reads: Vec<(u64, Vec<u8>)>,
+        }
+
+        impl<'a> Disk<'a> {
+            fn new(mem: &'a GuestMemoryMmap, path: &Path, uring: bool) -> Self {
+                fs::File::create(path).unwrap().set_len(DISK_SIZE).unwrap();
+                let vq = VirtQueue::new(GuestAddress(0), mem, RING_SIZE);
+                let disk = open_disk(path, false, CacheType::Writeback, false);
+                let mut worker = new_worker(disk, vq.create_queue(), mem);
+                if uring {
+                    let file = fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(path)
+                        .unwrap();
+                    worker.set_uring(UringDisk::new(file, RING_SIZE as u32, mem, false).unwrap());
+                }
+                Disk {
+                    vq,
+                    mem,
+                    worker,
+                    shadow: vec![0; DISK_SIZE as usize],
+                    reads: Vec::new(),
+                }
+            }
+
+            // Queues a random read or write of up to `DISK_SLOT` bytes into request slot `slot`,
+            // with its data split over two descriptors at times.
+            fn push(&mut self, slot: u64, sector: u64) {
+                let mut rng = rng();
+                let len = rng.random_range(1..=DISK_SLOT / 512) * 512;
+                let write = rng.random::<bool>();
+                let base = BUFFERS + slot * SLOT_SIZE;
+                let data = base + DATA_OFFSET;
+                let request_type = if write {
+                    VIRTIO_BLK_T_OUT
                 } else {
-                    self.disk
-                        .file
-                        .lock()
-                        .unwrap()
-                        .write_zeroes(
-                            discard_write_data.sector * 512,
-                            discard_write_data.num_sectors as u64 * 512,
-                        )
-                        .map_err(RequestError::WritingZeroes)?;
+                    VIRTIO_BLK_T_IN
+                };
+                self.mem
+                    .write_obj(header(request_type, sector), GuestAddress(base))
+                    .unwrap();
+
+                let range = (sector * 512) as usize..(sector * 512 + len) as usize;
+                if write {
+                    let mut buf = vec![0u8; len as usize];
+                    rng.fill(&mut buf[..]);
+                    self.mem.write_slice(&buf, GuestAddress(data)).unwrap();
+                    self.shadow[range].copy_from_slice(&buf);
+                } else {
+                    self.reads.push((data, self.shadow[range].to_vec()));
                 }
-                Ok(0)
+
+                let flags = if write { 0 } else { VIRTQ_DESC_F_WRITE };
+                let split = if rng.random::<bool>() { len / 2 } else { len };
+                let desc = slot as u16 * 4;
+                let table = &self.vq.dtable[desc as usize..];
+                table[0].set(
+                    base,
+                    size_of::<RequestHeader>() as u32,
+                    VIRTQ_DESC_F_NEXT,
+                    desc + 1,
+                );
+                table[1].set(data, split as u32, flags | VIRTQ_DESC_F_NEXT, desc + 2);
+                let mut status_desc = 2;
+                if split < len {
+                    table[2].set(
+                        data + split,
+                        (len - split) as u32,
+                        flags | VIRTQ_DESC_F_NEXT,
+                        desc + 3,
+                    );
+                    status_desc = 3;
+                }
+                table[status_desc].set(base + STATUS_OFFSET, 1, VIRTQ_DESC_F_WRITE, 0);
+                self.mem
+                    .write_obj(0xffu8, GuestAddress(base + STATUS_OFFSET))
+                    .unwrap();
+
+                let avail = self.vq.avail.idx.get();
+                self.vq.avail.ring[(avail % RING_SIZE) as usize].set(desc);
+                self.vq.avail.idx.set(avail.wrapping_add(1));
             }
-            _ => Err(RequestError::UnknownRequest),
+
+            // Runs a batch of requests and checks what the reads returned.
+            fn run(&mut self, requests: u64) {
+                let target = self.vq.avail.idx.get();
+                self.worker.process_queue(self.mem);
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while self.vq.used.idx.get() != target {
+                    assert!(Instant::now() < deadline, "requests not completed in time");
+                    self.worker.process_uring_completions();
+                }
+
+                for slot in 0..requests {
+                    let status: u8 = self
+                        .mem
+                        .read_obj(GuestAddress(BUFFERS + slot * SLOT_SIZE + STATUS_OFFSET))
+                        .unwrap();
+                    assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+                }
+                for (addr, expected) in std::mem::take(&mut self.reads) {
+                    let mut buf = vec![0u8; expected.len()];
+                    self.mem.read_slice(&mut buf, GuestAddress(addr)).unwrap();
+                    assert!(buf == expected, "read at {addr:#x} returned stale data");
+                }
+            }
+
+            // Queues and runs `BATCH` requests on distinct random slots of the disk.
+            fn random_batch(&mut self) {
+                let mut rng = rng();
+                let mut sectors = Vec::new();
+                while sectors.len() < BATCH as usize {
+                    let sector = rng.random_range(0..DISK_SIZE / DISK_SLOT) * DISK_SLOT / 512;
+                    if !sectors.contains(&sector) {
+                        sectors.push(sector);
+                    }
+                }
+                for (slot, sector) in sectors.into_iter().enumerate() {
+                    self.push(slot as u64, sector);
+                }
+                self.run(BATCH);
+            }
+        }
+
+        fn test_dir(name: &str) -> std::path::PathBuf {
+            let dir = std::env::temp_dir().join(format!("krun-blk-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            dir
+        }
+
+        #[test]
+        fn random_requests_match_shadow() {
+            let dir = test_dir("uring");
+            let path = dir.join("disk.raw");
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x60000)]).unwrap();
+            let mut disk = Disk::new(&mem, &path, true);
+
+            for _ in 0..50 {
+                disk.random_batch();
+            }
+            assert!(disk.worker.uring.as_ref().unwrap().is_idle());
+            // Every batch was in flight at once.
+            let stats = disk.worker.metrics.snapshot();
+            assert_eq!(stats.reads + stats.writes, 50 * BATCH);
+            assert_eq!(stats.errors, 0);
+            assert_eq!(stats.max_queue_depth, BATCH);
+            assert!(
+                fs::read(&path).unwrap() == disk.shadow,
+                "disk contents differ"
+            );
+
+            drop(disk);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // Compares requests per second with and without io_uring. Run with
+        // `cargo test -p msb_krun_devices --features io-uring --release -- --ignored --nocapture
+        // bench_uring`.
+        #[test]
+        #[ignore]
+        fn bench_uring_io() {
+            const BATCHES: usize = 5000;
+
+            let dir = test_dir("bench");
+            for uring in [false, true] {
+                let path = dir.join(format!("disk-{uring}.raw"));
+                let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x60000)]).unwrap();
+                let mut disk = Disk::new(&mem, &path, uring);
+
+                let start = Instant::now();
+                for _ in 0..BATCHES {
+                    disk.random_batch();
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "io_uring {uring}: {:.0} requests/s",
+                    (BATCHES * BATCH as usize) as f64 / elapsed.as_secs_f64()
+                );
+            }
+            fs::remove_dir_all(&dir).unwrap();
         }
     }
 }
@@ -122,6 +122,14 @@ impl Console {
             .console_resize(port_id, VirtioConsoleResize { rows, cols });
     }
 
+    /// Bytes the guest wrote to the port after its output on the host failed, which were thrown
+    /// away.
+    pub fn output_dropped(&self, port_id: u32) -> u64 {
+        self.ports
+            .get(port_id as usize)
+            .map_or(0, |port| port.output_dropped())
+    }
+
     pub(crate) fn process_control_rx(&mut self) -> bool {
         log::trace!("process_control_rx");
         let DeviceState::Activated(ref mem, _) = self.device_state else {
@@ -367,3 +375,377 @@ impl VmmExitObserver for Console {
         log::trace!("Console on_vmm_exit finished");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use vm_memory::GuestAddress;
+
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::console::console_control::Payload;
+    use crate::virtio::console::port_io;
+    use crate::virtio::queue::tests::VirtQueue;
+
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+    // Each queue gets its own page for its rings, and buffers go past all of them.
+    const RING_STRIDE: u64 = 0x1000;
+    const BUFFERS: u64 = 0x10000;
+
+    fn control(id: u32, event: u16, value: u16) -> VirtioConsoleControl {
+        VirtioConsoleControl { id, event, value }
+    }
+
+    // Activates `console` with one queue per entry of its queue config.
+    fn activate<'a>(console: &mut Console, mem: &'a GuestMemoryMmap) -> Vec<VirtQueue<'a>> {
+        let vqs: Vec<_> = (0..console.queue_config().len() as u64)
+            .map(|i| VirtQueue::new(GuestAddress(i * RING_STRIDE), mem, QUEUE_SIZE))
+            .collect();
+        let queues = vqs
+            .iter()
+            .map(|vq| {
+                DeviceQueue::new(
+                    vq.create_queue(),
+                    Arc::new(EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap()),
+                )
+            })
+            .collect();
+        let interrupt =
+            InterruptTransport::new(DummyIrqChip::new().into(), "console".into()).unwrap();
+        console.activate(mem.clone(), interrupt, queues).unwrap();
+        vqs
+    }
+
+    // Sends a control message from the guest and lets the device handle it.
+    fn send_control(
+        console: &mut Console,
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        msg: VirtioConsoleControl,
+    ) {
+        let avail = vq.avail.idx.get();
+        let slot = avail % QUEUE_SIZE;
+        let addr = BUFFERS + slot as u64 * 0x10;
+        mem.write_obj(msg, GuestAddress(addr)).unwrap();
+        vq.dtable[slot as usize].set(addr, size_of_val(&msg) as u32, 0, 0);
+        vq.avail.ring[slot as usize].set(slot);
+        vq.avail.idx.set(avail + 1);
+        console.process_control_tx();
+        assert_eq!(vq.used.idx.get(), avail + 1);
+    }
+
+    // Returns the (id, event, value) of the control messages queued for the guest, and the bytes
+    // following the header for names.
+    fn pending(console: &Console) -> Vec<(u32, u16, u16, Vec<u8>)> {
+        let header = size_of::<VirtioConsoleControl>();
+        std::iter::from_fn(|| console.control.queue_pop())
+            .map(|payload| {
+                let msg = match &payload {
+                    Payload::ConsoleControl(msg) => *msg,
+                    Payload::Bytes(bytes) => VirtioConsoleControl::from_slice(&bytes[..header])
+                        .copied()
+                        .unwrap(),
+                };
+                (msg.id, msg.event, msg.value, payload[header..].to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn control_queue_adds_and_names_ports() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let mut console = Console::new(vec![
+            PortDescription::console(None, None, port_io::term_fixed_size(80, 24)),
+            PortDescription::output_pipe("log", port_io::output_null()),
+        ])
+        .unwrap();
+        assert_eq!(console.queue_config().len(), 6);
+        let vqs = activate(&mut console, &mem);
+        let control_tx = &vqs[CONTROL_TXQ_INDEX];
+
+        send_control(
+            &mut console,
+            &mem,
+            control_tx,
+            control(0, control_event::VIRTIO_CONSOLE_DEVICE_READY, 1),
+        );
+        assert_eq!(
+            pending(&console),
+            vec![
+                (0, control_event::VIRTIO_CONSOLE_PORT_ADD, 0, vec![]),
+                (1, control_event::VIRTIO_CONSOLE_PORT_ADD, 0, vec![]),
+            ]
+        );
+
+        // The primary console is marked as such, opened and sized, but has no name.
+        send_control(
+            &mut console,
+            &mem,
+            control_tx,
+            control(0, control_event::VIRTIO_CONSOLE_PORT_READY, 1),
+        );
+        let resize = VirtioConsoleResize { cols: 80, rows: 24 };
+        assert_eq!(
+            pending(&console),
+            vec![
+                (0, control_event::VIRTIO_CONSOLE_CONSOLE_PORT, 1, vec![]),
+                (0, control_event::VIRTIO_CONSOLE_PORT_OPEN, 1, vec![]),
+                (
+                    0,
+                    control_event::VIRTIO_CONSOLE_RESIZE,
+                    0,
+                    resize.as_slice().to_vec()
+                ),
+            ]
+        );
+
+        send_control(
+            &mut console,
+            &mem,
+            control_tx,
+            control(1, control_event::VIRTIO_CONSOLE_PORT_READY, 1),
+        );
+        assert_eq!(
+            pending(&console),
+            vec![
+                (1, control_event::VIRTIO_CONSOLE_PORT_OPEN, 1, vec![]),
+                (
+                    1,
+                    control_event::VIRTIO_CONSOLE_PORT_NAME,
+                    1,
+                    b"log".to_vec()
+                ),
+            ]
+        );
+
+        // A port that failed to initialize in the guest is left alone.
+        send_control(
+            &mut console,
+            &mem,
+            control_tx,
+            control(1, control_event::VIRTIO_CONSOLE_PORT_READY, 0),
+        );
+        assert!(pending(&console).is_empty());
+
+        // The guest picks up queued messages from the control receive queue.
+        console.control.port_name(1, "log");
+        let control_rx = &vqs[CONTROL_RXQ_INDEX];
+        control_rx.dtable[0].set(BUFFERS + 0x1000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        control_rx.avail.ring[0].set(0);
+        control_rx.avail.idx.set(1);
+        assert!(console.process_control_rx());
+        assert_eq!(control_rx.used.idx.get(), 1);
+        assert_eq!(
+            control_rx.used.ring[0].get().len as usize,
+            size_of::<VirtioConsoleControl>() + 3
+        );
+
+        console.reset();
+    }
+
+    #[test]
+    fn named_port_echo() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let (host, mut peer) = UnixStream::pair().unwrap();
+        let mut console = Console::new(vec![
+            PortDescription::console(None, None, port_io::term_fixed_size(0, 0)),
+            PortDescription {
+                name: "echo".into(),
+                input: Some(port_io::input_to_raw_fd_dup(host.as_raw_fd()).unwrap()),
+                output: Some(port_io::output_to_raw_fd_dup(host.as_raw_fd()).unwrap()),
+                terminal: None,
+            },
+        ])
+        .unwrap();
+        drop(host);
+        let vqs = activate(&mut console, &mem);
+        let rx = &vqs[port_id_to_queue_idx(QueueDirection::Rx, 1)];
+        let tx = &vqs[port_id_to_queue_idx(QueueDirection::Tx, 1)];
+
+        // Before opening the port, the guest queues a message and a buffer for the reply.
+        let (out_addr, in_addr) = (BUFFERS + 0x2000, BUFFERS + 0x3000);
+        mem.write_slice(b"ping", GuestAddress(out_addr)).unwrap();
+        tx.dtable[0].set(out_addr, 4, 0, 0);
+        tx.avail.ring[0].set(0);
+        tx.avail.idx.set(1);
+        rx.dtable[0].set(in_addr, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        rx.avail.ring[0].set(0);
+        rx.avail.idx.set(1);
+
+        send_control(
+            &mut console,
+            &mem,
+            &vqs[CONTROL_TXQ_INDEX],
+            control(1, control_event::VIRTIO_CONSOLE_PORT_OPEN, 1),
+        );
+
+        // The host end echoes back what it gets.
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        peer.write_all(&buf).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while rx.used.idx.get() == 0 || tx.used.idx.get() == 0 {
+            assert!(Instant::now() < deadline, "no reply on the port");
+            thread::sleep(Duration::from_millis(1));
+        }
+        let used = rx.used.ring[0].get();
+        assert_eq!(used.len, 4);
+        let mut reply = [0u8; 4];
+        mem.read_slice(&mut reply, GuestAddress(in_addr)).unwrap();
+        assert_eq!(&reply, b"ping");
+
+        console.reset();
+    }
+
+    // Gives the device `count` receive buffers of 0x100 bytes from `base`.
+    fn post_rx_buffers(vq: &VirtQueue, base: u64, count: u16) {
+        for i in 0..count {
+            vq.dtable[i as usize].set(base + i as u64 * 0x100, 0x100, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i as usize].set(i);
+        }
+        vq.avail.idx.set(count);
+    }
+
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {what}");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Concatenates what the device put in the used receive buffers at `base`.
+    fn received(mem: &GuestMemoryMmap, vq: &VirtQueue, base: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..vq.used.idx.get() {
+            let used = vq.used.ring[i as usize].get();
+            let mut buf = vec![0u8; used.len as usize];
+            mem.read_slice(&mut buf, GuestAddress(base + used.id as u64 * 0x100))
+                .unwrap();
+            data.extend(buf);
+        }
+        data
+    }
+
+    #[test]
+    fn input_eof_reaches_guest() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let (console_in, mut console_writer) = UnixStream::pair().unwrap();
+        let (port_in, mut port_writer) = UnixStream::pair().unwrap();
+        let mut console = Console::new(vec![
+            PortDescription::console(
+                Some(port_io::input_to_raw_fd_dup(console_in.as_raw_fd()).unwrap()),
+                None,
+                port_io::term_fixed_size(0, 0),
+            ),
+            PortDescription::input_pipe(
+                "input",
+                port_io::input_to_raw_fd_dup(port_in.as_raw_fd()).unwrap(),
+            ),
+        ])
+        .unwrap();
+        drop((console_in, port_in));
+        let vqs = activate(&mut console, &mem);
+        let console_rx = &vqs[port_id_to_queue_idx(QueueDirection::Rx, 0)];
+        let port_rx = &vqs[port_id_to_queue_idx(QueueDirection::Rx, 1)];
+        let (console_bufs, port_bufs) = (BUFFERS + 0x2000, BUFFERS + 0x4000);
+        post_rx_buffers(console_rx, console_bufs, 4);
+        post_rx_buffers(port_rx, port_bufs, 4);
+        for port_id in 0..2 {
+            send_control(
+                &mut console,
+                &mem,
+                &vqs[CONTROL_TXQ_INDEX],
+                control(port_id, control_event::VIRTIO_CONSOLE_PORT_OPEN, 1),
+            );
+        }
+
+        console_writer.write_all(b"abc").unwrap();
+        port_writer.write_all(b"xyz").unwrap();
+        wait_for("input", || {
+            received(&mem, console_rx, console_bufs) == b"abc"
+                && received(&mem, port_rx, port_bufs) == b"xyz"
+        });
+
+        // Closing the host end mid-run shows up as EOF in the guest: the terminal gets the EOF
+        // character, and both ports are closed.
+        drop((console_writer, port_writer));
+        let mut closed = Vec::new();
+        wait_for("ports to close", || {
+            closed.extend(
+                pending(&console)
+                    .into_iter()
+                    .filter(|&(_, event, value, _)| {
+                        event == control_event::VIRTIO_CONSOLE_PORT_OPEN && value == 0
+                    })
+                    .map(|(id, ..)| id),
+            );
+            closed.len() == 2
+        });
+        closed.sort();
+        assert_eq!(closed, vec![0, 1]);
+        assert_eq!(received(&mem, console_rx, console_bufs), b"abc\x04");
+        assert_eq!(received(&mem, port_rx, port_bufs), b"xyz");
+
+        console.reset();
+    }
+
+    #[test]
+    fn output_closed_drops_and_counts() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let (port_out, mut reader) = UnixStream::pair().unwrap();
+        let mut console = Console::new(vec![
+            PortDescription::console(None, None, port_io::term_fixed_size(0, 0)),
+            PortDescription::output_pipe(
+                "output",
+                port_io::output_to_raw_fd_dup(port_out.as_raw_fd()).unwrap(),
+            ),
+        ])
+        .unwrap();
+        drop(port_out);
+        let vqs = activate(&mut console, &mem);
+        let tx = &vqs[port_id_to_queue_idx(QueueDirection::Tx, 1)];
+        send_control(
+            &mut console,
+            &mem,
+            &vqs[CONTROL_TXQ_INDEX],
+            control(1, control_event::VIRTIO_CONSOLE_PORT_OPEN, 1),
+        );
+
+        let send = |slot: u16, data: &[u8]| {
+            let addr = BUFFERS + 0x2000 + slot as u64 * 0x100;
+            mem.write_slice(data, GuestAddress(addr)).unwrap();
+            tx.dtable[slot as usize].set(addr, data.len() as u32, 0, 0);
+            tx.avail.ring[slot as usize].set(slot);
+            tx.avail.idx.set(slot + 1);
+            console.ports[1].notify_tx();
+        };
+
+        send(0, b"one");
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"one");
+        wait_for("the first write", || tx.used.idx.get() == 1);
+
+        // With the host end gone, the guest's writes still complete, and are counted.
+        drop(reader);
+        send(1, b"two");
+        send(2, b"three");
+        wait_for("writes after close", || tx.used.idx.get() == 3);
+        assert_eq!(tx.used.ring[2].get().len, 5);
+        assert_eq!(console.output_dropped(1), 8);
+
+        // Output queued when the device goes away is flushed, even if only to be dropped.
+        send(3, b"four");
+        console.reset();
+        assert_eq!(tx.used.idx.get(), 4);
+        assert_eq!(console.output_dropped(1), 12);
+    }
+}
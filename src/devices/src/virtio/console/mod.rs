@@ -6,10 +6,14 @@ pub mod port_io;
 mod port_queue_mapping;
 mod process_rx;
 mod process_tx;
+mod watch;
 
 pub use self::defs::uapi::VIRTIO_ID_CONSOLE as TYPE_CONSOLE;
 pub use self::device::Console;
 pub use self::port::PortDescription;
+pub use self::watch::{
+    ConsoleWatch, PortOutputWatch, DEFAULT_CONSOLE_TAIL_SIZE, DEFAULT_PANIC_PATTERN,
+};
 
 mod defs {
     pub const CONSOLE_DEV_ID: &str = "virtio_console";
@@ -1,5 +1,5 @@
 use std::borrow::Cow;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::{mem, thread};
@@ -77,6 +77,8 @@ pub(crate) struct Port {
     input: Option<Arc<Mutex<Box<dyn PortInput + Send>>>>,
     output: Option<Arc<Mutex<Box<dyn PortOutput + Send>>>>,
     terminal: Option<Box<dyn PortTerminalProperties>>,
+    /// Bytes the guest wrote after the output failed.
+    output_dropped: Arc<AtomicU64>,
 }
 
 impl Port {
@@ -90,6 +92,7 @@ impl Port {
                 .output
                 .map(|output| Arc::new(Mutex::new(output))),
             terminal: description.terminal,
+            output_dropped: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -101,6 +104,10 @@ impl Port {
         self.terminal.as_deref()
     }
 
+    pub fn output_dropped(&self) -> u64 {
+        self.output_dropped.load(Ordering::Relaxed)
+    }
+
     pub fn notify_rx(&self) {
         if let PortState::Active {
             rx_thread: Some(handle),
@@ -144,13 +151,14 @@ impl Port {
             let mem = mem.clone();
             let interrupt = interrupt.clone();
             let port_id = self.port_id;
+            let terminal = self.terminal.is_some();
             let stopfd = stopfd.try_clone().unwrap();
             let stop = stop.clone();
             thread::Builder::new()
                 .name("console port".into())
                 .spawn(move || {
                     process_rx(
-                        mem, rx_queue, interrupt, input, control, port_id, stopfd, stop,
+                        mem, rx_queue, interrupt, input, control, port_id, terminal, stopfd, stop,
                     )
                 })
                 .unwrap()
@@ -158,7 +166,8 @@ impl Port {
 
         let tx_thread = output.map(|output| {
             let stop = stop.clone();
-            thread::spawn(move || process_tx(mem, tx_queue, interrupt, output, stop))
+            let dropped = self.output_dropped.clone();
+            thread::spawn(move || process_tx(mem, tx_queue, interrupt, output, stop, dropped))
         });
 
         self.state = PortState::Active {
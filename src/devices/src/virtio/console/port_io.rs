@@ -84,6 +84,10 @@ pub fn output_to_log_as_err() -> Box<dyn PortOutput + Send> {
     Box::new(PortOutputLog::new())
 }
 
+pub fn output_null() -> Box<dyn PortOutput + Send> {
+    Box::new(PortOutputNull)
+}
+
 struct PortInputFd(OwnedFd);
 
 impl AsRawFd for PortInputFd {
@@ -336,6 +340,17 @@ impl PortOutput for PortOutputLog {
     fn wait_until_writable(&self) {}
 }
 
+// Throws away everything written to it, like /dev/null.
+struct PortOutputNull;
+
+impl PortOutput for PortOutputNull {
+    fn write_volatile(&mut self, buf: &VolatileSlice) -> Result<usize, io::Error> {
+        Ok(buf.len())
+    }
+
+    fn wait_until_writable(&self) {}
+}
+
 pub struct PortInputSigInt {
     sigint_evt: EventFd,
 }
@@ -2,7 +2,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{io, thread};
 
-use vm_memory::{GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion};
+use utils::sandbox::ThreadCategory;
+use vm_memory::{Bytes, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion};
 
 use crate::virtio::console::console_control::ConsoleControl;
 use crate::virtio::console::port_io::PortInput;
@@ -16,15 +17,20 @@ pub(crate) fn process_rx(
     input: Arc<Mutex<Box<dyn PortInput + Send>>>,
     control: Arc<ConsoleControl>,
     port_id: u32,
+    terminal: bool,
     stopfd: utils::eventfd::EventFd,
     stop: Arc<AtomicBool>,
 ) {
+    utils::sandbox::enter(ThreadCategory::Console);
+
     let mem = &mem;
     let mut eof = false;
 
     let mut input = input.lock().unwrap();
     loop {
-        let head = pop_head_blocking(&mut queue, mem, &interrupt);
+        let Some(head) = pop_head_blocking(&mut queue, mem, &interrupt, &stop) else {
+            return;
+        };
 
         let head_index = head.index;
         let mut bytes_read = 0;
@@ -51,6 +57,9 @@ pub(crate) fn process_rx(
 
         // We signal_used_queue only when we get WouldBlock or EOF
         if eof {
+            if terminal {
+                write_eot(&mut queue, mem, &interrupt, &stop);
+            }
             interrupt.signal_used_queue();
             log::trace!("signaling EOF on port {port_id}");
             control.port_open(port_id, false);
@@ -67,17 +76,51 @@ pub(crate) fn process_rx(
     }
 }
 
+// A terminal in the guest doesn't notice the port closing, so the end of the input is passed on
+// as the EOF character instead, which a reader in canonical mode (like `cat`) takes as the end of
+// its input.
+fn write_eot(
+    queue: &mut Queue,
+    mem: &GuestMemoryMmap,
+    interrupt: &InterruptTransport,
+    stop: &AtomicBool,
+) {
+    const EOT: u8 = 0x04;
+
+    let Some(head) = pop_head_blocking(queue, mem, interrupt, stop) else {
+        return;
+    };
+    let head_index = head.index;
+    let len = match head.into_iter().writable().next() {
+        Some(desc) => match mem.write_slice(&[EOT], desc.addr) {
+            Ok(()) => 1,
+            Err(e) => {
+                log::error!("Failed to write EOF: {e:?}");
+                0
+            }
+        },
+        None => 0,
+    };
+    if let Err(e) = queue.add_used(mem, head_index, len) {
+        error!("failed to add used elements to the queue: {e:?}");
+    }
+}
+
 fn pop_head_blocking<'mem>(
     queue: &mut Queue,
     mem: &'mem GuestMemoryMmap,
     interrupt: &InterruptTransport,
-) -> DescriptorChain<'mem> {
+    stop: &AtomicBool,
+) -> Option<DescriptorChain<'mem>> {
     loop {
         match queue.pop(mem) {
-            Some(descriptor) => break descriptor,
+            Some(descriptor) => break Some(descriptor),
             None => {
                 interrupt.signal_used_queue();
                 thread::park();
+                if stop.load(Ordering::Acquire) {
+                    break None;
+                }
                 log::trace!("rx unparked, queue len {}", queue.len(mem))
             }
         }
@@ -1,7 +1,8 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{io, thread};
 
+use utils::sandbox::ThreadCategory;
 use vm_memory::{GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion};
 
 use crate::virtio::console::port_io::PortOutput;
@@ -13,9 +14,21 @@ pub(crate) fn process_tx(
     interrupt: InterruptTransport,
     output: Arc<Mutex<Box<dyn PortOutput + Send>>>,
     stop: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
 ) {
+    utils::sandbox::enter(ThreadCategory::Console);
+
+    // Once the output fails, what the guest writes is thrown away, so that it doesn't block.
+    let mut closed = false;
+
     loop {
         let Some(head) = pop_head_blocking(&mut queue, &mem, &interrupt, &stop) else {
+            if closed {
+                log::warn!(
+                    "Dropped {} bytes of console output after the output failed",
+                    dropped.load(Ordering::Relaxed)
+                );
+            }
             return;
         };
 
@@ -24,6 +37,11 @@ pub(crate) fn process_tx(
 
         for desc in head.into_iter().readable() {
             let desc_len = desc.len as usize;
+            if closed {
+                dropped.fetch_add(desc_len as u64, Ordering::Relaxed);
+                bytes_written += desc_len;
+                continue;
+            }
             match write_desc_to_output(desc, output.lock().unwrap().as_mut(), &interrupt) {
                 Ok(0) => {
                     break;
@@ -32,13 +50,19 @@ pub(crate) fn process_tx(
                     assert_eq!(n, desc_len);
                     bytes_written += n;
                 }
+                Err(GuestMemoryError::IOError(e)) => {
+                    log::warn!("Output of console port failed, dropping further output: {e}");
+                    closed = true;
+                    dropped.fetch_add(desc_len as u64, Ordering::Relaxed);
+                    bytes_written += desc_len;
+                }
                 Err(e) => {
                     log::error!("Failed to write output: {e}");
                 }
             }
         }
 
-        if bytes_written == 0 {
+        if bytes_written == 0 && !closed {
             log::trace!("Tx Add used {bytes_written}");
             queue.undo_pop();
         } else {
@@ -57,14 +81,14 @@ fn pop_head_blocking<'mem>(
     stop: &AtomicBool,
 ) -> Option<DescriptorChain<'mem>> {
     loop {
+        // Checked before popping, so that what the guest queued before the stop is still written.
+        let stopping = stop.load(Ordering::Acquire);
         match queue.pop(mem) {
             Some(descriptor) => break Some(descriptor),
+            None if stopping => break None,
             None => {
                 interrupt.signal_used_queue();
                 thread::park();
-                if stop.load(Ordering::Acquire) {
-                    break None;
-                }
                 log::trace!("tx unparked, queue len {}", queue.len(mem))
             }
         }
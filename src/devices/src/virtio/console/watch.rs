@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use vm_memory::{VolatileMemoryError, VolatileSlice};
+
+use super::port_io::PortOutput;
+use crate::virtio::{BootStage, BootTimeline};
+
+/// What Linux prints when it panics.
+pub const DEFAULT_PANIC_PATTERN: &str = "Kernel panic - not syncing";
+
+/// How much of the console output a `ConsoleWatch` keeps by default.
+pub const DEFAULT_CONSOLE_TAIL_SIZE: usize = 16 << 10;
+
+/// Keeps the last bytes the guest wrote to its console, and looks for the kernel's panic banner
+/// in them.
+pub struct ConsoleWatch {
+    tail: Mutex<VecDeque<u8>>,
+    capacity: usize,
+    pattern: Vec<u8>,
+    panicked: AtomicBool,
+    panic_evt: EventFd,
+    timeline: Option<Arc<BootTimeline>>,
+}
+
+impl ConsoleWatch {
+    /// Creates a watch keeping the last `capacity` bytes of output, which spots a panic when
+    /// `pattern` shows up in it. An empty `pattern` never matches.
+    pub fn new(pattern: &str, capacity: usize) -> io::Result<Self> {
+        Ok(ConsoleWatch {
+            tail: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(pattern.len()),
+            pattern: pattern.as_bytes().to_vec(),
+            panicked: AtomicBool::new(false),
+            panic_evt: EventFd::new(EFD_NONBLOCK)?,
+            timeline: None,
+        })
+    }
+
+    /// Marks the guest's first output on `timeline`.
+    pub fn with_timeline(mut self, timeline: Arc<BootTimeline>) -> Self {
+        self.timeline = Some(timeline);
+        self
+    }
+
+    /// Adds what the guest wrote to the tail.
+    pub fn feed(&self, data: &[u8]) {
+        if let (Some(timeline), false) = (&self.timeline, data.is_empty()) {
+            timeline.mark(BootStage::FirstConsoleOutput);
+        }
+
+        let mut tail = self.tail.lock().unwrap();
+        tail.extend(data);
+
+        if !self.pattern.is_empty() && !self.panicked() {
+            // The banner may have started in an earlier write.
+            let window = (data.len() + self.pattern.len() - 1).min(tail.len());
+            let start = tail.len() - window;
+            if tail.make_contiguous()[start..]
+                .windows(self.pattern.len())
+                .any(|w| w == self.pattern)
+            {
+                self.panicked.store(true, Ordering::Release);
+                let _ = self.panic_evt.write(1);
+            }
+        }
+
+        let excess = tail.len().saturating_sub(self.capacity);
+        tail.drain(..excess);
+    }
+
+    /// The last bytes of output, with anything that isn't UTF-8 replaced.
+    pub fn tail(&self) -> String {
+        let mut tail = self.tail.lock().unwrap();
+        String::from_utf8_lossy(tail.make_contiguous()).into_owned()
+    }
+
+    /// Whether the panic banner showed up.
+    pub fn panicked(&self) -> bool {
+        self.panicked.load(Ordering::Acquire)
+    }
+
+    /// Becomes readable once the panic banner shows up.
+    pub fn panic_evt(&self) -> &EventFd {
+        &self.panic_evt
+    }
+}
+
+/// Passes the output of a console port on to another one, feeding a `ConsoleWatch` with it.
+///
+/// Once the other output fails, the output is still fed to the watch and then thrown away.
+pub struct PortOutputWatch {
+    inner: Option<Box<dyn PortOutput + Send>>,
+    watch: Arc<ConsoleWatch>,
+}
+
+impl PortOutputWatch {
+    pub fn new(inner: Option<Box<dyn PortOutput + Send>>, watch: Arc<ConsoleWatch>) -> Self {
+        PortOutputWatch { inner, watch }
+    }
+
+    fn feed(&self, buf: &VolatileSlice, len: usize) -> io::Result<()> {
+        let mut data = vec![0; len];
+        buf.subslice(0, len)
+            .map(|src| src.copy_to(&mut data))
+            .map_err(|e: VolatileMemoryError| io::Error::other(e))?;
+        self.watch.feed(&data);
+        Ok(())
+    }
+}
+
+impl PortOutput for PortOutputWatch {
+    fn write_volatile(&mut self, buf: &VolatileSlice) -> io::Result<usize> {
+        let written = match self.inner.as_mut().map(|inner| inner.write_volatile(buf)) {
+            Some(Ok(n)) => n,
+            Some(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => return Err(e),
+            Some(Err(e)) => {
+                log::warn!("Console output failed, only keeping its tail from now on: {e}");
+                self.inner = None;
+                buf.len()
+            }
+            None => buf.len(),
+        };
+        self.feed(buf, written)?;
+        Ok(written)
+    }
+
+    fn wait_until_writable(&self) {
+        if let Some(inner) = &self.inner {
+            inner.wait_until_writable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spots_panic_across_writes() {
+        let watch = ConsoleWatch::new(DEFAULT_PANIC_PATTERN, 64).unwrap();
+        watch.feed(b"[    0.100000] booting\n");
+        assert!(!watch.panicked());
+
+        watch.feed(b"[    1.200000] Kernel pan");
+        assert!(!watch.panicked());
+        watch.feed(b"ic - not syncing: Attempted to kill init!\n");
+        assert!(watch.panicked());
+        assert_eq!(watch.panic_evt().read().unwrap(), 1);
+
+        // Only the last 64 bytes are kept.
+        let tail = watch.tail();
+        assert_eq!(tail.len(), 64);
+        assert!(tail.ends_with("Attempted to kill init!\n"));
+
+        let watch = ConsoleWatch::new("", 64).unwrap();
+        watch.feed(DEFAULT_PANIC_PATTERN.as_bytes());
+        assert!(!watch.panicked());
+    }
+
+    #[test]
+    fn output_keeps_tail_without_sink() {
+        let watch = Arc::new(ConsoleWatch::new(DEFAULT_PANIC_PATTERN, 1024).unwrap());
+        let mut output = PortOutputWatch::new(None, watch.clone());
+
+        let mut line = b"Kernel panic - not syncing: VFS\n".to_vec();
+        // Safe because `line` outlives the slice.
+        let slice = unsafe { VolatileSlice::new(line.as_mut_ptr(), line.len()) };
+        assert_eq!(output.write_volatile(&slice).unwrap(), line.len());
+        assert!(watch.panicked());
+        assert_eq!(watch.tail(), "Kernel panic - not syncing: VFS\n");
+    }
+}
@@ -0,0 +1,137 @@
+//! How the guest's workload ended, as reported by its init.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How the guest's workload ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// It exited with this code.
+    Exited(i32),
+    /// It was killed by this signal.
+    Signaled { signal: i32, core_dumped: bool },
+}
+
+impl ExitStatus {
+    /// Decodes a status as returned by `waitpid()` on Linux. Returns `None` for a stopped or
+    /// continued process.
+    pub fn from_wait_status(status: i32) -> Option<Self> {
+        let signal = status & 0x7f;
+        match signal {
+            0 => Some(ExitStatus::Exited((status >> 8) & 0xff)),
+            0x7f => None,
+            _ => Some(ExitStatus::Signaled {
+                signal,
+                core_dumped: status & 0x80 != 0,
+            }),
+        }
+    }
+
+    /// The exit code a shell would report: the code itself, or 128 plus the signal.
+    pub fn code(&self) -> i32 {
+        match *self {
+            ExitStatus::Exited(code) => code,
+            ExitStatus::Signaled { signal, .. } => 128 + signal,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {code}"),
+            ExitStatus::Signaled {
+                signal,
+                core_dumped,
+            } => {
+                write!(f, "killed by signal {signal}")?;
+                if core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// The status is packed into a u64 so it can be set and read atomically: the low 32 bits hold the
+// code or the signal, and the high bits whether it's set, signaled and dumped core.
+const SET: u64 = 1 << 63;
+const SIGNALED: u64 = 1 << 62;
+const CORE_DUMPED: u64 = 1 << 61;
+
+/// The exit status of the guest's workload, shared between the filesystem that receives it from
+/// the guest's init and the VMM that exits with it.
+#[derive(Debug, Default)]
+pub struct SharedExitStatus(AtomicU64);
+
+impl SharedExitStatus {
+    /// Creates one with no status set yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, status: ExitStatus) {
+        let bits = match status {
+            ExitStatus::Exited(code) => SET | code as u32 as u64,
+            ExitStatus::Signaled {
+                signal,
+                core_dumped,
+            } => {
+                let core = if core_dumped { CORE_DUMPED } else { 0 };
+                SET | SIGNALED | core | signal as u32 as u64
+            }
+        };
+        self.0.store(bits, Ordering::SeqCst);
+    }
+
+    /// The status, if the guest has reported one.
+    pub fn get(&self) -> Option<ExitStatus> {
+        let bits = self.0.load(Ordering::SeqCst);
+        if bits & SET == 0 {
+            return None;
+        }
+        let value = bits as u32 as i32;
+        Some(if bits & SIGNALED != 0 {
+            ExitStatus::Signaled {
+                signal: value,
+                core_dumped: bits & CORE_DUMPED != 0,
+            }
+        } else {
+            ExitStatus::Exited(value)
+        })
+    }
+
+    /// The exit code of the status, if the guest has reported one. See [`ExitStatus::code`].
+    pub fn code(&self) -> Option<i32> {
+        self.get().map(|status| status.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let shared = SharedExitStatus::new();
+        assert_eq!(shared.get(), None);
+
+        for status in [
+            ExitStatus::Exited(0),
+            ExitStatus::Exited(-1),
+            ExitStatus::Signaled {
+                signal: 11,
+                core_dumped: true,
+            },
+            ExitStatus::Signaled {
+                signal: 9,
+                core_dumped: false,
+            },
+        ] {
+            shared.set(status);
+            assert_eq!(shared.get(), Some(status));
+        }
+        assert_eq!(shared.code(), Some(137));
+    }
+}
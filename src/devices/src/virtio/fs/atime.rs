@@ -0,0 +1,155 @@
+use std::io;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::bindings;
+use super::filesystem::SetattrValid;
+
+/// How long an atime may lag behind before `AtimePolicy::Relatime` updates it anyway, matching
+/// the kernel's `relatime`.
+const RELATIME_INTERVAL_SECS: i64 = 24 * 60 * 60;
+
+/// Controls which access time updates reach the shared directory on the host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// Every atime update is forwarded. This is the default.
+    #[default]
+    Strict,
+
+    /// Atime updates to the current time are only forwarded if the atime is older than the mtime
+    /// or ctime, or more than a day old, like the `relatime` mount option. Explicit atimes are
+    /// always set.
+    Relatime,
+
+    /// Atimes are left alone: files are opened with `O_NOATIME` where the host allows it, and
+    /// atime changes requested by the guest are dropped.
+    NoAtime,
+}
+
+impl FromStr for AtimePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" | "Strict" | "STRICT" => Ok(AtimePolicy::Strict),
+            "relatime" | "Relatime" | "RELATIME" => Ok(AtimePolicy::Relatime),
+            "noatime" | "NoAtime" | "NOATIME" => Ok(AtimePolicy::NoAtime),
+            _ => Err("invalid atime policy"),
+        }
+    }
+}
+
+impl AtimePolicy {
+    /// Removes the atime change from the `valid` attributes of a setattr request if this policy
+    /// keeps it from reaching the host. `stat` is only called if the file's current times are
+    /// needed to decide.
+    pub(crate) fn filter_setattr<F>(
+        self,
+        mut valid: SetattrValid,
+        stat: F,
+    ) -> io::Result<SetattrValid>
+    where
+        F: FnOnce() -> io::Result<bindings::stat64>,
+    {
+        // The FUSE client sets ATIME along with ATIME_NOW, so only ATIME alone carries a time.
+        let skip = match self {
+            AtimePolicy::Strict => false,
+            AtimePolicy::NoAtime => valid.contains(SetattrValid::ATIME),
+            AtimePolicy::Relatime if valid.contains(SetattrValid::ATIME_NOW) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0);
+                !self.allows_touch(&stat()?, now)
+            }
+            AtimePolicy::Relatime => false,
+        };
+        if skip {
+            valid.remove(SetattrValid::ATIME | SetattrValid::ATIME_NOW);
+        }
+
+        Ok(valid)
+    }
+
+    // Returns true if setting the atime of a file with attributes `st` to `now` (in seconds since
+    // the epoch) should go through.
+    fn allows_touch(self, st: &bindings::stat64, now: i64) -> bool {
+        match self {
+            AtimePolicy::Strict => true,
+            AtimePolicy::NoAtime => false,
+            AtimePolicy::Relatime => {
+                let atime = (st.st_atime, st.st_atime_nsec);
+                atime <= (st.st_mtime, st.st_mtime_nsec)
+                    || atime <= (st.st_ctime, st.st_ctime_nsec)
+                    || now - st.st_atime >= RELATIME_INTERVAL_SECS
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(atime: i64, mtime: i64, ctime: i64) -> bindings::stat64 {
+        // Safe because stat64 is plain old data.
+        let mut st: bindings::stat64 = unsafe { std::mem::zeroed() };
+        st.st_atime = atime;
+        st.st_mtime = mtime;
+        st.st_ctime = ctime;
+        st
+    }
+
+    #[test]
+    fn relatime() {
+        let now = 1_000_000;
+        let policy = AtimePolicy::Relatime;
+
+        assert!(!policy.allows_touch(&stat(now - 10, now - 20, now - 20), now));
+        assert!(policy.allows_touch(&stat(now - 30, now - 20, now - 40), now));
+        assert!(policy.allows_touch(&stat(now - 30, now - 40, now - 20), now));
+        assert!(policy.allows_touch(
+            &stat(
+                now - RELATIME_INTERVAL_SECS,
+                now - 2 * RELATIME_INTERVAL_SECS,
+                0
+            ),
+            now
+        ));
+
+        assert!(AtimePolicy::Strict.allows_touch(&stat(now, 0, 0), now));
+        assert!(!AtimePolicy::NoAtime.allows_touch(&stat(0, now, now), now));
+    }
+
+    #[test]
+    fn filter_setattr() {
+        let touch = SetattrValid::ATIME | SetattrValid::ATIME_NOW | SetattrValid::MTIME;
+        let explicit = SetattrValid::ATIME | SetattrValid::MTIME;
+        let no_stat = || -> io::Result<bindings::stat64> { panic!("stat not needed") };
+
+        let valid = AtimePolicy::Strict.filter_setattr(touch, no_stat).unwrap();
+        assert_eq!(valid, touch);
+        let valid = AtimePolicy::NoAtime
+            .filter_setattr(explicit, no_stat)
+            .unwrap();
+        assert_eq!(valid, SetattrValid::MTIME);
+        let valid = AtimePolicy::Relatime
+            .filter_setattr(explicit, no_stat)
+            .unwrap();
+        assert_eq!(valid, explicit);
+
+        // A fresh atime that is newer than the mtime and ctime isn't touched again.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let valid = AtimePolicy::Relatime
+            .filter_setattr(touch, || Ok(stat(now, now - 10, now - 10)))
+            .unwrap();
+        assert_eq!(valid, SetattrValid::MTIME);
+        let valid = AtimePolicy::Relatime
+            .filter_setattr(touch, || Ok(stat(now - 20, now - 10, now - 10)))
+            .unwrap();
+        assert_eq!(valid, touch);
+    }
+}
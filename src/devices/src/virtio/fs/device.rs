@@ -1,8 +1,9 @@
 #[cfg(target_os = "macos")]
 use crossbeam_channel::Sender;
 use std::cmp;
-use std::io::Write;
-use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 
@@ -13,14 +14,17 @@ use virtio_bindings::{virtio_config::VIRTIO_F_VERSION_1, virtio_ring::VIRTIO_RIN
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{
-    ActivateResult, DeviceQueue, DeviceState, FsError, QueueConfig, VirtioDevice, VirtioShmRegion,
+    ActivateError, ActivateResult, DeviceQueue, DeviceState, FsError, QueueConfig, VirtioDevice,
+    VirtioShmRegion,
 };
 use super::dyn_filesystem::{DynFileSystem, DynFileSystemAdapter};
+use super::filesystem::FileSystem;
 use super::passthrough::{self, PassthroughFs};
+use super::server::{Server, DEFAULT_MAX_IO_SIZE};
 use super::worker::FsWorker;
-use super::ExportTable;
 use super::{defs, defs::uapi};
-use crate::virtio::InterruptTransport;
+use super::{AtimePolicy, ExportTable, FsMetrics, IdMap, XattrRule};
+use crate::virtio::{BootTimeline, InterruptTransport, SharedExitStatus};
 
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
@@ -41,8 +45,10 @@ impl Default for VirtioFsConfig {
 unsafe impl ByteValued for VirtioFsConfig {}
 
 enum FsBackend {
-    Passthrough(passthrough::Config),
+    Passthrough(Box<passthrough::Config>),
     Custom(Arc<dyn DynFileSystem>),
+    // A hotplug slot with no share in it yet.
+    Vacant,
 }
 
 pub struct Fs {
@@ -52,9 +58,13 @@ pub struct Fs {
     config: VirtioFsConfig,
     shm_region: Option<VirtioShmRegion>,
     backend: FsBackend,
-    worker_thread: Option<JoinHandle<()>>,
+    queue_config: Vec<QueueConfig>,
+    worker_threads: Vec<JoinHandle<()>>,
     worker_stopfd: EventFd,
-    exit_code: Arc<AtomicI32>,
+    exit_status: Arc<SharedExitStatus>,
+    mounted: Arc<AtomicBool>,
+    boot_timeline: Option<Arc<BootTimeline>>,
+    max_io_size: u32,
     #[cfg(target_os = "macos")]
     map_sender: Option<Sender<WorkerMessage>>,
 }
@@ -63,7 +73,7 @@ impl Fs {
     pub fn new(
         fs_id: String,
         shared_dir: String,
-        exit_code: Arc<AtomicI32>,
+        exit_status: Arc<SharedExitStatus>,
         allow_root_dir_delete: bool,
     ) -> super::Result<Fs> {
         let avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
@@ -85,10 +95,14 @@ impl Fs {
             device_state: DeviceState::Inactive,
             config,
             shm_region: None,
-            backend: FsBackend::Passthrough(fs_cfg),
-            worker_thread: None,
+            backend: FsBackend::Passthrough(Box::new(fs_cfg)),
+            queue_config: queue_config(1),
+            worker_threads: Vec::new(),
             worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
-            exit_code,
+            exit_status,
+            mounted: Default::default(),
+            boot_timeline: None,
+            max_io_size: DEFAULT_MAX_IO_SIZE,
             #[cfg(target_os = "macos")]
             map_sender: None,
         })
@@ -97,7 +111,7 @@ impl Fs {
     pub fn with_custom_backend(
         fs_id: String,
         backend: Arc<dyn DynFileSystem>,
-        exit_code: Arc<AtomicI32>,
+        exit_status: Arc<SharedExitStatus>,
     ) -> super::Result<Fs> {
         let avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
 
@@ -113,14 +127,109 @@ impl Fs {
             config,
             shm_region: None,
             backend: FsBackend::Custom(backend),
-            worker_thread: None,
+            queue_config: queue_config(1),
+            worker_threads: Vec::new(),
             worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
-            exit_code,
+            exit_status,
+            mounted: Default::default(),
+            boot_timeline: None,
+            max_io_size: DEFAULT_MAX_IO_SIZE,
             #[cfg(target_os = "macos")]
             map_sender: None,
         })
     }
 
+    /// Creates an empty slot for a share to be plugged into while the VM runs.
+    ///
+    /// Until then, the device reports an ID of 0, which guest drivers take for a placeholder
+    /// and skip. Once a share is plugged in, the guest finds it by probing the device again,
+    /// such as by binding it to the `virtio-mmio` driver through sysfs. The slot has a single
+    /// request queue and no DAX window.
+    pub fn vacant(exit_status: Arc<SharedExitStatus>) -> super::Result<Fs> {
+        let config = VirtioFsConfig {
+            num_request_queues: 1,
+            ..Default::default()
+        };
+
+        Ok(Fs {
+            avail_features: (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX),
+            acked_features: 0,
+            device_state: DeviceState::Inactive,
+            config,
+            shm_region: None,
+            backend: FsBackend::Vacant,
+            queue_config: queue_config(1),
+            worker_threads: Vec::new(),
+            worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
+            exit_status,
+            mounted: Default::default(),
+            boot_timeline: None,
+            max_io_size: DEFAULT_MAX_IO_SIZE,
+            #[cfg(target_os = "macos")]
+            map_sender: None,
+        })
+    }
+
+    /// Plugs a share of the host directory `cfg.root_dir` into this slot under `tag`.
+    pub fn plug_passthrough(&mut self, tag: &str, cfg: passthrough::Config) -> io::Result<()> {
+        self.plug(tag, FsBackend::Passthrough(Box::new(cfg)))
+    }
+
+    /// Plugs a share served by `backend` into this slot under `tag`.
+    pub fn plug_custom(&mut self, tag: &str, backend: Arc<dyn DynFileSystem>) -> io::Result<()> {
+        self.plug(tag, FsBackend::Custom(backend))
+    }
+
+    fn plug(&mut self, tag: &str, backend: FsBackend) -> io::Result<()> {
+        if !self.is_vacant() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the slot already holds a share",
+            ));
+        }
+        let mut config_tag = [0; 36];
+        if tag.is_empty() || tag.len() > config_tag.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tag must be 1 to {} bytes long", config_tag.len()),
+            ));
+        }
+        config_tag[..tag.len()].copy_from_slice(tag.as_bytes());
+        self.config.tag = config_tag;
+        self.backend = backend;
+        Ok(())
+    }
+
+    /// Empties this slot again. Fails with `EBUSY` while the guest has the share mounted, unless
+    /// `force` is set, in which case the guest's requests to the share go unanswered.
+    pub fn unplug(&mut self, force: bool) -> io::Result<()> {
+        if self.is_mounted() && !force {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        self.reset();
+        self.backend = FsBackend::Vacant;
+        self.config.tag = [0; 36];
+        self.mounted.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether this is a hotplug slot with no share in it.
+    pub fn is_vacant(&self) -> bool {
+        matches!(self.backend, FsBackend::Vacant)
+    }
+
+    /// Whether the guest has mounted the share and not unmounted it since.
+    pub fn is_mounted(&self) -> bool {
+        self.mounted.load(Ordering::Relaxed)
+    }
+
+    /// The tag the guest mounts the share by.
+    pub fn tag(&self) -> String {
+        let tag = &self.config.tag;
+        let len = tag.iter().position(|&b| b == 0).unwrap_or(tag.len());
+        String::from_utf8_lossy(&tag[..len]).into_owned()
+    }
+
     pub fn id(&self) -> &str {
         defs::FS_DEV_ID
     }
@@ -137,14 +246,169 @@ impl Fs {
                 cfg.export_table = Some(export_table);
                 cfg.export_fsid
             }
-            FsBackend::Custom(_) => 0,
+            FsBackend::Custom(_) | FsBackend::Vacant => 0,
+        }
+    }
+
+    pub fn set_idmap(&mut self, uid_map: IdMap, gid_map: IdMap) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.uid_map = uid_map;
+            cfg.gid_map = gid_map;
+        }
+    }
+
+    pub fn set_atime_policy(&mut self, atime: AtimePolicy) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.atime = atime;
+        }
+    }
+
+    /// Hides `hidden`, paths relative to the shared directory, from the guest.
+    pub fn set_hidden_paths(&mut self, hidden: Vec<PathBuf>) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.hidden = hidden;
+        }
+    }
+
+    /// Translates extended attribute names between the guest and the host through `rules`.
+    pub fn set_xattr_map(&mut self, rules: Vec<XattrRule>) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.xattr_map = rules;
+        }
+    }
+
+    /// Keeps the guest from going past host mount points beneath the shared directory. With
+    /// `empty_mount_points`, they're shown as empty directories rather than hidden.
+    pub fn set_one_filesystem(&mut self, enabled: bool, empty_mount_points: bool) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.one_filesystem = enabled;
+            cfg.empty_mount_points = empty_mount_points;
+        }
+    }
+
+    /// Keeps the guest from changing anything in the shared directory.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.read_only = read_only;
+        }
+    }
+
+    /// Lets files the guest opens with `O_DIRECT` bypass the host's cache too.
+    pub fn set_direct_io(&mut self, enabled: bool) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.allow_direct_io = enabled;
+        }
+    }
+
+    /// Serves the share through `fd`, an open `/proc/self/fd`, instead of opening its own.
+    #[cfg(target_os = "linux")]
+    pub fn set_proc_fd(&mut self, fd: Arc<std::os::fd::OwnedFd>) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.proc_self_fd = Some(fd);
+        }
+    }
+
+    /// Has the worker threads confine themselves to the shared directory with Landlock. Only
+    /// warns and leaves them as they are if the host doesn't have Landlock.
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    pub fn set_landlock(&mut self, enabled: bool) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            if enabled {
+                if let Err(e) = utils::landlock::abi_version() {
+                    warn!(
+                        "fs: Landlock isn't available, not confining the workers to {}: {e}",
+                        cfg.root_dir
+                    );
+                    return;
+                }
+            }
+            cfg.landlock = enabled;
         }
     }
 
+    pub fn set_metrics(&mut self, metrics: Arc<FsMetrics>) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.metrics = metrics;
+        }
+    }
+
+    /// Serves `init_binary` as `init.krun` instead of the built-in init binary.
+    pub fn set_init_binary(&mut self, init_binary: Vec<u8>) {
+        if let FsBackend::Passthrough(cfg) = &mut self.backend {
+            cfg.init_binary = Some(init_binary);
+        }
+    }
+
+    /// Sets how many request queues the device offers to the guest, each served by its own worker
+    /// thread. Must be called before the device is attached to a transport.
+    pub fn set_num_request_queues(&mut self, num_request_queues: usize) {
+        let num_request_queues = num_request_queues.max(1);
+        self.config.num_request_queues = num_request_queues as u32;
+        self.queue_config = queue_config(num_request_queues);
+    }
+
+    /// Records the boot stages the guest's init reports through this share on `boot_timeline`.
+    pub fn set_boot_timeline(&mut self, boot_timeline: Arc<BootTimeline>) {
+        self.boot_timeline = Some(boot_timeline);
+    }
+
+    /// Sets the largest read or write the guest can send in a single request. `size` must pass
+    /// `check_max_io_size`.
+    pub fn set_max_io_size(&mut self, size: u32) {
+        self.max_io_size = size;
+    }
+
     #[cfg(target_os = "macos")]
     pub fn set_map_sender(&mut self, map_sender: Sender<WorkerMessage>) {
         self.map_sender = Some(map_sender);
     }
+
+    fn start_workers<F: FileSystem + Send + Sync + 'static>(
+        &mut self,
+        fs: F,
+        queues: Vec<DeviceQueue>,
+        interrupt: &InterruptTransport,
+        mem: &GuestMemoryMmap,
+        #[cfg(all(target_os = "linux", feature = "landlock"))] landlock_root: Option<PathBuf>,
+    ) {
+        let server = Arc::new(
+            Server::new(fs, self.mounted.clone())
+                .with_boot_timeline(self.boot_timeline.clone())
+                .with_max_io_size(self.max_io_size),
+        );
+
+        // The high priority queue goes to the first request queue's worker. Guests only send INIT
+        // and wait for its reply before using the other queues, and a FORGET is only sent once
+        // the guest has no requests left for the inode, so the workers need no further ordering.
+        let mut queues = queues.into_iter();
+        let mut worker_queues: Vec<DeviceQueue> = queues.next().into_iter().collect();
+        for (i, dq) in queues.enumerate() {
+            worker_queues.push(dq);
+            let (worker_queues, queue_evts) = std::mem::take(&mut worker_queues)
+                .into_iter()
+                .map(|dq| (dq.queue, dq.event))
+                .unzip();
+            let worker = FsWorker::new(
+                Arc::clone(&server),
+                worker_queues,
+                queue_evts,
+                interrupt.clone(),
+                mem.clone(),
+                self.shm_region.clone(),
+                self.worker_stopfd.try_clone().unwrap(),
+                self.exit_status.clone(),
+                #[cfg(target_os = "macos")]
+                self.map_sender.clone(),
+                #[cfg(all(target_os = "linux", feature = "landlock"))]
+                landlock_root.clone(),
+            );
+            self.worker_threads.push(worker.run(i));
+        }
+    }
+}
+
+fn queue_config(num_request_queues: usize) -> Vec<QueueConfig> {
+    vec![QueueConfig::new(defs::QUEUE_SIZE); defs::REQ_INDEX + num_request_queues]
 }
 
 impl VirtioDevice for Fs {
@@ -161,7 +425,11 @@ impl VirtioDevice for Fs {
     }
 
     fn device_type(&self) -> u32 {
-        uapi::VIRTIO_ID_FS
+        if self.is_vacant() {
+            0
+        } else {
+            uapi::VIRTIO_ID_FS
+        }
     }
 
     fn device_name(&self) -> &str {
@@ -169,7 +437,7 @@ impl VirtioDevice for Fs {
     }
 
     fn queue_config(&self) -> &[QueueConfig] {
-        &defs::QUEUE_CONFIG
+        &self.queue_config
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
@@ -200,51 +468,40 @@ impl VirtioDevice for Fs {
         interrupt: InterruptTransport,
         queues: Vec<DeviceQueue>,
     ) -> ActivateResult {
-        if self.worker_thread.is_some() {
-            panic!("virtio_fs: worker thread already exists");
-        }
-
-        // Extract queues and eventfds from DeviceQueues.
-        let mut worker_queues = Vec::with_capacity(queues.len());
-        let mut queue_evts = Vec::with_capacity(queues.len());
-        for dq in queues {
-            worker_queues.push(dq.queue);
-            queue_evts.push(dq.event);
+        if !self.worker_threads.is_empty() {
+            panic!("virtio_fs: worker threads already exist");
         }
 
-        let join_handle = match &self.backend {
+        match &self.backend {
             FsBackend::Passthrough(cfg) => {
-                let worker = FsWorker::new(
-                    PassthroughFs::new(cfg.clone()).unwrap(),
-                    worker_queues,
-                    queue_evts,
-                    interrupt.clone(),
-                    mem.clone(),
-                    self.shm_region.clone(),
-                    self.worker_stopfd.try_clone().unwrap(),
-                    self.exit_code.clone(),
-                    #[cfg(target_os = "macos")]
-                    self.map_sender.clone(),
+                #[cfg(all(target_os = "linux", feature = "landlock"))]
+                let landlock_root = cfg.landlock.then(|| PathBuf::from(&cfg.root_dir));
+                let fs = PassthroughFs::new(cfg.as_ref().clone()).unwrap();
+                self.start_workers(
+                    fs,
+                    queues,
+                    &interrupt,
+                    &mem,
+                    #[cfg(all(target_os = "linux", feature = "landlock"))]
+                    landlock_root,
                 );
-                worker.run()
             }
             FsBackend::Custom(dyn_fs) => {
-                let worker = FsWorker::new(
-                    DynFileSystemAdapter::new(Arc::clone(dyn_fs)),
-                    worker_queues,
-                    queue_evts,
-                    interrupt.clone(),
-                    mem.clone(),
-                    self.shm_region.clone(),
-                    self.worker_stopfd.try_clone().unwrap(),
-                    self.exit_code.clone(),
-                    #[cfg(target_os = "macos")]
-                    self.map_sender.clone(),
+                let fs = DynFileSystemAdapter::new(Arc::clone(dyn_fs));
+                self.start_workers(
+                    fs,
+                    queues,
+                    &interrupt,
+                    &mem,
+                    #[cfg(all(target_os = "linux", feature = "landlock"))]
+                    None,
                 );
-                worker.run()
             }
-        };
-        self.worker_thread = Some(join_handle);
+            FsBackend::Vacant => {
+                error!("virtio_fs: can't activate an empty hotplug slot");
+                return Err(ActivateError::BadActivate);
+            }
+        }
 
         self.device_state = DeviceState::Activated(mem, interrupt);
         Ok(())
@@ -259,13 +516,463 @@ impl VirtioDevice for Fs {
     }
 
     fn reset(&mut self) -> bool {
-        if let Some(worker) = self.worker_thread.take() {
+        if !self.worker_threads.is_empty() {
             let _ = self.worker_stopfd.write(1);
-            if let Err(e) = worker.join() {
-                error!("error waiting for worker thread: {e:?}");
+            for worker in self.worker_threads.drain(..) {
+                if let Err(e) = worker.join() {
+                    error!("error waiting for worker thread: {e:?}");
+                }
             }
+            let _ = self.worker_stopfd.read();
         }
         self.device_state = DeviceState::Inactive;
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::mem::size_of;
+    use std::time::{Duration, Instant};
+
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::super::filesystem::{Context, ZeroCopyWriter};
+    use super::super::fuse::{
+        AttrOut, EntryOut, GetattrIn, InHeader, InitInCompat, InitOut, Opcode, OpenIn, OpenOut,
+        OutHeader, ReadIn, WriteIn, WriteOut, KERNEL_MINOR_VERSION, KERNEL_VERSION,
+    };
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::bindings::stat64;
+    use crate::virtio::queue::tests::VirtQueue;
+
+    const RING_SIZE: u16 = 32;
+    // Descriptors used by each request: the request itself, the reply header and the reply body.
+    const DESCS_PER_REQUEST: u16 = 3;
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+    const SLOT_SIZE: u64 = 0x4000;
+    const REPLY_OFFSET: u64 = 0x2000;
+    const CHUNK: usize = 4096;
+    // Chunks written and read through each request queue.
+    const CHUNKS: usize = 8;
+
+    // Drives one virtqueue the way the guest driver would. Each request takes a slot of
+    // `SLOT_SIZE` bytes of buffers, with its reply at `REPLY_OFFSET`.
+    struct Guest<'a> {
+        vq: VirtQueue<'a>,
+        evt: Arc<EventFd>,
+        mem: &'a GuestMemoryMmap,
+        buffers: u64,
+        pending: u16,
+        unique: u64,
+    }
+
+    impl<'a> Guest<'a> {
+        fn new(mem: &'a GuestMemoryMmap, ring: u64, buffers: u64) -> Self {
+            Guest {
+                vq: VirtQueue::new(GuestAddress(ring), mem, RING_SIZE),
+                evt: Arc::new(EventFd::new(EFD_NONBLOCK).unwrap()),
+                mem,
+                buffers,
+                pending: 0,
+                unique: buffers,
+            }
+        }
+
+        fn device_queue(&self) -> DeviceQueue {
+            DeviceQueue::new(self.vq.create_queue(), self.evt.clone())
+        }
+
+        // Queues a request without notifying the device, with room for `out_len` bytes of reply
+        // after the header. Like the guest driver, the reply buffer isn't larger than needed.
+        fn push(&mut self, opcode: Opcode, nodeid: u64, body: &[u8], out_len: usize) {
+            let slot = self.pending;
+            assert!(slot < RING_SIZE / DESCS_PER_REQUEST);
+            let request = self.buffers + slot as u64 * SLOT_SIZE;
+            let len = (size_of::<InHeader>() + body.len()) as u32;
+            self.unique += 1;
+            let header = InHeader {
+                len,
+                opcode: opcode as u32,
+                unique: self.unique,
+                nodeid,
+                ..Default::default()
+            };
+            self.mem.write_obj(header, GuestAddress(request)).unwrap();
+            self.mem
+                .write_slice(body, GuestAddress(request + size_of::<InHeader>() as u64))
+                .unwrap();
+
+            // The driver puts the reply header in a descriptor of its own, which the server
+            // relies on to write the header after the body.
+            let desc = slot * DESCS_PER_REQUEST;
+            let reply = request + REPLY_OFFSET;
+            let header_len = size_of::<OutHeader>() as u32;
+            let table = &self.vq.dtable[desc as usize..];
+            table[0].set(request, len, VIRTQ_DESC_F_NEXT, desc + 1);
+            table[1].set(
+                reply,
+                header_len,
+                VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+                desc + 2,
+            );
+            table[2].set(
+                reply + header_len as u64,
+                out_len as u32,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            let avail = self.vq.avail.idx.get();
+            self.vq.avail.ring[(avail % RING_SIZE) as usize].set(desc);
+            self.vq.avail.idx.set(avail.wrapping_add(1));
+            self.pending += 1;
+        }
+
+        fn kick(&self) {
+            self.evt.write(1).unwrap();
+        }
+
+        // Waits for all the pushed requests to complete and returns the header and body of their
+        // replies, in the order they were pushed.
+        fn wait(&mut self) -> Vec<(OutHeader, Vec<u8>)> {
+            let target = self.vq.avail.idx.get();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while self.vq.used.idx.get() != target {
+                assert!(Instant::now() < deadline, "requests not completed in time");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            (0..std::mem::take(&mut self.pending))
+                .map(|slot| {
+                    let reply = self.buffers + slot as u64 * SLOT_SIZE + REPLY_OFFSET;
+                    let header: OutHeader = self.mem.read_obj(GuestAddress(reply)).unwrap();
+                    let mut body = vec![0u8; header.len as usize - size_of::<OutHeader>()];
+                    self.mem
+                        .read_slice(
+                            &mut body,
+                            GuestAddress(reply + size_of::<OutHeader>() as u64),
+                        )
+                        .unwrap();
+                    (header, body)
+                })
+                .collect()
+        }
+
+        // Sends a single request and returns its reply body, which must not be an error.
+        fn call(&mut self, opcode: Opcode, nodeid: u64, body: &[u8], out_len: usize) -> Vec<u8> {
+            self.push(opcode, nodeid, body, out_len);
+            self.kick();
+            let (header, body) = self.wait().pop().unwrap();
+            assert_eq!(header.error, 0, "{opcode:?} failed");
+            body
+        }
+    }
+
+    // Holds every read back until the test lets it through, like a read that has to fault its
+    // data in from a slow disk.
+    struct SlowReads {
+        file: fs::File,
+        release: crossbeam_channel::Receiver<()>,
+    }
+
+    impl DynFileSystem for SlowReads {
+        fn getattr(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            // Safe because stat64 is plain data.
+            Ok((unsafe { std::mem::zeroed() }, Duration::ZERO))
+        }
+
+        fn read(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _handle: u64,
+            w: &mut dyn ZeroCopyWriter,
+            size: u32,
+            offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            self.release
+                .recv_timeout(Duration::from_secs(10))
+                .map_err(|_| io::Error::from_raw_os_error(libc::ETIMEDOUT))?;
+            w.write_from(&self.file, size as usize, offset)
+        }
+    }
+
+    fn chunk_data(chunk: usize) -> Vec<u8> {
+        vec![chunk as u8 + 1; CHUNK]
+    }
+
+    #[test]
+    fn parallel_request_queues() {
+        let dir = std::env::temp_dir().join(format!("krun-fs-queues-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::File::create(dir.join("data"))
+            .unwrap()
+            .set_len((2 * CHUNKS * CHUNK) as u64)
+            .unwrap();
+
+        let mut dev = Fs::new(
+            "test".to_string(),
+            dir.to_str().unwrap().to_string(),
+            Arc::new(SharedExitStatus::new()),
+            false,
+        )
+        .unwrap();
+        dev.set_num_request_queues(2);
+        assert_eq!(dev.queue_config().len(), 3);
+        let mut num_request_queues = [0u8; 4];
+        dev.read_config(36, &mut num_request_queues);
+        assert_eq!(u32::from_le_bytes(num_request_queues), 2);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        let hpq = Guest::new(&mem, 0x0, 0x10000);
+        let mut guests = [
+            Guest::new(&mem, 0x1000, 0x40000),
+            Guest::new(&mem, 0x2000, 0x80000),
+        ];
+        let queues = vec![
+            hpq.device_queue(),
+            guests[0].device_queue(),
+            guests[1].device_queue(),
+        ];
+        let interrupt = InterruptTransport::new(DummyIrqChip::new().into(), "fs".into()).unwrap();
+        dev.activate(mem.clone(), interrupt, queues).unwrap();
+        assert_eq!(dev.worker_threads.len(), 2);
+
+        let init = InitInCompat {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        guests[0].call(Opcode::Init, 0, init.as_slice(), size_of::<InitOut>());
+
+        // Each queue opens the file on its own.
+        let mut handles = Vec::new();
+        for guest in guests.iter_mut() {
+            let entry = guest.call(Opcode::Lookup, 1, b"data\0", size_of::<EntryOut>());
+            let nodeid = EntryOut::from_slice(&entry[..size_of::<EntryOut>()])
+                .unwrap()
+                .nodeid;
+            let open = OpenIn {
+                flags: libc::O_RDWR as u32,
+                ..Default::default()
+            };
+            let out = guest.call(Opcode::Open, nodeid, open.as_slice(), size_of::<OpenOut>());
+            let fh = OpenOut::from_slice(&out).unwrap().fh;
+            handles.push((nodeid, fh));
+        }
+
+        // Queue i writes the chunks i, i + 2, i + 4... while the other queue writes the rest.
+        for (i, guest) in guests.iter_mut().enumerate() {
+            let (nodeid, fh) = handles[i];
+            for chunk in (i..2 * CHUNKS).step_by(2) {
+                let write = WriteIn {
+                    fh,
+                    offset: (chunk * CHUNK) as u64,
+                    size: CHUNK as u32,
+                    ..Default::default()
+                };
+                let mut body = write.as_slice().to_vec();
+                body.extend(chunk_data(chunk));
+                guest.push(Opcode::Write, nodeid, &body, size_of::<WriteOut>());
+            }
+        }
+        guests.iter().for_each(Guest::kick);
+        for guest in guests.iter_mut() {
+            for (header, body) in guest.wait() {
+                assert_eq!(header.error, 0);
+                assert_eq!(WriteOut::from_slice(&body).unwrap().size, CHUNK as u32);
+            }
+        }
+
+        // Then each queue reads back the chunks the other one wrote.
+        for (i, guest) in guests.iter_mut().enumerate() {
+            let (nodeid, fh) = handles[i];
+            for chunk in (1 - i..2 * CHUNKS).step_by(2) {
+                let read = ReadIn {
+                    fh,
+                    offset: (chunk * CHUNK) as u64,
+                    size: CHUNK as u32,
+                    ..Default::default()
+                };
+                guest.push(Opcode::Read, nodeid, read.as_slice(), CHUNK);
+            }
+        }
+        guests.iter().for_each(Guest::kick);
+        for (i, guest) in guests.iter_mut().enumerate() {
+            let replies = guest.wait();
+            for ((header, body), chunk) in replies.into_iter().zip((1 - i..2 * CHUNKS).step_by(2)) {
+                assert_eq!(header.error, 0);
+                assert!(body == chunk_data(chunk), "chunk {chunk}");
+            }
+        }
+
+        let data = fs::read(dir.join("data")).unwrap();
+        for (chunk, data) in data.chunks(CHUNK).enumerate() {
+            assert!(data == chunk_data(chunk), "chunk {chunk} on the host");
+        }
+
+        assert!(dev.reset());
+        assert!(dev.worker_threads.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hotplug_share() {
+        let dir = std::env::temp_dir().join(format!("krun-fs-hotplug-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("hello"), b"hello from the host").unwrap();
+
+        let mut dev = Fs::vacant(Arc::new(SharedExitStatus::new())).unwrap();
+        assert_eq!(dev.device_type(), 0);
+        assert!(dev.unplug(false).is_ok());
+
+        let cfg = passthrough::Config {
+            root_dir: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let err = dev
+            .plug_passthrough(&"x".repeat(37), cfg.clone())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        dev.plug_passthrough("extra", cfg.clone()).unwrap();
+        assert_eq!(dev.device_type(), uapi::VIRTIO_ID_FS);
+        assert_eq!(dev.tag(), "extra");
+        let err = dev.plug_passthrough("other", cfg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // The guest probes the device again, mounts the share and reads a file.
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        let hpq = Guest::new(&mem, 0x0, 0x10000);
+        let mut guest = Guest::new(&mem, 0x1000, 0x40000);
+        let queues = vec![hpq.device_queue(), guest.device_queue()];
+        let interrupt = InterruptTransport::new(DummyIrqChip::new().into(), "fs".into()).unwrap();
+        dev.activate(mem.clone(), interrupt, queues).unwrap();
+
+        let init = InitInCompat {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        guest.call(Opcode::Init, 0, init.as_slice(), size_of::<InitOut>());
+        assert!(dev.is_mounted());
+
+        let entry = guest.call(Opcode::Lookup, 1, b"hello\0", size_of::<EntryOut>());
+        let nodeid = EntryOut::from_slice(&entry[..size_of::<EntryOut>()])
+            .unwrap()
+            .nodeid;
+        let open = OpenIn {
+            flags: libc::O_RDONLY as u32,
+            ..Default::default()
+        };
+        let out = guest.call(Opcode::Open, nodeid, open.as_slice(), size_of::<OpenOut>());
+        let read = ReadIn {
+            fh: OpenOut::from_slice(&out).unwrap().fh,
+            size: 64,
+            ..Default::default()
+        };
+        let data = guest.call(Opcode::Read, nodeid, read.as_slice(), 19);
+        assert_eq!(data, b"hello from the host");
+
+        // The share can't go away while it's mounted.
+        let err = dev.unplug(false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        // FUSE_DESTROY has no reply, so just wait for the device to take it.
+        guest.push(Opcode::Destroy, 0, &[], 0);
+        guest.kick();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while guest.vq.used.idx.get() != guest.vq.avail.idx.get() {
+            assert!(Instant::now() < deadline, "destroy not completed in time");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        guest.pending = 0;
+        assert!(!dev.is_mounted());
+
+        dev.unplug(false).unwrap();
+        assert!(dev.is_vacant());
+        assert_eq!(dev.device_type(), 0);
+        assert!(dev.worker_threads.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn slow_reads_complete_out_of_order() {
+        let path = std::env::temp_dir().join(format!("krun-fs-slow-read-{}", std::process::id()));
+        fs::write(&path, b"slow data").unwrap();
+        let (release, receiver) = crossbeam_channel::bounded(1);
+        let backend = SlowReads {
+            file: fs::File::open(&path).unwrap(),
+            release: receiver,
+        };
+        let mut dev = Fs::with_custom_backend(
+            "test".to_string(),
+            Arc::new(backend),
+            Arc::new(SharedExitStatus::new()),
+        )
+        .unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        let hpq = Guest::new(&mem, 0x0, 0x10000);
+        let mut guest = Guest::new(&mem, 0x1000, 0x40000);
+        let queues = vec![hpq.device_queue(), guest.device_queue()];
+        let interrupt = InterruptTransport::new(DummyIrqChip::new().into(), "fs".into()).unwrap();
+        dev.activate(mem.clone(), interrupt, queues).unwrap();
+
+        let init = InitInCompat {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        guest.call(Opcode::Init, 0, init.as_slice(), size_of::<InitOut>());
+
+        // A read goes first, with getattrs queued behind it.
+        const GETATTRS: u16 = 4;
+        let used_before = guest.vq.used.idx.get();
+        let read = ReadIn {
+            size: 64,
+            ..Default::default()
+        };
+        guest.push(Opcode::Read, 1, read.as_slice(), 9);
+        for _ in 0..GETATTRS {
+            let getattr = GetattrIn::default();
+            guest.push(Opcode::Getattr, 1, getattr.as_slice(), size_of::<AttrOut>());
+        }
+        guest.kick();
+
+        // The getattrs are answered while the read is still stuck.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while guest.vq.used.idx.get() != used_before.wrapping_add(GETATTRS) {
+            assert!(Instant::now() < deadline, "getattrs not completed in time");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        for i in 0..GETATTRS {
+            let used = guest.vq.used.ring[(used_before.wrapping_add(i) % RING_SIZE) as usize].get();
+            assert_ne!(used.id, 0, "the read completed before getattr {i}");
+        }
+
+        release.send(()).unwrap();
+        let replies = guest.wait();
+        let last = (used_before.wrapping_add(GETATTRS) % RING_SIZE) as usize;
+        assert_eq!(guest.vq.used.ring[last].get().id, 0);
+        assert_eq!(replies[0].0.error, 0);
+        assert_eq!(replies[0].1, b"slow data");
+        for (header, _) in &replies[1..] {
+            assert_eq!(header.error, 0);
+        }
+
+        assert!(dev.reset());
+        fs::remove_file(&path).unwrap();
+    }
+}
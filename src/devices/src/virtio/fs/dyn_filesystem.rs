@@ -11,15 +11,16 @@ use utils::worker_message::WorkerMessage;
 
 use std::ffi::CStr;
 use std::io;
-use std::sync::atomic::AtomicI32;
 use std::sync::Arc;
 use std::time::Duration;
 
 use super::filesystem::{
-    Context, DirEntry, Entry, Extensions, FileSystem, FsOptions, GetxattrReply, ListxattrReply,
-    OpenOptions, RemovemappingOne, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    Advice, Context, DirEntry, Entry, Extensions, FileSystem, FsOptions, GetxattrReply,
+    ListxattrReply, OpenOptions, RemovemappingOne, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
+use super::fuse::FileLock;
 use crate::virtio::bindings::{stat64, statvfs64, LINUX_ENOSYS};
+use crate::virtio::SharedExitStatus;
 
 //--------------------------------------------------------------------------------------------------
 // Types
@@ -67,6 +68,16 @@ pub trait DynFileSystem: Send + Sync {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
+    /// Get attributes for a file / directory, along with its creation time if known.
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: Option<u64>,
+    ) -> io::Result<(stat64, Option<libc::timespec>, Duration)> {
+        Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
+    }
+
     /// Set attributes for a file / directory.
     fn setattr(
         &self,
@@ -179,6 +190,19 @@ pub trait DynFileSystem: Send + Sync {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
+    /// Create and open an unnamed temporary file.
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: u64,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
+    }
+
     /// Read data from a file.
     #[allow(clippy::too_many_arguments)]
     fn read(
@@ -260,6 +284,11 @@ pub trait DynFileSystem: Send + Sync {
         Ok(st)
     }
 
+    /// Synchronize the whole file system containing `inode` to disk.
+    fn syncfs(&self, ctx: Context, inode: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
+    }
+
     /// Set an extended attribute.
     fn setxattr(
         &self,
@@ -306,7 +335,8 @@ pub trait DynFileSystem: Send + Sync {
     /// Read a directory.
     ///
     /// Returns a vector of directory entries. Unlike the original FileSystem trait
-    /// which uses a callback, this returns entries directly for object safety.
+    /// which uses a callback, this returns entries directly for object safety. Entry
+    /// names may borrow from the filesystem.
     fn readdir(
         &self,
         ctx: Context,
@@ -314,7 +344,7 @@ pub trait DynFileSystem: Send + Sync {
         handle: u64,
         size: u32,
         offset: u64,
-    ) -> io::Result<Vec<DirEntry<'static>>> {
+    ) -> io::Result<Vec<DirEntry<'_>>> {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
@@ -322,6 +352,7 @@ pub trait DynFileSystem: Send + Sync {
     ///
     /// Returns a vector of (DirEntry, Entry) pairs. Unlike the original FileSystem
     /// trait which uses a callback, this returns entries directly for object safety.
+    /// Entry names may borrow from the filesystem.
     fn readdirplus(
         &self,
         ctx: Context,
@@ -329,7 +360,7 @@ pub trait DynFileSystem: Send + Sync {
         handle: u64,
         size: u32,
         offset: u64,
-    ) -> io::Result<Vec<(DirEntry<'static>, Entry)>> {
+    ) -> io::Result<Vec<(DirEntry<'_>, Entry)>> {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
@@ -360,6 +391,19 @@ pub trait DynFileSystem: Send + Sync {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
+    /// Apply an access pattern hint to a range of an open file.
+    fn fadvise(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
+    }
+
     /// Copy a range of data from one file to another.
     #[allow(clippy::too_many_arguments)]
     fn copyfilerange(
@@ -419,23 +463,47 @@ pub trait DynFileSystem: Send + Sync {
         arg: u64,
         in_size: u32,
         out_size: u32,
-        exit_code: &Arc<AtomicI32>,
+        exit_status: &Arc<SharedExitStatus>,
     ) -> io::Result<Vec<u8>> {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
-    /// Get file lock (not yet supported).
-    fn getlk(&self) -> io::Result<()> {
+    /// Test for a POSIX record lock.
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
-    /// Set file lock (not yet supported).
-    fn setlk(&self) -> io::Result<()> {
+    /// Acquire, modify or release a lock without waiting.
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
-    /// Set file lock and wait (not yet supported).
-    fn setlkw(&self) -> io::Result<()> {
+    /// Acquire a lock, returning `EAGAIN` instead of blocking so the request can be retried.
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(LINUX_ENOSYS))
     }
 
@@ -506,6 +574,15 @@ impl FileSystem for DynFileSystemAdapter {
         self.0.getattr(ctx, inode, handle)
     }
 
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: Option<u64>,
+    ) -> io::Result<(stat64, Option<libc::timespec>, Duration)> {
+        self.0.statx(ctx, inode, handle)
+    }
+
     fn setattr(
         &self,
         ctx: Context,
@@ -609,6 +686,18 @@ impl FileSystem for DynFileSystemAdapter {
             .create(ctx, parent, name, mode, kill_priv, flags, umask, extensions)
     }
 
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: u64,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        self.0.tmpfile(ctx, parent, mode, flags, umask, extensions)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn read<W: io::Write + ZeroCopyWriter>(
         &self,
@@ -692,6 +781,10 @@ impl FileSystem for DynFileSystemAdapter {
         self.0.statfs(ctx, inode)
     }
 
+    fn syncfs(&self, ctx: Context, inode: u64) -> io::Result<()> {
+        self.0.syncfs(ctx, inode)
+    }
+
     fn setxattr(
         &self,
         ctx: Context,
@@ -799,6 +892,18 @@ impl FileSystem for DynFileSystemAdapter {
         self.0.lseek(ctx, inode, handle, offset, whence)
     }
 
+    fn fadvise(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> io::Result<()> {
+        self.0.fadvise(ctx, inode, handle, offset, len, advice)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn copyfilerange(
         &self,
@@ -875,23 +980,55 @@ impl FileSystem for DynFileSystemAdapter {
         arg: u64,
         in_size: u32,
         out_size: u32,
-        exit_code: &Arc<AtomicI32>,
+        exit_status: &Arc<SharedExitStatus>,
     ) -> io::Result<Vec<u8>> {
         self.0.ioctl(
-            ctx, inode, handle, flags, cmd, arg, in_size, out_size, exit_code,
+            ctx,
+            inode,
+            handle,
+            flags,
+            cmd,
+            arg,
+            in_size,
+            out_size,
+            exit_status,
         )
     }
 
-    fn getlk(&self) -> io::Result<()> {
-        self.0.getlk()
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        self.0.getlk(ctx, inode, handle, owner, lock, flags)
     }
 
-    fn setlk(&self) -> io::Result<()> {
-        self.0.setlk()
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.0.setlk(ctx, inode, handle, owner, lock, flags)
     }
 
-    fn setlkw(&self) -> io::Result<()> {
-        self.0.setlkw()
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.0.setlkw(ctx, inode, handle, owner, lock, flags)
     }
 
     fn bmap(&self) -> io::Result<()> {
@@ -13,14 +13,15 @@ use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem;
-use std::sync::atomic::AtomicI32;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use super::bindings;
 use super::fuse;
+use crate::virtio::SharedExitStatus;
 
 pub use super::fuse::FsOptions;
+pub use fuse::Advice;
 pub use fuse::OpenOptions;
 pub use fuse::RemovemappingOne;
 pub use fuse::SetattrValid;
@@ -442,6 +443,20 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
+    /// Get attributes for a file / directory, along with its creation time.
+    ///
+    /// Like `getattr`, but the creation time is also returned if the host filesystem records
+    /// one. The client only asks for this when a program wants more than `getattr` gives, and
+    /// falls back to `getattr` for good once this fails with `ENOSYS`.
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(bindings::stat64, Option<libc::timespec>, Duration)> {
+        Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
+    }
+
     /// Set attributes for a file / directory.
     ///
     /// If `handle` is not `None`, then it contains the handle previously returned by the
@@ -683,6 +698,25 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
+    /// Create and open an unnamed temporary file in the directory `parent`, like `O_TMPFILE`.
+    ///
+    /// The file has no name until it is given one with `link`, and goes away once the last handle
+    /// and lookup reference to it are dropped. Returns the same values as `create`.
+    ///
+    /// If the file system returns an `ENOSYS` error, then the kernel will fail all future
+    /// `O_TMPFILE` opens with `EOPNOTSUPP` without calling this method.
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: Self::Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
+    }
+
     /// Read data from a file.
     ///
     /// Returns `size` bytes of data starting from offset `off` from the file associated with
@@ -872,6 +906,15 @@ pub trait FileSystem {
         Ok(st)
     }
 
+    /// Synchronize the whole file system containing `inode` to disk.
+    ///
+    /// This is sent when the guest calls `syncfs(2)` on the mount, with `inode` being the root of
+    /// the mount. If the file system returns an `ENOSYS` error, then the kernel will treat this as
+    /// success and not send any further `syncfs` requests.
+    fn syncfs(&self, ctx: Context, inode: Self::Inode) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
+    }
+
     /// Set an extended attribute.
     ///
     /// If this method fails with an `ENOSYS` error, then the kernel will treat that as a permanent
@@ -1113,6 +1156,24 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
+    /// Apply an access pattern hint to a range of an open file, like `posix_fadvise`.
+    ///
+    /// A `len` of 0 covers everything from `offset` to the end of the file. The guest kernel keeps
+    /// its own hints to itself, so this is only called when a guest program sends them through
+    /// the fadvise ioctl. Hints are best effort: implementations that can't apply one should
+    /// succeed anyway.
+    fn fadvise(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn copyfilerange(
         &self,
@@ -1169,23 +1230,61 @@ pub trait FileSystem {
         arg: u64,
         in_size: u32,
         out_size: u32,
-        exit_code: &Arc<AtomicI32>,
+        exit_status: &Arc<SharedExitStatus>,
     ) -> io::Result<Vec<u8>> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
-    /// TODO: support this
-    fn getlk(&self) -> io::Result<()> {
+    /// Test for a POSIX record lock.
+    ///
+    /// Returns a lock held by a different owner that conflicts with `lock`, or `lock` with its
+    /// type set to `F_UNLCK` if there is no such lock. Lock types and ranges use the Linux encoding
+    /// (`end` is inclusive and `i64::MAX` means "until the end of the file").
+    ///
+    /// Only called when the `FsOptions::POSIX_LOCKS` feature is enabled.
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<fuse::FileLock> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlk(&self) -> io::Result<()> {
+    /// Acquire, modify or release a lock without waiting.
+    ///
+    /// If `flags` contains `fuse::LK_FLOCK` this is a BSD `flock` request for `handle` (only sent
+    /// when `FsOptions::FLOCK_LOCKS` is enabled), otherwise it is a POSIX record lock belonging to
+    /// `owner`. Returns `EAGAIN` if a conflicting lock is held.
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlkw(&self) -> io::Result<()> {
+    /// Acquire a lock, waiting for conflicting locks to be released.
+    ///
+    /// File systems must not block in this method. If the lock cannot be granted yet they should
+    /// return `EAGAIN`; the server then parks the request and retries it later without holding up
+    /// the rest of the queue.
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::io;
 use std::mem;
 
 use super::bindings;
@@ -708,6 +709,9 @@ pub enum Opcode {
     CopyFileRange = 47,
     SetupMapping = 48,
     RemoveMapping = 49,
+    Syncfs = 50,
+    Tmpfile = 51,
+    Statx = 52,
 }
 
 #[repr(u32)]
@@ -767,6 +771,103 @@ pub struct GetattrIn {
 }
 unsafe impl ByteValued for GetattrIn {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxIn {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+unsafe impl ByteValued for StatxIn {}
+
+// Bits of `Statx::mask`, the same as for statx(2).
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+pub const STATX_BTIME: u32 = 0x800;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SxTime {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+unsafe impl ByteValued for SxTime {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: u16,
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: SxTime,
+    pub btime: SxTime,
+    pub ctime: SxTime,
+    pub mtime: SxTime,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+unsafe impl ByteValued for Statx {}
+
+impl Statx {
+    /// Fills in the basic attributes from `st`, and the creation time if there is one.
+    pub fn new(st: bindings::stat64, btime: Option<libc::timespec>) -> Statx {
+        let attr = Attr::from(st);
+        let time = |tv_sec, tv_nsec| SxTime {
+            tv_sec,
+            tv_nsec,
+            reserved: 0,
+        };
+        let mut statx = Statx {
+            mask: STATX_BASIC_STATS,
+            blksize: attr.blksize,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            mode: attr.mode as u16,
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: time(attr.atime as i64, attr.atimensec),
+            ctime: time(attr.ctime as i64, attr.ctimensec),
+            mtime: time(attr.mtime as i64, attr.mtimensec),
+            // `Attr::rdev` has the guest's encoding of device numbers.
+            rdev_major: (attr.rdev >> 8) & 0xfff,
+            rdev_minor: (attr.rdev & 0xff) | ((attr.rdev >> 12) & 0xfff00),
+            ..Default::default()
+        };
+        if let Some(btime) = btime {
+            statx.mask |= STATX_BTIME;
+            statx.btime = time(btime.tv_sec, btime.tv_nsec as u32);
+        }
+        statx
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxOut {
+    pub attr_valid: u64, /* Cache timeout for the attributes */
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: Statx,
+}
+unsafe impl ByteValued for StatxOut {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct AttrOut {
@@ -1066,6 +1167,43 @@ pub struct BmapOut {
 }
 unsafe impl ByteValued for BmapOut {}
 
+/// An access pattern hint for a range of a file, as in `posix_fadvise`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    Random,
+    Sequential,
+    WillNeed,
+    DontNeed,
+    NoReuse,
+}
+
+impl Advice {
+    /// Decodes the guest's `POSIX_FADV_*` value.
+    pub fn from_guest(advice: u32) -> io::Result<Advice> {
+        match advice {
+            0 => Ok(Advice::Normal),
+            1 => Ok(Advice::Random),
+            2 => Ok(Advice::Sequential),
+            3 => Ok(Advice::WillNeed),
+            4 => Ok(Advice::DontNeed),
+            5 => Ok(Advice::NoReuse),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+/// The argument of the guest's `posix_fadvise` ioctl.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FadviseIn {
+    pub offset: u64,
+    pub len: u64,
+    pub advice: u32,
+    pub padding: u32,
+}
+unsafe impl ByteValued for FadviseIn {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct IoctlIn {
@@ -1253,6 +1391,13 @@ pub struct LseekOut {
 }
 unsafe impl ByteValued for LseekOut {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SyncfsIn {
+    pub padding: u64,
+}
+unsafe impl ByteValued for SyncfsIn {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct CopyfilerangeIn {
@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(target_os = "linux")]
+const DIR_FLAGS: libc::c_int =
+    libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+#[cfg(target_os = "macos")]
+const DIR_FLAGS: libc::c_int =
+    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+
+/// Entries of a shared directory that the guest can't see, nor create or rename anything onto.
+///
+/// Each one is kept as the identity of the directory holding it and its name there, rather than
+/// as a path, so it stays hidden however the guest renames the directories above it.
+#[derive(Debug, Default)]
+pub struct HiddenNames {
+    // Names hidden in each directory, by (device, inode number).
+    dirs: HashMap<(u64, u64), HashSet<Box<[u8]>>>,
+}
+
+impl HiddenNames {
+    /// Looks up the directories holding `paths`, each relative to `root`. Fails if one of them
+    /// doesn't exist or is a symlink, so that nothing is left visible by mistake.
+    pub fn resolve(root: &File, paths: &[PathBuf]) -> io::Result<Self> {
+        let mut dirs: HashMap<_, HashSet<_>> = HashMap::new();
+        for path in paths {
+            let (parents, name) = split_hidden_path(path).map_err(|e| {
+                error!("{e}");
+                io::Error::from_raw_os_error(libc::EINVAL)
+            })?;
+            let mut dir = None;
+            for parent in parents {
+                let parent = CString::new(parent.as_bytes())?;
+                let at = dir.as_ref().unwrap_or(root).as_raw_fd();
+                // Safe because this doesn't modify any memory and we check the return value.
+                let fd = unsafe { libc::openat(at, parent.as_ptr(), DIR_FLAGS) };
+                if fd < 0 {
+                    let e = io::Error::last_os_error();
+                    error!("Can't hide {}: {e}", path.display());
+                    return Err(e);
+                }
+                // Safe because we just opened this fd.
+                dir = Some(unsafe { File::from_raw_fd(fd) });
+            }
+            let st = fstat(dir.as_ref().unwrap_or(root))?;
+            dirs.entry((st.st_dev as u64, st.st_ino))
+                .or_default()
+                .insert(name.as_bytes().into());
+        }
+        Ok(HiddenNames { dirs })
+    }
+
+    /// The names hidden in the directory with the given device and inode number, if any.
+    pub fn in_dir(&self, dev: u64, ino: u64) -> Option<&HashSet<Box<[u8]>>> {
+        self.dirs.get(&(dev, ino))
+    }
+
+    /// Whether `name` is hidden in the directory with the given device and inode number.
+    pub fn contains(&self, dev: u64, ino: u64, name: &[u8]) -> bool {
+        self.in_dir(dev, ino)
+            .is_some_and(|names| names.contains(name))
+    }
+}
+
+/// Splits a path to hide, relative to the root of the share, into the directories leading to it
+/// and its name. Fails unless the path is relative and made only of names.
+pub fn split_hidden_path(path: &Path) -> io::Result<(Vec<&OsStr>, &OsStr)> {
+    let mut names = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => Ok(name),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: hidden paths must be relative to the share and can't contain `.` or `..`",
+                    path.display()
+                ),
+            )),
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let name = names.pop().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "hidden paths can't be empty")
+    })?;
+    Ok((names, name))
+}
+
+fn fstat(f: &File) -> io::Result<libc::stat> {
+    let mut st = std::mem::MaybeUninit::<libc::stat>::zeroed();
+    // Safe because the kernel only writes to `st` and we check the return value.
+    let res = unsafe { libc::fstat(f.as_raw_fd(), st.as_mut_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the kernel filled it in.
+    Ok(unsafe { st.assume_init() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_paths_are_plain_names() {
+        let (parents, name) = split_hidden_path(Path::new("a/b/.ssh")).unwrap();
+        assert_eq!(parents, [OsStr::new("a"), OsStr::new("b")]);
+        assert_eq!(name, ".ssh");
+
+        for bad in ["", "/etc", "../x", "a/../b", "./x"] {
+            assert!(split_hidden_path(Path::new(bad)).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+/// The id reported to the guest for host owners that fall outside every mapping. This matches the
+/// kernel's default `overflowuid`/`overflowgid`.
+pub const DEFAULT_OVERFLOW_ID: u32 = 65534;
+
+/// A contiguous range of ids shifted between the guest and the host, in the same form as a line
+/// of `/proc/<pid>/uid_map`: guest ids `guest_id..guest_id + count` correspond to host ids
+/// `host_id..host_id + count`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMapping {
+    pub guest_id: u32,
+    pub host_id: u32,
+    pub count: u32,
+}
+
+/// A set of uid or gid ranges translating ownership between the guest and the host.
+///
+/// An empty map is the identity, which is what a shared directory uses unless one is configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdMap {
+    mappings: Vec<IdMapping>,
+    overflow_id: u32,
+}
+
+impl IdMap {
+    /// Creates a map from `mappings`. Host ids outside every range are reported to the guest as
+    /// `DEFAULT_OVERFLOW_ID`.
+    pub fn new(mappings: Vec<IdMapping>) -> Self {
+        IdMap {
+            mappings,
+            overflow_id: DEFAULT_OVERFLOW_ID,
+        }
+    }
+
+    /// Sets the id reported to the guest for unmapped host ids.
+    pub fn with_overflow_id(mut self, overflow_id: u32) -> Self {
+        self.overflow_id = overflow_id;
+        self
+    }
+
+    /// Returns true if this map leaves ids untouched.
+    pub fn is_identity(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Translates a guest id into the host id it stands for, or `None` if no range covers it.
+    pub fn to_host(&self, guest_id: u32) -> Option<u32> {
+        if self.is_identity() {
+            return Some(guest_id);
+        }
+
+        self.mappings.iter().find_map(|m| {
+            let offset = guest_id.checked_sub(m.guest_id)?;
+            if offset < m.count {
+                m.host_id.checked_add(offset)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Translates a host id into the id the guest sees, falling back to the overflow id.
+    pub fn to_guest(&self, host_id: u32) -> u32 {
+        if self.is_identity() {
+            return host_id;
+        }
+
+        self.mappings
+            .iter()
+            .find_map(|m| {
+                let offset = host_id.checked_sub(m.host_id)?;
+                if offset < m.count {
+                    m.guest_id.checked_add(offset)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(self.overflow_id)
+    }
+}
+
+impl Default for IdMap {
+    fn default() -> Self {
+        IdMap::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> IdMap {
+        IdMap::new(vec![
+            IdMapping {
+                guest_id: 0,
+                host_id: 100000,
+                count: 1000,
+            },
+            IdMapping {
+                guest_id: 1000,
+                host_id: 501,
+                count: 1,
+            },
+        ])
+    }
+
+    #[test]
+    fn identity() {
+        let m = IdMap::default();
+        assert!(m.is_identity());
+        assert_eq!(m.to_host(1234), Some(1234));
+        assert_eq!(m.to_guest(1234), 1234);
+    }
+
+    #[test]
+    fn round_trip() {
+        let m = map();
+        for guest in [0, 1, 999, 1000] {
+            let host = m.to_host(guest).unwrap();
+            assert_eq!(m.to_guest(host), guest);
+        }
+        assert_eq!(m.to_host(0), Some(100000));
+        assert_eq!(m.to_host(1000), Some(501));
+    }
+
+    #[test]
+    fn overflow() {
+        let m = map();
+        assert_eq!(m.to_host(1001), None);
+        assert_eq!(m.to_guest(0), DEFAULT_OVERFLOW_ID);
+        assert_eq!(m.to_guest(101000), DEFAULT_OVERFLOW_ID);
+
+        let m = map().with_overflow_id(3000);
+        assert_eq!(m.to_guest(502), 3000);
+    }
+}
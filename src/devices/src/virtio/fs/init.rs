@@ -0,0 +1,114 @@
+//! Checks on the init binary the filesystems serve as `init.krun`.
+
+use std::io;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+#[cfg(target_arch = "x86_64")]
+const EM_HOST: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_HOST: u16 = 183;
+#[cfg(target_arch = "riscv64")]
+const EM_HOST: u16 = 243;
+
+/// Checks that `binary` is a 64-bit little-endian ELF file built for the architecture of the
+/// host, which is the one the guest runs.
+pub fn validate_init_binary(binary: &[u8]) -> io::Result<()> {
+    if binary.len() < 20 || &binary[..4] != ELF_MAGIC {
+        return Err(invalid("not an ELF file"));
+    }
+    if binary[4] != ELFCLASS64 || binary[5] != ELFDATA2LSB {
+        return Err(invalid("not a 64-bit little-endian ELF file"));
+    }
+    let machine = u16::from_le_bytes([binary[18], binary[19]]);
+    if machine != EM_HOST {
+        return Err(invalid(&format!(
+            "built for machine {machine}, expected {EM_HOST}"
+        )));
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid init binary: {msg}"),
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ffi::CString;
+
+    use utils::tempdir::TempDir;
+
+    use super::super::filesystem::{Context, FileSystem, FsOptions};
+    use super::super::fuse;
+    use super::super::passthrough::{Config, PassthroughFs};
+    use super::*;
+
+    /// The smallest file `validate_init_binary` accepts, followed by `rest`.
+    pub(crate) fn fake_init(rest: &[u8]) -> Vec<u8> {
+        let mut binary = vec![0; 20];
+        binary[..4].copy_from_slice(ELF_MAGIC);
+        binary[4] = ELFCLASS64;
+        binary[5] = ELFDATA2LSB;
+        binary[18..20].copy_from_slice(&EM_HOST.to_le_bytes());
+        binary.extend_from_slice(rest);
+        binary
+    }
+
+    #[test]
+    fn validate() {
+        validate_init_binary(&fake_init(b"")).unwrap();
+
+        let mut other_arch = fake_init(b"");
+        other_arch[18..20].copy_from_slice(&(EM_HOST + 1).to_le_bytes());
+        for binary in [&b"#!/bin/sh\n"[..], &other_arch] {
+            let err = validate_init_binary(binary).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    // Mounts a new temporary directory, returning it with the filesystem, a root caller's context
+    // and the inode of init.krun.
+    fn test_fs() -> (TempDir, PassthroughFs, Context, u64) {
+        let dir = TempDir::new().unwrap();
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let name = CString::new("init.krun").unwrap();
+        let init = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        (dir, fs, ctx, init)
+    }
+
+    #[test]
+    fn statfs() {
+        let (_dir, fs, ctx, inode) = test_fs();
+
+        // init.krun isn't in the inode table, but reports the filesystem it appears in.
+        let root = fs.statfs(ctx, fuse::ROOT_ID).unwrap();
+        let init = fs.statfs(ctx, inode).unwrap();
+        assert_eq!(init.f_fsid, root.f_fsid);
+        assert_eq!(init.f_bsize, root.f_bsize);
+        assert_eq!(init.f_blocks, root.f_blocks);
+        assert_eq!(init.f_namemax, root.f_namemax);
+    }
+
+    #[test]
+    fn syncfs() {
+        let (_dir, fs, ctx, init) = test_fs();
+        fs.syncfs(ctx, fuse::ROOT_ID).unwrap();
+        fs.syncfs(ctx, init).unwrap();
+    }
+}
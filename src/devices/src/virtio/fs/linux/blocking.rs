@@ -0,0 +1,308 @@
+// A small pool of threads that runs host syscalls which may block indefinitely, so that the caller
+// can give up on them after a timeout instead of hanging the virtio-fs worker.
+//
+// A thread stuck in a call that timed out is replaced by a new one, up to a cap, so that one hung
+// file doesn't make every later call on the share time out as well. Once the stuck call returns,
+// the extra thread exits. Calls wait in a bounded queue and fail with EAGAIN when it's full, so
+// the calls abandoned while all threads are stuck can't pile up without bound. A call that panics
+// fails with EIO, and the thread goes on with the next one.
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+
+use super::super::locks::MutexExt;
+
+/// How many threads stuck in calls that timed out get replaced at most.
+const MAX_REPLACEMENTS: usize = 16;
+/// How many calls can wait for a thread.
+const QUEUE_LEN: usize = 64;
+
+// The states of a call, which both the thread running it and the caller waiting on it move along.
+const QUEUED: u8 = 0;
+const RUNNING: u8 = 1;
+const DONE: u8 = 2;
+const ABANDONED: u8 = 3;
+
+struct Job {
+    state: Arc<AtomicU8>,
+    f: Box<dyn FnOnce() + Send + 'static>,
+}
+
+#[derive(Default)]
+struct Threads {
+    live: usize,
+    stuck: usize,
+    spawned: usize,
+}
+
+struct Shared {
+    base: usize,
+    max_replacements: usize,
+    threads: Mutex<Threads>,
+}
+
+impl Shared {
+    // Starts a thread if there are fewer than the base count plus one per stuck thread.
+    fn maybe_spawn(self: &Arc<Self>, receiver: &Receiver<Job>) -> io::Result<()> {
+        let mut threads = self.threads.lock_unpoisoned();
+        let wanted = self.base + threads.stuck.min(self.max_replacements);
+        if threads.live >= wanted {
+            return Ok(());
+        }
+
+        let shared = self.clone();
+        let receiver = receiver.clone();
+        thread::Builder::new()
+            .name(format!("fs blocking {}", threads.spawned))
+            .spawn(move || shared.work(receiver))?;
+        threads.live += 1;
+        threads.spawned += 1;
+        Ok(())
+    }
+
+    fn work(&self, receiver: Receiver<Job>) {
+        for job in receiver {
+            // Calls that were given up on while waiting aren't worth making anymore.
+            if job
+                .state
+                .compare_exchange(QUEUED, RUNNING, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                continue;
+            }
+            // The caller sees the result channel close and fails the call with EIO.
+            if panic::catch_unwind(AssertUnwindSafe(job.f)).is_err() {
+                error!("A blocking fs call panicked");
+            }
+            if job
+                .state
+                .compare_exchange(RUNNING, DONE, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                continue;
+            }
+
+            // The call timed out and another thread may have been started in our place, in which
+            // case there's one thread too many now.
+            let mut threads = self.threads.lock_unpoisoned();
+            threads.stuck -= 1;
+            if threads.live > self.base + threads.stuck.min(self.max_replacements) {
+                threads.live -= 1;
+                return;
+            }
+        }
+
+        self.threads.lock_unpoisoned().live -= 1;
+    }
+}
+
+pub struct BlockingPool {
+    jobs: Sender<Job>,
+    receiver: Receiver<Job>,
+    shared: Arc<Shared>,
+    timeout: Duration,
+}
+
+impl BlockingPool {
+    /// Starts `threads` threads that run the calls passed to `run`, each of which is waited on for
+    /// at most `timeout`. The threads exit once the pool is dropped and they're done with the call
+    /// they are running, if any.
+    pub fn new(threads: usize, timeout: Duration) -> io::Result<Self> {
+        Self::with_limits(threads, MAX_REPLACEMENTS, QUEUE_LEN, timeout)
+    }
+
+    fn with_limits(
+        threads: usize,
+        max_replacements: usize,
+        queue_len: usize,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let (jobs, receiver) = bounded::<Job>(queue_len);
+        let shared = Arc::new(Shared {
+            base: threads.max(1),
+            max_replacements,
+            threads: Default::default(),
+        });
+        for _ in 0..shared.base {
+            shared.maybe_spawn(&receiver)?;
+        }
+
+        Ok(BlockingPool {
+            jobs,
+            receiver,
+            shared,
+            timeout,
+        })
+    }
+
+    /// Runs `f` on the pool and returns its result, or `ETIMEDOUT` if it doesn't finish in time.
+    /// A call that finishes after that has its result dropped, which closes any fds in it. Fails
+    /// with `EAGAIN` without waiting if too many calls are queued already.
+    pub fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let (result, receiver) = bounded(1);
+        let state = Arc::new(AtomicU8::new(QUEUED));
+        let job = Job {
+            state: state.clone(),
+            f: Box::new(move || {
+                // Nobody is waiting anymore if this fails, so the result just gets dropped.
+                let _ = result.send(f());
+            }),
+        };
+        self.jobs.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => io::Error::from_raw_os_error(libc::EAGAIN),
+            TrySendError::Disconnected(_) => io::Error::from_raw_os_error(libc::EIO),
+        })?;
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(res) => return res,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::from_raw_os_error(libc::EIO))
+            }
+        }
+
+        // Holding the lock keeps the thread from counting itself unstuck before we count it stuck.
+        let mut threads = self.shared.threads.lock_unpoisoned();
+        match state.swap(ABANDONED, Ordering::AcqRel) {
+            RUNNING => {
+                threads.stuck += 1;
+                drop(threads);
+                if let Err(e) = self.shared.maybe_spawn(&self.receiver) {
+                    error!("Failed to replace a stuck blocking thread: {e}");
+                }
+            }
+            // It finished just as we gave up on it.
+            DONE => {
+                if let Ok(res) = receiver.try_recv() {
+                    return res;
+                }
+            }
+            _ => {}
+        }
+        Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn hang(pool: &BlockingPool, wait: Receiver<()>) {
+        let err = pool
+            .run(move || {
+                let _ = wait.recv();
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+    }
+
+    #[test]
+    fn late_results_are_dropped() {
+        let pool = BlockingPool::new(1, Duration::from_millis(50)).unwrap();
+        assert_eq!(pool.run(|| Ok(7)).unwrap(), 7);
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let (release, wait) = bounded::<()>(0);
+        let flag = DropFlag(dropped.clone());
+        let err = pool
+            .run(move || {
+                let _ = wait.recv();
+                Ok(flag)
+            })
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        // Let the stuck call finish; its result has nowhere to go and gets dropped.
+        release.send(()).unwrap();
+        assert_eq!(pool.run(|| Ok(8)).unwrap(), 8);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(Instant::now() < deadline, "late result not dropped");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn stuck_threads_are_replaced() {
+        let pool = BlockingPool::new(2, Duration::from_millis(50)).unwrap();
+        let (release, wait) = bounded::<()>(0);
+        hang(&pool, wait.clone());
+        hang(&pool, wait);
+
+        // Both threads are stuck, but their replacements take the call.
+        assert_eq!(pool.run(|| Ok(7)).unwrap(), 7);
+        assert_eq!(pool.shared.threads.lock_unpoisoned().live, 4);
+
+        // The extra threads go away once the stuck calls return.
+        drop(release);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.shared.threads.lock_unpoisoned().live != 2 {
+            assert!(Instant::now() < deadline, "extra threads didn't exit");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pool.run(|| Ok(8)).unwrap(), 8);
+    }
+
+    #[test]
+    fn panicking_calls_fail_with_eio() {
+        let pool = BlockingPool::new(1, Duration::from_secs(5)).unwrap();
+        let err = pool
+            .run(|| -> io::Result<()> { panic!("boom") })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+        // The thread is still there to take the next call.
+        assert_eq!(pool.run(|| Ok(7)).unwrap(), 7);
+        assert_eq!(pool.shared.threads.lock_unpoisoned().live, 1);
+    }
+
+    #[test]
+    fn full_queue_fails_fast() {
+        let timeout = Duration::from_millis(50);
+        let pool = BlockingPool::with_limits(1, 0, 1, timeout).unwrap();
+        let (release, wait) = bounded::<()>(0);
+        hang(&pool, wait);
+
+        // With no replacement, the next call waits in the queue until it times out and is left
+        // there, so the one after that finds the queue full.
+        let err = pool.run(|| Ok(())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+        let start = Instant::now();
+        let err = pool.run(|| Ok(())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        assert!(start.elapsed() < timeout);
+
+        // Once the thread is free, it skips the abandoned call and takes new ones.
+        drop(release);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match pool.run(|| Ok(9)) {
+                Ok(v) => break assert_eq!(v, 9),
+                Err(_) => assert!(Instant::now() < deadline, "pool didn't recover"),
+            }
+        }
+    }
+}
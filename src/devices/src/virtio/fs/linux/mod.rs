@@ -1,2 +1,4 @@
+mod blocking;
 pub mod fs_utils;
 pub mod passthrough;
+pub mod privileges;
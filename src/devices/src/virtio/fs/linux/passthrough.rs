@@ -2,17 +2,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::collections::btree_map;
+use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, size_of, MaybeUninit};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::fd::OwnedFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
 use std::time::Duration;
 
 use caps::{has_cap, CapSet, Capability};
@@ -20,38 +22,78 @@ use nix::{request_code_none, request_code_read};
 
 use vm_memory::ByteValued;
 
+use super::super::atime::AtimePolicy;
+use super::super::bindings;
 use super::super::filesystem::{
-    Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
-    ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    Advice, Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions,
+    GetxattrReply, ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
-use super::super::multikey::MultikeyBTreeMap;
+use super::super::hidden::HiddenNames;
+use super::super::idmap::IdMap;
+use super::super::locks::{MutexExt, RwLockExt};
+use super::super::metrics::{FsMetrics, FsMetricsSnapshot};
+use super::super::sharded::{ShardedMap, ShardedMultikeyMap};
+use super::super::xattrmap::{self, XattrRule};
+use super::blocking::BlockingPool;
+use crate::virtio::{ExitStatus, SharedExitStatus};
 
 const CURRENT_DIR_CSTR: &[u8] = b".\0";
 const PARENT_DIR_CSTR: &[u8] = b"..\0";
 const EMPTY_CSTR: &[u8] = b"\0";
 const PROC_CSTR: &[u8] = b"/proc/self/fd\0";
 const INIT_CSTR: &[u8] = b"init.krun\0";
+const SECURITY_CAPABILITY_CSTR: &[u8] = b"security.capability\0";
+const POSIX_ACL_ACCESS_CSTR: &[u8] = b"system.posix_acl_access\0";
+const POSIX_ACL_DEFAULT_CSTR: &[u8] = b"system.posix_acl_default\0";
 
 static INIT_BINARY: &[u8] = include_bytes!("../../../../init");
 
 type Inode = u64;
 type Handle = u64;
 
-#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
-struct InodeAltKey {
-    ino: libc::ino64_t,
-    dev: libc::dev_t,
-    mnt_id: u64,
+// Where mount ids are unknown (see `MntIds::Unknown`) every `mnt_id` here is 0, so inodes are
+// only told apart by their device and inode number or file handle.
+#[derive(Clone, PartialOrd, Ord, PartialEq, Eq)]
+enum InodeAltKey {
+    Ids {
+        ino: libc::ino64_t,
+        dev: libc::dev_t,
+        mnt_id: u64,
+    },
+    // Inodes tracked by file handle are told apart by it, so that a reused inode number doesn't
+    // match the file that had it before.
+    Handle {
+        mnt_id: u64,
+        handle: Box<[u8]>,
+    },
 }
 
 struct InodeData {
     inode: Inode,
-    // Most of these aren't actually files but ¯\_(ツ)_/¯.
-    file: File,
+    // Most of these aren't actually files but ¯\_(ツ)_/¯. `None` while the fd is closed to stay
+    // under `Config::max_inode_fds`, or always with `handle`; use `PassthroughFs::inode_file` to
+    // get it.
+    file: Mutex<Option<Arc<File>>>,
+    // With `Config::inode_file_handles`, the kernel file handle the fd is reopened from for each
+    // use instead of being kept open.
+    handle: Option<Vec<u8>>,
+    // The directory the inode was last looked up in and its name there, which `file` is reopened
+    // from after being closed to stay under `Config::max_inode_fds`. Only inodes that have one are
+    // ever closed.
+    origin: Mutex<Option<(Arc<InodeData>, CString)>>,
+    // A mount point shown as an empty directory under `Config::one_filesystem`. Nothing in it can
+    // be looked up, listed or created, and it can't be changed.
+    sealed: bool,
+    ino: libc::ino64_t,
     dev: u64,
     mnt_id: u64,
     refcount: AtomicU64,
+    // Number of entries in `handles` for this inode.
+    open_handles: AtomicU64,
+    // When `file` was last used, as a tick of `PassthroughFs::inode_fd_clock`. Only kept up to date
+    // with `Config::max_inode_fds`.
+    last_used: AtomicU64,
 }
 
 struct HandleData {
@@ -188,6 +230,54 @@ fn einval() -> io::Error {
     io::Error::from_raw_os_error(libc::EINVAL)
 }
 
+// Whether names are resolved with `openat2`, until a call finds the kernel doesn't have it.
+static OPENAT2: AtomicBool = AtomicBool::new(true);
+
+// Opens `name` in the directory `dir` without following a symlink in its place. Where the kernel
+// has `openat2`, `name` must also resolve to something beneath `dir`, without going through `..`,
+// absolute or magic links, so a name holding a path can't lead out of the share.
+fn open_beneath(dir: &File, name: &CStr, flags: i32, mode: u32) -> io::Result<File> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    if OPENAT2.load(Ordering::Relaxed) {
+        // Safe because `open_how` is plain data, for which zero is a valid value.
+        let mut how: libc::open_how = unsafe { mem::zeroed() };
+        how.flags = flags as u64;
+        if flags & libc::O_CREAT != 0 {
+            how.mode = u64::from(mode & 0o7777);
+        }
+        how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                &how as *const libc::open_how,
+                size_of::<libc::open_how>(),
+            )
+        };
+        if fd >= 0 {
+            // Safe because we just opened this fd.
+            return Ok(unsafe { File::from_raw_fd(fd as RawFd) });
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ENOSYS) {
+            return Err(e);
+        }
+        if OPENAT2.swap(false, Ordering::Relaxed) {
+            warn!("passthroughfs: openat2 isn't available, resolving names with O_NOFOLLOW only");
+        }
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
 fn stat(f: &File) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -212,7 +302,260 @@ fn stat(f: &File) -> io::Result<libc::stat64> {
     }
 }
 
-fn statx(f: &File) -> io::Result<(libc::stat64, u64)> {
+// Host inode number of the directory containing `dir`.
+fn parent_ino(dir: &File) -> io::Result<libc::ino64_t> {
+    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+    // Safe because this is a constant value and a valid C string.
+    let pathname = unsafe { CStr::from_bytes_with_nul_unchecked(PARENT_DIR_CSTR) };
+
+    // Safe because the kernel will only write data in `st` and we check the return value.
+    let res = unsafe {
+        libc::fstatat64(
+            dir.as_raw_fd(),
+            pathname.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res >= 0 {
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        Ok(unsafe { st.assume_init() }.st_ino)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Copies up to `len` bytes from `fd_in` at `offset_in` to `fd_out` at `offset_out` with a
+// read/write loop, for when `copy_file_range` can't do it. Returns the number of bytes copied,
+// which is short only if the end of `fd_in` is reached.
+fn copy_range_fallback(
+    fd_in: RawFd,
+    mut offset_in: u64,
+    fd_out: RawFd,
+    mut offset_out: u64,
+    len: u64,
+) -> io::Result<usize> {
+    const BUF_SIZE: u64 = 128 * 1024;
+
+    let mut buf = vec![0u8; len.min(BUF_SIZE) as usize];
+    let mut copied = 0u64;
+    while copied < len {
+        let want = (len - copied).min(BUF_SIZE) as usize;
+
+        // Safe because this will only write into `buf`, which is at least `want` bytes long, and
+        // we check the return value.
+        let nread = unsafe {
+            libc::pread64(
+                fd_in,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                want,
+                offset_in as libc::off64_t,
+            )
+        };
+        if nread < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if nread == 0 {
+            break;
+        }
+
+        let mut written = 0;
+        while written < nread as usize {
+            // Safe because this only reads from `buf` within the bytes read above and we check
+            // the return value.
+            let res = unsafe {
+                libc::pwrite64(
+                    fd_out,
+                    buf[written..].as_ptr() as *const libc::c_void,
+                    nread as usize - written,
+                    (offset_out + written as u64) as libc::off64_t,
+                )
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Report what made it to the destination, like a short write would.
+                if copied > 0 || written > 0 {
+                    return Ok((copied + written as u64) as usize);
+                }
+                return Err(err);
+            }
+            written += res as usize;
+        }
+
+        offset_in += nread as u64;
+        offset_out += nread as u64;
+        copied += nread as u64;
+    }
+
+    Ok(copied as usize)
+}
+
+// The largest file handle the kernel hands out (`MAX_HANDLE_SZ`).
+const MAX_HANDLE_SZ: usize = 128;
+
+// Returns the kernel file handle of `f` as a `struct file_handle`, ready to be passed to
+// `open_by_handle_at`.
+fn file_handle(f: &File) -> io::Result<Vec<u8>> {
+    // `struct file_handle` is a `handle_bytes` and a `handle_type` followed by the handle itself.
+    let header = 2 * size_of::<u32>();
+    let mut buf = vec![0u8; header + MAX_HANDLE_SZ];
+    buf[..size_of::<u32>()].copy_from_slice(&(MAX_HANDLE_SZ as u32).to_ne_bytes());
+    let mut mount_id: libc::c_int = 0;
+
+    // Safe because this is a constant value and a valid C string.
+    let pathname = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+
+    // Safe because the kernel will only write to `buf` and `mount_id`, both of which are large
+    // enough, and we check the return value.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            f.as_raw_fd(),
+            pathname.as_ptr(),
+            buf.as_mut_ptr(),
+            &mut mount_id,
+            libc::AT_EMPTY_PATH,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let len = u32::from_ne_bytes(buf[..size_of::<u32>()].try_into().unwrap()) as usize;
+    buf.truncate(header + len);
+
+    Ok(buf)
+}
+
+// Opens the fd that `open_by_handle_at` needs to reopen files under `root_file`, and checks that
+// the root's own file handle can be turned back into an fd with it.
+fn open_mount_fd(root_file: &File) -> io::Result<File> {
+    let handle = file_handle(root_file)?;
+
+    // Safe because this is a constant value and a valid C string.
+    let current_dir = unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR) };
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe {
+        libc::openat(
+            root_file.as_raw_fd(),
+            current_dir.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this fd.
+    let mount_fd = unsafe { File::from_raw_fd(fd) };
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            mount_fd.as_raw_fd(),
+            handle.as_ptr(),
+            libc::O_PATH | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this fd and nothing else owns it.
+    drop(unsafe { File::from_raw_fd(fd as RawFd) });
+
+    Ok(mount_fd)
+}
+
+// Whether inodes under `root_dir` can be tracked by file handle, warning about why not otherwise.
+fn probe_file_handles(root_dir: &str) -> bool {
+    if !has_cap(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH).unwrap_or_default() {
+        warn!("passthroughfs: no CAP_DAC_READ_SEARCH, keeping an fd open for each inode");
+        return false;
+    }
+    let res = File::open(root_dir).and_then(|root| open_mount_fd(&root));
+    if let Err(e) = res {
+        warn!("passthroughfs: {root_dir} doesn't support file handles, keeping an fd open for each inode: {e}");
+        return false;
+    }
+    true
+}
+
+// Where the mount id of an inode comes from, found out once by `probe_mnt_ids`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MntIds {
+    // `statx` reports `STATX_MNT_ID`, since Linux 5.8.
+    Statx,
+    // Older kernels only show it in `/proc/self/fdinfo`.
+    Fdinfo,
+    // Neither works, so every inode is on mount 0 and only device numbers tell mounts apart.
+    Unknown,
+}
+
+// Finds out how the mount ids of inodes under `root_dir` can be had, warning if not from `statx`.
+fn probe_mnt_ids(root_dir: &str, proc_self_fd: &File) -> MntIds {
+    let Ok(root) = File::open(root_dir) else {
+        // `init` will fail to open it too, and say why.
+        return MntIds::Statx;
+    };
+    if statx(&root, MntIds::Statx, proc_self_fd).is_ok() {
+        return MntIds::Statx;
+    }
+    match fdinfo_mnt_id(&root, proc_self_fd) {
+        Ok(_) => {
+            warn!(
+                "passthroughfs: statx can't report mount ids, reading them from /proc/self/fdinfo"
+            );
+            MntIds::Fdinfo
+        }
+        Err(e) => {
+            warn!("passthroughfs: can't find mount ids, telling mounts apart by device only: {e}");
+            MntIds::Unknown
+        }
+    }
+}
+
+// Reads the id of the mount `f` is on from `/proc/self/fdinfo`, next to `proc_self_fd`.
+fn fdinfo_mnt_id(f: &File, proc_self_fd: &File) -> io::Result<u64> {
+    let name = CString::new(format!("../fdinfo/{}", f.as_raw_fd()))?;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe {
+        libc::openat(
+            proc_self_fd.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just opened this fd.
+    let fdinfo = io::read_to_string(unsafe { File::from_raw_fd(fd) })?;
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("mnt_id:"))
+        .and_then(|id| id.trim().parse().ok())
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+// Returns the attributes of `f` and the id of the mount it's on, or 0 if that's unknown.
+fn statx(f: &File, mnt_ids: MntIds, proc_self_fd: &File) -> io::Result<(libc::stat64, u64)> {
+    match mnt_ids {
+        MntIds::Statx => {}
+        MntIds::Fdinfo => return Ok((stat(f)?, fdinfo_mnt_id(f, proc_self_fd)?)),
+        MntIds::Unknown => return Ok((stat(f)?, 0)),
+    }
+
     let mut stx = MaybeUninit::<libc::statx>::zeroed();
 
     // Safe because this is a constant value and a valid C string.
@@ -232,6 +575,9 @@ fn statx(f: &File) -> io::Result<(libc::stat64, u64)> {
     if res >= 0 {
         // Safe because the kernel guarantees that the struct is now fully initialized.
         let stx = unsafe { stx.assume_init() };
+        if stx.stx_mask & libc::STATX_MNT_ID == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
 
         // Unfortunately, we cannot use an initializer to create the stat64 object,
         // because it may contain padding and reserved fields (depending on the
@@ -262,6 +608,207 @@ fn statx(f: &File) -> io::Result<(libc::stat64, u64)> {
     }
 }
 
+// Whether `name` in the directory `dir` is on another mount than `dir_data`, the inode of `dir`.
+// Mount points aren't followed, nor automounts triggered, to find out, unless `statx` can't report
+// mount ids and `name` has to be opened instead.
+fn on_other_mount(
+    dir: &File,
+    name: &[u8],
+    dir_data: &InodeData,
+    mnt_ids: MntIds,
+    proc_self_fd: &File,
+) -> bool {
+    let Ok(name) = CString::new(name) else {
+        return false;
+    };
+    if mnt_ids != MntIds::Statx {
+        let Ok(f) = open_beneath(dir, &name, libc::O_PATH, 0) else {
+            return false;
+        };
+        return statx(&f, mnt_ids, proc_self_fd)
+            .is_ok_and(|(st, mnt_id)| st.st_dev != dir_data.dev || mnt_id != dir_data.mnt_id);
+    }
+    let mut stx = MaybeUninit::<libc::statx>::zeroed();
+    // Safe because the kernel will only write data in `stx` and we check the return value.
+    let res = unsafe {
+        libc::statx(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_NO_AUTOMOUNT,
+            libc::STATX_MNT_ID,
+            stx.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return false;
+    }
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    let stx = unsafe { stx.assume_init() };
+    let dev = libc::makedev(stx.stx_dev_major, stx.stx_dev_minor);
+    dev != dir_data.dev || stx.stx_mnt_id != dir_data.mnt_id
+}
+
+// Raises the `st_blksize` of a regular file to the alignment its filesystem needs for direct
+// I/O, where the kernel reports it (Linux 6.1 and newer), so the guest sizes its I/O to fit.
+fn fit_blksize_to_direct_io(f: &File, st: &mut libc::stat64) {
+    if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return;
+    }
+    let mut stx = MaybeUninit::<libc::statx>::zeroed();
+    // Safe because the kernel will only write data in `stx` and we check the return value.
+    let res = unsafe {
+        libc::statx(
+            f.as_raw_fd(),
+            EMPTY_CSTR.as_ptr() as *const libc::c_char,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            libc::STATX_DIOALIGN,
+            stx.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return;
+    }
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    let stx = unsafe { stx.assume_init() };
+    if stx.stx_mask & libc::STATX_DIOALIGN != 0 {
+        st.st_blksize = st.st_blksize.max(stx.stx_dio_offset_align as _);
+    }
+}
+
+// Returns the creation time of `f`, if its filesystem records one.
+fn btime(f: &File) -> Option<libc::timespec> {
+    let mut stx = MaybeUninit::<libc::statx>::zeroed();
+    // Safe because the kernel will only write data in `stx` and we check the return value.
+    let res = unsafe {
+        libc::statx(
+            f.as_raw_fd(),
+            EMPTY_CSTR.as_ptr() as *const libc::c_char,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            libc::STATX_BTIME,
+            stx.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return None;
+    }
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    let stx = unsafe { stx.assume_init() };
+    (stx.stx_mask & libc::STATX_BTIME != 0).then_some(libc::timespec {
+        tv_sec: stx.stx_btime.tv_sec,
+        tv_nsec: stx.stx_btime.tv_nsec.into(),
+    })
+}
+
+fn host_advice(advice: Advice) -> libc::c_int {
+    match advice {
+        Advice::Normal => libc::POSIX_FADV_NORMAL,
+        Advice::Random => libc::POSIX_FADV_RANDOM,
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        Advice::NoReuse => libc::POSIX_FADV_NOREUSE,
+    }
+}
+
+// Clears the setuid and setgid bits of the regular file `fd` and removes its file capabilities,
+// like the kernel does when an unprivileged process modifies the file. The setgid bit is only
+// cleared if the group executable bit is set.
+fn remove_privs(fd: RawFd) -> io::Result<()> {
+    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+    // Safe because the kernel will only write data in `st` and we check the return value.
+    let res = unsafe { libc::fstat64(fd, st.as_mut_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    let mode = unsafe { st.assume_init() }.st_mode;
+    if mode & libc::S_IFMT != libc::S_IFREG {
+        return Ok(());
+    }
+
+    let mut new_mode = mode & !libc::S_ISUID;
+    if mode & libc::S_IXGRP != 0 {
+        new_mode &= !libc::S_ISGID;
+    }
+
+    if new_mode != mode {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::fchmod(fd, new_mode) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res =
+        unsafe { libc::fremovexattr(fd, SECURITY_CAPABILITY_CSTR.as_ptr() as *const libc::c_char) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        // The file not having capabilities, or not supporting them at all, is fine. Without
+        // CAP_SETFCAP we can't remove them, but then the kernel already did during the truncation.
+        if !matches!(
+            err.raw_os_error(),
+            Some(libc::ENODATA | libc::EOPNOTSUPP | libc::EPERM)
+        ) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+// The error returned by the xattr operations when xattr support is disabled. ACLs are reported as
+// unsupported like they would be by a host file system without ACL support, while ENOSYS makes the
+// guest stop sending any other xattr requests.
+fn xattr_disabled(name: &CStr) -> io::Error {
+    let name = name.to_bytes_with_nul();
+    if name == POSIX_ACL_ACCESS_CSTR || name == POSIX_ACL_DEFAULT_CSTR {
+        io::Error::from_raw_os_error(libc::EOPNOTSUPP)
+    } else {
+        io::Error::from_raw_os_error(libc::ENOSYS)
+    }
+}
+
+// Converts a FUSE lock request into a `flock64` suitable for the `F_OFD_*` fcntl commands.
+fn fuse_to_flock(lock: &fuse::FileLock) -> io::Result<libc::flock64> {
+    if lock.start > i64::MAX as u64 || lock.end < lock.start {
+        return Err(einval());
+    }
+
+    // Safe because `flock64` is a plain C struct for which all zeroes is a valid value. OFD locks
+    // require `l_pid` to be 0.
+    let mut fl: libc::flock64 = unsafe { mem::zeroed() };
+    fl.l_type = lock.type_ as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = lock.start as i64;
+    // FUSE uses an inclusive end offset where `OFFSET_MAX` means "up to the end of the file".
+    fl.l_len = if lock.end >= i64::MAX as u64 {
+        0
+    } else {
+        (lock.end - lock.start + 1) as i64
+    };
+
+    Ok(fl)
+}
+
+fn flock_to_fuse(fl: &libc::flock64) -> fuse::FileLock {
+    let end = if fl.l_len == 0 {
+        i64::MAX as u64
+    } else {
+        (fl.l_start + fl.l_len - 1) as u64
+    };
+
+    // OFD locks aren't owned by a process, so there's no meaningful pid to report.
+    fuse::FileLock {
+        start: fl.l_start as u64,
+        end,
+        type_: fl.l_type as u32,
+        pid: 0,
+    }
+}
+
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
 /// invalidated the next time that file is opened.
@@ -297,6 +844,19 @@ impl FromStr for CachePolicy {
     }
 }
 
+/// How the names the guest looks up or creates are resolved, as returned by
+/// `PassthroughFs::resolve_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolveMode {
+    /// With `openat2` and `RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS`: a name can't lead out of the
+    /// directory it's in, even if it holds a path through a symlink or `..`. Needs Linux 5.6.
+    Beneath,
+
+    /// With `openat` and `O_NOFOLLOW`, which only keeps a symlink in the name's own place from
+    /// being followed.
+    NoFollow,
+}
+
 /// Options that configure the behavior of the file system.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -345,18 +905,140 @@ pub struct Config {
     /// The default value for this options is `false`.
     pub xattr: bool,
 
-    /// Optional file descriptor for /proc/self/fd. Callers can obtain a file descriptor and pass it
-    /// here, so there's no need to open it in PassthroughFs::new(). This is specially useful for
-    /// sandboxing.
+    /// Whether the file system should support POSIX ACLs. The guest kernel enforces ACLs stored in
+    /// the `system.posix_acl_*` xattrs, and default ACLs on host directories are inherited by the
+    /// entries the guest creates in them. Requires `xattr`.
+    ///
+    /// The default value for this option is `false`.
+    pub posix_acl: bool,
+
+    /// Translation between guest uids and the uids owning files on the host. Ownership reported to
+    /// the guest is shifted through it, and so are the ids of files the guest creates or chowns.
+    /// Ids stored inside xattrs, such as ACL entries, are passed through as they are.
+    ///
+    /// The default is the identity map.
+    pub uid_map: IdMap,
+
+    /// Same as `uid_map`, for gids.
+    ///
+    /// The default is the identity map.
+    pub gid_map: IdMap,
+
+    /// Which access time updates reach the host. With `AtimePolicy::NoAtime`, files are also opened with
+    /// `O_NOATIME` when we have `CAP_FOWNER`, so reading them doesn't update their atime either.
+    ///
+    /// The default is `AtimePolicy::Strict`.
+    pub atime: AtimePolicy,
+
+    /// The maximum number of `O_PATH` fds kept open for inodes the guest has looked up. Past it,
+    /// the least recently used ones are closed, and reopened when needed again by the name they
+    /// were last looked up under, starting from their parent directory's fd, which is reopened the
+    /// same way if it was closed too. Inodes with open handles, mount points, and inodes that lost
+    /// their name to an unlink or rename stay open. A reopened name that now refers to another
+    /// file fails with `ENOENT`, as does one removed behind the guest's back.
+    ///
+    /// The default is `None`, which keeps every fd open.
+    pub max_inode_fds: Option<usize>,
+
+    /// Track inodes by their kernel file handle instead of keeping an `O_PATH` fd open for each,
+    /// and reopen them only for the duration of each request. The number of fds held then grows
+    /// with the number of open files rather than with the number of files the guest has looked
+    /// up. Needs `CAP_DAC_READ_SEARCH` and a filesystem that supports file handles; without them,
+    /// or for inodes on other mounts than the root, fds are kept open as usual. Requests on a file
+    /// removed since it was looked up fail with `ENOENT`.
+    ///
+    /// The default value for this option is `false`.
+    pub inode_file_handles: bool,
+
+    /// How long to wait for host calls that may block indefinitely, such as looking up, stat'ing
+    /// or opening files on a hung network mount. Those calls run on a pool of `blocking_threads`
+    /// threads and the request fails with `ETIMEDOUT` if they don't finish in time, so the rest of
+    /// the share keeps working.
+    ///
+    /// The default is `None`, which runs them directly and waits for as long as they take.
+    pub op_timeout: Option<Duration>,
+
+    /// The number of threads running calls guarded by `op_timeout`. A thread stuck in a call that
+    /// timed out is replaced by a new one until the call returns, up to 16 extra threads.
+    ///
+    /// The default is 2.
+    pub blocking_threads: usize,
+
+    /// An open `/proc/self/fd` directory, so there's no need to open it in `PassthroughFs::new()`.
+    /// Everything the file system does through `/proc` goes through it, which lets it run where
+    /// `/proc` isn't mounted, like a sandbox that dropped it after `pivot_root`. It's duplicated,
+    /// so the caller's fd stays open and can be shared by several file systems.
     ///
     /// The default is `None`.
-    pub proc_sfd_rawfd: Option<RawFd>,
+    pub proc_self_fd: Option<Arc<OwnedFd>>,
 
     /// ID of this filesystem to uniquely identify exports.
     pub export_fsid: u64,
     /// Table of exported FDs to share with other subsystems.
     pub export_table: Option<ExportTable>,
     pub allow_root_dir_delete: bool,
+
+    /// Counters of the operations this filesystem serves. Keep a clone of the `Arc` to read them
+    /// while the filesystem is in use.
+    ///
+    /// The default is a new, zeroed set of counters.
+    pub metrics: Arc<FsMetrics>,
+
+    /// What to serve as `init.krun` instead of the init binary built into the library. Check it
+    /// with `validate_init_binary` first.
+    ///
+    /// The default is `None`, serving the built-in one.
+    pub init_binary: Option<Vec<u8>>,
+
+    /// Paths, relative to `root_dir`, that the guest can't see: looking them up fails with
+    /// `ENOENT`, they're left out of directory listings, and creating or renaming anything onto
+    /// them fails with `EPERM`. The directories leading to them must exist when the guest mounts
+    /// the share, and they stay hidden if the guest renames those directories.
+    ///
+    /// The default is empty.
+    pub hidden: Vec<PathBuf>,
+
+    /// How extended attribute names are translated between the guest and the host, for example to
+    /// store the guest's `trusted.*` attributes under `user.*` on a host where we aren't
+    /// privileged. Listings show the guest's names.
+    ///
+    /// The default is empty, which leaves names as they are.
+    pub xattr_map: Vec<XattrRule>,
+
+    /// Whether the guest is kept on the host mount holding `root_dir`, like `find -xdev`. Looking
+    /// up anything mounted beneath it fails with `ENOENT`, and directory listings leave it out, so
+    /// sharing `/` doesn't also share `/proc`, `/sys` or network mounts.
+    ///
+    /// The default value for this option is `false`.
+    pub one_filesystem: bool,
+
+    /// With `one_filesystem`, show directories that other filesystems are mounted on as empty
+    /// directories instead of hiding them, for guests that expect them to exist.
+    ///
+    /// The default value for this option is `false`.
+    pub empty_mount_points: bool,
+
+    /// Whether the guest is kept from changing anything in the share, whatever the host
+    /// permissions would allow. Operations that would change it fail with `EROFS`, and files
+    /// are only opened for reading.
+    ///
+    /// The default value for this option is `false`.
+    pub read_only: bool,
+
+    /// Whether files the guest opens with `O_DIRECT` are opened with it on the host too, so
+    /// their I/O bypasses the host's page cache. Regular files then report the alignment direct
+    /// I/O needs on the host as their `st_blksize`. Otherwise the flag is dropped, and counted in
+    /// `FsMetricsSnapshot::direct_io_dropped`.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_direct_io: bool,
+
+    /// Whether the worker threads serving the file system confine themselves to `root_dir` with
+    /// Landlock, so the host kernel refuses to open anything outside it for them.
+    ///
+    /// The default value for this option is `false`.
+    #[cfg(feature = "landlock")]
+    pub landlock: bool,
 }
 
 impl Default for Config {
@@ -368,10 +1050,28 @@ impl Default for Config {
             writeback: false,
             root_dir: String::from("/"),
             xattr: true,
-            proc_sfd_rawfd: None,
+            posix_acl: false,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            atime: AtimePolicy::default(),
+            max_inode_fds: None,
+            inode_file_handles: false,
+            op_timeout: None,
+            blocking_threads: 2,
+            proc_self_fd: None,
             export_fsid: 0,
             export_table: None,
             allow_root_dir_delete: false,
+            metrics: Arc::new(FsMetrics::new()),
+            init_binary: None,
+            hidden: Vec::new(),
+            xattr_map: Vec::new(),
+            one_filesystem: false,
+            empty_mount_points: false,
+            read_only: false,
+            allow_direct_io: false,
+            #[cfg(feature = "landlock")]
+            landlock: false,
         }
     }
 }
@@ -385,30 +1085,67 @@ pub struct PassthroughFs {
     // File descriptors for various points in the file system tree. These fds are always opened with
     // the `O_PATH` option so they cannot be used for reading or writing any data. See the
     // documentation of the `O_PATH` flag in `open(2)` for more details on what one can and cannot
-    // do with an fd opened with this flag.
-    inodes: RwLock<MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>>,
+    // do with an fd opened with this flag. Both tables are sharded so that requests for different
+    // files rarely contend on the same lock.
+    inodes: ShardedMultikeyMap<Inode, InodeAltKey, Arc<InodeData>>,
     next_inode: AtomicU64,
     init_inode: u64,
 
     // File descriptors for open files and directories. Unlike the fds in `inodes`, these _can_ be
     // used for reading and writing data.
-    handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    handles: ShardedMap<Handle, Arc<HandleData>>,
+
+    // The number of inode fds currently open, the clock their uses are timed by to find the least
+    // recently used ones, and a lock held while closing those once over `Config::max_inode_fds`.
+    open_inode_fds: AtomicUsize,
+    inode_fd_clock: AtomicU64,
+    evicting_inode_fds: Mutex<()>,
+
+    // A regular (not `O_PATH`) fd for the root directory, which `open_by_handle_at` needs to
+    // reopen inodes tracked by file handle. Only set with `Config::inode_file_handles`.
+    mount_fd: RwLock<Option<File>>,
+
+    // Whether `Config::inode_file_handles` is in effect.
+    file_handles: bool,
+
+    // How the mount ids of inodes are found.
+    mnt_ids: MntIds,
+
     next_handle: AtomicU64,
     init_handle: u64,
 
+    // Files holding the POSIX locks of each (inode, lock owner) pair. See `posix_lock_file`.
+    posix_locks: Mutex<BTreeMap<(Inode, u64), Arc<File>>>,
+
     // File descriptor pointing to the `/proc/self/fd` directory. This is used to convert an fd from
     // `inodes` into one that can go into `handles`. This is accomplished by reading the
     // `/proc/self/fd/{}` symlink. We keep an open fd here in case the file system tree that we are
     // meant to be serving doesn't have access to `/proc/self/fd`.
-    proc_self_fd: File,
+    proc_self_fd: Arc<File>,
+
+    // Runs the host calls guarded by `Config::op_timeout`, if set.
+    blocking: Option<BlockingPool>,
 
     // Whether writeback caching is enabled for this directory. This will only be true when
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
     announce_submounts: AtomicBool,
+
+    // Whether POSIX ACLs are enabled. This will only be true when `cfg.posix_acl` and `cfg.xattr`
+    // are true and `init` was called with `FsOptions::POSIX_ACL`.
+    posix_acl: AtomicBool,
     my_uid: Option<libc::uid_t>,
     my_gid: Option<libc::gid_t>,
     cap_fowner: bool,
+    cap_fsetid: bool,
+
+    // Whether the guest relies on us to clear the setuid and setgid bits of files it modifies.
+    // This will only be true when we have CAP_FSETID, so the kernel leaves them alone unless we
+    // drop it, and `init` was called with `FsOptions::HANDLE_KILLPRIV_V2`.
+    killpriv_v2: AtomicBool,
+
+    // `Config::hidden`, resolved when the guest mounts the share.
+    hidden: RwLock<HiddenNames>,
 
     cfg: Config,
 }
@@ -417,13 +1154,15 @@ pub struct PassthroughFs {
 /// This enum encodes a fallback to handle those symlinks separately.
 enum FileOrLink {
     File(File),
-    Link(CString),
+    // The name of an fd in `/proc/self/fd` along with the fd, which must stay open while the name
+    // is in use. See `PassthroughFs::in_proc_self_fd`.
+    Link(CString, Arc<File>),
 }
 
 impl PassthroughFs {
     pub fn new(cfg: Config) -> io::Result<PassthroughFs> {
-        let fd = if let Some(fd) = cfg.proc_sfd_rawfd {
-            fd
+        let fd = if let Some(fd) = &cfg.proc_self_fd {
+            fd.try_clone()?.into_raw_fd()
         } else {
             // Safe because this is a constant value and a valid C string.
             let proc_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_CSTR) };
@@ -461,47 +1200,312 @@ impl PassthroughFs {
 
         let cap_fowner =
             has_cap(None, CapSet::Effective, Capability::CAP_FOWNER).unwrap_or_default();
+        let cap_fsetid =
+            has_cap(None, CapSet::Effective, Capability::CAP_FSETID).unwrap_or_default();
 
         // Safe because we just opened this fd or it was provided by our caller.
-        let proc_self_fd = unsafe { File::from_raw_fd(fd) };
+        let proc_self_fd = Arc::new(unsafe { File::from_raw_fd(fd) });
+
+        // Finds out whether the kernel has `openat2`, so `resolve_mode` is right from the start.
+        // Safe because this is a constant value and a valid C string.
+        let current_dir = unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR) };
+        if let Err(e) = open_beneath(&proc_self_fd, current_dir, libc::O_PATH, 0) {
+            warn!("passthroughfs: can't open /proc/self/fd: {e}");
+        }
+
+        let file_handles = cfg.inode_file_handles && probe_file_handles(&cfg.root_dir);
+        let mnt_ids = probe_mnt_ids(&cfg.root_dir, &proc_self_fd);
+
+        let blocking = cfg
+            .op_timeout
+            .map(|timeout| BlockingPool::new(cfg.blocking_threads, timeout))
+            .transpose()?;
 
         Ok(PassthroughFs {
-            inodes: RwLock::new(MultikeyBTreeMap::new()),
+            inodes: ShardedMultikeyMap::new(),
             next_inode: AtomicU64::new(fuse::ROOT_ID + 2),
             init_inode: fuse::ROOT_ID + 1,
 
-            handles: RwLock::new(BTreeMap::new()),
+            handles: ShardedMap::new(),
+            open_inode_fds: AtomicUsize::new(0),
+            inode_fd_clock: AtomicU64::new(0),
+            evicting_inode_fds: Mutex::new(()),
+            mount_fd: RwLock::new(None),
+            file_handles,
+            mnt_ids,
             next_handle: AtomicU64::new(1),
             init_handle: 0,
 
+            posix_locks: Mutex::new(BTreeMap::new()),
+
             proc_self_fd,
+            blocking,
 
             writeback: AtomicBool::new(false),
             announce_submounts: AtomicBool::new(false),
+            posix_acl: AtomicBool::new(false),
             my_uid,
             my_gid,
             cap_fowner,
+            cap_fsetid,
+            killpriv_v2: AtomicBool::new(false),
+            hidden: RwLock::new(HiddenNames::default()),
             cfg,
         })
     }
 
-    fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+    /// Returns the current values of this filesystem's operation counters.
+    pub fn metrics(&self) -> FsMetricsSnapshot {
+        self.cfg.metrics.snapshot()
+    }
 
-        let pathname = CString::new(format!("{}", data.file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    /// Returns how names are resolved, which depends on the host kernel.
+    pub fn resolve_mode(&self) -> ResolveMode {
+        if OPENAT2.load(Ordering::Relaxed) {
+            ResolveMode::Beneath
+        } else {
+            ResolveMode::NoFollow
+        }
+    }
 
-        // When writeback caching is enabled, the kernel may send read requests even if the
-        // userspace program opened the file write-only. So we need to ensure that we have opened
-        // the file for reading as well as writing.
-        let writeback = self.writeback.load(Ordering::Relaxed);
-        if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
+    /// Returns the number of `O_PATH` fds currently held open for inodes. With
+    /// `Config::max_inode_fds` set, this stays at or below the cap except for inodes that can't be
+    /// reopened or have open handles.
+    pub fn inode_fd_count(&self) -> usize {
+        self.open_inode_fds.load(Ordering::Relaxed)
+    }
+
+    // Returns the `O_PATH` fd of `data`, reopening it if it was closed.
+    fn inode_file(&self, data: &InodeData) -> io::Result<Arc<File>> {
+        if let Some(handle) = &data.handle {
+            return self.open_by_handle(handle).map(Arc::new);
+        }
+
+        if let Some(file) = data.file.lock_unpoisoned().clone() {
+            self.touch_inode_fd(data);
+            return Ok(file);
+        }
+
+        // Reopen without holding the lock, since that takes the parent's. If another request
+        // reopens it at the same time, whichever finishes last uses the fd of the first.
+        let file = Arc::new(self.reopen_by_name(data)?);
+        let mut slot = data.file.lock_unpoisoned();
+        let file = match &*slot {
+            Some(file) => file.clone(),
+            // A forgotten inode can still be reopened as the parent of one that isn't, but it has
+            // no place in the table to keep the fd.
+            None if data.refcount.load(Ordering::Acquire) == 0 => return Ok(file),
+            None => {
+                *slot = Some(file.clone());
+                self.open_inode_fds.fetch_add(1, Ordering::Relaxed);
+                file
+            }
+        };
+        drop(slot);
+        self.touch_inode_fd(data);
+
+        Ok(file)
+    }
+
+    fn open_by_handle(&self, handle: &[u8]) -> io::Result<File> {
+        let mount_fd = self.mount_fd.read_unpoisoned();
+        let mount_fd = mount_fd.as_ref().ok_or_else(ebadf)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_open_by_handle_at,
+                mount_fd.as_raw_fd(),
+                handle.as_ptr(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            // The file was deleted since it was looked up.
+            if e.raw_os_error() == Some(libc::ESTALE) {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            return Err(e);
+        }
+
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+    }
+
+    // Opens `data` again by its name in the directory it was looked up in, which is reopened the
+    // same way if its own fd was closed, all the way up to the root if need be.
+    fn reopen_by_name(&self, data: &InodeData) -> io::Result<File> {
+        let (parent, name) = data.origin.lock_unpoisoned().clone().ok_or_else(ebadf)?;
+        let dir = self.inode_file(&parent)?;
+        let f = self.blocking(move || open_beneath(&dir, &name, libc::O_PATH, 0))?;
+
+        // Something else may have taken the name since.
+        let st = stat(&f)?;
+        if st.st_ino != data.ino || st.st_dev != data.dev {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        Ok(f)
+    }
+
+    // Marks the fd of `data` as the most recently used one, and closes the least recently used
+    // ones once over `Config::max_inode_fds`. Only the eviction takes a lock, so requests using
+    // inodes whose fds are open don't wait on each other.
+    fn touch_inode_fd(&self, data: &InodeData) {
+        let Some(max) = self.cfg.max_inode_fds else {
+            return;
+        };
+        let tick = self.inode_fd_clock.fetch_add(1, Ordering::Relaxed);
+        data.last_used.store(tick, Ordering::Relaxed);
+        if self.open_inode_fds.load(Ordering::Relaxed) > max {
+            self.evict_inode_fds(max, data.inode);
+        }
+    }
+
+    // Closes the least recently used inode fds, other than the one of `current`, until an eighth
+    // of `max` is free again, so that the inode table is only scanned every so often. A request
+    // that finds another one already doing this goes on without waiting for it.
+    fn evict_inode_fds(&self, max: usize, current: Inode) {
+        let _evicting = match self.evicting_inode_fds.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        let target = max - max / 8;
+
+        // Inodes with open handles, and those that have nothing to be reopened by, are skipped.
+        let evictable = |data: &InodeData| {
+            data.inode != current
+                && data.open_handles.load(Ordering::Relaxed) == 0
+                && data.origin.lock_unpoisoned().is_some()
+        };
+        let mut victims: Vec<_> = self
+            .inodes
+            .values()
+            .into_iter()
+            .filter(|data| evictable(data) && data.file.lock_unpoisoned().is_some())
+            .map(|data| (data.last_used.load(Ordering::Relaxed), data))
+            .collect();
+        victims.sort_unstable_by_key(|(tick, _)| *tick);
+
+        for (_, data) in victims {
+            if self.open_inode_fds.load(Ordering::Relaxed) <= target {
+                break;
+            }
+            // It may have been opened or pinned since the scan.
+            if !evictable(&data) {
+                continue;
+            }
+            // Callers still holding the `Arc` keep the fd open until they're done with it.
+            if data.file.lock_unpoisoned().take().is_some() {
+                self.open_inode_fds.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Closes the fd of an inode that was dropped from the inode table. Its origin stays, since
+    // inodes looked up in it may need it to be reopened.
+    fn forget_inode_fd(&self, data: &InodeData) {
+        if data.file.lock_unpoisoned().take().is_some() {
+            self.open_inode_fds.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // Returns the inode `name` in `parent` refers to if the guest has it and it may have its fd
+    // closed, which is all that renames and unlinks need to keep up to date.
+    fn evictable_child(&self, parent: &InodeData, name: &CStr) -> Option<Arc<InodeData>> {
+        self.cfg.max_inode_fds?;
+
+        let dir = self.inode_file(parent).ok()?;
+        let mut st = MaybeUninit::<libc::stat64>::zeroed();
+        // Safe because the kernel will only write data in `st` and we check the return value.
+        let res = unsafe {
+            libc::fstatat64(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                st.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if res < 0 {
+            return None;
+        }
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        let st = unsafe { st.assume_init() };
+
+        self.inodes
+            .get_alt(&InodeAltKey::Ids {
+                ino: st.st_ino,
+                dev: st.st_dev,
+                mnt_id: parent.mnt_id,
+            })
+            .filter(|data| data.origin.lock_unpoisoned().is_some())
+    }
+
+    // Keeps the fd of `data` open from now on, as it's about to lose the name it would be reopened
+    // by and may have no other.
+    fn pin_inode_fd(&self, data: &InodeData) -> io::Result<()> {
+        self.inode_file(data)?;
+        *data.origin.lock_unpoisoned() = None;
+        Ok(())
+    }
+
+    fn add_handle(&self, handle: Handle, data: HandleData) {
+        if let Some(inode) = self.inodes.get(&data.inode) {
+            inode.open_handles.fetch_add(1, Ordering::Relaxed);
+        }
+        self.handles.insert(handle, Arc::new(data));
+    }
+
+    // Opens the fd that `open_by_handle_at` needs and checks that the root's file handle can be
+    // turned back into an fd with it.
+    fn probe_reopen(&self) -> io::Result<File> {
+        let root = self.inodes.get(&fuse::ROOT_ID).ok_or_else(ebadf)?;
+        open_mount_fd(&*self.inode_file(&root)?)
+    }
+
+    // Whether inodes on mount `mnt_id` of device `dev` can be reopened from their file handle.
+    fn reopenable(&self, mnt_id: u64, dev: u64) -> bool {
+        // Only inodes on the root's mount can be reopened, since that's the mount fd we pass to
+        // `open_by_handle_at`. Where mount ids are unknown, the device at least keeps handles of
+        // other filesystems out.
+        self.mount_fd.read_unpoisoned().is_some()
+            && self
+                .inodes
+                .get(&fuse::ROOT_ID)
+                .is_some_and(|root| root.mnt_id == mnt_id && root.dev == dev)
+    }
+
+    // Runs `f` on the blocking pool if `Config::op_timeout` is set, or right away otherwise.
+    fn blocking<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        match &self.blocking {
+            Some(pool) => pool.run(f),
+            None => f(),
+        }
+    }
+
+    // The bytes served as `init.krun`.
+    fn init_binary(&self) -> &[u8] {
+        self.cfg.init_binary.as_deref().unwrap_or(INIT_BINARY)
+    }
+
+    fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+        let file = self.inode_file(&data)?;
+
+        let pathname = CString::new(format!("{}", file.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // When writeback caching is enabled, the kernel may send read requests even if the
+        // userspace program opened the file write-only. So we need to ensure that we have opened
+        // the file for reading as well as writing.
+        let writeback = self.writeback.load(Ordering::Relaxed);
+        if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
             flags &= !libc::O_ACCMODE;
             flags |= libc::O_RDWR;
         }
@@ -516,23 +1520,31 @@ impl PassthroughFs {
             flags &= !libc::O_APPEND;
         }
 
-        // Safe because this doesn't modify any memory and we check the return value. We don't
-        // really check `flags` because if the kernel can't handle poorly specified flags then we
-        // have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since we need
-        // to follow the `/proc/self/fd` symlink to get the file.
-        let fd = unsafe {
-            libc::openat(
-                self.proc_self_fd.as_raw_fd(),
-                pathname.as_ptr(),
-                (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
-            )
+        // Opening a FIFO, or a file on a hung mount, can block.
+        let proc_self_fd = self.proc_self_fd.clone();
+        let open = move || {
+            // Safe because this doesn't modify any memory and we check the return value. We don't
+            // really check `flags` because if the kernel can't handle poorly specified flags then
+            // we have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since
+            // we need to follow the `/proc/self/fd` symlink to get the file. `file` is moved in
+            // here so that `pathname` keeps referring to it.
+            let fd = unsafe {
+                libc::openat(
+                    proc_self_fd.as_raw_fd(),
+                    pathname.as_ptr(),
+                    (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
+                )
+            };
+            drop(file);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Safe because we just opened this fd.
+            Ok(unsafe { File::from_raw_fd(fd) })
         };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
 
-        // Safe because we just opened this fd.
-        Ok(unsafe { File::from_raw_fd(fd) })
+        self.blocking(open)
     }
 
     fn open_inode_or_path(&self, inode: Inode, flags: i32) -> io::Result<FileOrLink> {
@@ -540,17 +1552,12 @@ impl PassthroughFs {
             Ok(a) => Ok(FileOrLink::File(a)),
             Err(e) => {
                 if e.raw_os_error() == Some(libc::ELOOP) {
-                    let data = self
-                        .inodes
-                        .read()
-                        .unwrap()
-                        .get(&inode)
-                        .cloned()
-                        .ok_or_else(ebadf)?;
-
-                    let pathname = CString::new(format!("/proc/self/fd/{}", data.file.as_raw_fd()))
+                    let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+                    let file = self.inode_file(&data)?;
+
+                    let pathname = CString::new(format!("{}", file.as_raw_fd()))
                         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                    Ok(FileOrLink::Link(pathname))
+                    Ok(FileOrLink::Link(pathname, file))
                 } else {
                     Err(e)
                 }
@@ -558,78 +1565,247 @@ impl PassthroughFs {
         }
     }
 
-    fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
-        let p = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+    // Runs `f` with the calling thread's working directory in `/proc/self/fd`, so that the names
+    // in a `FileOrLink::Link` can be used as paths without `/proc` being mounted. The first time,
+    // the thread stops sharing its working directory with the rest of the process.
+    fn in_proc_self_fd<T>(&self, f: impl FnOnce() -> T) -> io::Result<T> {
+        thread_local! {
+            static OWN_CWD: Cell<bool> = const { Cell::new(false) };
+        }
+        if !OWN_CWD.with(Cell::get) {
+            // Safe because this only detaches the calling thread's filesystem attributes.
+            if unsafe { libc::unshare(libc::CLONE_FS) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            OWN_CWD.with(|own| own.set(true));
+        }
 
         // Safe because this doesn't modify any memory and we check the return value.
         let fd = unsafe {
             libc::openat(
-                p.file.as_raw_fd(),
-                name.as_ptr(),
-                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                libc::AT_FDCWD,
+                CURRENT_DIR_CSTR.as_ptr() as *const libc::c_char,
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
             )
         };
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
-
         // Safe because we just opened this fd.
-        let f = unsafe { File::from_raw_fd(fd) };
+        let cwd = unsafe { File::from_raw_fd(fd) };
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::fchdir(self.proc_self_fd.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res = f();
+        // Safe because this doesn't modify any memory. Nothing in this thread uses relative paths
+        // otherwise, so there's nothing to do if going back fails.
+        unsafe { libc::fchdir(cwd.as_raw_fd()) };
+        Ok(res)
+    }
+
+    // Drops `O_DIRECT` from the guest's open `flags` unless `Config::allow_direct_io` is set.
+    fn direct_io_flags(&self, flags: u32) -> u32 {
+        let direct = libc::O_DIRECT as u32;
+        if flags & direct == 0 || self.cfg.allow_direct_io {
+            return flags;
+        }
+        self.cfg.metrics.direct_io_dropped();
+        flags & !direct
+    }
+
+    // Whether `name` in the directory `parent` is one of `Config::hidden`.
+    fn is_hidden(&self, parent: &InodeData, name: &CStr) -> bool {
+        self.hidden
+            .read_unpoisoned()
+            .contains(parent.dev, parent.ino, name.to_bytes())
+    }
+
+    // Fails with `err` if `name` in the directory `parent` is hidden, or `parent` is a sealed
+    // mount point.
+    fn check_hidden(&self, parent: &InodeData, name: &CStr, err: i32) -> io::Result<()> {
+        if parent.sealed || self.is_hidden(parent, name) {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        Ok(())
+    }
+
+    // Fails with `EROFS` if the share is read-only.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.cfg.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    // Fails with `EPERM` if `inode` is a sealed mount point.
+    fn check_sealed(&self, inode: Inode) -> io::Result<()> {
+        match self.inodes.get(&inode) {
+            Some(data) if data.sealed => Err(io::Error::from_raw_os_error(libc::EPERM)),
+            _ => Ok(()),
+        }
+    }
+
+    // Lists the host's names for the attributes of `inode`, before any mapping.
+    fn do_listxattr(&self, inode: Inode, size: u32) -> io::Result<ListxattrReply> {
+        let mut buf = vec![0; size as usize];
+
+        // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
+        // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
+        // functions in that case.
+        let res = match self.open_inode_or_path(inode, libc::O_RDONLY | libc::O_NONBLOCK)? {
+            FileOrLink::File(file) => {
+                // Safe because this will only modify the contents of `buf`.
+                unsafe {
+                    libc::flistxattr(
+                        file.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_char,
+                        size as libc::size_t,
+                    )
+                }
+            }
+            FileOrLink::Link(link, _file) => {
+                let buf = buf.as_mut_ptr() as *mut libc::c_char;
+                // Safe because this will only modify the contents of `buf`.
+                self.in_proc_self_fd(|| unsafe {
+                    libc::llistxattr(link.as_ptr(), buf, size as libc::size_t)
+                })?
+            }
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if size == 0 {
+            Ok(ListxattrReply::Count(res as u32))
+        } else {
+            buf.resize(res as usize, 0);
+            Ok(ListxattrReply::Names(buf))
+        }
+    }
+
+    fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        let p = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(&p, name, libc::ENOENT)?;
+
+        let dir = self.inode_file(&p)?;
+        let c_name = name.to_owned();
+        let f = self.blocking(move || open_beneath(&dir, &c_name, libc::O_PATH, 0))?;
+
+        let entry = self.add_entry(&p, Some(name), f)?;
+
+        debug!("do_lookup: {:?}, inode: {:?}", name, entry.inode);
+
+        Ok(entry)
+    }
+
+    // Returns an `Entry` for the `O_PATH` file `f` found in the directory `p` under `name`, adding
+    // it to the inode table or taking another reference on its existing inode.
+    fn add_entry(&self, p: &Arc<InodeData>, name: Option<&CStr>, f: File) -> io::Result<Entry> {
+        let mnt_ids = self.mnt_ids;
+        let proc_self_fd = self.proc_self_fd.clone();
+        let direct_io = self.cfg.allow_direct_io;
+        let (f, (mut st, mnt_id)) = self.blocking(move || {
+            let mut st = statx(&f, mnt_ids, &proc_self_fd)?;
+            if direct_io {
+                fit_blksize_to_direct_io(&f, &mut st.0);
+            }
+            Ok((f, st))
+        })?;
+        self.shift_to_guest(&mut st);
+
+        let is_dir = st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        let submount = st.st_dev != p.dev || mnt_id != p.mnt_id;
 
-        let (st, mnt_id) = statx(&f)?;
+        // With `Config::one_filesystem` the guest doesn't get past mount points.
+        let sealed = submount && self.cfg.one_filesystem;
+        if sealed && !(is_dir && self.cfg.empty_mount_points) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
 
         let mut attr_flags: u32 = 0;
 
-        if st.st_mode & libc::S_IFMT == libc::S_IFDIR
-            && self.announce_submounts.load(Ordering::Relaxed)
-            && (st.st_dev != p.dev || mnt_id != p.mnt_id)
-        {
+        if is_dir && submount && !sealed && self.announce_submounts.load(Ordering::Relaxed) {
             attr_flags |= fuse::ATTR_SUBMOUNT;
         }
 
-        let altkey = InodeAltKey {
-            ino: st.st_ino,
-            dev: st.st_dev,
-            mnt_id,
+        // With `Config::inode_file_handles`, inodes that can be reopened are keyed on their file
+        // handle and kept without an fd.
+        let by_handle = if self.file_handles && self.reopenable(mnt_id, st.st_dev) {
+            file_handle(&f).ok()
+        } else {
+            None
+        };
+        let altkey = match &by_handle {
+            Some(handle) => InodeAltKey::Handle {
+                mnt_id,
+                handle: handle.as_slice().into(),
+            },
+            None => InodeAltKey::Ids {
+                ino: st.st_ino,
+                dev: st.st_dev,
+                mnt_id,
+            },
         };
-        let data = self.inodes.read().unwrap().get_alt(&altkey).cloned();
+        let data = self.inodes.get_alt(&altkey);
 
         let inode = if let Some(data) = data {
             // Matches with the release store in `forget`.
             data.refcount.fetch_add(1, Ordering::Acquire);
+            self.cfg.metrics.inode_cache_hit();
+
+            // Reopen it by the name it was just found under from now on, and put the fd we just
+            // opened to use if its own was closed.
+            if data.handle.is_none() {
+                let mut origin = data.origin.lock_unpoisoned();
+                if let (Some(origin), Some(name)) = (origin.as_mut(), name) {
+                    *origin = (p.clone(), name.to_owned());
+                }
+                drop(origin);
+
+                let mut file = data.file.lock_unpoisoned();
+                if file.is_none() {
+                    *file = Some(Arc::new(f));
+                    drop(file);
+                    self.open_inode_fds.fetch_add(1, Ordering::Relaxed);
+                }
+                self.touch_inode_fd(&data);
+            }
+
             data.inode
         } else {
+            let fd_less = by_handle.is_some();
+            // Submounts are kept open, so that reopening never crosses a mount point.
+            let origin = name
+                .filter(|_| self.cfg.max_inode_fds.is_some() && !fd_less && !submount)
+                .map(|name| (p.clone(), name.to_owned()));
+
             // There is a possible race here where 2 threads end up adding the same file
             // into the inode list.  However, since each of those will get a unique Inode
             // value and unique file descriptors this shouldn't be that much of a problem.
             let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-            self.inodes.write().unwrap().insert(
+            let data = Arc::new(InodeData {
                 inode,
-                InodeAltKey {
-                    ino: st.st_ino,
-                    dev: st.st_dev,
-                    mnt_id,
-                },
-                Arc::new(InodeData {
-                    inode,
-                    file: f,
-                    dev: st.st_dev,
-                    mnt_id,
-                    refcount: AtomicU64::new(1),
-                }),
-            );
+                file: Mutex::new((!fd_less).then(|| Arc::new(f))),
+                handle: by_handle,
+                origin: Mutex::new(origin),
+                sealed,
+                ino: st.st_ino,
+                dev: st.st_dev,
+                mnt_id,
+                refcount: AtomicU64::new(1),
+                open_handles: AtomicU64::new(0),
+                last_used: AtomicU64::new(0),
+            });
+            self.inodes.insert(inode, altkey, data.clone());
+            if !fd_less {
+                self.open_inode_fds.fetch_add(1, Ordering::Relaxed);
+            }
+            self.touch_inode_fd(&data);
 
             inode
         };
 
-        debug!("do_lookup: {}, inode: {:?}", name.to_str().unwrap(), inode);
-
         Ok(Entry {
             inode,
             generation: 0,
@@ -657,20 +1833,56 @@ impl PassthroughFs {
 
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
+        let dir_data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+
+        // "." and ".." are made up rather than passed through from the host, where the root's ".."
+        // is outside the share. They take offsets 1 and 2, which filesystems also give the entries
+        // after their own dots, so the host's listing resumes from the start after either of them.
+        if offset < 2 {
+            let parent_ino = if inode == fuse::ROOT_ID {
+                dir_data.ino
+            } else {
+                parent_ino(&data.file.read_unpoisoned())?
+            };
+            let dots = [(&b"."[..], dir_data.ino), (b"..", parent_ino)];
+            for (i, (name, ino)) in dots.into_iter().enumerate().skip(offset as usize) {
+                let res = add_entry(DirEntry {
+                    ino,
+                    offset: i as u64 + 1,
+                    type_: u32::from(libc::DT_DIR),
+                    name,
+                })?;
+                if res == 0 {
+                    return Ok(());
+                }
+            }
+        }
+        let offset = if offset <= 2 { 0 } else { offset };
+
+        if dir_data.sealed {
+            return Ok(());
+        }
+
+        // Copied so that the lock isn't held while `add_entry` looks entries up.
+        let hidden = self
+            .hidden
+            .read_unpoisoned()
+            .in_dir(dir_data.dev, dir_data.ino)
+            .cloned();
+        // Entries on other mounts are left out, unless they're shown as empty directories.
+        let skip_mounts = self.cfg.one_filesystem && !self.cfg.empty_mount_points;
+
         let mut buf = vec![0; size as usize];
 
         {
             // Since we are going to work with the kernel offset, we have to acquire the file lock
             // for both the `lseek64` and `getdents64` syscalls to ensure that no other thread
             // changes the kernel offset while we are using it.
-            let dir = data.file.write().unwrap();
+            let dir = data.file.write_unpoisoned();
 
             // Safe because this doesn't modify any memory and we check the return value.
             let res =
@@ -721,9 +1933,21 @@ impl PassthroughFs {
                 .position(|&a| a == 0)
                 .expect("LinuxDirent64 name not NUL-terminated");
             let name = &name[..term];
-            let res = if name.starts_with(CURRENT_DIR_CSTR) || name.starts_with(PARENT_DIR_CSTR) {
-                // We don't want to report the "." and ".." entries. However, returning `Ok(0)` will
-                // break the loop so return `Ok` with a non-zero value instead.
+            let res = if name == b"." || name == b".." {
+                // The host's "." and ".." were already replaced above. However, returning `Ok(0)`
+                // will break the loop so return `Ok` with a non-zero value instead.
+                Ok(1)
+            } else if hidden.as_ref().is_some_and(|hidden| hidden.contains(name))
+                || (skip_mounts
+                    && dirent64.d_ty != libc::DT_LNK
+                    && on_other_mount(
+                        &data.file.read_unpoisoned(),
+                        name,
+                        &dir_data,
+                        self.mnt_ids,
+                        &self.proc_self_fd,
+                    ))
+            {
                 Ok(1)
             } else {
                 add_entry(DirEntry {
@@ -755,13 +1979,17 @@ impl PassthroughFs {
         kill_priv: bool,
         mut flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        self.cfg.metrics.open();
         debug!("do_open: {inode:?}");
+        flags = self.direct_io_flags(flags);
         if !self.cap_fowner {
             // O_NOATIME can only be used with CAP_FOWNER or if we are the file
             // owner. Not worth checking the latter, just drop it if we don't
             // have the cap. This makes overlayfs mounts with virtiofs lower dirs
             // work.
             flags &= !(libc::O_NOATIME as u32);
+        } else if self.cfg.atime == AtimePolicy::NoAtime {
+            flags |= libc::O_NOATIME as u32;
         }
 
         let file = {
@@ -780,7 +2008,7 @@ impl PassthroughFs {
             exported: Default::default(),
         };
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.add_handle(handle, data);
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -803,55 +2031,59 @@ impl PassthroughFs {
     }
 
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
-        let mut handles = self.handles.write().unwrap();
-
-        if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
-            if e.get().inode == inode {
-                if e.get().exported.load(Ordering::Relaxed) {
-                    self.cfg
-                        .export_table
-                        .as_ref()
-                        .unwrap()
-                        .lock()
-                        .unwrap()
-                        .remove(&(self.cfg.export_fsid, handle));
-                }
+        // We don't need to close the file here because that will happen automatically when the
+        // last `Arc` is dropped.
+        let data = self
+            .handles
+            .remove_if(&handle, |hd| hd.inode == inode)
+            .ok_or_else(ebadf)?;
+
+        if let Some(inode) = self.inodes.get(&inode) {
+            inode.open_handles.fetch_sub(1, Ordering::Relaxed);
+        }
 
-                // We don't need to close the file here because that will happen automatically when
-                // the last `Arc` is dropped.
-                e.remove();
-                return Ok(());
+        // The export ioctl marks handles while holding their shard's lock, so the flag can't
+        // change once the handle is out of the table.
+        if data.exported.load(Ordering::Relaxed) {
+            if let Some(export_table) = &self.cfg.export_table {
+                export_table
+                    .lock_unpoisoned()
+                    .remove(&(self.cfg.export_fsid, handle));
             }
         }
 
-        Err(ebadf())
+        Ok(())
     }
 
     fn do_getattr(&self, inode: Inode) -> io::Result<(libc::stat64, Duration)> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
-
-        let st = stat(&data.file)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+
+        let file = self.inode_file(&data)?;
+        let direct_io = self.cfg.allow_direct_io;
+        let mut st = self.blocking(move || {
+            let mut st = stat(&file)?;
+            if direct_io {
+                fit_blksize_to_direct_io(&file, &mut st);
+            }
+            Ok(st)
+        })?;
+        self.shift_to_guest(&mut st);
 
         Ok((st, self.cfg.attr_timeout))
     }
 
     fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        self.cfg.metrics.remove();
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(&data, name, libc::ENOENT)?;
+
+        if let Some(child) = self.evictable_child(&data, name) {
+            self.pin_inode_fd(&child)?;
+        }
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::unlinkat(data.file.as_raw_fd(), name.as_ptr(), flags) };
+        let res =
+            unsafe { libc::unlinkat(self.inode_file(&data)?.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
             Ok(())
         } else {
@@ -859,32 +2091,179 @@ impl PassthroughFs {
         }
     }
 
-    fn set_creds(
-        &self,
-        uid: libc::uid_t,
-        gid: libc::gid_t,
-    ) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
-        // Change the gid first, since once we change the uid we lose the capability to change the gid.
-        let scoped_gid = if gid == 0 || self.my_gid == Some(gid) {
-            // Always allow "root" accesses even if we don't have root powers.
-            // This means guest processes running as root can use /tmp (though
-            // the files will not be actually owned by root), which is desirable.
-            None
-        } else if self.my_gid.is_some() {
-            // Reject writes as any other gid if we do not have setgid
-            // privileges.
-            return Err(io::Error::from_raw_os_error(libc::EPERM));
+    // Returns the mode for a new entry in the directory `parent`. With POSIX ACLs enabled the guest
+    // sends the mode without applying its umask (`DONT_MASK`), and the umask must be ignored if
+    // the parent has a default ACL, which the host kernel then applies to the new entry.
+    fn create_mode(&self, parent: &InodeData, mode: u32, umask: u32) -> u32 {
+        if self.posix_acl.load(Ordering::Relaxed) && self.has_default_acl(parent) {
+            mode
         } else {
-            ScopedGid::new(gid)?
+            mode & !(umask & 0o777)
+        }
+    }
+
+    fn has_default_acl(&self, dir: &InodeData) -> bool {
+        let Ok(file) = self.open_inode(dir.inode, libc::O_RDONLY | libc::O_NONBLOCK) else {
+            return false;
+        };
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                POSIX_ACL_DEFAULT_CSTR.as_ptr() as *const libc::c_char,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        res > 0
+    }
+
+    // POSIX locks are passed through to the host as open file description locks. Every guest lock
+    // owner gets its own open file description for the inode, so owners conflict with each other
+    // the same way processes do on the host, and dropping the file releases all of its locks.
+    fn posix_lock_file(&self, inode: Inode, owner: u64) -> io::Result<Arc<File>> {
+        let mut locks = self.posix_locks.lock_unpoisoned();
+        if let Some(file) = locks.get(&(inode, owner)) {
+            return Ok(file.clone());
+        }
+
+        let file = Arc::new(self.open_lock_file(inode)?);
+        locks.insert((inode, owner), file.clone());
+        Ok(file)
+    }
+
+    // Write locks need a writable file, but fall back to read-only access so that read locks
+    // still work on files we can't open for writing.
+    fn open_lock_file(&self, inode: Inode) -> io::Result<File> {
+        match self.open_inode(inode, libc::O_RDWR | libc::O_NONBLOCK) {
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EACCES | libc::EROFS | libc::EISDIR | libc::ETXTBSY)
+                ) =>
+            {
+                self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)
+            }
+            res => res,
+        }
+    }
+
+    // Never blocks: conflicting locks are reported as `EAGAIN` and `setlkw` requests are retried by
+    // the server.
+    fn do_setlk(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags & fuse::LK_FLOCK != 0 {
+            return self.do_flock(inode, handle, &lock);
+        }
+
+        if lock.type_ == libc::F_UNLCK as u32
+            && !self
+                .posix_locks
+                .lock_unpoisoned()
+                .contains_key(&(inode, owner))
+        {
+            return Ok(());
+        }
+
+        let fl = fuse_to_flock(&lock)?;
+        let file = self.posix_lock_file(inode, owner)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &fl) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            // A conflicting lock may be reported as either EAGAIN or EACCES.
+            if err.raw_os_error() == Some(libc::EACCES) {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    // `flock` locks belong to the open file description, which is exactly what a handle is.
+    fn do_flock(&self, inode: Inode, handle: Handle, lock: &fuse::FileLock) -> io::Result<()> {
+        let data = self
+            .handles
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .ok_or_else(ebadf)?;
+
+        let op = match lock.type_ as i32 {
+            libc::F_RDLCK => libc::LOCK_SH,
+            libc::F_WRLCK => libc::LOCK_EX,
+            libc::F_UNLCK => libc::LOCK_UN,
+            _ => return Err(einval()),
+        };
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res =
+            unsafe { libc::flock(data.file.read_unpoisoned().as_raw_fd(), op | libc::LOCK_NB) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // Rewrites the ownership in `st` from host ids to the ids the guest knows them by.
+    fn shift_to_guest(&self, st: &mut libc::stat64) {
+        st.st_uid = self.cfg.uid_map.to_guest(st.st_uid);
+        st.st_gid = self.cfg.gid_map.to_guest(st.st_gid);
+    }
+
+    fn set_creds(
+        &self,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+    ) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        // New files can't be owned by a guest id the maps don't cover.
+        let host_uid = self
+            .cfg
+            .uid_map
+            .to_host(uid)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOVERFLOW))?;
+        let host_gid = self
+            .cfg
+            .gid_map
+            .to_host(gid)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EOVERFLOW))?;
+
+        // Change the gid first, since once we change the uid we lose the capability to change the gid.
+        let scoped_gid = if host_gid == 0
+            || (gid == 0 && self.my_gid.is_some())
+            || self.my_gid == Some(host_gid)
+        {
+            // Always allow "root" accesses even if we don't have root powers.
+            // This means guest processes running as root can use /tmp (though
+            // the files will not be actually owned by root), which is desirable.
+            None
+        } else if self.my_gid.is_some() {
+            // Reject writes as any other gid if we do not have setgid
+            // privileges.
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        } else {
+            ScopedGid::new(host_gid)?
         };
 
         // Same logic as above, for uid.
-        let scoped_uid = if uid == 0 || self.my_uid == Some(uid) {
+        let scoped_uid = if host_uid == 0
+            || (uid == 0 && self.my_uid.is_some())
+            || self.my_uid == Some(host_uid)
+        {
             None
         } else if self.my_uid.is_some() {
             return Err(io::Error::from_raw_os_error(libc::EPERM));
         } else {
-            ScopedUid::new(uid)?
+            ScopedUid::new(host_uid)?
         };
 
         Ok((scoped_uid, scoped_gid))
@@ -892,15 +2271,21 @@ impl PassthroughFs {
 }
 
 fn forget_one(
-    inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
+    inodes: &ShardedMultikeyMap<Inode, InodeAltKey, Arc<InodeData>>,
     inode: Inode,
     count: u64,
-) {
-    if let Some(data) = inodes.get(&inode) {
-        // Acquiring the write lock on the inode map prevents new lookups from incrementing the
-        // refcount but there is the possibility that a previous lookup already acquired a
-        // reference to the inode data and is in the process of updating the refcount so we need
-        // to loop here until we can decrement successfully.
+) -> Option<Arc<InodeData>> {
+    // The root is kept however often it's forgotten, since nothing could be looked up without it
+    // and the guest can't look it up again. `init` puts it back after `destroy`.
+    if inode == fuse::ROOT_ID {
+        return None;
+    }
+
+    inodes.remove_if(&inode, |data| {
+        // `remove_if` holds the alternate key index's write lock, which prevents new lookups from
+        // incrementing the refcount but there is the possibility that a previous lookup already
+        // acquired a reference to the inode data and is in the process of updating the refcount
+        // so we need to loop here until we can decrement successfully.
         loop {
             let refcount = data.refcount.load(Ordering::Relaxed);
 
@@ -912,21 +2297,17 @@ fn forget_one(
             if data
                 .refcount
                 .compare_exchange(refcount, new_count, Ordering::Release, Ordering::Relaxed)
-                .unwrap()
-                == refcount
+                .is_ok()
             {
-                if new_count == 0 {
-                    // We just removed the last refcount for this inode. There's no need for an
-                    // acquire fence here because we hold a write lock on the inode map and any
-                    // thread that is waiting to do a forget on the same inode will have to wait
-                    // until we release the lock. So there's is no other release store for us to
-                    // synchronize with before deleting the entry.
-                    inodes.remove(&inode);
-                }
-                break;
+                // If we just removed the last refcount for this inode, the entry gets deleted.
+                // There's no need for an acquire fence here because we hold the entry's locks
+                // and any thread that is waiting to do a forget on the same inode will have to
+                // wait until we release them. So there's is no other release store for us to
+                // synchronize with before deleting the entry.
+                return new_count == 0;
             }
         }
-    }
+    })
 }
 
 impl FileSystem for PassthroughFs {
@@ -953,31 +2334,46 @@ impl FileSystem for PassthroughFs {
         // Safe because we just opened this fd above.
         let f = unsafe { File::from_raw_fd(fd) };
 
-        let (st, mnt_id) = statx(&f)?;
+        let (st, mnt_id) = statx(&f, self.mnt_ids, &self.proc_self_fd)?;
+        *self.hidden.write_unpoisoned() = HiddenNames::resolve(&f, &self.cfg.hidden)?;
 
         // Safe because this doesn't modify any memory and there is no need to check the return
         // value because this system call always succeeds. We need to clear the umask here because
         // we want the client to be able to set all the bits in the mode.
         unsafe { libc::umask(0o000) };
 
-        let mut inodes = self.inodes.write().unwrap();
-
         // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
-        inodes.insert(
+        self.inodes.insert(
             fuse::ROOT_ID,
-            InodeAltKey {
+            InodeAltKey::Ids {
                 ino: st.st_ino,
                 dev: st.st_dev,
                 mnt_id,
             },
             Arc::new(InodeData {
                 inode: fuse::ROOT_ID,
-                file: f,
+                file: Mutex::new(Some(Arc::new(f))),
+                handle: None,
+                origin: Mutex::new(None),
+                sealed: false,
+                ino: st.st_ino,
                 dev: st.st_dev,
                 mnt_id,
                 refcount: AtomicU64::new(2),
+                open_handles: AtomicU64::new(0),
+                last_used: AtomicU64::new(0),
             }),
         );
+        self.open_inode_fds.fetch_add(1, Ordering::Relaxed);
+
+        // Inodes tracked by file handle are reopened with `open_by_handle_at`, which needs an fd
+        // for the root's mount.
+        if self.file_handles {
+            match self.probe_reopen() {
+                Ok(mount_fd) => *self.mount_fd.write_unpoisoned() = Some(mount_fd),
+                Err(e) => warn!("passthroughfs: can't reopen inodes from file handles, keeping their fds open: {e}"),
+            }
+        }
 
         let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
         if self.cfg.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
@@ -990,27 +2386,48 @@ impl FileSystem for PassthroughFs {
             self.announce_submounts.store(true, Ordering::Relaxed);
         }
 
+        // Without CAP_FSETID every write we make clears the setuid and setgid bits, even when the
+        // guest process was allowed to keep them, so leave it to the guest to clear them.
+        if self.cap_fsetid && capable.contains(FsOptions::HANDLE_KILLPRIV_V2) {
+            opts |= FsOptions::HANDLE_KILLPRIV_V2;
+            self.killpriv_v2.store(true, Ordering::Relaxed);
+        }
+
+        if self.cfg.posix_acl && self.cfg.xattr && capable.contains(FsOptions::POSIX_ACL) {
+            opts |= FsOptions::POSIX_ACL | FsOptions::DONT_MASK;
+            self.posix_acl.store(true, Ordering::Relaxed);
+        }
+
+        // Locks are passed through to the host so they are also visible to other users of the
+        // shared directory.
+        opts |= FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS;
+
         Ok(opts)
     }
 
     fn destroy(&self) {
-        self.handles.write().unwrap().clear();
-        self.inodes.write().unwrap().clear();
+        self.posix_locks.lock_unpoisoned().clear();
+        self.handles.clear();
+        self.inodes.clear();
+        *self.mount_fd.write_unpoisoned() = None;
+        self.open_inode_fds.store(0, Ordering::Relaxed);
     }
 
     fn statfs(&self, _ctx: Context, inode: Inode) -> io::Result<libc::statvfs64> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        // init.krun is synthetic and lives in the root directory, so report the root's filesystem.
+        let inode = if inode == self.init_inode {
+            fuse::ROOT_ID
+        } else {
+            inode
+        };
+
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
 
         // Safe because this will only modify `out` and we check the return value.
-        let res = unsafe { libc::fstatvfs64(data.file.as_raw_fd(), out.as_mut_ptr()) };
+        let res =
+            unsafe { libc::fstatvfs64(self.inode_file(&data)?.as_raw_fd(), out.as_mut_ptr()) };
         if res == 0 {
             // Safe because the kernel guarantees that `out` has been initialized.
             Ok(unsafe { out.assume_init() })
@@ -1019,13 +2436,44 @@ impl FileSystem for PassthroughFs {
         }
     }
 
+    fn syncfs(&self, _ctx: Context, inode: Inode) -> io::Result<()> {
+        let inode = if inode == self.init_inode {
+            fuse::ROOT_ID
+        } else {
+            inode
+        };
+
+        // `syncfs(2)` doesn't accept `O_PATH` fds, so open a real one.
+        let file = self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::syncfs(file.as_raw_fd()) };
+        if res == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ENOSYS) {
+            return Err(err);
+        }
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::fsync(file.as_raw_fd()) };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
     fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        self.cfg.metrics.lookup();
         debug!("do_lookup: {name:?}");
         let init_name = unsafe { CStr::from_bytes_with_nul_unchecked(INIT_CSTR) };
 
         if self.init_inode != 0 && name == init_name {
             let mut st: libc::stat64 = unsafe { mem::zeroed() };
-            st.st_size = INIT_BINARY.len() as i64;
+            st.st_size = self.init_binary().len() as i64;
             st.st_ino = self.init_inode;
             st.st_mode = 0o100_755;
 
@@ -1043,16 +2491,16 @@ impl FileSystem for PassthroughFs {
     }
 
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
-        let mut inodes = self.inodes.write().unwrap();
-
-        forget_one(&mut inodes, inode, count)
+        if let Some(data) = forget_one(&self.inodes, inode, count) {
+            self.forget_inode_fd(&data);
+        }
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
-        let mut inodes = self.inodes.write().unwrap();
-
         for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
+            if let Some(data) = forget_one(&self.inodes, inode, count) {
+                self.forget_inode_fd(&data);
+            }
         }
     }
 
@@ -1084,21 +2532,21 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
 
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(&data, name, libc::EPERM)?;
+        let mode = self.create_mode(&data, mode, umask);
+
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::mkdirat(data.file.as_raw_fd(), name.as_ptr(), mode & !umask) };
+        let res =
+            unsafe { libc::mkdirat(self.inode_file(&data)?.as_raw_fd(), name.as_ptr(), mode) };
         if res == 0 {
             self.do_lookup(parent, name)
         } else {
@@ -1107,6 +2555,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.do_unlink(parent, name, libc::AT_REMOVEDIR)
     }
 
@@ -1138,14 +2587,26 @@ impl FileSystem for PassthroughFs {
         F: FnMut(DirEntry, Entry) -> io::Result<usize>,
     {
         self.do_readdir(inode, handle, size, offset, |dir_entry| {
-            // Safe because the kernel guarantees that the buffer is nul-terminated. Additionally,
-            // the kernel will pad the name with '\0' bytes up to 8-byte alignment and there's no
-            // way for us to know exactly how many padding bytes there are. This would cause
-            // `CStr::from_bytes_with_nul` to return an error because it would think there are
-            // interior '\0' bytes. We trust the kernel to provide us with properly formatted data
-            // so we'll just skip the checks here.
-            let name = unsafe { CStr::from_bytes_with_nul_unchecked(dir_entry.name) };
-            let entry = self.do_lookup(inode, name)?;
+            // `do_readdir` hands out names without their terminating '\0'.
+            let name = CString::new(dir_entry.name).map_err(|_| einval())?;
+            let entry = if dir_entry.name == b"." || dir_entry.name == b".." {
+                // The kernel doesn't look up "." and "..", so they're sent without an inode that
+                // it would have to forget.
+                // Safe because `stat64` only has integer fields, for which zero is valid.
+                let mut attr = unsafe { mem::zeroed::<libc::stat64>() };
+                attr.st_ino = dir_entry.ino;
+                attr.st_mode = libc::S_IFDIR;
+                Entry {
+                    inode: 0,
+                    generation: 0,
+                    attr,
+                    attr_flags: 0,
+                    attr_timeout: Duration::ZERO,
+                    entry_timeout: Duration::ZERO,
+                }
+            } else {
+                self.do_lookup(inode, &name)?
+            };
 
             add_entry(dir_entry, entry)
         })
@@ -1158,6 +2619,13 @@ impl FileSystem for PassthroughFs {
         kill_priv: bool,
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        // Files of a read-only share are only ever opened for reading.
+        let flags = if self.cfg.read_only {
+            flags & !(libc::O_ACCMODE | bindings::LINUX_O_TRUNC | bindings::LINUX_O_APPEND) as u32
+        } else {
+            flags
+        };
+
         if inode == self.init_inode {
             Ok((Some(self.init_handle), OpenOptions::empty()))
         } else {
@@ -1171,10 +2639,14 @@ impl FileSystem for PassthroughFs {
         inode: Inode,
         _flags: u32,
         handle: Handle,
-        _flush: bool,
+        flush: bool,
         _flock_release: bool,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
+        if let (true, Some(owner)) = (flush, lock_owner) {
+            self.posix_locks.lock_unpoisoned().remove(&(inode, owner));
+        }
+
         self.do_release(inode, handle)
     }
 
@@ -1189,10 +2661,17 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
+        self.cfg.metrics.open();
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
 
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(&data, name, libc::EPERM)?;
+        let mode = self.create_mode(&data, mode, umask);
+
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
         let _killpriv_guard = if kill_priv {
             drop_effective_cap("FSETID")?
@@ -1200,42 +2679,116 @@ impl FileSystem for PassthroughFs {
             None
         };
 
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        // We don't really check `flags` because if the kernel can't handle poorly specified flags
+        // then we have much bigger problems.
+        let flags = self.direct_io_flags(flags);
+        let file = open_beneath(
+            &*self.inode_file(&data)?,
+            name,
+            flags as i32 | libc::O_CREAT,
+            mode,
+        )?;
+        let file = RwLock::new(file);
 
-        // Safe because this doesn't modify any memory and we check the return value. We don't
-        // really check `flags` because if the kernel can't handle poorly specified flags then we
-        // have much bigger problems.
-        let fd = unsafe {
-            libc::openat(
-                data.file.as_raw_fd(),
-                name.as_ptr(),
-                flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                mode & !(umask & 0o777),
-            )
+        let entry = self.do_lookup(parent, name)?;
+
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let data = HandleData {
+            inode: entry.inode,
+            file,
+            exported: Default::default(),
+        };
+
+        self.add_handle(handle, data);
+
+        let mut opts = OpenOptions::empty();
+        match self.cfg.cache_policy {
+            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
+            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
+            _ => {}
+        };
+
+        Ok((entry, Some(handle), opts))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
+        self.cfg.metrics.open();
+        if extensions.secctx.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        if data.sealed {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        // `O_TMPFILE` can't be combined with `O_CREAT`. `O_EXCL` is passed on so that the file
+        // can't be linked into the file system later if the guest asked for that.
+        let flags = self.direct_io_flags(flags) as i32;
+        let flags = (flags & !libc::O_CREAT) | libc::O_TMPFILE | libc::O_CLOEXEC;
+        let mode = self.create_mode(&data, mode, umask);
+
+        // Safe because this is a constant value and a valid C string.
+        let current_dir = unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR) };
+
+        let fd = {
+            let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            unsafe {
+                libc::openat(
+                    self.inode_file(&data)?.as_raw_fd(),
+                    current_dir.as_ptr(),
+                    flags,
+                    mode,
+                )
+            }
         };
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
 
         // Safe because we just opened this fd.
-        let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+        let file = unsafe { File::from_raw_fd(fd) };
 
-        let entry = self.do_lookup(parent, name)?;
+        // The file has no name, so get the `O_PATH` fd for the inode table through `/proc/self/fd`.
+        // This is also what `link` uses to give it a name later.
+        let procname = CString::new(format!("{fd}"))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let path_fd = unsafe {
+            libc::openat(
+                self.proc_self_fd.as_raw_fd(),
+                procname.as_ptr(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        if path_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd.
+        let entry = self.add_entry(&data, None, unsafe { File::from_raw_fd(path_fd) })?;
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
             inode: entry.inode,
-            file,
+            file: RwLock::new(file),
             exported: Default::default(),
         };
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.add_handle(handle, data);
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -1248,6 +2801,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.do_unlink(parent, name, 0)
     }
 
@@ -1265,27 +2819,25 @@ impl FileSystem for PassthroughFs {
         debug!("read: {inode:?}");
         if inode == self.init_inode {
             let off: usize = offset.try_into().map_err(|_| einval())?;
-            let len = if off + (size as usize) < INIT_BINARY.len() {
-                size as usize
-            } else {
-                INIT_BINARY.len() - off
-            };
-            return w.write(&INIT_BINARY[off..(off + len)]);
+            let init = self.init_binary();
+            let start = off.min(init.len());
+            let end = off.saturating_add(size as usize).min(init.len());
+            return w.write(&init[start..end]);
         }
 
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
-        let f = data.file.read().unwrap();
-        w.write_from(&f, size as usize, offset)
+        let f = data.file.read_unpoisoned();
+        let count = w.write_from(&f, size as usize, offset)?;
+        self.cfg.metrics.read(count);
+
+        Ok(count)
     }
 
     fn write<R: io::Read + ZeroCopyReader>(
@@ -1301,6 +2853,7 @@ impl FileSystem for PassthroughFs {
         kill_priv: bool,
         _flags: u32,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         let _killpriv_guard = if kill_priv {
             // We need to drop FSETID during a write so that the kernel will remove setuid
             // or setgid bits from the file if it was written to by someone other than the
@@ -1312,28 +2865,53 @@ impl FileSystem for PassthroughFs {
 
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
-        let f = data.file.read().unwrap();
-        r.read_to(&f, size as usize, offset)
+        let f = data.file.read_unpoisoned();
+        let count = r.read_to(&f, size as usize, offset)?;
+        self.cfg.metrics.write(count);
+
+        Ok(count)
     }
 
     fn getattr(
         &self,
         _ctx: Context,
         inode: Inode,
-        _handle: Option<Handle>,
+        handle: Option<Handle>,
     ) -> io::Result<(libc::stat64, Duration)> {
+        // An open handle can be stat'ed directly, skipping the inode table's `O_PATH` fd.
+        let data =
+            handle.and_then(|handle| self.handles.get(&handle).filter(|hd| hd.inode == inode));
+        if let Some(data) = data {
+            let file = data.file.read_unpoisoned();
+            let mut st = stat(&file)?;
+            if self.cfg.allow_direct_io {
+                fit_blksize_to_direct_io(&file, &mut st);
+            }
+            self.shift_to_guest(&mut st);
+            return Ok((st, self.cfg.attr_timeout));
+        }
+
         self.do_getattr(inode)
     }
 
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Option<libc::timespec>, Duration)> {
+        let (st, timeout) = self.getattr(ctx, inode, handle)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+        let btime = self.inode_file(&data).ok().and_then(|file| btime(&file));
+        Ok((st, btime, timeout))
+    }
+
     fn setattr(
         &self,
         _ctx: Context,
@@ -1342,13 +2920,12 @@ impl FileSystem for PassthroughFs {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
-        let inode_data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        self.check_writable()?;
+        let inode_data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+        if inode_data.sealed {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let inode_file = self.inode_file(&inode_data)?;
 
         enum Data {
             Handle(RawFd),
@@ -1359,17 +2936,14 @@ impl FileSystem for PassthroughFs {
         let data = if let Some(handle) = handle {
             let hd = self
                 .handles
-                .read()
-                .unwrap()
                 .get(&handle)
                 .filter(|hd| hd.inode == inode)
-                .cloned()
                 .ok_or_else(ebadf)?;
 
-            let fd = hd.file.write().unwrap().as_raw_fd();
+            let fd = hd.file.write_unpoisoned().as_raw_fd();
             Data::Handle(fd)
         } else {
-            let pathname = CString::new(format!("{}", inode_data.file.as_raw_fd()))
+            let pathname = CString::new(format!("{}", inode_file.as_raw_fd()))
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
             Data::ProcPath(pathname)
         };
@@ -1391,13 +2965,19 @@ impl FileSystem for PassthroughFs {
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                self.cfg
+                    .uid_map
+                    .to_host(attr.st_uid)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                self.cfg
+                    .gid_map
+                    .to_host(attr.st_gid)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
@@ -1409,7 +2989,7 @@ impl FileSystem for PassthroughFs {
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 libc::fchownat(
-                    inode_data.file.as_raw_fd(),
+                    inode_file.as_raw_fd(),
                     empty.as_ptr(),
                     uid,
                     gid,
@@ -1422,20 +3002,41 @@ impl FileSystem for PassthroughFs {
         }
 
         if valid.contains(SetattrValid::SIZE) {
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match data {
-                Data::Handle(fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
-                _ => {
-                    // There is no `ftruncateat` so we need to get a new fd and truncate it.
-                    let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
-                    unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
-                }
+            // There is no `ftruncateat` so we need to get a new fd if we don't have a handle.
+            let file = match data {
+                Data::Handle(_) => None,
+                _ => Some(self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?),
+            };
+            let fd = match data {
+                Data::Handle(fd) => fd,
+                _ => file.as_ref().unwrap().as_raw_fd(),
+            };
+
+            // With `HANDLE_KILLPRIV_V2` the guest relies on us to drop the file's privileges when
+            // an unprivileged process truncates it. We run with CAP_FSETID, so drop it for the
+            // truncation and then remove anything the kernel left behind.
+            let kill_priv = valid.contains(SetattrValid::KILL_SUIDGID);
+            let _killpriv_guard = if kill_priv {
+                drop_effective_cap("FSETID")?
+            } else {
+                None
             };
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::ftruncate(fd, attr.st_size) };
             if res < 0 {
                 return Err(io::Error::last_os_error());
             }
+
+            if kill_priv {
+                remove_privs(fd)?;
+            }
         }
 
+        let valid = self
+            .cfg
+            .atime
+            .filter_setattr(valid, || self.do_getattr(inode).map(|(st, _)| st))?;
         if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
             let mut tvs = [
                 libc::timespec {
@@ -1486,20 +3087,20 @@ impl FileSystem for PassthroughFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
-        let old_inode = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&olddir)
-            .cloned()
-            .ok_or_else(ebadf)?;
-        let new_inode = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&newdir)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        self.check_writable()?;
+        let old_inode = self.inodes.get(&olddir).ok_or_else(ebadf)?;
+        let new_inode = self.inodes.get(&newdir).ok_or_else(ebadf)?;
+        self.check_hidden(&old_inode, oldname, libc::ENOENT)?;
+        self.check_hidden(&new_inode, newname, libc::EPERM)?;
+
+        // Inodes whose fds may be closed are reopened by name, so they follow the rename. One that
+        // gets replaced loses its name.
+        let moved = self.evictable_child(&old_inode, oldname);
+        let target = self.evictable_child(&new_inode, newname);
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        if let Some(target) = target.as_ref().filter(|_| !exchange) {
+            self.pin_inode_fd(target)?;
+        }
 
         // Safe because this doesn't modify any memory and we check the return value.
         // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
@@ -1507,23 +3108,37 @@ impl FileSystem for PassthroughFs {
         let res = unsafe {
             libc::syscall(
                 libc::SYS_renameat2,
-                old_inode.file.as_raw_fd(),
+                self.inode_file(&old_inode)?.as_raw_fd(),
                 oldname.as_ptr(),
-                new_inode.file.as_raw_fd(),
+                self.inode_file(&new_inode)?.as_raw_fd(),
                 newname.as_ptr(),
                 flags,
             )
         };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
+        if res != 0 {
+            return Err(io::Error::last_os_error());
         }
-    }
 
-    fn mknod(
-        &self,
-        ctx: Context,
+        let mut renames = vec![(moved, &new_inode, newname)];
+        if exchange {
+            renames.push((target, &old_inode, oldname));
+        }
+        for (data, dir, name) in renames {
+            let Some(data) = data else {
+                continue;
+            };
+            let mut origin = data.origin.lock_unpoisoned();
+            if let Some(origin) = origin.as_mut() {
+                *origin = (dir.clone(), name.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mknod(
+        &self,
+        ctx: Context,
         parent: Inode,
         name: &CStr,
         mode: u32,
@@ -1531,25 +3146,24 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
 
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(&data, name, libc::EPERM)?;
+        let mode = self.create_mode(&data, mode, umask);
+
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
             libc::mknodat(
-                data.file.as_raw_fd(),
+                self.inode_file(&data)?.as_raw_fd(),
                 name.as_ptr(),
-                (mode & !umask) as libc::mode_t,
+                mode as libc::mode_t,
                 u64::from(rdev),
             )
         };
@@ -1568,22 +3182,13 @@ impl FileSystem for PassthroughFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
-        let new_inode = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&newparent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        self.check_writable()?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
+        let new_inode = self.inodes.get(&newparent).ok_or_else(ebadf)?;
+        self.check_hidden(&new_inode, newname, libc::EPERM)?;
 
-        let procname = CString::new(format!("{}", data.file.as_raw_fd()))
+        let file = self.inode_file(&data)?;
+        let procname = CString::new(format!("{}", file.as_raw_fd()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -1591,7 +3196,7 @@ impl FileSystem for PassthroughFs {
             libc::linkat(
                 self.proc_self_fd.as_raw_fd(),
                 procname.as_ptr(),
-                new_inode.file.as_raw_fd(),
+                self.inode_file(&new_inode)?.as_raw_fd(),
                 newname.as_ptr(),
                 libc::AT_SYMLINK_FOLLOW,
             )
@@ -1611,23 +3216,25 @@ impl FileSystem for PassthroughFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
         // Set security context on symlink.
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
 
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(&data, name, libc::EPERM)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res =
-            unsafe { libc::symlinkat(linkname.as_ptr(), data.file.as_raw_fd(), name.as_ptr()) };
+        let res = unsafe {
+            libc::symlinkat(
+                linkname.as_ptr(),
+                self.inode_file(&data)?.as_raw_fd(),
+                name.as_ptr(),
+            )
+        };
         if res == 0 {
             self.do_lookup(parent, name)
         } else {
@@ -1636,13 +3243,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn readlink(&self, _ctx: Context, inode: Inode) -> io::Result<Vec<u8>> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
         let mut buf = vec![0; libc::PATH_MAX as usize];
 
@@ -1652,7 +3253,7 @@ impl FileSystem for PassthroughFs {
         // Safe because this will only modify the contents of `buf` and we check the return value.
         let res = unsafe {
             libc::readlinkat(
-                data.file.as_raw_fd(),
+                self.inode_file(&data)?.as_raw_fd(),
                 empty.as_ptr(),
                 buf.as_mut_ptr() as *mut libc::c_char,
                 buf.len(),
@@ -1671,22 +3272,24 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
+        // Closing a file drops all of the owner's POSIX locks on it.
+        self.posix_locks
+            .lock_unpoisoned()
+            .remove(&(inode, lock_owner));
+
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
         // because this doesn't modify any memory and we check the return values.
         unsafe {
-            let newfd = libc::dup(data.file.write().unwrap().as_raw_fd());
+            let newfd = libc::dup(data.file.write_unpoisoned().as_raw_fd());
             if newfd < 0 {
                 return Err(io::Error::last_os_error());
             }
@@ -1702,14 +3305,11 @@ impl FileSystem for PassthroughFs {
     fn fsync(&self, _ctx: Context, inode: Inode, datasync: bool, handle: Handle) -> io::Result<()> {
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let fd = data.file.write_unpoisoned().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
@@ -1737,16 +3337,71 @@ impl FileSystem for PassthroughFs {
         self.fsync(ctx, inode, datasync, handle)
     }
 
+    fn getlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<fuse::FileLock> {
+        if flags & fuse::LK_FLOCK != 0 {
+            return Err(einval());
+        }
+
+        let mut fl = fuse_to_flock(&lock)?;
+        // An owner without locks on the inode conflicts with every lock there is, which a file of
+        // its own that is closed right after finds just as well.
+        let file = self
+            .posix_locks
+            .lock_unpoisoned()
+            .get(&(inode, owner))
+            .cloned();
+        let file = match file {
+            Some(file) => file,
+            None => Arc::new(self.open_lock_file(inode)?),
+        };
+
+        // Safe because this will only modify `fl` and we check the return value.
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut fl) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(flock_to_fuse(&fl))
+    }
+
+    fn setlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(inode, handle, owner, lock, flags)
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(inode, handle, owner, lock, flags)
+    }
+
     fn access(&self, ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
-        let st = stat(&data.file)?;
+        let file = self.inode_file(&data)?;
+        let mut st = self.blocking(move || stat(&file))?;
+        self.shift_to_guest(&mut st);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1801,9 +3456,12 @@ impl FileSystem for PassthroughFs {
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         if !self.cfg.xattr {
-            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            return Err(xattr_disabled(name));
         }
+        self.check_sealed(inode)?;
+        let name = xattrmap::to_host(&self.cfg.xattr_map, name)?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
@@ -1821,9 +3479,9 @@ impl FileSystem for PassthroughFs {
                     )
                 }
             }
-            FileOrLink::Link(link) => {
+            FileOrLink::Link(link, _file) => {
                 // Safe because this doesn't modify any memory and we check the return value.
-                unsafe {
+                self.in_proc_self_fd(|| unsafe {
                     libc::lsetxattr(
                         link.as_ptr(),
                         name.as_ptr(),
@@ -1831,7 +3489,7 @@ impl FileSystem for PassthroughFs {
                         value.len(),
                         flags as libc::c_int,
                     )
-                }
+                })?
             }
         };
 
@@ -1850,12 +3508,13 @@ impl FileSystem for PassthroughFs {
         size: u32,
     ) -> io::Result<GetxattrReply> {
         if !self.cfg.xattr {
-            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            return Err(xattr_disabled(name));
         }
 
         if inode == self.init_inode {
             return Err(io::Error::from_raw_os_error(libc::ENODATA));
         }
+        let name = xattrmap::to_host(&self.cfg.xattr_map, name)?;
 
         let mut buf = vec![0; size as usize];
 
@@ -1874,16 +3533,12 @@ impl FileSystem for PassthroughFs {
                     )
                 }
             }
-            FileOrLink::Link(link) => {
+            FileOrLink::Link(link, _file) => {
+                let buf = buf.as_mut_ptr() as *mut libc::c_void;
                 // Safe because this will only modify the contents of `buf`.
-                unsafe {
-                    libc::lgetxattr(
-                        link.as_ptr(),
-                        name.as_ptr(),
-                        buf.as_mut_ptr() as *mut libc::c_void,
-                        size as libc::size_t,
-                    )
-                }
+                self.in_proc_self_fd(|| unsafe {
+                    libc::lgetxattr(link.as_ptr(), name.as_ptr(), buf, size as libc::size_t)
+                })?
             }
         };
 
@@ -1903,47 +3558,37 @@ impl FileSystem for PassthroughFs {
         if !self.cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        if self.cfg.xattr_map.is_empty() {
+            return self.do_listxattr(inode, size);
+        }
 
-        let mut buf = vec![0; size as usize];
-
-        // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
-        // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
-        // functions in that case.
-        let res = match self.open_inode_or_path(inode, libc::O_RDONLY | libc::O_NONBLOCK)? {
-            FileOrLink::File(file) => {
-                // Safe because this will only modify the contents of `buf`.
-                unsafe {
-                    libc::flistxattr(
-                        file.as_raw_fd(),
-                        buf.as_mut_ptr() as *mut libc::c_char,
-                        size as libc::size_t,
-                    )
-                }
-            }
-            FileOrLink::Link(link) => unsafe {
-                libc::llistxattr(
-                    link.as_ptr(),
-                    buf.as_mut_ptr() as *mut libc::c_char,
-                    size as libc::size_t,
-                )
+        // Mapping changes the length of the names, so get the whole list to size the reply.
+        let names = match self.do_listxattr(inode, 0)? {
+            ListxattrReply::Count(0) => Vec::new(),
+            ListxattrReply::Count(count) => match self.do_listxattr(inode, count)? {
+                ListxattrReply::Names(names) => names,
+                ListxattrReply::Count(_) => unreachable!(),
             },
+            ListxattrReply::Names(_) => unreachable!(),
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
+        let names = xattrmap::to_guest_list(&self.cfg.xattr_map, &names);
 
         if size == 0 {
-            Ok(ListxattrReply::Count(res as u32))
+            Ok(ListxattrReply::Count(names.len() as u32))
+        } else if names.len() > size as usize {
+            Err(io::Error::from_raw_os_error(libc::ERANGE))
         } else {
-            buf.resize(res as usize, 0);
-            Ok(ListxattrReply::Names(buf))
+            Ok(ListxattrReply::Names(names))
         }
     }
 
     fn removexattr(&self, _ctx: Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         if !self.cfg.xattr {
-            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            return Err(xattr_disabled(name));
         }
+        self.check_sealed(inode)?;
+        let name = xattrmap::to_host(&self.cfg.xattr_map, name)?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
@@ -1953,9 +3598,11 @@ impl FileSystem for PassthroughFs {
                 // Safe because this doesn't modify any memory and we check the return value.
                 unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) }
             }
-            FileOrLink::Link(link) => {
+            FileOrLink::Link(link, _file) => {
                 // Safe because this doesn't modify any memory and we check the return value.
-                unsafe { libc::lremovexattr(link.as_ptr(), name.as_ptr()) }
+                self.in_proc_self_fd(|| unsafe {
+                    libc::lremovexattr(link.as_ptr(), name.as_ptr())
+                })?
             }
         };
 
@@ -1975,16 +3622,24 @@ impl FileSystem for PassthroughFs {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        self.check_writable()?;
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        // With `HANDLE_KILLPRIV_V2` the guest doesn't clear the file's privileges around
+        // fallocate, nor tell us whether the caller may keep them, so do what the kernel does for
+        // an unprivileged caller.
+        let kill_priv = self.killpriv_v2.load(Ordering::Relaxed);
+        let _killpriv_guard = if kill_priv {
+            drop_effective_cap("FSETID")?
+        } else {
+            None
+        };
+
+        let fd = data.file.write_unpoisoned().as_raw_fd();
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
             libc::fallocate64(
@@ -1994,11 +3649,15 @@ impl FileSystem for PassthroughFs {
                 length as libc::off64_t,
             )
         };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
+        if res < 0 {
+            return Err(io::Error::last_os_error());
         }
+
+        // Not every filesystem clears them on fallocate.
+        if kill_priv {
+            remove_privs(fd)?;
+        }
+        Ok(())
     }
 
     fn lseek(
@@ -2011,17 +3670,23 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<u64> {
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let whence = match whence as libc::c_int {
+            bindings::LINUX_SEEK_SET => libc::SEEK_SET,
+            bindings::LINUX_SEEK_CUR => libc::SEEK_CUR,
+            bindings::LINUX_SEEK_END => libc::SEEK_END,
+            bindings::LINUX_SEEK_DATA => libc::SEEK_DATA,
+            bindings::LINUX_SEEK_HOLE => libc::SEEK_HOLE,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let fd = data.file.write_unpoisoned().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek(fd, offset as libc::off64_t, whence as libc::c_int) };
+        let res = unsafe { libc::lseek(fd, offset as libc::off64_t, whence) };
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
@@ -2029,6 +3694,38 @@ impl FileSystem for PassthroughFs {
         }
     }
 
+    fn fadvise(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> io::Result<()> {
+        self.cfg.metrics.fadvise();
+        let data = self
+            .handles
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .ok_or_else(ebadf)?;
+        let fd = data.file.read_unpoisoned().as_raw_fd();
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::posix_fadvise64(
+                fd,
+                offset as libc::off64_t,
+                len as libc::off64_t,
+                host_advice(advice),
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+        Ok(())
+    }
+
     fn copyfilerange(
         &self,
         _ctx: Context,
@@ -2041,29 +3738,27 @@ impl FileSystem for PassthroughFs {
         len: u64,
         flags: u64,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         let data_in = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle_in)
             .filter(|hd| hd.inode == inode_in)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // Take just a read lock as we're not going to alter the file descriptor offset.
-        let fd_in = data_in.file.read().unwrap().as_raw_fd();
+        let fd_in = data_in.file.read_unpoisoned().as_raw_fd();
 
         let data_out = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle_out)
             .filter(|hd| hd.inode == inode_out)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // Take just a read lock as we're not going to alter the file descriptor offset.
-        let fd_out = data_out.file.read().unwrap().as_raw_fd();
+        let fd_out = data_out.file.read_unpoisoned().as_raw_fd();
+
+        let copy_len: usize = len.try_into().map_err(|_| einval())?;
+        let copy_flags: u32 = flags.try_into().map_err(|_| einval())?;
 
         // Safe because this will only modify `offset_in` and `offset_out` and we check
         // the return value.
@@ -2073,12 +3768,20 @@ impl FileSystem for PassthroughFs {
                 &mut (offset_in as i64) as &mut _ as *mut _,
                 fd_out,
                 &mut (offset_out as i64) as &mut _ as *mut _,
-                len.try_into().unwrap(),
-                flags.try_into().unwrap(),
+                copy_len,
+                copy_flags,
             )
         };
         if res < 0 {
-            Err(io::Error::last_os_error())
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // The host can't copy between these files in the kernel (they're on different
+                // filesystems, or it's too old), so copy through userspace instead.
+                Some(libc::EXDEV | libc::EOPNOTSUPP | libc::ENOSYS) if flags == 0 => {
+                    copy_range_fallback(fd_in, offset_in, fd_out, offset_out, len)
+                }
+                _ => Err(err),
+            }
         } else {
             Ok(res as usize)
         }
@@ -2096,6 +3799,10 @@ impl FileSystem for PassthroughFs {
         host_shm_base: u64,
         shm_size: u64,
     ) -> io::Result<()> {
+        if flags & fuse::SetupmappingFlags::WRITE.bits() != 0 {
+            self.check_writable()?;
+        }
+
         let open_flags = if (flags & fuse::SetupmappingFlags::WRITE.bits()) != 0 {
             libc::O_RDWR
         } else {
@@ -2131,15 +3838,16 @@ impl FileSystem for PassthroughFs {
                 return Err(io::Error::last_os_error());
             }
 
-            let to_copy = if len as usize > INIT_BINARY.len() {
-                INIT_BINARY.len()
+            let init = self.init_binary();
+            let to_copy = if len as usize > init.len() {
+                init.len()
             } else {
                 len as usize
             };
             unsafe {
                 libc::memcpy(
                     addr as *mut libc::c_void,
-                    INIT_BINARY.as_ptr() as *const _,
+                    init.as_ptr() as *const _,
                     to_copy,
                 )
             };
@@ -2207,7 +3915,7 @@ impl FileSystem for PassthroughFs {
         arg: u64,
         _in_size: u32,
         out_size: u32,
-        exit_code: &Arc<AtomicI32>,
+        exit_status: &Arc<SharedExitStatus>,
     ) -> io::Result<Vec<u8>> {
         const VIRTIO_IOC_MAGIC: u8 = b'v';
 
@@ -2227,6 +3935,12 @@ impl FileSystem for PassthroughFs {
         const VIRTIO_IOC_REMOVE_ROOT_DIR_REQ: u32 =
             request_code_none!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_REMOVE_ROOT_DIR_CODE) as u32;
 
+        // Carries the workload's raw wait status instead of just an exit code. Guests that
+        // predate it only send VIRTIO_IOC_EXIT_CODE_REQ.
+        const VIRTIO_IOC_TYPE_EXIT_STATUS: u8 = 4;
+        const VIRTIO_IOC_EXIT_STATUS_REQ: u32 =
+            request_code_none!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_TYPE_EXIT_STATUS) as u32;
+
         match cmd {
             VIRTIO_IOC_EXPORT_FD_REQ => {
                 if out_size as usize != VIRTIO_IOC_EXPORT_FD_SIZE {
@@ -2238,30 +3952,36 @@ impl FileSystem for PassthroughFs {
                     .export_table
                     .as_ref()
                     .ok_or(io::Error::from_raw_os_error(libc::EOPNOTSUPP))?
-                    .lock()
-                    .unwrap();
+                    .lock_unpoisoned();
 
-                let handles = self.handles.read().unwrap();
-                let data = handles
-                    .get(&handle)
-                    .filter(|hd| hd.inode == inode)
-                    .ok_or_else(ebadf)?;
+                // Keep the handle's shard locked until the export is recorded, so that a
+                // concurrent release either sees the flag or runs before the lookup.
+                self.handles.get_with(&handle, |data| {
+                    let data = data.filter(|hd| hd.inode == inode).ok_or_else(ebadf)?;
 
-                data.exported.store(true, Ordering::Relaxed);
+                    data.exported.store(true, Ordering::Relaxed);
 
-                let fd = data.file.read().unwrap().try_clone()?;
+                    let fd = data.file.read_unpoisoned().try_clone()?;
 
-                exports.insert((self.cfg.export_fsid, handle), fd);
+                    exports.insert((self.cfg.export_fsid, handle), fd);
+                    Ok::<(), io::Error>(())
+                })?;
 
                 let mut ret: Vec<_> = self.cfg.export_fsid.to_ne_bytes().into();
                 ret.extend_from_slice(&handle.to_ne_bytes());
                 Ok(ret)
             }
             VIRTIO_IOC_EXIT_CODE_REQ => {
-                exit_code.store(arg as i32, Ordering::SeqCst);
+                exit_status.set(ExitStatus::Exited(arg as i32));
+                Ok(Vec::new())
+            }
+            VIRTIO_IOC_EXIT_STATUS_REQ => {
+                let status = ExitStatus::from_wait_status(arg as i32).ok_or_else(einval)?;
+                exit_status.set(status);
                 Ok(Vec::new())
             }
             VIRTIO_IOC_REMOVE_ROOT_DIR_REQ if self.cfg.allow_root_dir_delete => {
+                self.check_writable()?;
                 std::fs::remove_dir_all(&self.cfg.root_dir)?;
                 Ok(Vec::new())
             }
@@ -2269,3 +3989,2017 @@ impl FileSystem for PassthroughFs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use utils::tempdir::TempDir;
+
+    use super::*;
+
+    // An empty directory for a test to share, removed again when the test ends, even on a panic.
+    fn test_dir(name: &str) -> TempDir {
+        TempDir::new_with_prefix(std::env::temp_dir().join(format!("krun-fs-{name}-"))).unwrap()
+    }
+
+    // Shares `dir` with the rest of `cfg` and mounts it.
+    fn mount(dir: &TempDir, cfg: Config) -> PassthroughFs {
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            ..cfg
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        fs
+    }
+
+    // A mounted share of a fresh directory, and the context of a root caller.
+    fn test_fs(name: &str, cfg: Config) -> (TempDir, PassthroughFs, Context) {
+        let dir = test_dir(name);
+        let fs = mount(&dir, cfg);
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        (dir, fs, ctx)
+    }
+
+    fn lock(type_: i32, start: u64, end: u64) -> fuse::FileLock {
+        fuse::FileLock {
+            start,
+            end,
+            type_: type_ as u32,
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn posix_locks_conflict_between_owners() {
+        let (tmp, fs, ctx) = test_fs("locks", Config::default());
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDWR as u32).unwrap();
+        let handle = handle.unwrap();
+
+        fs.setlk(ctx, inode, handle, 1, lock(libc::F_WRLCK, 0, 9), 0)
+            .unwrap();
+
+        // A second owner can't take an overlapping lock, but can lock a disjoint range.
+        let err = fs
+            .setlkw(ctx, inode, handle, 2, lock(libc::F_RDLCK, 5, 20), 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        fs.setlk(ctx, inode, handle, 2, lock(libc::F_WRLCK, 10, 20), 0)
+            .unwrap();
+
+        let conflict = fs
+            .getlk(ctx, inode, handle, 2, lock(libc::F_RDLCK, 0, u64::MAX), 0)
+            .unwrap();
+        assert_eq!(conflict.type_, libc::F_WRLCK as u32);
+        assert_eq!((conflict.start, conflict.end), (0, 9));
+
+        // Testing for a lock doesn't keep anything around for an owner without locks.
+        let conflict = fs
+            .getlk(ctx, inode, handle, 3, lock(libc::F_RDLCK, 0, u64::MAX), 0)
+            .unwrap();
+        assert_eq!((conflict.start, conflict.end), (0, 9));
+        assert!(!fs.posix_locks.lock().unwrap().contains_key(&(inode, 3)));
+
+        // Flushing on behalf of the first owner releases its locks.
+        fs.flush(ctx, inode, handle, 1).unwrap();
+        fs.setlkw(ctx, inode, handle, 2, lock(libc::F_RDLCK, 5, 20), 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn survives_panicking_operation() {
+        let (dir, fs, ctx) = test_fs("poison", Config::default());
+        let fs = Arc::new(fs);
+        std::fs::write(dir.as_path().join("file"), b"data").unwrap();
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDWR as u32).unwrap();
+        let handle = handle.unwrap();
+
+        // An operation panics on another worker while holding the handle's and the POSIX
+        // locks' locks.
+        let worker = fs.clone();
+        std::thread::spawn(move || {
+            let data = worker.handles.get(&handle).unwrap();
+            let _file = data.file.write().unwrap();
+            let _locks = worker.posix_locks.lock().unwrap();
+            panic!("injected failure");
+        })
+        .join()
+        .unwrap_err();
+
+        let mut w = VecWriter(Vec::new());
+        let n = fs.read(ctx, inode, handle, &mut w, 64, 0, None, 0).unwrap();
+        assert_eq!(&w.0[..n], b"data");
+        fs.flush(ctx, inode, handle, 1).unwrap();
+
+        // Later operations still fail cleanly rather than panicking.
+        let err = fs
+            .read(ctx, inode, handle + 1, &mut w, 64, 0, None, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn disabled_xattrs_reject_acls() {
+        let (_dir, fs, ctx) = test_fs(
+            "noxattr",
+            Config {
+                xattr: false,
+                ..Default::default()
+            },
+        );
+        let acl = CString::new("system.posix_acl_access").unwrap();
+        let user = CString::new("user.test").unwrap();
+
+        let err = fs.getxattr(ctx, fuse::ROOT_ID, &acl, 0).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+        let err = fs.setxattr(ctx, fuse::ROOT_ID, &acl, &[], 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+        let err = fs.getxattr(ctx, fuse::ROOT_ID, &user, 0).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    }
+
+    #[test]
+    fn default_acls_are_inherited() {
+        let tmp = test_dir("acl");
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join("inherit")).unwrap();
+        std::fs::create_dir_all(dir.join("plain")).unwrap();
+
+        // A default ACL granting everyone everything, plus read and execute to uid 12345, as
+        // `posix_acl_xattr_header` and `posix_acl_xattr_entry`s.
+        let entries: [(u16, u16, u32); 5] = [
+            (0x01, 7, u32::MAX), // ACL_USER_OBJ
+            (0x02, 5, 12345),    // ACL_USER
+            (0x04, 7, u32::MAX), // ACL_GROUP_OBJ
+            (0x10, 7, u32::MAX), // ACL_MASK
+            (0x20, 7, u32::MAX), // ACL_OTHER
+        ];
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        let c_dir = CString::new(dir.join("inherit").to_str().unwrap()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::setxattr(
+                c_dir.as_ptr(),
+                POSIX_ACL_DEFAULT_CSTR.as_ptr() as *const libc::c_char,
+                acl.as_ptr() as *const libc::c_void,
+                acl.len(),
+                0,
+            )
+        };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.to_str().unwrap().to_string(),
+            xattr: true,
+            posix_acl: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let opts = fs
+            .init(FsOptions::POSIX_ACL | FsOptions::DONT_MASK)
+            .unwrap();
+        assert!(opts.contains(FsOptions::POSIX_ACL | FsOptions::DONT_MASK));
+
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let lookup = |parent, name: &str| {
+            let name = CString::new(name).unwrap();
+            fs.lookup(ctx, parent, &name).unwrap().inode
+        };
+        let create = |parent, name: &str| {
+            let name = CString::new(name).unwrap();
+            let flags = (libc::O_RDWR | libc::O_EXCL) as u32;
+            let (entry, handle, _) = fs
+                .create(ctx, parent, &name, 0o666, false, flags, 0o022, Extensions::default())
+                .unwrap();
+            fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+                .unwrap();
+            entry
+        };
+        let inherit = lookup(fuse::ROOT_ID, "inherit");
+        let plain = lookup(fuse::ROOT_ID, "plain");
+
+        // The guest's umask only applies where there's no default ACL.
+        assert_eq!(create(plain, "file").attr.st_mode & 0o777, 0o644);
+        let file = create(inherit, "file");
+        assert_eq!(file.attr.st_mode & 0o777, 0o666);
+
+        // The new file's access ACL keeps the named user, and its mask is the group bits of the
+        // requested mode.
+        let access = CString::new("system.posix_acl_access").unwrap();
+        let GetxattrReply::Value(value) = fs.getxattr(ctx, file.inode, &access, 256).unwrap()
+        else {
+            panic!("expected an ACL");
+        };
+        let entries: Vec<(u16, u16, u32)> = value[4..]
+            .chunks(8)
+            .map(|e| {
+                (
+                    u16::from_le_bytes([e[0], e[1]]),
+                    u16::from_le_bytes([e[2], e[3]]),
+                    u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+                )
+            })
+            .collect();
+        assert!(entries.contains(&(0x02, 5, 12345)));
+        assert!(entries.contains(&(0x10, 6, u32::MAX)));
+
+        // Directories inherit the default ACL itself, too.
+        let subdir = CString::new("subdir").unwrap();
+        let entry = fs
+            .mkdir(ctx, inherit, &subdir, 0o777, 0o022, Extensions::default())
+            .unwrap();
+        assert_eq!(entry.attr.st_mode & 0o777, 0o777);
+        let default = CString::new("system.posix_acl_default").unwrap();
+        match fs.getxattr(ctx, entry.inode, &default, 256).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, acl),
+            GetxattrReply::Count(_) => panic!("expected an ACL"),
+        }
+    }
+
+    // Makes `path` setuid and grants it CAP_NET_BIND_SERVICE as a file capability. Returns false
+    // without CAP_SETFCAP, which setting file capabilities needs.
+    fn set_file_caps(path: &std::path::Path) -> bool {
+        if !has_cap(None, CapSet::Effective, Capability::CAP_SETFCAP).unwrap_or_default() {
+            return false;
+        }
+
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        // A version 2 `vfs_cap_data` granting CAP_NET_BIND_SERVICE.
+        let mut cap = [0u8; 20];
+        cap[..4].copy_from_slice(&0x0200_0000u32.to_le_bytes());
+        cap[4..8]
+            .copy_from_slice(&(1u32 << Capability::CAP_NET_BIND_SERVICE.index()).to_le_bytes());
+        // Safe because this doesn't modify any memory.
+        let res = unsafe {
+            libc::chmod(c_path.as_ptr(), 0o4755);
+            libc::setxattr(
+                c_path.as_ptr(),
+                SECURITY_CAPABILITY_CSTR.as_ptr() as *const libc::c_char,
+                cap.as_ptr() as *const libc::c_void,
+                cap.len(),
+                0,
+            )
+        };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        true
+    }
+
+    // Whether `path` still has file capabilities.
+    fn has_file_caps(path: &std::path::Path) -> bool {
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        // Safe because this doesn't modify any memory.
+        let res = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                SECURITY_CAPABILITY_CSTR.as_ptr() as *const libc::c_char,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if res < 0 {
+            assert_eq!(
+                io::Error::last_os_error().raw_os_error(),
+                Some(libc::ENODATA)
+            );
+        }
+        res >= 0
+    }
+
+    #[test]
+    fn truncate_kills_privs() {
+        let (tmp, fs, ctx) = test_fs("killpriv", Config::default());
+        let path = tmp.as_path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        if !set_file_caps(&path) {
+            return;
+        }
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+
+        // Safe because all zeroes is a valid `stat64`.
+        let mut attr: libc::stat64 = unsafe { mem::zeroed() };
+        attr.st_size = 1;
+        let (st, _) = fs
+            .setattr(
+                ctx,
+                inode,
+                attr,
+                None,
+                SetattrValid::SIZE | SetattrValid::KILL_SUIDGID,
+            )
+            .unwrap();
+        assert_eq!(st.st_size, 1);
+        assert_eq!(st.st_mode & 0o7777, 0o755);
+        assert!(!has_file_caps(&path));
+    }
+
+    #[test]
+    fn write_kills_privs() {
+        let (tmp, fs, ctx) = test_fs("killpriv-write", Config::default());
+        let path = tmp.as_path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        if !set_file_caps(&path) {
+            return;
+        }
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let flags = libc::O_RDWR as u32;
+        let (handle, _) = fs.open(ctx, inode, false, flags).unwrap();
+        let handle = handle.unwrap();
+        let n = fs
+            .write(
+                ctx,
+                inode,
+                handle,
+                SliceReader(b"more"),
+                4,
+                4,
+                None,
+                false,
+                true,
+                flags,
+            )
+            .unwrap();
+        assert_eq!(n, 4);
+
+        let (st, _) = fs.getattr(ctx, inode, Some(handle)).unwrap();
+        assert_eq!(st.st_mode & 0o7777, 0o755);
+        assert!(!has_file_caps(&path));
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn killpriv_v2() {
+        let dir = test_dir("killpriv2");
+        let path = dir.as_path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        let set_mode = |mode| {
+            std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(mode))
+                .unwrap()
+        };
+        let mode = || std::os::unix::fs::MetadataExt::mode(&std::fs::metadata(&path).unwrap());
+        set_mode(0o6755);
+
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let opts = fs.init(FsOptions::HANDLE_KILLPRIV_V2).unwrap();
+        if !fs.cap_fsetid {
+            // Without CAP_FSETID the guest has to clear the bits itself.
+            assert!(!opts.contains(FsOptions::HANDLE_KILLPRIV_V2));
+            return;
+        }
+        assert!(opts.contains(FsOptions::HANDLE_KILLPRIV_V2));
+
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let flags = (libc::O_RDWR | libc::O_APPEND) as u32;
+        let (handle, _) = fs.open(ctx, inode, false, flags).unwrap();
+        let handle = handle.unwrap();
+        let write = |kill_priv| {
+            fs.write(
+                ctx,
+                inode,
+                handle,
+                SliceReader(b"more"),
+                4,
+                0,
+                None,
+                false,
+                kill_priv,
+                flags,
+            )
+            .unwrap()
+        };
+
+        // A writer allowed to keep the bits doesn't lose them.
+        assert_eq!(write(false), 4);
+        assert_eq!(mode() & 0o7777, 0o6755);
+
+        // Otherwise the write clears both, the group executable bit being set, and appends.
+        assert_eq!(write(true), 4);
+        assert_eq!(mode() & 0o7777, 0o755);
+        assert_eq!(std::fs::read(&path).unwrap(), b"datamoremore");
+
+        // The setgid bit stays without the group executable bit.
+        set_mode(0o6745);
+        write(true);
+        assert_eq!(mode() & 0o7777, 0o2745);
+
+        // fallocate carries no flag, so it always clears them.
+        set_mode(0o6755);
+        fs.fallocate(ctx, inode, handle, 0, 0, 4096).unwrap();
+        assert_eq!(mode() & 0o7777, 0o755);
+    }
+
+    #[test]
+    fn init_binary_override() {
+        let init = crate::virtio::fs::init::tests::fake_init(b"custom init");
+        let (_dir, fs, ctx) = test_fs(
+            "init",
+            Config {
+                init_binary: Some(init.clone()),
+                ..Default::default()
+            },
+        );
+        let name = CString::new("init.krun").unwrap();
+        let entry = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_size, init.len() as i64);
+
+        let mut w = VecWriter(Vec::new());
+        let n = fs
+            .read(ctx, entry.inode, 0, &mut w, 4096, 0, None, 0)
+            .unwrap();
+        assert_eq!(&w.0[..n], &init[..]);
+
+        // Reading past the end returns nothing.
+        let mut w = VecWriter(Vec::new());
+        let offset = init.len() as u64 + 10;
+        let n = fs
+            .read(ctx, entry.inode, 0, &mut w, 16, offset, None, 0)
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn export_fd_ioctl() {
+        let exports = ExportTable::default();
+        let (tmp, fs, ctx) = test_fs(
+            "export",
+            Config {
+                export_fsid: 3,
+                export_table: Some(exports.clone()),
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+
+        let export_req = request_code_read!(b'v', 1, 16) as u32;
+        // The macOS backend can't compute this one and has it spelled out.
+        assert_eq!(export_req, 0x8010_7601);
+        let exit_status = Arc::new(SharedExitStatus::new());
+        let export = |inode, out_size| {
+            fs.ioctl(
+                ctx,
+                inode,
+                handle,
+                0,
+                export_req,
+                0,
+                0,
+                out_size,
+                &exit_status,
+            )
+        };
+
+        let out = export(inode, 16).unwrap();
+        assert_eq!(out[..8], 3u64.to_ne_bytes());
+        assert_eq!(out[8..], handle.to_ne_bytes());
+        let file = exports.lock().unwrap()[&(3, handle)].try_clone().unwrap();
+        assert_eq!(io::read_to_string(file).unwrap(), "data");
+
+        assert_eq!(
+            export(inode, 8).unwrap_err().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+        assert_eq!(
+            export(fuse::ROOT_ID, 16).unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+
+        // Releasing the handle takes its export away too.
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+        assert!(exports.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn exit_status_ioctls() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::Command;
+
+        let exit_code_req = request_code_none!(b'v', 2) as u32;
+        let exit_status_req = request_code_none!(b'v', 4) as u32;
+
+        let (_dir, fs, ctx) = test_fs("exit", Config::default());
+        let run = |script: &str| {
+            let status = Command::new("/bin/sh")
+                .args(["-c", script])
+                .status()
+                .unwrap();
+            status.into_raw() as u64
+        };
+        let ioctl = |cmd, arg| {
+            let exit_status = Arc::new(SharedExitStatus::new());
+            fs.ioctl(ctx, fuse::ROOT_ID, 0, 0, cmd, arg, 0, 0, &exit_status)
+                .unwrap();
+            exit_status.get().unwrap()
+        };
+
+        assert_eq!(
+            ioctl(exit_status_req, run("exit 200")),
+            ExitStatus::Exited(200)
+        );
+        let segv = ioctl(exit_status_req, run("kill -SEGV $$"));
+        assert!(matches!(
+            segv,
+            ExitStatus::Signaled {
+                signal: libc::SIGSEGV,
+                ..
+            }
+        ));
+        assert_eq!(segv.code(), 139);
+
+        // Older guests only send the code, which can't tell the two apart.
+        assert_eq!(ioctl(exit_code_req, 139), ExitStatus::Exited(139));
+    }
+
+    struct SliceReader<'a>(&'a [u8]);
+
+    impl io::Read for SliceReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl ZeroCopyReader for SliceReader<'_> {
+        fn read_to(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+            use std::os::unix::fs::FileExt;
+
+            let count = count.min(self.0.len());
+            let written = f.write_at(&self.0[..count], off)?;
+            self.0 = &self.0[written..];
+            Ok(written)
+        }
+    }
+
+    struct VecWriter(Vec<u8>);
+
+    impl io::Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+            use std::os::unix::fs::FileExt;
+
+            let mut buf = vec![0; count];
+            let read = f.read_at(&mut buf, off)?;
+            if read == 0 && count != 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            self.0.extend_from_slice(&buf[..read]);
+            Ok(read)
+        }
+    }
+
+    // Runs `iterations` rounds of lookup/open/read/release/forget on a distinct file per thread
+    // and returns how long it took.
+    fn parallel_file_ops(threads: usize, iterations: usize) -> Duration {
+        let (dir, fs, _) = test_fs("parallel", Config::default());
+        let fs = Arc::new(fs);
+        for t in 0..threads {
+            std::fs::write(dir.as_path().join(format!("file{t}")), format!("file{t}")).unwrap();
+        }
+
+        let start = std::time::Instant::now();
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    let ctx = Context {
+                        uid: 0,
+                        gid: 0,
+                        pid: 0,
+                    };
+                    let name = CString::new(format!("file{t}")).unwrap();
+                    for _ in 0..iterations {
+                        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+                        let (handle, _) =
+                            fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+                        let handle = handle.unwrap();
+                        let mut w = VecWriter(Vec::new());
+                        let n = fs.read(ctx, inode, handle, &mut w, 64, 0, None, 0).unwrap();
+                        assert_eq!(&w.0[..n], name.as_bytes());
+                        fs.release(ctx, inode, 0, handle, false, false, None)
+                            .unwrap();
+                        fs.forget(ctx, inode, 1);
+                    }
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        // Every reference taken above was dropped again, leaving just the root.
+        assert!(fs.handles.values().is_empty());
+        for t in 0..threads {
+            let name = CString::new(format!("file{t}")).unwrap();
+            let ctx = Context {
+                uid: 0,
+                gid: 0,
+                pid: 0,
+            };
+            let entry = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap();
+            let data = fs.inodes.get(&entry.inode).unwrap();
+            assert_eq!(data.refcount.load(Ordering::Relaxed), 1);
+        }
+
+        elapsed
+    }
+
+    #[test]
+    fn parallel_lookup_open_read() {
+        parallel_file_ops(8, 200);
+    }
+
+    // Throughput check for the inode and handle tables under contention. Run with
+    // `cargo test -p msb_krun_devices --release -- --ignored --nocapture bench_parallel`.
+    #[test]
+    #[ignore]
+    fn bench_parallel_file_ops() {
+        const ITERATIONS: usize = 20000;
+
+        for threads in [1, 4, 16] {
+            let elapsed = parallel_file_ops(threads, ITERATIONS);
+            println!(
+                "{threads} threads: {:.0} ops/s",
+                (threads * ITERATIONS) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (tmp, fs, ctx) = test_fs("non-utf8", Config::default());
+        let dir = tmp.as_path();
+        std::fs::write(dir.join(std::ffi::OsStr::from_bytes(b"\xe9")), b"data").unwrap();
+
+        let name = CString::new(&b"\xe9"[..]).unwrap();
+        let entry = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_size, 4);
+
+        let name = CString::new(&b"new\xe9"[..]).unwrap();
+        let (entry, handle, _) = fs
+            .create(
+                ctx,
+                fuse::ROOT_ID,
+                &name,
+                0o644,
+                false,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+        fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        assert!(dir.join(std::ffi::OsStr::from_bytes(b"new\xe9")).exists());
+    }
+
+    #[test]
+    fn statx_btime() {
+        let (tmp, fs, ctx) = test_fs("btime", Config::default());
+        let dir = tmp.as_path();
+        let name = CString::new("new").unwrap();
+        let entry = fs
+            .mknod(
+                ctx,
+                fuse::ROOT_ID,
+                &name,
+                libc::S_IFREG | 0o644,
+                0,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+
+        let (st, btime, _) = fs.statx(ctx, entry.inode, None).unwrap();
+        assert_eq!(st.st_ino, entry.attr.st_ino);
+        // The host filesystem may not record creation times.
+        match std::fs::metadata(dir.join("new")).unwrap().created() {
+            Ok(created) => {
+                let created = created.duration_since(std::time::UNIX_EPOCH).unwrap();
+                let btime = btime.unwrap();
+                assert_eq!(btime.tv_sec as u64, created.as_secs());
+                assert_eq!(btime.tv_nsec as u32, created.subsec_nanos());
+            }
+            Err(_) => assert!(btime.is_none()),
+        }
+    }
+
+    #[test]
+    fn fadvise() {
+        let (tmp, fs, ctx) = test_fs("fadvise", Config::default());
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+
+        for advice in [
+            Advice::Normal,
+            Advice::Random,
+            Advice::Sequential,
+            Advice::WillNeed,
+            Advice::DontNeed,
+            Advice::NoReuse,
+        ] {
+            fs.fadvise(ctx, inode, handle, 0, 0, advice).unwrap();
+        }
+        assert_eq!(fs.metrics().fadvises, 6);
+
+        // The handle has to belong to the inode named alongside it.
+        let err = fs
+            .fadvise(ctx, fuse::ROOT_ID, handle, 0, 0, Advice::Normal)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn getattr_through_handle() {
+        let (tmp, fs, ctx) = test_fs("getattr", Config::default());
+        let dir = tmp.as_path();
+        let path = dir.join("file");
+        std::fs::write(&path, b"data").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDWR as u32).unwrap();
+        let handle = handle.unwrap();
+
+        for i in 0..1000 {
+            if i % 100 == 0 {
+                std::fs::write(&path, vec![0u8; i]).unwrap();
+            }
+            let (fast, _) = fs.getattr(ctx, inode, Some(handle)).unwrap();
+            let (slow, _) = fs.getattr(ctx, inode, None).unwrap();
+            assert_eq!(fast.st_ino, slow.st_ino);
+            assert_eq!(fast.st_size, slow.st_size);
+            assert_eq!(fast.st_mode, slow.st_mode);
+            assert_eq!(
+                (fast.st_mtime, fast.st_mtime_nsec),
+                (slow.st_mtime, slow.st_mtime_nsec)
+            );
+        }
+
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn lseek_data_hole() {
+        use std::os::unix::fs::FileExt;
+
+        const MIB: u64 = 1 << 20;
+
+        let (tmp, fs, ctx) = test_fs("lseek", Config::default());
+        let dir = tmp.as_path();
+        let file = File::create(dir.join("sparse")).unwrap();
+        file.write_at(&[1u8; 4096], MIB).unwrap();
+        file.set_len(2 * MIB).unwrap();
+        drop(file);
+
+        let name = CString::new("sparse").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+        let seek = |offset, whence| fs.lseek(ctx, inode, handle, offset, whence as u32);
+
+        // File systems without hole support report the whole file as data.
+        let data = seek(0, bindings::LINUX_SEEK_DATA).unwrap();
+        assert!(data == 0 || data == MIB);
+        let hole = seek(MIB, bindings::LINUX_SEEK_HOLE).unwrap();
+        assert!(hole > MIB && hole <= 2 * MIB);
+        if data == MIB {
+            assert_eq!(seek(0, bindings::LINUX_SEEK_HOLE).unwrap(), 0);
+            assert_eq!(
+                seek(MIB + 8192, bindings::LINUX_SEEK_DATA)
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(libc::ENXIO)
+            );
+        }
+
+        assert_eq!(
+            seek(2 * MIB, bindings::LINUX_SEEK_DATA)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENXIO)
+        );
+        assert_eq!(seek(0, 5).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn tmpfile_link() {
+        let (tmp, fs, ctx) = test_fs("tmpfile", Config::default());
+        let dir = tmp.as_path();
+        let flags = libc::O_RDWR as u32;
+
+        // A temporary file that is never linked leaves nothing behind.
+        let (entry, handle, _) = fs
+            .tmpfile(ctx, fuse::ROOT_ID, 0o644, flags, 0, Extensions::default())
+            .unwrap();
+        assert_eq!(entry.attr.st_nlink, 0);
+        fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        fs.forget(ctx, entry.inode, 1);
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+
+        let (entry, handle, _) = fs
+            .tmpfile(ctx, fuse::ROOT_ID, 0o644, flags, 0, Extensions::default())
+            .unwrap();
+        let handle = handle.unwrap();
+        let data = b"written before linking";
+        fs.write(
+            ctx,
+            entry.inode,
+            handle,
+            SliceReader(data),
+            data.len() as u32,
+            0,
+            None,
+            false,
+            false,
+            0,
+        )
+        .unwrap();
+
+        let name = CString::new("linked").unwrap();
+        let linked = fs.link(ctx, entry.inode, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(linked.inode, entry.inode);
+        assert_eq!(std::fs::read(dir.join("linked")).unwrap(), data);
+
+        fs.release(ctx, entry.inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn idmap_chown_round_trip() {
+        use std::os::unix::fs::MetadataExt;
+
+        use super::super::super::idmap::IdMapping;
+
+        let map = IdMap::new(vec![IdMapping {
+            guest_id: 0,
+            host_id: 100000,
+            count: 1000,
+        }]);
+        let (tmp, fs, ctx) = test_fs(
+            "idmap",
+            Config {
+                uid_map: map.clone(),
+                gid_map: map,
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        let path = dir.join("file");
+        std::fs::write(&path, b"data").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let entry = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap();
+        let host_uid = std::fs::metadata(&path).unwrap().uid();
+        if !(100000..101000).contains(&host_uid) {
+            // Host owners outside the map show up as the overflow id.
+            assert_eq!(entry.attr.st_uid, 65534);
+        }
+
+        // Safe because all zeroes is a valid `stat64`.
+        let mut attr: libc::stat64 = unsafe { mem::zeroed() };
+        attr.st_uid = 5;
+        attr.st_gid = 7;
+        match fs.setattr(
+            ctx,
+            entry.inode,
+            attr,
+            None,
+            SetattrValid::UID | SetattrValid::GID,
+        ) {
+            Ok((st, _)) => {
+                assert_eq!((st.st_uid, st.st_gid), (5, 7));
+                let md = std::fs::metadata(&path).unwrap();
+                assert_eq!((md.uid(), md.gid()), (100005, 100007));
+            }
+            // Changing ownership needs CAP_CHOWN.
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EPERM)),
+        }
+
+        // Guest ids outside the map can't be stored on the host.
+        attr.st_uid = 2000;
+        let err = fs
+            .setattr(ctx, entry.inode, attr, None, SetattrValid::UID)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn capped_inode_fds() {
+        let (tmp, fs, ctx) = test_fs(
+            "inode-fds",
+            Config {
+                max_inode_fds: Some(64),
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        for d in 0..100 {
+            let sub = dir.join(format!("dir{d}"));
+            std::fs::create_dir_all(&sub).unwrap();
+            for f in 0..100 {
+                std::fs::write(sub.join(format!("file{f}")), b"").unwrap();
+            }
+        }
+
+        let mut dirs = Vec::new();
+        let mut inodes = Vec::new();
+        for d in 0..100 {
+            let name = CString::new(format!("dir{d}")).unwrap();
+            let parent = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+            dirs.push(parent);
+            for f in 0..100 {
+                let name = CString::new(format!("file{f}")).unwrap();
+                let entry = fs.lookup(ctx, parent, &name).unwrap();
+                inodes.push((entry.inode, entry.attr.st_ino, d, f));
+            }
+            // The root is never closed.
+            assert!(fs.inode_fd_count() <= 64 + 1);
+        }
+
+        // The least recently used fds are the ones that were closed.
+        let has_fd = |inode| {
+            let data = fs.inodes.get(&inode).unwrap();
+            let open = data.file.lock_unpoisoned().is_some();
+            open
+        };
+        assert!(!has_fd(inodes[0].0));
+        assert!(has_fd(inodes[inodes.len() - 1].0));
+
+        // Everything closed along the way must come back as the same file.
+        for &(inode, ino, d, f) in inodes.iter().step_by(37) {
+            let (st, _) = fs.getattr(ctx, inode, None).unwrap();
+            assert_eq!(st.st_ino, ino);
+            let host = std::fs::metadata(dir.join(format!("dir{d}/file{f}"))).unwrap();
+            assert_eq!(st.st_ino, std::os::unix::fs::MetadataExt::ino(&host));
+        }
+        assert!(fs.inode_fd_count() <= 64 + 1);
+
+        // Files whose directory was renamed are reopened through its new name.
+        let old_name = CString::new("dir0").unwrap();
+        let new_name = CString::new("renamed").unwrap();
+        fs.rename(ctx, fuse::ROOT_ID, &old_name, fuse::ROOT_ID, &new_name, 0)
+            .unwrap();
+        for &(inode, ino, _, _) in inodes.iter().filter(|(_, _, d, _)| *d == 0) {
+            assert_eq!(fs.getattr(ctx, inode, None).unwrap().0.st_ino, ino);
+        }
+
+        // A file that lost its only name stays open, since there's nothing to reopen it by.
+        let (inode, ino, _, _) = inodes[100];
+        let name = CString::new("file0").unwrap();
+        fs.unlink(ctx, dirs[1], &name).unwrap();
+        for &(other, _, _, _) in &inodes[200..300] {
+            fs.getattr(ctx, other, None).unwrap();
+        }
+        let (st, _) = fs.getattr(ctx, inode, None).unwrap();
+        assert_eq!((st.st_ino, st.st_nlink), (ino, 0));
+        assert!(fs.inode_fd_count() <= 64 + 2);
+
+        for (inode, _, _, _) in inodes {
+            fs.forget(ctx, inode, 1);
+        }
+        assert!(fs.inode_fd_count() <= 64 + 1);
+    }
+
+    #[test]
+    fn mapped_xattr_names() {
+        let (tmp, fs, ctx) = test_fs(
+            "xattrmap",
+            Config {
+                xattr_map: xattrmap::parse_xattr_map(":map:trusted.:user.virtiofs.:").unwrap(),
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        let path = dir.join("file");
+        std::fs::write(&path, b"").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+
+        let opaque = CString::new("trusted.overlay.opaque").unwrap();
+        match fs.setxattr(ctx, inode, &opaque, b"y", 0) {
+            Ok(()) => {}
+            // The temp dir doesn't support user xattrs.
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        }
+
+        // It's stored on the host under the mapped name.
+        let host_path = CString::new(path.to_str().unwrap()).unwrap();
+        let host_name = CString::new("user.virtiofs.trusted.overlay.opaque").unwrap();
+        let mut value = [0u8; 8];
+        // Safe because the kernel only writes to `value`, which is large enough.
+        let len = unsafe {
+            libc::getxattr(
+                host_path.as_ptr(),
+                host_name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        assert_eq!(len, 1);
+        assert_eq!(value[0], b'y');
+
+        match fs.getxattr(ctx, inode, &opaque, 8).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"y"),
+            GetxattrReply::Count(_) => panic!("expected a value"),
+        }
+
+        // Listings show the guest's name, sized for it, and hide the host's other `user.virtiofs.`
+        // names.
+        let other = CString::new("user.virtiofs.other").unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::setxattr(
+                host_path.as_ptr(),
+                other.as_ptr(),
+                b"x".as_ptr() as *const libc::c_void,
+                1,
+                0,
+            )
+        };
+        assert_eq!(res, 0);
+        let expected = b"trusted.overlay.opaque\0";
+        match fs.listxattr(ctx, inode, 0).unwrap() {
+            ListxattrReply::Count(n) => assert_eq!(n as usize, expected.len()),
+            ListxattrReply::Names(_) => panic!("expected a count"),
+        }
+        match fs.listxattr(ctx, inode, 64).unwrap() {
+            ListxattrReply::Names(names) => assert_eq!(names, expected),
+            ListxattrReply::Count(_) => panic!("expected names"),
+        }
+        let err = fs.getxattr(ctx, inode, &other, 8).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        fs.removexattr(ctx, inode, &opaque).unwrap();
+        match fs.listxattr(ctx, inode, 0).unwrap() {
+            ListxattrReply::Count(n) => assert_eq!(n, 0),
+            ListxattrReply::Names(_) => panic!("expected a count"),
+        }
+    }
+
+    #[test]
+    fn inodes_tracked_by_file_handle() {
+        let (tmp, fs, ctx) = test_fs(
+            "handles",
+            Config {
+                inode_file_handles: true,
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        for f in 0..200 {
+            std::fs::write(dir.join(format!("file{f}")), b"").unwrap();
+        }
+
+        // Needs CAP_DAC_READ_SEARCH.
+        if !fs.file_handles || fs.mount_fd.read_unpoisoned().is_none() {
+            return;
+        }
+
+        let mut inodes = Vec::new();
+        for f in 0..200 {
+            let name = CString::new(format!("file{f}")).unwrap();
+            inodes.push(fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap());
+        }
+        // Only the root keeps an fd.
+        assert_eq!(fs.inode_fd_count(), 1);
+
+        // Looking a file up again finds the same inode through its handle.
+        let name = CString::new("file7").unwrap();
+        assert_eq!(
+            fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode,
+            inodes[7].inode
+        );
+        let (st, _) = fs.getattr(ctx, inodes[7].inode, None).unwrap();
+        assert_eq!(st.st_ino, inodes[7].attr.st_ino);
+
+        // A file deleted behind the guest's back is gone, rather than a stale handle.
+        std::fs::remove_file(dir.join("file9")).unwrap();
+        let err = fs.getattr(ctx, inodes[9].inode, None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(fs.inode_fd_count(), 1);
+    }
+
+    #[test]
+    fn copy_range_across_filesystems() {
+        // /dev/shm is a tmpfs, so it's on a different filesystem than the temp dir unless that is
+        // one too.
+        let shm = TempDir::new_with_prefix("/dev/shm/krun-fs-copy-").unwrap();
+        let src_path = shm.as_path().join("src");
+        let dir = test_dir("copy");
+        let dst_path = dir.as_path().join("dst");
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src_path, &data).unwrap();
+
+        let src = File::open(&src_path).unwrap();
+        let dst = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&dst_path)
+            .unwrap();
+
+        let n =
+            copy_range_fallback(src.as_raw_fd(), 1000, dst.as_raw_fd(), 10, 200 * 1024).unwrap();
+        assert_eq!(n, 200 * 1024);
+        let out = std::fs::read(&dst_path).unwrap();
+        assert_eq!(&out[..10], &[0u8; 10]);
+        assert_eq!(&out[10..], &data[1000..1000 + 200 * 1024]);
+
+        // Copying past the end of the source stops there.
+        let n = copy_range_fallback(
+            src.as_raw_fd(),
+            data.len() as u64 - 5,
+            dst.as_raw_fd(),
+            0,
+            100,
+        )
+        .unwrap();
+        assert_eq!(n, 5);
+    }
+
+    #[test]
+    fn metrics_count_operations() {
+        let metrics = Arc::new(FsMetrics::new());
+        let (tmp, fs, ctx) = test_fs(
+            "metrics",
+            Config {
+                metrics: metrics.clone(),
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("existing"), b"0123456789").unwrap();
+
+        // Two lookups of the same file, the second one finding it in the inode table.
+        let name = CString::new("existing").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap();
+        let missing = CString::new("missing").unwrap();
+        assert!(fs.lookup(ctx, fuse::ROOT_ID, &missing).is_err());
+
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+        let mut w = VecWriter(Vec::new());
+        fs.read(ctx, inode, handle, &mut w, 4, 0, None, 0).unwrap();
+        fs.read(ctx, inode, handle, &mut w, 64, 4, None, 0).unwrap();
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+
+        let new = CString::new("new").unwrap();
+        let (entry, handle, _) = fs
+            .create(
+                ctx,
+                fuse::ROOT_ID,
+                &new,
+                0o644,
+                false,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+        let handle = handle.unwrap();
+        let data = b"hello";
+        fs.write(
+            ctx,
+            entry.inode,
+            handle,
+            SliceReader(data),
+            data.len() as u32,
+            0,
+            None,
+            false,
+            false,
+            0,
+        )
+        .unwrap();
+        fs.release(ctx, entry.inode, 0, handle, false, false, None)
+            .unwrap();
+
+        let subdir = CString::new("subdir").unwrap();
+        fs.mkdir(ctx, fuse::ROOT_ID, &subdir, 0o755, 0, Extensions::default())
+            .unwrap();
+        fs.unlink(ctx, fuse::ROOT_ID, &new).unwrap();
+        fs.rmdir(ctx, fuse::ROOT_ID, &subdir).unwrap();
+
+        let expected = FsMetricsSnapshot {
+            lookups: 3,
+            inode_cache_hits: 1,
+            opens: 2,
+            creates: 2,
+            removes: 2,
+            reads: 2,
+            bytes_read: 10,
+            writes: 1,
+            bytes_written: 5,
+            fadvises: 0,
+            direct_io_dropped: 0,
+        };
+        assert_eq!(fs.metrics(), expected);
+        assert_eq!(metrics.snapshot(), expected);
+    }
+
+    #[test]
+    fn atime_policies() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir("atime");
+        let path = dir.as_path().join("file");
+
+        // Reads through `policy`, then asks for an atime update, and returns whether each one
+        // changed the file's atime on the host. The atime starts out older than the mtime, so the
+        // host's own relatime doesn't get in the way.
+        let run = |policy: AtimePolicy| -> (bool, bool) {
+            std::fs::write(&path, b"data").unwrap();
+            let old = std::fs::File::open(&path).unwrap();
+            let times = [
+                libc::timespec {
+                    tv_sec: 1000,
+                    tv_nsec: 0,
+                },
+                libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+            ];
+            // Safe because this doesn't modify any memory and we check the return value.
+            assert_eq!(
+                unsafe { libc::futimens(old.as_raw_fd(), times.as_ptr()) },
+                0
+            );
+
+            let fs = mount(
+                &dir,
+                Config {
+                    atime: policy,
+                    ..Default::default()
+                },
+            );
+            let ctx = Context {
+                uid: 0,
+                gid: 0,
+                pid: 0,
+            };
+
+            let name = CString::new("file").unwrap();
+            let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+            let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+            let handle = handle.unwrap();
+            let mut w = VecWriter(Vec::new());
+            fs.read(ctx, inode, handle, &mut w, 4, 0, None, 0).unwrap();
+            fs.release(ctx, inode, 0, handle, false, false, None)
+                .unwrap();
+            let read = std::fs::metadata(&path).unwrap().atime() != 1000;
+
+            // Push the atime past the mtime so only a strict policy touches it again.
+            let now = std::fs::metadata(&path).unwrap().mtime() + 10;
+            let times = [
+                libc::timespec {
+                    tv_sec: now,
+                    tv_nsec: 0,
+                },
+                times[1],
+            ];
+            // Safe because this doesn't modify any memory and we check the return value.
+            assert_eq!(
+                unsafe { libc::futimens(old.as_raw_fd(), times.as_ptr()) },
+                0
+            );
+            // Safe because stat64 is plain old data.
+            let attr: libc::stat64 = unsafe { mem::zeroed() };
+            fs.setattr(
+                ctx,
+                inode,
+                attr,
+                None,
+                SetattrValid::ATIME | SetattrValid::ATIME_NOW,
+            )
+            .unwrap();
+            let touched = std::fs::metadata(&path).unwrap().atime() != now;
+
+            (read, touched)
+        };
+
+        assert_eq!(run(AtimePolicy::Strict), (true, true));
+        assert_eq!(run(AtimePolicy::Relatime), (true, false));
+        assert_eq!(run(AtimePolicy::NoAtime), (false, false));
+    }
+
+    #[test]
+    fn op_timeout_unblocks_fifo_open() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let (tmp, fs, ctx) = test_fs(
+            "timeout",
+            Config {
+                op_timeout: Some(Duration::from_millis(200)),
+                blocking_threads: 1,
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        let fifo = dir.join("fifo");
+        let c_fifo = CString::new(fifo.to_str().unwrap()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        assert_eq!(unsafe { libc::mkfifo(c_fifo.as_ptr(), 0o600) }, 0);
+
+        let name = CString::new("fifo").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+
+        // Nobody ever writes to the FIFO, so opening it for reading never returns on its own.
+        let start = std::time::Instant::now();
+        let err = fs
+            .open(ctx, inode, false, libc::O_RDONLY as u32)
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Showing up as a writer lets the stuck open finish, and the fd it returns is closed.
+        let writer = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&fifo)
+            .unwrap();
+        fs.getattr(ctx, fuse::ROOT_ID, None).unwrap();
+        drop(writer);
+    }
+
+    #[test]
+    fn forget_root() {
+        let (tmp, fs, ctx) = test_fs("forget-root", Config::default());
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let file = CString::new("file").unwrap();
+        fs.forget(ctx, fuse::ROOT_ID, u64::MAX);
+        fs.batch_forget(ctx, vec![(fuse::ROOT_ID, u64::MAX)]);
+        fs.getattr(ctx, fuse::ROOT_ID, None).unwrap();
+        fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+
+        // The root is back after the guest unmounts and mounts the share again.
+        fs.destroy();
+        fs.init(FsOptions::empty()).unwrap();
+        fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+    }
+
+    #[test]
+    fn dot_entries() {
+        let (tmp, fs, ctx) = test_fs("dots", Config::default());
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join("sub").join(name), b"").unwrap();
+        }
+
+        let host_ino = |path: &std::path::Path| {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(path).unwrap().ino()
+        };
+        let sub = fs
+            .lookup(ctx, fuse::ROOT_ID, &CString::new("sub").unwrap())
+            .unwrap()
+            .inode;
+
+        // Reads one entry per call, resuming from the offset of the last one.
+        let list = |inode: Inode| -> Vec<(Vec<u8>, u64)> {
+            let (handle, _) = fs.opendir(ctx, inode, 0).unwrap();
+            let handle = handle.unwrap();
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            loop {
+                let mut next = None;
+                fs.readdir(ctx, inode, handle, 4096, offset, |entry| {
+                    if next.is_some() {
+                        return Ok(0);
+                    }
+                    next = Some((entry.name.to_vec(), entry.ino, entry.offset));
+                    Ok(1)
+                })
+                .unwrap();
+                let Some((name, ino, next_offset)) = next else {
+                    break;
+                };
+                entries.push((name, ino));
+                offset = next_offset;
+            }
+            fs.releasedir(ctx, inode, 0, handle).unwrap();
+            entries
+        };
+
+        let root = list(fuse::ROOT_ID);
+        assert_eq!(
+            root,
+            [
+                (b".".to_vec(), host_ino(dir)),
+                (b"..".to_vec(), host_ino(dir)),
+                (b"sub".to_vec(), host_ino(&dir.join("sub"))),
+            ]
+        );
+
+        let entries = list(sub);
+        assert_eq!(entries[0], (b".".to_vec(), host_ino(&dir.join("sub"))));
+        assert_eq!(entries[1], (b"..".to_vec(), host_ino(dir)));
+        let mut names: Vec<_> = entries[2..].iter().map(|(name, _)| name.clone()).collect();
+        names.sort();
+        assert_eq!(names, [&b"a"[..], b"b", b"c"]);
+
+        // Dots don't take a lookup reference in readdirplus, even at the root.
+        let (handle, _) = fs.opendir(ctx, fuse::ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut plus = Vec::new();
+        fs.readdirplus(ctx, fuse::ROOT_ID, handle, 4096, 0, |entry, e| {
+            plus.push((entry.name.to_vec(), e.inode));
+            Ok(1)
+        })
+        .unwrap();
+        fs.releasedir(ctx, fuse::ROOT_ID, 0, handle).unwrap();
+        assert_eq!(plus[..2], [(b".".to_vec(), 0), (b"..".to_vec(), 0)]);
+        assert_eq!(plus[2], (b"sub".to_vec(), sub));
+    }
+
+    #[test]
+    fn hidden_paths() {
+        let tmp = test_dir("hidden");
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join(".ssh")).unwrap();
+        std::fs::write(dir.join(".ssh/id_ed25519"), b"secret").unwrap();
+        std::fs::create_dir_all(dir.join("home/.aws")).unwrap();
+        std::fs::write(dir.join("home/notes"), b"notes").unwrap();
+        std::fs::write(dir.join("decoy"), b"decoy").unwrap();
+
+        // Hidden paths are resolved when the share is mounted.
+        let fs = mount(
+            &tmp,
+            Config {
+                hidden: vec![".ssh".into(), "home/.aws".into()],
+                ..Default::default()
+            },
+        );
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+
+        let errno = |res: io::Result<Entry>| res.err().and_then(|e| e.raw_os_error());
+        let list = |inode: Inode| -> Vec<Vec<u8>> {
+            let (handle, _) = fs.opendir(ctx, inode, 0).unwrap();
+            let handle = handle.unwrap();
+            let mut names = Vec::new();
+            fs.readdir(ctx, inode, handle, 4096, 0, |entry| {
+                if entry.name != b"." && entry.name != b".." {
+                    names.push(entry.name.to_vec());
+                }
+                Ok(1)
+            })
+            .unwrap();
+            fs.releasedir(ctx, inode, 0, handle).unwrap();
+            names.sort();
+            names
+        };
+        let ssh = CString::new(".ssh").unwrap();
+        let aws = CString::new(".aws").unwrap();
+        let home = CString::new("home").unwrap();
+
+        assert_eq!(
+            errno(fs.lookup(ctx, fuse::ROOT_ID, &ssh)),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(list(fuse::ROOT_ID), [&b"decoy"[..], b"home"]);
+
+        // Renaming the directory above a hidden one doesn't reveal it.
+        let moved = CString::new("moved").unwrap();
+        fs.rename(ctx, fuse::ROOT_ID, &home, fuse::ROOT_ID, &moved, 0)
+            .unwrap();
+        let moved = fs.lookup(ctx, fuse::ROOT_ID, &moved).unwrap().inode;
+        assert_eq!(errno(fs.lookup(ctx, moved, &aws)), Some(libc::ENOENT));
+        assert_eq!(list(moved), [b"notes"]);
+
+        // Nothing can be put in place of a hidden name, nor can it be moved away.
+        let decoy = CString::new("decoy").unwrap();
+        let err = fs
+            .rename(ctx, fuse::ROOT_ID, &decoy, fuse::ROOT_ID, &ssh, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err = fs
+            .rename(ctx, fuse::ROOT_ID, &ssh, fuse::ROOT_ID, &decoy, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(
+            errno(fs.mkdir(ctx, moved, &aws, 0o755, 0, Extensions::default())),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            fs.unlink(ctx, fuse::ROOT_ID, &ssh)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            std::fs::read(dir.join(".ssh/id_ed25519")).unwrap(),
+            b"secret"
+        );
+        assert_eq!(std::fs::read(dir.join("decoy")).unwrap(), b"decoy");
+    }
+
+    #[test]
+    fn one_filesystem() {
+        let tmp = test_dir("xdev");
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join("mnt")).unwrap();
+        std::fs::write(dir.join("file"), b"").unwrap();
+
+        // A tmpfs stands in for /proc or a network mount beneath the share.
+        let mnt = CString::new(dir.join("mnt").to_str().unwrap()).unwrap();
+        let tmpfs = CString::new("tmpfs").unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::mount(
+                tmpfs.as_ptr(),
+                mnt.as_ptr(),
+                tmpfs.as_ptr(),
+                0,
+                std::ptr::null(),
+            )
+        };
+        if res < 0 {
+            // Mounting needs CAP_SYS_ADMIN.
+            return;
+        }
+        std::fs::write(dir.join("mnt/inside"), b"inside").unwrap();
+
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let mnt_name = CString::new("mnt").unwrap();
+        let inside = CString::new("inside").unwrap();
+        let errno = |res: io::Result<Entry>| res.err().and_then(|e| e.raw_os_error());
+        let list = |fs: &PassthroughFs, inode: Inode| -> Vec<Vec<u8>> {
+            let (handle, _) = fs.opendir(ctx, inode, 0).unwrap();
+            let handle = handle.unwrap();
+            let mut names = Vec::new();
+            fs.readdir(ctx, inode, handle, 4096, 0, |entry| {
+                if entry.name != b"." && entry.name != b".." {
+                    names.push(entry.name.to_vec());
+                }
+                Ok(1)
+            })
+            .unwrap();
+            fs.releasedir(ctx, inode, 0, handle).unwrap();
+            names.sort();
+            names
+        };
+        let share = |empty_mount_points| {
+            mount(
+                &tmp,
+                Config {
+                    one_filesystem: true,
+                    empty_mount_points,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // The mount point is hidden.
+        let fs = share(false);
+        assert_eq!(
+            errno(fs.lookup(ctx, fuse::ROOT_ID, &mnt_name)),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(list(&fs, fuse::ROOT_ID), [b"file"]);
+
+        // Or shown as an empty directory that can't be changed.
+        let fs = share(true);
+        let entry = fs.lookup(ctx, fuse::ROOT_ID, &mnt_name).unwrap();
+        assert_eq!(entry.attr_flags & fuse::ATTR_SUBMOUNT, 0);
+        assert_eq!(list(&fs, fuse::ROOT_ID), [&b"file"[..], b"mnt"]);
+        assert_eq!(
+            errno(fs.lookup(ctx, entry.inode, &inside)),
+            Some(libc::ENOENT)
+        );
+        assert!(list(&fs, entry.inode).is_empty());
+        assert_eq!(
+            errno(fs.mkdir(ctx, entry.inode, &inside, 0o755, 0, Extensions::default())),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            fs.unlink(ctx, entry.inode, &inside)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOENT)
+        );
+
+        // Safe because this doesn't modify any memory.
+        unsafe { libc::umount2(mnt.as_ptr(), libc::MNT_DETACH) };
+    }
+
+    #[test]
+    fn names_stay_beneath_the_share() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = test_dir("beneath");
+        let base = tmp.as_path();
+        let dir = base.join("share");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(base.join("secret"), b"secret").unwrap();
+        symlink(base, dir.join("abs")).unwrap();
+        symlink("../../", dir.join("sub/up")).unwrap();
+
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        if fs.resolve_mode() != ResolveMode::Beneath {
+            return;
+        }
+
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let sub = CString::new("sub").unwrap();
+        let sub = fs.lookup(ctx, fuse::ROOT_ID, &sub).unwrap().inode;
+
+        // The links themselves can be looked up, but not gone through.
+        let abs = CString::new("abs").unwrap();
+        let entry = fs.lookup(ctx, fuse::ROOT_ID, &abs).unwrap();
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        for (parent, name) in [
+            (fuse::ROOT_ID, "abs/secret"),
+            (sub, "up/secret"),
+            (sub, "../../secret"),
+        ] {
+            let name = CString::new(name).unwrap();
+            let err = fs.lookup(ctx, parent, &name).err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::EXDEV), "{name:?}");
+        }
+
+        let name = CString::new("up/planted").unwrap();
+        let err = fs
+            .create(
+                ctx,
+                sub,
+                &name,
+                0o644,
+                false,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        assert!(!base.join("planted").exists());
+    }
+
+    #[test]
+    fn mnt_ids_without_statx() {
+        let tmp = test_dir("mntid");
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        std::fs::hard_link(dir.join("file"), dir.join("link")).unwrap();
+
+        let root = File::open(dir).unwrap();
+        let proc_self_fd = File::open("/proc/self/fd").unwrap();
+        let host_mnt_id = fdinfo_mnt_id(&root, &proc_self_fd).unwrap();
+        if let Ok((_, mnt_id)) = statx(&root, MntIds::Statx, &proc_self_fd) {
+            assert_eq!(mnt_id, host_mnt_id);
+        }
+
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let file = CString::new("file").unwrap();
+        let link = CString::new("link").unwrap();
+        for (mnt_ids, root_mnt_id) in [(MntIds::Fdinfo, host_mnt_id), (MntIds::Unknown, 0)] {
+            let mut fs = PassthroughFs::new(Config {
+                root_dir: dir.to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+            // Stands in for a kernel without `STATX_MNT_ID`.
+            fs.mnt_ids = mnt_ids;
+            fs.init(FsOptions::empty()).unwrap();
+            assert_eq!(fs.inodes.get(&fuse::ROOT_ID).unwrap().mnt_id, root_mnt_id);
+
+            // Both names of the file lead to the same inode, which isn't seen as a submount.
+            let entry = fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+            assert_eq!(entry.attr.st_size, 4);
+            assert_eq!(
+                fs.lookup(ctx, fuse::ROOT_ID, &link).unwrap().inode,
+                entry.inode
+            );
+            assert_eq!(fs.inodes.get(&entry.inode).unwrap().mnt_id, root_mnt_id);
+            let data = fs.inodes.get(&fuse::ROOT_ID).unwrap();
+            assert!(!on_other_mount(
+                &root,
+                b"file",
+                &data,
+                mnt_ids,
+                &proc_self_fd
+            ));
+        }
+    }
+
+    #[test]
+    fn serves_without_proc_mounted() {
+        use std::os::fd::OwnedFd;
+        use std::os::unix::fs::symlink;
+
+        let tmp = test_dir("noproc");
+        let dir = tmp.as_path();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        symlink("file", dir.join("link")).unwrap();
+
+        // The thread gets a root of its own, without `/proc` in it.
+        let root = CString::new(dir.to_str().unwrap()).unwrap();
+        std::thread::spawn(move || {
+            let proc_self_fd = OwnedFd::from(File::open("/proc/self/fd").unwrap());
+            // Safe because these don't modify any memory and we check the return values.
+            unsafe {
+                assert_eq!(libc::unshare(libc::CLONE_FS), 0);
+                if libc::chroot(root.as_ptr()) < 0 {
+                    // Changing the root needs CAP_SYS_CHROOT.
+                    return;
+                }
+                assert_eq!(libc::chdir(c"/".as_ptr()), 0);
+            }
+            assert!(!std::path::Path::new("/proc").exists());
+
+            let mut fs = PassthroughFs::new(Config {
+                root_dir: "/".to_string(),
+                proc_self_fd: Some(Arc::new(proc_self_fd)),
+                ..Default::default()
+            })
+            .unwrap();
+            fs.mnt_ids = MntIds::Fdinfo;
+            fs.init(FsOptions::empty()).unwrap();
+
+            let ctx = Context {
+                uid: 0,
+                gid: 0,
+                pid: 0,
+            };
+            let file = CString::new("file").unwrap();
+            let link = CString::new("link").unwrap();
+            let inode = fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap().inode;
+            assert_ne!(fs.inodes.get(&inode).unwrap().mnt_id, 0);
+
+            // Files are opened through the fd.
+            let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+            let mut w = VecWriter(Vec::new());
+            let n = fs
+                .read(ctx, inode, handle.unwrap(), &mut w, 64, 0, None, 0)
+                .unwrap();
+            assert_eq!(&w.0[..n], b"data");
+
+            // So are the extended attributes of a symlink.
+            let link_inode = fs.lookup(ctx, fuse::ROOT_ID, &link).unwrap().inode;
+            let name = CString::new("user.missing").unwrap();
+            let res = fs.getxattr(ctx, link_inode, &name, 0);
+            assert_eq!(
+                res.err().and_then(|e| e.raw_os_error()),
+                Some(libc::ENODATA)
+            );
+            assert!(matches!(
+                fs.listxattr(ctx, link_inode, 0),
+                Ok(ListxattrReply::Count(_))
+            ));
+        })
+        .join()
+        .unwrap();
+
+        // The rest of the process kept its root.
+        assert!(std::path::Path::new("/proc/self/fd").exists());
+    }
+
+    #[test]
+    fn direct_io() {
+        let dir = test_dir("direct");
+        std::fs::write(dir.as_path().join("file"), vec![7u8; 4096]).unwrap();
+
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let file = CString::new("file").unwrap();
+        // The flags of the host fd behind `handle`, as the kernel reports them.
+        let host_flags = |fs: &PassthroughFs, handle: Handle| {
+            let data = fs.handles.get(&handle).unwrap();
+            let fd = data.file.read_unpoisoned().as_raw_fd();
+            let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{fd}")).unwrap();
+            let flags = fdinfo
+                .lines()
+                .find_map(|line| line.strip_prefix("flags:"))
+                .unwrap();
+            i32::from_str_radix(flags.trim(), 8).unwrap()
+        };
+
+        for allow_direct_io in [false, true] {
+            let fs = mount(
+                &dir,
+                Config {
+                    allow_direct_io,
+                    ..Default::default()
+                },
+            );
+            let inode = fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap().inode;
+
+            let flags = (libc::O_RDONLY | libc::O_DIRECT) as u32;
+            let handle = match fs.open(ctx, inode, false, flags) {
+                Ok((handle, _)) => handle.unwrap(),
+                // The filesystem holding the temporary directory can't do direct I/O.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+                Err(e) => panic!("{e}"),
+            };
+            assert_eq!(
+                host_flags(&fs, handle) & libc::O_DIRECT != 0,
+                allow_direct_io
+            );
+            assert_eq!(fs.metrics().direct_io_dropped, u64::from(!allow_direct_io));
+            let (st, _) = fs.getattr(ctx, inode, None).unwrap();
+            assert!(st.st_blksize > 0);
+
+            fs.release(ctx, inode, 0, handle, false, false, None)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn read_only_share() {
+        let (tmp, fs, ctx) = test_fs(
+            "ro",
+            Config {
+                read_only: true,
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join("dir")).unwrap();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let file = CString::new("file").unwrap();
+        let dir_name = CString::new("dir").unwrap();
+        let new = CString::new("new").unwrap();
+        let xattr = CString::new("user.test").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap().inode;
+        let dir_inode = fs.lookup(ctx, fuse::ROOT_ID, &dir_name).unwrap().inode;
+
+        // Opening for writing hands out a read-only file, without truncating it.
+        let flags = (libc::O_RDWR | libc::O_TRUNC | libc::O_APPEND) as u32;
+        let (handle, _) = fs.open(ctx, inode, false, flags).unwrap();
+        let handle = handle.unwrap();
+        let mut w = VecWriter(Vec::new());
+        let n = fs.read(ctx, inode, handle, &mut w, 64, 0, None, 0).unwrap();
+        assert_eq!(&w.0[..n], b"data");
+
+        // Safe because all zeroes is a valid `stat64`.
+        let attr: libc::stat64 = unsafe { mem::zeroed() };
+        let results = [
+            fs.create(
+                ctx,
+                fuse::ROOT_ID,
+                &new,
+                0o644,
+                false,
+                0,
+                0,
+                Extensions::default(),
+            )
+            .map(drop),
+            fs.tmpfile(ctx, fuse::ROOT_ID, 0o644, 0, 0, Extensions::default())
+                .map(drop),
+            fs.mkdir(ctx, fuse::ROOT_ID, &new, 0o755, 0, Extensions::default())
+                .map(drop),
+            fs.mknod(
+                ctx,
+                fuse::ROOT_ID,
+                &new,
+                libc::S_IFIFO | 0o644,
+                0,
+                0,
+                Extensions::default(),
+            )
+            .map(drop),
+            fs.symlink(ctx, &file, fuse::ROOT_ID, &new, Extensions::default())
+                .map(drop),
+            fs.link(ctx, inode, fuse::ROOT_ID, &new).map(drop),
+            fs.unlink(ctx, fuse::ROOT_ID, &file),
+            fs.rmdir(ctx, fuse::ROOT_ID, &dir_name),
+            fs.rename(ctx, fuse::ROOT_ID, &file, dir_inode, &new, 0),
+            fs.setattr(ctx, inode, attr, None, SetattrValid::SIZE)
+                .map(drop),
+            fs.setxattr(ctx, inode, &xattr, b"x", 0),
+            fs.removexattr(ctx, inode, &xattr),
+            fs.write(
+                ctx,
+                inode,
+                handle,
+                SliceReader(b"more"),
+                4,
+                0,
+                None,
+                false,
+                false,
+                0,
+            )
+            .map(drop),
+            fs.fallocate(ctx, inode, handle, 0, 0, 4096),
+            fs.copyfilerange(ctx, inode, handle, 0, inode, handle, 2, 2, 0)
+                .map(drop),
+        ];
+        for (i, res) in results.into_iter().enumerate() {
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EROFS), "{i}");
+        }
+
+        // Nothing changed on the host, and lookups and reads still work.
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"data");
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+        fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+        let n = fs.read(ctx, inode, handle, &mut w, 64, 0, None, 0).unwrap();
+        assert_eq!(n, 4);
+
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+}
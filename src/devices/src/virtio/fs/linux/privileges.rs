@@ -0,0 +1,139 @@
+//! Giving up the capabilities the VMM doesn't need once the VM is set up.
+
+use std::io;
+
+use caps::{CapSet, Capability, CapsHashSet};
+
+/// What the passthrough filesystem uses while serving requests: switching to the guest's
+/// credentials (`ScopedUid` and `ScopedGid`), opening, owning and changing files regardless of
+/// their owner and mode, reopening files by handle, creating device nodes and setting file
+/// capabilities. `PassthroughFs::new` checks for `CAP_SETUID`, `CAP_SETGID` and `CAP_FOWNER` to
+/// decide whether to use them.
+pub const FS_CAPABILITIES: &[Capability] = &[
+    Capability::CAP_CHOWN,
+    Capability::CAP_DAC_OVERRIDE,
+    Capability::CAP_DAC_READ_SEARCH,
+    Capability::CAP_FOWNER,
+    Capability::CAP_FSETID,
+    Capability::CAP_MKNOD,
+    Capability::CAP_SETFCAP,
+    Capability::CAP_SETGID,
+    Capability::CAP_SETUID,
+];
+
+/// The capabilities to hold on to when dropping privileges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Keep {
+    /// The ones the filesystem backends use, out of those the process has.
+    FsCreds,
+    /// None at all.
+    None,
+}
+
+/// Drops every capability of the calling thread but the ones `keep` asks for, from its bounding,
+/// permitted, effective, inheritable and ambient sets, and sets `no_new_privs`, so that neither
+/// it nor the threads it starts from now on can get them back.
+///
+/// Capabilities belong to each thread, so threads that are already running keep theirs.
+pub fn drop_privileges(keep: Keep) -> io::Result<()> {
+    let permitted = caps::read(None, CapSet::Permitted).map_err(to_io)?;
+    let keep: CapsHashSet = match keep {
+        Keep::FsCreds => FS_CAPABILITIES
+            .iter()
+            .copied()
+            .filter(|cap| permitted.contains(cap))
+            .collect(),
+        Keep::None => CapsHashSet::new(),
+    };
+
+    // Shrinking the bounding set takes CAP_SETPCAP, which goes away with the rest.
+    if permitted.contains(&Capability::CAP_SETPCAP) {
+        caps::raise(None, CapSet::Effective, Capability::CAP_SETPCAP).map_err(to_io)?;
+        for cap in caps::read(None, CapSet::Bounding).map_err(to_io)? {
+            if !keep.contains(&cap) {
+                caps::drop(None, CapSet::Bounding, cap).map_err(to_io)?;
+            }
+        }
+    }
+
+    caps::clear(None, CapSet::Ambient).map_err(to_io)?;
+    let inheritable = caps::read(None, CapSet::Inheritable).map_err(to_io)?;
+    caps::set(None, CapSet::Inheritable, &(&inheritable & &keep)).map_err(to_io)?;
+    caps::set(None, CapSet::Effective, &keep).map_err(to_io)?;
+    caps::set(None, CapSet::Permitted, &keep).map_err(to_io)?;
+
+    // Safe because the call doesn't touch memory.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn to_io(err: caps::errors::CapsError) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::thread;
+
+    // The capability sets and `no_new_privs` of the calling thread, as the kernel reports them.
+    fn status() -> HashMap<String, u64> {
+        std::fs::read_to_string("/proc/thread-self/status")
+            .unwrap()
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let value = value.trim();
+                let value = match key {
+                    "NoNewPrivs" => value.parse().ok()?,
+                    k if k.starts_with("Cap") => u64::from_str_radix(value, 16).ok()?,
+                    _ => return None,
+                };
+                Some((key.to_string(), value))
+            })
+            .collect()
+    }
+
+    fn mask(caps: &[Capability]) -> u64 {
+        caps.iter().fold(0, |mask, cap| mask | cap.bitmask())
+    }
+
+    // Each test drops the privileges of a thread of its own, leaving the others alone.
+
+    #[test]
+    fn drop_all() {
+        thread::spawn(|| {
+            drop_privileges(Keep::None).unwrap();
+            let status = status();
+            assert_eq!(status["CapEff"], 0);
+            assert_eq!(status["CapPrm"], 0);
+            assert_eq!(status["CapInh"], 0);
+            assert_eq!(status["CapAmb"], 0);
+            assert_eq!(status["NoNewPrivs"], 1);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn keep_fs_creds() {
+        let before = status();
+        thread::spawn(move || {
+            drop_privileges(Keep::FsCreds).unwrap();
+            let status = status();
+            let fs = mask(FS_CAPABILITIES) & before["CapPrm"];
+            assert_eq!(status["CapEff"], fs);
+            assert_eq!(status["CapPrm"], fs);
+            if before["CapPrm"] & Capability::CAP_SETPCAP.bitmask() != 0 {
+                assert_eq!(status["CapBnd"], fs);
+            }
+            assert_eq!(status["NoNewPrivs"], 1);
+        })
+        .join()
+        .unwrap();
+    }
+}
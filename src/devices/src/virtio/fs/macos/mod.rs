@@ -1,2 +1,3 @@
 pub mod fs_utils;
 pub mod passthrough;
+mod posix_locks;
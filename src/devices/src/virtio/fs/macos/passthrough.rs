@@ -2,17 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::collections::btree_map;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap};
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, MaybeUninit};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
 use std::ptr::null_mut;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
@@ -21,15 +20,23 @@ use nix::errno::Errno;
 use utils::worker_message::WorkerMessage;
 
 use crate::virtio::fs::filesystem::SecContext;
+use crate::virtio::{ExitStatus, SharedExitStatus};
 
-use super::super::super::linux_errno::{linux_error, LINUX_ERANGE};
+use super::super::super::linux_errno::linux_error;
+use super::super::atime::AtimePolicy;
 use super::super::bindings;
 use super::super::filesystem::{
-    Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
-    ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    Advice, Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions,
+    GetxattrReply, ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
-use super::super::multikey::MultikeyBTreeMap;
+use super::super::hidden::HiddenNames;
+use super::super::idmap::IdMap;
+use super::super::locks::{MutexExt, RwLockExt};
+use super::super::metrics::{FsMetrics, FsMetricsSnapshot};
+use super::super::sharded::{ShardedMap, ShardedMultikeyMap};
+use super::super::xattrmap::{self, XattrRule};
+use super::posix_locks::{LockTable, LockType};
 
 const INIT_CSTR: &[u8] = b"init.krun\0";
 const XATTR_KEY: &[u8] = b"user.containers.override_stat\0";
@@ -37,6 +44,15 @@ const SECURITY_CAPABILITY: &[u8] = b"security.capability\0";
 
 const UID_MAX: u32 = u32::MAX - 1;
 
+// The ids that give a thread back the credentials of its process, from `<sys/kauth.h>`.
+const KAUTH_UID_NONE: libc::uid_t = !0 - 100;
+const KAUTH_GID_NONE: libc::gid_t = !0 - 100;
+
+// Lock types as encoded by the (Linux) FUSE client.
+const LINUX_F_RDLCK: u32 = 0;
+const LINUX_F_WRLCK: u32 = 1;
+const LINUX_F_UNLCK: u32 = 2;
+
 static INIT_BINARY: &[u8] = include_bytes!("../../../../init");
 
 type Inode = u64;
@@ -52,8 +68,13 @@ struct InodeData {
     inode: Inode,
     ino: u64,
     dev: i32,
+    // The `/.vol` path of this inode, built once since every path-based operation needs it.
+    path: CString,
     refcount: AtomicU64,
     unlinked_fd: AtomicI64,
+    // A mount point shown as an empty directory under `Config::one_filesystem`. Nothing in it can
+    // be looked up, listed or created, and it can't be changed.
+    sealed: bool,
 }
 
 enum InodeHandle {
@@ -153,6 +174,7 @@ struct HandleData {
     inode: Inode,
     file: RwLock<File>,
     dirstream: Mutex<DirStream>,
+    exported: AtomicBool,
 }
 
 fn ebadf() -> io::Error {
@@ -163,6 +185,185 @@ fn einval() -> io::Error {
     linux_error(io::Error::from_raw_os_error(libc::EINVAL))
 }
 
+// The POSIX locks the guest holds on a file, and the descriptor the host locks standing for them
+// are taken through. Dropping it releases the host locks.
+struct PosixLocks {
+    table: LockTable,
+    file: File,
+}
+
+// Decodes the `l_type` of a lock request from the Linux guest. Unlocking is `None`.
+fn lock_type(type_: u32) -> io::Result<Option<LockType>> {
+    match type_ {
+        LINUX_F_RDLCK => Ok(Some(LockType::Read)),
+        LINUX_F_WRLCK => Ok(Some(LockType::Write)),
+        LINUX_F_UNLCK => Ok(None),
+        _ => Err(einval()),
+    }
+}
+
+fn check_lock_range(lock: &fuse::FileLock) -> io::Result<()> {
+    if lock.start > i64::MAX as u64 || lock.end < lock.start {
+        return Err(einval());
+    }
+    Ok(())
+}
+
+// Sets the host lock on `start..=end` of `fd` to `type_`, without waiting. FUSE uses an inclusive
+// end offset where `OFFSET_MAX` means "up to the end of the file".
+fn host_setlk(fd: RawFd, start: u64, end: u64, type_: Option<LockType>) -> io::Result<()> {
+    if start > i64::MAX as u64 {
+        return Ok(());
+    }
+
+    // Safe because `flock` is a plain C struct for which all zeroes is a valid value.
+    let mut fl: libc::flock = unsafe { mem::zeroed() };
+    fl.l_type = match type_ {
+        Some(LockType::Read) => libc::F_RDLCK,
+        Some(LockType::Write) => libc::F_WRLCK,
+        None => libc::F_UNLCK,
+    };
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = start as libc::off_t;
+    fl.l_len = if end >= i64::MAX as u64 {
+        0
+    } else {
+        (end - start + 1) as libc::off_t
+    };
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fcntl(fd, libc::F_SETLK, &fl) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        // A conflicting lock may be reported as either EAGAIN or EACCES.
+        if err.raw_os_error() == Some(libc::EACCES) {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EAGAIN)));
+        }
+        return Err(linux_error(err));
+    }
+
+    Ok(())
+}
+
+// Takes the host locks that `table` calls for on `start..=end`.
+fn host_setlk_table(fd: RawFd, table: &LockTable, start: u64, end: u64) -> io::Result<()> {
+    for (start, end, type_) in table.segments(start, end) {
+        host_setlk(fd, start, end, type_)?;
+    }
+    Ok(())
+}
+
+// Deallocates `start..end` of `fd`, which must be within the file. The host only punches holes
+// in whole blocks of `blksize`, so the partial blocks at either end are overwritten with zeroes
+// instead, as Linux does.
+fn punch_hole(fd: RawFd, start: u64, end: u64, blksize: u64) -> io::Result<()> {
+    if start >= end {
+        return Ok(());
+    }
+
+    let blksize = blksize.max(1);
+    let hole_start = start.div_ceil(blksize) * blksize;
+    let hole_end = end / blksize * blksize;
+    if hole_start >= hole_end {
+        return write_zeroes(fd, start, end);
+    }
+
+    write_zeroes(fd, start, hole_start)?;
+    write_zeroes(fd, hole_end, end)?;
+
+    let args = libc::fpunchhole_t {
+        fp_flags: 0,
+        reserved: 0,
+        fp_offset: hole_start as libc::off_t,
+        fp_length: (hole_end - hole_start) as libc::off_t,
+    };
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fcntl(fd, libc::F_PUNCHHOLE, &args) };
+    if res < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+fn write_zeroes(fd: RawFd, start: u64, end: u64) -> io::Result<()> {
+    if start >= end {
+        return Ok(());
+    }
+
+    let zeroes = vec![0u8; (end - start) as usize];
+    let mut written = 0;
+    while written < zeroes.len() {
+        // Safe because this only reads from `zeroes` and we check the return value.
+        let res = unsafe {
+            libc::pwrite(
+                fd,
+                zeroes[written..].as_ptr() as *const libc::c_void,
+                zeroes.len() - written,
+                (start + written as u64) as libc::off_t,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(linux_error(err));
+        }
+        written += res as usize;
+    }
+
+    Ok(())
+}
+
+// Implements Linux's `SEEK_DATA` (`data` is true) and `SEEK_HOLE` on top of the host's. Offsets at
+// or past EOF fail with `ENXIO`, EOF counts as a hole, and file systems that can't report holes
+// are treated as one data region spanning the whole file. The file offset is left at the result,
+// like `lseek(2)` does.
+fn seek_data_hole(fd: RawFd, offset: u64, data: bool) -> io::Result<u64> {
+    let size = fstat(fd, None)?.st_size as u64;
+    if offset >= size {
+        return Err(linux_error(io::Error::from_raw_os_error(libc::ENXIO)));
+    }
+
+    let mwhence = if data {
+        libc::SEEK_DATA
+    } else {
+        libc::SEEK_HOLE
+    };
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::lseek(fd, offset as libc::off_t, mwhence) };
+    let pos = if res >= 0 {
+        (res as u64).min(size)
+    } else {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // No data between `offset` and EOF, which Linux reports the same way.
+            Some(libc::ENXIO) if data => {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::ENXIO)))
+            }
+            // macOS fails instead of pointing at the virtual hole at EOF.
+            Some(libc::ENXIO) => size,
+            Some(libc::EINVAL) | Some(libc::ENOTSUP) if data => offset,
+            Some(libc::EINVAL) | Some(libc::ENOTSUP) => size,
+            _ => return Err(linux_error(err)),
+        }
+    };
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_SET) };
+    if res < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+
+    Ok(pos)
+}
+
+fn vol_path(dev: i32, ino: u64) -> io::Result<CString> {
+    CString::new(format!("/.vol/{dev}/{ino}")).map_err(|_| einval())
+}
+
 fn item_to_value(item: &[u8], radix: u32) -> Option<u32> {
     match std::str::from_utf8(item) {
         Ok(val) => match u32::from_str_radix(val, radix) {
@@ -198,6 +399,7 @@ fn get_xattr_common(buf: &[u8]) -> io::Result<(Option<u32>, Option<u32>, Option<
 fn get_xattr_fstat(
     fd: RawFd,
     st: bindings::stat64,
+    key: &CStr,
 ) -> io::Result<(Option<u32>, Option<u32>, Option<u32>)> {
     let mut buf: Vec<u8> = vec![0; 32];
     let options = if (st.st_mode & libc::S_IFMT) == libc::S_IFLNK {
@@ -208,7 +410,7 @@ fn get_xattr_fstat(
     let res = unsafe {
         libc::fgetxattr(
             fd,
-            XATTR_KEY.as_ptr() as *const i8,
+            key.as_ptr(),
             buf.as_mut_ptr() as *mut libc::c_void,
             buf.len(),
             0,
@@ -228,6 +430,7 @@ fn get_xattr_fstat(
 fn get_xattr_lstat(
     path: &CString,
     st: bindings::stat64,
+    key: &CStr,
 ) -> io::Result<(Option<u32>, Option<u32>, Option<u32>)> {
     let mut buf: Vec<u8> = vec![0; 32];
     let options = if (st.st_mode & libc::S_IFMT) == libc::S_IFLNK {
@@ -238,7 +441,7 @@ fn get_xattr_lstat(
     let res = unsafe {
         libc::getxattr(
             path.as_ptr(),
-            XATTR_KEY.as_ptr() as *const i8,
+            key.as_ptr(),
             buf.as_mut_ptr() as *mut libc::c_void,
             buf.len(),
             0,
@@ -271,8 +474,9 @@ fn set_xattr_stat(
     st: Option<bindings::stat64>,
     owner: Option<(u32, u32)>,
     mode: Option<u32>,
+    key: &CStr,
 ) -> io::Result<()> {
-    let st = st.unwrap_or(istat(file, true)?);
+    let st = st.unwrap_or(istat(file, None)?);
     let options = if (st.st_mode & libc::S_IFMT) == libc::S_IFLNK {
         libc::XATTR_NOFOLLOW
     } else {
@@ -285,8 +489,8 @@ fn set_xattr_stat(
         format!("{}:{}:0{:o}", owner.0, owner.1, mode)
     } else {
         let (orig_uid, orig_gid, orig_mode) = match file {
-            InodeHandle::Fd(fd) => get_xattr_fstat(*fd, st)?,
-            InodeHandle::Path(ref c_path) => get_xattr_lstat(c_path, st)?,
+            InodeHandle::Fd(fd) => get_xattr_fstat(*fd, st, key)?,
+            InodeHandle::Path(ref c_path) => get_xattr_lstat(c_path, st, key)?,
         };
 
         let (uid, gid) = match owner {
@@ -323,7 +527,7 @@ fn set_xattr_stat(
         InodeHandle::Path(path) => unsafe {
             libc::setxattr(
                 path.as_ptr(),
-                XATTR_KEY.as_ptr() as *const i8,
+                key.as_ptr(),
                 buf.as_ptr() as *mut libc::c_void,
                 buf.len() as libc::size_t,
                 0,
@@ -333,7 +537,7 @@ fn set_xattr_stat(
         InodeHandle::Fd(fd) => unsafe {
             libc::fsetxattr(
                 *fd,
-                XATTR_KEY.as_ptr() as *const i8,
+                key.as_ptr(),
                 buf.as_ptr() as *mut libc::c_void,
                 buf.len() as libc::size_t,
                 0,
@@ -354,28 +558,27 @@ fn stat_common(
     uid: Option<u32>,
     gid: Option<u32>,
     mode: Option<u32>,
-    host: bool,
-) -> io::Result<bindings::stat64> {
-    if !host {
-        if let Some(uid) = uid {
-            st.st_uid = uid;
-        }
-        if let Some(gid) = gid {
-            st.st_gid = gid;
-        }
-        if let Some(mode) = mode {
-            if mode as u16 & libc::S_IFMT == 0 {
-                st.st_mode = (st.st_mode & libc::S_IFMT) | mode as u16;
-            } else {
-                st.st_mode = mode as u16;
-            }
+) -> bindings::stat64 {
+    if let Some(uid) = uid {
+        st.st_uid = uid;
+    }
+    if let Some(gid) = gid {
+        st.st_gid = gid;
+    }
+    if let Some(mode) = mode {
+        if mode as u16 & libc::S_IFMT == 0 {
+            st.st_mode = (st.st_mode & libc::S_IFMT) | mode as u16;
+        } else {
+            st.st_mode = mode as u16;
         }
     }
 
-    Ok(st)
+    st
 }
 
-fn fstat(fd: RawFd, host: bool) -> io::Result<bindings::stat64> {
+// Stats `fd`, laying the ownership recorded in the `key` attribute over the host's when given
+// one.
+fn fstat(fd: RawFd, key: Option<&CStr>) -> io::Result<bindings::stat64> {
     let mut st = MaybeUninit::<bindings::stat64>::zeroed();
 
     // Safe because the kernel will only write data in `st` and we check the return
@@ -384,18 +587,19 @@ fn fstat(fd: RawFd, host: bool) -> io::Result<bindings::stat64> {
     if res >= 0 {
         // Safe because the kernel guarantees that the struct is now fully initialized.
         let st = unsafe { st.assume_init() };
-        if !host {
-            let (uid, gid, mode) = get_xattr_fstat(fd, st)?;
-            stat_common(st, uid, gid, mode, host)
-        } else {
-            Ok(st)
+        match key {
+            Some(key) => {
+                let (uid, gid, mode) = get_xattr_fstat(fd, st, key)?;
+                Ok(stat_common(st, uid, gid, mode))
+            }
+            None => Ok(st),
         }
     } else {
         Err(linux_error(io::Error::last_os_error()))
     }
 }
 
-fn lstat(c_path: &CString, host: bool) -> io::Result<bindings::stat64> {
+fn lstat(c_path: &CString, key: Option<&CStr>) -> io::Result<bindings::stat64> {
     let mut st = MaybeUninit::<bindings::stat64>::zeroed();
 
     // Safe because the kernel will only write data in `st` and we check the return
@@ -404,21 +608,112 @@ fn lstat(c_path: &CString, host: bool) -> io::Result<bindings::stat64> {
     if res >= 0 {
         // Safe because the kernel guarantees that the struct is now fully initialized.
         let st = unsafe { st.assume_init() };
-        if !host {
-            let (uid, gid, mode) = get_xattr_lstat(c_path, st)?;
-            stat_common(st, uid, gid, mode, host)
-        } else {
-            Ok(st)
+        match key {
+            Some(key) => {
+                let (uid, gid, mode) = get_xattr_lstat(c_path, st, key)?;
+                Ok(stat_common(st, uid, gid, mode))
+            }
+            None => Ok(st),
         }
     } else {
         Err(linux_error(io::Error::last_os_error()))
     }
 }
 
-fn istat(ihandle: &InodeHandle, host: bool) -> io::Result<bindings::stat64> {
+fn istat(ihandle: &InodeHandle, key: Option<&CStr>) -> io::Result<bindings::stat64> {
     match ihandle {
-        InodeHandle::Fd(fd) => fstat(*fd, host),
-        InodeHandle::Path(ref c_path) => lstat(c_path, host),
+        InodeHandle::Fd(fd) => fstat(*fd, key),
+        InodeHandle::Path(ref c_path) => lstat(c_path, key),
+    }
+}
+
+// Changes the host owner of `file`, leaving either id as it is if it's `u32::MAX`.
+fn chown(file: &InodeHandle, uid: u32, gid: u32) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = match file {
+        InodeHandle::Fd(fd) => unsafe { libc::fchown(*fd, uid, gid) },
+        InodeHandle::Path(path) => unsafe { libc::lchown(path.as_ptr(), uid, gid) },
+    };
+    if res < 0 {
+        Err(linux_error(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+// Changes the host permission bits of `file` to those in `mode`.
+fn chmod(file: &InodeHandle, mode: u32) -> io::Result<()> {
+    let mode = (mode & 0o7777) as libc::mode_t;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = match file {
+        InodeHandle::Fd(fd) => unsafe { libc::fchmod(*fd, mode) },
+        InodeHandle::Path(path) => unsafe {
+            libc::fchmodat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                mode,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        },
+    };
+    if res < 0 {
+        Err(linux_error(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+// Keeps the data read and written through `fd` out of the host's cache, which is as close as macOS
+// gets to `O_DIRECT`.
+fn set_nocache(fd: RawFd) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::fcntl(fd, libc::F_NOCACHE, 1) } < 0 {
+        Err(linux_error(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+// Converts a device number as the Linux guest encodes it to the host's encoding.
+fn host_rdev(rdev: u32) -> libc::dev_t {
+    let major = (rdev >> 8) & 0xfff;
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xfff00);
+    libc::makedev(major as i32, minor as i32)
+}
+
+extern "C" {
+    fn pthread_setugid_np(uid: libc::uid_t, gid: libc::gid_t) -> libc::c_int;
+}
+
+// Makes the calling thread act as a host uid and gid, so the files it creates are theirs and
+// their permissions are the ones checked, until dropped.
+//
+// `seteuid` and `setegid` would switch every thread of the process, including the other fs
+// workers, so this uses `pthread_setugid_np` instead. It only works for root, is marked deprecated
+// (though nothing replaces it), and a thread that has switched has to switch back before it can
+// switch again, so guards can't nest. The thread keeps the supplementary groups of the process.
+#[derive(Debug)]
+struct ScopedCreds;
+
+impl ScopedCreds {
+    fn new(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<ScopedCreds> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { pthread_setugid_np(uid, gid) } < 0 {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+        Ok(ScopedCreds)
+    }
+}
+
+impl Drop for ScopedCreds {
+    fn drop(&mut self) {
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { pthread_setugid_np(KAUTH_UID_NONE, KAUTH_GID_NONE) } < 0 {
+            error!(
+                "failed to change credentials back to the process's: {}",
+                io::Error::last_os_error(),
+            );
+        }
     }
 }
 
@@ -457,6 +752,33 @@ impl FromStr for CachePolicy {
     }
 }
 
+/// Where the file system keeps the owner and mode the guest sets on files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnershipMode {
+    /// They're recorded in the extended attribute `key`, and the host files keep the owner and
+    /// mode the VMM gave them. This needs no privileges, and lets the guest have device nodes and
+    /// other special files. The guest can't see or change the attribute itself.
+    XattrEmulation { key: CString },
+
+    /// They're applied to the host files with `chown` and `chmod`. Changing owners needs the VMM
+    /// to run as root; otherwise the guest gets `EPERM`. When the VMM runs as root, files are also
+    /// created with the credentials of the guest process creating them, so the host checks its
+    /// permissions on the directory.
+    Native,
+
+    /// The guest sees the host files' owners, and its owner changes are dropped. Modes are still
+    /// applied to the host files.
+    Ignore,
+}
+
+impl Default for OwnershipMode {
+    fn default() -> Self {
+        OwnershipMode::XattrEmulation {
+            key: CStr::from_bytes_with_nul(XATTR_KEY).unwrap().to_owned(),
+        }
+    }
+}
+
 /// Options that configure the behavior of the file system.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -505,6 +827,23 @@ pub struct Config {
     /// The default value for this options is `false`.
     pub xattr: bool,
 
+    /// Translation between guest uids and the uids recorded as owners on the host. Ownership
+    /// reported to the guest is shifted through it, and so are the ids of files the guest creates
+    /// or chowns.
+    ///
+    /// The default is the identity map.
+    pub uid_map: IdMap,
+
+    /// Same as `uid_map`, for gids.
+    ///
+    /// The default is the identity map.
+    pub gid_map: IdMap,
+
+    /// Which access time updates reach the host.
+    ///
+    /// The default is `AtimePolicy::Strict`.
+    pub atime: AtimePolicy,
+
     /// Optional file descriptor for /proc/self/fd. Callers can obtain a file descriptor and pass it
     /// here, so there's no need to open it in PassthroughFs::new(). This is specially useful for
     /// sandboxing.
@@ -512,11 +851,69 @@ pub struct Config {
     /// The default is `None`.
     pub proc_sfd_rawfd: Option<RawFd>,
 
-    /// ID of this filesystem to uniquely identify exports. Not supported for macos.
+    /// ID of this filesystem to uniquely identify exports.
     pub export_fsid: u64,
-    /// Table of exported FDs to share with other subsystems. Not supported for macos.
+    /// Table of exported FDs to share with other subsystems.
     pub export_table: Option<ExportTable>,
     pub allow_root_dir_delete: bool,
+
+    /// Counters of the operations this filesystem serves. Keep a clone of the `Arc` to read them
+    /// while the filesystem is in use.
+    ///
+    /// The default is a new, zeroed set of counters.
+    pub metrics: Arc<FsMetrics>,
+
+    /// What to serve as `init.krun` instead of the init binary built into the library. Check it
+    /// with `validate_init_binary` first.
+    ///
+    /// The default is `None`, serving the built-in one.
+    pub init_binary: Option<Vec<u8>>,
+
+    /// Paths, relative to `root_dir`, that the guest can't see: looking them up fails with
+    /// `ENOENT`, they're left out of directory listings, and creating or renaming anything onto
+    /// them fails with `EPERM`. The directories leading to them must exist when the guest mounts
+    /// the share, and they stay hidden if the guest renames those directories.
+    ///
+    /// The default is empty.
+    pub hidden: Vec<PathBuf>,
+
+    /// How extended attribute names are translated between the guest and the host. Listings show
+    /// the guest's names. The attribute holding the guest's view of file ownership can't be
+    /// reached through it.
+    ///
+    /// The default is empty, which leaves names as they are.
+    pub xattr_map: Vec<XattrRule>,
+
+    /// Whether the guest is kept on the host volume holding `root_dir`. Looking up anything
+    /// mounted beneath it fails with `ENOENT`, and directory listings leave it out.
+    ///
+    /// The default value for this option is `false`.
+    pub one_filesystem: bool,
+
+    /// With `one_filesystem`, show directories that other volumes are mounted on as empty
+    /// directories instead of hiding them.
+    ///
+    /// The default value for this option is `false`.
+    pub empty_mount_points: bool,
+
+    /// Whether the guest is kept from changing anything in the share, whatever the host
+    /// permissions would allow. Operations that would change it fail with `EROFS`, and files
+    /// are only opened for reading.
+    ///
+    /// The default value for this option is `false`.
+    pub read_only: bool,
+
+    /// Whether files the guest opens with `O_DIRECT` bypass the host's cache, with `F_NOCACHE`.
+    /// Otherwise the flag is dropped, and counted in `FsMetricsSnapshot::direct_io_dropped`.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_direct_io: bool,
+
+    /// How the owner and mode the guest gives files are kept on the host.
+    ///
+    /// The default is `OwnershipMode::XattrEmulation` with the `user.containers.override_stat`
+    /// attribute.
+    pub ownership_mode: OwnershipMode,
 }
 
 impl Default for Config {
@@ -528,10 +925,22 @@ impl Default for Config {
             writeback: false,
             root_dir: String::from("/"),
             xattr: true,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            atime: AtimePolicy::default(),
             proc_sfd_rawfd: None,
             export_fsid: 0,
             export_table: None,
             allow_root_dir_delete: false,
+            metrics: Arc::new(FsMetrics::new()),
+            init_binary: None,
+            hidden: Vec::new(),
+            xattr_map: Vec::new(),
+            one_filesystem: false,
+            empty_mount_points: false,
+            read_only: false,
+            allow_direct_io: false,
+            ownership_mode: OwnershipMode::default(),
         }
     }
 }
@@ -542,20 +951,31 @@ impl Default for Config {
 /// directory ends up as the root of the file system process. One way to accomplish this is via a
 /// combination of mount namespaces and the pivot_root system call.
 pub struct PassthroughFs {
-    inodes: RwLock<MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>>,
+    // Both tables are sharded so that requests for different files rarely contend on the same lock.
+    inodes: ShardedMultikeyMap<Inode, InodeAltKey, Arc<InodeData>>,
     next_inode: AtomicU64,
     init_inode: u64,
 
-    handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    handles: ShardedMap<Handle, Arc<HandleData>>,
     next_handle: AtomicU64,
     init_handle: u64,
 
     map_windows: Mutex<HashMap<u64, u64>>,
 
+    // The POSIX locks of the guest, by inode. See `posix_locks`.
+    posix_locks: Mutex<HashMap<Inode, PosixLocks>>,
+
     // Whether writeback caching is enabled for this directory. This will only be true when
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
     announce_submounts: AtomicBool,
+
+    // `Config::hidden`, resolved when the guest mounts the share.
+    hidden: RwLock<HiddenNames>,
+
+    // Whether files are created with the guest's credentials. See `set_creds`.
+    create_as_guest: bool,
+
     cfg: Config,
 }
 
@@ -577,36 +997,40 @@ impl PassthroughFs {
 
         unsafe { libc::close(fd) };
 
+        // SAFETY: This syscall is always safe to call and always succeeds.
+        let create_as_guest =
+            cfg.ownership_mode == OwnershipMode::Native && unsafe { libc::geteuid() } == 0;
+
         Ok(PassthroughFs {
-            inodes: RwLock::new(MultikeyBTreeMap::new()),
+            inodes: ShardedMultikeyMap::new(),
             next_inode: AtomicU64::new(fuse::ROOT_ID + 2),
             init_inode: fuse::ROOT_ID + 1,
 
-            handles: RwLock::new(BTreeMap::new()),
+            handles: ShardedMap::new(),
             next_handle: AtomicU64::new(1),
             init_handle: 0,
 
             map_windows: Mutex::new(HashMap::new()),
+            posix_locks: Mutex::new(HashMap::new()),
 
             writeback: AtomicBool::new(false),
             announce_submounts: AtomicBool::new(false),
+            hidden: RwLock::new(HiddenNames::default()),
+            create_as_guest,
             cfg,
         })
     }
 
+    /// Returns the current values of this filesystem's operation counters.
+    pub fn metrics(&self) -> FsMetricsSnapshot {
+        self.cfg.metrics.snapshot()
+    }
+
     fn inode_to_handle(&self, inode: Inode, supports_fd: bool) -> io::Result<InodeHandle> {
         debug!("inode_to_handle: inode={inode}");
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&inode).ok_or_else(ebadf)?;
 
-        let cstr =
-            CString::new(format!("/.vol/{}/{}", data.dev, data.ino)).map_err(|_| einval())?;
-        debug!("inode_to_handle: path={}", cstr.to_string_lossy());
+        debug!("inode_to_handle: path={:?}", data.path);
 
         if supports_fd {
             let unlinked_fd = data.unlinked_fd.load(Ordering::Acquire);
@@ -615,7 +1039,16 @@ impl PassthroughFs {
             }
         }
 
-        Ok(InodeHandle::Path(cstr))
+        Ok(InodeHandle::Path(data.path.clone()))
+    }
+
+    // Whether `name` in the directory `parent` is on another volume than `dev`.
+    fn on_other_volume(&self, parent: Inode, name: &[u8], dev: i32) -> bool {
+        CString::new(name)
+            .ok()
+            .and_then(|name| self.name_to_path(parent, &name).ok())
+            .and_then(|path| lstat(&path, None).ok())
+            .is_some_and(|st| st.st_dev != dev)
     }
 
     fn name_to_path(&self, parent: Inode, name: &CStr) -> io::Result<CString> {
@@ -624,25 +1057,54 @@ impl PassthroughFs {
             parent,
             name.to_string_lossy()
         );
-        let data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
 
-        let cstr = CString::new(format!(
-            "/.vol/{}/{}/{}",
-            data.dev,
-            data.ino,
-            name.to_string_lossy()
-        ))
-        .map_err(|_| einval())?;
+        // Names are arbitrary bytes, so append them as-is rather than through a `String`, which
+        // would replace anything that isn't UTF-8 and point at a different file.
+        let mut path = format!("/.vol/{}/{}/", data.dev, data.ino).into_bytes();
+        path.extend_from_slice(name.to_bytes());
+        let cstr = CString::new(path).map_err(|_| einval())?;
         debug!("name_to_path: path={}", cstr.to_string_lossy());
         Ok(cstr)
     }
 
+    // Fails with `err` if `name` in the directory `parent` is one of `Config::hidden`.
+    fn check_hidden(&self, parent: Inode, name: &CStr, err: i32) -> io::Result<()> {
+        let data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        if data.sealed
+            || self
+                .hidden
+                .read_unpoisoned()
+                .contains(data.dev as u64, data.ino, name.to_bytes())
+        {
+            return Err(linux_error(io::Error::from_raw_os_error(err)));
+        }
+        Ok(())
+    }
+
+    // Fails with `EROFS` if the share is read-only.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.cfg.read_only {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EROFS)));
+        }
+        Ok(())
+    }
+
+    // Fails with `EPERM` if `inode` is a sealed mount point.
+    fn check_sealed(&self, inode: Inode) -> io::Result<()> {
+        match self.inodes.get(&inode) {
+            Some(data) if data.sealed => {
+                Err(linux_error(io::Error::from_raw_os_error(libc::EPERM)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The bytes served as `init.krun`.
+    fn init_binary(&self) -> &[u8] {
+        self.cfg.init_binary.as_deref().unwrap_or(INIT_BINARY)
+    }
+
     fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
         // When writeback caching is enabled, the kernel may send read requests even if the
         // userspace program opened the file write-only. So we need to ensure that we have opened
@@ -683,29 +1145,26 @@ impl PassthroughFs {
     }
 
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
-        let parent_data = self
-            .inodes
-            .read()
-            .unwrap()
-            .get(&parent)
-            .cloned()
-            .ok_or_else(ebadf)?;
+        let parent_data = self.inodes.get(&parent).ok_or_else(ebadf)?;
+        self.check_hidden(parent, name, libc::ENOENT)?;
 
         let c_path = self.name_to_path(parent, name)?;
-        let st = lstat(&c_path, false)?;
+        let st = lstat(&c_path, self.ownership_key())?;
 
-        debug!(
-            "do_lookup: inode={} path={}",
-            st.st_ino,
-            c_path.to_str().unwrap()
-        );
+        debug!("do_lookup: inode={} path={:?}", st.st_ino, c_path);
+
+        let is_dir = st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        let submount = st.st_dev != parent_data.dev;
+
+        // With `Config::one_filesystem` the guest doesn't get past mount points.
+        let sealed = submount && self.cfg.one_filesystem;
+        if sealed && !(is_dir && self.cfg.empty_mount_points) {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::ENOENT)));
+        }
 
         let mut attr_flags: u32 = 0;
 
-        if st.st_mode & libc::S_IFMT == libc::S_IFDIR
-            && self.announce_submounts.load(Ordering::Relaxed)
-            && (st.st_dev != parent_data.dev)
-        {
+        if is_dir && submount && !sealed && self.announce_submounts.load(Ordering::Relaxed) {
             attr_flags |= fuse::ATTR_SUBMOUNT;
         }
 
@@ -713,18 +1172,19 @@ impl PassthroughFs {
             ino: st.st_ino,
             dev: st.st_dev,
         };
-        let data = self.inodes.read().unwrap().get_alt(&altkey).cloned();
+        let data = self.inodes.get_alt(&altkey);
 
         let inode = if let Some(data) = data {
             // Matches with the release store in `forget`.
             data.refcount.fetch_add(1, Ordering::Acquire);
+            self.cfg.metrics.inode_cache_hit();
             data.inode
         } else {
             // There is a possible race here where 2 threads end up adding the same file
             // into the inode list.  However, since each of those will get a unique Inode
             // value and unique file descriptors this shouldn't be that much of a problem.
             let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-            self.inodes.write().unwrap().insert(
+            self.inodes.insert(
                 inode,
                 InodeAltKey {
                     ino: st.st_ino,
@@ -734,14 +1194,19 @@ impl PassthroughFs {
                     inode,
                     ino: st.st_ino,
                     dev: st.st_dev,
+                    path: vol_path(st.st_dev, st.st_ino)?,
                     refcount: AtomicU64::new(1),
                     unlinked_fd: AtomicI64::new(-1),
+                    sealed,
                 }),
             );
 
             inode
         };
 
+        let mut st = st;
+        self.shift_to_guest(&mut st);
+
         Ok(Entry {
             inode,
             generation: 0,
@@ -769,18 +1234,31 @@ impl PassthroughFs {
 
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        let mut ds = data.dirstream.lock().unwrap();
+        let dir = self.inodes.get(&inode).ok_or_else(ebadf)?;
+
+        // Copied so that the lock isn't held while `add_entry` looks entries up.
+        let hidden = self
+            .hidden
+            .read_unpoisoned()
+            .in_dir(dir.dev as u64, dir.ino)
+            .cloned();
+        // Entries on other volumes are left out, unless they're shown as empty directories.
+        let skip_mounts = self.cfg.one_filesystem && !self.cfg.empty_mount_points;
+
+        let mut ds = data.dirstream.lock_unpoisoned();
 
         if !ds.ready {
-            // Fill the cache on first call
-            if let Err(e) = ds.fill_from_fd(data.file.write().unwrap().as_raw_fd()) {
+            // Fill the cache on first call. Sealed mount points are listed as empty.
+            let res = if dir.sealed {
+                Ok(())
+            } else {
+                ds.fill_from_fd(data.file.write_unpoisoned().as_raw_fd())
+            };
+            if let Err(e) = res {
                 if ds.entries.is_empty() {
                     return Err(e);
                 }
@@ -789,12 +1267,39 @@ impl PassthroughFs {
                 // the error.
                 warn!("virtio-fs: error in readdir {}: {:?}", inode, e);
             }
+
+            // The host's "." and ".." are left out by `fill_from_fd`, since the root's ".." is
+            // outside the share. They're put back first, with the share's own inodes.
+            let parent_ino = if inode == fuse::ROOT_ID {
+                dir.ino
+            } else {
+                let parent = CString::new("..").unwrap();
+                lstat(&self.name_to_path(inode, &parent)?, None)?.st_ino
+            };
+            ds.entries.splice(
+                0..0,
+                [(&b"."[..], dir.ino), (b"..", parent_ino)].map(|(name, ino)| CachedDirEntry {
+                    ino,
+                    name: name.into(),
+                    type_: libc::DT_DIR,
+                }),
+            );
             ds.ready = true;
         }
 
         while let Some(entry) = ds.get_entry(offset) {
             offset += 1;
 
+            // "." and ".." are never hidden or on another volume.
+            if offset > 2
+                && (hidden
+                    .as_ref()
+                    .is_some_and(|hidden| hidden.contains(entry.name))
+                    || (skip_mounts && self.on_other_volume(inode, entry.name, dir.dev)))
+            {
+                continue;
+            }
+
             let name = entry.name;
             match add_entry(entry) {
                 Ok(size) => {
@@ -822,21 +1327,28 @@ impl PassthroughFs {
         kill_priv: bool,
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        self.cfg.metrics.open();
+        let direct = self.direct_io(flags as i32);
         let flags = self.parse_open_flags(flags as i32);
 
-        let file = RwLock::new(self.open_inode(inode, flags)?);
+        let file = self.open_inode(inode, flags)?;
+        if direct {
+            set_nocache(file.as_raw_fd())?;
+        }
+        let file = RwLock::new(file);
 
         // If O_TRUNC and kill_priv (OPEN_KILL_SUIDGID), clear security.capability and suid/sgid
         if (flags & libc::O_TRUNC) != 0 && kill_priv {
-            let fd = file.read().unwrap().as_raw_fd();
+            let fd = file.read_unpoisoned().as_raw_fd();
             let ihandle = InodeHandle::Fd(fd);
 
             remove_security_capability(&ihandle);
 
-            if let Ok(st) = fstat(fd, false) {
+            if let Ok(st) = fstat(fd, self.ownership_key()) {
                 let new_mode = clear_suid_sgid(st.st_mode as u32);
                 if new_mode != st.st_mode as u32 {
-                    if let Err(err) = set_xattr_stat(&ihandle, Some(st), None, Some(new_mode)) {
+                    if let Err(err) = self.set_owner_perms(&ihandle, Some(st), None, Some(new_mode))
+                    {
                         error!("Couldn't clear suid/sgid for inode {inode}: {err}");
                     }
                 }
@@ -848,9 +1360,10 @@ impl PassthroughFs {
             inode,
             file,
             dirstream: Mutex::new(DirStream::new()),
+            exported: Default::default(),
         };
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.handles.insert(handle, Arc::new(data));
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -870,26 +1383,92 @@ impl PassthroughFs {
     }
 
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
-        let mut handles = self.handles.write().unwrap();
-
-        if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
-            if e.get().inode == inode {
-                // We don't need to close the file here because that will happen automatically when
-                // the last `Arc` is dropped.
-                e.remove();
-                return Ok(());
+        // We don't need to close the file here because that will happen automatically when the
+        // last `Arc` is dropped.
+        let data = self
+            .handles
+            .remove_if(&handle, |hd| hd.inode == inode)
+            .ok_or_else(ebadf)?;
+
+        // The export ioctl marks handles while holding their shard's lock, so the flag can't
+        // change once the handle is out of the table.
+        if data.exported.load(Ordering::Relaxed) {
+            if let Some(export_table) = &self.cfg.export_table {
+                export_table
+                    .lock_unpoisoned()
+                    .remove(&(self.cfg.export_fsid, handle));
             }
         }
 
-        Err(ebadf())
+        drop(data);
+        self.reassert_posix_locks(inode);
+
+        Ok(())
+    }
+
+    // Rewrites the ownership in `st` from host ids to the ids the guest knows them by.
+    fn shift_to_guest(&self, st: &mut bindings::stat64) {
+        st.st_uid = self.cfg.uid_map.to_guest(st.st_uid);
+        st.st_gid = self.cfg.gid_map.to_guest(st.st_gid);
+    }
+
+    // The attribute holding the guest's view of ownership, if the guest's view isn't the host's.
+    fn ownership_key(&self) -> Option<&CStr> {
+        match &self.cfg.ownership_mode {
+            OwnershipMode::XattrEmulation { key } => Some(key),
+            OwnershipMode::Native | OwnershipMode::Ignore => None,
+        }
+    }
+
+    // Gives `file` the owner and mode the guest asked for, as `Config::ownership_mode` says to.
+    // A `u32::MAX` id in `owner` leaves that id as it is. With `XattrEmulation`, `st` can be the
+    // host's stat of `file` if the caller already has it.
+    fn set_owner_perms(
+        &self,
+        file: &InodeHandle,
+        st: Option<bindings::stat64>,
+        owner: Option<(u32, u32)>,
+        mode: Option<u32>,
+    ) -> io::Result<()> {
+        match &self.cfg.ownership_mode {
+            OwnershipMode::XattrEmulation { key } => set_xattr_stat(file, st, owner, mode, key),
+            OwnershipMode::Native => {
+                if let Some((uid, gid)) = owner {
+                    chown(file, uid, gid)?;
+                }
+                mode.map_or(Ok(()), |mode| chmod(file, mode))
+            }
+            OwnershipMode::Ignore => mode.map_or(Ok(()), |mode| chmod(file, mode)),
+        }
+    }
+
+    // Switches the calling thread to `owner`, the host ids of a guest process about to create a
+    // file, if `OwnershipMode::Native` is in effect and the VMM runs as root. The file still gets
+    // `set_owner_perms` afterwards, as macOS gives new files the group of their directory.
+    fn set_creds(&self, owner: (u32, u32)) -> io::Result<Option<ScopedCreds>> {
+        if !self.create_as_guest || owner == (0, 0) {
+            return Ok(None);
+        }
+        ScopedCreds::new(owner.0, owner.1).map(Some)
+    }
+
+    // Returns the host ids recorded as the owner of a file created by the guest ids `uid`/`gid`.
+    fn host_owner(&self, uid: u32, gid: u32) -> io::Result<(u32, u32)> {
+        let overflow = || linux_error(io::Error::from_raw_os_error(libc::EOVERFLOW));
+        Ok((
+            self.cfg.uid_map.to_host(uid).ok_or_else(overflow)?,
+            self.cfg.gid_map.to_host(gid).ok_or_else(overflow)?,
+        ))
     }
 
     fn do_getattr(&self, inode: Inode) -> io::Result<(bindings::stat64, Duration)> {
         let ihandle = self.inode_to_handle(inode, true)?;
         let st = match ihandle {
-            InodeHandle::Path(c_path) => lstat(&c_path, false)?,
-            InodeHandle::Fd(fd) => fstat(fd, false)?,
+            InodeHandle::Path(c_path) => lstat(&c_path, self.ownership_key())?,
+            InodeHandle::Fd(fd) => fstat(fd, self.ownership_key())?,
         };
+        let mut st = st;
+        self.shift_to_guest(&mut st);
 
         Ok((st, self.cfg.attr_timeout))
     }
@@ -898,18 +1477,18 @@ impl PassthroughFs {
         let fd =
             unsafe { libc::openat(parent_fd, name.as_ptr(), libc::O_NOFOLLOW | libc::O_CLOEXEC) };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(linux_error(io::Error::last_os_error()));
         }
         Ok(fd)
     }
 
     fn store_unlinked_fd(&self, unlinked_fd: RawFd) -> io::Result<()> {
-        let st = fstat(unlinked_fd, true)?;
+        let st = fstat(unlinked_fd, None)?;
         let altkey = InodeAltKey {
             ino: st.st_ino,
             dev: st.st_dev,
         };
-        if let Some(data) = self.inodes.read().unwrap().get_alt(&altkey).cloned() {
+        if let Some(data) = self.inodes.get_alt(&altkey) {
             data.unlinked_fd
                 .store(unlinked_fd as i64, Ordering::Release);
         }
@@ -923,6 +1502,8 @@ impl PassthroughFs {
         name: &CStr,
         flags: libc::c_int,
     ) -> io::Result<()> {
+        self.cfg.metrics.remove();
+        self.check_hidden(parent, name, libc::ENOENT)?;
         let ihandle = self.inode_to_handle(parent, true)?;
 
         let (fd, close_fd) = match ihandle {
@@ -935,7 +1516,7 @@ impl PassthroughFs {
             InodeHandle::Fd(fd) => (fd, false),
         };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(linux_error(io::Error::last_os_error()));
         }
 
         // After unlinking this inode, we can't keep relying on getting a "/.vol/..." path
@@ -976,13 +1557,148 @@ impl PassthroughFs {
         }
     }
 
-    fn parse_open_flags(&self, flags: i32) -> i32 {
-        let mut mflags: i32 = flags & 0b11;
+    // POSIX locks are tracked per guest lock owner in a `LockTable`, which decides whether owners
+    // conflict with each other. What they hold together is mirrored as fcntl locks of the VMM on a
+    // descriptor kept for the inode, so host processes see them too. The host drops those whenever
+    // the VMM closes any descriptor of the file, so they're taken again after handles are flushed
+    // or released.
+    fn posix_lock_file(&self, inode: Inode) -> io::Result<File> {
+        // Write locks need a writable file, but fall back to read-only access so that read locks
+        // still work on files we can't open for writing.
+        match self.open_inode(inode, libc::O_RDWR | libc::O_NONBLOCK) {
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EACCES | libc::EROFS | libc::EISDIR | libc::ETXTBSY)
+                ) =>
+            {
+                self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)
+            }
+            res => res,
+        }
+    }
 
-        if (flags & bindings::LINUX_O_NONBLOCK) != 0 {
-            mflags |= libc::O_NONBLOCK;
+    // Takes the host locks standing for the guest's POSIX locks on `inode` again.
+    fn reassert_posix_locks(&self, inode: Inode) {
+        let locks = self.posix_locks.lock_unpoisoned();
+        if let Some(posix) = locks.get(&inode) {
+            if let Err(e) = host_setlk_table(posix.file.as_raw_fd(), &posix.table, 0, u64::MAX) {
+                warn!("Couldn't take the host locks of inode {inode} again: {e}");
+            }
         }
-        if (flags & bindings::LINUX_O_APPEND) != 0 {
+    }
+
+    // Drops the POSIX locks of `owner` on `inode`, leaving the host locks for the caller to update.
+    fn remove_posix_locks(&self, inode: Inode, owner: u64) {
+        let mut locks = self.posix_locks.lock_unpoisoned();
+        if let Some(posix) = locks.get_mut(&inode) {
+            posix.table.remove_owner(owner);
+            if posix.table.is_empty() {
+                locks.remove(&inode);
+            }
+        }
+    }
+
+    // Never blocks: conflicting locks are reported as `EAGAIN` and `setlkw` requests are retried by
+    // the server.
+    fn do_setlk(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags & fuse::LK_FLOCK != 0 {
+            return self.do_flock(inode, handle, &lock);
+        }
+
+        let type_ = lock_type(lock.type_)?;
+        check_lock_range(&lock)?;
+
+        let mut locks = self.posix_locks.lock_unpoisoned();
+        let posix = match locks.entry(inode) {
+            hash_map::Entry::Occupied(e) => e.into_mut(),
+            hash_map::Entry::Vacant(_) if type_.is_none() => return Ok(()),
+            hash_map::Entry::Vacant(e) => e.insert(PosixLocks {
+                table: LockTable::default(),
+                file: self.posix_lock_file(inode)?,
+            }),
+        };
+
+        if let Some(type_) = type_ {
+            if posix
+                .table
+                .conflict(owner, lock.start, lock.end, type_)
+                .is_some()
+            {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::EAGAIN)));
+            }
+        }
+
+        let mut table = posix.table.clone();
+        table.set(owner, lock.start, lock.end, type_);
+        let fd = posix.file.as_raw_fd();
+        let res = host_setlk_table(fd, &table, lock.start, lock.end);
+        match res {
+            // A host process holds a conflicting lock, so put back what the guest had.
+            Err(_) => {
+                let _ = host_setlk_table(fd, &posix.table, lock.start, lock.end);
+            }
+            Ok(()) => posix.table = table,
+        }
+
+        if posix.table.is_empty() {
+            locks.remove(&inode);
+        }
+        res
+    }
+
+    // `flock` locks belong to the open file description, which is exactly what a handle is.
+    fn do_flock(&self, inode: Inode, handle: Handle, lock: &fuse::FileLock) -> io::Result<()> {
+        let data = self
+            .handles
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .ok_or_else(ebadf)?;
+
+        let op = match lock.type_ {
+            LINUX_F_RDLCK => libc::LOCK_SH,
+            LINUX_F_WRLCK => libc::LOCK_EX,
+            LINUX_F_UNLCK => libc::LOCK_UN,
+            _ => return Err(einval()),
+        };
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res =
+            unsafe { libc::flock(data.file.read_unpoisoned().as_raw_fd(), op | libc::LOCK_NB) };
+        if res < 0 {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    // Whether a file the guest opens with `flags` bypasses the host's cache. The guest's
+    // `O_DIRECT` is dropped, and counted, unless `Config::allow_direct_io` is set.
+    fn direct_io(&self, flags: i32) -> bool {
+        if flags & bindings::LINUX_O_DIRECT == 0 {
+            return false;
+        }
+        if !self.cfg.allow_direct_io {
+            self.cfg.metrics.direct_io_dropped();
+            return false;
+        }
+        true
+    }
+
+    fn parse_open_flags(&self, flags: i32) -> i32 {
+        let mut mflags: i32 = flags & 0b11;
+
+        if (flags & bindings::LINUX_O_NONBLOCK) != 0 {
+            mflags |= libc::O_NONBLOCK;
+        }
+        if (flags & bindings::LINUX_O_APPEND) != 0 {
             mflags |= libc::O_APPEND;
         }
         if (flags & bindings::LINUX_O_CREAT) != 0 {
@@ -1031,7 +1747,7 @@ fn set_secctx(file: &InodeHandle, secctx: SecContext, symlink: bool) -> io::Resu
     };
 
     if ret != 0 {
-        Err(io::Error::last_os_error())
+        Err(linux_error(io::Error::last_os_error()))
     } else {
         Ok(())
     }
@@ -1048,8 +1764,8 @@ fn remove_security_capability(file: &InodeHandle) {
         },
     };
 
-    // ENODATA means the attribute didn't exist, which is fine
-    if ret != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::ENODATA) {
+    // ENOATTR means the attribute didn't exist, which is fine
+    if ret != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::ENOATTR) {
         warn!("Error removing security.capability from file");
     }
 }
@@ -1071,15 +1787,21 @@ fn clear_suid_sgid(mode: u32) -> u32 {
 }
 
 fn forget_one(
-    inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
+    inodes: &ShardedMultikeyMap<Inode, InodeAltKey, Arc<InodeData>>,
     inode: Inode,
     count: u64,
 ) {
-    if let Some(data) = inodes.get(&inode) {
-        // Acquiring the write lock on the inode map prevents new lookups from incrementing the
-        // refcount but there is the possibility that a previous lookup already acquired a
-        // reference to the inode data and is in the process of updating the refcount so we need
-        // to loop here until we can decrement successfully.
+    // The root is kept however often it's forgotten, since nothing could be looked up without it
+    // and the guest can't look it up again. `init` puts it back after `destroy`.
+    if inode == fuse::ROOT_ID {
+        return;
+    }
+
+    inodes.remove_if(&inode, |data| {
+        // `remove_if` holds the alternate key index's write lock, which prevents new lookups from
+        // incrementing the refcount but there is the possibility that a previous lookup already
+        // acquired a reference to the inode data and is in the process of updating the refcount
+        // so we need to loop here until we can decrement successfully.
         loop {
             let refcount = data.refcount.load(Ordering::Relaxed);
 
@@ -1091,8 +1813,7 @@ fn forget_one(
             if data
                 .refcount
                 .compare_exchange(refcount, new_count, Ordering::Release, Ordering::Relaxed)
-                .unwrap()
-                == refcount
+                .is_ok()
             {
                 if new_count == 0 {
                     // If we have unlinked this inode, we have opened a file descriptor to be
@@ -1101,17 +1822,16 @@ fn forget_one(
                     if fd >= 0 {
                         unsafe { libc::close(fd as RawFd) };
                     }
-                    // We just removed the last refcount for this inode. There's no need for an
-                    // acquire fence here because we hold a write lock on the inode map and any
-                    // thread that is waiting to do a forget on the same inode will have to wait
-                    // until we release the lock. So there's is no other release store for us to
-                    // synchronize with before deleting the entry.
-                    inodes.remove(&inode);
                 }
-                break;
+                // If we just removed the last refcount for this inode, the entry gets deleted.
+                // There's no need for an acquire fence here because we hold the entry's locks
+                // and any thread that is waiting to do a forget on the same inode will have to
+                // wait until we release them. So there's is no other release store for us to
+                // synchronize with before deleting the entry.
+                return new_count == 0;
             }
         }
-    }
+    });
 }
 
 impl FileSystem for PassthroughFs {
@@ -1132,23 +1852,23 @@ impl FileSystem for PassthroughFs {
             )
         };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(linux_error(io::Error::last_os_error()));
         }
 
         // Safe because we just opened this fd above.
         let f = unsafe { File::from_raw_fd(fd) };
 
-        let st = fstat(f.as_raw_fd(), true)?;
+        let st = fstat(f.as_raw_fd(), None)?;
+        *self.hidden.write_unpoisoned() =
+            HiddenNames::resolve(&f, &self.cfg.hidden).map_err(linux_error)?;
 
         // Safe because this doesn't modify any memory and there is no need to check the return
         // value because this system call always succeeds. We need to clear the umask here because
         // we want the client to be able to set all the bits in the mode.
         unsafe { libc::umask(0o000) };
 
-        let mut inodes = self.inodes.write().unwrap();
-
         // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
-        inodes.insert(
+        self.inodes.insert(
             fuse::ROOT_ID,
             InodeAltKey {
                 ino: st.st_ino,
@@ -1158,8 +1878,10 @@ impl FileSystem for PassthroughFs {
                 inode: fuse::ROOT_ID,
                 ino: st.st_ino,
                 dev: st.st_dev,
+                path: vol_path(st.st_dev, st.st_ino)?,
                 refcount: AtomicU64::new(2),
                 unlinked_fd: AtomicI64::new(-1),
+                sealed: false,
             }),
         );
 
@@ -1174,15 +1896,25 @@ impl FileSystem for PassthroughFs {
             self.announce_submounts.store(true, Ordering::Relaxed);
         }
 
+        opts |= FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS;
+
         Ok(opts)
     }
 
     fn destroy(&self) {
-        self.handles.write().unwrap().clear();
-        self.inodes.write().unwrap().clear();
+        self.posix_locks.lock_unpoisoned().clear();
+        self.handles.clear();
+        self.inodes.clear();
     }
 
     fn statfs(&self, _ctx: Context, inode: Inode) -> io::Result<bindings::statvfs64> {
+        // init.krun is synthetic and lives in the root directory, so report the root's filesystem.
+        let inode = if inode == self.init_inode {
+            fuse::ROOT_ID
+        } else {
+            inode
+        };
+
         let mut out = MaybeUninit::<bindings::statvfs64>::zeroed();
 
         let res = match self.inode_to_handle(inode, true)? {
@@ -1199,13 +1931,37 @@ impl FileSystem for PassthroughFs {
         }
     }
 
+    fn syncfs(&self, _ctx: Context, inode: Inode) -> io::Result<()> {
+        let inode = if inode == self.init_inode {
+            fuse::ROOT_ID
+        } else {
+            inode
+        };
+
+        let file = self.open_inode(inode, libc::O_RDONLY)?;
+
+        // macOS has no per-filesystem sync, so schedule every dirty buffer to be written and then
+        // have the drive flush its cache, which `fsync(2)` doesn't do on its own.
+        // Safe because these don't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::sync();
+            libc::fcntl(file.as_raw_fd(), libc::F_FULLFSYNC)
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(linux_error(io::Error::last_os_error()))
+        }
+    }
+
     fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        self.cfg.metrics.lookup();
         debug!("lookup: {name:?}");
         let _init_name = unsafe { CStr::from_bytes_with_nul_unchecked(INIT_CSTR) };
 
         if self.init_inode != 0 && name == _init_name {
             let mut st: bindings::stat64 = unsafe { mem::zeroed() };
-            st.st_size = INIT_BINARY.len() as i64;
+            st.st_size = self.init_binary().len() as i64;
             st.st_ino = self.init_inode;
             st.st_mode = 0o100_755;
 
@@ -1223,16 +1979,12 @@ impl FileSystem for PassthroughFs {
     }
 
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
-        let mut inodes = self.inodes.write().unwrap();
-
-        forget_one(&mut inodes, inode, count)
+        forget_one(&self.inodes, inode, count)
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
-        let mut inodes = self.inodes.write().unwrap();
-
         for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
+            forget_one(&self.inodes, inode, count)
         }
     }
 
@@ -1264,10 +2016,16 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
+        self.check_hidden(parent, name, libc::EPERM)?;
         let c_path = self.name_to_path(parent, name)?;
+        let owner = self.host_owner(ctx.uid, ctx.gid)?;
 
+        let creds = self.set_creds(owner)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::mkdir(c_path.as_ptr(), 0o700) };
+        drop(creds);
         if res == 0 {
             let ihandle = InodeHandle::Path(c_path);
             // Set security context
@@ -1275,12 +2033,7 @@ impl FileSystem for PassthroughFs {
                 set_secctx(&ihandle, secctx, false)?
             };
 
-            set_xattr_stat(
-                &ihandle,
-                None,
-                Some((ctx.uid, ctx.gid)),
-                Some(mode & !umask),
-            )?;
+            self.set_owner_perms(&ihandle, None, Some(owner), Some(mode & !umask))?;
             self.do_lookup(parent, name)
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1288,6 +2041,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn rmdir(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.do_unlink(ctx, parent, name, libc::AT_REMOVEDIR)
     }
 
@@ -1319,14 +2073,26 @@ impl FileSystem for PassthroughFs {
         F: FnMut(DirEntry, Entry) -> io::Result<usize>,
     {
         self.do_readdir(inode, handle, size, offset, |dir_entry| {
-            // Safe because the kernel guarantees that the buffer is nul-terminated. Additionally,
-            // the kernel will pad the name with '\0' bytes up to 8-byte alignment and there's no
-            // way for us to know exactly how many padding bytes there are. This would cause
-            // `CStr::from_bytes_with_nul` to return an error because it would think there are
-            // interior '\0' bytes. We trust the kernel to provide us with properly formatted data
-            // so we'll just skip the checks here.
-            let name = unsafe { CStr::from_bytes_with_nul_unchecked(dir_entry.name) };
-            let entry = self.do_lookup(inode, name)?;
+            // `do_readdir` hands out names without their terminating '\0'.
+            let name = CString::new(dir_entry.name).map_err(|_| einval())?;
+            let entry = if dir_entry.name == b"." || dir_entry.name == b".." {
+                // The kernel doesn't look up "." and "..", so they're sent without an inode that
+                // it would have to forget.
+                // Safe because `stat64` only has integer fields, for which zero is valid.
+                let mut attr: bindings::stat64 = unsafe { mem::zeroed() };
+                attr.st_ino = dir_entry.ino;
+                attr.st_mode = libc::S_IFDIR;
+                Entry {
+                    inode: 0,
+                    generation: 0,
+                    attr,
+                    attr_flags: 0,
+                    attr_timeout: Duration::ZERO,
+                    entry_timeout: Duration::ZERO,
+                }
+            } else {
+                self.do_lookup(inode, &name)?
+            };
 
             add_entry(dir_entry, entry)
         })
@@ -1339,6 +2105,13 @@ impl FileSystem for PassthroughFs {
         kill_priv: bool,
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        // Files of a read-only share are only ever opened for reading.
+        let flags = if self.cfg.read_only {
+            flags & !(libc::O_ACCMODE | bindings::LINUX_O_TRUNC | bindings::LINUX_O_APPEND) as u32
+        } else {
+            flags
+        };
+
         if inode == self.init_inode {
             Ok((Some(self.init_handle), OpenOptions::empty()))
         } else {
@@ -1352,10 +2125,14 @@ impl FileSystem for PassthroughFs {
         inode: Inode,
         _flags: u32,
         handle: Handle,
-        _flush: bool,
+        flush: bool,
         _flock_release: bool,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
+        if let (true, Some(owner)) = (flush, lock_owner) {
+            self.remove_posix_locks(inode, owner);
+        }
+
         self.do_release(inode, handle)
     }
 
@@ -1370,8 +2147,14 @@ impl FileSystem for PassthroughFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
+        self.cfg.metrics.open();
+        self.check_hidden(parent, name, libc::EPERM)?;
         let c_path = self.name_to_path(parent, name)?;
+        let owner = self.host_owner(ctx.uid, ctx.gid)?;
 
+        let direct = self.direct_io(flags as i32);
         let flags = self.parse_open_flags(flags as i32);
         let hostmode = if (flags & libc::O_DIRECTORY) != 0 {
             0o700
@@ -1379,6 +2162,7 @@ impl FileSystem for PassthroughFs {
             0o600
         };
 
+        let creds = self.set_creds(owner)?;
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
@@ -1389,15 +2173,22 @@ impl FileSystem for PassthroughFs {
                 hostmode,
             )
         };
+        drop(creds);
         if fd < 0 {
             return Err(linux_error(io::Error::last_os_error()));
         }
         let ihandle = InodeHandle::Fd(fd);
 
-        if let Err(e) = set_xattr_stat(
+        if direct {
+            if let Err(e) = set_nocache(fd) {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        }
+        if let Err(e) = self.set_owner_perms(
             &ihandle,
             None,
-            Some((ctx.uid, ctx.gid)),
+            Some(owner),
             Some(libc::S_IFREG as u32 | (mode & !(umask & 0o777))),
         ) {
             unsafe { libc::close(fd) };
@@ -1426,9 +2217,10 @@ impl FileSystem for PassthroughFs {
             inode: entry.inode,
             file,
             dirstream: Mutex::new(DirStream::new()),
+            exported: Default::default(),
         };
 
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.handles.insert(handle, Arc::new(data));
 
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
@@ -1441,6 +2233,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn unlink(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.do_unlink(ctx, parent, name, 0)
     }
 
@@ -1457,30 +2250,26 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<usize> {
         debug!("read: {inode:?}");
         if inode == self.init_inode {
-            let off: usize = offset
-                .try_into()
-                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
-            let len = if off + (size as usize) < INIT_BINARY.len() {
-                size as usize
-            } else {
-                INIT_BINARY.len() - off
-            };
-            return w.write(&INIT_BINARY[off..(off + len)]);
+            let off: usize = offset.try_into().map_err(|_| einval())?;
+            let init = self.init_binary();
+            let start = off.min(init.len());
+            let end = off.saturating_add(size as usize).min(init.len());
+            return w.write(&init[start..end]);
         }
 
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
-        let f = data.file.read().unwrap();
-        w.write_from(&f, size as usize, offset)
+        let f = data.file.read_unpoisoned();
+        let count = w.write_from(&f, size as usize, offset)?;
+        self.cfg.metrics.read(count);
+
+        Ok(count)
     }
 
     fn write<R: io::Read + ZeroCopyReader>(
@@ -1496,19 +2285,20 @@ impl FileSystem for PassthroughFs {
         kill_priv: bool,
         _flags: u32,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
-        let f = data.file.read().unwrap();
+        let f = data.file.read_unpoisoned();
         let result = r.read_to(&f, size as usize, offset);
+        if let Ok(count) = result {
+            self.cfg.metrics.write(count);
+        }
 
         // If write succeeded and kill_priv is set, clear security.capability and suid/sgid
         if result.is_ok() && kill_priv {
@@ -1517,11 +2307,12 @@ impl FileSystem for PassthroughFs {
 
             remove_security_capability(&ihandle);
 
-            if let Ok(st) = fstat(fd, false) {
+            if let Ok(st) = fstat(fd, self.ownership_key()) {
                 let new_mode = clear_suid_sgid(st.st_mode as u32);
                 if new_mode != st.st_mode as u32 {
                     // Update mode in xattr
-                    if let Err(err) = set_xattr_stat(&ihandle, Some(st), None, Some(new_mode)) {
+                    if let Err(err) = self.set_owner_perms(&ihandle, Some(st), None, Some(new_mode))
+                    {
                         error!("Couldn't clear suid/sgid for inode {inode}: {err}");
                     }
                 }
@@ -1535,11 +2326,38 @@ impl FileSystem for PassthroughFs {
         &self,
         _ctx: Context,
         inode: Inode,
-        _handle: Option<Handle>,
+        handle: Option<Handle>,
     ) -> io::Result<(bindings::stat64, Duration)> {
+        // An open handle can be stat'ed directly, skipping the `/.vol` path lookup.
+        let data =
+            handle.and_then(|handle| self.handles.get(&handle).filter(|hd| hd.inode == inode));
+        if let Some(data) = data {
+            let mut st = fstat(
+                data.file.read_unpoisoned().as_raw_fd(),
+                self.ownership_key(),
+            )?;
+            self.shift_to_guest(&mut st);
+            return Ok((st, self.cfg.attr_timeout));
+        }
+
         self.do_getattr(inode)
     }
 
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> io::Result<(bindings::stat64, Option<libc::timespec>, Duration)> {
+        // APFS and HFS+ record creation times, which `stat` already returns.
+        let (st, timeout) = self.getattr(ctx, inode, handle)?;
+        let btime = libc::timespec {
+            tv_sec: st.st_birthtime,
+            tv_nsec: st.st_birthtime_nsec,
+        };
+        Ok((st, Some(btime), timeout))
+    }
+
     fn setattr(
         &self,
         _ctx: Context,
@@ -1548,43 +2366,49 @@ impl FileSystem for PassthroughFs {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(bindings::stat64, Duration)> {
+        self.check_writable()?;
+        self.check_sealed(inode)?;
+
         // If we have a handle then use it otherwise get a new fd from the inode.
         let ihandle = if let Some(handle) = handle {
             let hd = self
                 .handles
-                .read()
-                .unwrap()
                 .get(&handle)
                 .filter(|hd| hd.inode == inode)
-                .cloned()
                 .ok_or_else(ebadf)?;
 
-            let fd = hd.file.write().unwrap().as_raw_fd();
+            let fd = hd.file.write_unpoisoned().as_raw_fd();
             InodeHandle::Fd(fd)
         } else {
             self.inode_to_handle(inode, true)?
         };
 
         if valid.contains(SetattrValid::MODE) {
-            set_xattr_stat(&ihandle, None, None, Some(attr.st_mode as u32))?
+            self.set_owner_perms(&ihandle, None, None, Some(attr.st_mode as u32))?
         }
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                self.cfg
+                    .uid_map
+                    .to_host(attr.st_uid)
+                    .ok_or_else(|| linux_error(io::Error::from_raw_os_error(libc::EINVAL)))?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                self.cfg
+                    .gid_map
+                    .to_host(attr.st_gid)
+                    .ok_or_else(|| linux_error(io::Error::from_raw_os_error(libc::EINVAL)))?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 u32::MAX
             };
 
             remove_security_capability(&ihandle);
-            let st = istat(&ihandle, false)?;
+            let st = istat(&ihandle, self.ownership_key())?;
 
             // Clear suid/sgid if UID or GID is being changed
             let new_mode = clear_suid_sgid(st.st_mode as u32);
@@ -1593,7 +2417,7 @@ impl FileSystem for PassthroughFs {
             } else {
                 None
             };
-            set_xattr_stat(&ihandle, Some(st), Some((uid, gid)), new_mode)?;
+            self.set_owner_perms(&ihandle, Some(st), Some((uid, gid)), new_mode)?;
         }
 
         if valid.contains(SetattrValid::SIZE) {
@@ -1607,10 +2431,10 @@ impl FileSystem for PassthroughFs {
 
                     // Clear security.capability on truncate unconditionally
                     remove_security_capability(&ihandle);
-                    let st = fstat(fd, false)?;
+                    let st = fstat(fd, self.ownership_key())?;
                     let new_mode = clear_suid_sgid(st.st_mode as u32);
                     if new_mode != st.st_mode as u32 {
-                        set_xattr_stat(&ihandle, Some(st), None, Some(new_mode))?;
+                        self.set_owner_perms(&ihandle, Some(st), None, Some(new_mode))?;
                     }
                 }
                 InodeHandle::Path(_) => {
@@ -1627,15 +2451,19 @@ impl FileSystem for PassthroughFs {
                     // reuse the FD we just opened, thus reducing the number of syscalls.
                     let ihandle = InodeHandle::Fd(f.as_raw_fd());
                     remove_security_capability(&ihandle);
-                    let st = istat(&ihandle, false)?;
+                    let st = istat(&ihandle, self.ownership_key())?;
                     let new_mode = clear_suid_sgid(st.st_mode as u32);
                     if new_mode != st.st_mode as u32 {
-                        set_xattr_stat(&ihandle, Some(st), None, Some(new_mode))?;
+                        self.set_owner_perms(&ihandle, Some(st), None, Some(new_mode))?;
                     }
                 }
             };
         }
 
+        let valid = self
+            .cfg
+            .atime
+            .filter_setattr(valid, || self.do_getattr(inode).map(|(st, _)| st))?;
         if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
             let mut tvs = [
                 libc::timespec {
@@ -1673,7 +2501,7 @@ impl FileSystem for PassthroughFs {
                 },
             };
             if res < 0 {
-                return Err(io::Error::last_os_error());
+                return Err(linux_error(io::Error::last_os_error()));
             }
         }
 
@@ -1689,6 +2517,7 @@ impl FileSystem for PassthroughFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         let mut mflags: u32 = 0;
         if ((flags as i32) & bindings::LINUX_RENAME_NOREPLACE) != 0 {
             mflags |= libc::RENAME_EXCL;
@@ -1703,12 +2532,24 @@ impl FileSystem for PassthroughFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
         }
 
+        self.check_hidden(olddir, oldname, libc::ENOENT)?;
+        self.check_hidden(newdir, newname, libc::EPERM)?;
         let old_cpath = self.name_to_path(olddir, oldname)?;
         let new_cpath = self.name_to_path(newdir, newname)?;
 
         let res = unsafe { libc::renamex_np(old_cpath.as_ptr(), new_cpath.as_ptr(), mflags) };
         if res == 0 {
-            if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0 {
+            if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0
+                && self.ownership_key().is_none()
+            {
+                // Whiteouts are character devices, which only a real one can be without the
+                // attribute to record the type in.
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe { libc::mknod(old_cpath.as_ptr(), libc::S_IFCHR | 0o600, 0) };
+                if res < 0 {
+                    return Err(linux_error(io::Error::last_os_error()));
+                }
+            } else if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0 {
                 let fd = unsafe {
                     libc::open(
                         old_cpath.as_ptr(),
@@ -1717,7 +2558,7 @@ impl FileSystem for PassthroughFs {
                     )
                 };
                 if fd > 0 {
-                    if let Err(e) = set_xattr_stat(
+                    if let Err(e) = self.set_owner_perms(
                         &InodeHandle::Fd(fd),
                         None,
                         None,
@@ -1745,12 +2586,47 @@ impl FileSystem for PassthroughFs {
         parent: Inode,
         name: &CStr,
         mode: u32,
-        _rdev: u32,
+        rdev: u32,
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
+        self.check_hidden(parent, name, libc::EPERM)?;
         let c_path = self.name_to_path(parent, name)?;
+        let owner = self.host_owner(ctx.uid, ctx.gid)?;
+
+        // FIFOs are always real ones, as a regular file can't stand in for a pipe. Devices are
+        // regular files with their type recorded in the ownership attribute, as macOS only lets
+        // root create them, unless there's no attribute to record it in.
+        let file_type = mode as libc::mode_t & libc::S_IFMT;
+        if file_type == libc::S_IFIFO
+            || (self.ownership_key().is_none()
+                && matches!(file_type, libc::S_IFCHR | libc::S_IFBLK))
+        {
+            let creds = self.set_creds(owner)?;
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                if file_type == libc::S_IFIFO {
+                    libc::mkfifo(c_path.as_ptr(), 0o600)
+                } else {
+                    libc::mknod(c_path.as_ptr(), file_type | 0o600, host_rdev(rdev))
+                }
+            };
+            drop(creds);
+            if res < 0 {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
 
+            let ihandle = InodeHandle::Path(c_path);
+            if let Some(secctx) = extensions.secctx {
+                set_secctx(&ihandle, secctx, false)?
+            };
+            self.set_owner_perms(&ihandle, None, Some(owner), Some(mode & !umask))?;
+            return self.do_lookup(parent, name);
+        }
+
+        let creds = self.set_creds(owner)?;
         let fd = unsafe {
             libc::open(
                 c_path.as_ptr(),
@@ -1758,6 +2634,7 @@ impl FileSystem for PassthroughFs {
                 0o600,
             )
         };
+        drop(creds);
         if fd < 0 {
             Err(linux_error(io::Error::last_os_error()))
         } else {
@@ -1768,12 +2645,7 @@ impl FileSystem for PassthroughFs {
                 set_secctx(&ihandle, secctx, false)?
             };
 
-            if let Err(e) = set_xattr_stat(
-                &ihandle,
-                None,
-                Some((ctx.uid, ctx.gid)),
-                Some(mode & !umask),
-            ) {
+            if let Err(e) = self.set_owner_perms(&ihandle, None, Some(owner), Some(mode & !umask)) {
                 unsafe { libc::close(fd) };
                 return Err(e);
             }
@@ -1790,10 +2662,12 @@ impl FileSystem for PassthroughFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         let orig_c_path = match self.inode_to_handle(inode, false)? {
             InodeHandle::Path(c_path) => c_path,
             InodeHandle::Fd(_) => return Err(ebadf()),
         };
+        self.check_hidden(newparent, newname, libc::EPERM)?;
         let link_c_path = self.name_to_path(newparent, newname)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -1813,10 +2687,16 @@ impl FileSystem for PassthroughFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
+        self.cfg.metrics.create();
+        self.check_hidden(parent, name, libc::EPERM)?;
         let c_path = self.name_to_path(parent, name)?;
+        let owner = self.host_owner(ctx.uid, ctx.gid)?;
 
+        let creds = self.set_creds(owner)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::symlink(linkname.as_ptr(), c_path.as_ptr()) };
+        drop(creds);
         if res == 0 {
             let ihandle = InodeHandle::Path(c_path);
 
@@ -1825,13 +2705,9 @@ impl FileSystem for PassthroughFs {
                 set_secctx(&ihandle, secctx, true)?
             };
 
-            let mut entry = self.do_lookup(parent, name)?;
             let mode = libc::S_IFLNK | 0o777;
-            set_xattr_stat(&ihandle, None, Some((ctx.uid, ctx.gid)), Some(mode as u32))?;
-            entry.attr.st_uid = ctx.uid;
-            entry.attr.st_gid = ctx.gid;
-            entry.attr.st_mode = mode;
-            Ok(entry)
+            self.set_owner_perms(&ihandle, None, Some(owner), Some(mode as u32))?;
+            self.do_lookup(parent, name)
         } else {
             Err(linux_error(io::Error::last_os_error()))
         }
@@ -1865,22 +2741,22 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
+        // Closing a file drops all of the owner's POSIX locks on it.
+        self.remove_posix_locks(inode, lock_owner);
+
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
         // because this doesn't modify any memory and we check the return values.
-        unsafe {
-            let newfd = libc::dup(data.file.write().unwrap().as_raw_fd());
+        let res = unsafe {
+            let newfd = libc::dup(data.file.write_unpoisoned().as_raw_fd());
             if newfd < 0 {
                 return Err(linux_error(io::Error::last_os_error()));
             }
@@ -1890,7 +2766,11 @@ impl FileSystem for PassthroughFs {
             } else {
                 Ok(())
             }
-        }
+        };
+
+        // The close dropped the host locks other owners still hold too.
+        self.reassert_posix_locks(inode);
+        res
     }
 
     fn fsync(
@@ -1902,14 +2782,11 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<()> {
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let fd = data.file.write_unpoisoned().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::fsync(fd) };
@@ -1931,12 +2808,119 @@ impl FileSystem for PassthroughFs {
         self.fsync(ctx, inode, datasync, handle)
     }
 
+    fn getlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<fuse::FileLock> {
+        if flags & fuse::LK_FLOCK != 0 {
+            return Err(einval());
+        }
+
+        let type_ = lock_type(lock.type_)?.ok_or_else(einval)?;
+        check_lock_range(&lock)?;
+
+        let locks = self.posix_locks.lock_unpoisoned();
+        let posix = locks.get(&inode);
+        if let Some(conflict) =
+            posix.and_then(|p| p.table.conflict(owner, lock.start, lock.end, type_))
+        {
+            // Lock owners aren't processes, so there's no meaningful pid to report.
+            return Ok(fuse::FileLock {
+                start: conflict.start,
+                end: conflict.end,
+                type_: match conflict.type_ {
+                    LockType::Read => LINUX_F_RDLCK,
+                    LockType::Write => LINUX_F_WRLCK,
+                },
+                pid: 0,
+            });
+        }
+
+        // Otherwise look for locks of host processes, which the host reports without the VMM's
+        // own. Closing a descriptor of our own is harmless if the guest holds no locks on the file.
+        let file;
+        let fd = match posix {
+            Some(posix) => posix.file.as_raw_fd(),
+            None => {
+                file = self.posix_lock_file(inode)?;
+                file.as_raw_fd()
+            }
+        };
+
+        // Safe because `flock` is a plain C struct for which all zeroes is a valid value.
+        let mut fl: libc::flock = unsafe { mem::zeroed() };
+        fl.l_type = match type_ {
+            LockType::Read => libc::F_RDLCK,
+            LockType::Write => libc::F_WRLCK,
+        };
+        fl.l_whence = libc::SEEK_SET as libc::c_short;
+        fl.l_start = lock.start as libc::off_t;
+        fl.l_len = if lock.end >= i64::MAX as u64 {
+            0
+        } else {
+            (lock.end - lock.start + 1) as libc::off_t
+        };
+
+        // Safe because this will only modify `fl` and we check the return value.
+        let res = unsafe { libc::fcntl(fd, libc::F_GETLK, &mut fl) };
+        if res < 0 {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+
+        let type_ = match fl.l_type {
+            libc::F_RDLCK => LINUX_F_RDLCK,
+            libc::F_WRLCK => LINUX_F_WRLCK,
+            _ => LINUX_F_UNLCK,
+        };
+        let end = if fl.l_len == 0 {
+            i64::MAX as u64
+        } else {
+            (fl.l_start + fl.l_len - 1) as u64
+        };
+        Ok(fuse::FileLock {
+            start: fl.l_start as u64,
+            end,
+            type_,
+            pid: 0,
+        })
+    }
+
+    fn setlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(inode, handle, owner, lock, flags)
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: fuse::FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(inode, handle, owner, lock, flags)
+    }
+
     fn access(&self, ctx: Context, inode: Inode, mask: u32) -> io::Result<()> {
-        let st = match self.inode_to_handle(inode, true)? {
-            InodeHandle::Path(c_path) => lstat(&c_path, false)?,
-            InodeHandle::Fd(fd) => fstat(fd, false)?,
+        let mut st = match self.inode_to_handle(inode, true)? {
+            InodeHandle::Path(c_path) => lstat(&c_path, self.ownership_key())?,
+            InodeHandle::Fd(fd) => fstat(fd, self.ownership_key())?,
         };
 
+        self.shift_to_guest(&mut st);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1991,13 +2975,16 @@ impl FileSystem for PassthroughFs {
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         debug!("setxattr: inode={inode} name={name:?} value={value:?}");
 
         if !self.cfg.xattr {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
+        self.check_sealed(inode)?;
 
-        if name.to_bytes() == XATTR_KEY {
+        let name = xattrmap::to_host(&self.cfg.xattr_map, name)?;
+        if self.ownership_key() == Some(&name) {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EACCES)));
         }
 
@@ -2057,7 +3044,8 @@ impl FileSystem for PassthroughFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENODATA)));
         }
 
-        if name.to_bytes() == XATTR_KEY {
+        let name = xattrmap::to_host(&self.cfg.xattr_map, name)?;
+        if self.ownership_key() == Some(&name) {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EACCES)));
         }
 
@@ -2147,44 +3135,37 @@ impl FileSystem for PassthroughFs {
 
         buf.truncate(res as usize);
 
-        if size == 0 {
-            let mut clean_size = res as usize;
-
-            for attr in buf.split(|c| *c == 0) {
-                if attr.starts_with(&XATTR_KEY[..XATTR_KEY.len() - 1]) {
-                    clean_size -= XATTR_KEY.len();
-                }
+        // The ownership attribute is dropped before mapping, so no rule can bring it back.
+        let key = self.ownership_key().map(CStr::to_bytes);
+        let mut clean_buf = Vec::new();
+        for attr in buf.split(|c| *c == 0) {
+            if attr.is_empty() || Some(attr) == key {
+                continue;
             }
 
-            Ok(ListxattrReply::Count(clean_size as u32))
-        } else {
-            let mut clean_buf = Vec::new();
-
-            for attr in buf.split(|c| *c == 0) {
-                if attr.is_empty() || attr.starts_with(&XATTR_KEY[..XATTR_KEY.len() - 1]) {
-                    continue;
-                }
-
-                clean_buf.extend_from_slice(attr);
-                clean_buf.push(0);
-            }
-
-            clean_buf.shrink_to_fit();
+            clean_buf.extend_from_slice(attr);
+            clean_buf.push(0);
+        }
+        let clean_buf = xattrmap::to_guest_list(&self.cfg.xattr_map, &clean_buf);
 
-            if clean_buf.len() > size as usize {
-                Err(io::Error::from_raw_os_error(LINUX_ERANGE))
-            } else {
-                Ok(ListxattrReply::Names(clean_buf))
-            }
+        if size == 0 {
+            Ok(ListxattrReply::Count(clean_buf.len() as u32))
+        } else if clean_buf.len() > size as usize {
+            Err(linux_error(io::Error::from_raw_os_error(libc::ERANGE)))
+        } else {
+            Ok(ListxattrReply::Names(clean_buf))
         }
     }
 
     fn removexattr(&self, _ctx: Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         if !self.cfg.xattr {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
+        self.check_sealed(inode)?;
 
-        if name.to_bytes() == XATTR_KEY {
+        let name = xattrmap::to_host(&self.cfg.xattr_map, name)?;
+        if self.ownership_key() == Some(&name) {
             return Err(linux_error(io::Error::from_raw_os_error(
                 bindings::LINUX_EACCES,
             )));
@@ -2209,45 +3190,69 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _mode: u32,
+        mode: u32,
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        self.check_writable()?;
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let keep_size = mode & bindings::LINUX_FALLOC_FL_KEEP_SIZE != 0;
+        let op = mode & !bindings::LINUX_FALLOC_FL_KEEP_SIZE;
+        // Like Linux, holes can only be punched without changing the size.
+        if (op == bindings::LINUX_FALLOC_FL_PUNCH_HOLE && !keep_size)
+            || !matches!(
+                op,
+                0 | bindings::LINUX_FALLOC_FL_PUNCH_HOLE | bindings::LINUX_FALLOC_FL_ZERO_RANGE
+            )
+        {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EOPNOTSUPP)));
+        }
 
-        let proposed_length = (offset + length) as i64;
-        let mut fs = libc::fstore_t {
-            fst_flags: libc::F_ALLOCATECONTIG,
-            fst_posmode: libc::F_PEOFPOSMODE,
-            fst_offset: 0,
-            fst_length: proposed_length,
-            fst_bytesalloc: 0,
-        };
+        let end = offset
+            .checked_add(length)
+            .filter(|end| *end <= i64::MAX as u64)
+            .ok_or_else(|| linux_error(io::Error::from_raw_os_error(libc::EFBIG)))?;
+        let fd = data.file.write_unpoisoned().as_raw_fd();
+        let st = fstat(fd, None)?;
+        let size = st.st_size as u64;
+
+        if op == 0 {
+            // `F_PEOFPOSMODE` allocates from the end of the file, so only what lies past it
+            // needs allocating.
+            if end > size {
+                let mut fs = libc::fstore_t {
+                    fst_flags: libc::F_ALLOCATECONTIG,
+                    fst_posmode: libc::F_PEOFPOSMODE,
+                    fst_offset: 0,
+                    fst_length: (end - size) as libc::off_t,
+                    fst_bytesalloc: 0,
+                };
 
-        let res = unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut fs as *mut _) };
-        if res < 0 {
-            fs.fst_flags = libc::F_ALLOCATEALL;
-            let res = unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut fs as &mut _) };
-            if res < 0 {
-                return Err(linux_error(io::Error::last_os_error()));
+                let res = unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut fs as *mut _) };
+                if res < 0 {
+                    fs.fst_flags = libc::F_ALLOCATEALL;
+                    let res = unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut fs as &mut _) };
+                    if res < 0 {
+                        return Err(linux_error(io::Error::last_os_error()));
+                    }
+                }
             }
+        } else {
+            // Zeroing a range is emulated by punching a hole in it, which reads back as zeroes
+            // too, but leaves the range unallocated.
+            punch_hole(fd, offset, end.min(size), st.st_blksize as u64)?;
         }
 
-        let st = fstat(fd, true)?;
-        if st.st_size >= proposed_length {
-            // fallocate should not shrink the file. The file is already larger than needed.
+        // fallocate never shrinks the file.
+        if keep_size || op == bindings::LINUX_FALLOC_FL_PUNCH_HOLE || end <= size {
             return Ok(());
         }
-        let res = unsafe { libc::ftruncate(fd, proposed_length) };
+        let res = unsafe { libc::ftruncate(fd, end as libc::off_t) };
 
         if res == 0 {
             Ok(())
@@ -2266,29 +3271,29 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<u64> {
         let data = self
             .handles
-            .read()
-            .unwrap()
             .get(&handle)
             .filter(|hd| hd.inode == inode)
-            .cloned()
             .ok_or_else(ebadf)?;
 
-        // SEEK_DATA and SEEK_HOLE have slightly different semantics
-        // in Linux vs. macOS, which means we can't support them.
-        let mwhence = if whence == 3 {
-            // SEEK_DATA
-            return Ok(offset);
-        } else if whence == 4 {
-            // SEEK_HOLE
-            libc::SEEK_END
-        } else {
-            whence as i32
+        let mwhence = match whence as libc::c_int {
+            bindings::LINUX_SEEK_SET => libc::SEEK_SET,
+            bindings::LINUX_SEEK_CUR => libc::SEEK_CUR,
+            bindings::LINUX_SEEK_END => libc::SEEK_END,
+            bindings::LINUX_SEEK_DATA | bindings::LINUX_SEEK_HOLE => {
+                let fd = data.file.write_unpoisoned().as_raw_fd();
+                return seek_data_hole(
+                    fd,
+                    offset,
+                    whence as libc::c_int == bindings::LINUX_SEEK_DATA,
+                );
+            }
+            _ => return Err(einval()),
         };
 
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let fd = data.file.write_unpoisoned().as_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek(fd, offset as bindings::off64_t, mwhence as libc::c_int) };
+        let res = unsafe { libc::lseek(fd, offset as bindings::off64_t, mwhence) };
         if res < 0 {
             Err(linux_error(io::Error::last_os_error()))
         } else {
@@ -2296,6 +3301,56 @@ impl FileSystem for PassthroughFs {
         }
     }
 
+    fn fadvise(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        len: u64,
+        advice: Advice,
+    ) -> io::Result<()> {
+        self.cfg.metrics.fadvise();
+        let data = self
+            .handles
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .ok_or_else(ebadf)?;
+        let fd = data.file.read_unpoisoned().as_raw_fd();
+
+        // macOS has no posix_fadvise, only per-fd read-ahead and caching switches, and a way to
+        // start reading a range in. Anything else is dropped, as hints may be.
+        // Safe because these don't modify any memory, and `ra` outlives the call using it.
+        let res = unsafe {
+            match advice {
+                Advice::Normal => {
+                    libc::fcntl(fd, libc::F_NOCACHE, 0);
+                    libc::fcntl(fd, libc::F_RDAHEAD, 1)
+                }
+                Advice::Sequential => libc::fcntl(fd, libc::F_RDAHEAD, 1),
+                Advice::Random => libc::fcntl(fd, libc::F_RDAHEAD, 0),
+                Advice::WillNeed => {
+                    let ra = libc::radvisory {
+                        ra_offset: offset.min(i64::MAX as u64) as libc::off_t,
+                        ra_count: if len == 0 {
+                            i32::MAX
+                        } else {
+                            len.min(i32::MAX as u64) as i32
+                        },
+                    };
+                    libc::fcntl(fd, libc::F_RDADVISE, &ra)
+                }
+                Advice::DontNeed | Advice::NoReuse => libc::fcntl(fd, libc::F_NOCACHE, 1),
+            }
+        };
+        if res < 0 {
+            debug!(
+                "fadvise: {advice:?} on inode {inode}: {}",
+                io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
     fn setupmapping(
         &self,
         _ctx: Context,
@@ -2309,6 +3364,10 @@ impl FileSystem for PassthroughFs {
         shm_size: u64,
         map_sender: &Option<Sender<WorkerMessage>>,
     ) -> io::Result<()> {
+        if flags & fuse::SetupmappingFlags::WRITE.bits() != 0 {
+            self.check_writable()?;
+        }
+
         if map_sender.is_none() {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
@@ -2359,16 +3418,15 @@ impl FileSystem for PassthroughFs {
                 guest_addr,
                 len,
             ))
-            .unwrap();
-        if !reply_receiver.recv().unwrap() {
+            .map_err(|_| linux_error(io::Error::from_raw_os_error(libc::EIO)))?;
+        if !reply_receiver.recv().unwrap_or(false) {
             error!("Error requesting HVF the addition of a DAX window");
             unsafe { libc::munmap(host_addr, len as usize) };
             return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
         }
 
         self.map_windows
-            .lock()
-            .unwrap()
+            .lock_unpoisoned()
             .insert(guest_addr, host_addr as u64);
 
         Ok(())
@@ -2391,7 +3449,7 @@ impl FileSystem for PassthroughFs {
             if (req.moffset + req.len) > shm_size {
                 return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
             }
-            let host_addr = match self.map_windows.lock().unwrap().remove(&guest_addr) {
+            let host_addr = match self.map_windows.lock_unpoisoned().remove(&guest_addr) {
                 Some(a) => a,
                 None => return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL))),
             };
@@ -2408,8 +3466,8 @@ impl FileSystem for PassthroughFs {
                     guest_addr,
                     req.len,
                 ))
-                .unwrap();
-            if !reply_receiver.recv().unwrap() {
+                .map_err(|_| linux_error(io::Error::from_raw_os_error(libc::EIO)))?;
+            if !reply_receiver.recv().unwrap_or(false) {
                 error!("Error requesting HVF the removal of a DAX window");
                 return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
             }
@@ -2427,30 +3485,820 @@ impl FileSystem for PassthroughFs {
     fn ioctl(
         &self,
         _ctx: Context,
-        _inode: Self::Inode,
-        _handle: Self::Handle,
+        inode: Self::Inode,
+        handle: Self::Handle,
         _flags: u32,
         cmd: u32,
         arg: u64,
         _in_size: u32,
-        _out_size: u32,
-        exit_code: &Arc<AtomicI32>,
+        out_size: u32,
+        exit_status: &Arc<SharedExitStatus>,
     ) -> io::Result<Vec<u8>> {
-        // We can't use nix::request_code_none here since it's system-dependent
+        // We can't use nix::request_code_* here since it's system-dependent
         // and we need the value from Linux.
+        const VIRTIO_IOC_EXPORT_FD_SIZE: usize = 2 * mem::size_of::<u64>();
+        const VIRTIO_IOC_EXPORT_FD_REQ: u32 = 0x8010_7601;
         const VIRTIO_IOC_EXIT_CODE_REQ: u32 = 0x7602;
         const VIRTIO_IOC_REMOVE_ROOT_DIR_REQ: u32 = 0x7603;
+        const VIRTIO_IOC_EXIT_STATUS_REQ: u32 = 0x7604;
 
         match cmd {
+            VIRTIO_IOC_EXPORT_FD_REQ => {
+                if out_size as usize != VIRTIO_IOC_EXPORT_FD_SIZE {
+                    return Err(einval());
+                }
+
+                let mut exports = self
+                    .cfg
+                    .export_table
+                    .as_ref()
+                    .ok_or_else(|| linux_error(io::Error::from_raw_os_error(libc::EOPNOTSUPP)))?
+                    .lock_unpoisoned();
+
+                // Keep the handle's shard locked until the export is recorded, so that a
+                // concurrent release either sees the flag or runs before the lookup.
+                self.handles.get_with(&handle, |data| {
+                    let data = data.filter(|hd| hd.inode == inode).ok_or_else(ebadf)?;
+
+                    data.exported.store(true, Ordering::Relaxed);
+
+                    let fd = data
+                        .file
+                        .read_unpoisoned()
+                        .try_clone()
+                        .map_err(linux_error)?;
+
+                    exports.insert((self.cfg.export_fsid, handle), fd);
+                    Ok::<(), io::Error>(())
+                })?;
+
+                let mut ret: Vec<_> = self.cfg.export_fsid.to_ne_bytes().into();
+                ret.extend_from_slice(&handle.to_ne_bytes());
+                Ok(ret)
+            }
             VIRTIO_IOC_EXIT_CODE_REQ => {
-                exit_code.store(arg as i32, Ordering::SeqCst);
+                exit_status.set(ExitStatus::Exited(arg as i32));
+                Ok(Vec::new())
+            }
+            VIRTIO_IOC_EXIT_STATUS_REQ => {
+                // The status is encoded as on Linux, where the guest runs.
+                let status = ExitStatus::from_wait_status(arg as i32).ok_or_else(einval)?;
+                exit_status.set(status);
                 Ok(Vec::new())
             }
             VIRTIO_IOC_REMOVE_ROOT_DIR_REQ if self.cfg.allow_root_dir_delete => {
-                std::fs::remove_dir_all(&self.cfg.root_dir)?;
+                self.check_writable()?;
+                std::fs::remove_dir_all(&self.cfg.root_dir).map_err(linux_error)?;
                 Ok(Vec::new())
             }
-            _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+            _ => Err(linux_error(io::Error::from_raw_os_error(libc::EOPNOTSUPP))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempdir::TempDir;
+
+    use super::*;
+    use crate::virtio::linux_errno::linux_errno_raw;
+
+    // An empty directory for a test to share, removed again when the test ends, even on a panic.
+    fn test_dir(name: &str) -> TempDir {
+        TempDir::new_with_prefix(std::env::temp_dir().join(format!("krun-fs-{name}-"))).unwrap()
+    }
+
+    // Shares `dir` with the rest of `cfg` and mounts it.
+    fn mount(dir: &TempDir, cfg: Config) -> PassthroughFs {
+        let fs = PassthroughFs::new(Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            ..cfg
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        fs
+    }
+
+    // A mounted share of a fresh directory, and the context of a root caller.
+    fn test_fs(name: &str, cfg: Config) -> (TempDir, PassthroughFs, Context) {
+        let dir = test_dir(name);
+        let fs = mount(&dir, cfg);
+        (dir, fs, ROOT_CTX)
+    }
+
+    #[test]
+    fn seek_data_hole_linux_semantics() {
+        use std::os::unix::fs::FileExt;
+
+        const MIB: u64 = 1 << 20;
+
+        let dir = test_dir("lseek");
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.as_path().join("sparse"))
+            .unwrap();
+        file.write_at(&[1u8; 4096], MIB).unwrap();
+        file.set_len(2 * MIB).unwrap();
+        let fd = file.as_raw_fd();
+
+        let data = seek_data_hole(fd, 0, true).unwrap();
+        assert!(data == 0 || data == MIB);
+        let hole = seek_data_hole(fd, MIB, false).unwrap();
+        assert!(hole > MIB && hole <= 2 * MIB);
+        // Safe because this doesn't modify any memory.
+        assert_eq!(unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) } as u64, hole);
+
+        // Offsets at EOF are always an error on Linux.
+        for data in [true, false] {
+            assert_eq!(
+                seek_data_hole(fd, 2 * MIB, data)
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(linux_errno_raw(libc::ENXIO))
+            );
+        }
+    }
+
+    #[test]
+    fn export_fd_ioctl() {
+        let exports = ExportTable::default();
+        let (dir, fs, ctx) = test_fs(
+            "export",
+            Config {
+                export_fsid: 3,
+                export_table: Some(exports.clone()),
+                ..Default::default()
+            },
+        );
+        std::fs::write(dir.as_path().join("file"), b"data").unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs.open(ctx, inode, false, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+
+        // `_IOR('v', 1, [u64; 2])` as the Linux guest encodes it.
+        let export_req = 0x8010_7601;
+        let exit_status = Arc::new(SharedExitStatus::new());
+        let export = |inode, out_size| {
+            fs.ioctl(
+                ctx,
+                inode,
+                handle,
+                0,
+                export_req,
+                0,
+                0,
+                out_size,
+                &exit_status,
+            )
+        };
+
+        let out = export(inode, 16).unwrap();
+        assert_eq!(out[..8], 3u64.to_ne_bytes());
+        assert_eq!(out[8..], handle.to_ne_bytes());
+        let file = exports.lock().unwrap()[&(3, handle)].try_clone().unwrap();
+        assert_eq!(io::read_to_string(file).unwrap(), "data");
+
+        assert_eq!(
+            export(inode, 8).unwrap_err().raw_os_error(),
+            Some(linux_errno_raw(libc::EINVAL))
+        );
+        assert_eq!(
+            export(fuse::ROOT_ID, 16).unwrap_err().raw_os_error(),
+            Some(linux_errno_raw(libc::EBADF))
+        );
+
+        // Releasing the handle takes its export away too.
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+        assert!(exports.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ioctl_errors_use_linux_numbers() {
+        let (_dir, fs, ctx) = test_fs("ioctl", Config::default());
+        let exit_status = Arc::new(SharedExitStatus::new());
+        let ioctl = |cmd, arg| {
+            fs.ioctl(ctx, fuse::ROOT_ID, 0, 0, cmd, arg, 0, 0, &exit_status)
+                .unwrap_err()
+                .raw_os_error()
+        };
+
+        // EOPNOTSUPP is 102 on macOS.
+        assert_eq!(ioctl(0x1234, 0), Some(95));
+        // Not a wait status.
+        assert_eq!(ioctl(0x7604, 0xffff), Some(linux_errno_raw(libc::EINVAL)));
+    }
+
+    fn lock(type_: u32, start: u64, end: u64) -> fuse::FileLock {
+        fuse::FileLock {
+            start,
+            end,
+            type_,
+            pid: 0,
+        }
+    }
+
+    // Whether another host process could take a write lock on `start..=end` of `path`.
+    fn other_process_can_lock(path: &CStr, start: u64, end: u64) -> bool {
+        // Safe because the child only makes async-signal-safe calls before exiting.
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                let fd = libc::open(path.as_ptr(), libc::O_RDWR);
+                let mut fl: libc::flock = mem::zeroed();
+                fl.l_type = libc::F_WRLCK;
+                fl.l_whence = libc::SEEK_SET as libc::c_short;
+                fl.l_start = start as libc::off_t;
+                fl.l_len = (end - start + 1) as libc::off_t;
+                libc::_exit((fd < 0 || libc::fcntl(fd, libc::F_SETLK, &fl) < 0) as i32);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
         }
     }
+
+    #[test]
+    fn posix_locks_conflict_between_owners() {
+        let (tmp, fs, ctx) = test_fs("locks", Config::default());
+        let file = tmp.as_path().join("file");
+        std::fs::write(&file, b"data").unwrap();
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let open = || {
+            fs.open(ctx, inode, false, libc::O_RDWR as u32)
+                .unwrap()
+                .0
+                .unwrap()
+        };
+        let (handle1, handle2) = (open(), open());
+
+        fs.setlk(ctx, inode, handle1, 1, lock(LINUX_F_WRLCK, 0, 9), 0)
+            .unwrap();
+
+        // A second owner can't take an overlapping lock, but can lock a disjoint range.
+        let err = fs
+            .setlkw(ctx, inode, handle2, 2, lock(LINUX_F_RDLCK, 5, 20), 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(linux_errno_raw(libc::EAGAIN)));
+        fs.setlk(ctx, inode, handle2, 2, lock(LINUX_F_WRLCK, 10, 20), 0)
+            .unwrap();
+
+        let conflict = fs
+            .getlk(ctx, inode, handle2, 2, lock(LINUX_F_RDLCK, 0, u64::MAX), 0)
+            .unwrap();
+        assert_eq!(conflict.type_, LINUX_F_WRLCK);
+        assert_eq!((conflict.start, conflict.end), (0, 9));
+
+        // Other host processes see the locks of both owners.
+        assert!(!other_process_can_lock(&path, 0, 0));
+        assert!(!other_process_can_lock(&path, 15, 15));
+        assert!(other_process_can_lock(&path, 30, 40));
+
+        // Flushing on behalf of the first owner releases its locks, and only those, even though
+        // closing a descriptor drops all of the process' locks on the host.
+        fs.flush(ctx, inode, handle1, 1).unwrap();
+        assert!(other_process_can_lock(&path, 0, 0));
+        assert!(!other_process_can_lock(&path, 15, 15));
+        fs.setlkw(ctx, inode, handle1, 3, lock(LINUX_F_RDLCK, 0, 9), 0)
+            .unwrap();
+
+        // The same goes for releasing a handle.
+        fs.release(ctx, inode, 0, handle1, true, false, Some(3))
+            .unwrap();
+        assert!(!other_process_can_lock(&path, 15, 15));
+        fs.setlk(ctx, inode, handle2, 2, lock(LINUX_F_UNLCK, 0, u64::MAX), 0)
+            .unwrap();
+        assert!(other_process_can_lock(&path, 15, 15));
+
+        fs.release(ctx, inode, 0, handle2, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn mknod_fifo_and_devices() {
+        use std::io::{Read, Write};
+        use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+        let (tmp, fs, _) = test_fs("mknod", Config::default());
+        let dir = tmp.as_path();
+        let mknod = |name: &str, mode: u32| {
+            let name = CString::new(name).unwrap();
+            fs.mknod(
+                ROOT_CTX,
+                fuse::ROOT_ID,
+                &name,
+                mode,
+                0,
+                0o022,
+                Extensions::default(),
+            )
+            .unwrap()
+            .attr
+            .st_mode
+        };
+
+        assert_eq!(
+            mknod("fifo", libc::S_IFIFO as u32 | 0o666),
+            libc::S_IFIFO | 0o644
+        );
+        assert!(std::fs::symlink_metadata(dir.join("fifo"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+
+        // Both ends opened on the host make a working pipe.
+        let mut reader = File::options()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(dir.join("fifo"))
+            .unwrap();
+        let mut writer = File::options().write(true).open(dir.join("fifo")).unwrap();
+        writer.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Devices are emulated, but still report their type.
+        assert_eq!(
+            mknod("chr", libc::S_IFCHR as u32 | 0o600),
+            libc::S_IFCHR | 0o600
+        );
+        assert_eq!(
+            mknod("blk", libc::S_IFBLK as u32 | 0o600),
+            libc::S_IFBLK | 0o600
+        );
+        assert!(std::fs::symlink_metadata(dir.join("chr"))
+            .unwrap()
+            .is_file());
+    }
+
+    #[test]
+    fn fallocate_modes() {
+        use std::os::unix::fs::{FileExt, MetadataExt};
+
+        const MIB: u64 = 1 << 20;
+        let (dir, fs, _) = test_fs("falloc", Config::default());
+        let path = dir.as_path().join("file");
+        std::fs::write(&path, vec![1u8; MIB as usize]).unwrap();
+
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ROOT_CTX, fuse::ROOT_ID, &name).unwrap().inode;
+        let (handle, _) = fs
+            .open(ROOT_CTX, inode, false, libc::O_RDWR as u32)
+            .unwrap();
+        let handle = handle.unwrap();
+        let fallocate =
+            |mode, offset, length| fs.fallocate(ROOT_CTX, inode, handle, mode, offset, length);
+        let file = File::open(&path).unwrap();
+        let read = |offset, len| {
+            let mut buf = vec![0xffu8; len];
+            file.read_exact_at(&mut buf, offset).unwrap();
+            buf
+        };
+
+        // Punching a hole frees its blocks, zeroing the partial ones at its edges.
+        let blocks = file.metadata().unwrap().blocks();
+        let punch = bindings::LINUX_FALLOC_FL_PUNCH_HOLE | bindings::LINUX_FALLOC_FL_KEEP_SIZE;
+        fallocate(punch, 100, MIB / 2).unwrap();
+        let st = file.metadata().unwrap();
+        assert!(st.blocks() < blocks);
+        assert_eq!(st.len(), MIB);
+        assert_eq!(read(0, 100), vec![1; 100]);
+        assert_eq!(read(100, (MIB / 2) as usize), vec![0; (MIB / 2) as usize]);
+        assert_eq!(read(MIB / 2 + 100, 100), vec![1; 100]);
+
+        // Zeroing past the end grows the file unless asked not to.
+        let zero = bindings::LINUX_FALLOC_FL_ZERO_RANGE;
+        fallocate(zero | bindings::LINUX_FALLOC_FL_KEEP_SIZE, MIB - 10, 20).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), MIB);
+        fallocate(zero, MIB - 10, 20).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), MIB + 10);
+        assert_eq!(read(MIB - 10, 20), vec![0; 20]);
+
+        // So does allocating.
+        fallocate(bindings::LINUX_FALLOC_FL_KEEP_SIZE, 0, 2 * MIB).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), MIB + 10);
+        fallocate(0, 0, 2 * MIB).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 2 * MIB);
+
+        // Punching holes has to keep the size, and unknown modes aren't ignored.
+        for mode in [bindings::LINUX_FALLOC_FL_PUNCH_HOLE, 0x08, punch | zero] {
+            assert_eq!(
+                fallocate(mode, 0, 10).unwrap_err().raw_os_error(),
+                Some(linux_errno_raw(libc::EOPNOTSUPP))
+            );
+        }
+    }
+
+    #[test]
+    fn forget_root() {
+        let (dir, fs, ctx) = test_fs("forget-root", Config::default());
+        std::fs::write(dir.as_path().join("file"), b"data").unwrap();
+
+        let file = CString::new("file").unwrap();
+        fs.forget(ctx, fuse::ROOT_ID, u64::MAX);
+        fs.batch_forget(ctx, vec![(fuse::ROOT_ID, u64::MAX)]);
+        fs.getattr(ctx, fuse::ROOT_ID, None).unwrap();
+        fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+
+        // The root is back after the guest unmounts and mounts the share again.
+        fs.destroy();
+        fs.init(FsOptions::empty()).unwrap();
+        fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+    }
+
+    #[test]
+    fn dot_entries() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (tmp, fs, ctx) = test_fs("dots", Config::default());
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join("sub").join(name), b"").unwrap();
+        }
+
+        let host_ino = |path: &std::path::Path| std::fs::metadata(path).unwrap().ino();
+        let sub = fs
+            .lookup(ctx, fuse::ROOT_ID, &CString::new("sub").unwrap())
+            .unwrap()
+            .inode;
+
+        // Reads one entry per call, resuming from the offset of the last one.
+        let list = |inode: Inode| -> Vec<(Vec<u8>, u64)> {
+            let (handle, _) = fs.opendir(ctx, inode, 0).unwrap();
+            let handle = handle.unwrap();
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            loop {
+                let mut next = None;
+                fs.readdir(ctx, inode, handle, 4096, offset, |entry| {
+                    if next.is_some() {
+                        return Ok(0);
+                    }
+                    next = Some((entry.name.to_vec(), entry.ino, entry.offset));
+                    Ok(1)
+                })
+                .unwrap();
+                let Some((name, ino, next_offset)) = next else {
+                    break;
+                };
+                entries.push((name, ino));
+                offset = next_offset;
+            }
+            fs.releasedir(ctx, inode, 0, handle).unwrap();
+            entries
+        };
+
+        let root = list(fuse::ROOT_ID);
+        assert_eq!(
+            root,
+            [
+                (b".".to_vec(), host_ino(dir)),
+                (b"..".to_vec(), host_ino(dir)),
+                (b"sub".to_vec(), host_ino(&dir.join("sub"))),
+            ]
+        );
+
+        let entries = list(sub);
+        assert_eq!(entries[0], (b".".to_vec(), host_ino(&dir.join("sub"))));
+        assert_eq!(entries[1], (b"..".to_vec(), host_ino(dir)));
+        let mut names: Vec<_> = entries[2..].iter().map(|(name, _)| name.clone()).collect();
+        names.sort();
+        assert_eq!(names, [&b"a"[..], b"b", b"c"]);
+
+        // Dots don't take a lookup reference in readdirplus, even at the root.
+        let (handle, _) = fs.opendir(ctx, fuse::ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut plus = Vec::new();
+        fs.readdirplus(ctx, fuse::ROOT_ID, handle, 4096, 0, |entry, e| {
+            plus.push((entry.name.to_vec(), e.inode));
+            Ok(1)
+        })
+        .unwrap();
+        fs.releasedir(ctx, fuse::ROOT_ID, 0, handle).unwrap();
+        assert_eq!(plus[..2], [(b".".to_vec(), 0), (b"..".to_vec(), 0)]);
+        assert_eq!(plus[2], (b"sub".to_vec(), sub));
+    }
+
+    #[test]
+    fn direct_io() {
+        let dir = test_dir("direct");
+        std::fs::write(dir.as_path().join("file"), b"data").unwrap();
+
+        let ctx = ROOT_CTX;
+        let file = CString::new("file").unwrap();
+        let new = CString::new("new").unwrap();
+        let flags = (libc::O_RDWR | bindings::LINUX_O_DIRECT) as u32;
+        for allow_direct_io in [false, true] {
+            let fs = mount(
+                &dir,
+                Config {
+                    allow_direct_io,
+                    ..Default::default()
+                },
+            );
+            let inode = fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap().inode;
+
+            // Either way the files open, but only a share without direct I/O counts the flag.
+            let (handle, _) = fs.open(ctx, inode, false, flags).unwrap();
+            fs.release(ctx, inode, 0, handle.unwrap(), false, false, None)
+                .unwrap();
+            let (entry, handle, _) = fs
+                .create(
+                    ctx,
+                    fuse::ROOT_ID,
+                    &new,
+                    0o644,
+                    false,
+                    flags,
+                    0,
+                    Extensions::default(),
+                )
+                .unwrap();
+            fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+                .unwrap();
+            fs.unlink(ctx, fuse::ROOT_ID, &new).unwrap();
+            let dropped = if allow_direct_io { 0 } else { 2 };
+            assert_eq!(fs.metrics().direct_io_dropped, dropped);
+        }
+    }
+
+    #[test]
+    fn read_only_share() {
+        let (tmp, fs, ctx) = test_fs(
+            "ro",
+            Config {
+                read_only: true,
+                ..Default::default()
+            },
+        );
+        let dir = tmp.as_path();
+        std::fs::create_dir_all(dir.join("dir")).unwrap();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let file = CString::new("file").unwrap();
+        let dir_name = CString::new("dir").unwrap();
+        let new = CString::new("new").unwrap();
+        let xattr = CString::new("user.test").unwrap();
+        let inode = fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap().inode;
+        let dir_inode = fs.lookup(ctx, fuse::ROOT_ID, &dir_name).unwrap().inode;
+
+        // Opening for writing hands out a read-only file, without truncating it.
+        let flags = (libc::O_RDWR | bindings::LINUX_O_TRUNC) as u32;
+        let (handle, _) = fs.open(ctx, inode, false, flags).unwrap();
+        let handle = handle.unwrap();
+
+        // Safe because all zeroes is a valid `stat64`.
+        let attr: bindings::stat64 = unsafe { mem::zeroed() };
+        let results = [
+            fs.create(
+                ctx,
+                fuse::ROOT_ID,
+                &new,
+                0o644,
+                false,
+                0,
+                0,
+                Extensions::default(),
+            )
+            .map(drop),
+            fs.mkdir(ctx, fuse::ROOT_ID, &new, 0o755, 0, Extensions::default())
+                .map(drop),
+            fs.mknod(
+                ctx,
+                fuse::ROOT_ID,
+                &new,
+                libc::S_IFIFO as u32 | 0o644,
+                0,
+                0,
+                Extensions::default(),
+            )
+            .map(drop),
+            fs.symlink(ctx, &file, fuse::ROOT_ID, &new, Extensions::default())
+                .map(drop),
+            fs.link(ctx, inode, fuse::ROOT_ID, &new).map(drop),
+            fs.unlink(ctx, fuse::ROOT_ID, &file),
+            fs.rmdir(ctx, fuse::ROOT_ID, &dir_name),
+            fs.rename(ctx, fuse::ROOT_ID, &file, dir_inode, &new, 0),
+            fs.setattr(ctx, inode, attr, None, SetattrValid::SIZE)
+                .map(drop),
+            fs.setxattr(ctx, inode, &xattr, b"x", 0),
+            fs.removexattr(ctx, inode, &xattr),
+            fs.fallocate(ctx, inode, handle, 0, 0, 4096),
+        ];
+        for (i, res) in results.into_iter().enumerate() {
+            assert_eq!(
+                res.unwrap_err().raw_os_error(),
+                Some(linux_errno_raw(libc::EROFS)),
+                "{i}"
+            );
+        }
+
+        // Nothing changed on the host, and lookups still work.
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"data");
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+        fs.lookup(ctx, fuse::ROOT_ID, &file).unwrap();
+
+        fs.release(ctx, inode, 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    const ROOT_CTX: Context = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    // Shares a new directory holding `file` with `mode`, and returns the file system, the directory
+    // and the inode of `file`.
+    fn ownership_fs(test: &str, mode: OwnershipMode) -> (PassthroughFs, TempDir, Inode) {
+        let (dir, fs, _) = test_fs(
+            test,
+            Config {
+                ownership_mode: mode,
+                ..Default::default()
+            },
+        );
+        std::fs::write(dir.as_path().join("file"), b"data").unwrap();
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(ROOT_CTX, fuse::ROOT_ID, &name).unwrap().inode;
+        (fs, dir, inode)
+    }
+
+    fn chown_chmod(
+        fs: &PassthroughFs,
+        inode: Inode,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> io::Result<bindings::stat64> {
+        // Safe because all zeroes is a valid `stat64`.
+        let mut attr: bindings::stat64 = unsafe { mem::zeroed() };
+        attr.st_uid = uid;
+        attr.st_gid = gid;
+        attr.st_mode = mode as u16;
+        let valid = SetattrValid::UID | SetattrValid::GID | SetattrValid::MODE;
+        fs.setattr(ROOT_CTX, inode, attr, None, valid)?;
+        Ok(fs.getattr(ROOT_CTX, inode, None)?.0)
+    }
+
+    #[test]
+    fn ownership_xattr_emulation() {
+        let key = CString::new("user.test.owner").unwrap();
+        let mode = OwnershipMode::XattrEmulation { key: key.clone() };
+        let (fs, tmp, inode) = ownership_fs("own-xattr", mode);
+        let dir = tmp.as_path();
+        let host = std::fs::metadata(dir.join("file")).unwrap();
+
+        let st = chown_chmod(&fs, inode, 1234, 5678, 0o640).unwrap();
+        assert_eq!((st.st_uid, st.st_gid), (1234, 5678));
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o640);
+
+        // The host file is left alone.
+        use std::os::unix::fs::MetadataExt;
+        let after = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!((after.uid(), after.mode()), (host.uid(), host.mode()));
+
+        // The configured key is the one kept from the guest.
+        let names = match fs.listxattr(ROOT_CTX, inode, 512).unwrap() {
+            ListxattrReply::Names(names) => names,
+            ListxattrReply::Count(_) => unreachable!(),
+        };
+        assert!(!names.split(|c| *c == 0).any(|n| n == key.as_bytes()));
+        assert_eq!(
+            fs.setxattr(ROOT_CTX, inode, &key, b"0:0:0777", 0)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(linux_errno_raw(libc::EACCES))
+        );
+        let default_key = CStr::from_bytes_with_nul(XATTR_KEY).unwrap();
+        fs.setxattr(ROOT_CTX, inode, default_key, b"x", 0).unwrap();
+    }
+
+    #[test]
+    fn ownership_native() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (fs, tmp, inode) = ownership_fs("own-native", OwnershipMode::Native);
+        let dir = tmp.as_path();
+        // Safe because these can't fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        let st = chown_chmod(&fs, inode, uid, gid, 0o640).unwrap();
+        assert_eq!((st.st_uid, st.st_gid), (uid, gid));
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o640);
+        let host = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!(host.mode(), (libc::S_IFREG | 0o640) as u32);
+
+        // Giving the file away takes privileges, which the guest is told it lacks.
+        let res = chown_chmod(&fs, inode, 1234, 5678, 0o600);
+        if uid == 0 {
+            let st = res.unwrap();
+            assert_eq!((st.st_uid, st.st_gid), (1234, 5678));
+            let host = std::fs::metadata(dir.join("file")).unwrap();
+            assert_eq!((host.uid(), host.gid()), (1234, 5678));
+        } else {
+            assert_eq!(
+                res.unwrap_err().raw_os_error(),
+                Some(linux_errno_raw(libc::EPERM))
+            );
+        }
+    }
+
+    #[test]
+    fn native_creates_with_guest_creds() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        // Safe because these syscalls always succeed.
+        let host_creds = || unsafe { (libc::geteuid(), libc::getegid()) };
+        let before = host_creds();
+        if before.0 != 0 {
+            // Switching credentials needs root.
+            return;
+        }
+
+        let (fs, tmp, _) = ownership_fs("own-creds", OwnershipMode::Native);
+        let dir = tmp.as_path();
+        for (sub, mode) in [("open", 0o777), ("closed", 0o755)] {
+            std::fs::create_dir(dir.join(sub)).unwrap();
+            std::fs::set_permissions(dir.join(sub), std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let lookup = |name: &str| {
+            let name = CString::new(name).unwrap();
+            fs.lookup(ROOT_CTX, fuse::ROOT_ID, &name).unwrap().inode
+        };
+        let (open, closed) = (lookup("open"), lookup("closed"));
+        let guest = Context {
+            uid: 1234,
+            gid: 5678,
+            pid: 0,
+        };
+        let new = CString::new("new").unwrap();
+        let file = CString::new("file").unwrap();
+        let link = CString::new("link").unwrap();
+
+        // Files are created by their owner, as host tools see them.
+        fs.mkdir(guest, open, &new, 0o755, 0, Extensions::default())
+            .unwrap();
+        let (entry, handle, _) = fs
+            .create(
+                guest,
+                open,
+                &file,
+                0o644,
+                false,
+                libc::O_RDWR as u32,
+                0,
+                Extensions::default(),
+            )
+            .unwrap();
+        fs.release(guest, entry.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        fs.symlink(guest, &file, open, &link, Extensions::default())
+            .unwrap();
+        for name in ["new", "file", "link"] {
+            let host = std::fs::symlink_metadata(dir.join("open").join(name)).unwrap();
+            assert_eq!((host.uid(), host.gid()), (1234, 5678), "{name}");
+        }
+        assert_eq!(host_creds(), before);
+
+        // The host checks the guest's permissions on the directory, and the thread gets its own
+        // credentials back when creating fails.
+        assert_eq!(
+            fs.mkdir(guest, closed, &new, 0o755, 0, Extensions::default())
+                .err()
+                .and_then(|e| e.raw_os_error()),
+            Some(linux_errno_raw(libc::EACCES))
+        );
+        assert_eq!(host_creds(), before);
+        fs.mkdir(ROOT_CTX, closed, &new, 0o755, 0, Extensions::default())
+            .unwrap();
+        let host = std::fs::metadata(dir.join("closed/new")).unwrap();
+        assert_eq!(host.uid(), 0);
+    }
+
+    #[test]
+    fn ownership_ignore() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (fs, tmp, inode) = ownership_fs("own-ignore", OwnershipMode::Ignore);
+        let dir = tmp.as_path();
+        let host = std::fs::metadata(dir.join("file")).unwrap();
+
+        let st = chown_chmod(&fs, inode, 1234, 5678, 0o640).unwrap();
+        assert_eq!((st.st_uid, st.st_gid), (host.uid(), host.gid()));
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o640);
+        let after = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!((after.uid(), after.gid()), (host.uid(), host.gid()));
+        assert_eq!(after.mode(), (libc::S_IFREG | 0o640) as u32);
+    }
 }
@@ -0,0 +1,206 @@
+//! POSIX record locks held by guest lock owners.
+//!
+//! macOS has no open file description locks, and the fcntl locks of a process are shared by all
+//! of its threads and dropped when it closes any descriptor of the file. So the locks of each guest
+//! lock owner are tracked here, and only their union is passed on to the host.
+
+/// The kind of a record lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockType {
+    Read,
+    Write,
+}
+
+/// A lock held by `owner` on the bytes `start..=end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RangeLock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub type_: LockType,
+}
+
+impl RangeLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+}
+
+/// The record locks on one file, split and merged the way `fcntl(2)` does for each owner.
+#[derive(Clone, Debug, Default)]
+pub struct LockTable {
+    locks: Vec<RangeLock>,
+}
+
+impl LockTable {
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+
+    /// Returns a lock of another owner that keeps `owner` from taking a `type_` lock on
+    /// `start..=end`, if there is one.
+    pub fn conflict(&self, owner: u64, start: u64, end: u64, type_: LockType) -> Option<RangeLock> {
+        self.locks
+            .iter()
+            .find(|l| {
+                l.owner != owner
+                    && l.overlaps(start, end)
+                    && (l.type_ == LockType::Write || type_ == LockType::Write)
+            })
+            .copied()
+    }
+
+    /// Replaces whatever `owner` holds on `start..=end` with a `type_` lock, or unlocks the range
+    /// if `type_` is `None`. Conflicts must have been checked with `conflict` first.
+    pub fn set(&mut self, owner: u64, start: u64, end: u64, type_: Option<LockType>) {
+        let mut locks = Vec::with_capacity(self.locks.len() + 2);
+        for l in self.locks.drain(..) {
+            if l.owner != owner || !l.overlaps(start, end) {
+                locks.push(l);
+                continue;
+            }
+
+            // Keep the parts of the old lock on either side of the range.
+            if l.start < start {
+                locks.push(RangeLock {
+                    end: start - 1,
+                    ..l
+                });
+            }
+            if l.end > end {
+                locks.push(RangeLock {
+                    start: end + 1,
+                    ..l
+                });
+            }
+        }
+        self.locks = locks;
+
+        let Some(type_) = type_ else {
+            return;
+        };
+
+        // Adjacent locks of the same owner and kind become one.
+        let mut new = RangeLock {
+            owner,
+            start,
+            end,
+            type_,
+        };
+        self.locks.retain(|l| {
+            let adjacent = l.owner == owner
+                && l.type_ == type_
+                && (l.end.checked_add(1) == Some(new.start)
+                    || new.end.checked_add(1) == Some(l.start));
+            if adjacent {
+                new.start = new.start.min(l.start);
+                new.end = new.end.max(l.end);
+            }
+            !adjacent
+        });
+        self.locks.push(new);
+    }
+
+    /// Drops all the locks of `owner`.
+    pub fn remove_owner(&mut self, owner: u64) {
+        self.locks.retain(|l| l.owner != owner);
+    }
+
+    /// Splits `start..=end` into consecutive ranges, each with the strongest lock any owner holds
+    /// on it, if any. This is what the host needs to hold for the guest.
+    pub fn segments(&self, start: u64, end: u64) -> Vec<(u64, u64, Option<LockType>)> {
+        let mut bounds = vec![start];
+        for l in self.locks.iter().filter(|l| l.overlaps(start, end)) {
+            if l.start > start {
+                bounds.push(l.start);
+            }
+            if l.end < end {
+                bounds.push(l.end + 1);
+            }
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut segments: Vec<(u64, u64, Option<LockType>)> = Vec::new();
+        for (i, &seg_start) in bounds.iter().enumerate() {
+            let seg_end = bounds.get(i + 1).map_or(end, |next| next - 1);
+            let type_ = self
+                .locks
+                .iter()
+                .filter(|l| l.overlaps(seg_start, seg_end))
+                .map(|l| l.type_)
+                .max();
+            match segments.last_mut() {
+                Some(last) if last.2 == type_ => last.1 = seg_end,
+                _ => segments.push((seg_start, seg_end, type_)),
+            }
+        }
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owners_conflict() {
+        let mut table = LockTable::default();
+        table.set(1, 0, 9, Some(LockType::Read));
+
+        assert_eq!(table.conflict(1, 0, 9, LockType::Write), None);
+        assert_eq!(table.conflict(2, 5, 20, LockType::Read), None);
+        let conflict = table.conflict(2, 5, 20, LockType::Write).unwrap();
+        assert_eq!((conflict.owner, conflict.start, conflict.end), (1, 0, 9));
+        assert_eq!(table.conflict(2, 10, 20, LockType::Write), None);
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut table = LockTable::default();
+        table.set(1, 0, u64::MAX, Some(LockType::Write));
+        table.set(1, 10, 19, None);
+        table.set(1, 20, 29, Some(LockType::Read));
+        assert_eq!(
+            table.segments(0, u64::MAX),
+            vec![
+                (0, 9, Some(LockType::Write)),
+                (10, 19, None),
+                (20, 29, Some(LockType::Read)),
+                (30, u64::MAX, Some(LockType::Write)),
+            ]
+        );
+
+        // Locking the gap again joins the pieces of the same kind.
+        table.set(1, 10, 19, Some(LockType::Write));
+        table.set(1, 20, 29, Some(LockType::Write));
+        assert_eq!(table.locks.len(), 1);
+        assert_eq!(table.locks[0].type_, LockType::Write);
+        table.remove_owner(1);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn segments_take_the_strongest_lock() {
+        let mut table = LockTable::default();
+        table.set(1, 0, 9, Some(LockType::Read));
+        table.set(2, 5, 14, Some(LockType::Read));
+        table.set(2, 20, 29, Some(LockType::Write));
+        assert_eq!(
+            table.segments(0, 39),
+            vec![
+                (0, 14, Some(LockType::Read)),
+                (15, 19, None),
+                (20, 29, Some(LockType::Write)),
+                (30, 39, None),
+            ]
+        );
+
+        // Owner 2 unlocking keeps owner 1's read lock on the host.
+        table.set(2, 0, 39, None);
+        assert_eq!(
+            table.segments(0, 39),
+            vec![(0, 9, Some(LockType::Read)), (10, 39, None)]
+        );
+    }
+}
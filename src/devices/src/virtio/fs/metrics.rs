@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Operation counters of a passthrough filesystem. Every counter is a relaxed atomic, so updating
+/// them costs next to nothing on the request path; read them all at once with `snapshot`.
+#[derive(Debug, Default)]
+pub struct FsMetrics {
+    lookups: AtomicU64,
+    inode_cache_hits: AtomicU64,
+    opens: AtomicU64,
+    creates: AtomicU64,
+    removes: AtomicU64,
+    reads: AtomicU64,
+    bytes_read: AtomicU64,
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+    fadvises: AtomicU64,
+    direct_io_dropped: AtomicU64,
+}
+
+/// The values of an `FsMetrics` at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsMetricsSnapshot {
+    /// Lookups of a name in a directory, including failed ones.
+    pub lookups: u64,
+    /// Successful lookups that found the file already in the inode table.
+    pub inode_cache_hits: u64,
+    /// Files and directories opened, including through `create`.
+    pub opens: u64,
+    /// Files, directories, device nodes and symlinks created.
+    pub creates: u64,
+    /// Files and directories removed.
+    pub removes: u64,
+    /// Read requests.
+    pub reads: u64,
+    /// Bytes returned by read requests.
+    pub bytes_read: u64,
+    /// Write requests.
+    pub writes: u64,
+    /// Bytes stored by write requests.
+    pub bytes_written: u64,
+    /// Access pattern hints passed on from the guest's `posix_fadvise`.
+    pub fadvises: u64,
+    /// Opens whose `O_DIRECT` was dropped, as the share doesn't allow direct I/O.
+    pub direct_io_dropped: u64,
+}
+
+impl FsMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> FsMetricsSnapshot {
+        FsMetricsSnapshot {
+            lookups: self.lookups.load(Ordering::Relaxed),
+            inode_cache_hits: self.inode_cache_hits.load(Ordering::Relaxed),
+            opens: self.opens.load(Ordering::Relaxed),
+            creates: self.creates.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            fadvises: self.fadvises.load(Ordering::Relaxed),
+            direct_io_dropped: self.direct_io_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn lookup(&self) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inode_cache_hit(&self) {
+        self.inode_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn open(&self) {
+        self.opens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn create(&self) {
+        self.creates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove(&self) {
+        self.removes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, bytes: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write(&self, bytes: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fadvise(&self) {
+        self.fadvises.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn direct_io_dropped(&self) {
+        self.direct_io_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
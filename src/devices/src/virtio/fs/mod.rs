@@ -1,12 +1,22 @@
+pub mod atime;
 mod device;
 pub mod dyn_filesystem;
 #[allow(dead_code)]
 pub mod filesystem;
 pub mod fuse;
+pub mod hidden;
+pub mod idmap;
+mod init;
+mod locks;
+pub mod metrics;
 #[allow(dead_code)]
 mod multikey;
 mod server;
+#[allow(dead_code)]
+mod sharded;
+pub mod single_file;
 mod worker;
+pub mod xattrmap;
 
 #[cfg(target_os = "linux")]
 pub mod linux;
@@ -14,6 +24,8 @@ pub mod linux;
 pub use linux::fs_utils;
 #[cfg(target_os = "linux")]
 pub use linux::passthrough;
+#[cfg(target_os = "linux")]
+pub use linux::privileges;
 #[cfg(target_os = "macos")]
 pub mod macos;
 #[cfg(target_os = "macos")]
@@ -24,21 +36,22 @@ pub use macos::passthrough;
 use super::bindings;
 use super::descriptor_utils;
 
+pub use self::atime::AtimePolicy;
 pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
 pub use self::device::Fs;
 pub use self::dyn_filesystem::{DynFileSystem, DynFileSystemAdapter};
 pub use self::filesystem::ExportTable;
+pub use self::idmap::{IdMap, IdMapping};
+pub use self::init::validate_init_binary;
+pub use self::metrics::{FsMetrics, FsMetricsSnapshot};
+pub use self::server::{check_max_io_size, DEFAULT_MAX_IO_SIZE};
+pub use self::single_file::SingleFileFs;
+pub use self::xattrmap::XattrRule;
 
 mod defs {
-    use super::super::QueueConfig;
-
     pub const FS_DEV_ID: &str = "virtio_fs";
-    pub const NUM_QUEUES: usize = 2;
     pub const QUEUE_SIZE: u16 = 1024;
-    pub static QUEUE_CONFIG: [QueueConfig; NUM_QUEUES] = [QueueConfig::new(QUEUE_SIZE); NUM_QUEUES];
-    // High priority queue.
-    pub const HPQ_INDEX: usize = 0;
-    // Request queue.
+    // First request queue, after the high priority queue.
     pub const REQ_INDEX: usize = 1;
 
     pub mod uapi {
@@ -71,6 +84,8 @@ pub enum FsError {
     /// The `size` field of the `SetxattrIn` message does not match the length
     /// of the decoded value.
     InvalidXattrSize((u32, usize)),
+    /// A blocking lock request cannot be granted yet and has to be retried later.
+    LockWouldBlock,
     QueueReader(DescriptorError),
     QueueWriter(DescriptorError),
 }
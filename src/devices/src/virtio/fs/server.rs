@@ -7,17 +7,19 @@ use crossbeam_channel::Sender;
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
 
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::size_of;
-use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
 use vm_memory::ByteValued;
 
-use super::super::linux_errno::linux_error;
+use super::super::linux_errno::{linux_errno_raw, linux_error};
 use super::bindings;
 use super::descriptor_utils::{Reader, Writer};
 use super::filesystem::{
@@ -26,12 +28,22 @@ use super::filesystem::{
 };
 use super::fs_utils::einval;
 use super::fuse::*;
+use super::locks::MutexExt;
 use super::{FsError as Error, Result};
-use crate::virtio::VirtioShmRegion;
+use crate::virtio::{BootStage, BootTimeline, SharedExitStatus, VirtioShmRegion};
 
 const MAX_BUFFER_SIZE: u32 = 1 << 20;
+/// How much data a single read or write request can carry unless configured otherwise.
+pub const DEFAULT_MAX_IO_SIZE: u32 = MAX_BUFFER_SIZE;
+// The guest needs this many descriptors of a request for everything but the data pages.
+const REQUEST_OVERHEAD_DESCRIPTORS: u32 = 4;
+// The page size the guest splits requests into, which is what `max_pages` is counted in.
+const GUEST_PAGE_SIZE: u32 = 4096;
 const BUFFER_HEADER_SIZE: u32 = 0x1000;
 const DIRENT_PADDING: [u8; 8] = [0; 8];
+// How many interrupts for requests we haven't seen yet are remembered. The guest's unique ids only
+// grow, so when there are more the oldest ones are for requests that already completed.
+const MAX_PENDING_INTERRUPTS: usize = 1024;
 
 struct ZCReader<'a>(Reader<'a>);
 
@@ -68,13 +80,98 @@ impl io::Write for ZCWriter<'_> {
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     options: AtomicU64,
+    // Unique ids of requests the guest has sent a FUSE_INTERRUPT for.
+    interrupted: Mutex<BTreeSet<u64>>,
+    // Signaled whenever a request may have released a lock, so the workers retry the blocking lock
+    // requests they parked.
+    lock_waiters: Mutex<Vec<EventFd>>,
+    // Whether the guest has the filesystem mounted: set by FUSE_INIT, cleared by FUSE_DESTROY.
+    mounted: Arc<AtomicBool>,
+    // Where the boot stages the guest's init reports are recorded.
+    boot_timeline: Option<Arc<BootTimeline>>,
+    // The largest read or write the guest is told it can send.
+    max_io_size: u32,
+}
+
+/// Checks that read and write requests of up to `size` bytes fit in the device's queues, so the
+/// guest will really send them that large.
+pub fn check_max_io_size(size: u32) -> io::Result<()> {
+    let queue_size = super::defs::QUEUE_SIZE;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    if size == 0 || !size.is_multiple_of(GUEST_PAGE_SIZE) {
+        return Err(invalid(format!(
+            "max I/O size {size} isn't a multiple of {GUEST_PAGE_SIZE}"
+        )));
+    }
+    let max = (u32::from(queue_size) - REQUEST_OVERHEAD_DESCRIPTORS) * GUEST_PAGE_SIZE;
+    if size > max {
+        return Err(invalid(format!(
+            "max I/O size {size} is larger than the {max} bytes a queue of {queue_size} descriptors can carry"
+        )));
+    }
+    Ok(())
+}
+
+/// Answers a request that couldn't be handled, e.g. because the filesystem panicked on it, with
+/// EIO. Requests the guest expects no reply to are left alone. Without a readable request there's
+/// no unique id to reply with, but the guest matches replies to requests by their buffers anyway.
+pub fn reply_eio(r: Option<Reader>, w: Writer) -> Result<usize> {
+    let unique = match r {
+        Some(mut r) => {
+            let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
+            let no_reply = [
+                Opcode::Forget,
+                Opcode::BatchForget,
+                Opcode::Interrupt,
+                Opcode::Destroy,
+            ];
+            if no_reply.iter().any(|&op| op as u32 == in_header.opcode) {
+                return Ok(0);
+            }
+            in_header.unique
+        }
+        None => 0,
+    };
+    reply_error(io::Error::from_raw_os_error(libc::EIO), unique, w)
 }
 
 impl<F: FileSystem + Sync> Server<F> {
-    pub fn new(fs: F) -> Server<F> {
+    pub fn new(fs: F, mounted: Arc<AtomicBool>) -> Server<F> {
         Server {
             fs,
             options: AtomicU64::new(FsOptions::empty().bits()),
+            interrupted: Mutex::new(BTreeSet::new()),
+            lock_waiters: Mutex::new(Vec::new()),
+            mounted,
+            boot_timeline: None,
+            max_io_size: DEFAULT_MAX_IO_SIZE,
+        }
+    }
+
+    /// Records the boot stages the guest's init reports on `boot_timeline`.
+    pub fn with_boot_timeline(mut self, boot_timeline: Option<Arc<BootTimeline>>) -> Self {
+        self.boot_timeline = boot_timeline;
+        self
+    }
+
+    /// Lets the guest read and write up to `max_io_size` bytes in a single request, which must
+    /// have been checked with `check_max_io_size`.
+    pub fn with_max_io_size(mut self, max_io_size: u32) -> Self {
+        self.max_io_size = max_io_size;
+        self
+    }
+
+    /// Returns an event that is signaled whenever a request may have released a lock, which is
+    /// when the blocking lock requests that couldn't be granted are worth retrying.
+    pub fn lock_release_evt(&self) -> io::Result<EventFd> {
+        let evt = EventFd::new(EFD_NONBLOCK)?;
+        self.lock_waiters.lock_unpoisoned().push(evt.try_clone()?);
+        Ok(evt)
+    }
+
+    fn locks_released(&self) {
+        for evt in self.lock_waiters.lock_unpoisoned().iter() {
+            let _ = evt.write(1);
         }
     }
 
@@ -84,23 +181,37 @@ impl<F: FileSystem + Sync> Server<F> {
         mut r: Reader,
         w: Writer,
         shm_region: &Option<VirtioShmRegion>,
-        exit_code: &Arc<AtomicI32>,
+        exit_status: &Arc<SharedExitStatus>,
         #[cfg(target_os = "macos")] map_sender: &Option<Sender<WorkerMessage>>,
     ) -> Result<usize> {
         let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if in_header.len > (MAX_BUFFER_SIZE + BUFFER_HEADER_SIZE) {
+        if in_header.len > (MAX_BUFFER_SIZE.max(self.max_io_size) + BUFFER_HEADER_SIZE) {
             return reply_error(
                 linux_error(io::Error::from_raw_os_error(libc::ENOMEM)),
                 in_header.unique,
                 w,
             );
         }
+        // An interrupt can only catch a request that is still queued or was parked waiting for a
+        // lock, as one that is already running just gets to finish. Answer those without running
+        // them.
+        if in_header.opcode != Opcode::Interrupt as u32
+            && self.interrupted.lock_unpoisoned().remove(&in_header.unique)
+        {
+            return reply_error(
+                linux_error(io::Error::from_raw_os_error(libc::EINTR)),
+                in_header.unique,
+                w,
+            );
+        }
+
         debug!("opcode: {}", in_header.opcode);
         match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.lookup(in_header, r, w),
             x if x == Opcode::Forget as u32 => self.forget(in_header, r), // No reply.
             x if x == Opcode::Getattr as u32 => self.getattr(in_header, r, w),
+            x if x == Opcode::Statx as u32 => self.statx(in_header, r, w),
             x if x == Opcode::Setattr as u32 => self.setattr(in_header, r, w),
             x if x == Opcode::Readlink as u32 => self.readlink(in_header, w),
             x if x == Opcode::Symlink as u32 => self.symlink(in_header, r, w),
@@ -114,6 +225,7 @@ impl<F: FileSystem + Sync> Server<F> {
             x if x == Opcode::Read as u32 => self.read(in_header, r, w),
             x if x == Opcode::Write as u32 => self.write(in_header, r, w),
             x if x == Opcode::Statfs as u32 => self.statfs(in_header, w),
+            x if x == Opcode::Syncfs as u32 => self.syncfs(in_header, r, w),
             x if x == Opcode::Release as u32 => self.release(in_header, r, w),
             x if x == Opcode::Fsync as u32 => self.fsync(in_header, r, w),
             x if x == Opcode::Setxattr as u32 => self.setxattr(in_header, r, w),
@@ -131,10 +243,11 @@ impl<F: FileSystem + Sync> Server<F> {
             x if x == Opcode::Setlkw as u32 => self.setlkw(in_header, r, w),
             x if x == Opcode::Access as u32 => self.access(in_header, r, w),
             x if x == Opcode::Create as u32 => self.create(in_header, r, w),
-            x if x == Opcode::Interrupt as u32 => self.interrupt(in_header),
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(in_header, r, w),
+            x if x == Opcode::Interrupt as u32 => self.interrupt(r), // No reply.
             x if x == Opcode::Bmap as u32 => self.bmap(in_header, r, w),
             x if x == Opcode::Destroy as u32 => self.destroy(),
-            x if x == Opcode::Ioctl as u32 => self.ioctl(in_header, r, w, exit_code),
+            x if x == Opcode::Ioctl as u32 => self.ioctl(in_header, r, w, exit_status),
             x if x == Opcode::Poll as u32 => self.poll(in_header, r, w),
             x if x == Opcode::NotifyReply as u32 => self.notify_reply(in_header, r, w),
             x if x == Opcode::BatchForget as u32 => self.batch_forget(in_header, r, w),
@@ -243,6 +356,34 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
+    fn statx(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let StatxIn {
+            getattr_flags, fh, ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        let handle = if (getattr_flags & GETATTR_FH) != 0 {
+            Some(fh.into())
+        } else {
+            None
+        };
+
+        match self
+            .fs
+            .statx(Context::from(in_header), in_header.nodeid.into(), handle)
+        {
+            Ok((st, btime, timeout)) => {
+                let out = StatxOut {
+                    attr_valid: timeout.as_secs(),
+                    attr_valid_nsec: timeout.subsec_nanos(),
+                    stat: Statx::new(st, btime),
+                    ..Default::default()
+                };
+                reply_ok(Some(out), None, in_header.unique, w)
+            }
+            Err(e) => reply_error(e, in_header.unique, w),
+        }
+    }
+
     fn setattr(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let setattr_in: SetattrIn = r.read_obj().map_err(Error::DecodeMessage)?;
 
@@ -548,7 +689,7 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if size > MAX_BUFFER_SIZE {
+        if size > self.max_io_size {
             return reply_error(
                 linux_error(io::Error::from_raw_os_error(libc::ENOMEM)),
                 in_header.unique,
@@ -602,7 +743,7 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        if size > MAX_BUFFER_SIZE {
+        if size > self.max_io_size {
             return reply_error(
                 linux_error(io::Error::from_raw_os_error(libc::ENOMEM)),
                 in_header.unique,
@@ -655,6 +796,18 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
+    fn syncfs(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let SyncfsIn { .. } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self
+            .fs
+            .syncfs(Context::from(in_header), in_header.nodeid.into())
+        {
+            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
+        }
+    }
+
     fn release(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let ReleaseIn {
             fh,
@@ -680,7 +833,10 @@ impl<F: FileSystem + Sync> Server<F> {
             flock_release,
             lock_owner,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Ok(()) => {
+                self.locks_released();
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -834,7 +990,10 @@ impl<F: FileSystem + Sync> Server<F> {
             fh.into(),
             lock_owner,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Ok(()) => {
+                self.locks_released();
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -884,7 +1043,9 @@ impl<F: FileSystem + Sync> Server<F> {
             );
         }
 
-        // These fuse features are supported by this server by default.
+        // These fuse features are supported by this server by default. Others, such as
+        // HANDLE_KILLPRIV_V2, depend on what the host lets the filesystem do, so it asks for them
+        // in its `init`.
         let mut supported = FsOptions::ASYNC_READ
             | FsOptions::PARALLEL_DIROPS
             | FsOptions::BIG_WRITES
@@ -894,7 +1055,6 @@ impl<F: FileSystem + Sync> Server<F> {
             | FsOptions::ATOMIC_O_TRUNC
             | FsOptions::MAX_PAGES
             | FsOptions::SUBMOUNTS
-            | FsOptions::HANDLE_KILLPRIV_V2
             | FsOptions::INIT_EXT
             | FsOptions::ALLOW_IDMAP;
 
@@ -906,12 +1066,13 @@ impl<F: FileSystem + Sync> Server<F> {
         let capable = FsOptions::from_bits_truncate(flags_64);
 
         let page_size: u32 = unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() };
-        let max_pages = ((MAX_BUFFER_SIZE - 1) / page_size) + 1;
+        let max_pages = ((self.max_io_size - 1) / page_size) + 1;
 
         match self.fs.init(capable) {
             Ok(want) => {
                 let enabled = (capable & (want | supported)).bits();
                 self.options.store(enabled, Ordering::Relaxed);
+                self.mounted.store(true, Ordering::Relaxed);
 
                 let out = InitOut {
                     major: KERNEL_VERSION,
@@ -920,7 +1081,7 @@ impl<F: FileSystem + Sync> Server<F> {
                     flags: enabled as u32,
                     max_background: u16::MAX,
                     congestion_threshold: (u16::MAX / 4) * 3,
-                    max_write: MAX_BUFFER_SIZE,
+                    max_write: self.max_io_size,
                     time_gran: 1, // nanoseconds
                     max_pages: max_pages.try_into().unwrap(),
                     map_alignment: 0,
@@ -1038,7 +1199,10 @@ impl<F: FileSystem + Sync> Server<F> {
             flags,
             fh.into(),
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Ok(()) => {
+                self.locks_released();
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -1060,27 +1224,79 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn getlk(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.getlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn getlk(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.getlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(lk) => reply_ok(Some(LkOut { lk }), None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlk(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.setlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn setlk(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.setlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(()) => {
+                self.locks_released();
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlkw(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.setlkw() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn setlkw(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.setlkw(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(()) => {
+                self.locks_released();
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            // Nothing has been written yet, so the worker can park the request and retry it.
+            Err(e) if e.raw_os_error() == Some(linux_errno_raw(libc::EAGAIN)) => {
+                Err(Error::LockWouldBlock)
+            }
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
@@ -1132,35 +1348,54 @@ impl<F: FileSystem + Sync> Server<F> {
             umask,
             extensions,
         ) {
-            Ok((entry, handle, opts)) => {
-                let entry_out = EntryOut {
-                    nodeid: entry.inode,
-                    generation: entry.generation,
-                    entry_valid: entry.entry_timeout.as_secs(),
-                    attr_valid: entry.attr_timeout.as_secs(),
-                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-                    attr: entry.attr.into(),
-                };
-                let open_out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
-                    ..Default::default()
-                };
+            Ok((entry, handle, opts)) => reply_create(entry, handle, opts, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
+        }
+    }
 
-                // Kind of a hack to write both structs.
-                reply_ok(
-                    Some(entry_out),
-                    Some(open_out.as_slice()),
-                    in_header.unique,
-                    w,
-                )
-            }
+    fn tmpfile(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let CreateIn {
+            flags, mode, umask, ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        // The request carries a placeholder name, which is only needed to locate the extensions.
+        let namelen = (in_header.len as usize)
+            .checked_sub(size_of::<InHeader>())
+            .and_then(|l| l.checked_sub(size_of::<CreateIn>()))
+            .ok_or(Error::InvalidHeaderLength)?;
+
+        let mut buf = vec![0; namelen];
+
+        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let mut components = buf.split_inclusive(|c| *c == b'\0');
+        let name = components.next().ok_or(Error::MissingParameter)?;
+
+        let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
+
+        let extensions = get_extensions(options, name.len(), buf.as_slice())?;
+
+        match self.fs.tmpfile(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            mode,
+            flags,
+            umask,
+            extensions,
+        ) {
+            Ok((entry, handle, opts)) => reply_create(entry, handle, opts, in_header.unique, w),
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn interrupt(&self, _in_header: InHeader) -> Result<usize> {
+    fn interrupt(&self, mut r: Reader) -> Result<usize> {
+        let InterruptIn { unique } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        let mut interrupted = self.interrupted.lock_unpoisoned();
+        interrupted.insert(unique);
+        if interrupted.len() > MAX_PENDING_INTERRUPTS {
+            interrupted.pop_first();
+        }
+
         Ok(0)
     }
 
@@ -1175,6 +1410,7 @@ impl<F: FileSystem + Sync> Server<F> {
     fn destroy(&self) -> Result<usize> {
         // No reply to this function.
         self.fs.destroy();
+        self.mounted.store(false, Ordering::Relaxed);
 
         Ok(0)
     }
@@ -1184,7 +1420,7 @@ impl<F: FileSystem + Sync> Server<F> {
         in_header: InHeader,
         mut r: Reader,
         w: Writer,
-        exit_code: &Arc<AtomicI32>,
+        exit_status: &Arc<SharedExitStatus>,
     ) -> Result<usize> {
         let IoctlIn {
             fh,
@@ -1195,6 +1431,49 @@ impl<F: FileSystem + Sync> Server<F> {
             out_size,
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        // `_IO('v', 5)` as the guest encodes it, the same for every backend: init reports the
+        // boot stage it reached in `arg`.
+        const VIRTIO_IOC_BOOT_STAGE_REQ: u32 = 0x7605;
+        // `_IOW('v', 6, struct fuse_fadvise_in)`: a guest program passes on its `posix_fadvise`
+        // hints, which the guest kernel would otherwise keep to itself.
+        const VIRTIO_IOC_FADVISE_REQ: u32 = 0x4018_7606;
+        if cmd == VIRTIO_IOC_BOOT_STAGE_REQ {
+            return match BootStage::from_guest_code(arg) {
+                Some(stage) => {
+                    if let Some(timeline) = &self.boot_timeline {
+                        timeline.mark(stage);
+                    }
+                    reply_ok(Some(IoctlOut::default()), None, in_header.unique, w)
+                }
+                None => reply_error(einval(), in_header.unique, w),
+            };
+        }
+        if cmd == VIRTIO_IOC_FADVISE_REQ {
+            if in_size as usize != size_of::<FadviseIn>() {
+                return reply_error(einval(), in_header.unique, w);
+            }
+            let FadviseIn {
+                offset,
+                len,
+                advice,
+                ..
+            } = r.read_obj().map_err(Error::DecodeMessage)?;
+            let res = Advice::from_guest(advice).and_then(|advice| {
+                self.fs.fadvise(
+                    Context::from(in_header),
+                    in_header.nodeid.into(),
+                    fh.into(),
+                    offset,
+                    len,
+                    advice,
+                )
+            });
+            return match res {
+                Ok(()) => reply_ok(Some(IoctlOut::default()), None, in_header.unique, w),
+                Err(e) => reply_error(e, in_header.unique, w),
+            };
+        }
+
         match self.fs.ioctl(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1204,7 +1483,7 @@ impl<F: FileSystem + Sync> Server<F> {
             arg,
             in_size,
             out_size,
-            exit_code,
+            exit_status,
         ) {
             Ok(data) => {
                 let out = IoctlOut {
@@ -1466,6 +1745,32 @@ fn reply_ok<T: ByteValued>(
     Ok(w.bytes_written())
 }
 
+fn reply_create<H: Into<u64>>(
+    entry: Entry,
+    handle: Option<H>,
+    opts: OpenOptions,
+    unique: u64,
+    w: Writer,
+) -> Result<usize> {
+    let entry_out = EntryOut {
+        nodeid: entry.inode,
+        generation: entry.generation,
+        entry_valid: entry.entry_timeout.as_secs(),
+        attr_valid: entry.attr_timeout.as_secs(),
+        entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
+        attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
+        attr: entry.attr.into(),
+    };
+    let open_out = OpenOut {
+        fh: handle.map(Into::into).unwrap_or(0),
+        open_flags: opts.bits(),
+        ..Default::default()
+    };
+
+    // Kind of a hack to write both structs.
+    reply_ok(Some(entry_out), Some(open_out.as_slice()), unique, w)
+}
+
 fn reply_error(e: io::Error, unique: u64, mut w: Writer) -> Result<usize> {
     let header = OutHeader {
         len: size_of::<OutHeader>() as u32,
@@ -1639,7 +1944,7 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
             ExtType::SupGroups => {
                 // We're not exposing this feature to the guest, so we shouldn't get
                 // any messages including this extension.
-                unimplemented!("Support for supplemental groups is not implemented");
+                return Err(Error::DecodeMessage(einval()));
             }
         }
 
@@ -1655,3 +1960,380 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
 
     Ok(extensions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::metrics::FsMetrics;
+    use super::*;
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    const REQUEST_ADDR: u64 = 0x1000;
+
+    // Never grants a blocking lock, like a lock held by another process for a long time.
+    #[derive(Default)]
+    struct BlockedLocks {
+        attempts: AtomicU64,
+    }
+
+    impl FileSystem for BlockedLocks {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn setlk(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _handle: u64,
+            _owner: u64,
+            _lock: FileLock,
+            _flags: u32,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn setlkw(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _handle: u64,
+            _owner: u64,
+            _lock: FileLock,
+            _flags: u32,
+        ) -> io::Result<()> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            Err(linux_error(io::Error::from_raw_os_error(libc::EAGAIN)))
+        }
+    }
+
+    // Counts the writes it gets, like the passthrough backend does.
+    #[derive(Default)]
+    struct CountedWrites {
+        metrics: FsMetrics,
+    }
+
+    impl FileSystem for CountedWrites {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn write<R: io::Read + ZeroCopyReader>(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _handle: u64,
+            mut r: R,
+            size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _delayed_write: bool,
+            _kill_priv: bool,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            let count = io::copy(&mut (&mut r).take(size.into()), &mut io::sink())? as usize;
+            self.metrics.write(count);
+            Ok(count)
+        }
+    }
+
+    // Remembers the last access pattern hint it got.
+    #[derive(Default)]
+    struct Advised {
+        last: Mutex<Option<(u64, u64, u64, Advice)>>,
+    }
+
+    impl FileSystem for Advised {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn fadvise(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            handle: u64,
+            offset: u64,
+            len: u64,
+            advice: Advice,
+        ) -> io::Result<()> {
+            *self.last.lock_unpoisoned() = Some((handle, offset, len, advice));
+            Ok(())
+        }
+    }
+
+    // Sends a request with `body` after its header, returning the result and the header of
+    // the reply, if any.
+    fn send<F: FileSystem + Sync, T: ByteValued>(
+        server: &Server<F>,
+        mem: &GuestMemoryMmap,
+        opcode: Opcode,
+        unique: u64,
+        body: T,
+    ) -> (Result<usize>, Option<OutHeader>) {
+        let (res, reply, _) = send_bytes(server, mem, opcode, unique, body.as_slice());
+        (res, reply)
+    }
+
+    // Like `send`, but also returns where the body of the reply starts.
+    fn send_bytes<F: FileSystem + Sync>(
+        server: &Server<F>,
+        mem: &GuestMemoryMmap,
+        opcode: Opcode,
+        unique: u64,
+        body: &[u8],
+    ) -> (Result<usize>, Option<OutHeader>, GuestAddress) {
+        let len = (size_of::<InHeader>() + body.len()) as u32;
+        let header = InHeader {
+            len,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            ..Default::default()
+        };
+        // The reply buffer follows the request.
+        let reply_addr = REQUEST_ADDR + len as u64;
+        mem.write_obj(header, GuestAddress(REQUEST_ADDR)).unwrap();
+        mem.write_slice(
+            body,
+            GuestAddress(REQUEST_ADDR + size_of::<InHeader>() as u64),
+        )
+        .unwrap();
+        mem.write_obj(OutHeader::default(), GuestAddress(reply_addr))
+            .unwrap();
+
+        let chain = create_descriptor_chain(
+            mem,
+            GuestAddress(0),
+            GuestAddress(REQUEST_ADDR),
+            vec![
+                (DescriptorType::Readable, len),
+                (DescriptorType::Writable, 0x1000),
+            ],
+            0,
+        )
+        .unwrap();
+        let reader = Reader::new(mem, chain.clone()).unwrap();
+        let writer = Writer::new(mem, chain).unwrap();
+
+        let res = server.handle_message(
+            reader,
+            writer,
+            &None,
+            &Arc::new(SharedExitStatus::new()),
+            #[cfg(target_os = "macos")]
+            &None,
+        );
+        let reply: OutHeader = mem.read_obj(GuestAddress(reply_addr)).unwrap();
+        (
+            res,
+            (reply.unique != 0).then_some(reply),
+            GuestAddress(reply_addr + size_of::<OutHeader>() as u64),
+        )
+    }
+
+    // Mounts `server` the way a Linux guest does and returns the reply to FUSE_INIT.
+    fn init<F: FileSystem + Sync>(server: &Server<F>, mem: &GuestMemoryMmap) -> InitOut {
+        let init = InitInCompat {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            max_readahead: 0x20000,
+            flags: (FsOptions::BIG_WRITES | FsOptions::MAX_PAGES).bits() as u32,
+        };
+        let (res, reply, out) = send_bytes(server, mem, Opcode::Init, 1, init.as_slice());
+        res.unwrap();
+        assert_eq!(reply.unwrap().error, 0);
+        mem.read_obj(out).unwrap()
+    }
+
+    // Writes `len` bytes the way a Linux guest does, in requests of at most the negotiated
+    // `max_write` bytes.
+    fn guest_write<F: FileSystem + Sync>(
+        server: &Server<F>,
+        mem: &GuestMemoryMmap,
+        max_write: u32,
+        len: u32,
+    ) {
+        let mut offset = 0;
+        while offset < len {
+            let size = max_write.min(len - offset);
+            let write = WriteIn {
+                offset: offset.into(),
+                size,
+                ..Default::default()
+            };
+            let mut body = write.as_slice().to_vec();
+            body.resize(body.len() + size as usize, 0xaa);
+            let (_, reply, _) = send_bytes(server, mem, Opcode::Write, 2, &body);
+            assert_eq!(reply.unwrap().error, 0);
+            offset += size;
+        }
+    }
+
+    #[test]
+    fn releasing_locks_wakes_parked_requests() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let server = Server::new(BlockedLocks::default(), Default::default());
+        let released = server.lock_release_evt().unwrap();
+        let lock = LkIn {
+            lk: FileLock {
+                type_: libc::F_WRLCK as u32,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // A request that has to wait releases nothing.
+        let (res, _) = send(&server, &mem, Opcode::Setlkw, 10, lock);
+        assert!(matches!(res, Err(Error::LockWouldBlock)));
+        assert!(released.read().is_err());
+
+        let unlock = LkIn {
+            lk: FileLock {
+                type_: libc::F_UNLCK as u32,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (_, reply) = send(&server, &mem, Opcode::Setlk, 11, unlock);
+        assert_eq!(reply.unwrap().error, 0);
+        assert_eq!(released.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn interrupt_parked_and_queued_requests() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let server = Server::new(BlockedLocks::default(), Default::default());
+        let lock = LkIn {
+            lk: FileLock {
+                type_: libc::F_WRLCK as u32,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // The lock can't be taken, so the request gets parked without a reply.
+        let (res, reply) = send(&server, &mem, Opcode::Setlkw, 10, lock);
+        assert!(matches!(res, Err(Error::LockWouldBlock)));
+        assert!(reply.is_none());
+
+        // Interrupts themselves are never answered.
+        let (res, reply) = send(
+            &server,
+            &mem,
+            Opcode::Interrupt,
+            11,
+            InterruptIn { unique: 10 },
+        );
+        assert_eq!(res.unwrap(), 0);
+        assert!(reply.is_none());
+
+        // When the worker retries the parked request, it fails with EINTR instead.
+        let (_, reply) = send(&server, &mem, Opcode::Setlkw, 10, lock);
+        let reply = reply.unwrap();
+        assert_eq!(reply.unique, 10);
+        assert_eq!(reply.error, -linux_errno_raw(libc::EINTR));
+        assert_eq!(server.fs.attempts.load(Ordering::Relaxed), 1);
+
+        // An interrupt that overtakes its request keeps the request from running at all.
+        let (res, _) = send(
+            &server,
+            &mem,
+            Opcode::Interrupt,
+            13,
+            InterruptIn { unique: 12 },
+        );
+        assert_eq!(res.unwrap(), 0);
+        let (_, reply) = send(&server, &mem, Opcode::Setlkw, 12, lock);
+        assert_eq!(reply.unwrap().error, -linux_errno_raw(libc::EINTR));
+        assert_eq!(server.fs.attempts.load(Ordering::Relaxed), 1);
+
+        // Each interrupt only applies once.
+        let (res, _) = send(&server, &mem, Opcode::Setlkw, 10, lock);
+        assert!(matches!(res, Err(Error::LockWouldBlock)));
+        assert_eq!(server.fs.attempts.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn max_io_size() {
+        const LEN: u32 = 4 << 20;
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 8 << 20)]).unwrap();
+
+        let server = Server::new(CountedWrites::default(), Default::default());
+        let out = init(&server, &mem);
+        assert_eq!(out.max_write, DEFAULT_MAX_IO_SIZE);
+        guest_write(&server, &mem, out.max_write, LEN);
+        let default = server.fs.metrics.snapshot();
+        assert_eq!(default.bytes_written, u64::from(LEN));
+
+        // Requests larger than negotiated are refused.
+        let write = WriteIn {
+            size: 2 << 20,
+            ..Default::default()
+        };
+        let mut body = write.as_slice().to_vec();
+        body.resize(body.len() + (2 << 20), 0);
+        let (_, reply, _) = send_bytes(&server, &mem, Opcode::Write, 3, &body);
+        assert_eq!(reply.unwrap().error, -linux_errno_raw(libc::ENOMEM));
+
+        // The same write arrives in fewer, larger requests.
+        check_max_io_size(2 << 20).unwrap();
+        let server =
+            Server::new(CountedWrites::default(), Default::default()).with_max_io_size(2 << 20);
+        let out = init(&server, &mem);
+        assert_eq!(out.max_write, 2 << 20);
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        assert_eq!(u32::from(out.max_pages) * page_size, 2 << 20);
+        guest_write(&server, &mem, out.max_write, LEN);
+        let larger = server.fs.metrics.snapshot();
+        assert_eq!(larger.bytes_written, u64::from(LEN));
+        assert_eq!(larger.writes, default.writes / 2);
+    }
+
+    #[test]
+    fn max_io_size_fits_queue() {
+        check_max_io_size(4096).unwrap();
+        check_max_io_size(DEFAULT_MAX_IO_SIZE).unwrap();
+        // Every page of data takes a descriptor, next to the ones for the headers.
+        let max = (u32::from(super::super::defs::QUEUE_SIZE) - 4) * 4096;
+        check_max_io_size(max).unwrap();
+        for size in [0, 1000, 4097, max + 4096] {
+            let err = check_max_io_size(size).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{size}");
+        }
+    }
+
+    #[test]
+    fn fadvise_ioctl() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let server = Server::new(Advised::default(), Default::default());
+        let request = |advice: u32, in_size: u32| {
+            let ioctl = IoctlIn {
+                fh: 7,
+                cmd: 0x4018_7606,
+                in_size,
+                ..Default::default()
+            };
+            let fadvise = FadviseIn {
+                offset: 4096,
+                len: 8192,
+                advice,
+                padding: 0,
+            };
+            let mut body = ioctl.as_slice().to_vec();
+            body.extend_from_slice(fadvise.as_slice());
+            let (_, reply, _) = send_bytes(&server, &mem, Opcode::Ioctl, 1, &body);
+            reply.unwrap().error
+        };
+
+        // POSIX_FADV_DONTNEED, as a Linux guest encodes it.
+        assert_eq!(request(4, 24), 0);
+        assert_eq!(
+            *server.fs.last.lock_unpoisoned(),
+            Some((7, 4096, 8192, Advice::DontNeed))
+        );
+
+        // Unknown hints and malformed requests never reach the filesystem.
+        *server.fs.last.lock_unpoisoned() = None;
+        assert_eq!(request(99, 24), -linux_errno_raw(libc::EINVAL));
+        assert_eq!(request(0, 16), -linux_errno_raw(libc::EINVAL));
+        assert!(server.fs.last.lock_unpoisoned().is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::RwLock;
+
+use super::locks::RwLockExt;
+
+/// The number of independently locked shards in each map.
+const NUM_SHARDS: usize = 16;
+
+fn shard_index<K: Hash>(key: &K) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % NUM_SHARDS
+}
+
+// A shard of a `ShardedMultikeyMap`, which keeps each value's alternate key next to it so entries
+// can be removed by main key alone.
+type MultikeyShard<K1, K2, V> = RwLock<BTreeMap<K1, (K2, V)>>;
+
+fn new_shards<T: Default>() -> Box<[RwLock<T>]> {
+    (0..NUM_SHARDS).map(|_| RwLock::default()).collect()
+}
+
+/// A map split into `NUM_SHARDS` BTreeMaps, each behind its own lock, so that operations on
+/// different keys rarely contend. Values are handed out by clone, which makes this meant for
+/// cheaply clonable values such as `Arc`s.
+pub struct ShardedMap<K, V> {
+    shards: Box<[RwLock<BTreeMap<K, V>>]>,
+}
+
+impl<K: Ord + Hash, V: Clone> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        ShardedMap {
+            shards: new_shards(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<BTreeMap<K, V>> {
+        &self.shards[shard_index(key)]
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).read_unpoisoned().get(key).cloned()
+    }
+
+    /// Calls `f` with the value for `key` while holding its shard's read lock, which keeps the
+    /// value from being removed until `f` returns.
+    pub fn get_with<F, R>(&self, key: &K, f: F) -> R
+    where
+        F: FnOnce(Option<&V>) -> R,
+    {
+        f(self.shard(key).read_unpoisoned().get(key))
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write_unpoisoned().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write_unpoisoned().remove(key)
+    }
+
+    /// Removes the value for `key` only if `f` returns true for it. The check and the removal
+    /// happen under the same lock.
+    pub fn remove_if<F>(&self, key: &K, f: F) -> Option<V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        let mut shard = self.shard(key).write_unpoisoned();
+        if shard.get(key).is_some_and(f) {
+            shard.remove(key)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a snapshot of every value in the map.
+    pub fn values(&self) -> Vec<V> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read_unpoisoned()
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.write_unpoisoned().clear();
+        }
+    }
+}
+
+impl<K: Ord + Hash, V: Clone> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The sharded counterpart of `MultikeyBTreeMap`: values are sharded by their main key, while a
+/// separately locked index maps each alternate key back to its main key. There is a 1:1
+/// relationship between the 2 key types.
+///
+/// Operations that touch the index always lock it before the shard, so holding the index's write
+/// lock (as `remove_if` does) keeps lookups by alternate key from observing a value that is about
+/// to be removed.
+pub struct ShardedMultikeyMap<K1, K2, V> {
+    shards: Box<[MultikeyShard<K1, K2, V>]>,
+    alt: RwLock<BTreeMap<K2, K1>>,
+}
+
+impl<K1, K2, V> ShardedMultikeyMap<K1, K2, V>
+where
+    K1: Clone + Ord + Hash,
+    K2: Clone + Ord,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        ShardedMultikeyMap {
+            shards: new_shards(),
+            alt: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn shard(&self, key: &K1) -> &MultikeyShard<K1, K2, V> {
+        &self.shards[shard_index(key)]
+    }
+
+    pub fn get(&self, key: &K1) -> Option<V> {
+        self.shard(key)
+            .read_unpoisoned()
+            .get(key)
+            .map(|(_, v)| v.clone())
+    }
+
+    pub fn get_alt(&self, key: &K2) -> Option<V> {
+        let alt = self.alt.read_unpoisoned();
+        let k1 = alt.get(key)?;
+        self.get(k1)
+    }
+
+    /// Inserts a new entry into the map with the given keys and value, with the same semantics as
+    /// `MultikeyBTreeMap::insert`.
+    pub fn insert(&self, k1: K1, k2: K2, v: V) -> Option<V> {
+        let mut alt = self.alt.write_unpoisoned();
+        let oldval = if let Some(oldkey) = alt.insert(k2.clone(), k1.clone()) {
+            self.shard(&oldkey).write_unpoisoned().remove(&oldkey)
+        } else {
+            None
+        };
+        self.shard(&k1)
+            .write_unpoisoned()
+            .insert(k1, (k2.clone(), v))
+            .or(oldval)
+            .map(|(oldk2, v)| {
+                if oldk2 != k2 {
+                    alt.remove(&oldk2);
+                }
+                v
+            })
+    }
+
+    /// Removes the entry for `key` only if `f` returns true for it. Lookups by either key are
+    /// blocked while `f` runs.
+    pub fn remove_if<F>(&self, key: &K1, f: F) -> Option<V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        let mut alt = self.alt.write_unpoisoned();
+        let mut shard = self.shard(key).write_unpoisoned();
+        if !shard.get(key).is_some_and(|(_, v)| f(v)) {
+            return None;
+        }
+
+        let (k2, v) = shard.remove(key)?;
+        alt.remove(&k2);
+        Some(v)
+    }
+
+    pub fn remove(&self, key: &K1) -> Option<V> {
+        self.remove_if(key, |_| true)
+    }
+
+    /// Returns a snapshot of every value in the map.
+    pub fn values(&self) -> Vec<V> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read_unpoisoned()
+                    .values()
+                    .map(|(_, v)| v.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        let mut alt = self.alt.write_unpoisoned();
+        for shard in self.shards.iter() {
+            shard.write_unpoisoned().clear();
+        }
+        alt.clear();
+    }
+}
+
+impl<K1, K2, V> Default for ShardedMultikeyMap<K1, K2, V>
+where
+    K1: Clone + Ord + Hash,
+    K2: Clone + Ord,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multikey_insert_replaces_both_keys() {
+        let m = ShardedMultikeyMap::<u64, i64, u32>::new();
+
+        assert!(m.insert(1, -1, 10).is_none());
+        assert!(m.insert(2, -2, 20).is_none());
+
+        // Both keys present: the main key's value is updated and the alternate key's entry goes.
+        assert_eq!(m.insert(1, -2, 11), Some(10));
+        assert_eq!(m.get(&1), Some(11));
+        assert_eq!(m.get_alt(&-2), Some(11));
+        assert_eq!(m.get_alt(&-1), None);
+        assert_eq!(m.get(&2), None);
+
+        assert_eq!(m.remove(&1), Some(11));
+        assert_eq!(m.get_alt(&-2), None);
+    }
+
+    #[test]
+    fn remove_if() {
+        let m = ShardedMultikeyMap::<u64, i64, u32>::new();
+        m.insert(1, -1, 10);
+
+        assert_eq!(m.remove_if(&1, |v| *v == 0), None);
+        assert_eq!(m.get_alt(&-1), Some(10));
+        assert_eq!(m.remove_if(&1, |v| *v == 10), Some(10));
+        assert_eq!(m.get_alt(&-1), None);
+
+        let h = ShardedMap::<u64, u32>::new();
+        for k in 0..100 {
+            h.insert(k, k as u32);
+        }
+        assert_eq!(h.values().len(), 100);
+        assert_eq!(h.remove_if(&7, |v| *v == 8), None);
+        assert_eq!(h.remove_if(&7, |v| *v == 7), Some(7));
+        h.clear();
+        assert_eq!(h.get(&8), None);
+    }
+
+    #[test]
+    fn concurrent_insert_remove() {
+        let m = std::sync::Arc::new(ShardedMultikeyMap::<u64, u64, u64>::new());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let m = m.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        let k = t * 1000 + i;
+                        m.insert(k, k + 1_000_000, k);
+                        assert_eq!(m.get_alt(&(k + 1_000_000)), Some(k));
+                        assert_eq!(m.remove(&k), Some(k));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(m.alt.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,901 @@
+//! A share holding a single host file.
+//!
+//! The guest sees a read-only root directory with one entry, the shared file. Nothing is
+//! created, renamed or removed in it; the file itself is read-only unless the share is writable.
+
+#[cfg(target_os = "macos")]
+use crossbeam_channel::{unbounded, Sender};
+#[cfg(target_os = "macos")]
+use utils::worker_message::WorkerMessage;
+
+#[cfg(target_os = "macos")]
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions as HostOpenOptions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+#[cfg(target_os = "macos")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::super::bindings::{self, stat64, statvfs64};
+use super::super::linux_errno::linux_error;
+use super::dyn_filesystem::DynFileSystem;
+use super::filesystem::{
+    Context, DirEntry, Entry, Extensions, FsOptions, OpenOptions, RemovemappingOne, SetattrValid,
+    ZeroCopyReader, ZeroCopyWriter,
+};
+use super::fuse::{SetupmappingFlags, ROOT_ID};
+#[cfg(target_os = "macos")]
+use super::locks::MutexExt;
+
+const FILE_INODE: u64 = ROOT_ID + 1;
+// The host file can change behind the guest's back, so don't let it cache attributes for long.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serves a root directory whose only entry is a host file, under a name of its own.
+pub struct SingleFileFs {
+    file: File,
+    name: Box<[u8]>,
+    writable: bool,
+    // The DAX windows mapped on the host, by guest address.
+    #[cfg(target_os = "macos")]
+    map_windows: Mutex<HashMap<u64, u64>>,
+}
+
+impl SingleFileFs {
+    /// Opens `path` to be served as `name`, for reading and also writing if `writable`.
+    pub fn new(path: &Path, name: &str, writable: bool) -> io::Result<Self> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name:?} isn't a valid file name"),
+            ));
+        }
+        let file = HostOpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a regular file", path.display()),
+            ));
+        }
+        Ok(SingleFileFs {
+            file,
+            name: name.as_bytes().into(),
+            writable,
+            #[cfg(target_os = "macos")]
+            map_windows: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn file_attr(&self) -> io::Result<stat64> {
+        let mut st = MaybeUninit::<stat64>::zeroed();
+        // Safe because the kernel only writes to `st` and we check the return value.
+        #[cfg(target_os = "linux")]
+        let res = unsafe { libc::fstat64(self.file.as_raw_fd(), st.as_mut_ptr()) };
+        #[cfg(target_os = "macos")]
+        let res = unsafe { libc::fstat(self.file.as_raw_fd(), st.as_mut_ptr()) };
+        if res < 0 {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+        // Safe because the kernel filled in `st`.
+        let mut st = unsafe { st.assume_init() };
+        st.st_ino = FILE_INODE;
+        st.st_nlink = 1;
+        if !self.writable {
+            st.st_mode &= !0o222;
+        }
+        Ok(st)
+    }
+
+    fn root_attr(&self) -> io::Result<stat64> {
+        let file = self.file_attr()?;
+        // Safe because stat64 only has POD fields.
+        let mut st: stat64 = unsafe { std::mem::zeroed() };
+        st.st_ino = ROOT_ID;
+        st.st_mode = libc::S_IFDIR | 0o555;
+        st.st_nlink = 2;
+        st.st_uid = file.st_uid;
+        st.st_gid = file.st_gid;
+        st.st_atime = file.st_atime;
+        st.st_mtime = file.st_mtime;
+        st.st_ctime = file.st_ctime;
+        st.st_blksize = file.st_blksize;
+        Ok(st)
+    }
+
+    fn attr(&self, inode: u64) -> io::Result<stat64> {
+        match inode {
+            ROOT_ID => self.root_attr(),
+            FILE_INODE => self.file_attr(),
+            _ => Err(errno(libc::ENOENT)),
+        }
+    }
+
+    fn entry(&self, inode: u64) -> io::Result<Entry> {
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.attr(inode)?,
+            attr_flags: 0,
+            attr_timeout: TIMEOUT,
+            entry_timeout: TIMEOUT,
+        })
+    }
+
+    fn check_file(&self, inode: u64) -> io::Result<()> {
+        match inode {
+            FILE_INODE => Ok(()),
+            ROOT_ID => Err(errno(libc::EISDIR)),
+            _ => Err(errno(libc::ENOENT)),
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(errno(libc::EROFS))
+        }
+    }
+
+    // The error for changing the root directory, which never changes.
+    fn no_entries(&self) -> io::Error {
+        if self.writable {
+            errno(libc::EPERM)
+        } else {
+            errno(libc::EROFS)
+        }
+    }
+
+    fn dir_entries(&self, offset: u64) -> Vec<DirEntry<'_>> {
+        let entries = [
+            (ROOT_ID, libc::DT_DIR, &b"."[..]),
+            (ROOT_ID, libc::DT_DIR, &b".."[..]),
+            (FILE_INODE, libc::DT_REG, &self.name[..]),
+        ];
+        entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (ino, type_, name))| DirEntry {
+                ino,
+                offset: i as u64 + 1,
+                type_: type_.into(),
+                name,
+            })
+            .collect()
+    }
+}
+
+fn errno(errno: i32) -> io::Error {
+    linux_error(io::Error::from_raw_os_error(errno))
+}
+
+impl DynFileSystem for SingleFileFs {
+    fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+        Ok(FsOptions::empty())
+    }
+
+    fn lookup(&self, _ctx: Context, parent: u64, name: &CStr) -> io::Result<Entry> {
+        match parent {
+            ROOT_ID if name.to_bytes() == &*self.name => self.entry(FILE_INODE),
+            ROOT_ID => Err(errno(libc::ENOENT)),
+            FILE_INODE => Err(errno(libc::ENOTDIR)),
+            _ => Err(errno(libc::ENOENT)),
+        }
+    }
+
+    fn getattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: Option<u64>,
+    ) -> io::Result<(stat64, Duration)> {
+        Ok((self.attr(inode)?, TIMEOUT))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        attr: stat64,
+        _handle: Option<u64>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        self.check_writable()?;
+        self.check_file(inode)?;
+        if valid.intersects(SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID) {
+            return Err(errno(libc::EPERM));
+        }
+
+        if valid.contains(SetattrValid::SIZE) {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { bindings::ftruncate64(self.file.as_raw_fd(), attr.st_size) };
+            if res < 0 {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+        }
+
+        if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
+            let time = |set: SetattrValid, now: SetattrValid, sec, nsec| {
+                let mut ts = libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                };
+                if valid.contains(now) {
+                    ts.tv_nsec = libc::UTIME_NOW;
+                } else if valid.contains(set) {
+                    ts.tv_sec = sec;
+                    ts.tv_nsec = nsec;
+                }
+                ts
+            };
+            let times = [
+                time(
+                    SetattrValid::ATIME,
+                    SetattrValid::ATIME_NOW,
+                    attr.st_atime,
+                    attr.st_atime_nsec,
+                ),
+                time(
+                    SetattrValid::MTIME,
+                    SetattrValid::MTIME_NOW,
+                    attr.st_mtime,
+                    attr.st_mtime_nsec,
+                ),
+            ];
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::futimens(self.file.as_raw_fd(), times.as_ptr()) };
+            if res < 0 {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+        }
+
+        Ok((self.file_attr()?, TIMEOUT))
+    }
+
+    fn symlink(
+        &self,
+        _ctx: Context,
+        _linkname: &CStr,
+        _parent: u64,
+        _name: &CStr,
+        _extensions: Extensions,
+    ) -> io::Result<Entry> {
+        Err(self.no_entries())
+    }
+
+    fn mknod(
+        &self,
+        _ctx: Context,
+        _parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _rdev: u32,
+        _umask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<Entry> {
+        Err(self.no_entries())
+    }
+
+    fn mkdir(
+        &self,
+        _ctx: Context,
+        _parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _umask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<Entry> {
+        Err(self.no_entries())
+    }
+
+    fn unlink(&self, _ctx: Context, _parent: u64, _name: &CStr) -> io::Result<()> {
+        Err(self.no_entries())
+    }
+
+    fn rmdir(&self, _ctx: Context, _parent: u64, _name: &CStr) -> io::Result<()> {
+        Err(self.no_entries())
+    }
+
+    fn rename(
+        &self,
+        _ctx: Context,
+        _olddir: u64,
+        _oldname: &CStr,
+        _newdir: u64,
+        _newname: &CStr,
+        _flags: u32,
+    ) -> io::Result<()> {
+        Err(self.no_entries())
+    }
+
+    fn link(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _newparent: u64,
+        _newname: &CStr,
+    ) -> io::Result<Entry> {
+        Err(self.no_entries())
+    }
+
+    fn open(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _kill_priv: bool,
+        flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        self.check_file(inode)?;
+        let flags = flags as i32;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & bindings::LINUX_O_TRUNC != 0 {
+            self.check_writable()?;
+        }
+        if flags & bindings::LINUX_O_TRUNC != 0 {
+            self.file.set_len(0).map_err(linux_error)?;
+        }
+        Ok((None, OpenOptions::empty()))
+    }
+
+    fn create(
+        &self,
+        _ctx: Context,
+        _parent: u64,
+        _name: &CStr,
+        _mode: u32,
+        _kill_priv: bool,
+        _flags: u32,
+        _umask: u32,
+        _extensions: Extensions,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        Err(self.no_entries())
+    }
+
+    fn read(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        self.check_file(inode)?;
+        w.write_from(&self.file, size as usize, offset)
+            .map_err(linux_error)
+    }
+
+    fn write(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _kill_priv: bool,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        self.check_writable()?;
+        self.check_file(inode)?;
+        r.read_to(&self.file, size as usize, offset)
+            .map_err(linux_error)
+    }
+
+    fn flush(&self, _ctx: Context, _inode: u64, _handle: u64, _lock_owner: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn fsync(&self, _ctx: Context, inode: u64, datasync: bool, _handle: u64) -> io::Result<()> {
+        self.check_file(inode)?;
+        if datasync {
+            self.file.sync_data().map_err(linux_error)
+        } else {
+            self.file.sync_all().map_err(linux_error)
+        }
+    }
+
+    fn release(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _flags: u32,
+        _handle: u64,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn statfs(&self, _ctx: Context, _inode: u64) -> io::Result<statvfs64> {
+        let mut out = MaybeUninit::<statvfs64>::zeroed();
+        // Safe because this will only modify `out` and we check the return value.
+        let res = unsafe { bindings::fstatvfs64(self.file.as_raw_fd(), out.as_mut_ptr()) };
+        if res < 0 {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+        // Safe because the kernel guarantees that `out` has been initialized.
+        Ok(unsafe { out.assume_init() })
+    }
+
+    fn opendir(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        match inode {
+            ROOT_ID => Ok((None, OpenOptions::CACHE_DIR)),
+            FILE_INODE => Err(errno(libc::ENOTDIR)),
+            _ => Err(errno(libc::ENOENT)),
+        }
+    }
+
+    fn readdir(
+        &self,
+        ctx: Context,
+        inode: u64,
+        _handle: u64,
+        _size: u32,
+        offset: u64,
+    ) -> io::Result<Vec<DirEntry<'_>>> {
+        self.opendir(ctx, inode, 0)?;
+        Ok(self.dir_entries(offset))
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        size: u32,
+        offset: u64,
+    ) -> io::Result<Vec<(DirEntry<'_>, Entry)>> {
+        self.readdir(ctx, inode, handle, size, offset)?
+            .into_iter()
+            .map(|dir_entry| {
+                let entry = self.entry(dir_entry.ino)?;
+                Ok((dir_entry, entry))
+            })
+            .collect()
+    }
+
+    fn fsyncdir(
+        &self,
+        _ctx: Context,
+        _inode: u64,
+        _datasync: bool,
+        _handle: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn releasedir(&self, _ctx: Context, _inode: u64, _flags: u32, _handle: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn access(&self, _ctx: Context, inode: u64, mask: u32) -> io::Result<()> {
+        self.attr(inode)?;
+        if mask as i32 & libc::W_OK != 0 {
+            if inode == ROOT_ID {
+                return Err(self.no_entries());
+            }
+            self.check_writable()?;
+        }
+        Ok(())
+    }
+
+    fn lseek(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        self.check_file(inode)?;
+        let whence = match whence as libc::c_int {
+            bindings::LINUX_SEEK_SET => libc::SEEK_SET,
+            bindings::LINUX_SEEK_CUR => libc::SEEK_CUR,
+            bindings::LINUX_SEEK_END => libc::SEEK_END,
+            bindings::LINUX_SEEK_DATA => libc::SEEK_DATA,
+            bindings::LINUX_SEEK_HOLE => libc::SEEK_HOLE,
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            bindings::lseek64(self.file.as_raw_fd(), offset as bindings::off64_t, whence)
+        };
+        if res < 0 {
+            Err(linux_error(io::Error::last_os_error()))
+        } else {
+            Ok(res as u64)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn setupmapping(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        host_shm_base: u64,
+        shm_size: u64,
+    ) -> io::Result<()> {
+        self.check_file(inode)?;
+        let prot_flags = if flags & SetupmappingFlags::WRITE.bits() != 0 {
+            self.check_writable()?;
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        if moffset.checked_add(len).is_none_or(|end| end > shm_size) {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let addr = host_shm_base + moffset;
+        // Safe because the window is in the shared memory region set aside for DAX, which the
+        // guest alone uses, and we check the return value.
+        let ret = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                len as usize,
+                prot_flags,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                self.file.as_raw_fd(),
+                foffset as libc::off_t,
+            )
+        };
+        if std::ptr::eq(ret, libc::MAP_FAILED) {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn removemapping(
+        &self,
+        _ctx: Context,
+        requests: Vec<RemovemappingOne>,
+        host_shm_base: u64,
+        shm_size: u64,
+    ) -> io::Result<()> {
+        for req in requests {
+            if req
+                .moffset
+                .checked_add(req.len)
+                .is_none_or(|end| end > shm_size)
+            {
+                return Err(errno(libc::EINVAL));
+            }
+            let addr = host_shm_base + req.moffset;
+            // Safe because the window is in the shared memory region set aside for DAX and we
+            // check the return value.
+            let ret = unsafe {
+                libc::mmap(
+                    addr as *mut libc::c_void,
+                    req.len as usize,
+                    libc::PROT_NONE,
+                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            };
+            if std::ptr::eq(ret, libc::MAP_FAILED) {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setupmapping(
+        &self,
+        _ctx: Context,
+        inode: u64,
+        _handle: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        guest_shm_base: u64,
+        shm_size: u64,
+        map_sender: &Option<Sender<WorkerMessage>>,
+    ) -> io::Result<()> {
+        let sender = map_sender.as_ref().ok_or_else(|| errno(libc::ENOSYS))?;
+        self.check_file(inode)?;
+        let prot_flags = if flags & SetupmappingFlags::WRITE.bits() != 0 {
+            self.check_writable()?;
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        if moffset.checked_add(len).is_none_or(|end| end > shm_size) {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let guest_addr = guest_shm_base + moffset;
+        // Safe because we let the kernel pick the address and check the return value.
+        let host_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                prot_flags,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                foffset as libc::off_t,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+
+        let (reply_sender, reply_receiver) = unbounded();
+        sender
+            .send(WorkerMessage::GpuAddMapping(
+                reply_sender,
+                host_addr as u64,
+                guest_addr,
+                len,
+            ))
+            .map_err(|_| errno(libc::EIO))?;
+        if !reply_receiver.recv().unwrap_or(false) {
+            error!("Error requesting HVF the addition of a DAX window");
+            // Safe because nothing else uses the mapping we just made.
+            unsafe { libc::munmap(host_addr, len as usize) };
+            return Err(errno(libc::EINVAL));
+        }
+
+        self.map_windows
+            .lock_unpoisoned()
+            .insert(guest_addr, host_addr as u64);
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn removemapping(
+        &self,
+        _ctx: Context,
+        requests: Vec<RemovemappingOne>,
+        guest_shm_base: u64,
+        shm_size: u64,
+        map_sender: &Option<Sender<WorkerMessage>>,
+    ) -> io::Result<()> {
+        let sender = map_sender.as_ref().ok_or_else(|| errno(libc::ENOSYS))?;
+        for req in requests {
+            if req
+                .moffset
+                .checked_add(req.len)
+                .is_none_or(|end| end > shm_size)
+            {
+                return Err(errno(libc::EINVAL));
+            }
+            let guest_addr = guest_shm_base + req.moffset;
+            let host_addr = self
+                .map_windows
+                .lock_unpoisoned()
+                .remove(&guest_addr)
+                .ok_or_else(|| errno(libc::EINVAL))?;
+
+            let (reply_sender, reply_receiver) = unbounded();
+            sender
+                .send(WorkerMessage::GpuRemoveMapping(
+                    reply_sender,
+                    guest_addr,
+                    req.len,
+                ))
+                .map_err(|_| errno(libc::EIO))?;
+            if !reply_receiver.recv().unwrap_or(false) {
+                error!("Error requesting HVF the removal of a DAX window");
+                return Err(errno(libc::EINVAL));
+            }
+
+            // Safe because the guest no longer sees the mapping.
+            let ret = unsafe { libc::munmap(host_addr as *mut libc::c_void, req.len as usize) };
+            if ret == -1 {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::os::unix::fs::FileExt;
+
+    struct Buf(Vec<u8>);
+
+    impl ZeroCopyWriter for Buf {
+        fn write_from(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+            let mut buf = vec![0; count];
+            let n = f.read_at(&mut buf, off)?;
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+    }
+
+    impl io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyReader for Buf {
+        fn read_to(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+            let count = count.min(self.0.len());
+            f.write_at(&self.0[..count], off)
+        }
+    }
+
+    impl io::Read for Buf {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            io::Read::read(&mut &self.0[..], buf)
+        }
+    }
+
+    fn ctx() -> Context {
+        Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    fn share(name: &str, writable: bool) -> (SingleFileFs, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("krun-fs-single-{name}-{}", std::process::id()));
+        let data: Vec<u8> = (0..=255).cycle().take(10000).collect();
+        std::fs::write(&path, data).unwrap();
+        let fs = SingleFileFs::new(&path, "weights.bin", writable).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        (fs, path)
+    }
+
+    fn errno_of<T>(res: io::Result<T>) -> Option<i32> {
+        res.err().and_then(|e| e.raw_os_error())
+    }
+
+    #[test]
+    fn lookup() {
+        let (fs, path) = share("lookup", false);
+        let name = CString::new("weights.bin").unwrap();
+        let entry = fs.lookup(ctx(), ROOT_ID, &name).unwrap();
+        assert_eq!(entry.inode, FILE_INODE);
+        assert_eq!(entry.attr.st_size, 10000);
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(entry.attr.st_mode & 0o222, 0);
+
+        for other in ["other", "weights", "weights.bin2"] {
+            let other = CString::new(other).unwrap();
+            assert_eq!(
+                errno_of(fs.lookup(ctx(), ROOT_ID, &other)),
+                Some(libc::ENOENT)
+            );
+        }
+        assert_eq!(
+            errno_of(fs.lookup(ctx(), FILE_INODE, &name)),
+            Some(libc::ENOTDIR)
+        );
+
+        let names: Vec<_> = fs
+            .readdir(ctx(), ROOT_ID, 0, 4096, 0)
+            .unwrap()
+            .iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, [&b"."[..], b"..", b"weights.bin"]);
+        assert_eq!(fs.readdir(ctx(), ROOT_ID, 0, 4096, 2).unwrap().len(), 1);
+        assert!(fs.readdir(ctx(), ROOT_ID, 0, 4096, 3).unwrap().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads() {
+        let (fs, path) = share("reads", false);
+        let expected: Vec<u8> = (0..=255).cycle().take(10000).collect();
+        fs.open(ctx(), FILE_INODE, false, libc::O_RDONLY as u32)
+            .unwrap();
+        for (offset, size) in [(0, 100), (4095, 2), (9990, 100), (10000, 10), (20000, 10)] {
+            let mut buf = Buf(Vec::new());
+            let n = fs
+                .read(ctx(), FILE_INODE, 0, &mut buf, size, offset, None, 0)
+                .unwrap();
+            let start = (offset as usize).min(expected.len());
+            let end = (start + size as usize).min(expected.len());
+            assert_eq!(n, end - start);
+            assert_eq!(buf.0, &expected[start..end], "{offset} {size}");
+        }
+        assert_eq!(
+            errno_of(fs.read(ctx(), ROOT_ID, 0, &mut Buf(Vec::new()), 10, 0, None, 0)),
+            Some(libc::EISDIR)
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_only_unless_writable() {
+        let (fs, path) = share("ro", false);
+        assert_eq!(
+            errno_of(fs.open(ctx(), FILE_INODE, false, libc::O_RDWR as u32)),
+            Some(libc::EROFS)
+        );
+        let res = fs.write(
+            ctx(),
+            FILE_INODE,
+            0,
+            &mut Buf(b"x".to_vec()),
+            1,
+            0,
+            None,
+            false,
+            false,
+            0,
+        );
+        assert_eq!(errno_of(res), Some(libc::EROFS));
+        std::fs::remove_file(path).unwrap();
+
+        let (fs, path) = share("rw", true);
+        fs.open(ctx(), FILE_INODE, false, libc::O_RDWR as u32)
+            .unwrap();
+        let n = fs
+            .write(
+                ctx(),
+                FILE_INODE,
+                0,
+                &mut Buf(b"tail".to_vec()),
+                4,
+                10000,
+                None,
+                false,
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(n, 4);
+        let (attr, _) = fs.getattr(ctx(), FILE_INODE, None).unwrap();
+        assert_eq!(attr.st_size, 10004);
+        assert_eq!(std::fs::read(&path).unwrap()[10000..], *b"tail");
+
+        let mut attr = attr;
+        attr.st_size = 10;
+        let (attr, _) = fs
+            .setattr(ctx(), FILE_INODE, attr, None, SetattrValid::SIZE)
+            .unwrap();
+        assert_eq!(attr.st_size, 10);
+
+        // The directory itself still can't change.
+        let name = CString::new("new").unwrap();
+        assert_eq!(
+            errno_of(fs.unlink(ctx(), ROOT_ID, &name)),
+            Some(libc::EPERM)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -4,108 +4,390 @@ use crossbeam_channel::Sender;
 use utils::worker_message::WorkerMessage;
 
 use std::os::fd::AsRawFd;
-use std::sync::atomic::AtomicI32;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crossbeam_channel::{unbounded, Receiver};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
-use utils::eventfd::EventFd;
-use vm_memory::GuestMemoryMmap;
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use utils::sandbox::ThreadCategory;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
 
-use super::super::{FsError, Queue};
-use super::defs::{HPQ_INDEX, REQ_INDEX};
+use super::super::{DescriptorChain, FsError, Queue};
 use super::descriptor_utils::{Reader, Writer};
 use super::filesystem::FileSystem;
-use super::server::Server;
-use crate::virtio::{InterruptTransport, VirtioShmRegion};
+use super::fuse::{InHeader, Opcode};
+use super::server::{reply_eio, Server};
+use crate::virtio::{InterruptTransport, SharedExitStatus, VirtioShmRegion};
 
+// Parked blocking lock requests are retried whenever a request may have released a lock. Locks
+// held by host processes are released without the worker hearing of it, so they're also retried
+// this often.
+const HOST_LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Threads each worker runs slow requests on.
+const POOL_THREADS: usize = 4;
+// Slow requests a worker hands to its pool at most. Past that, they're handled inline again, so
+// that a guest flooding the queue with reads can't make the worker take the whole ring.
+const MAX_POOLED_REQUESTS: usize = 4 * POOL_THREADS;
+
+// Requests that may keep the host busy for a long time, and run on the worker's pool so that the
+// requests queued behind them don't have to wait. Guests don't expect replies in order: only INIT
+// and DESTROY have to be ordered against everything else, and a FORGET is only sent once the
+// guest has no requests left for the inode.
+fn runs_long(opcode: u32) -> bool {
+    [
+        Opcode::Read,
+        Opcode::Write,
+        Opcode::CopyFileRange,
+        Opcode::Open,
+    ]
+    .iter()
+    .any(|&op| op as u32 == opcode)
+}
+
+fn is_barrier(opcode: u32) -> bool {
+    opcode == Opcode::Init as u32 || opcode == Opcode::Destroy as u32
+}
+
+// A request handed to the pool, which finds it again in the descriptor table.
+struct PoolJob {
+    queue_index: usize,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    head_index: u16,
+}
+
+// A request the pool is done with. `done` is false if it has been deferred, as `dispatch` returns.
+struct PoolCompletion {
+    queue_index: usize,
+    head_index: u16,
+    done: bool,
+}
+
+// What handling a request takes, shared by the worker and its pool threads.
+struct Handler<F: FileSystem + Sync + 'static> {
+    server: Arc<Server<F>>,
+    mem: GuestMemoryMmap,
+    shm_region: Option<VirtioShmRegion>,
+    exit_status: Arc<SharedExitStatus>,
+    #[cfg(target_os = "macos")]
+    map_sender: Option<Sender<WorkerMessage>>,
+}
+
+impl<F: FileSystem + Sync + 'static> Clone for Handler<F> {
+    fn clone(&self) -> Self {
+        Handler {
+            server: self.server.clone(),
+            mem: self.mem.clone(),
+            shm_region: self.shm_region.clone(),
+            exit_status: self.exit_status.clone(),
+            #[cfg(target_os = "macos")]
+            map_sender: self.map_sender.clone(),
+        }
+    }
+}
+
+impl<F: FileSystem + Sync + 'static> Handler<F> {
+    /// Handles a single request. Returns false if the request has been deferred and its descriptor
+    /// chain must not be returned to the guest yet.
+    ///
+    /// A request the filesystem panics on is answered with EIO, so the guest doesn't wait for it
+    /// forever and the thread handling it keeps going.
+    fn dispatch(&self, head: DescriptorChain) -> bool {
+        let writer = match Writer::new(&self.mem, head.clone()) {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!("dropping request: {:?}", FsError::QueueWriter(e));
+                return true;
+            }
+        };
+        let reader = match Reader::new(&self.mem, head.clone()) {
+            Ok(reader) => reader,
+            Err(e) => {
+                error!("error handling message: {:?}", FsError::QueueReader(e));
+                if let Err(e) = reply_eio(None, writer) {
+                    error!("error replying to message: {e:?}");
+                }
+                return true;
+            }
+        };
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.server.handle_message(
+                reader,
+                writer,
+                &self.shm_region,
+                &self.exit_status,
+                #[cfg(target_os = "macos")]
+                &self.map_sender,
+            )
+        }));
+        match res {
+            Ok(Err(FsError::LockWouldBlock)) => return false,
+            Ok(Err(e)) => error!("error handling message: {e:?}"),
+            Ok(Ok(_)) => {}
+            Err(_) => {
+                error!("fs request handler panicked");
+                let reader = Reader::new(&self.mem, head.clone()).ok();
+                if let Ok(writer) = Writer::new(&self.mem, head) {
+                    if let Err(e) = reply_eio(reader, writer) {
+                        error!("error replying to message: {e:?}");
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    // Returns the opcode of the request in `head`, if it has a header at all.
+    fn opcode(&self, head: &DescriptorChain) -> Option<u32> {
+        let mut reader = Reader::new(&self.mem, head.clone()).ok()?;
+        reader.read_obj::<InHeader>().ok().map(|h| h.opcode)
+    }
+}
+
+// The threads a worker runs slow requests on, and the way back for their completions.
+struct Pool {
+    jobs: Option<crossbeam_channel::Sender<PoolJob>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    completions: Receiver<PoolCompletion>,
+    completion_evt: EventFd,
+    in_flight: usize,
+}
+
+impl Pool {
+    fn new<F: FileSystem + Sync + Send + 'static>(
+        id: usize,
+        handler: &Handler<F>,
+    ) -> std::io::Result<Self> {
+        let (jobs, job_receiver) = unbounded::<PoolJob>();
+        let (completion_sender, completions) = unbounded();
+        let completion_evt = EventFd::new(EFD_NONBLOCK)?;
+
+        let mut threads = Vec::new();
+        for i in 0..POOL_THREADS {
+            let handler = handler.clone();
+            let jobs = job_receiver.clone();
+            let completion_sender = completion_sender.clone();
+            let completion_evt = completion_evt.try_clone()?;
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("fs worker {id} pool {i}"))
+                    .spawn(move || {
+                        for job in jobs {
+                            let done = match DescriptorChain::checked_new(
+                                &handler.mem,
+                                job.desc_table,
+                                job.queue_size,
+                                job.head_index,
+                            ) {
+                                Some(head) => handler.dispatch(head),
+                                None => {
+                                    error!("dropping invalid descriptor chain: {}", job.head_index);
+                                    true
+                                }
+                            };
+                            let _ = completion_sender.send(PoolCompletion {
+                                queue_index: job.queue_index,
+                                head_index: job.head_index,
+                                done,
+                            });
+                            let _ = completion_evt.write(1);
+                        }
+                    })?,
+            );
+        }
+
+        Ok(Pool {
+            jobs: Some(jobs),
+            threads,
+            completions,
+            completion_evt,
+            in_flight: 0,
+        })
+    }
+
+    // Lets the threads finish the requests they have and waits for them to exit.
+    fn shutdown(&mut self) {
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            if let Err(e) = thread.join() {
+                error!("error waiting for pool thread: {e:?}");
+            }
+        }
+    }
+}
+
+/// Services a subset of the device's queues. A device with several request queues runs one worker
+/// per queue, all sharing the same `Server`.
+///
+/// Slow requests run on a small pool of threads and are returned to the guest as they finish, so
+/// the used ring isn't in the order the requests were made.
 pub struct FsWorker<F: FileSystem + Sync + 'static> {
     queues: Vec<Queue>,
     queue_evts: Vec<Arc<EventFd>>,
     interrupt: InterruptTransport,
-    mem: GuestMemoryMmap,
-    shm_region: Option<VirtioShmRegion>,
-    server: Server<F>,
+    handler: Handler<F>,
     stop_fd: EventFd,
-    exit_code: Arc<AtomicI32>,
-    #[cfg(target_os = "macos")]
-    map_sender: Option<Sender<WorkerMessage>>,
+    // Blocking lock requests that could not be granted yet, as (queue index, head index) pairs.
+    parked: Vec<(usize, u16)>,
+    // When the parked requests were last retried.
+    parked_retried: Instant,
+    // Started by the worker thread, once it has confined itself.
+    pool: Option<Pool>,
+    // The directory the worker confines itself to with Landlock, if any.
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    landlock_root: Option<PathBuf>,
 }
 
 impl<F: FileSystem + Sync + Send + 'static> FsWorker<F> {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        fs: F,
+        server: Arc<Server<F>>,
         queues: Vec<Queue>,
         queue_evts: Vec<Arc<EventFd>>,
         interrupt: InterruptTransport,
         mem: GuestMemoryMmap,
         shm_region: Option<VirtioShmRegion>,
         stop_fd: EventFd,
-        exit_code: Arc<AtomicI32>,
+        exit_status: Arc<SharedExitStatus>,
         #[cfg(target_os = "macos")] map_sender: Option<Sender<WorkerMessage>>,
+        #[cfg(all(target_os = "linux", feature = "landlock"))] landlock_root: Option<PathBuf>,
     ) -> Self {
         Self {
             queues,
             queue_evts,
             interrupt,
-            mem,
-            shm_region,
-            server: Server::new(fs),
+            handler: Handler {
+                server,
+                mem,
+                shm_region,
+                exit_status,
+                #[cfg(target_os = "macos")]
+                map_sender,
+            },
             stop_fd,
-            exit_code,
-            #[cfg(target_os = "macos")]
-            map_sender,
+            parked: Vec::new(),
+            parked_retried: Instant::now(),
+            pool: None,
+            #[cfg(all(target_os = "linux", feature = "landlock"))]
+            landlock_root,
         }
     }
 
-    pub fn run(self) -> thread::JoinHandle<()> {
+    pub fn run(self, id: usize) -> thread::JoinHandle<()> {
         thread::Builder::new()
-            .name("fs worker".into())
-            .spawn(|| self.work())
+            .name(format!("fs worker {id}"))
+            .spawn(move || self.work(id))
             .unwrap()
     }
 
-    fn work(mut self) {
-        let virtq_hpq_ev_fd = self.queue_evts[HPQ_INDEX].as_raw_fd();
-        let virtq_req_ev_fd = self.queue_evts[REQ_INDEX].as_raw_fd();
+    fn work(mut self, id: usize) {
+        let queue_ev_fds: Vec<_> = self.queue_evts.iter().map(|e| e.as_raw_fd()).collect();
         let stop_ev_fd = self.stop_fd.as_raw_fd();
 
         let epoll = Epoll::new().unwrap();
 
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_hpq_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_hpq_ev_fd as u64),
-        );
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_req_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_req_ev_fd as u64),
-        );
+        for &fd in &queue_ev_fds {
+            let _ = epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                &EpollEvent::new(EventSet::IN, fd as u64),
+            );
+        }
         let _ = epoll.ctl(
             ControlOperation::Add,
             stop_ev_fd,
             &EpollEvent::new(EventSet::IN, stop_ev_fd as u64),
         );
 
+        #[cfg(all(target_os = "linux", feature = "landlock"))]
+        if let Some(root) = &self.landlock_root {
+            if let Err(e) = utils::landlock::restrict_self(&[root]) {
+                error!(
+                    "fs: failed to confine the worker to {}: {e}",
+                    root.display()
+                );
+            }
+        }
+        utils::sandbox::enter(ThreadCategory::Fs);
+
+        // The pool threads inherit the confinement of the worker.
+        match Pool::new(id, &self.handler) {
+            Ok(pool) => {
+                let fd = pool.completion_evt.as_raw_fd();
+                let _ = epoll.ctl(
+                    ControlOperation::Add,
+                    fd,
+                    &EpollEvent::new(EventSet::IN, fd as u64),
+                );
+                self.pool = Some(pool);
+            }
+            Err(e) => {
+                error!("fs: failed to start the worker pool, handling all requests inline: {e}")
+            }
+        }
+        let completion_ev_fd = self.pool.as_ref().map(|p| p.completion_evt.as_raw_fd());
+
+        let lock_release_evt = match self.handler.server.lock_release_evt() {
+            Ok(evt) => {
+                let fd = evt.as_raw_fd();
+                let _ = epoll.ctl(
+                    ControlOperation::Add,
+                    fd,
+                    &EpollEvent::new(EventSet::IN, fd as u64),
+                );
+                Some(evt)
+            }
+            Err(e) => {
+                error!("fs: failed to watch for released locks: {e}");
+                None
+            }
+        };
+        let lock_release_ev_fd = lock_release_evt.as_ref().map(|e| e.as_raw_fd());
+
         loop {
+            let timeout = if self.parked.is_empty() {
+                -1
+            } else {
+                let retry_in =
+                    HOST_LOCK_RETRY_INTERVAL.saturating_sub(self.parked_retried.elapsed());
+                retry_in.as_millis() as i32
+            };
+
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
-            match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
+            match epoll.wait(epoll_events.len(), timeout, epoll_events.as_mut_slice()) {
                 Ok(ev_cnt) => {
                     for event in &epoll_events[0..ev_cnt] {
                         let source = event.fd();
                         let event_set = event.event_set();
-                        match event_set {
-                            EventSet::IN if source == virtq_hpq_ev_fd => {
-                                self.handle_event(HPQ_INDEX);
+                        let queue_index = queue_ev_fds.iter().position(|&fd| fd == source);
+                        match (event_set, queue_index) {
+                            (EventSet::IN, Some(queue_index)) => {
+                                self.handle_event(queue_index);
+                            }
+                            (EventSet::IN, None) if Some(source) == completion_ev_fd => {
+                                self.handle_completions();
                             }
-                            EventSet::IN if source == virtq_req_ev_fd => {
-                                self.handle_event(REQ_INDEX);
+                            (EventSet::IN, None) if Some(source) == lock_release_ev_fd => {
+                                if let Some(evt) = &lock_release_evt {
+                                    let _ = evt.read();
+                                }
+                                self.retry_parked();
                             }
-                            EventSet::IN if source == stop_ev_fd => {
+                            (EventSet::IN, None) if source == stop_ev_fd => {
+                                // The stop event is shared by all the workers of the device, so
+                                // it is left for the device to consume once they have all exited.
                                 debug!("stopping worker thread");
-                                let _ = self.stop_fd.read();
+                                if let Some(pool) = &mut self.pool {
+                                    pool.shutdown();
+                                }
                                 return;
                             }
                             _ => {
@@ -120,6 +402,11 @@ impl<F: FileSystem + Sync + Send + 'static> FsWorker<F> {
                     debug!("failed to consume muxer epoll event: {e}");
                 }
             }
+
+            if !self.parked.is_empty() && self.parked_retried.elapsed() >= HOST_LOCK_RETRY_INTERVAL
+            {
+                self.retry_parked();
+            }
         }
     }
 
@@ -131,13 +418,13 @@ impl<F: FileSystem + Sync + Send + 'static> FsWorker<F> {
 
         loop {
             self.queues[queue_index]
-                .disable_notification(&self.mem)
+                .disable_notification(&self.handler.mem)
                 .unwrap();
 
             self.process_queue(queue_index);
 
             if !self.queues[queue_index]
-                .enable_notification(&self.mem)
+                .enable_notification(&self.handler.mem)
                 .unwrap()
             {
                 break;
@@ -146,33 +433,206 @@ impl<F: FileSystem + Sync + Send + 'static> FsWorker<F> {
     }
 
     fn process_queue(&mut self, queue_index: usize) {
-        let queue = &mut self.queues[queue_index];
-        while let Some(head) = queue.pop(&self.mem) {
-            let reader = Reader::new(&self.mem, head.clone())
-                .map_err(FsError::QueueReader)
-                .unwrap();
-            let writer = Writer::new(&self.mem, head.clone())
-                .map_err(FsError::QueueWriter)
-                .unwrap();
+        let mem = self.handler.mem.clone();
+        while let Some(head) = self.queues[queue_index].pop(&mem) {
+            let head_index = head.index;
+            let opcode = self.handler.opcode(&head).unwrap_or_default();
+            if runs_long(opcode) && self.send_to_pool(queue_index, head_index) {
+                continue;
+            }
+            if is_barrier(opcode) {
+                self.wait_for_pool();
+            }
 
-            if let Err(e) = self.server.handle_message(
-                reader,
-                writer,
-                &self.shm_region,
-                &self.exit_code,
-                #[cfg(target_os = "macos")]
-                &self.map_sender,
-            ) {
-                error!("error handling message: {e:?}");
+            if self.handler.dispatch(head) {
+                self.complete(queue_index, head_index);
+            } else {
+                self.parked.push((queue_index, head_index));
             }
+        }
+    }
 
-            if let Err(e) = queue.add_used(&self.mem, head.index, 0) {
-                error!("failed to add used elements to the queue: {e:?}");
+    // Hands a request to the pool, unless there is none or it has enough to do already.
+    fn send_to_pool(&mut self, queue_index: usize, head_index: u16) -> bool {
+        let Some(pool) = &mut self.pool else {
+            return false;
+        };
+        if pool.in_flight >= MAX_POOLED_REQUESTS {
+            return false;
+        }
+        let queue = &self.queues[queue_index];
+        let job = PoolJob {
+            queue_index,
+            desc_table: queue.desc_table,
+            queue_size: queue.actual_size(),
+            head_index,
+        };
+        match pool.jobs.as_ref().map(|jobs| jobs.send(job)) {
+            Some(Ok(())) => {
+                pool.in_flight += 1;
+                true
             }
+            _ => false,
+        }
+    }
+
+    fn handle_completions(&mut self) {
+        let Some(pool) = &mut self.pool else {
+            return;
+        };
+        let _ = pool.completion_evt.read();
+        let completions: Vec<_> = pool.completions.try_iter().collect();
+        pool.in_flight -= completions.len();
+        for completion in completions {
+            self.finish(completion);
+        }
+    }
 
-            if queue.needs_notification(&self.mem).unwrap() {
-                self.interrupt.signal_used_queue();
+    // Waits for the pool to be done with all the requests handed to it.
+    fn wait_for_pool(&mut self) {
+        while let Some(pool) = self.pool.as_mut().filter(|p| p.in_flight > 0) {
+            let Ok(completion) = pool.completions.recv() else {
+                return;
+            };
+            pool.in_flight -= 1;
+            self.finish(completion);
+        }
+    }
+
+    fn finish(&mut self, completion: PoolCompletion) {
+        if completion.done {
+            self.complete(completion.queue_index, completion.head_index);
+        } else {
+            self.parked
+                .push((completion.queue_index, completion.head_index));
+        }
+    }
+
+    fn retry_parked(&mut self) {
+        self.parked_retried = Instant::now();
+        for (queue_index, head_index) in std::mem::take(&mut self.parked) {
+            let queue = &self.queues[queue_index];
+            let Some(head) = DescriptorChain::checked_new(
+                &self.handler.mem,
+                queue.desc_table,
+                queue.actual_size(),
+                head_index,
+            ) else {
+                error!("dropping invalid parked descriptor chain: {head_index}");
+                continue;
+            };
+
+            if self.handler.dispatch(head) {
+                self.complete(queue_index, head_index);
+            } else {
+                self.parked.push((queue_index, head_index));
             }
         }
     }
+
+    fn complete(&mut self, queue_index: usize, head_index: u16) {
+        let queue = &mut self.queues[queue_index];
+        if let Err(e) = queue.add_used(&self.handler.mem, head_index, 0) {
+            error!("failed to add used elements to the queue: {e:?}");
+        }
+
+        if queue.needs_notification(&self.handler.mem).unwrap() {
+            self.interrupt.signal_used_queue();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::mem::size_of;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    use vm_memory::{ByteValued, Bytes};
+
+    use super::*;
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use crate::virtio::fs::bindings;
+    use crate::virtio::fs::filesystem::Context;
+    use crate::virtio::fs::fuse::{GetattrIn, OutHeader};
+
+    const REQUEST_ADDR: u64 = 0x1000;
+
+    // Panics on every getattr, like a backend with a bug in it.
+    struct PanickingGetattr;
+
+    impl FileSystem for PanickingGetattr {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: Context,
+            _inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(bindings::stat64, Duration)> {
+            panic!("getattr");
+        }
+    }
+
+    // Dispatches a request for the root inode and returns the header of its reply.
+    fn send<F: FileSystem + Sync>(handler: &Handler<F>, opcode: Opcode, unique: u64) -> OutHeader {
+        let body = GetattrIn::default();
+        let len = (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32;
+        let header = InHeader {
+            len,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            ..Default::default()
+        };
+        let mem = &handler.mem;
+        let reply_addr = GuestAddress(REQUEST_ADDR + len as u64);
+        mem.write_obj(header, GuestAddress(REQUEST_ADDR)).unwrap();
+        mem.write_slice(
+            body.as_slice(),
+            GuestAddress(REQUEST_ADDR + size_of::<InHeader>() as u64),
+        )
+        .unwrap();
+        mem.write_obj(OutHeader::default(), reply_addr).unwrap();
+
+        let chain = create_descriptor_chain(
+            mem,
+            GuestAddress(0),
+            GuestAddress(REQUEST_ADDR),
+            vec![
+                (DescriptorType::Readable, len),
+                (DescriptorType::Writable, 0x1000),
+            ],
+            0,
+        )
+        .unwrap();
+        assert!(handler.dispatch(chain));
+        mem.read_obj(reply_addr).unwrap()
+    }
+
+    #[test]
+    fn panicking_requests_get_eio() {
+        let handler = Handler {
+            server: Arc::new(Server::new(
+                PanickingGetattr,
+                Arc::new(AtomicBool::new(false)),
+            )),
+            mem: GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+            shm_region: None,
+            exit_status: Arc::new(SharedExitStatus::new()),
+            #[cfg(target_os = "macos")]
+            map_sender: None,
+        };
+
+        let reply = send(&handler, Opcode::Getattr, 7);
+        assert_eq!(reply.unique, 7);
+        assert_eq!(reply.error, -libc::EIO);
+
+        // The next request is handled as usual.
+        let reply = send(&handler, Opcode::Statfs, 8);
+        assert_eq!(reply.unique, 8);
+        assert_eq!(reply.error, 0);
+    }
 }
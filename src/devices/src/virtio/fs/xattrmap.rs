@@ -0,0 +1,258 @@
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::io;
+
+/// A rule translating extended attribute names between the guest and the host.
+///
+/// Rules are tried in order and the first one whose prefix matches a name decides what happens to
+/// it. Names that no rule matches are left as they are, so an empty list changes nothing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum XattrRule {
+    /// Guest names starting with `guest` are stored on the host with that prefix replaced by
+    /// `host`, and host names starting with `host` are listed to the guest with it replaced by
+    /// `guest`. For example, `trusted.` to `user.virtiofs.trusted.` lets an unprivileged host
+    /// keep the guest's `trusted.*` attributes.
+    Prefix { guest: Vec<u8>, host: Vec<u8> },
+
+    /// Names starting with `prefix` are left as they are.
+    Pass { prefix: Vec<u8> },
+
+    /// Names starting with `prefix` can't be used by the guest, which gets `EPERM`, and host
+    /// attributes starting with it are left out of listings.
+    Deny { prefix: Vec<u8> },
+}
+
+impl XattrRule {
+    fn guest_prefix(&self) -> &[u8] {
+        match self {
+            XattrRule::Prefix { guest, .. } => guest,
+            XattrRule::Pass { prefix } | XattrRule::Deny { prefix } => prefix,
+        }
+    }
+
+    fn host_prefix(&self) -> &[u8] {
+        match self {
+            XattrRule::Prefix { host, .. } => host,
+            XattrRule::Pass { prefix } | XattrRule::Deny { prefix } => prefix,
+        }
+    }
+}
+
+/// Translates the attribute name the guest uses into the one stored on the host.
+pub fn to_host<'a>(rules: &[XattrRule], name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+    let bytes = name.to_bytes();
+    match rules.iter().find(|r| bytes.starts_with(r.guest_prefix())) {
+        Some(XattrRule::Prefix { guest, host }) => {
+            let mut host = host.clone();
+            host.extend_from_slice(&bytes[guest.len()..]);
+            CString::new(host)
+                .map(Cow::Owned)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+        }
+        Some(XattrRule::Deny { .. }) => Err(io::Error::from_raw_os_error(libc::EPERM)),
+        Some(XattrRule::Pass { .. }) | None => Ok(Cow::Borrowed(name)),
+    }
+}
+
+/// Translates an attribute name stored on the host into the one the guest sees, or `None` if the
+/// guest shouldn't see it.
+pub fn to_guest<'a>(rules: &[XattrRule], name: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+    match rules.iter().find(|r| name.starts_with(r.host_prefix())) {
+        Some(XattrRule::Prefix { guest, host }) => {
+            let mut guest = guest.clone();
+            guest.extend_from_slice(&name[host.len()..]);
+            Some(Cow::Owned(guest))
+        }
+        Some(XattrRule::Deny { .. }) => None,
+        Some(XattrRule::Pass { .. }) | None => Some(Cow::Borrowed(name)),
+    }
+}
+
+/// Translates a `listxattr` reply from the host, a list of NUL-terminated names, into the one the
+/// guest sees.
+pub fn to_guest_list(rules: &[XattrRule], list: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(list.len());
+    for name in list.split(|c| *c == 0).filter(|name| !name.is_empty()) {
+        if let Some(name) = to_guest(rules, name) {
+            out.extend_from_slice(&name);
+            out.push(0);
+        }
+    }
+    out
+}
+
+/// Parses a map in the format of virtiofsd's `--xattrmap` option.
+///
+/// Each rule is `:type:scope:key:prepend:`, where the first character is the separator used for
+/// the rest of the rule, and `type` is `prefix`, `ok` or `bad`. Only the `all` scope is
+/// supported. A final `:map:key:prepend:` rule stores the guest's `key*` attributes as
+/// `prepend key*` on the host, keeps the guest away from other `prepend*` attributes, and leaves
+/// everything else alone.
+pub fn parse_xattr_map(map: &str) -> io::Result<Vec<XattrRule>> {
+    let mut rules = Vec::new();
+    let mut rest = map.trim_start();
+    while let Some(sep) = rest.chars().next() {
+        rest = &rest[sep.len_utf8()..];
+        let kind = field(&mut rest, sep)?;
+
+        if kind == "map" {
+            let key = field(&mut rest, sep)?.as_bytes();
+            let prepend = field(&mut rest, sep)?.as_bytes();
+            if !rest.trim().is_empty() {
+                return Err(invalid("xattr map: `map` must be the last rule"));
+            }
+            rules.push(XattrRule::Prefix {
+                guest: key.to_vec(),
+                host: [prepend, key].concat(),
+            });
+            if key.is_empty() {
+                rules.push(XattrRule::Deny { prefix: Vec::new() });
+            } else {
+                rules.push(XattrRule::Deny {
+                    prefix: prepend.to_vec(),
+                });
+                rules.push(XattrRule::Pass { prefix: Vec::new() });
+            }
+            break;
+        }
+
+        let scope = field(&mut rest, sep)?;
+        let key = field(&mut rest, sep)?.as_bytes().to_vec();
+        let prepend = field(&mut rest, sep)?.as_bytes();
+        if scope != "all" {
+            return Err(invalid(format!(
+                "xattr map: unsupported scope `{scope}`, only `all` is"
+            )));
+        }
+        rules.push(match kind {
+            "prefix" => XattrRule::Prefix {
+                host: [prepend, &key].concat(),
+                guest: key,
+            },
+            "ok" => XattrRule::Pass { prefix: key },
+            "bad" => XattrRule::Deny { prefix: key },
+            _ => return Err(invalid(format!("xattr map: unknown rule type `{kind}`"))),
+        });
+        rest = rest.trim_start();
+    }
+    Ok(rules)
+}
+
+// Takes the next `sep`-terminated field off the front of `rest`.
+fn field<'a>(rest: &mut &'a str, sep: char) -> io::Result<&'a str> {
+    let (field, tail) = rest
+        .split_once(sep)
+        .ok_or_else(|| invalid("xattr map: rule ends early"))?;
+    *rest = tail;
+    Ok(field)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cstr(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn trusted() -> Vec<XattrRule> {
+        parse_xattr_map(":map:trusted.:user.virtiofs.:").unwrap()
+    }
+
+    #[test]
+    fn prefix() {
+        let rules = trusted();
+        assert_eq!(
+            to_host(&rules, &cstr("trusted.overlay.opaque"))
+                .unwrap()
+                .as_ref(),
+            cstr("user.virtiofs.trusted.overlay.opaque").as_c_str()
+        );
+        assert_eq!(
+            to_guest(&rules, b"user.virtiofs.trusted.overlay.opaque").unwrap(),
+            &b"trusted.overlay.opaque"[..]
+        );
+    }
+
+    #[test]
+    fn pass() {
+        let rules = trusted();
+        assert!(matches!(
+            to_host(&rules, &cstr("user.mime_type")).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            to_guest(&rules, b"user.mime_type").unwrap(),
+            &b"user.mime_type"[..]
+        );
+
+        // No rules leaves every name alone.
+        assert!(matches!(
+            to_host(&[], &cstr("trusted.x")).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(to_guest(&[], b"trusted.x").unwrap(), &b"trusted.x"[..]);
+    }
+
+    #[test]
+    fn deny() {
+        // The guest can't reach the host's copies of its attributes directly.
+        let rules = trusted();
+        let err = to_host(&rules, &cstr("user.virtiofs.trusted.x")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert_eq!(to_guest(&rules, b"user.virtiofs.other"), None);
+
+        let rules = parse_xattr_map(":bad:all:security.:: :ok:all:::").unwrap();
+        assert_eq!(
+            rules,
+            [
+                XattrRule::Deny {
+                    prefix: b"security.".to_vec()
+                },
+                XattrRule::Pass { prefix: Vec::new() },
+            ]
+        );
+        let err = to_host(&rules, &cstr("security.selinux")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert_eq!(to_guest(&rules, b"security.selinux"), None);
+    }
+
+    #[test]
+    fn list_round_trip() {
+        let rules = trusted();
+        let host = b"user.virtiofs.trusted.a\0user.virtiofs.junk\0user.b\0";
+        let guest = to_guest_list(&rules, host);
+        assert_eq!(guest, b"trusted.a\0user.b\0");
+
+        for name in guest.split(|c| *c == 0).filter(|name| !name.is_empty()) {
+            let name = CString::new(name).unwrap();
+            let back = to_host(&rules, &name).unwrap();
+            assert!(host.split(|c| *c == 0).any(|host| host == back.to_bytes()));
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in [
+            ":prefix:all:trusted.:user.",
+            ":prefix:client:trusted.:user.:",
+            ":rename:all:a:b:",
+            ":map:a:b: :ok:all:::",
+        ] {
+            assert!(parse_xattr_map(bad).is_err(), "{bad:?}");
+        }
+        assert_eq!(parse_xattr_map("").unwrap(), []);
+        // Any character can separate the fields.
+        assert_eq!(
+            parse_xattr_map("/prefix/all/trusted./user./").unwrap(),
+            [XattrRule::Prefix {
+                guest: b"trusted.".to_vec(),
+                host: b"user.trusted.".to_vec(),
+            }]
+        );
+    }
+}
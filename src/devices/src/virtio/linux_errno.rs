@@ -4,6 +4,7 @@ const LINUX_ESRCH: i32 = 3;
 const LINUX_EINTR: i32 = 4;
 const LINUX_EIO: i32 = 5;
 const LINUX_ENXIO: i32 = 6;
+const LINUX_E2BIG: i32 = 7;
 const LINUX_ENOEXEC: i32 = 8;
 const LINUX_EBADF: i32 = 9;
 const LINUX_ECHILD: i32 = 10;
@@ -30,6 +31,7 @@ const LINUX_EROFS: i32 = 30;
 const LINUX_EMLINK: i32 = 31;
 const LINUX_EPIPE: i32 = 32;
 const LINUX_EDOM: i32 = 33;
+const LINUX_ERANGE: i32 = 34;
 const LINUX_EDEADLK: i32 = 35;
 const LINUX_ENAMETOOLONG: i32 = 36;
 const LINUX_ENOLCK: i32 = 37;
@@ -84,13 +86,13 @@ const LINUX_ECANCELED: i32 = 125;
 const LINUX_EOWNERDEAD: i32 = 130;
 const LINUX_ENOTRECOVERABLE: i32 = 131;
 
-// Errors to be directly used.
-pub const LINUX_ERANGE: i32 = 34;
-
 pub fn linux_error(error: std::io::Error) -> std::io::Error {
     std::io::Error::from_raw_os_error(linux_errno_raw(error.raw_os_error().unwrap_or(libc::EIO)))
 }
 
+/// Translates a host errno to the number a Linux guest knows it by. Host errors Linux has no
+/// counterpart for are reported as the closest Linux error, and anything unknown as `EIO`, so
+/// that the guest never sees a number that means something else to it.
 pub fn linux_errno_raw(errno: i32) -> i32 {
     match errno {
         libc::EPERM => LINUX_EPERM,
@@ -99,6 +101,7 @@ pub fn linux_errno_raw(errno: i32) -> i32 {
         libc::EINTR => LINUX_EINTR,
         libc::EIO => LINUX_EIO,
         libc::ENXIO => LINUX_ENXIO,
+        libc::E2BIG => LINUX_E2BIG,
         libc::ENOEXEC => LINUX_ENOEXEC,
         libc::EBADF => LINUX_EBADF,
         libc::ECHILD => LINUX_ECHILD,
@@ -125,6 +128,7 @@ pub fn linux_errno_raw(errno: i32) -> i32 {
         libc::EMLINK => LINUX_EMLINK,
         libc::EPIPE => LINUX_EPIPE,
         libc::EDOM => LINUX_EDOM,
+        libc::ERANGE => LINUX_ERANGE,
         libc::EAGAIN => LINUX_EAGAIN,
         libc::EINPROGRESS => LINUX_EINPROGRESS,
         libc::EALREADY => LINUX_EALREADY,
@@ -169,6 +173,26 @@ pub fn linux_errno_raw(errno: i32) -> i32 {
         libc::EILSEQ => LINUX_EILSEQ,
         #[cfg(target_os = "macos")]
         libc::ENOATTR => LINUX_ENODATA,
+        // Linux has a single number for both.
+        #[cfg(target_os = "macos")]
+        libc::ENOTSUP => LINUX_EOPNOTSUPP,
+        #[cfg(target_os = "macos")]
+        libc::EFTYPE => LINUX_EINVAL,
+        #[cfg(target_os = "macos")]
+        libc::EAUTH | libc::ENEEDAUTH => LINUX_EACCES,
+        // Sun RPC failures of network filesystems.
+        #[cfg(target_os = "macos")]
+        libc::EBADRPC
+        | libc::ERPCMISMATCH
+        | libc::EPROGUNAVAIL
+        | libc::EPROGMISMATCH
+        | libc::EPROCUNAVAIL => LINUX_EPROTO,
+        #[cfg(target_os = "macos")]
+        libc::EPROCLIM => LINUX_EAGAIN,
+        #[cfg(target_os = "macos")]
+        libc::EBADEXEC | libc::EBADARCH | libc::ESHLIBVERS | libc::EBADMACHO => LINUX_ENOEXEC,
+        #[cfg(target_os = "macos")]
+        libc::EQFULL => LINUX_ENOBUFS,
         libc::EBADMSG => LINUX_EBADMSG,
         libc::EMULTIHOP => LINUX_EMULTIHOP,
         libc::ENODATA => LINUX_ENODATA,
@@ -183,3 +207,157 @@ pub fn linux_errno_raw(errno: i32) -> i32 {
         _ => LINUX_EIO,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Errors that go by the same name on every host.
+    const COMMON: &[(i32, i32)] = &[
+        (libc::EPERM, LINUX_EPERM),
+        (libc::ENOENT, LINUX_ENOENT),
+        (libc::ESRCH, LINUX_ESRCH),
+        (libc::EINTR, LINUX_EINTR),
+        (libc::EIO, LINUX_EIO),
+        (libc::ENXIO, LINUX_ENXIO),
+        (libc::E2BIG, LINUX_E2BIG),
+        (libc::ENOEXEC, LINUX_ENOEXEC),
+        (libc::EBADF, LINUX_EBADF),
+        (libc::ECHILD, LINUX_ECHILD),
+        (libc::EDEADLK, LINUX_EDEADLK),
+        (libc::ENOMEM, LINUX_ENOMEM),
+        (libc::EACCES, LINUX_EACCES),
+        (libc::EFAULT, LINUX_EFAULT),
+        (libc::ENOTBLK, LINUX_ENOTBLK),
+        (libc::EBUSY, LINUX_EBUSY),
+        (libc::EEXIST, LINUX_EEXIST),
+        (libc::EXDEV, LINUX_EXDEV),
+        (libc::ENODEV, LINUX_ENODEV),
+        (libc::ENOTDIR, LINUX_ENOTDIR),
+        (libc::EISDIR, LINUX_EISDIR),
+        (libc::EINVAL, LINUX_EINVAL),
+        (libc::ENFILE, LINUX_ENFILE),
+        (libc::EMFILE, LINUX_EMFILE),
+        (libc::ENOTTY, LINUX_ENOTTY),
+        (libc::ETXTBSY, LINUX_ETXTBSY),
+        (libc::EFBIG, LINUX_EFBIG),
+        (libc::ENOSPC, LINUX_ENOSPC),
+        (libc::ESPIPE, LINUX_ESPIPE),
+        (libc::EROFS, LINUX_EROFS),
+        (libc::EMLINK, LINUX_EMLINK),
+        (libc::EPIPE, LINUX_EPIPE),
+        (libc::EDOM, LINUX_EDOM),
+        (libc::ERANGE, LINUX_ERANGE),
+        (libc::EAGAIN, LINUX_EAGAIN),
+        (libc::EWOULDBLOCK, LINUX_EAGAIN),
+        (libc::EINPROGRESS, LINUX_EINPROGRESS),
+        (libc::EALREADY, LINUX_EALREADY),
+        (libc::ENOTSOCK, LINUX_ENOTSOCK),
+        (libc::EDESTADDRREQ, LINUX_EDESTADDRREQ),
+        (libc::EMSGSIZE, LINUX_EMSGSIZE),
+        (libc::EPROTOTYPE, LINUX_EPROTOTYPE),
+        (libc::ENOPROTOOPT, LINUX_ENOPROTOOPT),
+        (libc::EPROTONOSUPPORT, LINUX_EPROTONOSUPPORT),
+        (libc::ESOCKTNOSUPPORT, LINUX_ESOCKTNOSUPPORT),
+        (libc::EOPNOTSUPP, LINUX_EOPNOTSUPP),
+        (libc::ENOTSUP, LINUX_EOPNOTSUPP),
+        (libc::EPFNOSUPPORT, LINUX_EPFNOSUPPORT),
+        (libc::EAFNOSUPPORT, LINUX_EAFNOSUPPORT),
+        (libc::EADDRINUSE, LINUX_EADDRINUSE),
+        (libc::EADDRNOTAVAIL, LINUX_EADDRNOTAVAIL),
+        (libc::ENETDOWN, LINUX_ENETDOWN),
+        (libc::ENETUNREACH, LINUX_ENETUNREACH),
+        (libc::ENETRESET, LINUX_ENETRESET),
+        (libc::ECONNABORTED, LINUX_ECONNABORTED),
+        (libc::ECONNRESET, LINUX_ECONNRESET),
+        (libc::ENOBUFS, LINUX_ENOBUFS),
+        (libc::EISCONN, LINUX_EISCONN),
+        (libc::ENOTCONN, LINUX_ENOTCONN),
+        (libc::ESHUTDOWN, LINUX_ESHUTDOWN),
+        (libc::ETOOMANYREFS, LINUX_ETOOMANYREFS),
+        (libc::ETIMEDOUT, LINUX_ETIMEDOUT),
+        (libc::ECONNREFUSED, LINUX_ECONNREFUSED),
+        (libc::ELOOP, LINUX_ELOOP),
+        (libc::ENAMETOOLONG, LINUX_ENAMETOOLONG),
+        (libc::EHOSTDOWN, LINUX_EHOSTDOWN),
+        (libc::EHOSTUNREACH, LINUX_EHOSTUNREACH),
+        (libc::ENOTEMPTY, LINUX_ENOTEMPTY),
+        (libc::EUSERS, LINUX_EUSERS),
+        (libc::EDQUOT, LINUX_EDQUOT),
+        (libc::ESTALE, LINUX_ESTALE),
+        (libc::EREMOTE, LINUX_EREMOTE),
+        (libc::ENOLCK, LINUX_ENOLCK),
+        (libc::ENOSYS, LINUX_ENOSYS),
+        (libc::EOVERFLOW, LINUX_EOVERFLOW),
+        (libc::ECANCELED, LINUX_ECANCELED),
+        (libc::EIDRM, LINUX_EIDRM),
+        (libc::ENOMSG, LINUX_ENOMSG),
+        (libc::EILSEQ, LINUX_EILSEQ),
+        (libc::EBADMSG, LINUX_EBADMSG),
+        (libc::EMULTIHOP, LINUX_EMULTIHOP),
+        (libc::ENODATA, LINUX_ENODATA),
+        (libc::ENOLINK, LINUX_ENOLINK),
+        (libc::ENOSR, LINUX_ENOSR),
+        (libc::ENOSTR, LINUX_ENOSTR),
+        (libc::EPROTO, LINUX_EPROTO),
+        (libc::ETIME, LINUX_ETIME),
+        (libc::ENOTRECOVERABLE, LINUX_ENOTRECOVERABLE),
+        (libc::EOWNERDEAD, LINUX_EOWNERDEAD),
+    ];
+
+    // Errors only macOS has, with the Linux errors they stand in for.
+    #[cfg(target_os = "macos")]
+    const MACOS: &[(i32, i32)] = &[
+        (libc::ENOATTR, LINUX_ENODATA),
+        (libc::EFTYPE, LINUX_EINVAL),
+        (libc::EAUTH, LINUX_EACCES),
+        (libc::ENEEDAUTH, LINUX_EACCES),
+        (libc::EBADRPC, LINUX_EPROTO),
+        (libc::ERPCMISMATCH, LINUX_EPROTO),
+        (libc::EPROGUNAVAIL, LINUX_EPROTO),
+        (libc::EPROGMISMATCH, LINUX_EPROTO),
+        (libc::EPROCUNAVAIL, LINUX_EPROTO),
+        (libc::EPROCLIM, LINUX_EAGAIN),
+        (libc::EBADEXEC, LINUX_ENOEXEC),
+        (libc::EBADARCH, LINUX_ENOEXEC),
+        (libc::ESHLIBVERS, LINUX_ENOEXEC),
+        (libc::EBADMACHO, LINUX_ENOEXEC),
+        (libc::EQFULL, LINUX_ENOBUFS),
+        (libc::EPWROFF, LINUX_EIO),
+        (libc::EDEVERR, LINUX_EIO),
+    ];
+
+    #[test]
+    fn translation_table() {
+        #[cfg(target_os = "macos")]
+        let table = COMMON.iter().chain(MACOS);
+        #[cfg(not(target_os = "macos"))]
+        let table = COMMON.iter();
+        for &(host, linux) in table {
+            assert_eq!(linux_errno_raw(host), linux, "host errno {host}");
+            assert_eq!(
+                linux_error(std::io::Error::from_raw_os_error(host)).raw_os_error(),
+                Some(linux),
+                "host errno {host}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_errors_are_eio() {
+        assert_eq!(linux_errno_raw(0), LINUX_EIO);
+        assert_eq!(linux_errno_raw(-1), LINUX_EIO);
+        assert_eq!(linux_errno_raw(10_000), LINUX_EIO);
+        // Errors that don't come from the OS have no number to translate.
+        let err = std::io::Error::other("not an OS error");
+        assert_eq!(linux_error(err).raw_os_error(), Some(LINUX_EIO));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn identity_on_linux() {
+        for &(host, linux) in COMMON {
+            assert_eq!(host, linux);
+        }
+    }
+}
@@ -315,9 +315,19 @@ impl MmioTransport {
         for dq in &mut device_queues {
             dq.queue.set_event_idx(event_idx_enabled);
         }
-        locked_device
-            .activate(self.mem.clone(), self.interrupt.clone(), device_queues)
-            .expect("Failed to activate device");
+        if let Err(e) =
+            locked_device.activate(self.mem.clone(), self.interrupt.clone(), device_queues)
+        {
+            // The guest drives activation, so don't let it take the VMM down. It can reset the
+            // device and try again.
+            error!(
+                "{}: failed to activate device: {:?}",
+                locked_device.device_name(),
+                e
+            );
+            drop(locked_device);
+            self.device_status |= device_status::FAILED;
+        }
     }
 
     /// Update device status according to the state machine defined by VirtIO Spec 1.0.
@@ -358,8 +368,9 @@ impl MmioTransport {
                 }
 
                 // If the backend device driver doesn't support reset,
-                // just leave the device marked as FAILED.
-                if self.device_status & FAILED == 0 {
+                // just leave the device marked as FAILED. A device that
+                // never got activated has nothing to reset.
+                if self.device_status & FAILED == 0 || !self.locked_device().is_activated() {
                     self.reset();
                 }
             }
@@ -1022,6 +1033,87 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_bus_device_activate_failure() {
+        struct BrokenDevice(DummyDevice);
+
+        impl VirtioDevice for BrokenDevice {
+            fn device_type(&self) -> u32 {
+                self.0.device_type()
+            }
+
+            fn device_name(&self) -> &str {
+                "broken"
+            }
+
+            fn read_config(&self, offset: u64, data: &mut [u8]) {
+                self.0.read_config(offset, data)
+            }
+
+            fn write_config(&mut self, offset: u64, data: &[u8]) {
+                self.0.write_config(offset, data)
+            }
+
+            fn avail_features(&self) -> u64 {
+                self.0.avail_features()
+            }
+
+            fn acked_features(&self) -> u64 {
+                self.0.acked_features()
+            }
+
+            fn set_acked_features(&mut self, acked_features: u64) {
+                self.0.set_acked_features(acked_features)
+            }
+
+            fn queue_config(&self) -> &[QueueConfig] {
+                self.0.queue_config()
+            }
+
+            fn activate(
+                &mut self,
+                _mem: GuestMemoryMmap,
+                _interrupt: InterruptTransport,
+                _queues: Vec<DeviceQueue>,
+            ) -> ActivateResult {
+                Err(ActivateError::BadActivate)
+            }
+
+            fn is_activated(&self) -> bool {
+                false
+            }
+        }
+
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioTransport::new(
+            m,
+            DummyIrqChip::new().into(),
+            Arc::new(Mutex::new(BrokenDevice(DummyDevice::new()))),
+        )
+        .unwrap();
+
+        set_device_status(&mut d, device_status::ACKNOWLEDGE);
+        set_device_status(&mut d, device_status::ACKNOWLEDGE | device_status::DRIVER);
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK,
+        );
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE
+                | device_status::DRIVER
+                | device_status::FEATURES_OK
+                | device_status::DRIVER_OK,
+        );
+        assert_ne!(d.device_status & device_status::FAILED, 0);
+        assert!(!d.locked_device().is_activated());
+
+        // The guest can reset the device and start over.
+        set_device_status(&mut d, 0);
+        assert_eq!(d.device_status, device_status::INIT);
+        assert!(d.queues.is_some());
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
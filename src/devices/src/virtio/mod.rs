@@ -20,6 +20,7 @@ pub mod block;
 pub mod console;
 pub mod descriptor_utils;
 pub mod device;
+pub mod exit_status;
 pub mod file_traits;
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
 pub mod fs;
@@ -32,18 +33,22 @@ mod mmio;
 #[cfg(feature = "net")]
 pub mod net;
 mod queue;
+#[cfg(any(feature = "blk", feature = "net"))]
+pub mod rate_limiter;
 #[cfg(not(feature = "tee"))]
 pub mod rng;
 #[cfg(feature = "snd")]
 pub mod snd;
+pub mod timeline;
 pub mod vsock;
 
 #[cfg(not(feature = "tee"))]
 pub use self::balloon::*;
 #[cfg(feature = "blk")]
-pub use self::block::{Block, CacheType};
+pub use self::block::{Block, BlockMetrics, BlockMetricsSnapshot, BlockSlot, CacheType};
 pub use self::console::*;
 pub use self::device::*;
+pub use self::exit_status::{ExitStatus, SharedExitStatus};
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
 pub use self::fs::*;
 #[cfg(feature = "gpu")]
@@ -52,10 +57,13 @@ pub use self::mmio::*;
 #[cfg(feature = "net")]
 pub use self::net::Net;
 pub use self::queue::{Descriptor, DescriptorChain, Queue};
+#[cfg(any(feature = "blk", feature = "net"))]
+pub use self::rate_limiter::{RateLimit, RateLimiter};
 #[cfg(not(feature = "tee"))]
 pub use self::rng::*;
 #[cfg(feature = "snd")]
 pub use self::snd::Snd;
+pub use self::timeline::{BootStage, BootTimeline};
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
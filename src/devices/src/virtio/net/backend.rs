@@ -13,6 +13,8 @@ pub enum ConnectError {
     TunSetIff(io::Error),
     TunSetVnetHdrSz(io::Error),
     TunSetOffload(io::Error),
+    /// The backend can't open another handle for an extra queue pair.
+    CloneBackend(io::Error),
 }
 
 #[allow(dead_code)]
@@ -43,4 +45,21 @@ pub trait NetBackend {
     fn has_unfinished_write(&self) -> bool;
     fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError>;
     fn raw_socket_fd(&self) -> RawFd;
+
+    /// Opens another handle to the same network, for an extra queue pair.
+    ///
+    /// Each queue pair of a multiqueue device gets its own handle, used by its own worker
+    /// thread, so a handle is never shared between threads. Every handle must read and write
+    /// whole frames on its own. Backends that can't do that, like a byte stream of
+    /// length-prefixed frames, keep this default and are limited to a single queue pair.
+    fn try_clone(&self) -> io::Result<Box<dyn NetBackend + Send>> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Called with `false` when the guest stops using the queue pair of this handle, and with
+    /// `true` when it starts again. A disabled handle isn't read from, so a backend that spreads
+    /// incoming frames over its handles should stop handing this one any.
+    fn set_enabled(&mut self, _enabled: bool) -> io::Result<()> {
+        Ok(())
+    }
 }
@@ -4,9 +4,10 @@
 // Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
-use crate::virtio::net::Result;
-use crate::virtio::net::{NUM_QUEUES, QUEUE_CONFIG};
+use crate::virtio::net::{Error, Result};
+use crate::virtio::net::{CTRL_QUEUE_SIZE, MAX_QUEUE_PAIRS, QUEUE_CONFIG, QUEUE_SIZE};
 use crate::virtio::queue::Error as QueueError;
+use crate::virtio::rate_limiter::{RateLimit, RateLimiter, Throttle};
 use crate::virtio::{
     ActivateError, ActivateResult, DeviceQueue, DeviceState, InterruptTransport, QueueConfig,
     VirtioDevice, TYPE_NET,
@@ -14,13 +15,15 @@ use crate::virtio::{
 use crate::Error as DeviceError;
 
 use super::backend::{NetBackend, ReadError, WriteError};
-use super::worker::NetWorker;
+use super::metrics::NetMetrics;
+use super::worker::{connect, NetWorker, QueuePairs};
 
 use std::cmp;
 use std::io::Write;
 use std::os::fd::RawFd;
 use std::path::PathBuf;
-use virtio_bindings::virtio_net::VIRTIO_NET_F_MAC;
+use std::sync::Arc;
+use virtio_bindings::virtio_net::{VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ};
 use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestMemoryError, GuestMemoryMmap};
 
@@ -79,25 +82,52 @@ pub struct Net {
     pub(crate) device_state: DeviceState,
 
     config: VirtioNetConfig,
+    queue_pairs: u16,
+    queue_config: Vec<QueueConfig>,
+    metrics: Arc<NetMetrics>,
+    // One for each direction, with the same limit.
+    rx_rate_limiter: Arc<RateLimiter>,
+    tx_rate_limiter: Arc<RateLimiter>,
 }
 
 impl Net {
-    /// Create a new virtio network device using the backend
+    /// Create a new virtio network device using the backend, offering the guest `queue_pairs`
+    /// pairs of receive and transmit queues.
+    ///
+    /// With more than one pair, every pair is served by its own worker thread with its own
+    /// handle to the backend, opened with `NetBackend::try_clone`.
     pub fn new(
         id: String,
         cfg_backend: VirtioNetBackend,
         mac: [u8; 6],
         features: u32,
+        queue_pairs: u16,
     ) -> Result<Self> {
-        let avail_features = features as u64
+        let single_queue = matches!(
+            cfg_backend,
+            VirtioNetBackend::UnixstreamFd(_) | VirtioNetBackend::UnixstreamPath(_)
+        );
+        if queue_pairs == 0 || queue_pairs > MAX_QUEUE_PAIRS || (single_queue && queue_pairs > 1) {
+            return Err(Error::QueuePairs(queue_pairs));
+        }
+
+        let mut avail_features = features as u64
             | (1 << VIRTIO_NET_F_MAC)
             | (1 << VIRTIO_RING_F_EVENT_IDX)
             | (1 << VIRTIO_F_VERSION_1);
+        let queue_config = if queue_pairs > 1 {
+            avail_features |= (1 << VIRTIO_NET_F_CTRL_VQ) | (1 << VIRTIO_NET_F_MQ);
+            let mut queue_config = vec![QueueConfig::new(QUEUE_SIZE); 2 * queue_pairs as usize];
+            queue_config.push(QueueConfig::new(CTRL_QUEUE_SIZE));
+            queue_config
+        } else {
+            QUEUE_CONFIG.to_vec()
+        };
 
         let config = VirtioNetConfig {
             mac,
             status: 0,
-            max_virtqueue_pairs: 0,
+            max_virtqueue_pairs: queue_pairs,
         };
 
         Ok(Net {
@@ -109,6 +139,11 @@ impl Net {
 
             device_state: DeviceState::Inactive,
             config,
+            queue_pairs,
+            queue_config,
+            metrics: Arc::new(NetMetrics::new()),
+            rx_rate_limiter: Arc::new(RateLimiter::default()),
+            tx_rate_limiter: Arc::new(RateLimiter::default()),
         })
     }
 
@@ -116,6 +151,24 @@ impl Net {
     pub fn id(&self) -> &str {
         &self.id
     }
+
+    /// The frame counters of this net device, summed over all its queue pairs.
+    pub fn metrics(&self) -> Arc<NetMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The ceiling on the frames and bytes per second going each way, over all queue pairs.
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rx_rate_limiter.limit()
+    }
+
+    /// Caps the rate of frames going each way, taking effect right away if the device is
+    /// running. Received frames over the limit wait in the backend, and transmitted ones on the
+    /// queue.
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.rx_rate_limiter.set_limit(limit);
+        self.tx_rate_limiter.set_limit(limit);
+    }
 }
 
 impl VirtioDevice for Net {
@@ -140,7 +193,7 @@ impl VirtioDevice for Net {
     }
 
     fn queue_config(&self) -> &[QueueConfig] {
-        &QUEUE_CONFIG
+        &self.queue_config
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
@@ -171,40 +224,171 @@ impl VirtioDevice for Net {
         interrupt: InterruptTransport,
         queues: Vec<DeviceQueue>,
     ) -> ActivateResult {
-        let [rx_q, tx_q]: [_; NUM_QUEUES] = queues.try_into().map_err(|_| {
-            error!("Cannot perform activate. Expected {} queue(s)", NUM_QUEUES);
-            ActivateError::BadActivate
-        })?;
+        if queues.len() != self.queue_config.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s)",
+                self.queue_config.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
 
         let cfg_backend = self.cfg_backend.take().ok_or_else(|| {
             error!("Cannot activate net device: backend already taken");
             ActivateError::BadActivate
         })?;
 
-        match NetWorker::new(
-            rx_q,
-            tx_q,
-            interrupt.clone(),
-            mem.clone(),
-            self.acked_features,
-            cfg_backend,
-        ) {
-            Ok(worker) => {
-                worker.run();
-                self.device_state = DeviceState::Activated(mem, interrupt);
-                Ok(())
-            }
-            Err(err) => {
-                error!(
-                    "Error activating virtio-net ({}) backend: {err:?}",
-                    self.id()
-                );
-                Err(ActivateError::BadActivate)
+        // Without multiqueue, the guest only uses the first pair, and the control queue comes
+        // right after it.
+        let pairs = if self.acked_features & (1 << VIRTIO_NET_F_MQ) != 0 {
+            self.queue_pairs as usize
+        } else {
+            1
+        };
+        let mut queues: Vec<_> = queues.into_iter().map(Some).collect();
+        let mut ctrl_q = if self.acked_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            queues.get_mut(2 * pairs).and_then(Option::take)
+        } else {
+            None
+        };
+
+        let backends = connect(cfg_backend, self.acked_features, pairs).map_err(|err| {
+            error!(
+                "Error activating virtio-net ({}) backend: {err:?}",
+                self.id()
+            );
+            ActivateError::BadActivate
+        })?;
+        let queue_pairs = Arc::new(QueuePairs::new(pairs).map_err(|e| {
+            error!("Cannot create virtio-net queue pair events: {e:?}");
+            ActivateError::BadActivate
+        })?);
+
+        let mut queues = queues.into_iter();
+        for (index, backend) in backends.into_iter().enumerate() {
+            let (Some(Some(rx_q)), Some(Some(tx_q))) = (queues.next(), queues.next()) else {
+                return Err(ActivateError::BadActivate);
+            };
+            let throttle = |limiter: &Arc<RateLimiter>| {
+                Throttle::new(Arc::clone(limiter)).map_err(|e| {
+                    error!("Cannot create virtio-net rate limiter timer: {e:?}");
+                    ActivateError::BadActivate
+                })
+            };
+            let mut worker = NetWorker::new(
+                index,
+                rx_q,
+                tx_q,
+                interrupt.clone(),
+                mem.clone(),
+                self.acked_features,
+                backend,
+                Arc::clone(&queue_pairs),
+                Arc::clone(&self.metrics),
+                throttle(&self.rx_rate_limiter)?,
+                throttle(&self.tx_rate_limiter)?,
+            );
+            if index == 0 {
+                worker.set_ctrl_queue(ctrl_q.take());
             }
+            worker.run();
         }
+        self.device_state = DeviceState::Activated(mem, interrupt);
+        Ok(())
     }
 
     fn is_activated(&self) -> bool {
         self.device_state.is_activated()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use virtio_bindings::virtio_net::{
+        VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4,
+        VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6,
+        VIRTIO_NET_F_HOST_UFO,
+    };
+
+    use crate::virtio::net::Offloads;
+
+    fn net(features: u32, queue_pairs: u16) -> Result<Net> {
+        Net::new(
+            "eth0".into(),
+            VirtioNetBackend::UnixgramFd(-1),
+            [0; 6],
+            features,
+            queue_pairs,
+        )
+    }
+
+    #[test]
+    fn offloads_set_features() {
+        let offload_bits = [
+            VIRTIO_NET_F_CSUM,
+            VIRTIO_NET_F_GUEST_CSUM,
+            VIRTIO_NET_F_HOST_TSO4,
+            VIRTIO_NET_F_GUEST_TSO4,
+            VIRTIO_NET_F_HOST_TSO6,
+            VIRTIO_NET_F_GUEST_TSO6,
+            VIRTIO_NET_F_HOST_UFO,
+        ];
+        let offered = |offloads: Offloads| {
+            let features = net(offloads.features(), 1).unwrap().avail_features();
+            offload_bits
+                .iter()
+                .copied()
+                .filter(|bit| features & (1 << bit) != 0)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(offered(Offloads::default()).is_empty());
+        // Segmentation needs checksum offload.
+        let tso = Offloads {
+            tso4: true,
+            tso6: true,
+            ufo: true,
+            ..Default::default()
+        };
+        assert!(offered(tso).is_empty());
+        assert_eq!(
+            offered(Offloads {
+                csum: true,
+                tso4: true,
+                ..Default::default()
+            }),
+            [
+                VIRTIO_NET_F_CSUM,
+                VIRTIO_NET_F_GUEST_CSUM,
+                VIRTIO_NET_F_HOST_TSO4,
+                VIRTIO_NET_F_GUEST_TSO4
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_pairs_set_features() {
+        let single = net(0, 1).unwrap();
+        assert_eq!(single.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_eq!(single.queue_config().len(), 2);
+
+        let multi = net(0, 4).unwrap();
+        assert_ne!(multi.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(multi.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        assert_eq!(multi.queue_config().len(), 9);
+        let mut pairs = [0u8; 2];
+        multi.read_config(8, &mut pairs);
+        assert_eq!(u16::from_le_bytes(pairs), 4);
+
+        assert!(net(0, 0).is_err());
+        assert!(Net::new(
+            "eth0".into(),
+            VirtioNetBackend::UnixstreamFd(-1),
+            [0; 6],
+            0,
+            2
+        )
+        .is_err());
+    }
+}
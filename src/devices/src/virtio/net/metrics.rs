@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Frame counters of a network device, shared by the workers of all its queue pairs. Every
+/// counter is a relaxed atomic, so they can be read from any thread while the workers keep going.
+#[derive(Debug, Default)]
+pub struct NetMetrics {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_dropped: AtomicU64,
+}
+
+/// The values of a `NetMetrics` at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetMetricsSnapshot {
+    /// Frames handed to the guest.
+    pub rx_packets: u64,
+    /// Bytes of the frames handed to the guest, without the virtio-net header.
+    pub rx_bytes: u64,
+    /// Frames from the backend that didn't fit in the guest's buffers.
+    pub rx_dropped: u64,
+    /// Frames the backend took from the guest.
+    pub tx_packets: u64,
+    /// Bytes of the frames the backend took, without the virtio-net header.
+    pub tx_bytes: u64,
+    /// Frames from the guest that were too large or that the backend refused.
+    pub tx_dropped: u64,
+}
+
+impl NetMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> NetMetricsSnapshot {
+        NetMetricsSnapshot {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_dropped: self.tx_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rx_dropped(&self) {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn tx_dropped(&self) {
+        self.tx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -2,17 +2,27 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{io, mem, result};
-use virtio_bindings::virtio_net::virtio_net_hdr_v1;
+use virtio_bindings::virtio_net::{
+    virtio_net_hdr_v1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
+    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO,
+};
 
 use super::QueueConfig;
 
 pub const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 1024;
+const CTRL_QUEUE_SIZE: u16 = 64;
+/// The MTU the guest uses, as the device doesn't offer `VIRTIO_NET_F_MTU` to set another.
+pub const DEFAULT_MTU: u16 = 1500;
 pub const NUM_QUEUES: usize = 2;
 pub static QUEUE_CONFIG: [QueueConfig; NUM_QUEUES] = [QueueConfig::new(QUEUE_SIZE); NUM_QUEUES];
+/// The most queue pairs a device can have, as set by the virtio spec.
+pub const MAX_QUEUE_PAIRS: u16 = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16;
 
 pub mod backend;
 pub mod device;
+pub mod metrics;
 #[cfg(target_os = "linux")]
 mod tap;
 pub mod unixgram;
@@ -31,11 +41,49 @@ fn write_virtio_net_hdr(buf: &mut [u8]) -> usize {
     len
 }
 
+/// Offloads a network device offers the guest, for frames going both ways. With checksum
+/// offload, frames may carry a partial checksum that the other side completes; with
+/// segmentation offloads, they may be far larger than the MTU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Offloads {
+    /// TCP/UDP checksum offload.
+    pub csum: bool,
+    /// TCP segmentation offload over IPv4.
+    pub tso4: bool,
+    /// TCP segmentation offload over IPv6.
+    pub tso6: bool,
+    /// UDP fragmentation offload.
+    pub ufo: bool,
+}
+
+impl Offloads {
+    /// The virtio-net feature bits offering these offloads. Segmentation needs checksum
+    /// offload, so none is offered without it.
+    pub fn features(&self) -> u32 {
+        if !self.csum {
+            return 0;
+        }
+        let mut features = (1 << VIRTIO_NET_F_CSUM) | (1 << VIRTIO_NET_F_GUEST_CSUM);
+        if self.tso4 {
+            features |= (1 << VIRTIO_NET_F_HOST_TSO4) | (1 << VIRTIO_NET_F_GUEST_TSO4);
+        }
+        if self.tso6 {
+            features |= (1 << VIRTIO_NET_F_HOST_TSO6) | (1 << VIRTIO_NET_F_GUEST_TSO6);
+        }
+        if self.ufo {
+            features |= (1 << VIRTIO_NET_F_HOST_UFO) | (1 << VIRTIO_NET_F_GUEST_UFO);
+        }
+        features
+    }
+}
+
 pub use self::device::Net;
 #[derive(Debug)]
 pub enum Error {
     /// EventFd error.
     EventFd(io::Error),
+    /// The number of queue pairs is out of range, or the backend can only use one.
+    QueuePairs(u16),
 }
 
 pub type Result<T> = result::Result<T, Error>;
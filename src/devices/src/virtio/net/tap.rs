@@ -1,6 +1,6 @@
 use libc::{
-    c_char, c_int, ifreq, IFF_NO_PI, IFF_TAP, IFF_VNET_HDR, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6,
-    TUN_F_UFO,
+    c_char, c_int, ifreq, IFF_ATTACH_QUEUE, IFF_DETACH_QUEUE, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TAP,
+    IFF_VNET_HDR, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_UFO,
 };
 use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
 use nix::sys::stat::Mode;
@@ -14,18 +14,29 @@ use virtio_bindings::virtio_net::{
 };
 
 use super::backend::{ConnectError, NetBackend, ReadError, WriteError};
+use super::vnet_hdr_len;
 
 ioctl_write_ptr!(tunsetiff, b'T', 202, c_int);
 ioctl_write_int!(tunsetoffload, b'T', 208);
 ioctl_write_ptr!(tunsetvnethdrsz, b'T', 216, c_int);
+ioctl_write_ptr!(tunsetqueue, b'T', 217, c_int);
 
 pub struct Tap {
     fd: OwnedFd,
+    tap_name: String,
+    vnet_features: u64,
+    multi_queue: bool,
 }
 
 impl Tap {
-    /// Create an endpoint using the file descriptor of a tap device
-    pub fn new(tap_name: String, vnet_features: u64) -> Result<Self, ConnectError> {
+    /// Create an endpoint using the file descriptor of a tap device. With `multi_queue`, more
+    /// queues of the same device can be opened with `try_clone`, and the kernel spreads incoming
+    /// frames over them.
+    pub fn new(
+        tap_name: String,
+        vnet_features: u64,
+        multi_queue: bool,
+    ) -> Result<Self, ConnectError> {
         let fd = match open("/dev/net/tun", OFlag::O_RDWR, Mode::empty()) {
             Ok(fd) => fd,
             Err(err) => return Err(ConnectError::OpenNetTun(err)),
@@ -41,7 +52,11 @@ impl Tap {
             );
         }
 
-        req.ifr_ifru.ifru_flags = IFF_TAP as i16 | IFF_NO_PI as i16 | IFF_VNET_HDR as i16;
+        let mut flags = IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+        if multi_queue {
+            flags |= IFF_MULTI_QUEUE;
+        }
+        req.ifr_ifru.ifru_flags = flags as i16;
 
         let mut offload_flags: u64 = 0;
         if (vnet_features & (1 << VIRTIO_NET_F_GUEST_CSUM)) != 0 {
@@ -62,8 +77,7 @@ impl Tap {
                 return Err(ConnectError::TunSetIff(io::Error::from(err)));
             }
 
-            // TODO(slp): replace hardcoded vnet size with cons
-            if let Err(err) = tunsetvnethdrsz(fd.as_raw_fd(), &12) {
+            if let Err(err) = tunsetvnethdrsz(fd.as_raw_fd(), &(vnet_hdr_len() as c_int)) {
                 return Err(ConnectError::TunSetVnetHdrSz(io::Error::from(err)));
             }
 
@@ -84,7 +98,12 @@ impl Tap {
             Err(e) => error!("couldn't obtain fd flags id={fd:?}, err={e}"),
         };
 
-        Ok(Self { fd })
+        Ok(Self {
+            fd,
+            tap_name,
+            vnet_features,
+            multi_queue,
+        })
     }
 }
 
@@ -125,4 +144,26 @@ impl NetBackend for Tap {
     fn raw_socket_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
+
+    fn try_clone(&self) -> io::Result<Box<dyn NetBackend + Send>> {
+        if !self.multi_queue {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        let tap = Tap::new(self.tap_name.clone(), self.vnet_features, true)
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
+        Ok(Box::new(tap))
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> io::Result<()> {
+        let mut req: ifreq = unsafe { mem::zeroed() };
+        req.ifr_ifru.ifru_flags = if enabled {
+            IFF_ATTACH_QUEUE as i16
+        } else {
+            IFF_DETACH_QUEUE as i16
+        };
+        // Safe because the kernel only reads the flags of `req`.
+        unsafe { tunsetqueue(self.fd.as_raw_fd(), &mut req as *mut _ as *mut _) }
+            .map_err(io::Error::from)?;
+        Ok(())
+    }
 }
@@ -4,6 +4,7 @@ use nix::sys::socket::{
     SockFlag, SockType, UnixAddr,
 };
 use nix::unistd::unlink;
+use std::io;
 use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 use std::path::PathBuf;
 
@@ -136,4 +137,12 @@ impl NetBackend for Unixgram {
     fn raw_socket_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
+
+    /// Every handle shares the same socket, so each datagram is read by whichever worker gets to
+    /// it first.
+    fn try_clone(&self) -> io::Result<Box<dyn NetBackend + Send>> {
+        Ok(Box::new(Unixgram {
+            fd: self.fd.try_clone()?,
+        }))
+    }
 }
@@ -1,28 +1,130 @@
+use crate::virtio::descriptor_utils::{Reader, Writer};
 use crate::virtio::net::backend::ConnectError;
 #[cfg(target_os = "linux")]
 use crate::virtio::net::tap::Tap;
 use crate::virtio::net::unixgram::Unixgram;
 use crate::virtio::net::unixstream::Unixstream;
 use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE};
+use crate::virtio::rate_limiter::Throttle;
 use crate::virtio::{DeviceQueue, InterruptTransport};
 
 use super::backend::{NetBackend, ReadError, WriteError};
 use super::device::{FrontendError, RxError, TxError, VirtioNetBackend};
+use super::metrics::NetMetrics;
 use super::vnet_hdr_len;
 
+use std::io;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::{cmp, result};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use utils::sandbox::ThreadCategory;
+use virtio_bindings::virtio_net::{
+    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM,
+    VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_OK,
+};
 use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 
+/// The queue pairs of a device, and how many of them the guest uses, shared by their workers.
+pub(crate) struct QueuePairs {
+    active: AtomicUsize,
+    // One per worker, written when `active` changes.
+    events: Vec<EventFd>,
+}
+
+impl QueuePairs {
+    /// Until the guest asks for more, only the first pair is used.
+    pub fn new(count: usize) -> io::Result<Self> {
+        Ok(QueuePairs {
+            active: AtomicUsize::new(1),
+            events: (0..count)
+                .map(|_| EventFd::new(EFD_NONBLOCK))
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    fn is_active(&self, index: usize) -> bool {
+        index < self.active.load(Ordering::Acquire)
+    }
+
+    // Returns false if the guest asked for more pairs than there are.
+    fn set_active(&self, count: usize) -> bool {
+        if count == 0 || count > self.events.len() {
+            return false;
+        }
+        self.active.store(count, Ordering::Release);
+        for event in &self.events {
+            if let Err(e) = event.write(1) {
+                error!("Failed to signal queue pair change: {e:?}");
+            }
+        }
+        true
+    }
+}
+
+/// Opens the backend, and another handle to it for every queue pair past the first.
+pub(crate) fn connect(
+    cfg_backend: VirtioNetBackend,
+    _vnet_features: u64,
+    pairs: usize,
+) -> Result<Vec<Box<dyn NetBackend + Send>>, ConnectError> {
+    let backend = match cfg_backend {
+        VirtioNetBackend::UnixstreamFd(fd) => {
+            // SAFETY: we need to trust that the library user has configured
+            // the backend with a healthy file descriptor.
+            let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Box::new(Unixstream::new(owned_fd)) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::UnixstreamPath(path) => {
+            Box::new(Unixstream::open(path)?) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::UnixgramFd(fd) => {
+            // SAFETY: we need to trust that the library user has configured
+            // the backend with a healthy file descriptor.
+            let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Box::new(Unixgram::new(owned_fd)) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::UnixgramPath(path, vfkit_magic) => {
+            Box::new(Unixgram::open(path, vfkit_magic)?) as Box<dyn NetBackend + Send>
+        }
+        #[cfg(target_os = "linux")]
+        VirtioNetBackend::Tap(tap_name) => {
+            Box::new(Tap::new(tap_name, _vnet_features, pairs > 1)?) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::Custom(backend) => backend,
+    };
+
+    let mut backends = Vec::with_capacity(pairs);
+    for _ in 1..pairs {
+        backends.push(backend.try_clone().map_err(ConnectError::CloneBackend)?);
+    }
+    backends.insert(0, backend);
+    Ok(backends)
+}
+
+/// Serves one pair of receive and transmit queues, and the control queue if it's the first.
 pub struct NetWorker {
+    index: usize,
     rx_q: DeviceQueue,
     tx_q: DeviceQueue,
+    ctrl_q: Option<DeviceQueue>,
     interrupt: InterruptTransport,
 
     mem: GuestMemoryMmap,
     backend: Box<dyn NetBackend + Send>,
+    queue_pairs: Arc<QueuePairs>,
+    // Whether the guest uses this pair. The backend isn't read from while it doesn't.
+    enabled: bool,
+    // Whether the backend takes frames with a partial checksum.
+    csum_offload: bool,
+    metrics: Arc<NetMetrics>,
+    rx_throttle: Throttle,
+    tx_throttle: Throttle,
+    // Whether a frame was put back on the transmit queue until `tx_throttle`'s timer fires.
+    tx_throttled: bool,
 
     rx_frame_buf: [u8; MAX_BUFFER_SIZE],
     rx_frame_buf_len: usize,
@@ -34,47 +136,36 @@ pub struct NetWorker {
 }
 
 impl NetWorker {
-    pub fn new(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        index: usize,
         rx_q: DeviceQueue,
         tx_q: DeviceQueue,
         interrupt: InterruptTransport,
         mem: GuestMemoryMmap,
-        _vnet_features: u64,
-        cfg_backend: VirtioNetBackend,
-    ) -> Result<Self, ConnectError> {
-        let backend = match cfg_backend {
-            VirtioNetBackend::UnixstreamFd(fd) => {
-                // SAFETY: we need to trust that the library user has configured
-                // the backend with a healthy file descriptor.
-                let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
-                Box::new(Unixstream::new(owned_fd)) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::UnixstreamPath(path) => {
-                Box::new(Unixstream::open(path)?) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::UnixgramFd(fd) => {
-                // SAFETY: we need to trust that the library user has configured
-                // the backend with a healthy file descriptor.
-                let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
-                Box::new(Unixgram::new(owned_fd)) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::UnixgramPath(path, vfkit_magic) => {
-                Box::new(Unixgram::open(path, vfkit_magic)?) as Box<dyn NetBackend + Send>
-            }
-            #[cfg(target_os = "linux")]
-            VirtioNetBackend::Tap(tap_name) => {
-                Box::new(Tap::new(tap_name, _vnet_features)?) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::Custom(backend) => backend,
-        };
-
-        Ok(Self {
+        vnet_features: u64,
+        backend: Box<dyn NetBackend + Send>,
+        queue_pairs: Arc<QueuePairs>,
+        metrics: Arc<NetMetrics>,
+        rx_throttle: Throttle,
+        tx_throttle: Throttle,
+    ) -> Self {
+        Self {
+            index,
             rx_q,
             tx_q,
+            ctrl_q: None,
 
             mem,
             backend,
             interrupt,
+            enabled: queue_pairs.is_active(index),
+            queue_pairs,
+            csum_offload: vnet_features & (1 << VIRTIO_NET_F_CSUM) != 0,
+            metrics,
+            rx_throttle,
+            tx_throttle,
+            tx_throttled: false,
 
             rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             rx_frame_buf_len: 0,
@@ -83,7 +174,11 @@ impl NetWorker {
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_len: 0,
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
-        })
+        }
+    }
+
+    pub(crate) fn set_ctrl_queue(&mut self, ctrl_q: Option<DeviceQueue>) {
+        self.ctrl_q = ctrl_q;
     }
 
     pub fn run(self) {
@@ -96,10 +191,33 @@ impl NetWorker {
     fn work(mut self) {
         let virtq_rx_ev_fd = self.rx_q.event.as_raw_fd();
         let virtq_tx_ev_fd = self.tx_q.event.as_raw_fd();
+        let virtq_ctrl_ev_fd = self.ctrl_q.as_ref().map(|q| q.event.as_raw_fd());
+        let pairs_ev_fd = self.queue_pairs.events[self.index].as_raw_fd();
         let backend_socket = self.backend.raw_socket_fd();
+        let rx_throttle_fd = self.rx_throttle.as_raw_fd();
+        let tx_throttle_fd = self.tx_throttle.as_raw_fd();
+
+        if !self.enabled {
+            if let Err(e) = self.backend.set_enabled(false) {
+                log::error!("Failed to disable queue pair {}: {e:?}", self.index);
+            }
+        }
 
         let epoll = Epoll::new().unwrap();
 
+        let _ = epoll.ctl(
+            ControlOperation::Add,
+            pairs_ev_fd,
+            &EpollEvent::new(EventSet::IN, pairs_ev_fd as u64),
+        );
+        if let Some(fd) = virtq_ctrl_ev_fd {
+            let _ = epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                &EpollEvent::new(EventSet::IN, fd as u64),
+            );
+        }
+
         let _ = epoll.ctl(
             ControlOperation::Add,
             virtq_rx_ev_fd,
@@ -110,6 +228,13 @@ impl NetWorker {
             virtq_tx_ev_fd,
             &EpollEvent::new(EventSet::IN, virtq_tx_ev_fd as u64),
         );
+        for fd in [rx_throttle_fd, tx_throttle_fd] {
+            let _ = epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                &EpollEvent::new(EventSet::IN, fd as u64),
+            );
+        }
         let _ = epoll.ctl(
             ControlOperation::Add,
             backend_socket,
@@ -119,6 +244,8 @@ impl NetWorker {
             ),
         );
 
+        utils::sandbox::enter(ThreadCategory::Net);
+
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
             match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
@@ -133,6 +260,18 @@ impl NetWorker {
                             EventSet::IN if source == virtq_tx_ev_fd => {
                                 self.process_tx_queue_event();
                             }
+                            EventSet::IN if Some(source) == virtq_ctrl_ev_fd => {
+                                self.process_ctrl_queue_event();
+                            }
+                            EventSet::IN if source == pairs_ev_fd => {
+                                self.process_queue_pairs_event();
+                            }
+                            EventSet::IN if source == rx_throttle_fd => {
+                                self.process_rx_throttle_event();
+                            }
+                            EventSet::IN if source == tx_throttle_fd => {
+                                self.process_tx_throttle_event();
+                            }
                             _ if source == backend_socket => {
                                 if event_set.contains(EventSet::HANG_UP)
                                     || event_set.contains(EventSet::READ_HANG_UP)
@@ -188,6 +327,82 @@ impl NetWorker {
         }
     }
 
+    pub(crate) fn process_ctrl_queue_event(&mut self) {
+        let Some(ctrl_q) = self.ctrl_q.as_mut() else {
+            return;
+        };
+        if let Err(e) = ctrl_q.event.read() {
+            log::error!("Failed to get ctrl queue event from queue: {e:?}");
+        }
+
+        let mut used = false;
+        while let Some(head) = ctrl_q.queue.pop(&self.mem) {
+            let index = head.index;
+            let len = match (
+                Reader::new(&self.mem, head.clone()),
+                Writer::new(&self.mem, head),
+            ) {
+                (Ok(mut reader), Ok(mut writer)) => {
+                    let ack = process_ctrl_command(&mut reader, &self.queue_pairs);
+                    match writer.write_obj(ack) {
+                        Ok(()) => 1,
+                        Err(e) => {
+                            log::error!("Failed to write ctrl command status: {e:?}");
+                            0
+                        }
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    log::error!("Invalid ctrl descriptor chain: {e:?}");
+                    0
+                }
+            };
+            if let Err(e) = ctrl_q.queue.add_used(&self.mem, index, len) {
+                log::error!("Failed to add used elements to the ctrl queue: {e:?}");
+            }
+            used = true;
+        }
+
+        if used && ctrl_q.queue.needs_notification(&self.mem).unwrap() {
+            if let Err(e) = self.interrupt.try_signal_used_queue() {
+                log::error!("Failed to signal ctrl queue: {e:?}");
+            }
+        }
+    }
+
+    pub(crate) fn process_queue_pairs_event(&mut self) {
+        if let Err(e) = self.queue_pairs.events[self.index].read() {
+            log::error!("Failed to get queue pair event: {e:?}");
+        }
+        let enabled = self.queue_pairs.is_active(self.index);
+        if enabled == self.enabled {
+            return;
+        }
+        if let Err(e) = self.backend.set_enabled(enabled) {
+            log::error!("Failed to switch queue pair {}: {e:?}", self.index);
+        }
+        self.enabled = enabled;
+        // Frames may have come in while the pair was unused, without another edge to come.
+        if enabled {
+            self.process_backend_socket_readable();
+        }
+    }
+
+    // The rate limit held back a received frame, and there are tokens for it now.
+    pub(crate) fn process_rx_throttle_event(&mut self) {
+        self.rx_throttle.clear_timer();
+        if let Err(e) = self.process_rx() {
+            log::error!("Failed to process rx: {e:?} (triggered by rate limiter)");
+        }
+    }
+
+    // The rate limit left frames on the transmit queue, and there are tokens for them now.
+    pub(crate) fn process_tx_throttle_event(&mut self) {
+        self.tx_throttle.clear_timer();
+        self.tx_throttled = false;
+        self.process_tx_loop();
+    }
+
     pub(crate) fn process_backend_socket_readable(&mut self) {
         if let Err(e) = self.rx_q.queue.enable_notification(&self.mem) {
             error!("error disabling queue notifications: {e:?}");
@@ -217,10 +432,14 @@ impl NetWorker {
     }
 
     fn process_rx(&mut self) -> result::Result<(), RxError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
         // if we have a deferred frame we try to process it first,
         // if that is not possible, we don't continue processing other frames
         if self.rx_has_deferred_frame {
-            if self.write_frame_to_guest() {
+            if self.deliver_frame() {
                 self.rx_has_deferred_frame = false;
             } else {
                 return Ok(());
@@ -233,7 +452,7 @@ impl NetWorker {
         let result = loop {
             match self.read_into_rx_frame_buf_from_backend() {
                 Ok(()) => {
-                    if self.write_frame_to_guest() {
+                    if self.deliver_frame() {
                         signal_queue = true;
                     } else {
                         self.rx_has_deferred_frame = true;
@@ -264,6 +483,10 @@ impl NetWorker {
                 log::error!("Failed to process rx: {e:?} (triggered by backend socket readable)");
             };
 
+            // Notifications stay off until the throttle's timer resumes the queue.
+            if self.tx_throttled {
+                break;
+            }
             if !self.tx_q.queue.enable_notification(&self.mem).unwrap() {
                 break;
             }
@@ -299,10 +522,24 @@ impl NetWorker {
                 next_desc = desc.next_descriptor();
             }
 
+            let frame_len: usize = self.tx_iovec.iter().map(|(_, len)| len).sum();
+            let data_len = frame_len.saturating_sub(vnet_hdr_len()) as u64;
+            if !self.tx_throttle.consume(1, data_len) {
+                tx_queue.undo_pop();
+                self.tx_throttled = true;
+                break;
+            }
+
             // Copy buffer from across multiple descriptors.
             let mut read_count = 0;
+            let mut dropped = false;
             for (desc_addr, desc_len) in self.tx_iovec.drain(..) {
-                let limit = cmp::min(read_count + desc_len, self.tx_frame_buf.len());
+                let limit = read_count + desc_len;
+                if limit > self.tx_frame_buf.len() {
+                    log::warn!("Dropping tx frame larger than {MAX_BUFFER_SIZE} bytes");
+                    dropped = true;
+                    break;
+                }
 
                 let read_result = self
                     .mem
@@ -313,12 +550,25 @@ impl NetWorker {
                     }
                     Err(e) => {
                         log::error!("Failed to read slice: {e:?}");
-                        read_count = 0;
+                        dropped = true;
                         break;
                     }
                 }
             }
 
+            if dropped || read_count < vnet_hdr_len() {
+                self.metrics.tx_dropped();
+                tx_queue
+                    .add_used(&self.mem, head_index, 0)
+                    .map_err(TxError::QueueError)?;
+                raise_irq = true;
+                continue;
+            }
+
+            if !self.csum_offload {
+                complete_checksum(&mut self.tx_frame_buf[..read_count]);
+            }
+
             self.tx_frame_len = read_count;
             match self
                 .backend
@@ -326,6 +576,7 @@ impl NetWorker {
             {
                 Ok(()) => {
                     self.tx_frame_len = 0;
+                    self.metrics.sent(read_count - vnet_hdr_len());
                     tx_queue
                         .add_used(&self.mem, head_index, 0)
                         .map_err(TxError::QueueError)?;
@@ -348,6 +599,7 @@ impl NetWorker {
                     the backend could be blocked on sending a remainder of a frame to us - us waiting
                     for backend would cause a deadlock.
                      */
+                    self.metrics.sent(read_count - vnet_hdr_len());
                     tx_queue
                         .add_used(&self.mem, head_index, 0)
                         .map_err(TxError::QueueError)?;
@@ -355,7 +607,18 @@ impl NetWorker {
                     break;
                 }
                 Err(e @ WriteError::Internal(_) | e @ WriteError::ProcessNotRunning) => {
-                    return Err(TxError::Backend(e))
+                    // Give the frame back to the guest, it won't be sent.
+                    self.tx_frame_len = 0;
+                    self.metrics.tx_dropped();
+                    tx_queue
+                        .add_used(&self.mem, head_index, 0)
+                        .map_err(TxError::QueueError)?;
+                    if tx_queue.needs_notification(&self.mem).unwrap() {
+                        self.interrupt
+                            .try_signal_used_queue()
+                            .map_err(TxError::DeviceError)?;
+                    }
+                    return Err(TxError::Backend(e));
                 }
             }
         }
@@ -418,13 +681,37 @@ impl NetWorker {
         result
     }
 
+    // Copies the frame in `self.rx_frame_buf` into the guest like `write_frame_to_guest()`, unless
+    // the rate limit holds it back, in which case it's delivered once `rx_throttle`'s timer fires.
+    fn deliver_frame(&mut self) -> bool {
+        let data_len = self.rx_frame_buf_len.saturating_sub(vnet_hdr_len()) as u64;
+        if !self.rx_throttle.consume(1, data_len) {
+            return false;
+        }
+        if self.write_frame_to_guest() {
+            return true;
+        }
+        // Not delivered after all, for lack of receive buffers.
+        self.rx_throttle.refund(1, data_len);
+        false
+    }
+
     // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
+    // the operation if possible. Returns true if the operation was successfull, or if the frame
+    // was dropped because it doesn't fit in the guest's buffers.
     fn write_frame_to_guest(&mut self) -> bool {
         let max_iterations = self.rx_q.queue.actual_size();
         for _ in 0..max_iterations {
             match self.write_frame_to_guest_impl() {
-                Ok(()) => return true,
+                Ok(()) => {
+                    self.metrics
+                        .received(self.rx_frame_buf_len.saturating_sub(vnet_hdr_len()));
+                    return true;
+                }
+                Err(FrontendError::DescriptorChainTooSmall) => {
+                    self.metrics.rx_dropped();
+                    return true;
+                }
                 Err(FrontendError::EmptyQueue) => {
                     // retry
                     continue;
@@ -445,3 +732,580 @@ impl NetWorker {
         Ok(())
     }
 }
+
+// Fills in the checksum of a frame the guest left partially checksummed, for backends that
+// weren't offered checksum offload. A guest shouldn't send those, but one that does would have
+// the backend pass on corrupted packets.
+fn complete_checksum(frame: &mut [u8]) {
+    let hdr_len = vnet_hdr_len();
+    if frame.len() < hdr_len || frame[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM as u8 == 0 {
+        return;
+    }
+    let csum_start = hdr_len + u16::from_le_bytes([frame[6], frame[7]]) as usize;
+    let field = csum_start + u16::from_le_bytes([frame[8], frame[9]]) as usize;
+    if field + 2 > frame.len() {
+        log::warn!("Checksum of frame out of bounds, leaving it partial");
+        return;
+    }
+
+    // The field holds the checksum of the pseudo header, so it's summed along with the data.
+    let mut sum = frame[csum_start..]
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    // Zero means no checksum for UDP, so it's sent as 0xffff, which is the same value.
+    let csum = match !(sum as u16) {
+        0 => 0xffff,
+        csum => csum,
+    };
+    frame[field..field + 2].copy_from_slice(&csum.to_be_bytes());
+    frame[0] &= !(VIRTIO_NET_HDR_F_NEEDS_CSUM as u8);
+}
+
+// Runs a command from the control queue, returning the status for the guest.
+fn process_ctrl_command(reader: &mut Reader, queue_pairs: &QueuePairs) -> u8 {
+    let (Ok(class), Ok(command)) = (reader.read_obj::<u8>(), reader.read_obj::<u8>()) else {
+        return VIRTIO_NET_ERR as u8;
+    };
+    match (class as u32, command as u32) {
+        (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => match reader.read_obj::<u16>() {
+            Ok(pairs) if queue_pairs.set_active(u16::from_le(pairs) as usize) => {
+                debug!("guest uses {} queue pairs", u16::from_le(pairs));
+                VIRTIO_NET_OK as u8
+            }
+            _ => VIRTIO_NET_ERR as u8,
+        },
+        _ => {
+            log::warn!("Unsupported virtio-net ctrl command: class={class} command={command}");
+            VIRTIO_NET_ERR as u8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{HashSet, VecDeque};
+    use std::os::fd::RawFd;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::super::metrics::NetMetricsSnapshot;
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::queue::tests::VirtQueue;
+    use crate::virtio::rate_limiter::{RateLimit, RateLimiter};
+
+    const RING_SIZE: u16 = 16;
+    const BUF_LEN: u32 = 0x800;
+    // Guest memory of each queue pair: its two rings, then the receive and transmit buffers.
+    const PAIR_SIZE: u64 = 0x40000;
+    const TX_RING: u64 = 0x1000;
+    const RX_BUFFERS: u64 = 0x10000;
+    const TX_BUFFERS: u64 = 0x20000;
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+    // Hands every frame written to any of its handles back to whichever handle reads first, like
+    // a switch port looped back to itself.
+    struct Loopback {
+        frames: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        event: EventFd,
+    }
+
+    impl Loopback {
+        const CAPACITY: usize = 64;
+
+        fn new() -> Self {
+            Loopback {
+                frames: Arc::new(Mutex::new(VecDeque::new())),
+                event: EventFd::new(EFD_NONBLOCK).unwrap(),
+            }
+        }
+    }
+
+    impl NetBackend for Loopback {
+        fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+            let frame = self
+                .frames
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(ReadError::NothingRead)?;
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(frame.len())
+        }
+
+        fn write_frame(&mut self, _hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() == Self::CAPACITY {
+                return Err(WriteError::NothingWritten);
+            }
+            frames.push_back(buf.to_vec());
+            Ok(())
+        }
+
+        fn has_unfinished_write(&self) -> bool {
+            false
+        }
+
+        fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+            Ok(())
+        }
+
+        fn raw_socket_fd(&self) -> RawFd {
+            self.event.as_raw_fd()
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn NetBackend + Send>> {
+            Ok(Box::new(Loopback {
+                frames: Arc::clone(&self.frames),
+                event: EventFd::new(EFD_NONBLOCK)?,
+            }))
+        }
+    }
+
+    fn new_worker(
+        index: usize,
+        vnet_features: u64,
+        mem: &GuestMemoryMmap,
+        rx: &VirtQueue,
+        tx: &VirtQueue,
+        backend: Box<dyn NetBackend + Send>,
+        queue_pairs: &Arc<QueuePairs>,
+    ) -> NetWorker {
+        let queue = |vq: &VirtQueue| {
+            DeviceQueue::new(
+                vq.create_queue(),
+                Arc::new(EventFd::new(EFD_NONBLOCK).unwrap()),
+            )
+        };
+        let interrupt = InterruptTransport::new(DummyIrqChip::new().into(), "net".into()).unwrap();
+        NetWorker::new(
+            index,
+            queue(rx),
+            queue(tx),
+            interrupt,
+            mem.clone(),
+            vnet_features,
+            backend,
+            Arc::clone(queue_pairs),
+            Arc::new(NetMetrics::new()),
+            Throttle::new(Arc::new(RateLimiter::default())).unwrap(),
+            Throttle::new(Arc::new(RateLimiter::default())).unwrap(),
+        )
+    }
+
+    // Gives the device every receive buffer of the pair at `base`.
+    fn post_rx_buffers(rx: &VirtQueue, base: u64) {
+        for i in 0..RING_SIZE {
+            rx.dtable[i as usize].set(
+                base + RX_BUFFERS + i as u64 * BUF_LEN as u64,
+                BUF_LEN,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            rx.avail.ring[i as usize].set(i);
+        }
+        rx.avail.idx.set(RING_SIZE);
+    }
+
+    // A frame of varying length that says which pair sent it and in what order.
+    fn frame(pair: usize, seq: u32) -> Vec<u8> {
+        let len = vnet_hdr_len() + 60 + (seq as usize * 7) % 1400;
+        let mut frame = vec![0u8; len];
+        frame[vnet_hdr_len()] = pair as u8;
+        frame[vnet_hdr_len() + 1..vnet_hdr_len() + 5].copy_from_slice(&seq.to_le_bytes());
+        for (i, b) in frame.iter_mut().enumerate().skip(vnet_hdr_len() + 5) {
+            *b = (pair as u32 * 31 + seq + i as u32) as u8;
+        }
+        frame
+    }
+
+    #[test]
+    fn ctrl_sets_queue_pairs() {
+        let mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 2 * PAIR_SIZE as usize)]).unwrap();
+        let queue_pairs = Arc::new(QueuePairs::new(2).unwrap());
+        let loopback = Loopback::new();
+        let frames = Arc::clone(&loopback.frames);
+
+        let rx0 = VirtQueue::new(GuestAddress(0), &mem, RING_SIZE);
+        let tx0 = VirtQueue::new(GuestAddress(TX_RING), &mem, RING_SIZE);
+        let ctrl = VirtQueue::new(GuestAddress(2 * TX_RING), &mem, RING_SIZE);
+        let mut worker0 = new_worker(
+            0,
+            0,
+            &mem,
+            &rx0,
+            &tx0,
+            loopback.try_clone().unwrap(),
+            &queue_pairs,
+        );
+        worker0.set_ctrl_queue(Some(DeviceQueue::new(
+            ctrl.create_queue(),
+            Arc::new(EventFd::new(EFD_NONBLOCK).unwrap()),
+        )));
+
+        let rx1 = VirtQueue::new(GuestAddress(PAIR_SIZE), &mem, RING_SIZE);
+        let tx1 = VirtQueue::new(GuestAddress(PAIR_SIZE + TX_RING), &mem, RING_SIZE);
+        let mut worker1 = new_worker(1, 0, &mem, &rx1, &tx1, Box::new(loopback), &queue_pairs);
+        post_rx_buffers(&rx1, PAIR_SIZE);
+
+        // Until the guest asks for it, the second pair doesn't take frames.
+        frames.lock().unwrap().push_back(frame(0, 0));
+        worker1.process_backend_socket_readable();
+        assert_eq!(rx1.used.idx.get(), 0);
+
+        let mut command = |class: u8, command: u8, pairs: u16| -> u8 {
+            let request = 0x30000;
+            let ack = request + 0x100;
+            mem.write_obj(class, GuestAddress(request)).unwrap();
+            mem.write_obj(command, GuestAddress(request + 1)).unwrap();
+            mem.write_obj(pairs.to_le(), GuestAddress(request + 2))
+                .unwrap();
+            mem.write_obj(0xffu8, GuestAddress(ack)).unwrap();
+            let avail = ctrl.avail.idx.get();
+            ctrl.dtable[0].set(request, 4, VIRTQ_DESC_F_NEXT, 1);
+            ctrl.dtable[1].set(ack, 1, VIRTQ_DESC_F_WRITE, 0);
+            ctrl.avail.ring[(avail % RING_SIZE) as usize].set(0);
+            ctrl.avail.idx.set(avail + 1);
+            worker0.ctrl_q.as_ref().unwrap().event.write(1).unwrap();
+            worker0.process_ctrl_queue_event();
+            assert_eq!(ctrl.used.idx.get(), avail + 1);
+            mem.read_obj(GuestAddress(ack)).unwrap()
+        };
+        let mq = VIRTIO_NET_CTRL_MQ as u8;
+        let set = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8;
+        assert_eq!(command(mq, set, 3), VIRTIO_NET_ERR as u8);
+        assert_eq!(command(mq, set, 0), VIRTIO_NET_ERR as u8);
+        assert_eq!(command(0, 0, 0), VIRTIO_NET_ERR as u8);
+        assert!(!queue_pairs.is_active(1));
+        assert_eq!(command(mq, set, 2), VIRTIO_NET_OK as u8);
+        assert!(queue_pairs.is_active(1));
+
+        // The frame that came in before is delivered once the pair is in use.
+        worker1.process_queue_pairs_event();
+        assert_eq!(rx1.used.idx.get(), 1);
+        let used = rx1.used.ring[0].get();
+        let mut buf = vec![0u8; used.len as usize];
+        mem.read_slice(&mut buf, GuestAddress(PAIR_SIZE + RX_BUFFERS))
+            .unwrap();
+        assert!(buf == frame(0, 0));
+
+        assert_eq!(command(mq, set, 1), VIRTIO_NET_OK as u8);
+        worker1.process_queue_pairs_event();
+        assert!(!worker1.enabled);
+    }
+
+    // Sends frames from every queue pair at once through a shared loopback, checking that each
+    // comes back exactly once and intact on some pair.
+    #[test]
+    fn queue_pairs_stress() {
+        const PAIRS: usize = 4;
+        const FRAMES: u32 = 2000;
+
+        let mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PAIRS * PAIR_SIZE as usize)]).unwrap();
+        let queue_pairs = Arc::new(QueuePairs::new(PAIRS).unwrap());
+        assert!(queue_pairs.set_active(PAIRS));
+        let mut backends = Vec::new();
+        let loopback = Loopback::new();
+        for _ in 1..PAIRS {
+            backends.push(loopback.try_clone().unwrap());
+        }
+        backends.insert(0, Box::new(loopback) as Box<dyn NetBackend + Send>);
+
+        let received = AtomicUsize::new(0);
+        let seen = Mutex::new(HashSet::new());
+        let total = PAIRS * FRAMES as usize;
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        thread::scope(|s| {
+            for (pair, backend) in backends.into_iter().enumerate() {
+                let (mem, queue_pairs, received, seen) = (&mem, &queue_pairs, &received, &seen);
+                s.spawn(move || {
+                    let base = pair as u64 * PAIR_SIZE;
+                    let rx = VirtQueue::new(GuestAddress(base), mem, RING_SIZE);
+                    let tx = VirtQueue::new(GuestAddress(base + TX_RING), mem, RING_SIZE);
+                    let mut worker = new_worker(pair, 0, mem, &rx, &tx, backend, queue_pairs);
+                    post_rx_buffers(&rx, base);
+
+                    let (mut sent, mut rx_used) = (0u32, 0u16);
+                    while received.load(Ordering::Relaxed) < total {
+                        assert!(Instant::now() < deadline, "frames lost");
+
+                        let tx_avail = tx.avail.idx.get();
+                        let mut posted = tx_avail;
+                        while sent < FRAMES && posted.wrapping_sub(tx.used.idx.get()) < RING_SIZE {
+                            let slot = posted % RING_SIZE;
+                            let addr = base + TX_BUFFERS + slot as u64 * BUF_LEN as u64;
+                            let frame = frame(pair, sent);
+                            mem.write_slice(&frame, GuestAddress(addr)).unwrap();
+                            tx.dtable[slot as usize].set(addr, frame.len() as u32, 0, 0);
+                            tx.avail.ring[slot as usize].set(slot);
+                            posted = posted.wrapping_add(1);
+                            sent += 1;
+                        }
+                        if posted != tx_avail {
+                            tx.avail.idx.set(posted);
+                            worker.tx_q.event.write(1).unwrap();
+                            worker.process_tx_queue_event();
+                        }
+
+                        worker.process_backend_socket_readable();
+                        while rx_used != rx.used.idx.get() {
+                            let used = rx.used.ring[(rx_used % RING_SIZE) as usize].get();
+                            let addr = base + RX_BUFFERS + used.id as u64 * BUF_LEN as u64;
+                            let mut buf = vec![0u8; used.len as usize];
+                            mem.read_slice(&mut buf, GuestAddress(addr)).unwrap();
+                            let from = buf[vnet_hdr_len()] as usize;
+                            let seq = u32::from_le_bytes(
+                                buf[vnet_hdr_len() + 1..vnet_hdr_len() + 5]
+                                    .try_into()
+                                    .unwrap(),
+                            );
+                            assert!(buf == frame(from, seq), "frame {from}/{seq} corrupted");
+                            assert!(seen.lock().unwrap().insert((from, seq)), "duplicate frame");
+
+                            let avail = rx.avail.idx.get();
+                            rx.avail.ring[(avail % RING_SIZE) as usize].set(used.id as u16);
+                            rx.avail.idx.set(avail.wrapping_add(1));
+                            rx_used = rx_used.wrapping_add(1);
+                            received.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(seen.lock().unwrap().len(), total);
+    }
+
+    // Sends a UDP datagram left partially checksummed by the guest, returning what reaches the
+    // backend and the checksum the datagram should carry.
+    fn send_partial_checksum(vnet_features: u64) -> (Vec<u8>, u16) {
+        const ETH_LEN: usize = 14;
+        const IP_LEN: usize = 20;
+        const CSUM_OFFSET: usize = 6;
+
+        let payload = b"partially checksummed, odd length";
+        let udp_len = 8 + payload.len();
+        let mut frame = vec![0u8; vnet_hdr_len() + ETH_LEN + IP_LEN + udp_len];
+        let (hdr, packet) = frame.split_at_mut(vnet_hdr_len());
+        hdr[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+        hdr[6..8].copy_from_slice(&((ETH_LEN + IP_LEN) as u16).to_le_bytes());
+        hdr[8..10].copy_from_slice(&(CSUM_OFFSET as u16).to_le_bytes());
+        packet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        let ip = &mut packet[ETH_LEN..];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&((IP_LEN + udp_len) as u16).to_be_bytes());
+        ip[8] = 64;
+        ip[9] = 17;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        let udp = &mut ip[IP_LEN..];
+        udp[0..2].copy_from_slice(&5353u16.to_be_bytes());
+        udp[2..4].copy_from_slice(&53u16.to_be_bytes());
+        udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        udp[8..].copy_from_slice(payload);
+
+        let fold = |mut sum: u32| {
+            while sum >> 16 != 0 {
+                sum = (sum & 0xffff) + (sum >> 16);
+            }
+            sum as u16
+        };
+        let words = |bytes: &[u8]| -> u32 {
+            bytes
+                .chunks(2)
+                .map(|w| u16::from_be_bytes([w[0], w.get(1).copied().unwrap_or(0)]) as u32)
+                .sum()
+        };
+        let pseudo = words(&[10, 0, 0, 1, 10, 0, 0, 2]) + 17 + udp_len as u32;
+        let expected = !fold(pseudo + words(udp));
+        // Like the guest would, seed the checksum with the pseudo header.
+        udp[CSUM_OFFSET..CSUM_OFFSET + 2].copy_from_slice(&fold(pseudo).to_be_bytes());
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PAIR_SIZE as usize)]).unwrap();
+        let queue_pairs = Arc::new(QueuePairs::new(1).unwrap());
+        let loopback = Loopback::new();
+        let frames = Arc::clone(&loopback.frames);
+        let rx = VirtQueue::new(GuestAddress(0), &mem, RING_SIZE);
+        let tx = VirtQueue::new(GuestAddress(TX_RING), &mem, RING_SIZE);
+        let mut worker = new_worker(
+            0,
+            vnet_features,
+            &mem,
+            &rx,
+            &tx,
+            Box::new(loopback),
+            &queue_pairs,
+        );
+
+        mem.write_slice(&frame, GuestAddress(TX_BUFFERS)).unwrap();
+        tx.dtable[0].set(TX_BUFFERS, frame.len() as u32, 0, 0);
+        tx.avail.ring[0].set(0);
+        tx.avail.idx.set(1);
+        worker.tx_q.event.write(1).unwrap();
+        worker.process_tx_queue_event();
+        assert_eq!(tx.used.idx.get(), 1);
+
+        let sent = frames.lock().unwrap().pop_front().unwrap();
+        (sent, expected)
+    }
+
+    #[test]
+    fn checksums_completed_without_offload() {
+        let field = vnet_hdr_len() + 14 + 20 + 6;
+
+        let (frame, expected) = send_partial_checksum(0);
+        assert_eq!(frame[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM as u8, 0);
+        assert_eq!(
+            u16::from_be_bytes([frame[field], frame[field + 1]]),
+            expected
+        );
+
+        // With checksum offload, the backend gets the frame as the guest sent it.
+        let (frame, expected) = send_partial_checksum(1 << VIRTIO_NET_F_CSUM);
+        assert_ne!(frame[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM as u8, 0);
+        assert_ne!(
+            u16::from_be_bytes([frame[field], frame[field + 1]]),
+            expected
+        );
+    }
+
+    #[test]
+    fn counters_match_frames() {
+        const FRAMES: u32 = 40;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PAIR_SIZE as usize)]).unwrap();
+        let queue_pairs = Arc::new(QueuePairs::new(1).unwrap());
+        let rx = VirtQueue::new(GuestAddress(0), &mem, RING_SIZE);
+        let tx = VirtQueue::new(GuestAddress(TX_RING), &mem, RING_SIZE);
+        let mut worker = new_worker(
+            0,
+            0,
+            &mem,
+            &rx,
+            &tx,
+            Box::new(Loopback::new()),
+            &queue_pairs,
+        );
+        post_rx_buffers(&rx, 0);
+
+        let mut bytes = 0;
+        let send = |worker: &mut NetWorker, seq: u16, len: u32| {
+            let slot = seq % RING_SIZE;
+            tx.dtable[slot as usize].set(TX_BUFFERS, len, 0, 0);
+            tx.avail.ring[slot as usize].set(slot);
+            tx.avail.idx.set(seq.wrapping_add(1));
+            worker.tx_q.event.write(1).unwrap();
+            worker.process_tx_queue_event();
+            assert_eq!(tx.used.idx.get(), seq.wrapping_add(1));
+        };
+        for seq in 0..FRAMES {
+            let frame = frame(0, seq);
+            bytes += (frame.len() - vnet_hdr_len()) as u64;
+            mem.write_slice(&frame, GuestAddress(TX_BUFFERS)).unwrap();
+            send(&mut worker, seq as u16, frame.len() as u32);
+
+            worker.process_backend_socket_readable();
+            let avail = rx.avail.idx.get();
+            let used = rx.used.ring[(seq as u16 % RING_SIZE) as usize].get();
+            rx.avail.ring[(avail % RING_SIZE) as usize].set(used.id as u16);
+            rx.avail.idx.set(avail.wrapping_add(1));
+        }
+        // Too large for the device, then too short to hold a header.
+        send(&mut worker, FRAMES as u16, MAX_BUFFER_SIZE as u32 + 1);
+        send(&mut worker, FRAMES as u16 + 1, vnet_hdr_len() as u32 - 1);
+
+        assert_eq!(
+            worker.metrics.snapshot(),
+            NetMetricsSnapshot {
+                rx_packets: FRAMES as u64,
+                rx_bytes: bytes,
+                rx_dropped: 0,
+                tx_packets: FRAMES as u64,
+                tx_bytes: bytes,
+                tx_dropped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn rate_limit_caps_throughput() {
+        const FRAMES: u16 = RING_SIZE;
+        const DATA_LEN: usize = 1024;
+        const TX_BYTES_PER_SEC: u64 = 64 << 10;
+        const RX_FRAMES_PER_SEC: u64 = 100;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), PAIR_SIZE as usize)]).unwrap();
+        let queue_pairs = Arc::new(QueuePairs::new(1).unwrap());
+        let rx = VirtQueue::new(GuestAddress(0), &mem, RING_SIZE);
+        let tx = VirtQueue::new(GuestAddress(TX_RING), &mem, RING_SIZE);
+        let mut worker = new_worker(
+            0,
+            0,
+            &mem,
+            &rx,
+            &tx,
+            Box::new(Loopback::new()),
+            &queue_pairs,
+        );
+        let throttle = |limit| Throttle::new(Arc::new(RateLimiter::new(limit))).unwrap();
+        worker.tx_throttle = throttle(RateLimit {
+            bytes_per_sec: TX_BYTES_PER_SEC,
+            bytes_burst: DATA_LEN as u64,
+            ..Default::default()
+        });
+        worker.rx_throttle = throttle(RateLimit {
+            ops_per_sec: RX_FRAMES_PER_SEC,
+            ops_burst: 1,
+            ..Default::default()
+        });
+
+        // Every frame but the first waits for the bucket to refill with its data.
+        let start = Instant::now();
+        let len = (vnet_hdr_len() + DATA_LEN) as u32;
+        for seq in 0..FRAMES {
+            tx.dtable[seq as usize].set(TX_BUFFERS, len, 0, 0);
+            tx.avail.ring[seq as usize].set(seq);
+            tx.avail.idx.set(seq + 1);
+            worker.tx_q.event.write(1).unwrap();
+            worker.process_tx_queue_event();
+            while worker.tx_throttled {
+                worker.process_tx_throttle_event();
+            }
+            assert_eq!(tx.used.idx.get(), seq + 1);
+        }
+        let tx_elapsed = start.elapsed().as_secs_f64();
+        let tx_expected = f64::from(FRAMES - 1) * DATA_LEN as f64 / TX_BYTES_PER_SEC as f64;
+        assert!(
+            tx_elapsed >= tx_expected * 0.95 && tx_elapsed < tx_expected * 2.0,
+            "sending took {tx_elapsed}s, expected {tx_expected}s"
+        );
+
+        // The frames come back one at a time.
+        post_rx_buffers(&rx, 0);
+        let start = Instant::now();
+        worker.process_backend_socket_readable();
+        while worker.rx_has_deferred_frame {
+            worker.process_rx_throttle_event();
+        }
+        assert_eq!(rx.used.idx.get(), FRAMES);
+        let rx_elapsed = start.elapsed().as_secs_f64();
+        let rx_expected = f64::from(FRAMES - 1) / RX_FRAMES_PER_SEC as f64;
+        assert!(
+            rx_elapsed >= rx_expected * 0.95 && rx_elapsed < rx_expected * 2.0,
+            "receiving took {rx_elapsed}s, expected {rx_expected}s"
+        );
+
+        let counters = worker.metrics.snapshot();
+        assert_eq!(counters.tx_packets, u64::from(FRAMES));
+        assert_eq!(counters.rx_bytes, u64::from(FRAMES) * DATA_LEN as u64);
+    }
+}
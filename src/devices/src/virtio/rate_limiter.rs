@@ -0,0 +1,310 @@
+//! Token buckets capping the rate of a device's I/O.
+//!
+//! A device takes tokens for each request before working on it. Once a bucket runs dry, the
+//! request is put back on its queue and the worker arms a timer for when there will be tokens
+//! again, so the guest sees slower I/O but nothing it wouldn't from a slow device.
+
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use utils::timerfd::TimerFd;
+
+/// How long a throttled worker waits at most before checking the buckets again, so that a
+/// limit raised at runtime takes effect soon.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+/// How long it waits at least, so that it doesn't wake up for a sliver of a token.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// A ceiling on the rate of a device's I/O. A rate of 0 leaves it unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests, or frames for a network device, per second.
+    pub ops_per_sec: u64,
+    /// Bytes of data per second.
+    pub bytes_per_sec: u64,
+    /// How many requests can go through at once after a quiet spell. 0 means one second's worth.
+    pub ops_burst: u64,
+    /// How many bytes can go through at once after a quiet spell. 0 means one second's worth.
+    pub bytes_burst: u64,
+}
+
+impl RateLimit {
+    /// Whether nothing is limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.ops_per_sec == 0 && self.bytes_per_sec == 0
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    // Goes below zero when a request bigger than the capacity is let through.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    // Starts full, or is `None` if there's no limit.
+    fn new(rate: u64, burst: u64) -> Option<Self> {
+        (rate != 0).then(|| {
+            let capacity = if burst == 0 { rate } else { burst };
+            TokenBucket {
+                rate,
+                capacity,
+                tokens: capacity as f64,
+                refilled: Instant::now(),
+            }
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.capacity as f64);
+        self.refilled = now;
+    }
+
+    // Requests bigger than the bucket only need it full, and then leave it owing.
+    fn needed(&self, count: u64) -> f64 {
+        count.min(self.capacity) as f64
+    }
+
+    // How long until there are enough tokens for `count`.
+    fn wait(&self, count: u64) -> Duration {
+        Duration::from_secs_f64((self.needed(count) - self.tokens).max(0.0) / self.rate as f64)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    limit: RateLimit,
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit) -> Self {
+        Buckets {
+            limit,
+            ops: TokenBucket::new(limit.ops_per_sec, limit.ops_burst),
+            bytes: TokenBucket::new(limit.bytes_per_sec, limit.bytes_burst),
+        }
+    }
+
+    // The limited buckets, each with its share of `ops` and `bytes`.
+    fn with_counts(
+        &mut self,
+        ops: u64,
+        bytes: u64,
+    ) -> impl Iterator<Item = (&mut TokenBucket, u64)> {
+        [(self.ops.as_mut(), ops), (self.bytes.as_mut(), bytes)]
+            .into_iter()
+            .filter_map(|(bucket, count)| Some((bucket?, count)))
+    }
+}
+
+/// The token buckets of a device, shared by its workers and whoever changes its limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            buckets: Mutex::new(Buckets::new(limit)),
+        }
+    }
+
+    /// The limit in force.
+    pub fn limit(&self) -> RateLimit {
+        self.buckets.lock().unwrap().limit
+    }
+
+    /// Replaces the limit. Buckets that were already limited keep the tokens they had, up to
+    /// their new capacity, so that changing the limit doesn't hand out a free burst.
+    pub fn set_limit(&self, limit: RateLimit) {
+        let buckets = &mut *self.buckets.lock().unwrap();
+        let mut new = Buckets::new(limit);
+        let now = Instant::now();
+        for (old, new) in [
+            (buckets.ops.as_mut(), new.ops.as_mut()),
+            (buckets.bytes.as_mut(), new.bytes.as_mut()),
+        ] {
+            if let (Some(old), Some(new)) = (old, new) {
+                old.refill(now);
+                new.tokens = old.tokens.min(new.capacity as f64);
+                new.refilled = now;
+            }
+        }
+        *buckets = new;
+    }
+
+    /// Takes the tokens for `ops` requests moving `bytes`, or, if a bucket hasn't enough, takes
+    /// none and returns how long until it will.
+    pub fn consume(&self, ops: u64, bytes: u64) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for (bucket, count) in buckets.with_counts(ops, bytes) {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(count));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (bucket, count) in buckets.with_counts(ops, bytes) {
+            bucket.tokens -= count as f64;
+        }
+        Ok(())
+    }
+
+    /// Gives back the tokens for work that was taken but couldn't be done.
+    pub fn refund(&self, ops: u64, bytes: u64) {
+        let mut buckets = self.buckets.lock().unwrap();
+        for (bucket, count) in buckets.with_counts(ops, bytes) {
+            bucket.tokens = (bucket.tokens + count as f64).min(bucket.capacity as f64);
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimit::default())
+    }
+}
+
+/// A worker's view of its device's limiter, with the timer that wakes the worker up once it can
+/// go on.
+pub(crate) struct Throttle {
+    limiter: Arc<RateLimiter>,
+    timer: TimerFd,
+}
+
+impl Throttle {
+    pub fn new(limiter: Arc<RateLimiter>) -> std::io::Result<Self> {
+        Ok(Throttle {
+            limiter,
+            timer: TimerFd::new()?,
+        })
+    }
+
+    /// Takes the tokens for `ops` requests moving `bytes`, or arms the timer for when there will
+    /// be enough and returns false.
+    pub fn consume(&mut self, ops: u64, bytes: u64) -> bool {
+        match self.limiter.consume(ops, bytes) {
+            Ok(()) => true,
+            Err(wait) => {
+                if let Err(e) = self.timer.reset(wait.clamp(MIN_WAIT, MAX_WAIT), None) {
+                    error!("Failed to arm the rate limiter timer: {e:?}");
+                }
+                false
+            }
+        }
+    }
+
+    #[cfg(feature = "net")]
+    pub fn refund(&self, ops: u64, bytes: u64) {
+        self.limiter.refund(ops, bytes);
+    }
+
+    /// Acknowledges that the timer fired.
+    pub fn clear_timer(&mut self) {
+        if let Err(e) = self.timer.wait() {
+            error!("Failed to read the rate limiter timer: {e:?}");
+        }
+    }
+}
+
+impl AsRawFd for Throttle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let limiter = RateLimiter::new(RateLimit {
+            ops_per_sec: 1000,
+            bytes_per_sec: 1 << 20,
+            ops_burst: 10,
+            bytes_burst: 4096,
+        });
+
+        let bytes_time = |bytes: u64| Duration::from_secs_f64(bytes as f64 / (1 << 20) as f64);
+
+        // The burst goes through at once, then the bytes run out first. However long the calls
+        // take, the wait plus the time since the bucket emptied is what refilling it takes.
+        let start = Instant::now();
+        assert_eq!(limiter.consume(1, 4096), Ok(()));
+        let wait = limiter.consume(1, 4096).unwrap_err();
+        assert!(wait <= bytes_time(4096));
+        assert!(wait + start.elapsed() >= bytes_time(4096));
+
+        // Requests bigger than the burst only wait for a full bucket, and leave it owing.
+        std::thread::sleep(Duration::from_millis(5));
+        let start = Instant::now();
+        assert_eq!(limiter.consume(1, 8192), Ok(()));
+        let wait = limiter.consume(1, 1).unwrap_err();
+        assert!(wait + start.elapsed() >= bytes_time(4097));
+
+        // Refunds fill the bucket back up.
+        limiter.set_limit(RateLimit {
+            ops_per_sec: 1000,
+            ops_burst: 2,
+            ..Default::default()
+        });
+        assert_eq!(limiter.consume(2, u64::MAX), Ok(()));
+        limiter.refund(1, 0);
+        assert_eq!(limiter.consume(1, 0), Ok(()));
+        assert!(limiter.consume(1, 0).is_err());
+
+        limiter.set_limit(RateLimit::default());
+        assert!(limiter.limit().is_unlimited());
+        assert_eq!(limiter.consume(u64::MAX, u64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn new_limits_keep_the_tokens_left() {
+        let limiter = RateLimiter::new(RateLimit {
+            ops_per_sec: 1,
+            ops_burst: 4,
+            ..Default::default()
+        });
+        assert_eq!(limiter.consume(4, 0), Ok(()));
+
+        // An empty bucket stays empty under a bigger limit.
+        limiter.set_limit(RateLimit {
+            ops_per_sec: 1,
+            ops_burst: 8,
+            ..Default::default()
+        });
+        assert!(limiter.consume(1, 0).is_err());
+
+        // A bucket that wasn't limited before starts full.
+        limiter.set_limit(RateLimit {
+            ops_per_sec: 1,
+            ops_burst: 8,
+            bytes_per_sec: 1,
+            bytes_burst: 16,
+        });
+        assert!(limiter.consume(1, 0).is_err());
+        assert_eq!(limiter.consume(0, 16), Ok(()));
+
+        // A full bucket is cut down to a smaller capacity.
+        limiter.set_limit(RateLimit::default());
+        limiter.set_limit(RateLimit {
+            ops_per_sec: 1,
+            ops_burst: 2,
+            ..Default::default()
+        });
+        assert_eq!(limiter.consume(2, 0), Ok(()));
+        assert!(limiter.consume(1, 0).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! When the VM got through each stage of its boot.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A stage of the VM's boot, in the order they're reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BootStage {
+    /// The VM's resources are configured.
+    Configured,
+    /// Starting the VM began.
+    Starting,
+    /// The kernel is loaded into guest memory.
+    KernelLoaded,
+    /// The devices are set up and the vCPUs started, so the kernel is booting.
+    Started,
+    /// The guest wrote to its console for the first time.
+    FirstConsoleOutput,
+    /// The guest's init started running, as it reports.
+    InitStarted,
+    /// The guest's init is about to run the workload, as it reports.
+    WorkloadStarted,
+    /// The VM is exiting.
+    Exited,
+}
+
+impl BootStage {
+    /// Every stage, in order.
+    pub const ALL: [BootStage; 8] = [
+        BootStage::Configured,
+        BootStage::Starting,
+        BootStage::KernelLoaded,
+        BootStage::Started,
+        BootStage::FirstConsoleOutput,
+        BootStage::InitStarted,
+        BootStage::WorkloadStarted,
+        BootStage::Exited,
+    ];
+
+    /// The stage the guest's init reports with `code`.
+    pub fn from_guest_code(code: u64) -> Option<Self> {
+        match code {
+            1 => Some(BootStage::InitStarted),
+            2 => Some(BootStage::WorkloadStarted),
+            _ => None,
+        }
+    }
+}
+
+/// When each stage was reached, from a monotonic clock started as the timeline is created.
+/// Shared between the VMM, the devices that see the guest's milestones and whoever reads it.
+#[derive(Debug)]
+pub struct BootTimeline {
+    origin: Instant,
+    // Nanoseconds since `origin` plus one, so that 0 means not reached yet.
+    stages: [AtomicU64; BootStage::ALL.len()],
+}
+
+impl BootTimeline {
+    /// Creates a timeline starting now.
+    pub fn new() -> Self {
+        BootTimeline {
+            origin: Instant::now(),
+            stages: Default::default(),
+        }
+    }
+
+    /// Records that `stage` was reached now, unless it was already.
+    pub fn mark(&self, stage: BootStage) {
+        let nanos = self.origin.elapsed().as_nanos() as u64 + 1;
+        let _ = self.stages[stage as usize].compare_exchange(
+            0,
+            nanos,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// When `stage` was reached, counting from the creation of the timeline.
+    pub fn reached(&self, stage: BootStage) -> Option<Duration> {
+        match self.stages[stage as usize].load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos - 1)),
+        }
+    }
+
+    /// When the timeline was created.
+    pub fn origin(&self) -> Instant {
+        self.origin
+    }
+}
+
+impl Default for BootTimeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_mark_wins() {
+        let timeline = BootTimeline::new();
+        assert_eq!(timeline.reached(BootStage::Started), None);
+
+        timeline.mark(BootStage::Started);
+        let started = timeline.reached(BootStage::Started).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        timeline.mark(BootStage::Started);
+        assert_eq!(timeline.reached(BootStage::Started), Some(started));
+
+        timeline.mark(BootStage::Exited);
+        assert!(timeline.reached(BootStage::Exited).unwrap() > started);
+        assert_eq!(
+            BootStage::from_guest_code(2),
+            Some(BootStage::WorkloadStarted)
+        );
+        assert_eq!(BootStage::from_guest_code(0), None);
+    }
+}
@@ -19,9 +19,11 @@ use super::super::{
 };
 use super::muxer::VsockMuxer;
 use super::packet::VsockPacket;
+use super::proxy::VsockConnInfo;
+use super::stream::VsockConnectHandler;
 use super::TsiFlags;
 use super::{defs, defs::uapi};
-use crate::virtio::InterruptTransport;
+use crate::virtio::{InterruptTransport, VmmExitObserver};
 
 pub(crate) const RXQ_INDEX: usize = 0;
 pub(crate) const TXQ_INDEX: usize = 1;
@@ -78,6 +80,32 @@ impl Vsock {
         self.cid
     }
 
+    /// Describes the stream connections between the guest and the host, sorted by host port.
+    /// Each connection is looked at on its own while the device keeps going, so they may not
+    /// all be from the same instant.
+    pub fn connections(&self) -> Vec<VsockConnInfo> {
+        self.muxer.connections()
+    }
+
+    /// How many connections the guest asked for to host ports nothing listens on.
+    pub fn refused_connections(&self) -> u64 {
+        self.muxer.refused_connections()
+    }
+
+    /// Has `handler` called on a new thread with a [`VsockStream`](super::VsockStream) for every
+    /// connection the guest makes to `port` on the host. Replaces any handler already listening
+    /// on `port`.
+    pub fn add_listener(&mut self, port: u32, handler: VsockConnectHandler) {
+        self.muxer.add_listener(port, handler);
+    }
+
+    /// Whether to send the guest the host's time whenever the host's clock jumps, such as after
+    /// the host slept, and every minute besides. The guest's init sets its clock from it. On by
+    /// default on macOS, where the guest's clock stops while the host sleeps.
+    pub fn set_clock_resync(&mut self, enabled: bool) {
+        self.muxer.set_clock_resync(enabled);
+    }
+
     /// Walk the driver-provided RX queue buffers and attempt to fill them up with any data that we
     /// have pending. Return `true` if descriptors have been added to the used ring, and `false`
     /// otherwise.
@@ -277,3 +305,9 @@ impl VirtioDevice for Vsock {
         self.device_state.is_activated()
     }
 }
+
+impl VmmExitObserver for Vsock {
+    fn on_vmm_exit(&mut self, _exit_code: i32) {
+        self.muxer.abort_connections();
+    }
+}
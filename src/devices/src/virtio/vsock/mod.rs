@@ -14,7 +14,7 @@ mod muxer_thread;
 mod packet;
 mod proxy;
 mod reaper;
-#[cfg(target_os = "macos")]
+mod stream;
 mod timesync;
 mod tsi_dgram;
 mod tsi_stream;
@@ -23,6 +23,8 @@ mod unix;
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::defs::TsiFlags;
 pub use self::device::Vsock;
+pub use self::proxy::{VsockConnInfo, VsockConnState};
+pub use self::stream::{VsockConnectHandler, VsockStream};
 
 use bitflags::bitflags;
 use vm_memory::GuestMemoryError;
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
-use std::os::unix::io::RawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
 use super::super::Queue as VirtQueue;
@@ -9,9 +12,9 @@ use super::defs::uapi;
 use super::muxer_rxq::{rx_to_pkt, MuxerRxQ};
 use super::muxer_thread::MuxerThread;
 use super::packet::{TsiConnectReq, TsiGetnameRsp, VsockPacket};
-use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate};
+use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate, VsockConnInfo};
 use super::reaper::ReaperThread;
-#[cfg(target_os = "macos")]
+use super::stream::{VsockConnectHandler, VsockStream};
 use super::timesync::TimesyncThread;
 use super::tsi_dgram::TsiDgramProxy;
 use super::tsi_stream::TsiStreamProxy;
@@ -107,6 +110,9 @@ pub struct VsockMuxer {
     proxy_map: ProxyMap,
     reaper_sender: Option<Sender<u64>>,
     unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    listeners: HashMap<u32, VsockConnectHandler>,
+    refused: AtomicU64,
+    clock_resync: bool,
     tsi_flags: TsiFlags,
 }
 
@@ -128,10 +134,49 @@ impl VsockMuxer {
             proxy_map: Arc::new(RwLock::new(HashMap::new())),
             reaper_sender: None,
             unix_ipc_port_map,
+            listeners: HashMap::new(),
+            refused: AtomicU64::new(0),
+            clock_resync: cfg!(target_os = "macos"),
             tsi_flags,
         }
     }
 
+    /// Has `handler` called with a stream for every connection the guest makes to `port`.
+    pub(crate) fn add_listener(&mut self, port: u32, handler: VsockConnectHandler) {
+        self.listeners.insert(port, handler);
+    }
+
+    /// Whether to send the guest the host's time when the host's clock jumps, such as after the
+    /// host slept. Takes effect on activation.
+    pub(crate) fn set_clock_resync(&mut self, enabled: bool) {
+        self.clock_resync = enabled;
+    }
+
+    /// Closes the host side of every connection, so that whoever holds the other end sees it end.
+    pub(crate) fn abort_connections(&self) {
+        for proxy in self.proxy_map.read().unwrap().values() {
+            proxy.lock().unwrap().abort();
+        }
+    }
+
+    /// Describes the stream connections between the guest and the host, as they are now.
+    pub(crate) fn connections(&self) -> Vec<VsockConnInfo> {
+        let mut conns: Vec<_> = self
+            .proxy_map
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|proxy| proxy.lock().unwrap().conn_info())
+            .collect();
+        conns.sort_by_key(|conn| (conn.local_port, conn.peer_port));
+        conns
+    }
+
+    /// How many connections the guest asked for to ports nothing on the host listens on.
+    pub(crate) fn refused_connections(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
     pub(crate) fn activate(
         &mut self,
         mem: GuestMemoryMmap,
@@ -142,8 +187,7 @@ impl VsockMuxer {
         self.mem = Some(mem.clone());
         self.interrupt = Some(interrupt.clone());
 
-        #[cfg(target_os = "macos")]
-        {
+        if self.clock_resync {
             let timesync =
                 TimesyncThread::new(self.cid, mem.clone(), queue.clone(), interrupt.clone());
             timesync.run();
@@ -545,44 +589,115 @@ impl VsockMuxer {
             if let Some(update) = proxy.lock().unwrap().confirm_connect(pkt) {
                 self.process_proxy_update(id, update);
             }
-        } else if let Some(ref mut ipc_map) = &mut self.unix_ipc_port_map {
-            if let Some((path, listen)) = ipc_map.get(&pkt.dst_port()) {
-                let mem = self.mem.as_ref().unwrap();
-                let queue = self.queue.as_ref().unwrap();
-                if *listen {
-                    warn!("Attempting to connect a socket that is listening, sending rst");
+        } else if let Some(handler) = self.listeners.get(&pkt.dst_port()) {
+            let (ours, theirs) = match UnixStream::pair() {
+                Ok(pair) => pair,
+                Err(e) => {
+                    error!(
+                        "can't create a stream for vsock port {}: {e}",
+                        pkt.dst_port()
+                    );
                     let rx = MuxerRx::Reset {
                         local_port: pkt.dst_port(),
                         peer_port: pkt.src_port(),
                     };
-                    push_packet(self.cid, rx, &self.rxq, queue, mem);
+                    push_packet(
+                        self.cid,
+                        rx,
+                        &self.rxq,
+                        self.queue.as_ref().unwrap(),
+                        self.mem.as_ref().unwrap(),
+                    );
                     return;
                 }
-                let rxq = self.rxq.clone();
+            };
 
-                let mut unix = UnixProxy::new(
-                    id,
-                    self.cid,
-                    pkt.dst_port(),
-                    pkt.src_port(),
-                    mem.clone(),
-                    queue.clone(),
-                    rxq,
-                    path.to_path_buf(),
-                )
-                .unwrap();
-                let tsi = TsiConnectReq {
-                    peer_port: 0,
-                    addr: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0).into(),
-                };
-                let update = unix.connect(pkt, tsi);
-                unix.confirm_connect(pkt);
-                proxy_map.insert(id, Mutex::new(Box::new(unix)));
-                self.process_proxy_update(id, update);
+            let mut unix = UnixProxy::new_connected(
+                id,
+                self.cid,
+                pkt.dst_port(),
+                pkt.src_port(),
+                OwnedFd::from(ours),
+                self.mem.clone().unwrap(),
+                self.queue.clone().unwrap(),
+                self.rxq.clone(),
+            );
+            unix.confirm_connect(pkt);
+            let update = ProxyUpdate {
+                polling: Some((id, unix.as_raw_fd(), EventSet::IN)),
+                signal_queue: true,
+                ..Default::default()
+            };
+            proxy_map.insert(id, Mutex::new(Box::new(unix)));
+            self.process_proxy_update(id, update);
+
+            let handler = handler.clone();
+            let stream = VsockStream::new(pkt.dst_port(), pkt.src_port(), theirs);
+            if let Err(e) = std::thread::Builder::new()
+                .name(format!("vsock port {}", pkt.dst_port()))
+                .spawn(move || handler(stream))
+            {
+                // The stream was dropped with the closure, so the guest gets a reset.
+                error!("can't start handler for vsock port {}: {e}", pkt.dst_port());
             }
+        } else if let Some((path, listen)) = self
+            .unix_ipc_port_map
+            .as_ref()
+            .and_then(|ipc_map| ipc_map.get(&pkt.dst_port()))
+        {
+            if *listen {
+                warn!("Attempting to connect a socket that is listening, sending rst");
+                self.refuse(pkt);
+                return;
+            }
+            let mem = self.mem.as_ref().unwrap();
+            let queue = self.queue.as_ref().unwrap();
+            let rxq = self.rxq.clone();
+
+            let mut unix = UnixProxy::new(
+                id,
+                self.cid,
+                pkt.dst_port(),
+                pkt.src_port(),
+                mem.clone(),
+                queue.clone(),
+                rxq,
+                path.to_path_buf(),
+            )
+            .unwrap();
+            let tsi = TsiConnectReq {
+                peer_port: 0,
+                addr: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0).into(),
+            };
+            let update = unix.connect(pkt, tsi);
+            unix.confirm_connect(pkt);
+            proxy_map.insert(id, Mutex::new(Box::new(unix)));
+            self.process_proxy_update(id, update);
+        } else {
+            debug!(
+                "nothing listens on vsock port {}, sending rst",
+                pkt.dst_port()
+            );
+            self.refuse(pkt);
         }
     }
 
+    // Resets a connection the guest asked for, counting it as refused.
+    fn refuse(&self, pkt: &VsockPacket) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        let rx = MuxerRx::Reset {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+        };
+        push_packet(
+            self.cid,
+            rx,
+            &self.rxq,
+            self.queue.as_ref().unwrap(),
+            self.mem.as_ref().unwrap(),
+        );
+    }
+
     fn process_op_response(&self, pkt: &VsockPacket) {
         debug!("OP_RESPONSE");
         let id: u64 = ((pkt.src_port() as u64) << 32) | (pkt.dst_port() as u64);
@@ -711,3 +826,112 @@ impl VsockMuxer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::super::packet::VSOCK_PKT_HDR_SIZE;
+    use super::super::proxy::VsockConnState;
+    use crate::virtio::queue::tests::VirtQueue;
+
+    // A muxer with the queues and memory it would get on activation, but none of its threads.
+    fn muxer(mem: &GuestMemoryMmap, rx: &VirtQueue) -> VsockMuxer {
+        let mut muxer = VsockMuxer::new(3, None, None, TsiFlags::empty());
+        muxer.mem = Some(mem.clone());
+        muxer.queue = Some(Arc::new(Mutex::new(rx.create_queue())));
+        muxer
+    }
+
+    #[test]
+    fn connections_snapshot() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rx = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let muxer = muxer(&mem, &rx);
+
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let established = UnixProxy::new_connected(
+            1,
+            3,
+            1234,
+            5000,
+            OwnedFd::from(ours),
+            mem.clone(),
+            muxer.queue.clone().unwrap(),
+            muxer.rxq.clone(),
+        );
+        let (ours, _theirs_too) = UnixStream::pair().unwrap();
+        let requesting = UnixProxy::new_reverse(
+            2,
+            3,
+            1024,
+            6000,
+            OwnedFd::from(ours),
+            mem.clone(),
+            muxer.queue.clone().unwrap(),
+            muxer.rxq.clone(),
+        );
+        {
+            let mut proxy_map = muxer.proxy_map.write().unwrap();
+            proxy_map.insert(1, Mutex::new(Box::new(established)));
+            proxy_map.insert(2, Mutex::new(Box::new(requesting)));
+        }
+
+        let conns = muxer.connections();
+        assert_eq!(conns.len(), 2);
+        assert_eq!(conns[0].local_port, 1024);
+        assert_eq!(conns[0].peer_port, 6000);
+        assert_eq!(conns[0].state, VsockConnState::Requesting);
+        assert_eq!(
+            conns[1],
+            VsockConnInfo {
+                local_cid: uapi::VSOCK_HOST_CID,
+                local_port: 1234,
+                peer_cid: 3,
+                peer_port: 5000,
+                state: VsockConnState::Established,
+                peer_buf_alloc: 0,
+                peer_fwd_cnt: 0,
+                rx_cnt: 0,
+                tx_cnt: 0,
+                rx_bytes: 0,
+                tx_bytes: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_port() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rx = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let tx = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut muxer = muxer(&mem, &rx);
+
+        // The guest's port 5000 asks to connect to port 1234, which nothing listens on.
+        let hdr = 0x4000;
+        mem.write_obj(5000u32, GuestAddress(hdr + 16)).unwrap();
+        mem.write_obj(1234u32, GuestAddress(hdr + 20)).unwrap();
+        mem.write_obj(uapi::VSOCK_OP_REQUEST, GuestAddress(hdr + 30))
+            .unwrap();
+        tx.dtable[0].set(hdr, VSOCK_PKT_HDR_SIZE as u32, 0, 0);
+        tx.avail.ring[0].set(0);
+        tx.avail.idx.set(1);
+        let mut queue = tx.create_queue();
+        let head = queue.pop(&mem).unwrap();
+        let pkt = VsockPacket::from_tx_virtq_head(&head).unwrap();
+
+        muxer.process_op_request(&pkt);
+
+        assert_eq!(muxer.refused_connections(), 1);
+        assert!(muxer.connections().is_empty());
+        assert!(matches!(
+            muxer.rxq.lock().unwrap().pop(),
+            Some(MuxerRx::Reset {
+                local_port: 1234,
+                peer_port: 5000,
+            })
+        ));
+    }
+}
@@ -521,7 +521,9 @@ impl VsockPacket {
             defs::LINUX_AF_INET6 => {
                 debug!("parse_address: AF_INET6");
                 let in_port: u16 = byte_order::read_be_u16(&buf[2..4]);
-                let flowinfo: u32 = byte_order::read_be_u32(&buf[4..8]);
+                // Like on Linux, flowinfo and scope_id are passed through as the guest laid them
+                // out, without swapping bytes.
+                let flowinfo: u32 = byte_order::read_le_u32(&buf[4..8]);
                 let in6_addr = Ipv6Addr::new(
                     byte_order::read_be_u16(&buf[8..10]),
                     byte_order::read_be_u16(&buf[10..12]),
@@ -532,7 +534,7 @@ impl VsockPacket {
                     byte_order::read_be_u16(&buf[20..22]),
                     byte_order::read_be_u16(&buf[22..24]),
                 );
-                let scope_id: u32 = byte_order::read_be_u32(&buf[24..28]);
+                let scope_id: u32 = byte_order::read_le_u32(&buf[24..28]);
                 Some(SocketAddrV6::new(in6_addr, in_port, flowinfo, scope_id).into())
             }
             defs::LINUX_AF_UNIX => {
@@ -706,3 +708,65 @@ impl VsockPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv6Addr, SocketAddrV6};
+
+    // Lays out a Linux `struct sockaddr_in6`, as the guest sends it.
+    fn sockaddr_in6(addr: Ipv6Addr, port: u16, flowinfo: u32, scope_id: u32) -> [u8; 28] {
+        let mut buf = [0u8; 28];
+        byte_order::write_le_u16(&mut buf[0..], defs::LINUX_AF_INET6);
+        byte_order::write_be_u16(&mut buf[2..], port);
+        byte_order::write_le_u32(&mut buf[4..], flowinfo);
+        buf[8..24].copy_from_slice(&addr.octets());
+        byte_order::write_le_u32(&mut buf[24..], scope_id);
+        buf
+    }
+
+    #[test]
+    fn parse_inet_address() {
+        let mut buf = [0u8; 16];
+        byte_order::write_le_u16(&mut buf[0..], defs::LINUX_AF_INET);
+        byte_order::write_be_u16(&mut buf[2..], 8080);
+        buf[4..8].copy_from_slice(&[127, 0, 0, 1]);
+
+        let addr = VsockPacket::parse_address(&buf, buf.len() as u32).unwrap();
+        let sin = addr.as_sockaddr_in().unwrap();
+        assert_eq!(sin.ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(sin.port(), 8080);
+    }
+
+    #[test]
+    fn parse_inet6_address() {
+        let ip = "2001:db8::53".parse().unwrap();
+        let buf = sockaddr_in6(ip, 53, 0x1234, 3);
+
+        let addr = VsockPacket::parse_address(&buf, buf.len() as u32).unwrap();
+        let sin6 = addr.as_sockaddr_in6().unwrap();
+        assert_eq!(sin6.ip(), ip);
+        assert_eq!(sin6.port(), 53);
+        assert_eq!(sin6.flowinfo(), 0x1234);
+        assert_eq!(sin6.scope_id(), 3);
+        assert_eq!(
+            SocketAddrV6::from(*sin6),
+            SocketAddrV6::new(ip, 53, 0x1234, 3)
+        );
+
+        // v4-mapped addresses are kept as they are, for the host's AF_INET6 socket to handle.
+        let mapped = Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped();
+        let buf = sockaddr_in6(mapped, 443, 0, 0);
+        let addr = VsockPacket::parse_address(&buf, buf.len() as u32).unwrap();
+        assert_eq!(addr.as_sockaddr_in6().unwrap().ip(), mapped);
+    }
+
+    #[test]
+    fn parse_unsupported_address() {
+        let mut buf = [0u8; 16];
+        // AF_PACKET
+        byte_order::write_le_u16(&mut buf[0..], 17);
+        assert!(VsockPacket::parse_address(&buf, buf.len() as u32).is_none());
+    }
+}
@@ -23,6 +23,7 @@ pub enum ProxyError {
     InvalidFamily,
     SettingReuseAddr(nix::errno::Errno),
     SettingReusePort(nix::errno::Errno),
+    SettingV6Only(nix::errno::Errno),
 }
 
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
@@ -37,6 +38,57 @@ pub enum ProxyStatus {
     WaitingOnAccept,
 }
 
+impl ProxyStatus {
+    /// Where a proxy in this state is in the life of its vsock connection, if it has one.
+    pub fn conn_state(self) -> Option<VsockConnState> {
+        match self {
+            ProxyStatus::Connecting | ProxyStatus::ReverseInit => Some(VsockConnState::Requesting),
+            ProxyStatus::Connected | ProxyStatus::WaitingCreditUpdate => {
+                Some(VsockConnState::Established)
+            }
+            ProxyStatus::Closed => Some(VsockConnState::Closing),
+            ProxyStatus::Idle | ProxyStatus::Listening | ProxyStatus::WaitingOnAccept => None,
+        }
+    }
+}
+
+/// Where a vsock stream connection is in its life.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VsockConnState {
+    /// One side asked to connect and the other hasn't answered yet.
+    Requesting,
+    /// Data can flow both ways.
+    Established,
+    /// The host end closed and the connection is being torn down.
+    Closing,
+}
+
+/// A vsock stream connection as it was at one point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VsockConnInfo {
+    /// The host's CID, always 2.
+    pub local_cid: u64,
+    /// The host's port of the connection.
+    pub local_port: u32,
+    /// The guest's CID.
+    pub peer_cid: u64,
+    /// The guest's port of the connection.
+    pub peer_port: u32,
+    pub state: VsockConnState,
+    /// The buffer space the guest gave the connection.
+    pub peer_buf_alloc: u32,
+    /// Bytes the guest has taken out of that space, wrapping at 2^32.
+    pub peer_fwd_cnt: u32,
+    /// Bytes sent to the guest, wrapping at 2^32, as used for credit.
+    pub rx_cnt: u32,
+    /// Bytes from the guest passed on to the host end, wrapping at 2^32, as used for credit.
+    pub tx_cnt: u32,
+    /// Bytes sent to the guest.
+    pub rx_bytes: u64,
+    /// Bytes from the guest passed on to the host end.
+    pub tx_bytes: u64,
+}
+
 #[derive(Default)]
 pub enum ProxyRemoval {
     #[default]
@@ -93,6 +145,12 @@ pub trait Proxy: Send + AsRawFd {
     fn enqueue_accept(&mut self) {}
     fn push_accept_rsp(&self, _result: i32) {}
     fn shutdown(&mut self, _pkt: &VsockPacket) {}
+    /// Closes the host side of the connection because the VM is going away.
+    fn abort(&mut self) {}
+    /// Describes the proxy's vsock stream connection, if it has one.
+    fn conn_info(&self) -> Option<VsockConnInfo> {
+        None
+    }
     fn release(&mut self) -> ProxyUpdate;
     fn process_event(&mut self, evset: EventSet) -> ProxyUpdate;
 }
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Called on a thread of its own for every connection the guest makes to a port the host listens
+/// on.
+pub type VsockConnectHandler = Arc<dyn Fn(VsockStream) + Send + Sync>;
+
+/// The host end of a connection the guest made over virtio-vsock.
+///
+/// Data goes both ways as on a TCP stream. Writes block, or fail with `WouldBlock` in
+/// non-blocking mode, while the guest has no room for more. Reads return 0 once the guest shuts
+/// down its sending side or the connection is reset, and dropping the stream resets the
+/// connection in the guest.
+pub struct VsockStream {
+    port: u32,
+    peer_port: u32,
+    inner: UnixStream,
+}
+
+impl VsockStream {
+    pub(crate) fn new(port: u32, peer_port: u32, inner: UnixStream) -> Self {
+        VsockStream {
+            port,
+            peer_port,
+            inner,
+        }
+    }
+
+    /// The port the guest connected to.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// The guest's port of the connection.
+    pub fn peer_port(&self) -> u32 {
+        self.peer_port
+    }
+
+    /// Creates another handle to the same connection, so that it can be read and written from
+    /// different threads.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(VsockStream {
+            port: self.port,
+            peer_port: self.peer_port,
+            inner: self.inner.try_clone()?,
+        })
+    }
+
+    /// Shuts down the reading side, the writing side or both. The guest sees the end of the
+    /// stream once the writing side is shut down.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+}
+
+impl fmt::Debug for VsockStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VsockStream")
+            .field("port", &self.port)
+            .field("peer_port", &self.peer_port)
+            .field("fd", &self.inner.as_raw_fd())
+            .finish()
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Read for &VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for &VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.inner).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for VsockStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
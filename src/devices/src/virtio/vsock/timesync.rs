@@ -18,6 +18,9 @@ pub struct TimesyncThread {
     mem: GuestMemoryMmap,
     queue_mutex: Arc<Mutex<VirtQueue>>,
     interrupt: InterruptTransport,
+    // Host wall clock times, in nanoseconds.
+    last_update: Option<u64>,
+    last_awake: u64,
 }
 
 impl TimesyncThread {
@@ -32,49 +35,69 @@ impl TimesyncThread {
             mem,
             queue_mutex,
             interrupt,
+            last_update: None,
+            last_awake: 0,
         }
     }
 
-    fn send_time(&self, time: u64) {
+    // Returns false if the guest had no buffer to take the time.
+    fn send_time(&self, time: u64) -> bool {
         let mut queue = self.queue_mutex.lock().unwrap();
-        if let Some(head) = queue.pop(&self.mem) {
-            if let Ok(mut pkt) = VsockPacket::from_rx_virtq_head(&head) {
-                pkt.set_op(uapi::VSOCK_OP_RW)
-                    .set_src_cid(uapi::VSOCK_HOST_CID)
-                    .set_dst_cid(self.cid)
-                    .set_src_port(TSYNC_PORT)
-                    .set_dst_port(TSYNC_PORT)
-                    .set_type(uapi::VSOCK_TYPE_DGRAM);
-
-                pkt.write_time_sync(time);
-                pkt.set_len(pkt.buf().unwrap().len() as u32);
-                if let Err(e) =
-                    queue.add_used(&self.mem, head.index, pkt.hdr().len() as u32 + pkt.len())
-                {
-                    error!("failed to add used elements to the queue: {e:?}");
-                }
-                self.interrupt.signal_used_queue();
+        let Some(head) = queue.pop(&self.mem) else {
+            return false;
+        };
+        if let Ok(mut pkt) = VsockPacket::from_rx_virtq_head(&head) {
+            pkt.set_op(uapi::VSOCK_OP_RW)
+                .set_src_cid(uapi::VSOCK_HOST_CID)
+                .set_dst_cid(self.cid)
+                .set_src_port(TSYNC_PORT)
+                .set_dst_port(TSYNC_PORT)
+                .set_type(uapi::VSOCK_TYPE_DGRAM);
+
+            pkt.write_time_sync(time);
+            pkt.set_len(pkt.buf().unwrap().len() as u32);
+            if let Err(e) =
+                queue.add_used(&self.mem, head.index, pkt.hdr().len() as u32 + pkt.len())
+            {
+                error!("failed to add used elements to the queue: {e:?}");
             }
+            self.interrupt.signal_used_queue();
+        }
+        true
+    }
+
+    /// Sends the guest the host's time `now` if its clock may have drifted: the first time,
+    /// every UPDATE_INTERVAL, and whenever the host's clock jumped since the previous call.
+    /// Returns true if the guest got the time.
+    fn tick(&mut self, now: u64) -> bool {
+        /*
+         * A nap more than 3 times longer than expected means the host
+         * was asleep, and a clock going backwards means it was set. The
+         * guest's clock is off by as much in both cases.
+         */
+        let jumped = now < self.last_awake || now - self.last_awake >= SLEEP_NSECS * 3;
+        let due = match self.last_update {
+            None => true,
+            Some(last_update) => jumped || now.saturating_sub(last_update) >= UPDATE_INTERVAL,
+        };
+        self.last_awake = now;
+
+        // If the guest has no buffers yet, keep trying on every tick rather than waiting for
+        // the next interval.
+        if due && self.send_time(now) {
+            self.last_update = Some(now);
+            return true;
+        }
+        if jumped {
+            self.last_update = None;
         }
+        false
     }
 
     fn work(&mut self) {
-        let mut last_update = 0u64;
-        let mut last_awake = utils::time::get_time(utils::time::ClockType::Real);
+        self.last_awake = utils::time::get_time(utils::time::ClockType::Real);
         loop {
-            let now = utils::time::get_time(utils::time::ClockType::Real);
-            /*
-             * We send a time sync packet if we slept for 3 times more
-             * nanoseconds than expected (which is an indication the
-             * system forced us to take a long nap), or if UPDATE_INTERVAL
-             * has been reached.
-             */
-            if (now - last_awake) >= (SLEEP_NSECS * 3) || (now - last_update) >= UPDATE_INTERVAL {
-                self.send_time(now);
-                last_update = now;
-            }
-
-            last_awake = utils::time::get_time(utils::time::ClockType::Real);
+            self.tick(utils::time::get_time(utils::time::ClockType::Real));
             thread::sleep(time::Duration::from_nanos(SLEEP_NSECS));
         }
     }
@@ -86,3 +109,75 @@ impl TimesyncThread {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::super::packet::VSOCK_PKT_HDR_SIZE;
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::queue::tests::VirtQueue as TestQueue;
+
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+    const HDR_DST_PORT: u64 = 20;
+    const SECOND: u64 = 1000 * 1000 * 1000;
+
+    #[test]
+    fn resync_after_clock_jump() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rx = TestQueue::new(GuestAddress(0), &mem, 16);
+        let interrupt =
+            InterruptTransport::new(DummyIrqChip::new().into(), "vsock".into()).unwrap();
+        let mut timesync = TimesyncThread::new(
+            3,
+            mem.clone(),
+            Arc::new(Mutex::new(rx.create_queue())),
+            interrupt,
+        );
+
+        let post_rx_buffer = |index: u16, addr: u64| {
+            rx.dtable[index as usize].set(addr, 0x100, VIRTQ_DESC_F_WRITE, 0);
+            rx.avail.ring[index as usize].set(index);
+            rx.avail.idx.set(index + 1);
+        };
+        let sent_time = |addr: u64| {
+            assert_eq!(
+                mem.read_obj::<u32>(GuestAddress(addr + HDR_DST_PORT))
+                    .unwrap(),
+                TSYNC_PORT
+            );
+            mem.read_obj::<u64>(GuestAddress(addr + VSOCK_PKT_HDR_SIZE as u64))
+                .unwrap()
+        };
+
+        // The guest has no buffers yet, so the first update waits for one.
+        let boot = 1000 * SECOND;
+        assert!(!timesync.tick(boot));
+        post_rx_buffer(0, 0x4000);
+        let now = boot + SLEEP_NSECS;
+        assert!(timesync.tick(now));
+        assert_eq!(rx.used.idx.get(), 1);
+        assert_eq!(sent_time(0x4000), now);
+
+        // Nothing to do while the host keeps time.
+        post_rx_buffer(1, 0x4100);
+        let now = now + SLEEP_NSECS;
+        assert!(!timesync.tick(now));
+        assert_eq!(rx.used.idx.get(), 1);
+
+        // The host slept for an hour, so the guest's clock is an hour behind.
+        let now = now + 3600 * SECOND;
+        assert!(timesync.tick(now));
+        assert_eq!(rx.used.idx.get(), 2);
+        assert_eq!(sent_time(0x4100), now);
+
+        // A clock set backwards needs fixing as well.
+        post_rx_buffer(2, 0x4200);
+        let now = now - 60 * SECOND;
+        assert!(timesync.tick(now));
+        assert_eq!(rx.used.idx.get(), 3);
+        assert_eq!(sent_time(0x4200), now);
+    }
+}
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 use std::num::Wrapping;
 use std::os::fd::OwnedFd;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -7,8 +7,8 @@ use std::sync::{Arc, Mutex};
 
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
-    bind, connect, getpeername, recv, send, sendto, socket, AddressFamily, MsgFlags, SockFlag,
-    SockType, SockaddrIn, SockaddrLike, SockaddrStorage,
+    bind, connect, getpeername, recv, send, sendto, setsockopt, socket, sockopt, AddressFamily,
+    MsgFlags, SockFlag, SockType, SockaddrLike, SockaddrStorage,
 };
 
 #[cfg(target_os = "macos")]
@@ -29,6 +29,7 @@ use vm_memory::GuestMemoryMmap;
 pub struct TsiDgramProxy {
     pub id: u64,
     cid: u64,
+    family: AddressFamily,
     local_port: u32,
     peer_port: u32,
     fd: OwnedFd,
@@ -78,6 +79,12 @@ impl TsiDgramProxy {
             Err(e) => error!("couldn't obtain fd flags id={id}, err={e}"),
         };
 
+        // Guests expect v4-mapped addresses to work on AF_INET6 sockets, whatever the host's
+        // net.ipv6.bindv6only says.
+        if family == AddressFamily::Inet6 {
+            setsockopt(&fd, sockopt::Ipv6V6Only, &false).map_err(ProxyError::SettingV6Only)?;
+        }
+
         #[cfg(target_os = "macos")]
         {
             // nix doesn't provide an abstraction for SO_NOSIGPIPE, fall back to libc.
@@ -96,6 +103,7 @@ impl TsiDgramProxy {
         Ok(TsiDgramProxy {
             id,
             cid,
+            family,
             local_port: 0,
             peer_port,
             fd,
@@ -339,7 +347,14 @@ impl Proxy for TsiDgramProxy {
 
         self.sendto_addr = Some(req.addr);
         if !self.listening {
-            match bind(self.fd.as_raw_fd(), &SockaddrIn::new(0, 0, 0, 0, 0)) {
+            // Bind to the wildcard address of the socket's own family, so that replies to
+            // datagrams sent to IPv6 peers (such as DNS servers) can come back.
+            let any: SockaddrStorage = if self.family == AddressFamily::Inet6 {
+                SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into()
+            } else {
+                SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into()
+            };
+            match bind(self.fd.as_raw_fd(), &any) {
                 Ok(_) => {
                     self.listening = true;
                     update.polling = Some((self.id, self.fd.as_raw_fd(), EventSet::IN));
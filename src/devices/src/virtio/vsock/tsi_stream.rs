@@ -31,7 +31,7 @@ use super::packet::{
     TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
 };
 use super::proxy::{
-    NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
+    NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt, VsockConnInfo,
 };
 use utils::epoll::EventSet;
 
@@ -56,6 +56,8 @@ pub struct TsiStreamProxy {
     peer_buf_alloc: u32,
     peer_fwd_cnt: Wrapping<u32>,
     push_cnt: Wrapping<u32>,
+    rx_bytes: u64,
+    tx_bytes: u64,
     pending_accepts: u64,
     unixsock_path: Option<PathBuf>,
 }
@@ -102,6 +104,12 @@ impl TsiStreamProxy {
             setsockopt(&fd, sockopt::ReusePort, &true).map_err(ProxyError::SettingReusePort)?;
         }
 
+        // Guests expect v4-mapped addresses to work on AF_INET6 sockets, whatever the host's
+        // net.ipv6.bindv6only says.
+        if family == AddressFamily::Inet6 {
+            setsockopt(&fd, sockopt::Ipv6V6Only, &false).map_err(ProxyError::SettingV6Only)?;
+        }
+
         #[cfg(target_os = "macos")]
         {
             // nix doesn't provide an abstraction for SO_NOSIGPIPE, fall back to libc.
@@ -136,6 +144,8 @@ impl TsiStreamProxy {
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
             pending_accepts: 0,
             unixsock_path: None,
         })
@@ -174,6 +184,8 @@ impl TsiStreamProxy {
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
             pending_accepts: 0,
             unixsock_path: None,
         }
@@ -210,7 +222,7 @@ impl TsiStreamProxy {
             } else if let Some(sin6) = req.addr.as_sockaddr_in6() {
                 debug!("sockaddr is ipv6");
                 if let Some(port) = port_map.get(&sin6.port()) {
-                    SocketAddrV6::new(sin6.ip(), *port, sin6.flowinfo(), sin6.flowinfo()).into()
+                    SocketAddrV6::new(sin6.ip(), *port, sin6.flowinfo(), sin6.scope_id()).into()
                 } else {
                     req.addr
                 }
@@ -339,6 +351,7 @@ impl TsiStreamProxy {
                     }
                     RecvPkt::Read(cnt) => {
                         self.rx_cnt += Wrapping(cnt as u32);
+                        self.rx_bytes += cnt as u64;
                         self.init_data_pkt(&mut pkt);
                         pkt.set_len(cnt as u32);
                         pkt.hdr().len() + cnt
@@ -599,6 +612,7 @@ impl Proxy for TsiStreamProxy {
                         error!("couldn't set everything: buf={}, sent={}", buf.len(), sent);
                     }
                     self.tx_cnt += Wrapping(sent as u32);
+                    self.tx_bytes += sent as u64;
                     sent as i32
                 }
                 Err(err) => {
@@ -784,6 +798,22 @@ impl Proxy for TsiStreamProxy {
         }
     }
 
+    fn conn_info(&self) -> Option<VsockConnInfo> {
+        Some(VsockConnInfo {
+            local_cid: uapi::VSOCK_HOST_CID,
+            local_port: self.local_port,
+            peer_cid: self.cid,
+            peer_port: self.peer_port,
+            state: self.status.conn_state()?,
+            peer_buf_alloc: self.peer_buf_alloc,
+            peer_fwd_cnt: self.peer_fwd_cnt.0,
+            rx_cnt: self.rx_cnt.0,
+            tx_cnt: self.tx_cnt.0,
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+        })
+    }
+
     fn release(&mut self) -> ProxyUpdate {
         debug!(
             "release: id={}, tx_cnt={}, last_tx_cnt={}",
@@ -901,3 +931,89 @@ impl Drop for TsiStreamProxy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv6Addr, TcpListener};
+
+    use utils::byte_order;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::super::packet::VSOCK_PKT_HDR_SIZE;
+    use crate::virtio::queue::tests::VirtQueue;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+
+    #[test]
+    fn connect_inet6_loopback() {
+        let listener = match TcpListener::bind("[::1]:0") {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("skipping, no IPv6 loopback on this host: {e}");
+                return;
+            }
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let tx = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let rx = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+
+        // The guest asks to connect to [::1]:port: the peer port, the address length, then its
+        // struct sockaddr_in6.
+        let (hdr, data) = (0x4000, 0x5000);
+        let mut req = [0u8; 36];
+        byte_order::write_le_u32(&mut req[0..], 1234);
+        byte_order::write_le_u32(&mut req[4..], 28);
+        byte_order::write_le_u16(&mut req[8..], defs::LINUX_AF_INET6);
+        byte_order::write_be_u16(&mut req[10..], port);
+        req[16..32].copy_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        mem.write_slice(&req, GuestAddress(data)).unwrap();
+        mem.write_obj(req.len() as u32, GuestAddress(hdr + 24))
+            .unwrap();
+        tx.dtable[0].set(hdr, VSOCK_PKT_HDR_SIZE as u32, VIRTQ_DESC_F_NEXT, 1);
+        tx.dtable[1].set(data, req.len() as u32, 0, 0);
+        tx.avail.ring[0].set(0);
+        tx.avail.idx.set(1);
+
+        let mut queue = tx.create_queue();
+        let head = queue.pop(&mem).unwrap();
+        let pkt = VsockPacket::from_tx_virtq_head(&head).unwrap();
+        let req = pkt.read_connect_req().unwrap();
+
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = TsiStreamProxy::new(
+            1,
+            3,
+            defs::LINUX_AF_INET6,
+            1024,
+            1234,
+            1025,
+            mem.clone(),
+            Arc::new(Mutex::new(rx.create_queue())),
+            Arc::clone(&rxq),
+        )
+        .unwrap();
+        proxy.connect(&pkt, req);
+
+        let (_stream, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), Ipv6Addr::LOCALHOST);
+        if proxy.status == ProxyStatus::Connecting {
+            proxy.process_event(EventSet::OUT);
+        }
+        assert_eq!(proxy.status, ProxyStatus::Connected);
+
+        // The guest has no receive buffers, so the response waits in the muxer's queue.
+        let rsp = rxq.lock().unwrap().pop();
+        assert!(matches!(
+            rsp,
+            Some(MuxerRx::ConnResponse {
+                peer_port: 1025,
+                result: 0,
+                ..
+            })
+        ));
+    }
+}
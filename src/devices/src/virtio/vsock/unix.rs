@@ -22,7 +22,7 @@ use super::super::Queue as VirtQueue;
 use super::muxer::{push_packet, MuxerRx};
 use super::muxer_rxq::MuxerRxQ;
 use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
-use super::proxy::{NewProxyType, Proxy, ProxyError, ProxyStatus, ProxyUpdate};
+use super::proxy::{NewProxyType, Proxy, ProxyError, ProxyStatus, ProxyUpdate, VsockConnInfo};
 use utils::epoll::EventSet;
 
 use vm_memory::GuestMemoryMmap;
@@ -44,6 +44,8 @@ pub struct UnixProxy {
     tx_cnt: Wrapping<u32>,
     last_tx_cnt_sent: Wrapping<u32>,
     push_cnt: Wrapping<u32>,
+    rx_bytes: u64,
+    tx_bytes: u64,
     rx_cnt: Wrapping<u32>,
 }
 
@@ -70,23 +72,26 @@ fn proxy_fd_create(id: u64) -> Result<OwnedFd, ProxyError> {
     };
 
     #[cfg(target_os = "macos")]
-    {
-        // nix doesn't provide an abstraction for SO_NOSIGPIPE, fall back to libc.
-        let option_value: libc::c_int = 1;
-        unsafe {
-            libc::setsockopt(
-                fd.as_raw_fd(),
-                libc::SOL_SOCKET,
-                libc::SO_NOSIGPIPE,
-                &option_value as *const _ as *const libc::c_void,
-                std::mem::size_of_val(&option_value) as libc::socklen_t,
-            )
-        };
-    }
+    set_nosigpipe(&fd);
 
     Ok(fd)
 }
 
+#[cfg(target_os = "macos")]
+fn set_nosigpipe(fd: &OwnedFd) {
+    // nix doesn't provide an abstraction for SO_NOSIGPIPE, fall back to libc.
+    let option_value: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_NOSIGPIPE,
+            &option_value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&option_value) as libc::socklen_t,
+        )
+    };
+}
+
 impl UnixProxy {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -118,6 +123,8 @@ impl UnixProxy {
             tx_cnt: Wrapping(0),
             last_tx_cnt_sent: Wrapping(0),
             push_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
             rx_cnt: Wrapping(0),
         })
     }
@@ -151,6 +158,49 @@ impl UnixProxy {
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            path: Default::default(),
+        }
+    }
+
+    /// Creates a proxy for a connection the guest made to a port with a host listener. `fd` is
+    /// the muxer's end of the stream handed to the listener, so there is nothing to connect to.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_connected(
+        id: u64,
+        cid: u64,
+        local_port: u32,
+        peer_port: u32,
+        fd: OwnedFd,
+        mem: GuestMemoryMmap,
+        queue: Arc<Mutex<VirtQueue>>,
+        rxq: Arc<Mutex<MuxerRxQ>>,
+    ) -> Self {
+        debug!("new_connected: id={id} local_port={local_port} peer_port={peer_port}");
+
+        #[cfg(target_os = "macos")]
+        set_nosigpipe(&fd);
+
+        UnixProxy {
+            id,
+            cid,
+            local_port,
+            peer_port,
+            control_port: 0,
+            fd,
+            status: ProxyStatus::Connected,
+            mem,
+            queue,
+            rxq,
+            rx_cnt: Wrapping(0),
+            tx_cnt: Wrapping(0),
+            last_tx_cnt_sent: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            push_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
             path: Default::default(),
         }
     }
@@ -258,6 +308,7 @@ impl UnixProxy {
                     }
                     RecvPkt::Read(cnt) => {
                         self.rx_cnt += Wrapping(cnt as u32);
+                        self.rx_bytes += cnt as u64;
                         self.init_data_pkt(&mut pkt);
                         pkt.set_len(cnt as u32);
                         pkt.hdr().len() + cnt
@@ -403,6 +454,7 @@ impl Proxy for UnixProxy {
                         error!("couldn't set everything: buf={}, sent={}", buf.len(), sent);
                     }
                     self.tx_cnt += Wrapping(sent as u32);
+                    self.tx_bytes += sent as u64;
                     sent as i32
                 }
                 Err(err) => {
@@ -531,6 +583,26 @@ impl Proxy for UnixProxy {
         }
     }
 
+    fn abort(&mut self) {
+        let _ = shutdown(self.fd.as_raw_fd(), Shutdown::Both);
+    }
+
+    fn conn_info(&self) -> Option<VsockConnInfo> {
+        Some(VsockConnInfo {
+            local_cid: uapi::VSOCK_HOST_CID,
+            local_port: self.local_port,
+            peer_cid: self.cid,
+            peer_port: self.peer_port,
+            state: self.status.conn_state()?,
+            peer_buf_alloc: self.peer_buf_alloc,
+            peer_fwd_cnt: self.peer_fwd_cnt.0,
+            rx_cnt: self.rx_cnt.0,
+            tx_cnt: self.tx_cnt.0,
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+        })
+    }
+
     fn release(&mut self) -> ProxyUpdate {
         debug!(
             "release: id={}, tx_cnt={}, last_tx_cnt={}",
@@ -721,3 +793,124 @@ impl AsRawFd for UnixAcceptorProxy {
         self.fd.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::super::packet::VSOCK_PKT_HDR_SIZE;
+    use super::super::stream::VsockStream;
+    use crate::virtio::queue::tests::VirtQueue;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+    // Offsets of the header fields the test looks at.
+    const HDR_LEN: u64 = 24;
+    const HDR_OP: u64 = 30;
+
+    #[test]
+    fn listener_echo() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let tx = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let rx = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+
+        // The guest's port 5000 sends "hello" to the host's port 1234, with 64 KiB of room.
+        let (hdr, data) = (0x4000, 0x5000);
+        mem.write_obj(5000u32, GuestAddress(hdr + 16)).unwrap();
+        mem.write_obj(1234u32, GuestAddress(hdr + 20)).unwrap();
+        mem.write_obj(5u32, GuestAddress(hdr + HDR_LEN)).unwrap();
+        mem.write_obj(uapi::VSOCK_OP_RW, GuestAddress(hdr + HDR_OP))
+            .unwrap();
+        mem.write_obj(0x10000u32, GuestAddress(hdr + 36)).unwrap();
+        mem.write_slice(b"hello", GuestAddress(data)).unwrap();
+        tx.dtable[0].set(hdr, VSOCK_PKT_HDR_SIZE as u32, VIRTQ_DESC_F_NEXT, 1);
+        tx.dtable[1].set(data, 5, 0, 0);
+        tx.avail.ring[0].set(0);
+        tx.avail.idx.set(1);
+        let mut queue = tx.create_queue();
+        let head = queue.pop(&mem).unwrap();
+        let pkt = VsockPacket::from_tx_virtq_head(&head).unwrap();
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let handler = thread::spawn(move || {
+            let stream = VsockStream::new(1234, 5000, theirs);
+            io::copy(&mut &stream, &mut &stream).unwrap();
+        });
+
+        let rxq = Arc::new(Mutex::new(MuxerRxQ::new()));
+        let mut proxy = UnixProxy::new_connected(
+            1,
+            3,
+            1234,
+            5000,
+            OwnedFd::from(ours),
+            mem.clone(),
+            Arc::new(Mutex::new(rx.create_queue())),
+            Arc::clone(&rxq),
+        );
+
+        // The guest has no receive buffers yet, so the response waits in the muxer's queue.
+        assert!(proxy.confirm_connect(&pkt).is_none());
+        assert!(matches!(
+            rxq.lock().unwrap().pop(),
+            Some(MuxerRx::OpResponse {
+                local_port: 1234,
+                peer_port: 5000,
+            })
+        ));
+
+        proxy.sendmsg(&pkt);
+
+        let post_rx_buffer = |index: u16, addr: u64| {
+            rx.dtable[index as usize].set(addr, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+            rx.avail.ring[index as usize].set(index);
+            rx.avail.idx.set(index + 1);
+        };
+        let wait_for_used = |proxy: &mut UnixProxy, idx: u16| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while rx.used.idx.get() < idx {
+                assert!(Instant::now() < deadline, "nothing for the guest");
+                proxy.process_event(EventSet::IN);
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        post_rx_buffer(0, 0x6000);
+        wait_for_used(&mut proxy, 1);
+        let mut echo = [0u8; 5];
+        mem.read_slice(&mut echo, GuestAddress(0x6000 + VSOCK_PKT_HDR_SIZE as u64))
+            .unwrap();
+        assert_eq!(&echo, b"hello");
+        assert_eq!(
+            mem.read_obj::<u16>(GuestAddress(0x6000 + HDR_OP)).unwrap(),
+            uapi::VSOCK_OP_RW
+        );
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(0x6000 + HDR_LEN)).unwrap(),
+            5
+        );
+
+        // Once the guest is done sending, the handler sees the end of the stream and returns,
+        // which resets the connection.
+        mem.write_obj(uapi::VSOCK_FLAGS_SHUTDOWN_SEND, GuestAddress(hdr + 32))
+            .unwrap();
+        proxy.shutdown(&pkt);
+        handler.join().unwrap();
+
+        post_rx_buffer(1, 0x7000);
+        wait_for_used(&mut proxy, 2);
+        assert_eq!(proxy.status, ProxyStatus::Closed);
+        assert_eq!(
+            mem.read_obj::<u16>(GuestAddress(0x7000 + HDR_OP)).unwrap(),
+            uapi::VSOCK_OP_RST
+        );
+    }
+}
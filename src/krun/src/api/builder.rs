@@ -1,13 +1,21 @@
 //! VM Builder for creating and configuring microVMs using nested builders.
 
-use std::sync::atomic::AtomicI32;
-#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+#[cfg(not(feature = "tee"))]
+use std::path::Path;
 #[cfg(any(feature = "tee", feature = "aws-nitro"))]
 use std::sync::Arc;
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+use std::sync::{Arc, Mutex};
 
+use devices::virtio::console::port_io;
+use devices::virtio::{
+    BootStage, BootTimeline, ConsoleWatch, DEFAULT_CONSOLE_TAIL_SIZE, DEFAULT_PANIC_PATTERN,
+};
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
-use vmm::resources::{VirtioConsoleConfigMode, VmResources};
+use vmm::resources::{PortConfig, VirtioConsoleConfigMode, VmResources};
 use vmm::vmm_config::machine_config::VmConfig;
 use vmm::vmm_config::machine_config::VmConfigError;
 
@@ -16,15 +24,27 @@ use vmm::vmm_config::fs::CustomFsDeviceConfig;
 #[cfg(not(feature = "tee"))]
 use vmm::vmm_config::fs::FsDeviceConfig;
 
+use super::builders::ConsolePortConfig;
+use super::builders::DnsConfig;
+use super::builders::{
+    BalloonBuilder, ConsoleBuilder, ExecBuilder, FsBuilder, KernelBuilder, MachineBuilder, TtyMode,
+    VsockBuilder,
+};
 #[cfg(feature = "blk")]
-use super::builders::DiskBuilder;
+use super::builders::{CacheMode, DiskBuilder, DiskConfig};
+#[cfg(not(feature = "tee"))]
+use super::builders::DaxConfig;
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
 use super::builders::FsConfig;
-use super::builders::{ConsoleBuilder, ExecBuilder, FsBuilder, KernelBuilder, MachineBuilder};
 #[cfg(feature = "net")]
-use super::builders::{NetBuilder, NetConfig};
+use super::builders::{NetBuilder, NetConfig, NetOptions};
 
+#[cfg(target_os = "linux")]
+use super::builders::Keep;
+#[cfg(feature = "seccomp")]
+use super::builders::SandboxLevel;
 use super::error::{BuildError, ConfigError, Error, Result};
+use super::exit_handle::SharedExitStatus;
 use super::vm::Vm;
 
 #[cfg(feature = "blk")]
@@ -72,6 +92,14 @@ pub struct VmBuilder {
     net: NetBuilder,
     #[cfg(feature = "blk")]
     disk: DiskBuilder,
+    vsock: VsockBuilder,
+    balloon: BalloonBuilder,
+    #[cfg(feature = "seccomp")]
+    sandbox: SandboxLevel,
+    #[cfg(target_os = "linux")]
+    drop_privileges: Option<Keep>,
+    hostname: Option<String>,
+    host_entries: Vec<(String, String)>,
     exit_observers: Vec<Box<dyn Fn(i32) + Send + 'static>>,
 }
 
@@ -98,6 +126,14 @@ impl VmBuilder {
             net: NetBuilder::new(),
             #[cfg(feature = "blk")]
             disk: DiskBuilder::new(),
+            vsock: VsockBuilder::new(),
+            balloon: BalloonBuilder::new(),
+            #[cfg(feature = "seccomp")]
+            sandbox: SandboxLevel::Off,
+            #[cfg(target_os = "linux")]
+            drop_privileges: None,
+            hostname: None,
+            host_entries: Vec::new(),
             exit_observers: Vec::new(),
         }
     }
@@ -155,10 +191,10 @@ impl VmBuilder {
     /// Root filesystem with additional named mounts:
     ///
     /// ```rust,no_run
-    /// # use msb_krun::VmBuilder;
+    /// # use msb_krun::{DaxConfig, VmBuilder};
     /// VmBuilder::new()
     ///     .fs(|fs| fs.root("/path/to/rootfs"))
-    ///     .fs(|fs| fs.tag("data").shm_size(1 << 30).path("/host/data"))
+    ///     .fs(|fs| fs.tag("data").dax(DaxConfig::WindowMib(1024)).path("/host/data"))
     ///     .fs(|fs| fs.tag("logs").path("/host/logs"));
     /// ```
     ///
@@ -172,6 +208,7 @@ impl VmBuilder {
     pub fn fs(mut self, f: impl FnOnce(FsBuilder) -> FsBuilder) -> Self {
         let new_fs = f(FsBuilder::new());
         self.fs.configs.extend(new_fs.configs);
+        self.fs.hotplug_slots += new_fs.hotplug_slots;
         self
     }
 
@@ -205,6 +242,9 @@ impl VmBuilder {
     pub fn net(mut self, f: impl FnOnce(NetBuilder) -> NetBuilder) -> Self {
         let new_net = f(NetBuilder::new());
         self.net.configs.extend(new_net.configs);
+        if new_net.dns.is_some() {
+            self.net.dns = new_net.dns;
+        }
         self
     }
 
@@ -223,6 +263,7 @@ impl VmBuilder {
     pub fn disk(mut self, f: impl FnOnce(DiskBuilder) -> DiskBuilder) -> Self {
         let new_disk = f(DiskBuilder::new()).finalize();
         self.disk.configs.extend(new_disk.configs);
+        self.disk.hotplug_slots += new_disk.hotplug_slots;
         self
     }
 
@@ -252,6 +293,40 @@ impl VmBuilder {
         self
     }
 
+    /// Configure host services the guest reaches over virtio-vsock.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::VmBuilder;
+    /// use std::io::copy;
+    ///
+    /// VmBuilder::new()
+    ///     .vsock(|v| {
+    ///         v.on_connect(1234, |stream| {
+    ///             let _ = copy(&mut &stream, &mut &stream);
+    ///         })
+    ///     });
+    /// ```
+    pub fn vsock(mut self, f: impl FnOnce(VsockBuilder) -> VsockBuilder) -> Self {
+        self.vsock = f(self.vsock);
+        self
+    }
+
+    /// Configure the balloon device.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::VmBuilder;
+    /// VmBuilder::new()
+    ///     .balloon(|b| b.free_page_reporting(false));
+    /// ```
+    pub fn balloon(mut self, f: impl FnOnce(BalloonBuilder) -> BalloonBuilder) -> Self {
+        self.balloon = f(self.balloon);
+        self
+    }
+
     /// Register a callback that runs synchronously on graceful guest-initiated shutdown.
     ///
     /// Multiple observers are supported and are called in registration order.
@@ -272,6 +347,99 @@ impl VmBuilder {
         self
     }
 
+    /// Restrict the system calls of the threads handling the guest's
+    /// requests, so that a guest exploiting a bug in a device can't make the
+    /// host do much else. Linux only.
+    ///
+    /// Filters are installed once the threads are set up: the vCPU threads,
+    /// the virtio-fs, network and console workers, and the threads they
+    /// start, including the ones running `on_connect` handlers and custom
+    /// backends. A thread making a system call its filter doesn't allow is
+    /// stopped, and [`Vm::enter()`](super::vm::Vm::enter) returns
+    /// [`RuntimeError::SandboxViolation`](super::error::RuntimeError::SandboxViolation).
+    /// The level applies to the whole process.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::{SandboxLevel, VmBuilder};
+    /// VmBuilder::new().sandbox(SandboxLevel::Strict);
+    /// ```
+    #[cfg(feature = "seccomp")]
+    pub fn sandbox(mut self, level: SandboxLevel) -> Self {
+        self.sandbox = level;
+        self
+    }
+
+    /// Drop the capabilities the VMM doesn't need once the VM is set up.
+    ///
+    /// Right before the vCPUs start, the thread entering the VM gives up
+    /// every capability but the ones `keep` asks for, from all its sets
+    /// including the bounding set, and sets `no_new_privs`. The VMM's
+    /// threads all start after that, so none of them can get the others
+    /// back. Threads of the process that are already running keep theirs.
+    ///
+    /// [`Keep::FsCreds`] keeps what the filesystem backends use to act with
+    /// the guest's credentials, such as `CAP_SETUID` and `CAP_SETGID`, out
+    /// of what the process has. Linux only.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::{Keep, VmBuilder};
+    /// VmBuilder::new().drop_privileges(Keep::FsCreds);
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn drop_privileges(mut self, keep: Keep) -> Self {
+        self.drop_privileges = Some(keep);
+        self
+    }
+
+    /// Set the guest's hostname.
+    ///
+    /// The guest's init sets it before starting the workload, exports it as
+    /// `HOSTNAME` and writes it to `/etc/hostname`. It's also added to
+    /// `/etc/hosts` as `127.0.1.1`. Files already in the root filesystem are
+    /// covered for this VM only rather than changed, so a root shared by
+    /// several VMs can give each its own name.
+    ///
+    /// [`build()`](Self::build) fails unless `name` is a valid RFC 1123
+    /// hostname.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::VmBuilder;
+    /// VmBuilder::new().hostname("worker-1");
+    /// ```
+    pub fn hostname(mut self, name: &str) -> Self {
+        self.hostname = Some(name.to_string());
+        self
+    }
+
+    /// Add a static entry to the guest's `/etc/hosts`, resolving `name` to
+    /// `ip`. Can be called multiple times.
+    ///
+    /// Entries follow whatever the root filesystem's `/etc/hosts` already
+    /// has, in the order they were added. As with
+    /// [`hostname()`](Self::hostname), the file is covered for this VM only.
+    ///
+    /// [`build()`](Self::build) fails unless `ip` is an IPv4 or IPv6 address
+    /// and `name` a valid RFC 1123 hostname.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::VmBuilder;
+    /// VmBuilder::new()
+    ///     .host_entry("10.0.0.2", "db")
+    ///     .host_entry("fd00::3", "cache.internal");
+    /// ```
+    pub fn host_entry(mut self, ip: &str, name: &str) -> Self {
+        self.host_entries.push((ip.to_string(), name.to_string()));
+        self
+    }
+
     /// Configure execution settings.
     ///
     /// # Examples
@@ -310,6 +478,9 @@ impl VmBuilder {
     ///
     /// This validates the configuration and creates a `Vm` instance ready to run.
     pub fn build(self) -> Result<Vm> {
+        // Boot timings count from here.
+        let boot_timeline = Arc::new(BootTimeline::new());
+
         // Validate configuration
         if self.machine.vcpus == 0 {
             return Err(Error::Config(ConfigError::InvalidVcpuCount(0)));
@@ -319,6 +490,34 @@ impl VmBuilder {
             return Err(Error::Config(ConfigError::InvalidMemorySize(0)));
         }
 
+        if let Some(name) = &self.hostname {
+            check_hostname(name)?;
+        }
+        let hosts = host_entries(&self.host_entries)?;
+
+        #[cfg(feature = "net")]
+        let (ips, static_dns) = static_ips(&self.net.configs)?;
+        #[cfg(not(feature = "net"))]
+        let (ips, static_dns) = (None, Vec::new());
+
+        // The host's resolvers are only reachable from the guest when TSI forwards its traffic.
+        #[cfg(feature = "net")]
+        let (dns, tsi_inet) = (self.net.dns, self.net.configs.is_empty());
+        #[cfg(not(feature = "net"))]
+        let (dns, tsi_inet) = (None, true);
+        let dns = match dns {
+            Some(dns) => {
+                check_dns(&dns)?;
+                Some(dns)
+            }
+            None if !static_dns.is_empty() => Some(DnsConfig {
+                servers: static_dns,
+                search: Vec::new(),
+            }),
+            None if tsi_inet => host_dns(),
+            None => None,
+        };
+
         // Build VmResources
         let mut vmr = VmResources::default();
 
@@ -334,6 +533,24 @@ impl VmBuilder {
         vmr.nested_enabled = self.machine.nested_virt;
         vmr.split_irqchip = self.machine.split_irqchip;
         vmr.request_vsock = self.machine.vsock;
+        vmr.clock_resync = self.machine.clock_resync;
+        #[cfg(feature = "gdbstub")]
+        if let Some(addr) = self.machine.gdb {
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            {
+                vmr.gdb_addr = Some(addr);
+            }
+            #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+            return Err(Error::Config(ConfigError::Gdb(format!(
+                "cannot listen on {addr}: only supported on Linux x86_64 hosts"
+            ))));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            vmr.drop_privileges = self.drop_privileges;
+        }
+        vmr.vsock_listeners = self.vsock.listeners;
+        vmr.balloon.free_page_reporting = self.balloon.free_page_reporting;
 
         // Apply filesystem configuration
         #[cfg(not(feature = "tee"))]
@@ -342,23 +559,102 @@ impl VmBuilder {
                 FsConfig::Path {
                     tag,
                     path,
-                    shm_size,
+                    dax,
+                    uid_map,
+                    gid_map,
+                    atime,
+                    queues,
+                    init,
+                    hidden,
+                    xattr_map,
+                    one_filesystem,
+                    empty_mount_points,
+                    max_io_size,
+                    landlock,
+                    read_only,
+                    direct_io,
+                    proc_fd,
                 } => {
+                    let shm_size = dax_window_size(&tag, dax)?;
+                    let init_binary = init.map(|path| read_init_binary(&tag, &path)).transpose()?;
+                    for path in &hidden {
+                        devices::virtio::fs::hidden::split_hidden_path(path).map_err(|e| {
+                            Error::Config(ConfigError::Filesystem(format!("{tag}: {e}")))
+                        })?;
+                    }
+                    if let Some(size) = max_io_size {
+                        devices::virtio::fs::check_max_io_size(size).map_err(|e| {
+                            Error::Config(ConfigError::Filesystem(format!("{tag}: {e}")))
+                        })?;
+                    }
+                    #[cfg(not(target_os = "linux"))]
+                    if landlock {
+                        return Err(Error::Config(ConfigError::Filesystem(format!(
+                            "{tag}: Landlock is only supported on Linux hosts"
+                        ))));
+                    }
                     let fs_config = FsDeviceConfig {
                         fs_id: tag,
                         shared_dir: path.to_string_lossy().to_string(),
                         shm_size,
                         allow_root_dir_delete: false,
+                        uid_map,
+                        gid_map,
+                        atime,
+                        metrics: Default::default(),
+                        num_request_queues: queues,
+                        init_binary,
+                        hidden,
+                        xattr_map,
+                        one_filesystem,
+                        empty_mount_points,
+                        max_io_size,
+                        landlock,
+                        read_only,
+                        direct_io,
+                        proc_fd: proc_fd.map(Arc::new),
                     };
                     vmr.fs.push(fs_config);
                 }
                 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
-                FsConfig::Custom { tag, backend } => {
+                FsConfig::File {
+                    tag,
+                    path,
+                    name,
+                    writable,
+                    dax,
+                    queues,
+                } => {
+                    let shm_size = dax_window_size(&tag, dax)?;
+                    let backend = devices::virtio::fs::SingleFileFs::new(&path, &name, writable)
+                        .map_err(|e| {
+                            Error::Config(ConfigError::Filesystem(format!(
+                                "{tag}: {}: {e}",
+                                path.display()
+                            )))
+                        })?;
+                    let custom_config = CustomFsDeviceConfig {
+                        fs_id: tag,
+                        backend: Arc::new(backend),
+                        shm_size,
+                        num_request_queues: queues,
+                    };
+                    vmr.custom_fs.push(custom_config);
+                }
+                #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+                FsConfig::Custom {
+                    tag,
+                    backend,
+                    dax,
+                    queues,
+                } => {
                     let backend: Box<dyn devices::virtio::fs::DynFileSystem> = backend;
+                    let shm_size = dax_window_size(&tag, dax)?;
                     let custom_config = CustomFsDeviceConfig {
                         fs_id: tag,
                         backend: Arc::from(backend),
-                        shm_size: None,
+                        shm_size,
+                        num_request_queues: queues,
                     };
                     vmr.custom_fs.push(custom_config);
                 }
@@ -388,17 +684,38 @@ impl VmBuilder {
 
         // Apply console port configuration
         if !self.console.ports.is_empty() {
+            let ports = self
+                .console
+                .ports
+                .into_iter()
+                .map(console_port_config)
+                .collect::<Result<Vec<_>>>()?;
             vmr.virtio_consoles
-                .push(VirtioConsoleConfigMode::Explicit(self.console.ports));
+                .push(VirtioConsoleConfigMode::Explicit(ports));
         }
 
         if self.console.disable_implicit {
             vmr.disable_implicit_console = true;
         }
+        if self.exec.tty == TtyMode::Never {
+            vmr.stdio_as_pipes = true;
+        }
+
+        let console_watch = ConsoleWatch::new(
+            self.console
+                .panic_pattern
+                .as_deref()
+                .unwrap_or(DEFAULT_PANIC_PATTERN),
+            self.console.tail_size.unwrap_or(DEFAULT_CONSOLE_TAIL_SIZE),
+        )
+        .map_err(|e| Error::Build(BuildError::Start(format!("console watch: {e:?}"))))?
+        .with_timeline(boot_timeline.clone());
+        vmr.console_watch = Some(Arc::new(console_watch));
+        vmr.boot_timeline = Some(boot_timeline.clone());
 
         // Apply network configuration
         #[cfg(feature = "net")]
-        for (i, config) in self.net.configs.into_iter().enumerate() {
+        for (i, (config, options)) in self.net.configs.into_iter().enumerate() {
             let (mac, backend) = match config {
                 NetConfig::UnixgramFd { mac, fd } => {
                     (mac, VirtioNetBackend::UnixgramFd(fd.into_raw_fd()))
@@ -426,7 +743,9 @@ impl VmBuilder {
                 iface_id,
                 backend,
                 mac,
-                features: 0,
+                features: options.offloads.features(),
+                queue_pairs: options.queues,
+                rate_limit: options.rate_limit,
             };
 
             vmr.net
@@ -436,23 +755,16 @@ impl VmBuilder {
 
         // Apply block device configuration
         #[cfg(feature = "blk")]
+        let disk_count = self.disk.configs.len();
+        #[cfg(feature = "blk")]
         for (i, config) in self.disk.configs.into_iter().enumerate() {
-            let block_id = format!("vd{}", (b'a' + i as u8) as char);
-            let image_type: ImageType = config.format.into();
-
-            let blk_config = BlockDeviceConfig {
-                block_id,
-                cache_type: CacheType::Writeback,
-                disk_image_path: config.path.to_string_lossy().to_string(),
-                disk_image_format: image_type,
-                is_disk_read_only: config.read_only,
-                direct_io: false,
-                sync_mode: devices::virtio::block::SyncMode::default(),
-            };
-
-            vmr.add_block_device(blk_config)
+            vmr.add_block_device(block_device_config(disk_id(i), config))
                 .map_err(|e| Error::Config(ConfigError::Block(e.to_string())))?;
         }
+        #[cfg(feature = "blk")]
+        for i in disk_count..disk_count + self.disk.hotplug_slots {
+            vmr.block.add_hotplug_slot(disk_id(i));
+        }
 
         // Format execution configuration
         let exec_path = self.exec.path;
@@ -497,9 +809,16 @@ impl VmBuilder {
 
         let exit_evt = EventFd::new(EFD_NONBLOCK)
             .map_err(|e| Error::Build(BuildError::Start(format!("exit EventFd: {e:?}"))))?;
-        let exit_code = Arc::new(AtomicI32::new(i32::MAX));
+        let exit_status = Arc::new(SharedExitStatus::new());
 
-        Ok(Vm::new(
+        #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+        for _ in 0..self.fs.hotplug_slots {
+            let slot = devices::virtio::Fs::vacant(exit_status.clone())
+                .map_err(|e| Error::Build(BuildError::Start(format!("fs hotplug slot: {e:?}"))))?;
+            vmr.fs_hotplug_slots.push(Arc::new(Mutex::new(slot)));
+        }
+
+        let mut vm = Vm::new(
             vmr,
             self.kernel.cmdline,
             exec_path,
@@ -507,12 +826,26 @@ impl VmBuilder {
             env,
             self.exec.workdir,
             rlimits,
+            self.exec.tty,
+            self.hostname,
+            hosts,
+            dns,
+            ips,
             self.kernel.krunfw_path,
             self.kernel.init_path,
             self.exit_observers,
             exit_evt,
-            exit_code,
-        ))
+            exit_status,
+        );
+        #[cfg(feature = "seccomp")]
+        vm.set_sandbox(self.sandbox);
+
+        // Attach vsock now rather than on entering, so that its connections
+        // can be watched from a handle taken before then.
+        vm.configure_vsock()?;
+
+        boot_timeline.mark(BootStage::Configured);
+        Ok(vm)
     }
 }
 
@@ -543,6 +876,260 @@ fn generate_mac(index: usize) -> [u8; 6] {
     ]
 }
 
+/// The ID of the `index`th disk in the guest, counting the hotplug slots after the boot disks.
+#[cfg(feature = "blk")]
+fn disk_id(index: usize) -> String {
+    format!("vd{}", (b'a' + index as u8) as char)
+}
+
+/// Turns the configuration of a disk into that of the block device backing it.
+#[cfg(feature = "blk")]
+pub(crate) fn block_device_config(block_id: String, config: DiskConfig) -> BlockDeviceConfig {
+    let (cache_type, direct_io) = match config.cache {
+        CacheMode::Writeback => (CacheType::Writeback, false),
+        CacheMode::Writethrough => (CacheType::Writethrough, false),
+        CacheMode::DirectSync => (CacheType::Writethrough, true),
+    };
+
+    BlockDeviceConfig {
+        block_id,
+        cache_type,
+        disk_image_path: config.path.to_string_lossy().to_string(),
+        disk_image_format: ImageType::from(config.format),
+        is_disk_read_only: config.read_only,
+        direct_io,
+        sync_mode: devices::virtio::block::SyncMode::default(),
+        rate_limit: config.rate_limit,
+    }
+}
+
+/// Returns the size in bytes of the DAX window of the mount tagged `tag`, if it has one.
+#[cfg(not(feature = "tee"))]
+fn dax_window_size(tag: &str, dax: DaxConfig) -> Result<Option<usize>> {
+    let mib = match dax {
+        DaxConfig::Off => return Ok(None),
+        DaxConfig::WindowMib(mib) => mib,
+    };
+
+    if cfg!(feature = "aws-nitro") {
+        return Err(Error::Config(ConfigError::Filesystem(format!(
+            "{tag}: DAX isn't supported in Nitro Enclaves"
+        ))));
+    }
+
+    if mib == 0 {
+        return Err(Error::Config(ConfigError::Filesystem(format!(
+            "{tag}: DAX window can't be empty, use DaxConfig::Off to disable DAX"
+        ))));
+    }
+
+    mib.checked_mul(1 << 20)
+        .and_then(|size| usize::try_from(size).ok())
+        .map(Some)
+        .ok_or_else(|| {
+            Error::Config(ConfigError::Filesystem(format!(
+                "{tag}: DAX window of {mib} MiB is too large"
+            )))
+        })
+}
+
+/// Reads and checks the init binary a mount serves instead of the built-in one.
+#[cfg(not(feature = "tee"))]
+fn read_init_binary(tag: &str, path: &Path) -> Result<Vec<u8>> {
+    let fail = |e: std::io::Error| {
+        Error::Config(ConfigError::Filesystem(format!(
+            "{tag}: init binary {}: {e}",
+            path.display()
+        )))
+    };
+    let binary = std::fs::read(path).map_err(fail)?;
+    devices::virtio::fs::validate_init_binary(&binary).map_err(fail)?;
+    Ok(binary)
+}
+
+/// Whether `name` is a hostname as RFC 1123 has it: dot-separated labels of
+/// letters, digits and hyphens, not starting or ending with a hyphen.
+fn valid_hostname(name: &str) -> bool {
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    name.len() <= 253 && name.split('.').all(valid_label)
+}
+
+fn check_hostname(name: &str) -> Result<()> {
+    if !valid_hostname(name) {
+        return Err(Error::Config(ConfigError::Hostname(format!(
+            "{name:?} isn't a valid hostname"
+        ))));
+    }
+    Ok(())
+}
+
+/// Checks resolver settings given with `NetBuilder::dns`.
+fn check_dns(dns: &DnsConfig) -> Result<()> {
+    let fail = |msg: String| Err(Error::Config(ConfigError::Network(format!("DNS: {msg}"))));
+    if dns.servers.is_empty() {
+        return fail("no servers given".to_string());
+    }
+    for ip in &dns.servers {
+        let broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+        if ip.is_unspecified() || ip.is_multicast() || broadcast {
+            return fail(format!("{ip} can't be a server's address"));
+        }
+    }
+    for domain in &dns.search {
+        if !valid_hostname(domain) {
+            return fail(format!("{domain:?} isn't a valid search domain"));
+        }
+    }
+    Ok(())
+}
+
+/// The host's resolvers, for a guest whose traffic TSI forwards. Ones on the
+/// host's loopback are left out since the guest's own would be used instead,
+/// so for a local stub like systemd-resolved, the servers it forwards to are
+/// used.
+fn host_dns() -> Option<DnsConfig> {
+    ["/etc/resolv.conf", "/run/systemd/resolve/resolv.conf"]
+        .into_iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|conf| parse_resolv_conf(&conf))
+        .find(|dns| !dns.servers.is_empty())
+}
+
+/// Picks the usable servers and search domains out of a `resolv.conf`.
+fn parse_resolv_conf(conf: &str) -> DnsConfig {
+    let mut dns = DnsConfig::default();
+    for line in conf.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            // Addresses with a zone (`fe80::1%eth0`) don't parse and are skipped too.
+            Some("nameserver") => dns.servers.extend(
+                words
+                    .next()
+                    .and_then(|ip| ip.parse::<IpAddr>().ok())
+                    .filter(|ip| !ip.is_loopback()),
+            ),
+            // As with glibc, the last one wins.
+            Some("search" | "domain") => {
+                dns.search = words
+                    .filter(|d| valid_hostname(d))
+                    .map(String::from)
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+    dns
+}
+
+/// Checks the `/etc/hosts` entries and puts them in the form the guest's init
+/// takes them in, `ip=name` pairs separated by commas.
+fn host_entries(entries: &[(String, String)]) -> Result<Option<String>> {
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let mut hosts = Vec::with_capacity(entries.len());
+    for (ip, name) in entries {
+        let ip: IpAddr = ip.parse().map_err(|e| {
+            Error::Config(ConfigError::Hostname(format!(
+                "{name}: {ip:?} isn't an IP address: {e}"
+            )))
+        })?;
+        check_hostname(name)?;
+        hosts.push(format!("{ip}={name}"));
+    }
+    Ok(Some(hosts.join(",")))
+}
+
+/// Parses the static addresses of the network devices and puts them in the
+/// form the guest's init takes them in, `iface,ip/prefix,gateway` for each
+/// separated by semicolons. Also returns the DNS servers given with them.
+#[cfg(feature = "net")]
+fn static_ips(configs: &[(NetConfig, NetOptions)]) -> Result<(Option<String>, Vec<IpAddr>)> {
+    let mut ips = Vec::new();
+    let mut dns = Vec::new();
+    for (i, (_, options)) in configs.iter().enumerate() {
+        let Some(ip) = &options.static_ip else {
+            continue;
+        };
+        let iface = format!("eth{i}");
+        let fail = |msg: String| Error::Config(ConfigError::Network(format!("{iface}: {msg}")));
+        let parse_ip = |ip: &str| {
+            ip.parse::<IpAddr>()
+                .map_err(|e| fail(format!("{ip:?} isn't an IP address: {e}")))
+        };
+
+        let (addr, prefix) = ip
+            .cidr
+            .split_once('/')
+            .ok_or_else(|| fail(format!("{:?} isn't in CIDR notation", ip.cidr)))?;
+        let addr = parse_ip(addr)?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| fail(format!("{prefix:?} isn't a prefix length")))?;
+        let gateway = parse_ip(&ip.gateway)?;
+        if !in_subnet(gateway, addr, prefix) {
+            return Err(fail(format!("gateway {gateway} isn't in {addr}/{prefix}")));
+        }
+        if let Some(server) = &ip.dns {
+            dns.push(parse_ip(server)?);
+        }
+
+        ips.push(format!("{iface},{addr}/{prefix},{gateway}"));
+    }
+
+    Ok(((!ips.is_empty()).then(|| ips.join(";")), dns))
+}
+
+/// Whether `ip` is in the subnet of `addr` with a `prefix` long mask.
+#[cfg(feature = "net")]
+fn in_subnet(ip: IpAddr, addr: IpAddr, prefix: u8) -> bool {
+    match (ip, addr) {
+        (IpAddr::V4(ip), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Sets up the host end of a console port, connecting it if it goes to a Unix socket.
+fn console_port_config(port: ConsolePortConfig) -> Result<PortConfig> {
+    let (name, path) = match port {
+        ConsolePortConfig::Port(config) => return Ok(config),
+        ConsolePortConfig::UnixSocket { name, path } => (name, path),
+    };
+
+    let err = |e: &dyn std::fmt::Display| {
+        Error::Config(ConfigError::Console(format!(
+            "{name}: can't connect to {}: {e}",
+            path.display()
+        )))
+    };
+    let stream = UnixStream::connect(&path).map_err(|e| err(&e))?;
+    let input = port_io::input_to_raw_fd_dup(stream.as_raw_fd()).map_err(|e| err(&e))?;
+    let output = port_io::output_to_raw_fd_dup(stream.as_raw_fd()).map_err(|e| err(&e))?;
+    Ok(PortConfig::Custom {
+        name,
+        input,
+        output,
+    })
+}
+
 fn map_vm_config_error(machine: &MachineBuilder, err: VmConfigError) -> Error {
     match err {
         VmConfigError::InvalidVcpuCount => {
@@ -562,6 +1149,8 @@ fn map_vm_config_error(machine: &MachineBuilder, err: VmConfigError) -> Error {
 mod tests {
     use super::*;
 
+    use crate::api::builders::PortDest;
+
     #[test]
     fn build_rejects_invalid_machine_config() {
         let err = match VmBuilder::new()
@@ -577,4 +1166,239 @@ mod tests {
             other => panic!("unexpected error: {other:?}"),
         }
     }
+
+    #[test]
+    fn build_rejects_invalid_hostnames() {
+        for name in ["", "-worker", "worker-", "work_er", "a..b", &"a".repeat(64)] {
+            match VmBuilder::new().hostname(name).build() {
+                Err(Error::Config(ConfigError::Hostname(_))) => {}
+                Err(other) => panic!("unexpected error: {other:?}"),
+                Ok(_) => panic!("{name:?} should fail as a hostname"),
+            }
+        }
+        assert!(check_hostname("worker-1.example.com").is_ok());
+    }
+
+    #[test]
+    fn host_entries_are_checked() {
+        let entries = |list: &[(&str, &str)]| {
+            host_entries(
+                &list
+                    .iter()
+                    .map(|(ip, name)| (ip.to_string(), name.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(entries(&[]).unwrap(), None);
+        assert_eq!(
+            entries(&[("10.0.0.2", "db"), ("fd00:0::3", "cache.internal")]).unwrap(),
+            Some("10.0.0.2=db,fd00::3=cache.internal".to_string())
+        );
+        for bad in [
+            ("10.0.0.256", "db"),
+            ("db", "10.0.0.2"),
+            ("10.0.0.2", "d b"),
+        ] {
+            match entries(&[bad]) {
+                Err(Error::Config(ConfigError::Hostname(_))) => {}
+                other => panic!("{bad:?} should fail, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn dns_servers_are_checked() {
+        let dns = |servers: &[&str], search: &[&str]| DnsConfig {
+            servers: servers.iter().map(|ip| ip.parse().unwrap()).collect(),
+            search: search.iter().map(|s| s.to_string()).collect(),
+        };
+
+        assert!(check_dns(&dns(&["10.0.0.53", "fd00::53"], &["corp.example.com"])).is_ok());
+        for bad in [
+            dns(&[], &[]),
+            dns(&["0.0.0.0"], &[]),
+            dns(&["255.255.255.255"], &[]),
+            dns(&["ff02::1"], &[]),
+            dns(&["10.0.0.53"], &["corp example"]),
+        ] {
+            match check_dns(&bad) {
+                Err(Error::Config(ConfigError::Network(msg))) => assert!(msg.starts_with("DNS: ")),
+                other => panic!("{bad:?} should fail, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn resolv_conf_parsing() {
+        let dns = parse_resolv_conf(
+            "# comment\n\
+             nameserver 127.0.0.53\n\
+             nameserver 10.0.0.53\n\
+             nameserver fe80::1%eth0\n\
+             nameserver 2001:db8::53\n\
+             search ignored.example\n\
+             search corp.example.com . lab.example.com\n\
+             options edns0\n",
+        );
+
+        assert_eq!(
+            dns,
+            DnsConfig {
+                servers: vec![
+                    "10.0.0.53".parse().unwrap(),
+                    "2001:db8::53".parse().unwrap()
+                ],
+                search: vec![
+                    "corp.example.com".to_string(),
+                    "lab.example.com".to_string()
+                ],
+            }
+        );
+        assert!(parse_resolv_conf("nameserver 127.0.0.53\n")
+            .servers
+            .is_empty());
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn static_ips_are_parsed() {
+        let builder = NetBuilder::new()
+            .static_ip("10.0.0.2/24", "10.0.0.1", Some("10.0.0.53"))
+            .unixstream_path("/nonexistent")
+            .unixstream_path("/nonexistent")
+            .static_ip("fd00::2/64", "fd00::1", None)
+            .unixstream_path("/nonexistent");
+
+        let (ips, dns) = static_ips(&builder.configs).unwrap();
+        assert_eq!(
+            ips.as_deref(),
+            Some("eth0,10.0.0.2/24,10.0.0.1;eth2,fd00::2/64,fd00::1")
+        );
+        assert_eq!(dns, ["10.0.0.53".parse::<IpAddr>().unwrap()]);
+
+        for (cidr, gateway, dns) in [
+            ("10.0.0.2", "10.0.0.1", None),
+            ("10.0.0.2/33", "10.0.0.1", None),
+            ("10.0.0.256/24", "10.0.0.1", None),
+            ("10.0.0.2/24", "10.0.1.1", None),
+            ("10.0.0.2/24", "fd00::1", None),
+            ("10.0.0.2/24", "10.0.0.1", Some("dns")),
+        ] {
+            let builder = NetBuilder::new()
+                .static_ip(cidr, gateway, dns)
+                .unixstream_path("/nonexistent");
+            match static_ips(&builder.configs) {
+                Err(Error::Config(ConfigError::Network(msg))) => assert!(msg.starts_with("eth0: ")),
+                other => panic!("{cidr} via {gateway} should fail, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn dax_window_sizes() {
+        assert_eq!(dax_window_size("data", DaxConfig::Off).unwrap(), None);
+        assert_eq!(
+            dax_window_size("data", DaxConfig::WindowMib(4096)).unwrap(),
+            Some(4 << 30)
+        );
+    }
+
+    #[test]
+    fn build_rejects_empty_dax_window() {
+        let err = match VmBuilder::new()
+            .fs(|fs| fs.tag("data").dax(DaxConfig::WindowMib(0)).path("/tmp"))
+            .build()
+        {
+            Ok(_) => panic!("an empty DAX window should fail"),
+            Err(err) => err,
+        };
+
+        match err {
+            Error::Config(ConfigError::Filesystem(msg)) => assert!(msg.starts_with("data: ")),
+            other => panic!("unexpected error: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn build_rejects_oversized_io() {
+        for bytes in [1000, 64 << 20] {
+            let err = match VmBuilder::new()
+                .fs(|fs| fs.tag("data").max_io_size(bytes).path("/tmp"))
+                .build()
+            {
+                Ok(_) => panic!("a max I/O size of {bytes} should fail"),
+                Err(err) => err,
+            };
+
+            match err {
+                Error::Config(ConfigError::Filesystem(msg)) => assert!(msg.starts_with("data: ")),
+                other => panic!("unexpected error: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn build_shares_single_file() {
+        let path = std::env::temp_dir().join(format!("krun-weights-{}", std::process::id()));
+        std::fs::write(&path, b"weights").unwrap();
+        let shared = VmBuilder::new()
+            .fs(|fs| fs.tag("weights").file(&path, "model.bin"))
+            .build();
+        let missing = VmBuilder::new()
+            .fs(|fs| {
+                fs.tag("weights")
+                    .file(path.with_extension("missing"), "model.bin")
+            })
+            .build();
+        let bad_name = VmBuilder::new()
+            .fs(|fs| fs.tag("weights").file(&path, "../model.bin"))
+            .build();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(shared.is_ok());
+        for result in [missing, bad_name] {
+            match result {
+                Err(Error::Config(ConfigError::Filesystem(msg))) => {
+                    assert!(msg.starts_with("weights: "))
+                }
+                Err(other) => panic!("unexpected error: {other:?}"),
+                Ok(_) => panic!("the share should fail"),
+            }
+        }
+    }
+
+    #[test]
+    fn build_rejects_invalid_init_binary() {
+        let path = std::env::temp_dir().join(format!("krun-init-{}", std::process::id()));
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        let result = VmBuilder::new()
+            .fs(|fs| fs.init_from(&path).root("/tmp"))
+            .build();
+        std::fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(Error::Config(ConfigError::Filesystem(msg))) => {
+                assert!(msg.starts_with("/dev/root: init binary "))
+            }
+            Err(other) => panic!("unexpected error: {other:?}"),
+            Ok(_) => panic!("a script should fail as an init binary"),
+        }
+    }
+
+    #[test]
+    fn build_rejects_unreachable_port_socket() {
+        let err = match VmBuilder::new()
+            .console(|c| c.port("log", PortDest::UnixSocket("/nonexistent/log.sock".into())))
+            .build()
+        {
+            Ok(_) => panic!("a port socket nobody listens on should fail"),
+            Err(err) => err,
+        };
+
+        match err {
+            Error::Config(ConfigError::Console(msg)) => assert!(msg.starts_with("log: ")),
+            other => panic!("unexpected error: {other:?}"),
+        }
+    }
 }
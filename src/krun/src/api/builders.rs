@@ -1,22 +1,36 @@
 //! Sub-builders for VmBuilder nested configuration.
 
-use std::os::fd::RawFd;
+use std::collections::HashMap;
+use std::net::IpAddr;
+#[cfg(feature = "gdbstub")]
+use std::net::SocketAddr;
+use std::os::fd::{OwnedFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use devices::virtio::console::port_io::{
-    ConsolePortBackend, ConsolePortBackendInputAdapter, ConsolePortBackendOutputAdapter,
+    self, ConsolePortBackend, ConsolePortBackendInputAdapter, ConsolePortBackendOutputAdapter,
+    PortInputEmpty,
 };
+use devices::virtio::fs::{AtimePolicy, IdMap, XattrRule};
+use devices::virtio::VsockConnectHandler;
 use vmm::resources::PortConfig;
 
+#[cfg(target_os = "linux")]
+pub use devices::virtio::fs::privileges::Keep;
+pub use devices::virtio::{BalloonMetrics, BalloonMetricsSnapshot, VsockStream};
+#[cfg(feature = "seccomp")]
+pub use utils::sandbox::SandboxLevel;
+
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
 use crate::backends::fs::DynFileSystem;
 
-#[cfg(feature = "net")]
-use std::os::fd::OwnedFd;
-
 #[cfg(feature = "net")]
 use crate::backends::net::NetBackend;
+#[cfg(feature = "net")]
+pub use devices::virtio::net::Offloads;
+#[cfg(any(feature = "blk", feature = "net"))]
+pub use devices::virtio::RateLimit;
 
 //--------------------------------------------------------------------------------------------------
 // Types: Machine Builder
@@ -44,6 +58,9 @@ pub struct MachineBuilder {
     pub(crate) nested_virt: bool,
     pub(crate) split_irqchip: bool,
     pub(crate) vsock: bool,
+    pub(crate) clock_resync: bool,
+    #[cfg(feature = "gdbstub")]
+    pub(crate) gdb: Option<SocketAddr>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -88,13 +105,22 @@ pub struct KernelBuilder {
 /// Root filesystem with additional named mounts:
 ///
 /// ```rust,no_run
-/// # use msb_krun::VmBuilder;
+/// # use msb_krun::{DaxConfig, VmBuilder};
 /// VmBuilder::new()
 ///     .fs(|fs| fs.root("/path/to/rootfs"))
-///     .fs(|fs| fs.tag("data").shm_size(1 << 30).path("/host/data"))
+///     .fs(|fs| fs.tag("data").dax(DaxConfig::WindowMib(1024)).path("/host/data"))
 ///     .fs(|fs| fs.tag("logs").path("/host/logs"));
 /// ```
 ///
+/// A single host file, such as model weights, in a directory of its own:
+///
+/// ```rust,no_run
+/// # use msb_krun::VmBuilder;
+/// VmBuilder::new()
+///     .fs(|fs| fs.root("/path/to/rootfs"))
+///     .fs(|fs| fs.tag("weights").file("/host/models/llama.gguf", "model.gguf"));
+/// ```
+///
 /// Custom filesystem backend:
 ///
 /// ```rust,ignore
@@ -104,7 +130,36 @@ pub struct KernelBuilder {
 pub struct FsBuilder {
     pub(crate) configs: Vec<FsConfig>,
     current_tag: Option<String>,
-    current_shm_size: Option<usize>,
+    current_dax: Option<DaxConfig>,
+    current_idmap: Option<(IdMap, IdMap)>,
+    current_atime: Option<AtimePolicy>,
+    current_queues: Option<usize>,
+    current_init: Option<PathBuf>,
+    current_hidden: Vec<PathBuf>,
+    current_xattr_map: Vec<XattrRule>,
+    current_one_filesystem: bool,
+    current_empty_mount_points: bool,
+    current_max_io_size: Option<u32>,
+    current_landlock: bool,
+    current_read_only: bool,
+    current_direct_io: bool,
+    current_proc_fd: Option<OwnedFd>,
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    current_writable: bool,
+    #[cfg(not(feature = "tee"))]
+    pub(crate) hotplug_slots: usize,
+}
+
+/// DAX setting of a filesystem mount.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DaxConfig {
+    /// File data goes through the request queues. This is the default.
+    #[default]
+    Off,
+
+    /// Lets the guest map file data straight into a shared memory window of this many MiB. The
+    /// window takes up guest physical address space, but no memory until files are mapped.
+    WindowMib(u64),
 }
 
 /// Configuration for a single filesystem mount.
@@ -113,13 +168,39 @@ pub enum FsConfig {
     Path {
         tag: String,
         path: PathBuf,
-        shm_size: Option<usize>,
+        dax: DaxConfig,
+        uid_map: IdMap,
+        gid_map: IdMap,
+        atime: AtimePolicy,
+        queues: usize,
+        init: Option<PathBuf>,
+        hidden: Vec<PathBuf>,
+        xattr_map: Vec<XattrRule>,
+        one_filesystem: bool,
+        empty_mount_points: bool,
+        max_io_size: Option<u32>,
+        landlock: bool,
+        read_only: bool,
+        direct_io: bool,
+        proc_fd: Option<OwnedFd>,
+    },
+    /// A single host file.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    File {
+        tag: String,
+        path: PathBuf,
+        name: String,
+        writable: bool,
+        dax: DaxConfig,
+        queues: usize,
     },
     /// Custom filesystem backend.
     #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
     Custom {
         tag: String,
         backend: Box<dyn DynFileSystem + Send + Sync>,
+        dax: DaxConfig,
+        queues: usize,
     },
 }
 
@@ -137,8 +218,36 @@ pub enum FsConfig {
 /// ```
 #[cfg(feature = "net")]
 pub struct NetBuilder {
-    pub(crate) configs: Vec<NetConfig>,
+    pub(crate) configs: Vec<(NetConfig, NetOptions)>,
+    pub(crate) dns: Option<DnsConfig>,
     current_mac: Option<[u8; 6]>,
+    current_options: NetOptions,
+}
+
+/// Settings of a network device that apply to any backend.
+#[cfg(feature = "net")]
+#[derive(Clone, Debug)]
+pub(crate) struct NetOptions {
+    pub(crate) queues: u16,
+    pub(crate) offloads: Offloads,
+    pub(crate) rate_limit: RateLimit,
+    pub(crate) static_ip: Option<StaticIp>,
+}
+
+/// A guest address given with `NetBuilder::static_ip`, parsed by `build()`.
+#[cfg(feature = "net")]
+#[derive(Clone, Debug)]
+pub(crate) struct StaticIp {
+    pub(crate) cidr: String,
+    pub(crate) gateway: String,
+    pub(crate) dns: Option<String>,
+}
+
+/// What the guest's `/etc/resolv.conf` is written with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct DnsConfig {
+    pub(crate) servers: Vec<IpAddr>,
+    pub(crate) search: Vec<String>,
 }
 
 /// Configuration for a single network device.
@@ -194,8 +303,10 @@ pub enum NetConfig {
 #[derive(Default)]
 pub struct ConsoleBuilder {
     pub(crate) output: Option<PathBuf>,
-    pub(crate) ports: Vec<PortConfig>,
+    pub(crate) ports: Vec<ConsolePortConfig>,
     pub(crate) disable_implicit: bool,
+    pub(crate) panic_pattern: Option<String>,
+    pub(crate) tail_size: Option<usize>,
     #[cfg(feature = "snd")]
     pub(crate) sound: bool,
     #[cfg(feature = "gpu")]
@@ -204,6 +315,31 @@ pub struct ConsoleBuilder {
     pub(crate) gpu_shm_size: Option<usize>,
 }
 
+/// Where the host end of a named console port goes.
+#[derive(Clone, Debug)]
+pub enum PortDest {
+    /// Connect to the Unix stream socket listening at this path when the VM
+    /// is built. What the guest writes goes to the socket, and what comes in
+    /// on it goes to the guest.
+    UnixSocket(PathBuf),
+
+    /// Read from `input` and write to `output`, such as the ends of two
+    /// pipes. Pass the same fd for both with a bidirectional socket, or -1 to
+    /// leave out a direction.
+    Pipe { input: RawFd, output: RawFd },
+
+    /// Throw away what the guest writes and never send it anything.
+    Null,
+}
+
+/// Configuration for a single console port.
+pub(crate) enum ConsolePortConfig {
+    /// A port the VMM can set up as it is.
+    Port(PortConfig),
+    /// A port connected to a Unix socket when the VM is built.
+    UnixSocket { name: String, path: PathBuf },
+}
+
 //--------------------------------------------------------------------------------------------------
 // Types: Exec Builder
 //--------------------------------------------------------------------------------------------------
@@ -244,6 +380,24 @@ pub struct ExecBuilder {
     pub(crate) env: Vec<(String, String)>,
     pub(crate) workdir: Option<String>,
     pub(crate) rlimits: Vec<(String, u64, u64)>,
+    pub(crate) tty: TtyMode,
+}
+
+/// Whether the workload's stdio is a terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TtyMode {
+    /// A terminal wherever the host's stdio is one, or the console's output
+    /// goes to a file, and a pipe elsewhere.
+    #[default]
+    Auto,
+
+    /// Always a terminal, from a pseudo-terminal the guest's init opens. It
+    /// is also the workload's controlling terminal.
+    Force,
+
+    /// Never a terminal. The host's terminal isn't put in raw mode, so it
+    /// keeps its own line editing and signal keys.
+    Never,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -259,6 +413,22 @@ pub enum DiskImageFormat {
     Vmdk,
 }
 
+/// How writes to a disk reach the host.
+#[cfg(feature = "blk")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Writes go through the host page cache and are synced when the guest flushes them.
+    #[default]
+    Writeback,
+
+    /// Writes go through the host page cache and are synced before the guest sees them complete.
+    Writethrough,
+
+    /// Like `Writethrough`, but the image is opened with `O_DIRECT` so the host page cache is
+    /// bypassed. Fails to build if the filesystem holding the image doesn't support direct I/O.
+    DirectSync,
+}
+
 /// Builder for block device configuration.
 ///
 /// # Example
@@ -275,6 +445,9 @@ pub struct DiskBuilder {
     current_path: Option<PathBuf>,
     current_read_only: bool,
     current_format: DiskImageFormat,
+    current_cache: CacheMode,
+    current_rate_limit: RateLimit,
+    pub(crate) hotplug_slots: usize,
 }
 
 /// Configuration for a single block device.
@@ -284,6 +457,50 @@ pub struct DiskConfig {
     pub path: PathBuf,
     pub read_only: bool,
     pub format: DiskImageFormat,
+    pub cache: CacheMode,
+    pub rate_limit: RateLimit,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Types: Balloon Builder
+//--------------------------------------------------------------------------------------------------
+
+/// Builder for balloon device configuration.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use msb_krun::VmBuilder;
+/// VmBuilder::new()
+///     .balloon(|b| b.free_page_reporting(false));
+/// ```
+#[derive(Debug, Clone)]
+pub struct BalloonBuilder {
+    pub(crate) free_page_reporting: bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Types: Vsock Builder
+//--------------------------------------------------------------------------------------------------
+
+/// Builder for host services the guest reaches over virtio-vsock.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use msb_krun::VmBuilder;
+/// use std::io::copy;
+///
+/// VmBuilder::new()
+///     .vsock(|v| {
+///         v.on_connect(1234, |stream| {
+///             let _ = copy(&mut &stream, &mut &stream);
+///         })
+///     });
+/// ```
+#[derive(Default)]
+pub struct VsockBuilder {
+    pub(crate) listeners: HashMap<u32, VsockConnectHandler>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -300,6 +517,9 @@ impl MachineBuilder {
             nested_virt: false,
             split_irqchip: false,
             vsock: false,
+            clock_resync: cfg!(target_os = "macos"),
+            #[cfg(feature = "gdbstub")]
+            gdb: None,
         }
     }
 
@@ -350,6 +570,32 @@ impl MachineBuilder {
         self.vsock = enabled;
         self
     }
+
+    /// Keep the guest's clock in step with the host's across host sleep.
+    ///
+    /// The guest's clock stops while the host sleeps, and is behind by as
+    /// long as the host slept once it wakes. With this set, the host sends
+    /// the guest its time over vsock whenever its clock jumps, and once a
+    /// minute besides, and the guest's init sets its clock from it. Only
+    /// takes effect when the VM has a vsock device. Defaults to `true` on
+    /// macOS and `false` elsewhere.
+    pub fn clock_resync(mut self, enabled: bool) -> Self {
+        self.clock_resync = enabled;
+        self
+    }
+
+    /// Debug the guest with gdb over TCP on `listen_addr`.
+    ///
+    /// The first vCPU stops before its first instruction until gdb connects
+    /// with `target remote`, and from then on can be stepped, broken into,
+    /// and have its registers and memory read and written. The other vCPUs
+    /// run on regardless. Only available on Linux x86_64 hosts; building
+    /// the VM elsewhere fails.
+    #[cfg(feature = "gdbstub")]
+    pub fn gdb(mut self, listen_addr: SocketAddr) -> Self {
+        self.gdb = Some(listen_addr);
+        self
+    }
 }
 
 impl Default for MachineBuilder {
@@ -407,7 +653,24 @@ impl FsBuilder {
         Self {
             configs: Vec::new(),
             current_tag: None,
-            current_shm_size: None,
+            current_dax: None,
+            current_idmap: None,
+            current_atime: None,
+            current_queues: None,
+            current_init: None,
+            current_hidden: Vec::new(),
+            current_xattr_map: Vec::new(),
+            current_one_filesystem: false,
+            current_empty_mount_points: false,
+            current_max_io_size: None,
+            current_landlock: false,
+            current_read_only: false,
+            current_direct_io: false,
+            current_proc_fd: None,
+            #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+            current_writable: false,
+            #[cfg(not(feature = "tee"))]
+            hotplug_slots: 0,
         }
     }
 
@@ -415,10 +678,39 @@ impl FsBuilder {
     ///
     /// Uses the virtiofs tag `/dev/root`, matching the kernel's expected root device name.
     pub fn root(mut self, path: impl AsRef<Path>) -> Self {
+        let dax = self.current_dax.take().unwrap_or_default();
+        let (uid_map, gid_map) = self.current_idmap.take().unwrap_or_default();
+        let atime = self.current_atime.take().unwrap_or_default();
+        let queues = self.current_queues.take().unwrap_or(1);
+        let init = self.current_init.take();
+        let hidden = std::mem::take(&mut self.current_hidden);
+        let xattr_map = std::mem::take(&mut self.current_xattr_map);
+        let one_filesystem = std::mem::take(&mut self.current_one_filesystem);
+        let empty_mount_points = std::mem::take(&mut self.current_empty_mount_points);
+        let max_io_size = self.current_max_io_size.take();
+        let landlock = std::mem::take(&mut self.current_landlock);
+        let read_only = std::mem::take(&mut self.current_read_only);
+        let direct_io = std::mem::take(&mut self.current_direct_io);
+        let proc_fd = self.current_proc_fd.take();
+
         self.configs.push(FsConfig::Path {
             tag: "/dev/root".to_string(),
             path: path.as_ref().to_path_buf(),
-            shm_size: None,
+            dax,
+            uid_map,
+            gid_map,
+            atime,
+            queues,
+            init,
+            hidden,
+            xattr_map,
+            one_filesystem,
+            empty_mount_points,
+            max_io_size,
+            landlock,
+            read_only,
+            direct_io,
+            proc_fd,
         });
         self
     }
@@ -437,19 +729,239 @@ impl FsBuilder {
             .current_tag
             .take()
             .unwrap_or_else(|| format!("fs{}", self.configs.len()));
-        let shm_size = self.current_shm_size.take();
+        let dax = self.current_dax.take().unwrap_or_default();
+        let (uid_map, gid_map) = self.current_idmap.take().unwrap_or_default();
+        let atime = self.current_atime.take().unwrap_or_default();
+        let queues = self.current_queues.take().unwrap_or(1);
+        let init = self.current_init.take();
+        let hidden = std::mem::take(&mut self.current_hidden);
+        let xattr_map = std::mem::take(&mut self.current_xattr_map);
+        let one_filesystem = std::mem::take(&mut self.current_one_filesystem);
+        let empty_mount_points = std::mem::take(&mut self.current_empty_mount_points);
+        let max_io_size = self.current_max_io_size.take();
+        let landlock = std::mem::take(&mut self.current_landlock);
+        let read_only = std::mem::take(&mut self.current_read_only);
+        let direct_io = std::mem::take(&mut self.current_direct_io);
+        let proc_fd = self.current_proc_fd.take();
 
         self.configs.push(FsConfig::Path {
             tag,
             path: path.as_ref().to_path_buf(),
-            shm_size,
+            dax,
+            uid_map,
+            gid_map,
+            atime,
+            queues,
+            init,
+            hidden,
+            xattr_map,
+            one_filesystem,
+            empty_mount_points,
+            max_io_size,
+            landlock,
+            read_only,
+            direct_io,
+            proc_fd,
         });
         self
     }
 
-    /// Set the DAX shared memory size for the next mount.
-    pub fn shm_size(mut self, size: usize) -> Self {
-        self.current_shm_size = Some(size);
+    /// Set whether the next mount uses DAX, and the size of its shared memory window.
+    ///
+    /// A window of several GiB suits guests that map large files, such as model weights. Leave
+    /// DAX off on small VMs to save guest address space. `VmBuilder::build()` fails if the window
+    /// is empty or DAX isn't available in this build.
+    pub fn dax(mut self, dax: DaxConfig) -> Self {
+        self.current_dax = Some(dax);
+        self
+    }
+
+    /// Set the DAX shared memory size for the next mount, rounded up to a whole MiB.
+    ///
+    /// Same as `dax(DaxConfig::WindowMib(..))`.
+    pub fn shm_size(self, size: usize) -> Self {
+        self.dax(DaxConfig::WindowMib(size.div_ceil(1 << 20) as u64))
+    }
+
+    /// Shift file ownership for the next `root()` or `path()` mount.
+    ///
+    /// Guest ids are translated to host ids through `uid_map`/`gid_map` when files are created or
+    /// chowned, and host owners are translated back when the guest stats them. Host ids outside
+    /// the maps show up as the map's overflow id.
+    pub fn idmap(mut self, uid_map: IdMap, gid_map: IdMap) -> Self {
+        self.current_idmap = Some((uid_map, gid_map));
+        self
+    }
+
+    /// Set which access time updates reach the host for the next `root()` or `path()` mount.
+    ///
+    /// Use `AtimePolicy::NoAtime` to keep guest reads from updating atimes in a shared image
+    /// store.
+    pub fn atime(mut self, policy: AtimePolicy) -> Self {
+        self.current_atime = Some(policy);
+        self
+    }
+
+    /// Set the number of request queues for the next mount. Defaults to 1.
+    ///
+    /// Each queue is served by its own host thread, so a slow request on one queue doesn't hold
+    /// up the others. Guest kernels that support multiqueue virtio-fs (Linux 6.11 and newer) spread
+    /// their requests over the queues by CPU.
+    pub fn queues(mut self, n: usize) -> Self {
+        self.current_queues = Some(n);
+        self
+    }
+
+    /// Serve the file at `path` as `init.krun` on the next `root()` or `path()` mount, instead
+    /// of the init binary built into the library.
+    ///
+    /// The file is read when the VM is built. `VmBuilder::build()` fails if it can't be read or
+    /// isn't an ELF executable for the host's architecture.
+    pub fn init_from(mut self, path: impl AsRef<Path>) -> Self {
+        self.current_init = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Hide `path`, relative to the shared directory, from the guest on the next `root()` or
+    /// `path()` mount. Call it again to hide more.
+    ///
+    /// The guest can't look it up or list it, and creating or renaming anything onto it fails
+    /// with `EPERM`, so it can't be replaced either. It stays hidden if the guest renames the
+    /// directories above it, which must exist when the guest mounts the share.
+    /// `VmBuilder::build()` fails unless `path` is relative and free of `.` and `..`.
+    pub fn hide(mut self, path: impl AsRef<Path>) -> Self {
+        self.current_hidden.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Translate extended attribute names between the guest and the host for the next `root()` or
+    /// `path()` mount.
+    ///
+    /// Guests that set `trusted.*` or `security.*` attributes, such as container runtimes, get
+    /// `EPERM` on an unprivileged host. A rule storing them under `user.` keeps them working:
+    /// `parse_xattr_map(":map:trusted.:user.virtiofs.:")` builds one from the same syntax as
+    /// virtiofsd's `--xattrmap`.
+    pub fn xattr_map(mut self, rules: Vec<XattrRule>) -> Self {
+        self.current_xattr_map = rules;
+        self
+    }
+
+    /// Keep the guest on the host filesystem of the next `root()` or `path()` mount, like
+    /// `find -xdev`.
+    ///
+    /// Anything mounted beneath the shared directory can't be looked up and is left out of
+    /// directory listings, so sharing `/` doesn't also share `/proc`, `/sys` or network mounts.
+    pub fn one_filesystem(mut self, enabled: bool) -> Self {
+        self.current_one_filesystem = enabled;
+        self
+    }
+
+    /// With `one_filesystem(true)`, show the mount points of the next `root()` or `path()` mount
+    /// as empty directories instead of hiding them, for guests that expect them to exist.
+    pub fn empty_mount_points(mut self, enabled: bool) -> Self {
+        self.current_empty_mount_points = enabled;
+        self
+    }
+
+    /// Let the guest move up to `bytes` in a single read or write request to the next `root()`
+    /// or `path()` mount, instead of 1 MiB.
+    ///
+    /// Larger requests speed up big sequential transfers, smaller ones keep a busy share from
+    /// holding up other requests for long. `bytes` must be a multiple of 4 KiB that fits in the
+    /// device's queues, or `VmBuilder::build()` fails. Linux guests also cap requests at 1 MiB
+    /// unless their `fs.fuse.max_pages_limit` sysctl is raised.
+    pub fn max_io_size(mut self, bytes: u32) -> Self {
+        self.current_max_io_size = Some(bytes);
+        self
+    }
+
+    /// Confine the threads serving the next `root()` or `path()` mount to its directory with
+    /// Landlock (Linux 5.13 and newer), so the host kernel refuses to open anything outside the
+    /// share for them even if the guest got past the path checks.
+    ///
+    /// On hosts without Landlock the share works as usual and a warning is logged.
+    /// `VmBuilder::build()` fails if this is enabled on a host OS other than Linux.
+    #[cfg(feature = "landlock")]
+    pub fn landlock(mut self, enabled: bool) -> Self {
+        self.current_landlock = enabled;
+        self
+    }
+
+    /// Keep the guest from changing anything in the next `root()` or `path()` mount, even where
+    /// the host permissions would let it.
+    ///
+    /// Files are only opened for reading, and creating, removing, renaming or writing to anything
+    /// fails with `EROFS`.
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self.current_read_only = enabled;
+        self
+    }
+
+    /// Let files the guest opens with `O_DIRECT` in the next `root()` or `path()` mount bypass
+    /// the host's page cache too, for workloads that test what actually reaches the disk.
+    ///
+    /// On Linux hosts the files are opened with `O_DIRECT`, and report the alignment it needs as
+    /// their block size. On macOS they're set to `F_NOCACHE`. When this is off, the guest's
+    /// `O_DIRECT` is dropped and counted in the share's metrics.
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.current_direct_io = enabled;
+        self
+    }
+
+    /// Serve the next `root()` or `path()` mount through `fd`, an open `/proc/self/fd` directory,
+    /// instead of opening `/proc/self/fd` when the VM starts.
+    ///
+    /// For hosts that drop `/proc` from view, such as a sandbox after `pivot_root`: open
+    /// `/proc/self/fd` with `O_PATH` beforehand and pass it here.
+    #[cfg(target_os = "linux")]
+    pub fn proc_fd(mut self, fd: OwnedFd) -> Self {
+        self.current_proc_fd = Some(fd);
+        self
+    }
+
+    /// Reserve `n` slots for shares added while the VM runs, through
+    /// [`Vm::fs_shares()`](super::vm::Vm::fs_shares).
+    ///
+    /// Each slot takes up a virtio-mmio device from boot on, and shares
+    /// added to it have a single request queue and no DAX window.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fn hotplug_slots(mut self, n: usize) -> Self {
+        self.hotplug_slots += n;
+        self
+    }
+
+    /// Let the guest write to the file shared by the next `file()` mount, which is read-only
+    /// otherwise.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fn writable(mut self, enabled: bool) -> Self {
+        self.current_writable = enabled;
+        self
+    }
+
+    /// Share the single host file at `path`, which the guest sees as `name` in an otherwise
+    /// empty, read-only directory.
+    ///
+    /// Handy for one large asset such as model weights, without building a directory around it.
+    /// `dax()` lets the guest map the file. `VmBuilder::build()` fails if `path` isn't a regular
+    /// file or `name` isn't a plain file name.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fn file(mut self, path: impl AsRef<Path>, name: &str) -> Self {
+        let tag = self
+            .current_tag
+            .take()
+            .unwrap_or_else(|| format!("fs{}", self.configs.len()));
+        let dax = self.current_dax.take().unwrap_or_default();
+        let queues = self.current_queues.take().unwrap_or(1);
+        let writable = std::mem::take(&mut self.current_writable);
+
+        self.configs.push(FsConfig::File {
+            tag,
+            path: path.as_ref().to_path_buf(),
+            name: name.to_string(),
+            writable,
+            dax,
+            queues,
+        });
         self
     }
 
@@ -460,8 +972,15 @@ impl FsBuilder {
             .current_tag
             .take()
             .unwrap_or_else(|| format!("fs{}", self.configs.len()));
+        let dax = self.current_dax.take().unwrap_or_default();
+        let queues = self.current_queues.take().unwrap_or(1);
 
-        self.configs.push(FsConfig::Custom { tag, backend });
+        self.configs.push(FsConfig::Custom {
+            tag,
+            backend,
+            dax,
+            queues,
+        });
         self
     }
 }
@@ -482,7 +1001,9 @@ impl NetBuilder {
     pub fn new() -> Self {
         Self {
             configs: Vec::new(),
+            dns: None,
             current_mac: None,
+            current_options: NetOptions::default(),
         }
     }
 
@@ -492,17 +1013,135 @@ impl NetBuilder {
         self
     }
 
+    /// Set the number of receive/transmit queue pairs for the next network
+    /// device (default: 1).
+    ///
+    /// With more than one, the guest can spread its traffic over several
+    /// vCPUs, and every pair is handled by its own host thread with its own
+    /// handle to the backend (see
+    /// [`NetBackend::try_clone()`](crate::backends::net::NetBackend::try_clone)).
+    /// The unixstream backend only supports a single pair.
+    pub fn queues(mut self, queues: u16) -> Self {
+        self.current_options.queues = queues;
+        self
+    }
+
+    /// Set the checksum and segmentation offloads the next network device
+    /// offers the guest (default: none).
+    ///
+    /// Only enable the offloads the backend can handle: with `csum`, frames
+    /// may reach it with a partial checksum, and with the segmentation
+    /// offloads, much larger than the MTU. Without `csum`, no segmentation
+    /// offload is offered either, and the device fills in any partial
+    /// checksum a guest still sends. A TAP backend is set up to match.
+    pub fn offloads(mut self, offloads: Offloads) -> Self {
+        self.current_options.offloads = offloads;
+        self
+    }
+
+    /// Cap the frames and bytes per second the next network device sends and
+    /// receives, each way on its own. 0 leaves either unlimited, which is the
+    /// default.
+    ///
+    /// Frames over the limit are held back, which slows down what the guest
+    /// sends and, once the backend's buffers fill up, drops what it would
+    /// receive, like a congested link. Change it while the VM runs with
+    /// [`Vm::set_net_rate_limit()`](super::vm::Vm::set_net_rate_limit).
+    pub fn rate_limit(mut self, pps: u64, bytes_per_sec: u64) -> Self {
+        self.current_options.rate_limit.ops_per_sec = pps;
+        self.current_options.rate_limit.bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Set how many frames and bytes the next network device lets through at
+    /// once after a quiet spell. 0 means one second's worth, which is the
+    /// default.
+    pub fn rate_limit_burst(mut self, frames: u64, bytes: u64) -> Self {
+        self.current_options.rate_limit.ops_burst = frames;
+        self.current_options.rate_limit.bytes_burst = bytes;
+        self
+    }
+
+    /// Give the next network device a static address in the guest, for
+    /// backends such as TAP that have no DHCP server behind them.
+    ///
+    /// `cidr` is the address with its prefix length, like `10.0.0.2/24` or
+    /// `fd00::2/64`, and `gateway` the default route's next hop, which has
+    /// to be in that subnet. The guest's init sets both up before starting
+    /// the workload, on `eth0` for the first device added, `eth1` for the
+    /// second, and so on. With `dns`, the guest's `/etc/resolv.conf` uses
+    /// that server, unless [`dns()`](Self::dns) gives others.
+    ///
+    /// A DHCP client run by the guest itself isn't stopped, and may replace
+    /// the address or add one, so use either that or this.
+    /// [`build()`](super::builder::VmBuilder::build) fails if something
+    /// doesn't parse.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::VmBuilder;
+    /// # #[cfg(target_os = "linux")]
+    /// VmBuilder::new().net(|n| {
+    ///     n.static_ip("10.0.0.2/24", "10.0.0.1", Some("10.0.0.1"))
+    ///         .tap("tap0")
+    /// });
+    /// ```
+    pub fn static_ip(mut self, cidr: &str, gateway: &str, dns: Option<&str>) -> Self {
+        self.current_options.static_ip = Some(StaticIp {
+            cidr: cidr.to_string(),
+            gateway: gateway.to_string(),
+            dns: dns.map(String::from),
+        });
+        self
+    }
+
+    fn push(&mut self, config: NetConfig) {
+        let options = std::mem::take(&mut self.current_options);
+        self.configs.push((config, options));
+    }
+
+    /// Set the DNS servers and search domains of the guest's
+    /// `/etc/resolv.conf`.
+    ///
+    /// The guest's init writes the file at boot, covering the root
+    /// filesystem's for this VM only, so it works with a read-only root too.
+    /// Without this, a VM whose network goes through TSI (no network device)
+    /// gets the host's resolvers, with queries forwarded by the host;
+    /// otherwise the root filesystem's file is left as it is.
+    ///
+    /// [`build()`](super::builder::VmBuilder::build) fails if `servers` is
+    /// empty or has an address that can't be a server's, such as `0.0.0.0`
+    /// or a multicast one, or if a search domain isn't a valid domain name.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::VmBuilder;
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// VmBuilder::new().net(|n| {
+    ///     n.dns(&[IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53))], &["corp.example.com"])
+    /// });
+    /// ```
+    pub fn dns(mut self, servers: &[IpAddr], search: &[&str]) -> Self {
+        self.dns = Some(DnsConfig {
+            servers: servers.to_vec(),
+            search: search.iter().map(|s| s.to_string()).collect(),
+        });
+        self
+    }
+
     /// Attach a unixgram network backend from a pre-opened fd.
     pub fn unixgram(mut self, fd: OwnedFd) -> Self {
         let mac = self.current_mac.take();
-        self.configs.push(NetConfig::UnixgramFd { mac, fd });
+        self.push(NetConfig::UnixgramFd { mac, fd });
         self
     }
 
     /// Attach a unixgram network backend connecting to a socket path.
     pub fn unixgram_path(mut self, path: impl AsRef<Path>, send_vfkit_magic: bool) -> Self {
         let mac = self.current_mac.take();
-        self.configs.push(NetConfig::UnixgramPath {
+        self.push(NetConfig::UnixgramPath {
             mac,
             path: path.as_ref().to_path_buf(),
             send_vfkit_magic,
@@ -513,14 +1152,14 @@ impl NetBuilder {
     /// Attach a unixstream network backend from a pre-opened fd.
     pub fn unixstream(mut self, fd: OwnedFd) -> Self {
         let mac = self.current_mac.take();
-        self.configs.push(NetConfig::UnixstreamFd { mac, fd });
+        self.push(NetConfig::UnixstreamFd { mac, fd });
         self
     }
 
     /// Attach a unixstream network backend connecting to a socket path.
     pub fn unixstream_path(mut self, path: impl AsRef<Path>) -> Self {
         let mac = self.current_mac.take();
-        self.configs.push(NetConfig::UnixstreamPath {
+        self.push(NetConfig::UnixstreamPath {
             mac,
             path: path.as_ref().to_path_buf(),
         });
@@ -531,7 +1170,7 @@ impl NetBuilder {
     #[cfg(target_os = "linux")]
     pub fn tap(mut self, name: impl Into<String>) -> Self {
         let mac = self.current_mac.take();
-        self.configs.push(NetConfig::Tap {
+        self.push(NetConfig::Tap {
             mac,
             name: name.into(),
         });
@@ -541,11 +1180,23 @@ impl NetBuilder {
     /// Use a custom network backend.
     pub fn custom(mut self, backend: Box<dyn NetBackend + Send>) -> Self {
         let mac = self.current_mac.take();
-        self.configs.push(NetConfig::Custom { mac, backend });
+        self.push(NetConfig::Custom { mac, backend });
         self
     }
 }
 
+#[cfg(feature = "net")]
+impl Default for NetOptions {
+    fn default() -> Self {
+        Self {
+            queues: 1,
+            offloads: Offloads::default(),
+            rate_limit: RateLimit::default(),
+            static_ip: None,
+        }
+    }
+}
+
 #[cfg(feature = "net")]
 impl Default for NetBuilder {
     fn default() -> Self {
@@ -569,6 +1220,26 @@ impl ConsoleBuilder {
         self
     }
 
+    /// Set the text that tells the guest kernel panicked when it shows up on
+    /// its console. An empty pattern turns panic detection off.
+    ///
+    /// Defaults to Linux's `Kernel panic - not syncing`. Once it shows up,
+    /// [`Vm::enter()`](super::vm::Vm::enter) stops the VM and returns
+    /// [`RuntimeError::GuestPanic`](super::error::RuntimeError::GuestPanic).
+    pub fn panic_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.panic_pattern = Some(pattern.into());
+        self
+    }
+
+    /// Set how many of the last bytes of console output to keep for
+    /// [`RuntimeError::GuestPanic`](super::error::RuntimeError::GuestPanic).
+    /// Defaults to 16 KiB. They're kept wherever the output goes, even
+    /// nowhere.
+    pub fn tail_size(mut self, bytes: usize) -> Self {
+        self.tail_size = Some(bytes);
+        self
+    }
+
     /// Enable the virtio-snd device.
     #[cfg(feature = "snd")]
     pub fn sound(mut self, enabled: bool) -> Self {
@@ -590,17 +1261,38 @@ impl ConsoleBuilder {
         self
     }
 
-    /// Add a bidirectional I/O port to the console device.
+    /// Add a named port to the console device, connected to `dest` on the host.
     ///
-    /// Creates a named port accessible in the guest via `/sys/class/virtio-ports/<name>`.
-    /// The host reads from `input_fd` and writes to `output_fd`. Pass the same FD for both
-    /// when using a bidirectional socket.
-    pub fn port(mut self, name: &str, input_fd: RawFd, output_fd: RawFd) -> Self {
-        self.ports.push(PortConfig::InOut {
-            name: name.to_string(),
-            input_fd,
-            output_fd,
-        });
+    /// The guest sees it as a `/dev/vport*` device, with its name in
+    /// `/sys/class/virtio-ports/<port>/name` for udev to create a
+    /// `/dev/virtio-ports/<name>` link. The primary console is unaffected.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::{PortDest, VmBuilder};
+    /// VmBuilder::new()
+    ///     .console(|c| {
+    ///         c.port("log", PortDest::UnixSocket("/tmp/vm-log.sock".into()))
+    ///             .port("unused", PortDest::Null)
+    ///     });
+    /// ```
+    pub fn port(mut self, name: &str, dest: PortDest) -> Self {
+        let name = name.to_string();
+        let port = match dest {
+            PortDest::UnixSocket(path) => ConsolePortConfig::UnixSocket { name, path },
+            PortDest::Pipe { input, output } => ConsolePortConfig::Port(PortConfig::InOut {
+                name,
+                input_fd: input,
+                output_fd: output,
+            }),
+            PortDest::Null => ConsolePortConfig::Port(PortConfig::Custom {
+                name,
+                input: Box::new(PortInputEmpty::new()),
+                output: port_io::output_null(),
+            }),
+        };
+        self.ports.push(port);
         self
     }
 
@@ -610,10 +1302,10 @@ impl ConsoleBuilder {
     /// The `tty_fd` must be a valid terminal file descriptor. Terminal raw mode is configured
     /// automatically.
     pub fn port_tty(mut self, name: &str, tty_fd: RawFd) -> Self {
-        self.ports.push(PortConfig::Tty {
+        self.ports.push(ConsolePortConfig::Port(PortConfig::Tty {
             name: name.to_string(),
             tty_fd,
-        });
+        }));
         self
     }
 
@@ -643,11 +1335,11 @@ impl ConsoleBuilder {
         let backend: Arc<dyn ConsolePortBackend> = Arc::from(backend);
         let input = Box::new(ConsolePortBackendInputAdapter::new(Arc::clone(&backend)));
         let output = Box::new(ConsolePortBackendOutputAdapter::new(backend));
-        self.ports.push(PortConfig::Custom {
+        self.ports.push(ConsolePortConfig::Port(PortConfig::Custom {
             name: name.to_string(),
             input,
             output,
-        });
+        }));
         self
     }
 }
@@ -709,6 +1401,23 @@ impl ExecBuilder {
         self.rlimits.push((resource.to_string(), soft, hard));
         self
     }
+
+    /// Set whether the workload's stdin, stdout and stderr are a terminal,
+    /// for programs that act differently when `isatty()` says so.
+    ///
+    /// The workload's output goes to the same place in every mode, the
+    /// console's [`output()`](ConsoleBuilder::output) file included.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use msb_krun::{TtyMode, VmBuilder};
+    /// VmBuilder::new().exec(|e| e.path("/bin/ls").tty(TtyMode::Never));
+    /// ```
+    pub fn tty(mut self, mode: TtyMode) -> Self {
+        self.tty = mode;
+        self
+    }
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -724,6 +1433,9 @@ impl DiskBuilder {
             current_path: None,
             current_read_only: false,
             current_format: DiskImageFormat::Raw,
+            current_cache: CacheMode::default(),
+            current_rate_limit: RateLimit::default(),
+            hotplug_slots: 0,
         }
     }
 
@@ -741,9 +1453,13 @@ impl DiskBuilder {
                 path: pending_path,
                 read_only: self.current_read_only,
                 format: self.current_format,
+                cache: self.current_cache,
+                rate_limit: self.current_rate_limit,
             });
             self.current_read_only = false;
             self.current_format = DiskImageFormat::Raw;
+            self.current_cache = CacheMode::default();
+            self.current_rate_limit = RateLimit::default();
         }
 
         self.current_path = Some(path.as_ref().to_path_buf());
@@ -756,6 +1472,41 @@ impl DiskBuilder {
         self
     }
 
+    /// Set the cache mode for the current disk. Defaults to `CacheMode::Writeback`.
+    pub fn cache(mut self, mode: CacheMode) -> Self {
+        self.current_cache = mode;
+        self
+    }
+
+    /// Cap the requests and bytes per second of the current disk. 0 leaves
+    /// either unlimited, which is the default.
+    ///
+    /// A guest going over the limit sees its requests slow down. Change it
+    /// while the VM runs with [`Vm::set_disk_rate_limit()`](super::vm::Vm::set_disk_rate_limit).
+    pub fn rate_limit(mut self, ops_per_sec: u64, bytes_per_sec: u64) -> Self {
+        self.current_rate_limit.ops_per_sec = ops_per_sec;
+        self.current_rate_limit.bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Set how many requests and bytes the current disk lets through at once
+    /// after a quiet spell. 0 means one second's worth, which is the default.
+    pub fn rate_limit_burst(mut self, ops: u64, bytes: u64) -> Self {
+        self.current_rate_limit.ops_burst = ops;
+        self.current_rate_limit.bytes_burst = bytes;
+        self
+    }
+
+    /// Reserve `n` slots for disks added while the VM runs, through
+    /// [`Vm::disks()`](super::vm::Vm::disks).
+    ///
+    /// Each slot takes up a virtio-mmio device and a disk ID from boot on,
+    /// after those of the disks given with [`path()`](Self::path).
+    pub fn hotplug_slots(mut self, n: usize) -> Self {
+        self.hotplug_slots += n;
+        self
+    }
+
     /// Finalize the builder (called internally).
     pub(crate) fn finalize(mut self) -> Self {
         if let Some(path) = self.current_path.take() {
@@ -763,6 +1514,8 @@ impl DiskBuilder {
                 path,
                 read_only: self.current_read_only,
                 format: self.current_format,
+                cache: self.current_cache,
+                rate_limit: self.current_rate_limit,
             });
         }
         self
@@ -790,3 +1543,63 @@ impl From<DiskImageFormat> for devices::virtio::block::ImageType {
         }
     }
 }
+
+//--------------------------------------------------------------------------------------------------
+// Methods: Balloon Builder
+//--------------------------------------------------------------------------------------------------
+
+impl BalloonBuilder {
+    /// Create a new balloon builder.
+    pub fn new() -> Self {
+        Self {
+            free_page_reporting: true,
+        }
+    }
+
+    /// Offer the guest free page reporting. Enabled by default.
+    ///
+    /// The guest then tells the host about the memory it frees, which is
+    /// dropped from the host's memory so the VM's RSS shrinks. The guest gets
+    /// it back, zeroed, the next time it touches it. See
+    /// [`Vm::balloon_metrics()`](super::vm::Vm::balloon_metrics) for how
+    /// much was given back.
+    pub fn free_page_reporting(mut self, enabled: bool) -> Self {
+        self.free_page_reporting = enabled;
+        self
+    }
+}
+
+impl Default for BalloonBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods: Vsock Builder
+//--------------------------------------------------------------------------------------------------
+
+impl VsockBuilder {
+    /// Create a new vsock builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listen on vsock `port` of the host.
+    ///
+    /// Each connection the guest makes to the port is handed to `handler` on
+    /// a thread of its own, as a [`VsockStream`] that can be read and written
+    /// like a TCP stream. Writes block while the guest has no room for more
+    /// data. The connection is reset in the guest when the stream is dropped,
+    /// and reads return 0 once the guest closes it or the VM exits.
+    ///
+    /// This attaches the vsock device even if nothing else needs it.
+    pub fn on_connect(
+        mut self,
+        port: u32,
+        handler: impl Fn(VsockStream) + Send + Sync + 'static,
+    ) -> Self {
+        self.listeners.insert(port, Arc::new(handler));
+        self
+    }
+}
@@ -0,0 +1,179 @@
+//! Handles for changing the disks of a running VM.
+
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::{Block, BlockSlot, RateLimit};
+use vmm::vmm_config::block::BlockBuilder;
+
+use super::builder::block_device_config;
+use super::builders::DiskConfig;
+use super::error::{ConfigError, Error, Result};
+
+pub use devices::virtio::BlockMetricsSnapshot;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A thread-safe, cloneable handle to one of the VM's disks.
+///
+/// Obtained via [`Vm::disk()`](super::vm::Vm::disk) before calling
+/// [`Vm::enter()`](super::vm::Vm::enter), and usable from any thread while
+/// the VM runs.
+#[derive(Clone)]
+pub struct DiskHandle {
+    block: Arc<Mutex<Block>>,
+}
+
+/// I/O counters of a disk, as returned by [`DiskHandle::stats()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    /// Counts since the VM was built. `max_queue_depth` is the most
+    /// requests the disk ever had in flight at once.
+    pub total: BlockMetricsSnapshot,
+
+    /// How much the counts grew since the previous call, from any handle to
+    /// the same disk. `max_queue_depth` is the most requests in flight at
+    /// once in that time.
+    pub delta: BlockMetricsSnapshot,
+}
+
+/// A thread-safe, cloneable handle for adding disks to the VM and removing
+/// them while it runs.
+///
+/// Obtained via [`Vm::disks()`](super::vm::Vm::disks) before calling
+/// [`Vm::enter()`](super::vm::Vm::enter). Disks go into the slots reserved
+/// with [`DiskBuilder::hotplug_slots()`](super::builders::DiskBuilder::hotplug_slots),
+/// which the guest sees as placeholder virtio-mmio devices until a disk is
+/// added. The guest picks up a new disk by probing its device again, for
+/// example by writing the device's name to
+/// `/sys/bus/platform/drivers/virtio-mmio/bind`.
+#[derive(Clone)]
+pub struct DisksHandle {
+    slots: Vec<Arc<Mutex<BlockSlot>>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl DiskHandle {
+    pub(crate) fn new(block: Arc<Mutex<Block>>) -> Self {
+        Self { block }
+    }
+
+    /// The ID of the disk in the guest, such as `vda`.
+    pub fn id(&self) -> String {
+        self.block.lock().unwrap().id().clone()
+    }
+
+    /// Read the I/O counters of the disk.
+    ///
+    /// Counters are updated as the guest's requests complete, without
+    /// stopping the disk, so this is cheap to poll while the VM runs.
+    pub fn stats(&self) -> DiskStats {
+        let metrics = self.block.lock().unwrap().metrics();
+        let delta = metrics.interval();
+        DiskStats {
+            total: metrics.snapshot(),
+            delta,
+        }
+    }
+
+    /// Resize the disk to `new_size` bytes and let the guest know.
+    ///
+    /// The backing image is grown or truncated to match, keeping any data
+    /// the host already added past the old end. `new_size` must be a
+    /// multiple of 512. Shrinking drops the data past the new end, so it
+    /// fails unless `force` is set.
+    pub fn resize(&self, new_size: u64, force: bool) -> Result<()> {
+        self.block
+            .lock()
+            .unwrap()
+            .resize(new_size, force)
+            .map_err(Error::Io)
+    }
+
+    /// The rate limit of the disk.
+    pub fn rate_limit(&self) -> RateLimit {
+        self.block.lock().unwrap().rate_limit()
+    }
+
+    /// Replace the rate limit of the disk, as set with
+    /// [`DiskBuilder::rate_limit()`](super::builders::DiskBuilder::rate_limit).
+    ///
+    /// Requests held back by the old limit go on within 100 ms.
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.block.lock().unwrap().set_rate_limit(limit);
+    }
+}
+
+impl DisksHandle {
+    pub(crate) fn new(slots: Vec<Arc<Mutex<BlockSlot>>>) -> Self {
+        Self { slots }
+    }
+
+    /// Add the disk described by `config` in the first free slot, returning
+    /// its ID in the guest, such as `vdc`.
+    ///
+    /// Fails if the image can't be opened or every hotplug slot is taken.
+    pub fn add(&self, config: DiskConfig) -> Result<String> {
+        let slot = self
+            .slots
+            .iter()
+            .find(|slot| slot.lock().unwrap().is_vacant())
+            .ok_or_else(|| {
+                Error::Config(ConfigError::Block(format!(
+                    "all {} hotplug slots are in use, reserve more with DiskBuilder::hotplug_slots()",
+                    self.slots.len()
+                )))
+            })?;
+
+        let mut slot = slot.lock().unwrap();
+        let id = slot.id().clone();
+        let block = BlockBuilder::create_block(block_device_config(id.clone(), config))
+            .map_err(|e| Error::Config(ConfigError::Block(format!("{id}: {e}"))))?;
+        slot.plug(block).map_err(Error::Io)?;
+        Ok(id)
+    }
+
+    /// Remove the disk with the given ID, freeing its slot.
+    ///
+    /// Requests the guest has in flight complete first, and the image is
+    /// synced to the host before this returns. Fails with `EBUSY` while the
+    /// guest's driver is bound to the disk, unless `force` is set, in which
+    /// case the guest's further requests to it go unanswered.
+    pub fn remove(&self, id: &str, force: bool) -> Result<()> {
+        let slot = self
+            .slots
+            .iter()
+            .find(|slot| {
+                let slot = slot.lock().unwrap();
+                !slot.is_vacant() && slot.id() == id
+            })
+            .ok_or_else(|| {
+                Error::Config(ConfigError::Block(format!(
+                    "{id}: no disk was added with this ID"
+                )))
+            })?;
+        slot.lock().unwrap().unplug(force).map_err(Error::Io)
+    }
+
+    /// The IDs of the disks added so far and not removed.
+    pub fn ids(&self) -> Vec<String> {
+        self.slots
+            .iter()
+            .map(|slot| slot.lock().unwrap())
+            .filter(|slot| !slot.is_vacant())
+            .map(|slot| slot.id().clone())
+            .collect()
+    }
+
+    /// How many more disks can be added.
+    pub fn free_slots(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.lock().unwrap().is_vacant())
+            .count()
+    }
+}
@@ -53,6 +53,13 @@ pub enum ConfigError {
 
     /// Vsock configuration error.
     Vsock(String),
+
+    /// Guest hostname or host entry configuration error.
+    Hostname(String),
+
+    /// Debugger configuration error.
+    #[cfg(feature = "gdbstub")]
+    Gdb(String),
 }
 
 /// VM build errors.
@@ -85,6 +92,14 @@ pub enum RuntimeError {
 
     /// Shutdown error.
     Shutdown(String),
+
+    /// A thread of the VMM was stopped for making a system call its sandbox
+    /// doesn't allow. `syscall` is the number of the system call.
+    SandboxViolation { thread: String, syscall: i64 },
+
+    /// The guest kernel panicked. `console_tail` is the end of what the
+    /// guest wrote to its console, with the panic in it.
+    GuestPanic { console_tail: String },
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -114,6 +129,9 @@ impl fmt::Display for ConfigError {
             ConfigError::Block(s) => write!(f, "block device: {}", s),
             ConfigError::Console(s) => write!(f, "console: {}", s),
             ConfigError::Vsock(s) => write!(f, "vsock: {}", s),
+            ConfigError::Hostname(s) => write!(f, "hostname: {}", s),
+            #[cfg(feature = "gdbstub")]
+            ConfigError::Gdb(s) => write!(f, "gdb: {}", s),
         }
     }
 }
@@ -136,6 +154,14 @@ impl fmt::Display for RuntimeError {
             RuntimeError::AlreadyRunning => write!(f, "VM is already running"),
             RuntimeError::NotStarted => write!(f, "VM has not been started"),
             RuntimeError::Shutdown(s) => write!(f, "shutdown: {}", s),
+            RuntimeError::SandboxViolation { thread, syscall } => {
+                write!(
+                    f,
+                    "{} thread made disallowed system call {}",
+                    thread, syscall
+                )
+            }
+            RuntimeError::GuestPanic { .. } => write!(f, "guest kernel panicked"),
         }
     }
 }
@@ -5,6 +5,8 @@ use std::{io, result};
 
 use utils::eventfd::EventFd;
 
+pub use devices::virtio::{ExitStatus, SharedExitStatus};
+
 //--------------------------------------------------------------------------------------------------
 // Types
 //--------------------------------------------------------------------------------------------------
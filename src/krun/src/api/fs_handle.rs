@@ -0,0 +1,129 @@
+//! Handle for plugging filesystem shares into a running VM.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::fs::passthrough;
+use devices::virtio::Fs;
+
+use super::error::{ConfigError, Error, Result};
+use crate::backends::fs::DynFileSystem;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A thread-safe, cloneable handle for adding virtio-fs shares to the VM
+/// and removing them while it runs.
+///
+/// Obtained via [`Vm::fs_shares()`](super::vm::Vm::fs_shares) before
+/// calling [`Vm::enter()`](super::vm::Vm::enter). Shares go into the slots
+/// reserved with [`FsBuilder::hotplug_slots()`](super::builders::FsBuilder::hotplug_slots),
+/// which the guest sees as placeholder virtio-mmio devices until a share is
+/// added. The guest picks up a new share by probing its device again, for
+/// example by writing the device's name to
+/// `/sys/bus/platform/drivers/virtio-mmio/bind`, and then mounts it with
+/// `mount -t virtiofs <tag> <dir>`.
+#[derive(Clone)]
+pub struct FsSharesHandle {
+    slots: Vec<Arc<Mutex<Fs>>>,
+    boot_tags: Vec<String>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsSharesHandle {
+    pub(crate) fn new(slots: Vec<Arc<Mutex<Fs>>>, boot_tags: Vec<String>) -> Self {
+        Self { slots, boot_tags }
+    }
+
+    /// Share the host directory at `path` with the guest under `tag`.
+    ///
+    /// Nothing tells the guest about the new share; it shows up once the
+    /// guest probes the slot's device again, as described on the type.
+    ///
+    /// Fails if `tag` is already in use or every hotplug slot is taken.
+    pub fn add(&self, tag: &str, path: impl AsRef<Path>) -> Result<()> {
+        let cfg = passthrough::Config {
+            root_dir: path.as_ref().to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        self.with_free_slot(tag, |fs| fs.plug_passthrough(tag, cfg))
+    }
+
+    /// Share a custom filesystem backend with the guest under `tag`.
+    ///
+    /// Fails if `tag` is already in use or every hotplug slot is taken.
+    pub fn add_custom(
+        &self,
+        tag: &str,
+        backend: Box<dyn DynFileSystem + Send + Sync>,
+    ) -> Result<()> {
+        let backend: Arc<dyn DynFileSystem + Send + Sync> = Arc::from(backend);
+        self.with_free_slot(tag, |fs| fs.plug_custom(tag, backend))
+    }
+
+    /// Remove the share added under `tag`, freeing its slot.
+    ///
+    /// Fails while the guest has the share mounted, unless `force` is set,
+    /// in which case the guest's further requests to it go unanswered.
+    pub fn remove(&self, tag: &str, force: bool) -> Result<()> {
+        let slot = self
+            .slots
+            .iter()
+            .find(|slot| {
+                let fs = slot.lock().unwrap();
+                !fs.is_vacant() && fs.tag() == tag
+            })
+            .ok_or_else(|| {
+                Error::Config(ConfigError::Filesystem(format!(
+                    "{tag}: no share was added with this tag"
+                )))
+            })?;
+        slot.lock().unwrap().unplug(force).map_err(Error::Io)
+    }
+
+    /// The tags of the shares added so far and not removed.
+    pub fn tags(&self) -> Vec<String> {
+        self.slots
+            .iter()
+            .map(|slot| slot.lock().unwrap())
+            .filter(|fs| !fs.is_vacant())
+            .map(|fs| fs.tag())
+            .collect()
+    }
+
+    /// How many more shares can be added.
+    pub fn free_slots(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.lock().unwrap().is_vacant())
+            .count()
+    }
+
+    fn with_free_slot(
+        &self,
+        tag: &str,
+        plug: impl FnOnce(&mut Fs) -> std::io::Result<()>,
+    ) -> Result<()> {
+        if self.boot_tags.iter().any(|t| t == tag) || self.tags().iter().any(|t| t == tag) {
+            return Err(Error::Config(ConfigError::Filesystem(format!(
+                "{tag}: tag already in use"
+            ))));
+        }
+
+        for slot in &self.slots {
+            let mut fs = slot.lock().unwrap();
+            if fs.is_vacant() {
+                return plug(&mut fs).map_err(Error::Io);
+            }
+        }
+
+        Err(Error::Config(ConfigError::Filesystem(format!(
+            "{tag}: all {} hotplug slots are in use, reserve more with FsBuilder::hotplug_slots()",
+            self.slots.len()
+        ))))
+    }
+}
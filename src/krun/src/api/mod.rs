@@ -29,9 +29,17 @@
 
 pub mod builder;
 pub mod builders;
+#[cfg(feature = "blk")]
+pub mod disk_handle;
 pub mod error;
 pub mod exit_handle;
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+pub mod fs_handle;
+#[cfg(feature = "net")]
+pub mod net_handle;
+pub mod timing_handle;
 pub mod vm;
+pub mod vsock_handle;
 
 //--------------------------------------------------------------------------------------------------
 // Re-Exports
@@ -39,12 +47,33 @@ pub mod vm;
 
 pub use builder::VmBuilder;
 #[cfg(feature = "blk")]
+pub use builders::CacheMode;
+#[cfg(feature = "blk")]
 pub use builders::DiskBuilder;
 #[cfg(feature = "blk")]
+pub use builders::DiskConfig;
+#[cfg(feature = "blk")]
 pub use builders::DiskImageFormat;
+#[cfg(target_os = "linux")]
+pub use builders::Keep;
+#[cfg(any(feature = "blk", feature = "net"))]
+pub use builders::RateLimit;
+#[cfg(feature = "seccomp")]
+pub use builders::SandboxLevel;
+pub use builders::{
+    BalloonBuilder, BalloonMetrics, BalloonMetricsSnapshot, ConsoleBuilder, DaxConfig, ExecBuilder,
+    FsBuilder, KernelBuilder, MachineBuilder, PortDest, TtyMode, VsockBuilder, VsockStream,
+};
 #[cfg(feature = "net")]
-pub use builders::NetBuilder;
-pub use builders::{ConsoleBuilder, ExecBuilder, FsBuilder, KernelBuilder, MachineBuilder};
+pub use builders::{NetBuilder, Offloads};
+#[cfg(feature = "blk")]
+pub use disk_handle::{DiskHandle, DiskStats, DisksHandle};
 pub use error::{BuildError, ConfigError, Error, Result, RuntimeError};
-pub use exit_handle::ExitHandle;
+pub use exit_handle::{ExitHandle, ExitStatus, SharedExitStatus};
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+pub use fs_handle::FsSharesHandle;
+#[cfg(feature = "net")]
+pub use net_handle::{NetHandle, NetStats};
+pub use timing_handle::{BootTimings, TimingHandle};
 pub use vm::Vm;
+pub use vsock_handle::{VsockConnInfo, VsockConnState, VsockHandle};
@@ -0,0 +1,79 @@
+//! Handle for watching a network interface of a running VM.
+
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::net::DEFAULT_MTU;
+use devices::virtio::{Net, RateLimit, VirtioDevice};
+
+pub use devices::virtio::net::metrics::NetMetricsSnapshot;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A thread-safe, cloneable handle to one of the VM's network interfaces.
+///
+/// Obtained via [`Vm::net()`](super::vm::Vm::net) before calling
+/// [`Vm::enter()`](super::vm::Vm::enter), and usable from any thread while
+/// the VM runs.
+#[derive(Clone)]
+pub struct NetHandle {
+    net: Arc<Mutex<Net>>,
+}
+
+/// Counters and settings of a network interface, as returned by
+/// [`NetHandle::stats()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetStats {
+    /// Frames and bytes received and sent since the VM was built, summed
+    /// over all queue pairs.
+    pub counters: NetMetricsSnapshot,
+
+    /// The virtio-net feature bits the guest driver accepted, or 0 before
+    /// it has set the device up.
+    pub features: u64,
+
+    /// The MTU of the interface in the guest.
+    pub mtu: u16,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl NetHandle {
+    pub(crate) fn new(net: Arc<Mutex<Net>>) -> Self {
+        Self { net }
+    }
+
+    /// The ID of the interface, such as `eth0`.
+    pub fn id(&self) -> String {
+        self.net.lock().unwrap().id().to_string()
+    }
+
+    /// Read the counters of the interface.
+    ///
+    /// Counters are updated as frames go through, without stopping the
+    /// device, so this is cheap to poll while the VM runs.
+    pub fn stats(&self) -> NetStats {
+        let net = self.net.lock().unwrap();
+        NetStats {
+            counters: net.metrics().snapshot(),
+            features: net.acked_features(),
+            mtu: DEFAULT_MTU,
+        }
+    }
+
+    /// The rate limit of the interface, which applies to each direction.
+    pub fn rate_limit(&self) -> RateLimit {
+        self.net.lock().unwrap().rate_limit()
+    }
+
+    /// Replace the rate limit of the interface, as set with
+    /// [`NetBuilder::rate_limit()`](super::builders::NetBuilder::rate_limit).
+    ///
+    /// Frames held back by the old limit go on within 100 ms.
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.net.lock().unwrap().set_rate_limit(limit);
+    }
+}
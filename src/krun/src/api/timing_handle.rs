@@ -0,0 +1,113 @@
+//! Handle for reading when the VM got through each stage of its boot.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use devices::virtio::{BootStage, BootTimeline};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// When the VM got through each stage of its boot, counted from the start of
+/// [`VmBuilder::build()`](super::builder::VmBuilder::build) on a monotonic clock.
+///
+/// A stage is `None` until it's reached. The guest-side ones rely on the guest: the first
+/// console output only shows up when the guest writes to `hvc0`, and the init milestones only
+/// when its init reports them through a virtio-fs root. The durations between stages are `None`
+/// unless both ends were reached.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BootTimings {
+    /// The VM's resources were configured, at the end of `build()`.
+    pub configured: Option<Duration>,
+    /// `enter()` began starting the VM.
+    pub starting: Option<Duration>,
+    /// The kernel was loaded into guest memory.
+    pub kernel_loaded: Option<Duration>,
+    /// The devices were set up and the vCPUs started.
+    pub started: Option<Duration>,
+    /// The guest wrote to its console for the first time.
+    pub first_console_output: Option<Duration>,
+    /// The guest's init started running.
+    pub init_started: Option<Duration>,
+    /// The guest's init was about to run the workload.
+    pub workload_started: Option<Duration>,
+    /// The VM began exiting.
+    pub exited: Option<Duration>,
+
+    /// From the start of `build()` to `configured`.
+    pub setup: Option<Duration>,
+    /// From `starting` to `kernel_loaded`, including loading libkrunfw.
+    pub kernel_load: Option<Duration>,
+    /// From `kernel_loaded` to `started`.
+    pub device_init: Option<Duration>,
+    /// From `started` to `init_started`.
+    pub kernel_boot: Option<Duration>,
+    /// From `init_started` to `workload_started`.
+    pub init: Option<Duration>,
+    /// From `workload_started` to `exited`.
+    pub workload: Option<Duration>,
+}
+
+/// A thread-safe, cloneable handle to the VM's boot timings.
+///
+/// Obtained via [`Vm::timing_handle()`](super::vm::Vm::timing_handle) before calling
+/// [`Vm::enter()`](super::vm::Vm::enter), to read the timings while the VM boots or from an
+/// [`on_exit`](super::builder::VmBuilder::on_exit) closure, which runs after `exited` is set.
+#[derive(Clone)]
+pub struct TimingHandle {
+    timeline: Arc<BootTimeline>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl BootTimings {
+    fn from_timeline(timeline: &BootTimeline) -> Self {
+        let at = |stage| timeline.reached(stage);
+        let between = |from: Option<Duration>, to: Option<Duration>| to?.checked_sub(from?);
+
+        let configured = at(BootStage::Configured);
+        let starting = at(BootStage::Starting);
+        let kernel_loaded = at(BootStage::KernelLoaded);
+        let started = at(BootStage::Started);
+        let init_started = at(BootStage::InitStarted);
+        let workload_started = at(BootStage::WorkloadStarted);
+        let exited = at(BootStage::Exited);
+
+        BootTimings {
+            configured,
+            starting,
+            kernel_loaded,
+            started,
+            first_console_output: at(BootStage::FirstConsoleOutput),
+            init_started,
+            workload_started,
+            exited,
+            setup: configured,
+            kernel_load: between(starting, kernel_loaded),
+            device_init: between(kernel_loaded, started),
+            kernel_boot: between(started, init_started),
+            init: between(init_started, workload_started),
+            workload: between(workload_started, exited),
+        }
+    }
+}
+
+impl TimingHandle {
+    pub(crate) fn new(timeline: Arc<BootTimeline>) -> Self {
+        Self { timeline }
+    }
+
+    /// The timings as they are now.
+    pub fn timings(&self) -> BootTimings {
+        BootTimings::from_timeline(&self.timeline)
+    }
+
+    /// When the timings count from.
+    pub fn origin(&self) -> Instant {
+        self.timeline.origin()
+    }
+}
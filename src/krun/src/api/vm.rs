@@ -1,8 +1,9 @@
 //! VM handle for entering microVMs.
 
 use std::convert::Infallible;
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+use std::path::Path;
 use std::path::PathBuf;
-use std::sync::atomic::AtomicI32;
 use std::sync::Arc;
 use std::time::SystemTime;
 
@@ -10,18 +11,44 @@ use std::time::SystemTime;
 use std::env;
 #[cfg(target_os = "linux")]
 use std::ffi::CString;
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
 
 use crossbeam_channel::unbounded;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::fs::FsMetrics;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::BalloonMetrics;
+use devices::virtio::{BootStage, ConsoleWatch};
 use log::error;
 use polly::event_manager::EventManager;
+use polly::event_manager::Subscriber;
+use utils::epoll::{EpollEvent, EventSet};
 use utils::eventfd::EventFd;
+#[cfg(feature = "seccomp")]
+use utils::sandbox::SandboxLevel;
 use vmm::resources::VmResources;
 use vmm::vmm_config::kernel_bundle::KernelBundle;
 use vmm::vmm_config::kernel_cmdline::KernelCmdlineConfig;
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 
+#[cfg(feature = "blk")]
+use super::builders::DiskConfig;
+#[cfg(any(feature = "blk", feature = "net"))]
+use super::builders::RateLimit;
+use super::builders::{DnsConfig, TtyMode};
+#[cfg(feature = "blk")]
+use super::disk_handle::{DiskHandle, DiskStats, DisksHandle};
+#[cfg(any(feature = "blk", feature = "net"))]
+use super::error::ConfigError;
 use super::error::{BuildError, Error, Result, RuntimeError};
-use super::exit_handle::ExitHandle;
+use super::exit_handle::{ExitHandle, SharedExitStatus};
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+use super::fs_handle::FsSharesHandle;
+#[cfg(feature = "net")]
+use super::net_handle::{NetHandle, NetStats};
+use super::timing_handle::{BootTimings, TimingHandle};
+use super::vsock_handle::{VsockConnInfo, VsockHandle};
 
 //--------------------------------------------------------------------------------------------------
 // Constants
@@ -44,15 +71,35 @@ pub struct Vm {
     env: Option<String>,
     workdir: Option<String>,
     rlimits: Option<String>,
+    tty: TtyMode,
+    hostname: Option<String>,
+    /// `/etc/hosts` entries as the guest's init takes them, `ip=name` pairs separated by commas.
+    hosts: Option<String>,
+    dns: Option<DnsConfig>,
+    /// Static addresses of the network devices as the guest's init takes them.
+    ips: Option<String>,
     krunfw_path: Option<PathBuf>,
     init_path: Option<String>,
     exit_observers: Vec<Box<dyn Fn(i32) + Send + 'static>>,
     /// Pre-created exit event fd for triggering VM shutdown.
     exit_evt: EventFd,
-    /// Shared exit code — written by the VMM, readable by exit observers.
-    exit_code: Arc<AtomicI32>,
+    /// Shared exit status — written when the guest reports it, readable by exit observers.
+    exit_status: Arc<SharedExitStatus>,
     /// Keeps the libkrunfw library loaded so kernel memory pointers remain valid.
     _krunfw_library: Option<libloading::Library>,
+    #[cfg(feature = "seccomp")]
+    sandbox: SandboxLevel,
+}
+
+/// Wakes the event loop when the guest kernel panics.
+struct PanicWatch {
+    watch: Arc<ConsoleWatch>,
+}
+
+/// Wakes the event loop when a sandboxed thread is stopped.
+#[cfg(feature = "seccomp")]
+struct SandboxWatch {
+    evt: EventFd,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -70,11 +117,16 @@ impl Vm {
         env: Option<String>,
         workdir: Option<String>,
         rlimits: Option<String>,
+        tty: TtyMode,
+        hostname: Option<String>,
+        hosts: Option<String>,
+        dns: Option<DnsConfig>,
+        ips: Option<String>,
         krunfw_path: Option<PathBuf>,
         init_path: Option<String>,
         exit_observers: Vec<Box<dyn Fn(i32) + Send + 'static>>,
         exit_evt: EventFd,
-        exit_code: Arc<AtomicI32>,
+        exit_status: Arc<SharedExitStatus>,
     ) -> Self {
         Self {
             vmr,
@@ -84,15 +136,27 @@ impl Vm {
             env,
             workdir,
             rlimits,
+            tty,
+            hostname,
+            hosts,
+            dns,
+            ips,
             krunfw_path,
             init_path,
             exit_observers,
             exit_evt,
-            exit_code,
+            exit_status,
             _krunfw_library: None,
+            #[cfg(feature = "seccomp")]
+            sandbox: SandboxLevel::Off,
         }
     }
 
+    #[cfg(feature = "seccomp")]
+    pub(crate) fn set_sandbox(&mut self, level: SandboxLevel) {
+        self.sandbox = level;
+    }
+
     /// Get a cloneable handle that triggers VM exit from any thread.
     ///
     /// Must be called **before** [`enter()`](Self::enter). Background tasks
@@ -102,15 +166,247 @@ impl Vm {
             .expect("Failed to create ExitHandle from exit EventFd")
     }
 
-    /// Get a shared reference to the VM exit code.
+    /// Get a shared reference to the exit status of the guest's workload.
     ///
-    /// The VMM writes the guest exit code here before invoking exit
+    /// The guest's init reports it here before the VMM invokes exit
     /// observers. Read it inside an [`on_exit`](super::builder::VmBuilder::on_exit)
-    /// closure to record the exit status.
+    /// closure to tell a workload killed by a signal from one that exited
+    /// with the same code. It stays unset if the guest never reported one.
+    pub fn exit_status(&self) -> Arc<SharedExitStatus> {
+        Arc::clone(&self.exit_status)
+    }
+
+    /// Get when the VM got through each stage of its boot so far.
+    ///
+    /// Before [`enter()`](Self::enter), only the stages of
+    /// [`build()`](super::builder::VmBuilder::build) are set; use
+    /// [`timing_handle()`](Self::timing_handle) to follow the rest.
+    pub fn timings(&self) -> BootTimings {
+        self.timing_handle().timings()
+    }
+
+    /// Get a cloneable handle to the VM's boot timings.
+    ///
+    /// Take this before [`enter()`](Self::enter) and read it from another
+    /// thread while the VM boots, or from an
+    /// [`on_exit`](super::builder::VmBuilder::on_exit) closure.
+    pub fn timing_handle(&self) -> TimingHandle {
+        TimingHandle::new(self.vmr.boot_timeline.clone().unwrap_or_default())
+    }
+
+    /// Get the operation counters of the virtio-fs share tagged `tag`.
+    ///
+    /// The counters keep updating after [`enter()`](Self::enter), so take
+    /// this before entering and read it from another thread with
+    /// [`FsMetrics::snapshot`]. Returns `None` if there's no share with
+    /// that tag backed by a host directory.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_metrics(&self, tag: &str) -> Option<Arc<FsMetrics>> {
+        self.vmr
+            .fs
+            .iter()
+            .find(|config| config.fs_id == tag)
+            .map(|config| Arc::clone(&config.metrics))
+    }
+
+    /// Get a handle for adding virtio-fs shares to the VM and removing them
+    /// while it runs.
+    ///
+    /// Take this before [`enter()`](Self::enter), like
+    /// [`exit_handle()`](Self::exit_handle). Shares go into the slots
+    /// reserved with [`FsBuilder::hotplug_slots()`](super::builders::FsBuilder::hotplug_slots).
+    ///
+    /// The guest isn't notified when a share is added: its driver skipped
+    /// the empty slot at boot and isn't listening for the slot's interrupts.
+    /// Something in the guest has to probe the slot's device again, for
+    /// example by writing its name to
+    /// `/sys/bus/platform/drivers/virtio-mmio/bind`, before the share can be
+    /// mounted.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fn fs_shares(&self) -> FsSharesHandle {
+        let boot_tags = self
+            .vmr
+            .fs
+            .iter()
+            .map(|config| config.fs_id.clone())
+            .chain(self.vmr.custom_fs.iter().map(|config| config.fs_id.clone()))
+            .collect();
+        FsSharesHandle::new(self.vmr.fs_hotplug_slots.clone(), boot_tags)
+    }
+
+    /// Share the host directory at `path` with the guest under `tag`.
+    ///
+    /// See [`FsSharesHandle::add`]; use [`fs_shares()`](Self::fs_shares) to
+    /// add shares once the VM is running. The guest has to probe for the
+    /// share itself, as described there.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fn add_fs_share(&self, tag: &str, path: impl AsRef<Path>) -> Result<()> {
+        self.fs_shares().add(tag, path)
+    }
+
+    /// Remove the share added under `tag`.
+    ///
+    /// See [`FsSharesHandle::remove`]; use [`fs_shares()`](Self::fs_shares)
+    /// to remove shares once the VM is running.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fn remove_fs_share(&self, tag: &str, force: bool) -> Result<()> {
+        self.fs_shares().remove(tag, force)
+    }
+
+    /// Get the free page reporting counters of the balloon device.
+    ///
+    /// The counters keep updating after [`enter()`](Self::enter), so take
+    /// this before entering and read it from another thread with
+    /// [`BalloonMetrics::snapshot`].
+    #[cfg(not(feature = "tee"))]
+    pub fn balloon_metrics(&self) -> Arc<BalloonMetrics> {
+        Arc::clone(&self.vmr.balloon.metrics)
+    }
+
+    /// Get a handle to the disk with the given ID, such as `vda`, for
+    /// changing it while the VM runs.
+    ///
+    /// Take this before [`enter()`](Self::enter), like
+    /// [`exit_handle()`](Self::exit_handle). Returns `None` if there's no
+    /// disk with that ID.
+    #[cfg(feature = "blk")]
+    pub fn disk(&self, id: &str) -> Option<DiskHandle> {
+        self.vmr
+            .block
+            .list
+            .iter()
+            .find(|block| block.lock().unwrap().id() == id)
+            .map(|block| DiskHandle::new(Arc::clone(block)))
+    }
+
+    /// Resize the disk with the given ID to `new_size` bytes.
+    ///
+    /// See [`DiskHandle::resize`]; use [`disk()`](Self::disk) to resize it
+    /// once the VM is running.
+    #[cfg(feature = "blk")]
+    pub fn resize_disk(&self, id: &str, new_size: u64, force: bool) -> Result<()> {
+        self.disk(id)
+            .ok_or_else(|| Error::Config(ConfigError::Block(format!("no disk {id}"))))?
+            .resize(new_size, force)
+    }
+
+    /// Replace the rate limit of the disk with the given ID.
+    ///
+    /// See [`DiskHandle::set_rate_limit`]; use [`disk()`](Self::disk) to
+    /// change it once the VM is running.
+    #[cfg(feature = "blk")]
+    pub fn set_disk_rate_limit(&self, id: &str, limit: RateLimit) -> Result<()> {
+        self.disk(id)
+            .ok_or_else(|| Error::Config(ConfigError::Block(format!("no disk {id}"))))?
+            .set_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Get a handle for adding disks to the VM and removing them while it
+    /// runs.
+    ///
+    /// Take this before [`enter()`](Self::enter), like
+    /// [`exit_handle()`](Self::exit_handle). Disks go into the slots
+    /// reserved with [`DiskBuilder::hotplug_slots()`](super::builders::DiskBuilder::hotplug_slots).
     ///
-    /// Sentinel value `i32::MAX` means "not yet set".
-    pub fn exit_code(&self) -> Arc<AtomicI32> {
-        Arc::clone(&self.exit_code)
+    /// As with [`fs_shares()`](Self::fs_shares), the guest isn't notified
+    /// when a disk is added and has to probe the slot's device again.
+    #[cfg(feature = "blk")]
+    pub fn disks(&self) -> DisksHandle {
+        DisksHandle::new(self.vmr.block.hotplug_slots.clone())
+    }
+
+    /// Add the disk described by `config`, returning its ID in the guest.
+    ///
+    /// See [`DisksHandle::add`]; use [`disks()`](Self::disks) to add disks
+    /// once the VM is running.
+    #[cfg(feature = "blk")]
+    pub fn add_disk(&self, config: DiskConfig) -> Result<String> {
+        self.disks().add(config)
+    }
+
+    /// Remove the disk with the given ID, added with [`add_disk()`](Self::add_disk).
+    ///
+    /// See [`DisksHandle::remove`]; use [`disks()`](Self::disks) to remove
+    /// disks once the VM is running.
+    #[cfg(feature = "blk")]
+    pub fn remove_disk(&self, id: &str, force: bool) -> Result<()> {
+        self.disks().remove(id, force)
+    }
+
+    /// Read the I/O counters of the disk with the given ID.
+    ///
+    /// See [`DiskHandle::stats`]; use [`disk()`](Self::disk) to read them
+    /// once the VM is running. Returns `None` if there's no disk with that
+    /// ID.
+    #[cfg(feature = "blk")]
+    pub fn disk_stats(&self, id: &str) -> Option<DiskStats> {
+        self.disk(id).map(|disk| disk.stats())
+    }
+
+    /// Get a handle to the network interface at `index`, in the order they
+    /// were added to the [`NetBuilder`](super::builders::NetBuilder), for
+    /// watching it while the VM runs.
+    ///
+    /// Take this before [`enter()`](Self::enter), like
+    /// [`exit_handle()`](Self::exit_handle). Returns `None` if there's no
+    /// interface at that index.
+    #[cfg(feature = "net")]
+    pub fn net(&self, index: usize) -> Option<NetHandle> {
+        self.vmr
+            .net
+            .list
+            .get(index)
+            .map(|net| NetHandle::new(Arc::clone(net)))
+    }
+
+    /// Read the counters of the network interface at `index`.
+    ///
+    /// See [`NetHandle::stats`]; use [`net()`](Self::net) to read them once
+    /// the VM is running. Returns `None` if there's no interface at that
+    /// index.
+    #[cfg(feature = "net")]
+    pub fn net_stats(&self, index: usize) -> Option<NetStats> {
+        self.net(index).map(|net| net.stats())
+    }
+
+    /// Replace the rate limit of the network interface at `index`.
+    ///
+    /// See [`NetHandle::set_rate_limit`]; use [`net()`](Self::net) to change
+    /// it once the VM is running.
+    #[cfg(feature = "net")]
+    pub fn set_net_rate_limit(&self, index: usize, limit: RateLimit) -> Result<()> {
+        self.net(index)
+            .ok_or_else(|| {
+                Error::Config(ConfigError::Network(format!(
+                    "no interface at index {index}"
+                )))
+            })?
+            .set_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Get a handle to the vsock device, for watching its connections while
+    /// the VM runs.
+    ///
+    /// Take this before [`enter()`](Self::enter), like
+    /// [`exit_handle()`](Self::exit_handle). Returns `None` if the VM has no
+    /// vsock device.
+    pub fn vsock(&self) -> Option<VsockHandle> {
+        self.vmr
+            .vsock
+            .get()
+            .map(|vsock| VsockHandle::new(Arc::clone(vsock)))
+    }
+
+    /// List the stream connections between the guest and the host.
+    ///
+    /// See [`VsockHandle::connections`]; use [`vsock()`](Self::vsock) to
+    /// list them once the VM is running.
+    pub fn vsock_connections(&self) -> Vec<VsockConnInfo> {
+        self.vsock()
+            .map(|vsock| vsock.connections())
+            .unwrap_or_default()
     }
 
     /// Start the VM. This call never returns on success — the VMM calls
@@ -132,6 +428,14 @@ impl Vm {
         let mut event_manager = EventManager::new()
             .map_err(|e| Error::Build(BuildError::Start(format!("EventManager: {e:?}"))))?;
 
+        // Before any of the threads that install filters start.
+        #[cfg(feature = "seccomp")]
+        self.start_sandbox(&mut event_manager)?;
+
+        if let Some(timeline) = &self.vmr.boot_timeline {
+            timeline.mark(BootStage::Starting);
+        }
+
         // Load kernel from libkrunfw if not already configured
         if self.vmr.external_kernel.is_none()
             && self.vmr.kernel_bundle.is_none()
@@ -154,9 +458,6 @@ impl Vm {
             .set_kernel_cmdline(kernel_cmdline)
             .map_err(|e| Error::Build(BuildError::Start(format!("kernel cmdline: {e:?}"))))?;
 
-        // Configure vsock
-        self.configure_vsock()?;
-
         // Create shutdown EventFd on macOS aarch64 (needed for GPIO shutdown device)
         let shutdown_efd = if cfg!(target_arch = "aarch64") && cfg!(target_os = "macos") {
             Some(
@@ -176,7 +477,7 @@ impl Vm {
             shutdown_efd,
             sender,
             self.exit_evt,
-            self.exit_code,
+            self.exit_status,
         )
         .map_err(|e| Error::Build(BuildError::Start(format!("build_microvm: {e:?}"))))?;
 
@@ -205,10 +506,45 @@ impl Vm {
         vmm::worker::start_worker_thread(_vmm.clone(), _receiver.clone())
             .map_err(|e| Error::Runtime(RuntimeError::EventLoop(format!("{e:?}"))))?;
 
+        let console_watch = self.vmr.console_watch.clone();
+        if let Some(watch) = &console_watch {
+            event_manager
+                .add_subscriber(Arc::new(Mutex::new(PanicWatch {
+                    watch: watch.clone(),
+                })))
+                .map_err(|e| Error::Build(BuildError::Start(format!("panic watch: {e:?}"))))?;
+        }
+
         // Run the event loop. On normal guest exit, the VMM calls _exit() directly.
         loop {
             match event_manager.run() {
-                Ok(_) => {}
+                Ok(_) => {
+                    if let Some(watch) = console_watch.as_ref().filter(|w| w.panicked()) {
+                        let mut vmm = _vmm.lock().expect("Poisoned VMM mutex");
+                        if let Err(e) = vmm.pause_vcpus() {
+                            error!("Failed to stop the vcpus: {e:?}");
+                        }
+                        vmm.notify_exit_observers(1);
+                        return Err(Error::Runtime(RuntimeError::GuestPanic {
+                            console_tail: watch.tail(),
+                        }));
+                    }
+
+                    #[cfg(feature = "seccomp")]
+                    if let Some(violation) = utils::sandbox::violation() {
+                        error!(
+                            "{} thread stopped for system call {}",
+                            violation.category, violation.syscall
+                        );
+                        _vmm.lock()
+                            .expect("Poisoned VMM mutex")
+                            .notify_exit_observers(1);
+                        return Err(Error::Runtime(RuntimeError::SandboxViolation {
+                            thread: violation.category.to_string(),
+                            syscall: violation.syscall,
+                        }));
+                    }
+                }
                 Err(e) => {
                     error!("Error in EventManager loop: {e:?}");
                     // Run exit observers before returning so cleanup (terminal
@@ -222,6 +558,25 @@ impl Vm {
         }
     }
 
+    /// Set the sandbox level for the threads started from now on, and have
+    /// the event loop woken up when one of them is stopped.
+    #[cfg(feature = "seccomp")]
+    fn start_sandbox(&self, event_manager: &mut EventManager) -> Result<()> {
+        if self.sandbox == SandboxLevel::Off {
+            return Ok(());
+        }
+
+        let evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)
+            .map_err(|e| Error::Build(BuildError::Start(format!("sandbox EventFd: {e:?}"))))?;
+        utils::sandbox::set_violation_eventfd(evt.as_raw_fd());
+        event_manager
+            .add_subscriber(Arc::new(Mutex::new(SandboxWatch { evt })))
+            .map_err(|e| Error::Build(BuildError::Start(format!("sandbox: {e:?}"))))?;
+
+        utils::sandbox::set_level(self.sandbox)
+            .map_err(|e| Error::Build(BuildError::Start(format!("sandbox: {e}"))))
+    }
+
     /// Load kernel from libkrunfw.
     fn load_krunfw(&mut self) -> Result<()> {
         let krunfw = load_krunfw_library(self.krunfw_path.as_deref())?;
@@ -259,11 +614,12 @@ impl Vm {
     /// Configure the vsock device.
     ///
     /// The device is only attached when actually needed — either because the
-    /// caller explicitly requested it (`VmBuilder::vsock(true)`), or because
+    /// caller explicitly requested it (`MachineBuilder::vsock(true)`), because
+    /// it listens on vsock ports (`VsockBuilder::on_connect`), or because
     /// TSI needs it as a transport (no virtio-net → HIJACK_INET; single root
     /// virtio-fs on Linux → HIJACK_UNIX). This keeps the per-VM IRQ/MMIO
     /// budget free when nothing actually uses vsock.
-    fn configure_vsock(&mut self) -> Result<()> {
+    pub(crate) fn configure_vsock(&mut self) -> Result<()> {
         use devices::virtio::TsiFlags;
 
         let mut tsi_flags = TsiFlags::empty();
@@ -285,7 +641,7 @@ impl Vm {
             tsi_flags = self.maybe_enable_hijack_unix(tsi_flags);
         }
 
-        if !self.vmr.request_vsock && tsi_flags.is_empty() {
+        if !self.vmr.request_vsock && self.vmr.vsock_listeners.is_empty() && tsi_flags.is_empty() {
             return Ok(());
         }
 
@@ -301,6 +657,14 @@ impl Vm {
             .set_vsock_device(vsock_config)
             .map_err(|e| Error::Build(BuildError::DeviceRegistration(format!("vsock: {e:?}"))))?;
 
+        if let Some(vsock) = self.vmr.vsock.get() {
+            let mut vsock = vsock.lock().unwrap();
+            vsock.set_clock_resync(self.vmr.clock_resync);
+            for (port, handler) in self.vmr.vsock_listeners.drain() {
+                vsock.add_listener(port, handler);
+            }
+        }
+
         Ok(())
     }
 
@@ -325,6 +689,55 @@ impl Vm {
             .unwrap_or_default()
     }
 
+    fn get_tty(&self) -> String {
+        match self.tty {
+            TtyMode::Auto => String::new(),
+            TtyMode::Force => "KRUN_TTY=force".to_string(),
+            TtyMode::Never => "KRUN_TTY=never".to_string(),
+        }
+    }
+
+    fn get_hostname(&self) -> String {
+        self.hostname
+            .as_ref()
+            .map(|h| format!("KRUN_HOSTNAME={h}"))
+            .unwrap_or_default()
+    }
+
+    fn get_hosts(&self) -> String {
+        self.hosts
+            .as_ref()
+            .map(|h| format!("KRUN_HOSTS={h}"))
+            .unwrap_or_default()
+    }
+
+    fn get_dns(&self) -> String {
+        let Some(dns) = &self.dns else {
+            return String::new();
+        };
+        let servers = dns
+            .servers
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        if dns.search.is_empty() {
+            format!("KRUN_NAMESERVERS={servers}")
+        } else {
+            format!(
+                "KRUN_NAMESERVERS={servers} KRUN_DNS_SEARCH={}",
+                dns.search.join(",")
+            )
+        }
+    }
+
+    fn get_ips(&self) -> String {
+        self.ips
+            .as_ref()
+            .map(|ips| format!("KRUN_IPS={ips}"))
+            .unwrap_or_default()
+    }
+
     fn get_env(&self) -> String {
         self.env
             .as_ref()
@@ -351,10 +764,15 @@ impl Vm {
                 user_cmdline,
             )),
             krun_env: Some(format!(
-                " {} {} {} {} KRUN_BOOT_START_NS={boot_start_ns}",
+                " {} {} {} {} {} {} {} {} {} KRUN_BOOT_START_NS={boot_start_ns}",
                 self.get_exec_path(),
                 self.get_workdir(),
                 self.get_rlimits(),
+                self.get_tty(),
+                self.get_hostname(),
+                self.get_hosts(),
+                self.get_dns(),
+                self.get_ips(),
                 self.get_env(),
             )),
             epilog: Some(format!(" -- {}", self.get_args())),
@@ -381,6 +799,36 @@ impl Vm {
     }
 }
 
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl Subscriber for PanicWatch {
+    fn process(&mut self, _: &EpollEvent, _: &mut EventManager) {
+        // The panic itself is picked up once the event loop returns.
+        let _ = self.watch.panic_evt().read();
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.watch.panic_evt().as_raw_fd() as u64,
+        )]
+    }
+}
+
+#[cfg(feature = "seccomp")]
+impl Subscriber for SandboxWatch {
+    fn process(&mut self, _: &EpollEvent, _: &mut EventManager) {
+        // The violation itself is picked up once the event loop returns.
+        let _ = self.evt.read();
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.evt.as_raw_fd() as u64)]
+    }
+}
+
 //--------------------------------------------------------------------------------------------------
 // Functions
 //--------------------------------------------------------------------------------------------------
@@ -434,6 +882,7 @@ fn load_krunfw_library(path: Option<&std::path::Path>) -> Result<KrunfwBindings>
 mod tests {
     use super::*;
     use devices::virtio::TsiFlags;
+    use std::time::Duration;
     use utils::eventfd::EFD_NONBLOCK;
     #[cfg(not(feature = "tee"))]
     use vmm::vmm_config::fs::FsDeviceConfig;
@@ -447,11 +896,19 @@ mod tests {
             None,
             None,
             None,
+            TtyMode::Force,
+            Some("worker-1".to_string()),
+            Some("10.0.0.2=db".to_string()),
+            Some(DnsConfig {
+                servers: vec!["10.0.0.53".parse().unwrap(), "fd00::53".parse().unwrap()],
+                search: vec!["corp.example.com".to_string()],
+            }),
+            Some("eth0,10.0.0.2/24,10.0.0.1".to_string()),
             None,
             None,
             Vec::new(),
             EventFd::new(EFD_NONBLOCK).unwrap(),
-            Arc::new(AtomicI32::new(i32::MAX)),
+            Arc::new(SharedExitStatus::new()),
         )
     }
 
@@ -465,6 +922,34 @@ mod tests {
         assert!(prolog.contains("init=/init.krun"));
     }
 
+    #[test]
+    fn build_kernel_cmdline_passes_tty_mode() {
+        let mut vm = make_vm();
+        let krun_env = vm.build_kernel_cmdline(42).krun_env.unwrap();
+        assert!(krun_env.contains(" KRUN_TTY=force "));
+
+        vm.tty = TtyMode::Never;
+        let krun_env = vm.build_kernel_cmdline(42).krun_env.unwrap();
+        assert!(krun_env.contains(" KRUN_TTY=never "));
+
+        vm.tty = TtyMode::Auto;
+        let krun_env = vm.build_kernel_cmdline(42).krun_env.unwrap();
+        assert!(!krun_env.contains("KRUN_TTY"));
+    }
+
+    #[test]
+    fn build_kernel_cmdline_passes_network_config() {
+        let vm = make_vm();
+        let cmdline = vm.build_kernel_cmdline(42);
+
+        let krun_env = cmdline.krun_env.expect("missing krun_env");
+        assert!(krun_env.contains(" KRUN_HOSTNAME=worker-1 "));
+        assert!(krun_env.contains(" KRUN_HOSTS=10.0.0.2=db "));
+        assert!(krun_env.contains(" KRUN_NAMESERVERS=10.0.0.53,fd00::53 "));
+        assert!(krun_env.contains(" KRUN_IPS=eth0,10.0.0.2/24,10.0.0.1 "));
+        assert!(krun_env.contains(" KRUN_DNS_SEARCH=corp.example.com "));
+    }
+
     #[cfg(not(feature = "tee"))]
     #[test]
     fn maybe_enable_hijack_unix_respects_platform_support() {
@@ -474,6 +959,21 @@ mod tests {
             shared_dir: "/tmp/rootfs".to_string(),
             shm_size: None,
             allow_root_dir_delete: false,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+            atime: Default::default(),
+            metrics: Default::default(),
+            num_request_queues: 1,
+            init_binary: None,
+            hidden: Vec::new(),
+            xattr_map: Vec::new(),
+            one_filesystem: false,
+            empty_mount_points: false,
+            max_io_size: None,
+            landlock: false,
+            read_only: false,
+            direct_io: false,
+            proc_fd: None,
         });
 
         let flags = vm.maybe_enable_hijack_unix(TsiFlags::HIJACK_INET);
@@ -494,10 +994,259 @@ mod tests {
             shared_dir: "/".to_string(),
             shm_size: None,
             allow_root_dir_delete: false,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+            atime: Default::default(),
+            metrics: Default::default(),
+            num_request_queues: 1,
+            init_binary: None,
+            hidden: Vec::new(),
+            xattr_map: Vec::new(),
+            one_filesystem: false,
+            empty_mount_points: false,
+            max_io_size: None,
+            landlock: false,
+            read_only: false,
+            direct_io: false,
+            proc_fd: None,
         });
 
         let flags = vm.maybe_enable_hijack_unix(TsiFlags::HIJACK_INET);
 
         assert!(!flags.contains(TsiFlags::HIJACK_UNIX));
     }
+
+    #[cfg(not(feature = "tee"))]
+    #[test]
+    fn fs_metrics_by_tag() {
+        let mut vm = make_vm();
+        let metrics = Arc::new(FsMetrics::new());
+        vm.vmr.fs.push(FsDeviceConfig {
+            fs_id: "data".to_string(),
+            shared_dir: "/".to_string(),
+            shm_size: None,
+            allow_root_dir_delete: false,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+            atime: Default::default(),
+            metrics: metrics.clone(),
+            num_request_queues: 1,
+            init_binary: None,
+            hidden: Vec::new(),
+            xattr_map: Vec::new(),
+            one_filesystem: false,
+            empty_mount_points: false,
+            max_io_size: None,
+            landlock: false,
+            read_only: false,
+            direct_io: false,
+            proc_fd: None,
+        });
+
+        assert!(Arc::ptr_eq(&vm.fs_metrics("data").unwrap(), &metrics));
+        assert!(vm.fs_metrics("other").is_none());
+    }
+
+    #[test]
+    fn console_watch_follows_builder() {
+        use crate::api::builder::VmBuilder;
+
+        let vm = VmBuilder::new().build().unwrap();
+        let watch = vm.vmr.console_watch.as_ref().unwrap();
+        watch.feed(b"Kernel panic - not syncing: Attempted to kill init!\n");
+        assert!(watch.panicked());
+
+        let vm = VmBuilder::new()
+            .console(|c| c.panic_pattern("BUG: oops").tail_size(8))
+            .build()
+            .unwrap();
+        let watch = vm.vmr.console_watch.as_ref().unwrap();
+        watch.feed(b"Kernel panic - not syncing\n");
+        assert!(!watch.panicked());
+        watch.feed(b"[ 1.0] BUG: oops\n");
+        assert!(watch.panicked());
+        // The tail always has room for the pattern.
+        assert_eq!(watch.tail().len(), "BUG: oops".len());
+    }
+
+    #[test]
+    fn timings_follow_boot_order() {
+        use crate::api::builder::VmBuilder;
+
+        let vm = VmBuilder::new().build().unwrap();
+        let timings = vm.timings();
+        assert!(timings.configured.is_some());
+        assert_eq!(timings.setup, timings.configured);
+        assert_eq!(timings.starting, None);
+        assert_eq!(timings.kernel_load, None);
+
+        // Go through the rest of the boot the way entering would.
+        let timeline = vm.vmr.boot_timeline.clone().unwrap();
+        for stage in [
+            BootStage::Starting,
+            BootStage::KernelLoaded,
+            BootStage::Started,
+        ] {
+            timeline.mark(stage);
+        }
+        vm.vmr
+            .console_watch
+            .as_ref()
+            .unwrap()
+            .feed(b"[    0.000000] Linux");
+        for stage in [
+            BootStage::InitStarted,
+            BootStage::WorkloadStarted,
+            BootStage::Exited,
+        ] {
+            timeline.mark(stage);
+        }
+
+        let timings = vm.timing_handle().timings();
+        let stages = [
+            timings.configured,
+            timings.starting,
+            timings.kernel_loaded,
+            timings.started,
+            timings.first_console_output,
+            timings.init_started,
+            timings.workload_started,
+            timings.exited,
+        ]
+        .map(Option::unwrap);
+        assert!(stages.windows(2).all(|w| w[0] <= w[1]));
+
+        let durations = [
+            timings.kernel_load,
+            timings.device_init,
+            timings.kernel_boot,
+            timings.init,
+            timings.workload,
+        ]
+        .map(Option::unwrap);
+        let total: Duration = durations.iter().sum();
+        assert_eq!(total, stages[7] - stages[1]);
+    }
+
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    #[test]
+    fn fs_shares_fill_hotplug_slots() {
+        use crate::api::builder::VmBuilder;
+        use crate::api::error::ConfigError;
+
+        let vm = VmBuilder::new()
+            .fs(|fs| fs.root("/tmp").hotplug_slots(1))
+            .build()
+            .unwrap();
+        let shares = vm.fs_shares();
+        assert_eq!(shares.free_slots(), 1);
+
+        // Tags of the shares from boot are taken.
+        match vm.add_fs_share("/dev/root", "/tmp") {
+            Err(Error::Config(ConfigError::Filesystem(msg))) => assert!(msg.contains("in use")),
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        shares.add("extra", "/tmp").unwrap();
+        assert_eq!(vm.fs_shares().tags(), ["extra"]);
+        match shares.add("more", "/tmp") {
+            Err(Error::Config(ConfigError::Filesystem(msg))) => {
+                assert!(msg.contains("hotplug slots"))
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        vm.remove_fs_share("extra", false).unwrap();
+        assert!(shares.remove("extra", false).is_err());
+        assert_eq!(shares.free_slots(), 1);
+    }
+
+    #[cfg(feature = "blk")]
+    #[test]
+    fn disks_fill_hotplug_slots() {
+        use std::fs;
+
+        use crate::api::builder::VmBuilder;
+        use crate::api::builders::{CacheMode, DiskConfig, DiskImageFormat};
+        use crate::api::error::ConfigError;
+
+        let dir = std::env::temp_dir().join(format!("krun-disks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let disk = |name: &str| {
+            let path = dir.join(name);
+            fs::File::create(&path).unwrap().set_len(1 << 20).unwrap();
+            DiskConfig {
+                path,
+                read_only: false,
+                format: DiskImageFormat::Raw,
+                cache: CacheMode::Writeback,
+                rate_limit: RateLimit::default(),
+            }
+        };
+
+        let vm = VmBuilder::new()
+            .disk(|d| d.path(disk("boot.raw").path).hotplug_slots(1))
+            .build()
+            .unwrap();
+        let disks = vm.disks();
+        assert_eq!(disks.free_slots(), 1);
+
+        // Hot-added disks take the IDs after those of the boot disks.
+        assert_eq!(vm.add_disk(disk("scratch.raw")).unwrap(), "vdb");
+        assert_eq!(disks.ids(), ["vdb"]);
+        match disks.add(disk("more.raw")) {
+            Err(Error::Config(ConfigError::Block(msg))) => assert!(msg.contains("hotplug slots")),
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        assert!(vm.remove_disk("vda", false).is_err());
+        vm.remove_disk("vdb", false).unwrap();
+        assert!(disks.remove("vdb", false).is_err());
+        assert_eq!(disks.free_slots(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Entering only returns on failure, so the VM runs in a child process.
+    #[cfg(all(feature = "seccomp", target_os = "linux"))]
+    #[ignore = "needs KVM and libkrunfw"]
+    #[test]
+    fn strict_sandbox_runs_to_completion() {
+        use crate::api::builder::VmBuilder;
+        use crate::api::builders::SandboxLevel;
+
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        // The child can't fail the test itself, so it sends back why it didn't get to exit.
+        let (mut errors, mut child_errors) = UnixStream::pair().unwrap();
+
+        // Safe because the child only builds and enters the VM before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            drop(errors);
+            let result = VmBuilder::new()
+                .sandbox(SandboxLevel::Strict)
+                .fs(|fs| fs.root("/"))
+                .exec(|e| e.path("/bin/true"))
+                .build()
+                .and_then(|vm| vm.enter());
+            let _ = write!(child_errors, "{result:?}");
+            // Safe because it exits the child only.
+            unsafe { libc::_exit(100) };
+        }
+        drop(child_errors);
+
+        let mut result = String::new();
+        errors.read_to_string(&mut result).unwrap();
+        assert!(result.is_empty(), "{result}");
+
+        let mut status = 0;
+        // Safe because `status` outlives the call.
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status), "the VMM was killed");
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
 }
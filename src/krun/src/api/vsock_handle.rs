@@ -0,0 +1,51 @@
+//! Handle for watching the vsock device of a running VM.
+
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::Vsock;
+
+pub use devices::virtio::{VsockConnInfo, VsockConnState};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A thread-safe, cloneable handle to the VM's vsock device.
+///
+/// Obtained via [`Vm::vsock()`](super::vm::Vm::vsock) before calling
+/// [`Vm::enter()`](super::vm::Vm::enter), and usable from any thread while
+/// the VM runs.
+#[derive(Clone)]
+pub struct VsockHandle {
+    vsock: Arc<Mutex<Vsock>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl VsockHandle {
+    pub(crate) fn new(vsock: Arc<Mutex<Vsock>>) -> Self {
+        Self { vsock }
+    }
+
+    /// The guest's CID.
+    pub fn cid(&self) -> u64 {
+        self.vsock.lock().unwrap().cid()
+    }
+
+    /// List the stream connections between the guest and the host, sorted by
+    /// host port.
+    ///
+    /// Connections are sampled one at a time while the device keeps going,
+    /// so this is cheap to poll while the VM runs.
+    pub fn connections(&self) -> Vec<VsockConnInfo> {
+        self.vsock.lock().unwrap().connections()
+    }
+
+    /// How many connections the guest asked for to host ports that nothing
+    /// listens on. A growing count usually means a port mapping is missing.
+    pub fn refused_connections(&self) -> u64 {
+        self.vsock.lock().unwrap().refused_connections()
+    }
+}
@@ -34,8 +34,12 @@
 //--------------------------------------------------------------------------------------------------
 
 pub use devices::virtio::bindings::{stat64, statvfs64};
+pub use devices::virtio::fs::atime::AtimePolicy;
 pub use devices::virtio::fs::dyn_filesystem::DynFileSystem;
 pub use devices::virtio::fs::filesystem::{
     Context, DirEntry, Entry, Extensions, FsOptions, GetxattrReply, ListxattrReply, OpenOptions,
     RemovemappingOne, SecContext, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
+pub use devices::virtio::fs::idmap::{IdMap, IdMapping};
+pub use devices::virtio::fs::metrics::{FsMetrics, FsMetricsSnapshot};
+pub use devices::virtio::fs::xattrmap::{parse_xattr_map, XattrRule};
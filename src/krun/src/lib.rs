@@ -39,15 +39,36 @@ pub mod backends;
 
 pub use api::builder::VmBuilder;
 #[cfg(feature = "blk")]
+pub use api::builders::CacheMode;
+#[cfg(feature = "blk")]
 pub use api::builders::DiskBuilder;
 #[cfg(feature = "blk")]
+pub use api::builders::DiskConfig;
+#[cfg(feature = "blk")]
 pub use api::builders::DiskImageFormat;
+#[cfg(target_os = "linux")]
+pub use api::builders::Keep;
+#[cfg(any(feature = "blk", feature = "net"))]
+pub use api::builders::RateLimit;
+#[cfg(feature = "seccomp")]
+pub use api::builders::SandboxLevel;
+pub use api::builders::{
+    BalloonBuilder, BalloonMetrics, BalloonMetricsSnapshot, ConsoleBuilder, DaxConfig, ExecBuilder,
+    FsBuilder, KernelBuilder, MachineBuilder, PortDest, TtyMode, VsockBuilder, VsockStream,
+};
 #[cfg(feature = "net")]
-pub use api::builders::NetBuilder;
-pub use api::builders::{ConsoleBuilder, ExecBuilder, FsBuilder, KernelBuilder, MachineBuilder};
+pub use api::builders::{NetBuilder, Offloads};
+#[cfg(feature = "blk")]
+pub use api::disk_handle::{DiskHandle, DiskStats, DisksHandle};
 pub use api::error::{BuildError, ConfigError, Error, Result, RuntimeError};
-pub use api::exit_handle::ExitHandle;
+pub use api::exit_handle::{ExitHandle, ExitStatus, SharedExitStatus};
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+pub use api::fs_handle::FsSharesHandle;
+#[cfg(feature = "net")]
+pub use api::net_handle::{NetHandle, NetStats};
+pub use api::timing_handle::{BootTimings, TimingHandle};
 pub use api::vm::Vm;
+pub use api::vsock_handle::{VsockConnInfo, VsockConnState, VsockHandle};
 
 pub use backends::console::ConsolePortBackend;
 
@@ -10,6 +10,9 @@ use devices::virtio::gpu::display::DisplayInfo;
 use devices::virtio::net::device::VirtioNetBackend;
 #[cfg(feature = "blk")]
 use devices::virtio::CacheType;
+#[cfg(any(feature = "blk", feature = "net"))]
+use devices::virtio::RateLimit;
+use devices::virtio::SharedExitStatus;
 use env_logger::{Env, Target};
 #[cfg(feature = "gpu")]
 use krun_display::DisplayBackend;
@@ -594,6 +597,21 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
                 // Default to a conservative 512 MB window.
                 shm_size: Some(1 << 29),
                 allow_root_dir_delete: false,
+                uid_map: Default::default(),
+                gid_map: Default::default(),
+                atime: Default::default(),
+                metrics: Default::default(),
+                num_request_queues: 1,
+                init_binary: None,
+                hidden: Vec::new(),
+                xattr_map: Vec::new(),
+                one_filesystem: false,
+                empty_mount_points: false,
+                max_io_size: None,
+                landlock: false,
+                read_only: false,
+                direct_io: false,
+                proc_fd: None,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -627,6 +645,21 @@ pub unsafe extern "C" fn krun_add_virtiofs(
                 shared_dir: path.to_string(),
                 shm_size: None,
                 allow_root_dir_delete: false,
+                uid_map: Default::default(),
+                gid_map: Default::default(),
+                atime: Default::default(),
+                metrics: Default::default(),
+                num_request_queues: 1,
+                init_binary: None,
+                hidden: Vec::new(),
+                xattr_map: Vec::new(),
+                one_filesystem: false,
+                empty_mount_points: false,
+                max_io_size: None,
+                landlock: false,
+                read_only: false,
+                direct_io: false,
+                proc_fd: None,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -661,6 +694,21 @@ pub unsafe extern "C" fn krun_add_virtiofs2(
                 shared_dir: path.to_string(),
                 shm_size: Some(shm_size.try_into().unwrap()),
                 allow_root_dir_delete: false,
+                uid_map: Default::default(),
+                gid_map: Default::default(),
+                atime: Default::default(),
+                metrics: Default::default(),
+                num_request_queues: 1,
+                init_binary: None,
+                hidden: Vec::new(),
+                xattr_map: Vec::new(),
+                one_filesystem: false,
+                empty_mount_points: false,
+                max_io_size: None,
+                landlock: false,
+                read_only: false,
+                direct_io: false,
+                proc_fd: None,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -712,6 +760,7 @@ pub unsafe extern "C" fn krun_add_disk(
                 sync_mode: SyncMode::Full,
                 #[cfg(target_os = "macos")]
                 sync_mode: SyncMode::Relaxed,
+                rate_limit: RateLimit::default(),
             };
             cfg.add_block_cfg(block_device_config);
         }
@@ -760,6 +809,7 @@ pub unsafe extern "C" fn krun_add_disk2(
                 sync_mode: SyncMode::Full,
                 #[cfg(target_os = "macos")]
                 sync_mode: SyncMode::Relaxed,
+                rate_limit: RateLimit::default(),
             };
             cfg.add_block_cfg(block_device_config);
         }
@@ -812,6 +862,7 @@ pub unsafe extern "C" fn krun_add_disk3(
                 is_disk_read_only: read_only,
                 direct_io,
                 sync_mode,
+                rate_limit: RateLimit::default(),
             };
             cfg.add_block_cfg(block_device_config);
         }
@@ -844,6 +895,7 @@ pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_c
                 sync_mode: SyncMode::Full,
                 #[cfg(target_os = "macos")]
                 sync_mode: SyncMode::Relaxed,
+                rate_limit: RateLimit::default(),
             };
             cfg.set_root_block_cfg(block_device_config);
         }
@@ -876,6 +928,7 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
                 sync_mode: SyncMode::Full,
                 #[cfg(target_os = "macos")]
                 sync_mode: SyncMode::Relaxed,
+                rate_limit: RateLimit::default(),
             };
             cfg.set_data_block_cfg(block_device_config);
         }
@@ -1973,6 +2026,8 @@ fn create_virtio_net(
         backend,
         mac,
         features,
+        queue_pairs: 1,
+        rate_limit: RateLimit::default(),
     };
     ctx_cfg.net_index += 1;
     ctx_cfg
@@ -2293,6 +2348,21 @@ pub unsafe extern "C" fn krun_set_root_disk_remount(
                 // Default to a conservative 512 MB window.
                 shm_size: Some(1 << 29),
                 allow_root_dir_delete: true,
+                uid_map: Default::default(),
+                gid_map: Default::default(),
+                atime: Default::default(),
+                metrics: Default::default(),
+                num_request_queues: 1,
+                init_binary: None,
+                hidden: Vec::new(),
+                xattr_map: Vec::new(),
+                one_filesystem: false,
+                empty_mount_points: false,
+                max_io_size: None,
+                landlock: false,
+                read_only: false,
+                direct_io: false,
+                proc_fd: None,
             });
 
             ctx_cfg.set_block_root(device, fstype, options);
@@ -2702,7 +2772,7 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
             return -libc::EINVAL;
         }
     };
-    let exit_code = Arc::new(AtomicI32::new(i32::MAX));
+    let exit_status = Arc::new(SharedExitStatus::new());
 
     let _vmm = match vmm::builder::build_microvm(
         &mut ctx_cfg.vmr,
@@ -2710,7 +2780,7 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         ctx_cfg.shutdown_efd,
         sender,
         exit_evt,
-        exit_code,
+        exit_status,
     ) {
         Ok(vmm) => vmm,
         Err(e) => {
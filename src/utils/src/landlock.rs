@@ -0,0 +1,162 @@
+//! Landlock rulesets confining a thread's filesystem access to a few directories.
+//!
+//! Unlike the seccomp filters in [`crate::sandbox`], which limit the system calls a thread can
+//! make, these limit the files it can open through them: once [`restrict_self`] returns, the
+//! calling thread, and the threads it starts from then on, can only open what's beneath the
+//! directories it was given. Descriptors opened before keep working.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+// `struct landlock_ruleset_attr`, up to the fields of the first ABI.
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+// `struct landlock_path_beneath_attr`.
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The version of the Landlock ABI the kernel supports, or an `Unsupported` error if it has none
+/// or it was turned off at boot.
+pub fn abi_version() -> io::Result<u32> {
+    // Safe because this doesn't access any memory and we check the return value.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+                io::Error::new(io::ErrorKind::Unsupported, e)
+            }
+            _ => e,
+        });
+    }
+    Ok(ret as u32)
+}
+
+/// Confines the calling thread to the files beneath `dirs`, which it keeps full access to.
+/// Fails with an `Unsupported` error if the kernel doesn't have Landlock.
+pub fn restrict_self(dirs: &[&Path]) -> io::Result<()> {
+    // Every filesystem access right of the ABI, each bit being one more right than the ABI before
+    // knew. Refer, which makes renames and links across directories possible, comes with ABI 2,
+    // truncate with ABI 3 and device ioctls with ABI 5.
+    let handled_access_fs = match abi_version()? {
+        1 => (1 << 13) - 1,
+        2 => (1 << 14) - 1,
+        3 | 4 => (1 << 15) - 1,
+        _ => (1 << 16) - 1,
+    };
+
+    let attr = RulesetAttr { handled_access_fs };
+    // Safe because the kernel only reads `attr`, and we check the return value.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created this fd.
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ret as RawFd) };
+
+    for dir in dirs {
+        let dir = open_path(dir)?;
+        let rule = PathBeneathAttr {
+            allowed_access: handled_access_fs,
+            parent_fd: dir.as_raw_fd(),
+        };
+        // Safe because the kernel only reads `rule`, and we check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Without this, only a thread with CAP_SYS_ADMIN could restrict itself.
+    // Safe because this doesn't access any memory and we check the return value.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because this doesn't access any memory and we check the return value.
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn open_path(path: &Path) -> io::Result<OwnedFd> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just opened this fd.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confines_the_thread() {
+        if abi_version().is_err() {
+            return;
+        }
+
+        let base = std::env::temp_dir().join(format!("krun-landlock-{}", std::process::id()));
+        let share = base.join("share");
+        std::fs::create_dir_all(&share).unwrap();
+        std::fs::write(share.join("inside"), b"inside").unwrap();
+        std::fs::write(base.join("outside"), b"outside").unwrap();
+
+        // Like a filesystem worker, the thread restricts itself once it's started.
+        let (inside, outside) = std::thread::scope(|s| {
+            s.spawn(|| {
+                restrict_self(&[&share]).unwrap();
+                (
+                    std::fs::read(share.join("inside")).map_err(|e| e.raw_os_error()),
+                    std::fs::read(base.join("outside")).map_err(|e| e.raw_os_error()),
+                )
+            })
+            .join()
+            .unwrap()
+        });
+        assert_eq!(inside, Ok(b"inside".to_vec()));
+        assert_eq!(outside, Err(Some(libc::EACCES)));
+
+        // The rest of the process isn't restricted.
+        assert_eq!(std::fs::read(base.join("outside")).unwrap(), b"outside");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
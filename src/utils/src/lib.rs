@@ -3,9 +3,11 @@
 
 pub use vmm_sys_util::{errno, tempdir, tempfile, terminal};
 #[cfg(target_os = "linux")]
-pub use vmm_sys_util::{eventfd, ioctl};
+pub use vmm_sys_util::{eventfd, ioctl, timerfd};
 
 pub mod byte_order;
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+pub mod landlock;
 #[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "linux")]
@@ -16,9 +18,12 @@ pub mod macos;
 pub use macos::epoll;
 #[cfg(target_os = "macos")]
 pub use macos::eventfd;
+#[cfg(target_os = "macos")]
+pub use macos::timerfd;
 pub mod pollable_channel;
 #[cfg(target_arch = "x86_64")]
 pub mod rand;
+pub mod sandbox;
 #[cfg(target_os = "linux")]
 pub mod signal;
 pub mod sized_vec;
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A timer that can be polled like a Linux timerfd, built on a kqueue of its own.
+//!
+//! The kqueue only ever holds the timer, and it becomes readable for the kqueue it is added to
+//! once the timer fires.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+use std::{io, ptr};
+
+// The timer is the only event in the queue, so any identifier does.
+const TIMER_IDENT: usize = 0;
+
+#[derive(Debug)]
+pub struct TimerFd {
+    queue: OwnedFd,
+}
+
+impl TimerFd {
+    /// Creates a disarmed timer.
+    pub fn new() -> io::Result<TimerFd> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let queue = unsafe { libc::kqueue() };
+        if queue < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we uniquely own the file descriptor.
+        Ok(TimerFd {
+            queue: unsafe { OwnedFd::from_raw_fd(queue) },
+        })
+    }
+
+    /// Arms the timer to fire after `dur`, and then every `interval` if there's one, replacing
+    /// whatever it was armed with. A periodic timer fires every `interval` from the start.
+    pub fn reset(&mut self, dur: Duration, interval: Option<Duration>) -> io::Result<()> {
+        let (period, flags) = match interval {
+            Some(interval) => (interval, libc::EV_ADD),
+            None => (dur, libc::EV_ADD | libc::EV_ONESHOT),
+        };
+        self.change(flags, period.as_nanos().min(isize::MAX as u128) as isize)
+    }
+
+    /// Waits for the timer to fire, returning how many times it did since the last call.
+    pub fn wait(&mut self) -> io::Result<u64> {
+        let mut event = Self::event(0, 0);
+        // Safe because the kernel only writes one event into `event`, and we check the return
+        // value.
+        let ret = unsafe {
+            libc::kevent(
+                self.queue.as_raw_fd(),
+                ptr::null(),
+                0,
+                &mut event,
+                1,
+                ptr::null(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(event.data as u64)
+    }
+
+    /// Disarms the timer.
+    pub fn clear(&mut self) -> io::Result<()> {
+        match self.change(libc::EV_DELETE, 0) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn change(&self, flags: u16, nanos: isize) -> io::Result<()> {
+        let event = Self::event(flags, nanos);
+        // Safe because the kernel only reads `event`, and we check the return value.
+        let ret = unsafe {
+            libc::kevent(
+                self.queue.as_raw_fd(),
+                &event,
+                1,
+                ptr::null_mut(),
+                0,
+                ptr::null(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn event(flags: u16, nanos: isize) -> libc::kevent {
+        libc::kevent {
+            ident: TIMER_IDENT,
+            filter: libc::EVFILT_TIMER,
+            flags,
+            fflags: libc::NOTE_NSECONDS,
+            data: nanos,
+            udata: ptr::null_mut(),
+        }
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.queue.as_raw_fd()
+    }
+}
@@ -0,0 +1,437 @@
+//! Seccomp filters for the threads that handle the guest's requests.
+//!
+//! A guest that finds a bug in the emulation of a device gets to run code in the thread handling
+//! that device. Each such thread calls [`enter`] once it is set up, which restricts it to the
+//! system calls its kind of thread needs, as listed in the tables below. A thread making any
+//! other system call is stopped on the spot, without taking the rest of the process with it, and
+//! the violation is recorded for the VMM to report.
+//!
+//! Filters are inherited by the threads a thread starts, and only ever narrow down. Devices are
+//! activated, and start their own threads, from the vCPU threads, which is why those get the
+//! union of every table.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, Ordering};
+
+/// How much the threads handling the guest's requests are restricted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SandboxLevel {
+    /// No filters.
+    #[default]
+    Off,
+    /// Every thread is restricted to the system calls any of them needs.
+    Basic,
+    /// Every thread is restricted to the system calls its own kind of thread needs.
+    Strict,
+}
+
+/// The kinds of threads that get a filter of their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadCategory {
+    Vcpu,
+    Fs,
+    Net,
+    Console,
+}
+
+/// A system call that a thread wasn't allowed to make.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub category: ThreadCategory,
+    pub syscall: i64,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+// The first violation, as `1 << 63 | category << 32 | syscall`, or 0.
+static VIOLATION: AtomicU64 = AtomicU64::new(0);
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+thread_local! {
+    static CATEGORY: Cell<Option<ThreadCategory>> = const { Cell::new(None) };
+}
+
+//--------------------------------------------------------------------------------------------------
+// Allowlists
+//--------------------------------------------------------------------------------------------------
+
+/// What every thread needs to run Rust code: memory, locks, signals, time, polling and reading or
+/// writing the descriptors it already has.
+#[cfg(target_os = "linux")]
+const BASE: &[libc::c_long] = &[
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_close,
+    libc::SYS_dup,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_futex,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_gettid,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_ppoll,
+    libc::SYS_prctl,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_restart_syscall,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_set_robust_list,
+    libc::SYS_sigaltstack,
+    libc::SYS_statx,
+    libc::SYS_tgkill,
+    libc::SYS_write,
+    libc::SYS_writev,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+];
+
+/// Running the vCPU and kicking the others out of the guest.
+#[cfg(target_os = "linux")]
+const VCPU: &[libc::c_long] = &[libc::SYS_ioctl, libc::SYS_rt_sigtimedwait];
+
+/// The passthrough filesystem, including the helper threads it starts. Requests are made with
+/// the guest's credentials, switched per thread with `setresuid`/`setresgid` (`ScopedUid` and
+/// `ScopedGid`), and workers may confine themselves to their share with Landlock first.
+/// Extended attributes of symlinks are reached from the thread's own working directory in
+/// `/proc/self/fd`, which takes `unshare(CLONE_FS)` and `fchdir`.
+#[cfg(target_os = "linux")]
+const FS: &[libc::c_long] = &[
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_copy_file_range,
+    libc::SYS_fallocate,
+    libc::SYS_fchdir,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_fdatasync,
+    libc::SYS_fgetxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_flock,
+    libc::SYS_fremovexattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_fstatfs,
+    libc::SYS_fsync,
+    libc::SYS_ftruncate,
+    libc::SYS_getdents64,
+    libc::SYS_getegid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getuid,
+    libc::SYS_getxattr,
+    libc::SYS_ioctl,
+    libc::SYS_landlock_add_rule,
+    libc::SYS_landlock_create_ruleset,
+    libc::SYS_landlock_restrict_self,
+    libc::SYS_lgetxattr,
+    libc::SYS_linkat,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_mkdirat,
+    libc::SYS_mknodat,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_pread64,
+    libc::SYS_preadv,
+    libc::SYS_preadv2,
+    libc::SYS_pwrite64,
+    libc::SYS_pwritev,
+    libc::SYS_pwritev2,
+    libc::SYS_readlinkat,
+    libc::SYS_removexattr,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_setresgid,
+    libc::SYS_setresuid,
+    libc::SYS_setxattr,
+    libc::SYS_symlinkat,
+    libc::SYS_syncfs,
+    libc::SYS_umask,
+    libc::SYS_unlinkat,
+    libc::SYS_unshare,
+    libc::SYS_utimensat,
+];
+
+/// Moving frames between the guest and a tap device or the socket of a userspace backend, and
+/// arming the timer that resumes a rate-limited queue.
+#[cfg(target_os = "linux")]
+const NET: &[libc::c_long] = &[
+    libc::SYS_getsockopt,
+    libc::SYS_ioctl,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_timerfd_settime,
+];
+
+/// Copying between the console's queues and the host's terminal, files or sockets.
+#[cfg(target_os = "linux")]
+const CONSOLE: &[libc::c_long] = &[libc::SYS_ioctl];
+
+/// What the devices without a table of their own need, on top of the others: vsock sockets,
+/// disk I/O and the threads and descriptors devices set up when the guest activates them.
+#[cfg(target_os = "linux")]
+const DEVICES: &[libc::c_long] = &[
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_connect,
+    libc::SYS_epoll_create1,
+    libc::SYS_eventfd2,
+    libc::SYS_getpeername,
+    libc::SYS_getsockname,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_io_uring_setup,
+    libc::SYS_listen,
+    libc::SYS_memfd_create,
+    libc::SYS_pipe2,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+];
+
+/// The system calls a thread of `category` may make at `level`.
+#[cfg(target_os = "linux")]
+pub fn allowed_syscalls(level: SandboxLevel, category: ThreadCategory) -> Vec<i64> {
+    let tables: &[&[libc::c_long]] = match (level, category) {
+        (SandboxLevel::Off, _) => &[],
+        (SandboxLevel::Basic, _) | (SandboxLevel::Strict, ThreadCategory::Vcpu) => {
+            &[BASE, VCPU, FS, NET, CONSOLE, DEVICES]
+        }
+        (SandboxLevel::Strict, ThreadCategory::Fs) => &[BASE, FS],
+        (SandboxLevel::Strict, ThreadCategory::Net) => &[BASE, NET],
+        (SandboxLevel::Strict, ThreadCategory::Console) => &[BASE, CONSOLE],
+    };
+    let mut syscalls: Vec<i64> = tables.iter().flat_map(|t| t.iter().copied()).collect();
+    syscalls.sort_unstable();
+    syscalls.dedup();
+    syscalls
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Sets the level the threads calling [`enter`] from now on are restricted to. This applies to
+/// the whole process.
+pub fn set_level(level: SandboxLevel) -> io::Result<()> {
+    #[cfg(all(target_os = "linux", feature = "seccomp"))]
+    if level != SandboxLevel::Off {
+        vmm_sys_util::signal::register_signal_handler(libc::SIGSYS, handle_sigsys)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+    }
+    #[cfg(not(all(target_os = "linux", feature = "seccomp")))]
+    if level != SandboxLevel::Off {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn level() -> SandboxLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => SandboxLevel::Basic,
+        2 => SandboxLevel::Strict,
+        _ => SandboxLevel::Off,
+    }
+}
+
+/// Has `fd`, an eventfd, signalled when a thread is stopped for a violation.
+pub fn set_violation_eventfd(fd: RawFd) {
+    WAKE_FD.store(fd, Ordering::Relaxed);
+}
+
+/// The first system call a thread was stopped for, if any.
+pub fn violation() -> Option<Violation> {
+    let v = VIOLATION.load(Ordering::Relaxed);
+    if v == 0 {
+        return None;
+    }
+    let category = match (v >> 32) as u8 & 0x7f {
+        0 => ThreadCategory::Vcpu,
+        1 => ThreadCategory::Fs,
+        2 => ThreadCategory::Net,
+        _ => ThreadCategory::Console,
+    };
+    Some(Violation {
+        category,
+        syscall: v as u32 as i64,
+    })
+}
+
+/// Restricts the calling thread, and the threads it starts from now on, to the system calls
+/// threads of `category` need, if a level was set. Failing to do so is logged, and leaves the
+/// thread as it was.
+pub fn enter(category: ThreadCategory) {
+    let level = level();
+    if level == SandboxLevel::Off {
+        return;
+    }
+    CATEGORY.with(|c| c.set(Some(category)));
+
+    #[cfg(all(target_os = "linux", feature = "seccomp"))]
+    if let Err(e) = install(&allowed_syscalls(level, category)) {
+        log::error!("failed to install the {category} seccomp filter: {e}");
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "seccomp"))]
+fn install(syscalls: &[i64]) -> Result<(), seccompiler::Error> {
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+
+    let rules = syscalls.iter().map(|&nr| (nr, Vec::new())).collect();
+    let arch = TargetArch::try_from(std::env::consts::ARCH)?;
+    let filter = SeccompFilter::new(rules, SeccompAction::Trap, SeccompAction::Allow, arch)?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter(&program)
+}
+
+// The fields of a `siginfo_t` for SIGSYS.
+#[cfg(all(target_os = "linux", feature = "seccomp"))]
+#[repr(C)]
+struct SigsysInfo {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    #[cfg(target_pointer_width = "64")]
+    _pad: libc::c_int,
+    call_addr: *mut libc::c_void,
+    syscall: libc::c_int,
+    arch: libc::c_uint,
+}
+
+#[cfg(all(target_os = "linux", feature = "seccomp"))]
+extern "C" fn handle_sigsys(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Safe because the kernel fills in the SIGSYS fields when a filter traps a system call.
+    let syscall = unsafe { (*(info as *const SigsysInfo)).syscall };
+    let category = CATEGORY.with(|c| c.get()).unwrap_or(ThreadCategory::Vcpu);
+    let v = 1 << 63 | (category as u64) << 32 | syscall as u32 as u64;
+    let _ = VIOLATION.compare_exchange(0, v, Ordering::Relaxed, Ordering::Relaxed);
+
+    let fd = WAKE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let one = 1u64;
+        // Safe because `one` outlives the call.
+        unsafe { libc::write(fd, &one as *const u64 as *const libc::c_void, 8) };
+    }
+
+    // Only this thread goes away, along with whatever it was holding. Both system calls are in
+    // every allowlist.
+    // Safe because the thread doesn't run any more code.
+    unsafe { libc::syscall(libc::SYS_exit, 0) };
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Display for ThreadCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadCategory::Vcpu => write!(f, "vcpu"),
+            ThreadCategory::Fs => write!(f, "virtio-fs"),
+            ThreadCategory::Net => write!(f, "net"),
+            ThreadCategory::Console => write!(f, "console"),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(all(test, target_os = "linux", feature = "seccomp"))]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn strict_tables() {
+        let console = allowed_syscalls(SandboxLevel::Strict, ThreadCategory::Console);
+        let fs = allowed_syscalls(SandboxLevel::Strict, ThreadCategory::Fs);
+        let vcpu = allowed_syscalls(SandboxLevel::Strict, ThreadCategory::Vcpu);
+        assert!(!console.contains(&libc::SYS_openat));
+        assert!(fs.contains(&libc::SYS_setresuid) && fs.contains(&libc::SYS_setresgid));
+        assert!(!fs.contains(&libc::SYS_socket));
+        // The threads devices start from a vCPU thread keep its filter as well.
+        assert!(fs.iter().all(|nr| vcpu.contains(nr)));
+        assert_eq!(
+            vcpu,
+            allowed_syscalls(SandboxLevel::Basic, ThreadCategory::Console)
+        );
+        assert!(!vcpu.contains(&libc::SYS_execve));
+    }
+
+    #[test]
+    fn disallowed_syscall_stops_thread() {
+        set_level(SandboxLevel::Strict).unwrap();
+
+        let reached = Arc::new(AtomicBool::new(false));
+        let worker_reached = reached.clone();
+        // The thread never returns, so it is left unjoined.
+        let _worker = thread::spawn(move || {
+            enter(ThreadCategory::Console);
+            // Safe because the call has no side effects on memory.
+            unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+            worker_reached.store(true, Ordering::Relaxed);
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let violation = loop {
+            if let Some(v) = violation() {
+                break v;
+            }
+            assert!(Instant::now() < deadline, "the thread wasn't stopped");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(
+            violation,
+            Violation {
+                category: ThreadCategory::Console,
+                syscall: libc::SYS_socket,
+            }
+        );
+        thread::sleep(Duration::from_millis(50));
+        assert!(!reached.load(Ordering::Relaxed));
+
+        // The rest of the process carries on.
+        assert!(std::fs::metadata("/").is_ok());
+    }
+}
@@ -13,9 +13,8 @@ use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::{self, IsTerminal, Read};
 use std::os::fd::AsRawFd;
-use std::os::fd::{BorrowedFd, FromRawFd};
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
 use std::path::PathBuf;
-use std::sync::atomic::AtomicI32;
 use std::sync::{Arc, Mutex};
 
 use super::{Error, Vmm};
@@ -26,6 +25,8 @@ use crate::device_manager::mmio::MMIODeviceManager;
 use crate::resources::{
     DefaultVirtioConsoleConfig, PortConfig, TsiFlags, VirtioConsoleConfigMode, VmResources,
 };
+#[cfg(not(feature = "tee"))]
+use crate::vmm_config::balloon::BalloonDeviceConfig;
 use crate::vmm_config::external_kernel::{ExternalKernel, KernelFormat};
 #[cfg(feature = "net")]
 use crate::vmm_config::net::NetBuilder;
@@ -43,13 +44,18 @@ use devices::legacy::{IoApic, IrqChipT, KvmIoapic};
 use devices::legacy::{IrqChip, IrqChipDevice};
 #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
 use devices::legacy::{KvmGicV2, KvmGicV3};
-use devices::virtio::{port_io, MmioTransport, PortDescription, VirtioDevice, Vsock};
+use devices::virtio::{
+    port_io, BootStage, MmioTransport, PortDescription, PortOutputWatch, SharedExitStatus,
+    VirtioDevice, Vsock,
+};
 
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 
 use crate::device_manager;
 use crate::exit_signal::register_exit_signal_handlers;
+#[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+use crate::gdb::{GdbServer, VcpuDebug};
 #[cfg(target_os = "linux")]
 use crate::signal_handler::register_sigint_handler;
 #[cfg(target_os = "linux")]
@@ -72,7 +78,7 @@ use devices::virtio::display::DisplayInfo;
 #[cfg(feature = "gpu")]
 use devices::virtio::display::NoopDisplayBackend;
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
-use devices::virtio::{fs::ExportTable, VirtioShmRegion};
+use devices::virtio::{fs::ExportTable, BootTimeline, VirtioShmRegion};
 use flate2::read::GzDecoder;
 #[cfg(feature = "gpu")]
 use krun_display::DisplayBackend;
@@ -107,6 +113,9 @@ static EDK2_BINARY: &[u8] = include_bytes!("../KRUN_EFI.silent.fd");
 pub enum StartMicrovmError {
     /// Unable to attach block device to Vmm.
     AttachBlockDevice(io::Error),
+    #[cfg(target_os = "linux")]
+    /// Failed to drop the capabilities the VMM no longer needs.
+    DropPrivileges(io::Error),
     #[cfg(target_os = "macos")]
     /// Failed to create HVF in-kernel IrqChip.
     CreateHvfIrqChip(hvf::Error),
@@ -117,6 +126,9 @@ pub enum StartMicrovmError {
     CreateRateLimiter(io::Error),
     /// Cannot open the file containing the kernel code.
     ElfOpenKernel(io::Error),
+    #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+    /// Failed to listen for gdb.
+    Gdb(io::Error),
     /// Cannot load the kernel into the VM.
     ElfLoadKernel(linux_loader::loader::Error),
     /// The firmware can't be loaded into the provided memory address.
@@ -239,6 +251,10 @@ impl Display for StartMicrovmError {
             AttachBlockDevice(ref err) => {
                 write!(f, "Unable to attach block device to Vmm. Error: {err}")
             }
+            #[cfg(target_os = "linux")]
+            DropPrivileges(ref err) => write!(f, "Cannot drop privileges: {err}"),
+            #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+            Gdb(ref err) => write!(f, "Cannot listen for gdb: {err}"),
             #[cfg(target_os = "macos")]
             CreateHvfIrqChip(ref err) => {
                 write!(f, "Cannot create HVF in-kernel IrqChip: {err}")
@@ -565,7 +581,7 @@ pub fn build_microvm(
     _shutdown_efd: Option<EventFd>,
     _sender: Sender<WorkerMessage>,
     exit_evt: EventFd,
-    exit_code: Arc<AtomicI32>,
+    exit_status: Arc<SharedExitStatus>,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
     let payload = choose_payload(vm_resources)?;
 
@@ -577,6 +593,9 @@ pub fn build_microvm(
         vm_resources,
         &payload,
     )?;
+    if let Some(timeline) = &vm_resources.boot_timeline {
+        timeline.mark(BootStage::KernelLoaded);
+    }
 
     let vcpu_config = vm_resources.vcpu_config();
 
@@ -861,6 +880,19 @@ pub fn build_microvm(
         .map_err(StartMicrovmError::Internal)?;
     }
 
+    // The first vCPU waits for gdb before running anything.
+    #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+    let mut vcpus = vcpus;
+    #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+    let gdb_server = match vm_resources.gdb_addr {
+        Some(addr) => {
+            let (debug, port) = VcpuDebug::new(guest_memory.clone());
+            vcpus[0].set_debug(debug);
+            Some(GdbServer::bind(addr, port).map_err(StartMicrovmError::Gdb)?)
+        }
+        None => None,
+    };
+
     #[cfg(feature = "tdx")]
     {
         for vcpu in &vcpus {
@@ -966,7 +998,7 @@ pub fn build_microvm(
         )?;
     }
 
-    // exit_code is pre-created and shared with callers via Vm::exit_code().
+    // exit_status is pre-created and shared with callers via Vm::exit_status().
 
     let mut vmm = Vmm {
         guest_memory,
@@ -975,7 +1007,9 @@ pub fn build_microvm(
         vcpus_handles: Vec::new(),
         exit_evt,
         exit_observers: Vec::new(),
-        exit_code: exit_code.clone(),
+        exit_status: exit_status.clone(),
+        console_watch: vm_resources.console_watch.clone(),
+        boot_timeline: vm_resources.boot_timeline.clone(),
         vm,
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
@@ -988,7 +1022,7 @@ pub fn build_microvm(
     }
 
     #[cfg(not(feature = "tee"))]
-    attach_balloon_device(&mut vmm, event_manager, intc.clone())?;
+    attach_balloon_device(&mut vmm, &vm_resources.balloon, event_manager, intc.clone())?;
     #[cfg(not(feature = "tee"))]
     attach_rng_device(&mut vmm, event_manager, intc.clone())?;
     let mut console_id = 0;
@@ -1056,7 +1090,8 @@ pub fn build_microvm(
         #[cfg(not(feature = "tee"))]
         export_table,
         intc.clone(),
-        exit_code.clone(),
+        exit_status.clone(),
+        vm_resources.boot_timeline.as_ref(),
         #[cfg(target_os = "macos")]
         _sender.clone(),
     )?;
@@ -1067,7 +1102,17 @@ pub fn build_microvm(
         &mut _shm_manager,
         vm_resources.fs.len(),
         intc.clone(),
-        exit_code,
+        exit_status,
+        vm_resources.boot_timeline.as_ref(),
+        #[cfg(target_os = "macos")]
+        _sender.clone(),
+    )?;
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    attach_fs_hotplug_slots(
+        &mut vmm,
+        &vm_resources.fs_hotplug_slots,
+        vm_resources.fs.len() + vm_resources.custom_fs.len(),
+        intc.clone(),
         #[cfg(target_os = "macos")]
         _sender,
     )?;
@@ -1143,9 +1188,28 @@ pub fn build_microvm(
         println!("Starting TEE/microVM.");
     }
 
+    // Every device is set up, and the threads started from now on inherit
+    // what is left.
+    #[cfg(target_os = "linux")]
+    if let Some(keep) = vm_resources.drop_privileges {
+        devices::virtio::fs::privileges::drop_privileges(keep)
+            .map_err(StartMicrovmError::DropPrivileges)?;
+    }
+
+    if let Some(timeline) = &vm_resources.boot_timeline {
+        timeline.mark(BootStage::Started);
+    }
     vmm.start_vcpus(vcpus)
         .map_err(StartMicrovmError::Internal)?;
 
+    #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+    if let Some(server) = gdb_server {
+        let kicker = vmm.vcpus_handles[0].kicker();
+        server
+            .start(move || kicker.kick())
+            .map_err(StartMicrovmError::Gdb)?;
+    }
+
     // Clippy thinks we don't need Arc<Mutex<...
     // but we don't want to change the event_manager interface
     #[allow(clippy::arc_with_non_send_sync)]
@@ -1913,13 +1977,15 @@ fn attach_mmio_device(
 }
 
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+#[allow(clippy::too_many_arguments)]
 fn attach_fs_devices(
     vmm: &mut Vmm,
     fs_devs: &[FsDeviceConfig],
     shm_manager: &mut ShmManager,
     #[cfg(not(feature = "tee"))] export_table: Option<ExportTable>,
     intc: IrqChip,
-    exit_code: Arc<AtomicI32>,
+    exit_status: Arc<SharedExitStatus>,
+    boot_timeline: Option<&Arc<BootTimeline>>,
     #[cfg(target_os = "macos")] map_sender: Sender<WorkerMessage>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
@@ -1929,7 +1995,7 @@ fn attach_fs_devices(
             devices::virtio::Fs::new(
                 config.fs_id.clone(),
                 config.shared_dir.clone(),
-                exit_code.clone(),
+                exit_status.clone(),
                 config.allow_root_dir_delete,
             )
             .unwrap(),
@@ -1937,6 +2003,37 @@ fn attach_fs_devices(
 
         let id = format!("{}{}", String::from(fs.lock().unwrap().id()), i);
 
+        fs.lock()
+            .unwrap()
+            .set_idmap(config.uid_map.clone(), config.gid_map.clone());
+        fs.lock().unwrap().set_atime_policy(config.atime);
+        fs.lock().unwrap().set_hidden_paths(config.hidden.clone());
+        fs.lock().unwrap().set_xattr_map(config.xattr_map.clone());
+        fs.lock()
+            .unwrap()
+            .set_one_filesystem(config.one_filesystem, config.empty_mount_points);
+        if let Some(size) = config.max_io_size {
+            fs.lock().unwrap().set_max_io_size(size);
+        }
+        fs.lock().unwrap().set_read_only(config.read_only);
+        fs.lock().unwrap().set_direct_io(config.direct_io);
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = &config.proc_fd {
+            fs.lock().unwrap().set_proc_fd(fd.clone());
+        }
+        #[cfg(all(target_os = "linux", feature = "landlock"))]
+        fs.lock().unwrap().set_landlock(config.landlock);
+        fs.lock().unwrap().set_metrics(config.metrics.clone());
+        if let Some(timeline) = boot_timeline {
+            fs.lock().unwrap().set_boot_timeline(timeline.clone());
+        }
+        fs.lock()
+            .unwrap()
+            .set_num_request_queues(config.num_request_queues);
+        if let Some(init_binary) = &config.init_binary {
+            fs.lock().unwrap().set_init_binary(init_binary.clone());
+        }
+
         if let Some(shm_region) = shm_manager.fs_region(i) {
             fs.lock().unwrap().set_shm_region(VirtioShmRegion {
                 host_addr: vmm
@@ -1964,13 +2061,15 @@ fn attach_fs_devices(
 }
 
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+#[allow(clippy::too_many_arguments)]
 fn attach_custom_fs_devices(
     vmm: &mut Vmm,
     custom_fs_devs: &[CustomFsDeviceConfig],
     shm_manager: &mut ShmManager,
     index_offset: usize,
     intc: IrqChip,
-    exit_code: Arc<AtomicI32>,
+    exit_status: Arc<SharedExitStatus>,
+    boot_timeline: Option<&Arc<BootTimeline>>,
     #[cfg(target_os = "macos")] map_sender: Sender<WorkerMessage>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
@@ -1980,7 +2079,7 @@ fn attach_custom_fs_devices(
             devices::virtio::Fs::with_custom_backend(
                 config.fs_id.clone(),
                 Arc::clone(&config.backend),
-                exit_code.clone(),
+                exit_status.clone(),
             )
             .unwrap(),
         ));
@@ -1991,6 +2090,13 @@ fn attach_custom_fs_devices(
             index_offset + i
         );
 
+        fs.lock()
+            .unwrap()
+            .set_num_request_queues(config.num_request_queues);
+        if let Some(timeline) = boot_timeline {
+            fs.lock().unwrap().set_boot_timeline(timeline.clone());
+        }
+
         let shm_index = index_offset + i;
         if let Some(shm_region) = shm_manager.fs_region(shm_index) {
             fs.lock().unwrap().set_shm_region(VirtioShmRegion {
@@ -2012,6 +2118,31 @@ fn attach_custom_fs_devices(
     Ok(())
 }
 
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+fn attach_fs_hotplug_slots(
+    vmm: &mut Vmm,
+    slots: &[Arc<Mutex<devices::virtio::Fs>>],
+    index_offset: usize,
+    intc: IrqChip,
+    #[cfg(target_os = "macos")] map_sender: Sender<WorkerMessage>,
+) -> std::result::Result<(), StartMicrovmError> {
+    for (i, fs) in slots.iter().enumerate() {
+        let id = format!(
+            "{}{}",
+            String::from(fs.lock().unwrap().id()),
+            index_offset + i
+        );
+
+        #[cfg(target_os = "macos")]
+        fs.lock().unwrap().set_map_sender(map_sender.clone());
+
+        attach_mmio_device(vmm, id, intc.clone(), fs.clone())
+            .map_err(StartMicrovmError::RegisterFsDevice)?;
+    }
+
+    Ok(())
+}
+
 fn autoconfigure_console_ports(
     vmm: &mut Vmm,
     vm_resources: &VmResources,
@@ -2047,12 +2178,14 @@ fn autoconfigure_console_ports(
             Some(c) => (c.input_fd, c.output_fd, c.err_fd),
             None => (STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO),
         };
-        let input_is_terminal =
-            input_fd >= 0 && isatty(unsafe { BorrowedFd::borrow_raw(input_fd) }).unwrap_or(false);
-        let output_is_terminal =
-            output_fd >= 0 && isatty(unsafe { BorrowedFd::borrow_raw(output_fd) }).unwrap_or(false);
-        let error_is_terminal =
-            err_fd >= 0 && isatty(unsafe { BorrowedFd::borrow_raw(err_fd) }).unwrap_or(false);
+        let is_terminal = |fd: RawFd| {
+            !vm_resources.stdio_as_pipes
+                && fd >= 0
+                && isatty(unsafe { BorrowedFd::borrow_raw(fd) }).unwrap_or(false)
+        };
+        let input_is_terminal = is_terminal(input_fd);
+        let output_is_terminal = is_terminal(output_fd);
+        let error_is_terminal = is_terminal(err_fd);
 
         let term_fd = if input_is_terminal {
             Some(unsafe { BorrowedFd::borrow_raw(input_fd) })
@@ -2219,7 +2352,7 @@ fn attach_console_devices(
 
     let creating_implicit_console = cfg.is_none();
 
-    let ports = match cfg {
+    let mut ports = match cfg {
         None => autoconfigure_console_ports(vmm, vm_resources, None, creating_implicit_console)?,
         Some(VirtioConsoleConfigMode::Autoconfigure(autocfg)) => autoconfigure_console_ports(
             vmm,
@@ -2230,6 +2363,16 @@ fn attach_console_devices(
         Some(VirtioConsoleConfigMode::Explicit(ports)) => create_explicit_ports(vmm, ports)?,
     };
 
+    // The first port of hvc0 is the kernel's console.
+    if let (0, Some(watch), Some(port)) =
+        (id_number, &vm_resources.console_watch, ports.first_mut())
+    {
+        port.output = Some(Box::new(PortOutputWatch::new(
+            port.output.take(),
+            watch.clone(),
+        )));
+    }
+
     let console = Arc::new(Mutex::new(devices::virtio::Console::new(ports).unwrap()));
 
     vmm.exit_observers.push(console.clone());
@@ -2278,6 +2421,8 @@ fn attach_unixsock_vsock_device(
 
     let id = String::from(unix_vsock.lock().unwrap().id());
 
+    vmm.exit_observers.push(unix_vsock.clone());
+
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(vmm, id, intc, unix_vsock.clone()).map_err(RegisterVsockDevice)?;
 
@@ -2287,12 +2432,15 @@ fn attach_unixsock_vsock_device(
 #[cfg(not(feature = "tee"))]
 fn attach_balloon_device(
     vmm: &mut Vmm,
+    config: &BalloonDeviceConfig,
     event_manager: &mut EventManager,
     intc: IrqChip,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    let balloon = Arc::new(Mutex::new(devices::virtio::Balloon::new().unwrap()));
+    let balloon = Arc::new(Mutex::new(
+        devices::virtio::Balloon::new(config.free_page_reporting, config.metrics.clone()).unwrap(),
+    ));
 
     event_manager
         .add_subscriber(balloon.clone())
@@ -2321,6 +2469,11 @@ fn attach_block_devices(
         attach_mmio_device(vmm, id, intc.clone(), block.clone()).map_err(RegisterBlockDevice)?;
     }
 
+    for slot in block_devs.hotplug_slots.iter() {
+        let id = String::from(slot.lock().unwrap().id());
+        attach_mmio_device(vmm, id, intc.clone(), slot.clone()).map_err(RegisterBlockDevice)?;
+    }
+
     Ok(())
 }
 
@@ -2453,6 +2606,46 @@ pub mod tests {
         create_guest_memory(mem_size_mib, &vm_resources, &Payload::Empty)
     }
 
+    #[test]
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    fn test_fs_shm_regions() {
+        let mut vm_resources = VmResources::default();
+        vm_resources.kernel_bundle = Some(KernelBundle {
+            host_addr: 0x1000,
+            guest_addr: 0x1000,
+            entry_addr: 0x1000,
+            size: 0x1000,
+        });
+        for (i, shm_size) in [Some(512 << 20), None].into_iter().enumerate() {
+            vm_resources.fs.push(FsDeviceConfig {
+                fs_id: format!("fs{i}"),
+                shared_dir: "/".to_string(),
+                shm_size,
+                allow_root_dir_delete: false,
+                uid_map: Default::default(),
+                gid_map: Default::default(),
+                atime: Default::default(),
+                metrics: Default::default(),
+                num_request_queues: 1,
+                init_binary: None,
+                hidden: Vec::new(),
+                xattr_map: Vec::new(),
+                one_filesystem: false,
+                empty_mount_points: false,
+                max_io_size: None,
+                landlock: false,
+                read_only: false,
+                direct_io: false,
+                proc_fd: None,
+            });
+        }
+
+        let (_guest_memory, _arch_memory_info, shm_manager, _payload_config) =
+            create_guest_memory(128, &vm_resources, &Payload::Empty).unwrap();
+        assert_eq!(shm_manager.fs_region(0).unwrap().size, 512 << 20);
+        assert!(shm_manager.fs_region(1).is_none());
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_create_vcpus_x86_64() {
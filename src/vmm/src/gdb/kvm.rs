@@ -0,0 +1,356 @@
+//! The vCPU side of debugging: registers, memory and breakpoints through KVM, on x86_64.
+
+use std::collections::HashMap;
+
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use kvm_bindings::{
+    kvm_debug_exit_arch, kvm_guest_debug, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP,
+    KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+};
+use kvm_ioctls::VcpuFd;
+use log::{error, warn};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::protocol::BreakpointKind;
+use super::{DebugRequest, DebugResponse, GdbPort, StopReason};
+
+// The `int3` instruction.
+const INT3: u8 = 0xcc;
+const BP_VECTOR: u32 = 3;
+const PAGE_SIZE: u64 = 0x1000;
+// As much memory as gdb asks for at once, given the packet size.
+const MAX_MEMORY_ACCESS: usize = 0x2000;
+// The debug registers that hold breakpoint addresses.
+const HW_BREAKPOINTS: usize = 4;
+// The bits of DR6 telling which of them hit.
+const DR6_BREAKPOINTS: u64 = 0xf;
+
+// The general registers in the order gdb's `g` packet has them, each 8 bytes, followed by
+// eflags and the segment selectors, each 4 bytes.
+const GENERAL_REGS: usize = 17;
+const REGS_SIZE: usize = GENERAL_REGS * 8 + 7 * 4;
+
+/// What the vCPU does after a request.
+pub enum Handled {
+    /// Stays stopped, waiting for the next one.
+    Stay,
+    /// Runs on.
+    Resume,
+    /// Stops the VM.
+    Kill,
+}
+
+/// The state of the vCPU being debugged.
+pub struct VcpuDebug {
+    requests: Receiver<DebugRequest>,
+    responses: Sender<DebugResponse>,
+    mem: GuestMemoryMmap,
+    // The original byte of each patched instruction, by virtual address.
+    sw_breakpoints: HashMap<u64, (GuestAddress, u8)>,
+    hw_breakpoints: [Option<u64>; HW_BREAKPOINTS],
+    // Stop before the first instruction.
+    halt_on_start: bool,
+}
+
+impl VcpuDebug {
+    /// Creates the debug state for a vCPU accessing `mem`, and the port a `GdbServer` talks to
+    /// it through.
+    pub fn new(mem: GuestMemoryMmap) -> (Self, GdbPort) {
+        let (req_tx, req_rx) = unbounded();
+        let (resp_tx, resp_rx) = unbounded();
+        let debug = VcpuDebug {
+            requests: req_rx,
+            responses: resp_tx,
+            mem,
+            sw_breakpoints: HashMap::new(),
+            hw_breakpoints: [None; HW_BREAKPOINTS],
+            halt_on_start: true,
+        };
+        let port = GdbPort {
+            requests: req_tx,
+            responses: resp_rx,
+        };
+        (debug, port)
+    }
+
+    /// Whether the vCPU should stop now, before running its first instruction or because the
+    /// server asked it to.
+    pub fn pending_stop(&mut self) -> Option<StopReason> {
+        if std::mem::take(&mut self.halt_on_start) {
+            return Some(StopReason::Start);
+        }
+        match self.requests.try_recv() {
+            Ok(DebugRequest::Interrupt) => Some(StopReason::Interrupted),
+            Ok(request) => {
+                warn!("Ignoring debug request while running: {request:?}");
+                None
+            }
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Why the vCPU stopped on a debug exit.
+    pub fn stop_reason(&self, exit: &kvm_debug_exit_arch) -> StopReason {
+        if exit.exception == BP_VECTOR {
+            StopReason::SoftwareBreakpoint
+        } else if exit.dr6 & DR6_BREAKPOINTS != 0 {
+            StopReason::HardwareBreakpoint
+        } else {
+            StopReason::Step
+        }
+    }
+
+    /// Tells the server the vCPU stopped.
+    pub fn notify_stopped(&self, reason: StopReason) {
+        let _ = self.responses.send(DebugResponse::Stopped(reason));
+    }
+
+    /// Waits for the next request while stopped, and handles it.
+    pub fn handle_next(&mut self, fd: &VcpuFd) -> Handled {
+        let Ok(request) = self.requests.recv() else {
+            // The server is gone: run on undisturbed.
+            self.detach(fd);
+            return Handled::Resume;
+        };
+
+        let response = match request {
+            DebugRequest::ReadRegisters => data(self.read_registers(fd)),
+            DebugRequest::WriteRegisters(regs) => done(self.write_registers(fd, &regs)),
+            DebugRequest::ReadMemory { addr, len } => {
+                let mut buf = vec![0; len.min(MAX_MEMORY_ACCESS)];
+                data(self.read_virt(fd, addr, &mut buf).map(|_| buf))
+            }
+            DebugRequest::WriteMemory { addr, data } => done(self.write_virt(fd, addr, &data)),
+            DebugRequest::InsertBreakpoint { kind, addr } => {
+                done(self.insert_breakpoint(fd, kind, addr))
+            }
+            DebugRequest::RemoveBreakpoint { kind, addr } => {
+                done(self.remove_breakpoint(kind, addr))
+            }
+            DebugRequest::Resume { step, addr } => {
+                if let Some(addr) = addr {
+                    if let Err(e) = Self::set_rip(fd, addr) {
+                        warn!("Failed to resume from {addr:#x}: {e}");
+                    }
+                }
+                if let Err(e) = self.update_guest_debug(fd, step) {
+                    error!("Failed to set up guest debugging: {e}");
+                }
+                return Handled::Resume;
+            }
+            // Sent as the vCPU stopped on its own.
+            DebugRequest::Interrupt => return Handled::Stay,
+            DebugRequest::Detach => {
+                self.detach(fd);
+                let _ = self.responses.send(DebugResponse::Ok);
+                return Handled::Resume;
+            }
+            DebugRequest::Kill => return Handled::Kill,
+        };
+        let _ = self.responses.send(response);
+        Handled::Stay
+    }
+
+    fn read_registers(&self, fd: &VcpuFd) -> Result<Vec<u8>, i32> {
+        let regs = fd.get_regs().map_err(|e| e.errno())?;
+        let sregs = fd.get_sregs().map_err(|e| e.errno())?;
+
+        let general = [
+            regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+            regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+        ];
+        let segments = [
+            regs.rflags as u32,
+            u32::from(sregs.cs.selector),
+            u32::from(sregs.ss.selector),
+            u32::from(sregs.ds.selector),
+            u32::from(sregs.es.selector),
+            u32::from(sregs.fs.selector),
+            u32::from(sregs.gs.selector),
+        ];
+
+        let mut data = Vec::with_capacity(REGS_SIZE);
+        general.iter().for_each(|r| data.extend(r.to_le_bytes()));
+        segments.iter().for_each(|r| data.extend(r.to_le_bytes()));
+        Ok(data)
+    }
+
+    // Sets the general registers and eflags. The segment selectors are left alone, as they
+    // can't be changed without their descriptors.
+    fn write_registers(&self, fd: &VcpuFd, data: &[u8]) -> Result<(), i32> {
+        if data.len() < GENERAL_REGS * 8 + 4 {
+            return Err(libc::EINVAL);
+        }
+        let reg = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+
+        let mut regs = fd.get_regs().map_err(|e| e.errno())?;
+        let general = [
+            &mut regs.rax,
+            &mut regs.rbx,
+            &mut regs.rcx,
+            &mut regs.rdx,
+            &mut regs.rsi,
+            &mut regs.rdi,
+            &mut regs.rbp,
+            &mut regs.rsp,
+            &mut regs.r8,
+            &mut regs.r9,
+            &mut regs.r10,
+            &mut regs.r11,
+            &mut regs.r12,
+            &mut regs.r13,
+            &mut regs.r14,
+            &mut regs.r15,
+            &mut regs.rip,
+        ];
+        for (i, r) in general.into_iter().enumerate() {
+            *r = reg(i);
+        }
+        let flags = &data[GENERAL_REGS * 8..GENERAL_REGS * 8 + 4];
+        regs.rflags = u64::from(u32::from_le_bytes(flags.try_into().unwrap()));
+        fd.set_regs(&regs).map_err(|e| e.errno())
+    }
+
+    fn set_rip(fd: &VcpuFd, rip: u64) -> Result<(), kvm_ioctls::Error> {
+        let mut regs = fd.get_regs()?;
+        regs.rip = rip;
+        fd.set_regs(&regs)
+    }
+
+    // Translates a virtual address through the vCPU's page tables.
+    fn translate(fd: &VcpuFd, addr: u64) -> Result<GuestAddress, i32> {
+        let tr = fd.translate_gva(addr).map_err(|e| e.errno())?;
+        if tr.valid == 0 {
+            return Err(libc::EFAULT);
+        }
+        Ok(GuestAddress(tr.physical_address))
+    }
+
+    // Calls `f` on each page-sized piece of the access at `addr`, with its guest physical
+    // address and its range in the buffer.
+    fn for_each_page(
+        fd: &VcpuFd,
+        addr: u64,
+        len: usize,
+        mut f: impl FnMut(GuestAddress, std::ops::Range<usize>) -> Result<(), i32>,
+    ) -> Result<(), i32> {
+        let mut done = 0;
+        while done < len {
+            let virt = addr.wrapping_add(done as u64);
+            let chunk = ((PAGE_SIZE - virt % PAGE_SIZE) as usize).min(len - done);
+            f(Self::translate(fd, virt)?, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn read_virt(&self, fd: &VcpuFd, addr: u64, data: &mut [u8]) -> Result<(), i32> {
+        Self::for_each_page(fd, addr, data.len(), |phys, range| {
+            self.mem
+                .read_slice(&mut data[range], phys)
+                .map_err(|_| libc::EFAULT)
+        })
+    }
+
+    fn write_virt(&self, fd: &VcpuFd, addr: u64, data: &[u8]) -> Result<(), i32> {
+        Self::for_each_page(fd, addr, data.len(), |phys, range| {
+            self.mem
+                .write_slice(&data[range], phys)
+                .map_err(|_| libc::EFAULT)
+        })
+    }
+
+    fn insert_breakpoint(
+        &mut self,
+        fd: &VcpuFd,
+        kind: BreakpointKind,
+        addr: u64,
+    ) -> Result<(), i32> {
+        match kind {
+            BreakpointKind::Software => {
+                if self.sw_breakpoints.contains_key(&addr) {
+                    return Ok(());
+                }
+                let phys = Self::translate(fd, addr)?;
+                let orig: u8 = self.mem.read_obj(phys).map_err(|_| libc::EFAULT)?;
+                self.mem.write_obj(INT3, phys).map_err(|_| libc::EFAULT)?;
+                self.sw_breakpoints.insert(addr, (phys, orig));
+            }
+            BreakpointKind::Hardware => {
+                if self.hw_breakpoints.contains(&Some(addr)) {
+                    return Ok(());
+                }
+                let slot = self
+                    .hw_breakpoints
+                    .iter_mut()
+                    .find(|slot| slot.is_none())
+                    .ok_or(libc::ENOSPC)?;
+                *slot = Some(addr);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, kind: BreakpointKind, addr: u64) -> Result<(), i32> {
+        match kind {
+            BreakpointKind::Software => {
+                let (phys, orig) = self.sw_breakpoints.remove(&addr).ok_or(libc::ENOENT)?;
+                self.mem.write_obj(orig, phys).map_err(|_| libc::EFAULT)?;
+            }
+            BreakpointKind::Hardware => {
+                let slot = self
+                    .hw_breakpoints
+                    .iter_mut()
+                    .find(|slot| **slot == Some(addr))
+                    .ok_or(libc::ENOENT)?;
+                *slot = None;
+            }
+        }
+        Ok(())
+    }
+
+    // Tells KVM which debug exits to take on the next run.
+    fn update_guest_debug(&self, fd: &VcpuFd, step: bool) -> Result<(), kvm_ioctls::Error> {
+        let mut debug = kvm_guest_debug {
+            control: KVM_GUESTDBG_ENABLE,
+            ..Default::default()
+        };
+        // Only catch `int3` while there are breakpoints, as the guest kernel uses it too, such
+        // as for patching its own code.
+        if !self.sw_breakpoints.is_empty() {
+            debug.control |= KVM_GUESTDBG_USE_SW_BP;
+        }
+        if step {
+            debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+        for (i, addr) in self.hw_breakpoints.iter().enumerate() {
+            if let Some(addr) = addr {
+                debug.control |= KVM_GUESTDBG_USE_HW_BP;
+                debug.arch.debugreg[i] = *addr;
+                // Globally enabled, breaking on execution.
+                debug.arch.debugreg[7] |= 1 << (i * 2 + 1);
+            }
+        }
+        fd.set_guest_debug(&debug)
+    }
+
+    fn detach(&mut self, fd: &VcpuFd) {
+        for (_, (phys, orig)) in self.sw_breakpoints.drain() {
+            if let Err(e) = self.mem.write_obj(orig, phys) {
+                error!("Failed to remove breakpoint at {phys:?}: {e}");
+            }
+        }
+        self.hw_breakpoints = [None; HW_BREAKPOINTS];
+        if let Err(e) = fd.set_guest_debug(&kvm_guest_debug::default()) {
+            error!("Failed to turn guest debugging off: {e}");
+        }
+    }
+}
+
+fn data(result: Result<Vec<u8>, i32>) -> DebugResponse {
+    result.map_or_else(DebugResponse::Error, DebugResponse::Data)
+}
+
+fn done(result: Result<(), i32>) -> DebugResponse {
+    result.map_or_else(DebugResponse::Error, |_| DebugResponse::Ok)
+}
@@ -0,0 +1,386 @@
+//! A GDB remote protocol server for debugging the guest through its first vCPU.
+//!
+//! The vCPU stops before running its first instruction and waits for gdb, which connects over
+//! TCP with `target remote`. From then on it can read and write registers and guest memory,
+//! single-step, and set software and hardware breakpoints. Addresses are virtual, translated
+//! through the vCPU's page tables.
+//!
+//! Only the first vCPU is debugged; the others run on regardless. Watchpoints, `vCont` and the
+//! FPU and vector registers aren't supported, so gdb falls back to plain `c` and `s` and shows
+//! only the general registers.
+
+mod kvm;
+mod protocol;
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use log::{error, info, warn};
+
+pub use self::kvm::{Handled, VcpuDebug};
+use self::protocol::{encode, encode_hex, BreakpointKind, Command, Incoming, PacketReader};
+
+/// What the server asks the vCPU to do. Only `Interrupt` is sent while the vCPU runs; the rest
+/// are answered once it stops.
+#[derive(Debug)]
+pub enum DebugRequest {
+    ReadRegisters,
+    WriteRegisters(Vec<u8>),
+    ReadMemory {
+        addr: u64,
+        len: usize,
+    },
+    WriteMemory {
+        addr: u64,
+        data: Vec<u8>,
+    },
+    InsertBreakpoint {
+        kind: BreakpointKind,
+        addr: u64,
+    },
+    RemoveBreakpoint {
+        kind: BreakpointKind,
+        addr: u64,
+    },
+    /// Runs on, from `addr` if it's set, for a single instruction if `step` is.
+    Resume {
+        step: bool,
+        addr: Option<u64>,
+    },
+    /// Stops the running vCPU.
+    Interrupt,
+    /// Clears every breakpoint and runs on without debugging.
+    Detach,
+    /// Stops the VM.
+    Kill,
+}
+
+/// What the vCPU answers.
+#[derive(Debug)]
+pub enum DebugResponse {
+    Ok,
+    Data(Vec<u8>),
+    /// Failed with this errno.
+    Error(i32),
+    /// The vCPU stopped and waits for requests.
+    Stopped(StopReason),
+}
+
+/// Why the vCPU stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// Before running its first instruction.
+    Start,
+    /// After a single step.
+    Step,
+    /// On a software breakpoint, with the instruction pointer on it.
+    SoftwareBreakpoint,
+    /// On a hardware breakpoint.
+    HardwareBreakpoint,
+    /// Because gdb asked it to.
+    Interrupted,
+}
+
+impl StopReason {
+    /// The stop reply gdb gets.
+    fn reply(self) -> &'static str {
+        match self {
+            StopReason::Start | StopReason::Step => "S05",
+            StopReason::SoftwareBreakpoint => "T05swbreak:;",
+            StopReason::HardwareBreakpoint => "T05hwbreak:;",
+            StopReason::Interrupted => "S02",
+        }
+    }
+}
+
+/// The server's end of the connection to the vCPU.
+pub struct GdbPort {
+    requests: Sender<DebugRequest>,
+    responses: Receiver<DebugResponse>,
+}
+
+/// Serves gdb, one connection at a time.
+pub struct GdbServer {
+    listener: TcpListener,
+    port: GdbPort,
+    // Why the vCPU is stopped, if it is.
+    stopped: Option<StopReason>,
+}
+
+impl GdbServer {
+    /// Listens on `addr` for gdb to connect.
+    pub fn bind(addr: SocketAddr, port: GdbPort) -> io::Result<Self> {
+        Ok(GdbServer {
+            listener: TcpListener::bind(addr)?,
+            port,
+            stopped: None,
+        })
+    }
+
+    /// Serves connections on a thread of its own. `kick` gets the vCPU out of the guest, so
+    /// that it sees the requests sent while it runs.
+    pub fn start(mut self, kick: impl Fn() + Send + 'static) -> io::Result<()> {
+        let addr = self.listener.local_addr()?;
+        thread::Builder::new()
+            .name("gdb server".into())
+            .spawn(move || {
+                info!("Waiting for gdb to connect on {addr}");
+                for stream in self.listener.try_clone().unwrap().incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Failed to accept gdb connection: {e}");
+                            continue;
+                        }
+                    };
+                    info!("gdb connected from {:?}", stream.peer_addr());
+                    match self.serve(stream, &kick) {
+                        Ok(true) => return,
+                        Ok(false) => info!("gdb disconnected"),
+                        Err(e) => warn!("gdb connection failed: {e}"),
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    // Serves one connection. Returns whether the VM is stopping.
+    fn serve(&mut self, mut stream: TcpStream, kick: &dyn Fn()) -> io::Result<bool> {
+        stream.set_nodelay(true)?;
+        let incoming = Self::read_incoming(stream.try_clone()?)?;
+
+        // gdb expects the target stopped once it connects.
+        if self.stopped.is_none() {
+            self.interrupt(kick)?;
+            self.wait_stopped()?;
+        }
+
+        loop {
+            select! {
+                recv(incoming) -> msg => {
+                    let packet = match msg {
+                        Ok(Incoming::Packet(packet)) => packet,
+                        Ok(Incoming::Corrupt) => {
+                            stream.write_all(b"-")?;
+                            continue;
+                        }
+                        Ok(Incoming::Interrupt) => {
+                            if self.stopped.is_none() {
+                                self.interrupt(kick)?;
+                            }
+                            continue;
+                        }
+                        Err(_) => {
+                            // Gone without detaching: let the guest run on.
+                            if self.stopped.is_none() {
+                                self.interrupt(kick)?;
+                                self.wait_stopped()?;
+                            }
+                            self.request(DebugRequest::Detach)?;
+                            self.stopped = None;
+                            return Ok(false);
+                        }
+                    };
+                    stream.write_all(b"+")?;
+
+                    // gdb waits for a stop reply before sending anything else.
+                    if self.stopped.is_none() {
+                        stream.write_all(&encode(b"E10"))?;
+                        continue;
+                    }
+                    let command = Command::parse(&packet);
+                    let ends = match command {
+                        Command::Detach => Some(false),
+                        Command::Kill => Some(true),
+                        _ => None,
+                    };
+                    if let Some(reply) = self.handle(command)? {
+                        stream.write_all(&encode(reply.as_bytes()))?;
+                    }
+                    if let Some(stopping) = ends {
+                        return Ok(stopping);
+                    }
+                }
+                recv(self.port.responses) -> resp => match resp {
+                    Ok(DebugResponse::Stopped(reason)) => {
+                        self.stopped = Some(reason);
+                        stream.write_all(&encode(reason.reply().as_bytes()))?;
+                    }
+                    Ok(resp) => warn!("Unexpected response from the vCPU: {resp:?}"),
+                    Err(_) => return Ok(true),
+                },
+            }
+        }
+    }
+
+    // Handles a command while the vCPU is stopped, returning the reply, if there's one now.
+    fn handle(&mut self, command: Command) -> io::Result<Option<String>> {
+        let reply = match command {
+            Command::HaltReason => self.stopped.unwrap_or(StopReason::Start).reply().into(),
+            Command::ReadRegisters => self.request_reply(DebugRequest::ReadRegisters)?,
+            Command::WriteRegisters(data) => {
+                self.request_reply(DebugRequest::WriteRegisters(data))?
+            }
+            Command::ReadMemory { addr, len } => {
+                self.request_reply(DebugRequest::ReadMemory { addr, len })?
+            }
+            Command::WriteMemory { addr, data } => {
+                self.request_reply(DebugRequest::WriteMemory { addr, data })?
+            }
+            Command::InsertBreakpoint { kind, addr } => {
+                self.request_reply(DebugRequest::InsertBreakpoint { kind, addr })?
+            }
+            Command::RemoveBreakpoint { kind, addr } => {
+                self.request_reply(DebugRequest::RemoveBreakpoint { kind, addr })?
+            }
+            Command::Continue(addr) | Command::Step(addr) => {
+                let step = matches!(command, Command::Step(_));
+                self.send(DebugRequest::Resume { step, addr })?;
+                self.stopped = None;
+                return Ok(None);
+            }
+            Command::Supported => "PacketSize=4000;swbreak+;hwbreak+".into(),
+            Command::Attached => "1".into(),
+            Command::CurrentThread => "QC1".into(),
+            Command::FirstThreadInfo => "m1".into(),
+            Command::NextThreadInfo => "l".into(),
+            Command::SetThread => "OK".into(),
+            Command::Detach => {
+                self.request(DebugRequest::Detach)?;
+                self.stopped = None;
+                "OK".into()
+            }
+            Command::Kill => {
+                self.send(DebugRequest::Kill)?;
+                return Ok(None);
+            }
+            Command::Unsupported => String::new(),
+            Command::Malformed => format!("E{:02x}", libc::EINVAL),
+        };
+        Ok(Some(reply))
+    }
+
+    fn send(&self, request: DebugRequest) -> io::Result<()> {
+        self.port
+            .requests
+            .send(request)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn request(&self, request: DebugRequest) -> io::Result<DebugResponse> {
+        self.send(request)?;
+        self.port
+            .responses
+            .recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn request_reply(&self, request: DebugRequest) -> io::Result<String> {
+        Ok(match self.request(request)? {
+            DebugResponse::Ok => "OK".into(),
+            DebugResponse::Data(data) => encode_hex(&data),
+            DebugResponse::Error(errno) => format!("E{:02x}", errno & 0xff),
+            DebugResponse::Stopped(reason) => {
+                warn!("vCPU stopped ({reason:?}) while already stopped");
+                format!("E{:02x}", libc::EIO)
+            }
+        })
+    }
+
+    fn interrupt(&self, kick: &dyn Fn()) -> io::Result<()> {
+        self.send(DebugRequest::Interrupt)?;
+        kick();
+        Ok(())
+    }
+
+    fn wait_stopped(&mut self) -> io::Result<()> {
+        while self.stopped.is_none() {
+            match self.port.responses.recv() {
+                Ok(DebugResponse::Stopped(reason)) => self.stopped = Some(reason),
+                Ok(resp) => warn!("Unexpected response from the vCPU: {resp:?}"),
+                Err(_) => return Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            }
+        }
+        Ok(())
+    }
+
+    // Reads from gdb on a thread of its own, so that the vCPU stopping can be waited on too.
+    fn read_incoming(mut stream: TcpStream) -> io::Result<Receiver<Incoming>> {
+        let (tx, rx) = unbounded();
+        thread::Builder::new()
+            .name("gdb reader".into())
+            .spawn(move || {
+                let mut reader = PacketReader::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => n,
+                    };
+                    for incoming in reader.feed(&buf[..n]) {
+                        if tx.send(incoming).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })?;
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_a_stopped_vcpu() {
+        let (req_tx, req_rx) = unbounded();
+        let (resp_tx, resp_rx) = unbounded();
+        let port = GdbPort {
+            requests: req_tx,
+            responses: resp_rx,
+        };
+        let server = GdbServer::bind("127.0.0.1:0".parse().unwrap(), port).unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        server.start(|| {}).unwrap();
+
+        // Stands in for the vCPU, which stops when gdb connects.
+        let vcpu = thread::spawn(move || {
+            for request in req_rx.iter() {
+                let response = match request {
+                    DebugRequest::Interrupt => DebugResponse::Stopped(StopReason::Interrupted),
+                    DebugRequest::ReadMemory { addr: 0x1000, len } => {
+                        DebugResponse::Data(vec![0xcc; len])
+                    }
+                    DebugRequest::ReadMemory { .. } => DebugResponse::Error(libc::EFAULT),
+                    DebugRequest::Detach => {
+                        resp_tx.send(DebugResponse::Ok).unwrap();
+                        return;
+                    }
+                    request => panic!("unexpected request {request:?}"),
+                };
+                resp_tx.send(response).unwrap();
+            }
+        });
+
+        let mut gdb = TcpStream::connect(addr).unwrap();
+        let mut exchange = |packet: &[u8], expected: &[u8]| {
+            gdb.write_all(&encode(packet)).unwrap();
+            let mut reply = vec![0u8; expected.len()];
+            gdb.read_exact(&mut reply).unwrap();
+            assert_eq!(
+                reply,
+                expected,
+                "reply to {:?}",
+                String::from_utf8_lossy(packet)
+            );
+        };
+        exchange(b"?", b"+$S02#b5");
+        exchange(b"m1000,2", b"+$cccc#8c");
+        exchange(b"m2000,2", b"+$E0e#da");
+        exchange(b"D", b"+$OK#9a");
+        vcpu.join().unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Framing and parsing of GDB remote serial protocol packets.
+
+use std::fmt::Write;
+
+/// The byte gdb sends, outside of any packet, to stop the target.
+const INTERRUPT: u8 = 0x03;
+
+/// What arrived from gdb.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    /// A packet, with its checksum checked and its escapes undone.
+    Packet(Vec<u8>),
+    /// A packet whose checksum didn't match, which gdb sends again on a `-`.
+    Corrupt,
+    /// A request to stop the target.
+    Interrupt,
+}
+
+/// Splits the bytes gdb sends into packets.
+#[derive(Default)]
+pub struct PacketReader {
+    buf: Vec<u8>,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds bytes read from gdb, and returns what they complete.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Incoming> {
+        let mut incoming = Vec::new();
+        for &byte in data {
+            if self.buf.is_empty() {
+                match byte {
+                    b'$' => self.buf.push(byte),
+                    INTERRUPT => incoming.push(Incoming::Interrupt),
+                    // Acknowledgments, which are only worth anything over unreliable links.
+                    _ => {}
+                }
+                continue;
+            }
+
+            self.buf.push(byte);
+            // A packet ends two digits after its '#'.
+            let len = self.buf.len();
+            if len >= 4 && self.buf[len - 3] == b'#' {
+                incoming.push(Self::decode(&self.buf[1..len - 3], &self.buf[len - 2..]));
+                self.buf.clear();
+            }
+        }
+        incoming
+    }
+
+    fn decode(body: &[u8], checksum: &[u8]) -> Incoming {
+        let sum = body.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if parse_hex(checksum) != Some(u64::from(sum)) {
+            return Incoming::Corrupt;
+        }
+
+        let mut data = Vec::with_capacity(body.len());
+        let mut bytes = body.iter();
+        while let Some(&byte) = bytes.next() {
+            match byte {
+                b'}' => match bytes.next() {
+                    Some(&escaped) => data.push(escaped ^ 0x20),
+                    None => return Incoming::Corrupt,
+                },
+                _ => data.push(byte),
+            }
+        }
+        Incoming::Packet(data)
+    }
+}
+
+/// Frames `data` into a packet, escaping what needs to be.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    let mut sum = 0u8;
+    for &byte in data {
+        let escaped: &[u8] = match byte {
+            b'#' | b'$' | b'}' | b'*' => &[b'}', byte ^ 0x20],
+            _ => &[byte],
+        };
+        for &b in escaped {
+            sum = sum.wrapping_add(b);
+            packet.push(b);
+        }
+    }
+    packet.extend(format!("#{sum:02x}").bytes());
+    packet
+}
+
+/// The kinds of breakpoint gdb asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// An instruction patched into guest memory.
+    Software,
+    /// A debug register of the vCPU.
+    Hardware,
+}
+
+/// A request from gdb.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `?`: why the target stopped.
+    HaltReason,
+    /// `g`: the values of all registers.
+    ReadRegisters,
+    /// `G`: sets all registers.
+    WriteRegisters(Vec<u8>),
+    /// `m`: reads guest memory at a virtual address.
+    ReadMemory { addr: u64, len: usize },
+    /// `M`: writes guest memory at a virtual address.
+    WriteMemory { addr: u64, data: Vec<u8> },
+    /// `c`: resumes, from the given address if there's one.
+    Continue(Option<u64>),
+    /// `s`: runs one instruction, from the given address if there's one.
+    Step(Option<u64>),
+    /// `Z0` and `Z1`: sets a breakpoint.
+    InsertBreakpoint { kind: BreakpointKind, addr: u64 },
+    /// `z0` and `z1`: clears a breakpoint.
+    RemoveBreakpoint { kind: BreakpointKind, addr: u64 },
+    /// `qSupported`: what the stub supports.
+    Supported,
+    /// `qAttached`: whether gdb attached to an existing process.
+    Attached,
+    /// `qC`: the current thread.
+    CurrentThread,
+    /// `qfThreadInfo`: the first part of the thread list.
+    FirstThreadInfo,
+    /// `qsThreadInfo`: the rest of the thread list.
+    NextThreadInfo,
+    /// `H`: picks the thread further requests apply to, of which there's only one.
+    SetThread,
+    /// `D`: detaches, letting the guest run on.
+    Detach,
+    /// `k`: stops the VM.
+    Kill,
+    /// Anything else, which gets the empty reply gdb takes for "not supported".
+    Unsupported,
+    /// A request that is supported but couldn't be parsed.
+    Malformed,
+}
+
+impl Command {
+    pub fn parse(packet: &[u8]) -> Command {
+        let Some((&kind, args)) = packet.split_first() else {
+            return Command::Unsupported;
+        };
+        let parsed = match kind {
+            b'?' => Some(Command::HaltReason),
+            b'g' => Some(Command::ReadRegisters),
+            b'G' => decode_hex(args).map(Command::WriteRegisters),
+            b'm' => Self::parse_memory(args).map(|(addr, len)| Command::ReadMemory {
+                addr,
+                len: len as usize,
+            }),
+            b'M' => Self::parse_write(args),
+            b'c' => Self::parse_resume_addr(args).map(Command::Continue),
+            b's' => Self::parse_resume_addr(args).map(Command::Step),
+            b'Z' | b'z' => match Self::parse_breakpoint(args) {
+                Some(Some((kind, addr))) if packet[0] == b'Z' => {
+                    Some(Command::InsertBreakpoint { kind, addr })
+                }
+                Some(Some((kind, addr))) => Some(Command::RemoveBreakpoint { kind, addr }),
+                // Watchpoints.
+                Some(None) => return Command::Unsupported,
+                None => None,
+            },
+            b'q' => {
+                return match args {
+                    a if a.starts_with(b"Supported") => Command::Supported,
+                    a if a.starts_with(b"Attached") => Command::Attached,
+                    b"C" => Command::CurrentThread,
+                    b"fThreadInfo" => Command::FirstThreadInfo,
+                    b"sThreadInfo" => Command::NextThreadInfo,
+                    _ => Command::Unsupported,
+                }
+            }
+            b'H' => Some(Command::SetThread),
+            b'D' => Some(Command::Detach),
+            b'k' => Some(Command::Kill),
+            _ => return Command::Unsupported,
+        };
+        parsed.unwrap_or(Command::Malformed)
+    }
+
+    // `addr,length`
+    fn parse_memory(args: &[u8]) -> Option<(u64, u64)> {
+        let mut parts = args.splitn(2, |&b| b == b',');
+        Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+    }
+
+    // `addr,length:XX...`
+    fn parse_write(args: &[u8]) -> Option<Command> {
+        let colon = args.iter().position(|&b| b == b':')?;
+        let (addr, len) = Self::parse_memory(&args[..colon])?;
+        let data = decode_hex(&args[colon + 1..])?;
+        (data.len() as u64 == len).then_some(Command::WriteMemory { addr, data })
+    }
+
+    fn parse_resume_addr(args: &[u8]) -> Option<Option<u64>> {
+        if args.is_empty() {
+            Some(None)
+        } else {
+            parse_hex(args).map(Some)
+        }
+    }
+
+    // `type,addr,kind`, where `None` is a type other than a breakpoint.
+    fn parse_breakpoint(args: &[u8]) -> Option<Option<(BreakpointKind, u64)>> {
+        let mut parts = args.split(|&b| b == b',');
+        let kind = match parts.next()? {
+            b"0" => BreakpointKind::Software,
+            b"1" => BreakpointKind::Hardware,
+            _ => return Some(None),
+        };
+        let addr = parse_hex(parts.next()?)?;
+        Some(Some((kind, addr)))
+    }
+}
+
+/// Parses a big-endian hex number, as gdb writes addresses and lengths.
+pub fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    let hex = std::str::from_utf8(hex).ok()?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Decodes a string of hex byte pairs.
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| parse_hex(pair).map(|b| b as u8))
+        .collect()
+}
+
+/// Encodes bytes as hex pairs.
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter()
+        .fold(String::with_capacity(data.len() * 2), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_packets() {
+        let mut reader = PacketReader::new();
+        assert_eq!(
+            reader.feed(b"+$g#67"),
+            vec![Incoming::Packet(b"g".to_vec())]
+        );
+
+        // Split across reads, with an interrupt and a corrupted packet in between.
+        assert!(reader.feed(b"$m1000,").is_empty());
+        assert_eq!(
+            reader.feed(b"4#8e\x03$g#00"),
+            vec![
+                Incoming::Packet(b"m1000,4".to_vec()),
+                Incoming::Interrupt,
+                Incoming::Corrupt,
+            ]
+        );
+
+        // Escapes round-trip.
+        let data = b"a#b$c}d*e";
+        let packet = encode(data);
+        assert_eq!(reader.feed(&packet), vec![Incoming::Packet(data.to_vec())]);
+        assert_eq!(encode(b"OK"), b"$OK#9a");
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse(b"?"), Command::HaltReason);
+        assert_eq!(
+            Command::parse(b"mffffffff81000000,10"),
+            Command::ReadMemory {
+                addr: 0xffff_ffff_8100_0000,
+                len: 16
+            }
+        );
+        assert_eq!(
+            Command::parse(b"M1000,2:cc90"),
+            Command::WriteMemory {
+                addr: 0x1000,
+                data: vec![0xcc, 0x90]
+            }
+        );
+        assert_eq!(Command::parse(b"M1000,3:cc90"), Command::Malformed);
+        assert_eq!(Command::parse(b"c"), Command::Continue(None));
+        assert_eq!(Command::parse(b"s1000"), Command::Step(Some(0x1000)));
+        assert_eq!(
+            Command::parse(b"Z0,ffffffff81000000,1"),
+            Command::InsertBreakpoint {
+                kind: BreakpointKind::Software,
+                addr: 0xffff_ffff_8100_0000
+            }
+        );
+        assert_eq!(
+            Command::parse(b"z1,2000,1"),
+            Command::RemoveBreakpoint {
+                kind: BreakpointKind::Hardware,
+                addr: 0x2000
+            }
+        );
+        assert_eq!(Command::parse(b"Z2,2000,4"), Command::Unsupported);
+        assert_eq!(
+            Command::parse(b"qSupported:multiprocess+;swbreak+"),
+            Command::Supported
+        );
+        assert_eq!(Command::parse(b"vCont?"), Command::Unsupported);
+        assert_eq!(Command::parse(b"Gzz"), Command::Malformed);
+        assert_eq!(encode_hex(&[0xde, 0xad]), "dead");
+    }
+}
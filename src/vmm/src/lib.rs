@@ -18,6 +18,9 @@ pub mod builder;
 pub(crate) mod device_manager;
 /// Cross-platform exit signal handlers (SIGTERM, SIGUSR1).
 pub mod exit_signal;
+/// GDB remote protocol server for debugging the guest.
+#[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+pub mod gdb;
 /// Resource store for configured microVM resources.
 pub mod resources;
 /// Signal handling utilities.
@@ -41,7 +44,6 @@ use macos::vstate;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::{Arc, Mutex};
 #[cfg(target_os = "linux")]
 use std::time::Duration;
@@ -59,7 +61,7 @@ use crossbeam_channel::Sender;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use devices::fdt;
 use devices::legacy::IrqChip;
-use devices::virtio::VmmExitObserver;
+use devices::virtio::{BootStage, BootTimeline, ConsoleWatch, SharedExitStatus, VmmExitObserver};
 use devices::{BusDevice, DeviceType};
 use kernel::cmdline::Cmdline as KernelCmdline;
 use polly::event_manager::{self, EventManager, Subscriber};
@@ -203,7 +205,9 @@ pub struct Vmm {
     exit_evt: EventFd,
     vm: Vm,
     exit_observers: Vec<Arc<Mutex<dyn VmmExitObserver>>>,
-    exit_code: Arc<AtomicI32>,
+    exit_status: Arc<SharedExitStatus>,
+    console_watch: Option<Arc<ConsoleWatch>>,
+    boot_timeline: Option<Arc<BootTimeline>>,
 
     // Guest VM devices.
     mmio_device_manager: MMIODeviceManager,
@@ -267,6 +271,23 @@ impl Vmm {
         Ok(())
     }
 
+    /// Asks the vcpus to stop running guest code, such as after the guest kernel panicked
+    /// without rebooting, without waiting for them to do so.
+    #[cfg(target_os = "linux")]
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        for handle in self.vcpus_handles.iter() {
+            handle
+                .send_event(VcpuEvent::Pause)
+                .map_err(Error::VcpuEvent)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Configures the system for boot.
     pub fn configure_system(
         &self,
@@ -365,6 +386,9 @@ impl Vmm {
     /// Each observer is wrapped in `catch_unwind` so that a panic in one
     /// observer does not prevent subsequent observers from running.
     pub fn notify_exit_observers(&mut self, exit_code: i32) {
+        if let Some(timeline) = &self.boot_timeline {
+            timeline.mark(BootStage::Exited);
+        }
         for observer in &self.exit_observers {
             let obs = Arc::clone(observer);
             if let Err(e) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
@@ -436,10 +460,15 @@ impl Subscriber for Vmm {
                     _ => None,
                 })
                 .unwrap_or(FC_EXIT_CODE_OK);
-            let vmm_exit_code = self.exit_code.load(Ordering::SeqCst);
-            let exit_code = if vmm_exit_code != i32::MAX {
-                debug!("using vmm exit code: {vmm_exit_code}");
-                vmm_exit_code
+            // A panicking kernel reboots. The guest waits for its console output to be taken
+            // before going on, so the banner has been seen by now.
+            if self.console_watch.as_ref().is_some_and(|w| w.panicked()) {
+                error!("Guest kernel panicked");
+                return;
+            }
+            let exit_code = if let Some(status) = self.exit_status.get() {
+                debug!("using guest exit status: {status}");
+                status.code()
             } else {
                 debug!("using vcpu exit code: {vcpu_exit_code}");
                 vcpu_exit_code as i32
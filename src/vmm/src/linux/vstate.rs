@@ -37,6 +37,8 @@ use super::tee::inteltdx::{Error as TdxError, IntelTdx};
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 
+#[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+use crate::gdb::{Handled, StopReason, VcpuDebug};
 #[cfg(feature = "tee")]
 use crate::resources::TeeConfig;
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
@@ -59,6 +61,7 @@ use kvm_bindings::{kvm_enable_cap, KVM_CAP_EXIT_HYPERCALL, KVM_MEMORY_EXIT_FLAG_
 use kvm_bindings::{kvm_memory_attributes, KVM_MEMORY_ATTRIBUTE_PRIVATE};
 use kvm_ioctls::{Cap::*, *};
 use utils::eventfd::EventFd;
+use utils::sandbox::ThreadCategory;
 use utils::signal::{register_signal_handler, sigrtmin, Killable};
 use utils::sm::StateMachine;
 #[cfg(feature = "tee")]
@@ -949,6 +952,9 @@ pub struct Vcpu {
 
     #[cfg(feature = "tee")]
     pm_sender: Sender<WorkerMessage>,
+
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    debug: Option<VcpuDebug>,
 }
 
 impl Vcpu {
@@ -1081,6 +1087,8 @@ impl Vcpu {
             response_sender,
             #[cfg(feature = "tee")]
             pm_sender,
+            #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+            debug: None,
         })
     }
 
@@ -1148,6 +1156,12 @@ impl Vcpu {
         self.mpidr
     }
 
+    /// Lets gdb debug this vcpu, which stops before running its first instruction.
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    pub fn set_debug(&mut self, debug: VcpuDebug) {
+        self.debug = Some(debug);
+    }
+
     /// Sets a MMIO bus for this vcpu.
     pub fn set_mmio_bus(&mut self, mmio_bus: devices::Bus) {
         self.mmio_bus = Some(mmio_bus);
@@ -1282,6 +1296,7 @@ impl Vcpu {
                     .send(true)
                     .expect("Cannot notify vcpu TLS initialization.");
 
+                utils::sandbox::enter(ThreadCategory::Vcpu);
                 self.run();
             })
             .map_err(Error::VcpuSpawn)?;
@@ -1504,6 +1519,8 @@ impl Vcpu {
                     }
                     Ok(VcpuEmulation::Handled)
                 }
+                #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+                VcpuExit::Debug(exit) if self.debug.is_some() => Ok(VcpuEmulation::Debug(exit)),
                 VcpuExit::Hlt => {
                     info!("Received KVM_EXIT_HLT signal");
                     Ok(VcpuEmulation::Stopped)
@@ -1568,6 +1585,11 @@ impl Vcpu {
 
     // This is the main loop of the `Running` state.
     fn running(&mut self) -> StateMachine<Self> {
+        #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+        if let Some(reason) = self.debug.as_mut().and_then(VcpuDebug::pending_stop) {
+            return self.debug_stop(reason);
+        }
+
         // This loop is here just for optimizing the emulation path.
         // No point in ticking the state machine if there are no external events.
         loop {
@@ -1586,6 +1608,11 @@ impl Vcpu {
                 Ok(VcpuEmulation::Stopped) => return self.exit(FC_EXIT_CODE_OK),
                 // Emulation errors lead to vCPU exit.
                 Err(_) => return self.exit(FC_EXIT_CODE_GENERIC_ERROR),
+                #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+                Ok(VcpuEmulation::Debug(exit)) => {
+                    let reason = self.debug.as_ref().unwrap().stop_reason(&exit);
+                    return self.debug_stop(reason);
+                }
             }
         }
 
@@ -1646,6 +1673,23 @@ impl Vcpu {
         }
     }
 
+    // Stops for gdb, telling it why.
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    fn debug_stop(&mut self, reason: StopReason) -> StateMachine<Self> {
+        self.debug.as_ref().unwrap().notify_stopped(reason);
+        StateMachine::next(Self::debugging)
+    }
+
+    // This is the main loop of the state of being stopped for gdb.
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    fn debugging(&mut self) -> StateMachine<Self> {
+        match self.debug.as_mut().unwrap().handle_next(&self.fd) {
+            Handled::Stay => StateMachine::next(Self::debugging),
+            Handled::Resume => StateMachine::next(Self::running),
+            Handled::Kill => self.exit(FC_EXIT_CODE_OK),
+        }
+    }
+
     #[cfg(not(test))]
     // Transition to the exited state.
     fn exit(&mut self, exit_code: u8) -> StateMachine<Self> {
@@ -1772,12 +1816,41 @@ impl VcpuHandle {
     pub fn response_receiver(&self) -> &Receiver<VcpuResponse> {
         &self.response_receiver
     }
+
+    /// Gets a handle that kicks the vcpu out of the guest from any thread.
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    pub fn kicker(&self) -> VcpuKicker {
+        VcpuKicker(self.vcpu_thread.as_ref().unwrap().pthread_handle())
+    }
+}
+
+/// Kicks a vcpu out of the guest, so that it looks for requests.
+#[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+pub struct VcpuKicker(libc::pthread_t);
+
+// Safe because the vcpu threads never finish before the process exits.
+#[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+unsafe impl Killable for VcpuKicker {
+    fn pthread_handle(&self) -> libc::pthread_t {
+        self.0
+    }
+}
+
+#[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+impl VcpuKicker {
+    pub fn kick(&self) {
+        if let Err(e) = self.kill(sigrtmin() + VCPU_RTSIG_OFFSET) {
+            error!("Failed to kick vcpu: {e}");
+        }
+    }
 }
 
 enum VcpuEmulation {
     Handled,
     Interrupted,
     Stopped,
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    Debug(kvm_bindings::kvm_debug_exit_arch),
 }
 
 #[cfg(test)]
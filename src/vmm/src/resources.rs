@@ -3,16 +3,23 @@
 
 //#![deny(warnings)]
 
+use std::collections::HashMap;
 #[cfg(feature = "tee")]
 use std::fs::File;
 #[cfg(feature = "tee")]
 use std::io::BufReader;
+#[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+use std::net::SocketAddr;
 use std::os::fd::RawFd;
 use std::path::PathBuf;
+use std::sync::Arc;
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+use std::sync::Mutex;
 
 #[cfg(feature = "tee")]
 use serde::{Deserialize, Serialize};
 
+use crate::vmm_config::balloon::BalloonDeviceConfig;
 #[cfg(feature = "blk")]
 use crate::vmm_config::block::{BlockBuilder, BlockConfigError, BlockDeviceConfig};
 use crate::vmm_config::external_kernel::ExternalKernel;
@@ -30,6 +37,11 @@ use crate::vmm_config::vsock::*;
 use crate::vstate::VcpuConfig;
 #[cfg(feature = "gpu")]
 use devices::virtio::display::DisplayInfo;
+#[cfg(target_os = "linux")]
+use devices::virtio::fs::privileges::Keep;
+#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+use devices::virtio::Fs;
+use devices::virtio::{BootTimeline, ConsoleWatch, VsockConnectHandler};
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 #[cfg(feature = "gpu")]
@@ -155,8 +167,13 @@ pub struct VmResources {
     /// Custom filesystem devices.
     #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
     pub custom_fs: Vec<CustomFsDeviceConfig>,
+    /// Empty virtio-fs devices that shares can be plugged into while the VM runs.
+    #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+    pub fs_hotplug_slots: Vec<Arc<Mutex<Fs>>>,
     /// The vsock device.
     pub vsock: VsockBuilder,
+    /// The balloon device.
+    pub balloon: BalloonDeviceConfig,
     /// The virtio-blk device.
     #[cfg(feature = "blk")]
     pub block: BlockBuilder,
@@ -194,14 +211,35 @@ pub struct VmResources {
     /// determines it is needed (HIJACK_INET when there's no virtio-net, or
     /// HIJACK_UNIX when there's a single root virtio-fs on Linux).
     pub request_vsock: bool,
+    /// Handlers for the connections the guest makes to these vsock ports on
+    /// the host. Having any makes the vsock device needed.
+    pub vsock_listeners: HashMap<u32, VsockConnectHandler>,
+    /// Send the guest the host's time over vsock when the host's clock jumps,
+    /// such as after the host slept.
+    pub clock_resync: bool,
+    /// Capabilities to keep once the devices are set up, dropping the others
+    /// before the vcpus start.
+    #[cfg(target_os = "linux")]
+    pub drop_privileges: Option<Keep>,
     /// Do not create an implicit console device in the guest
     pub disable_implicit_console: bool,
+    /// Hand the host's stdio to the guest as pipes even when they're terminals, which are
+    /// then also left out of raw mode.
+    pub stdio_as_pipes: bool,
     /// The console id to use for console= in the kernel cmdline
     pub kernel_console: Option<String>,
     /// Serial consoles to attach to the guest
     pub serial_consoles: Vec<SerialConsoleConfig>,
     /// Virtio consoles to attach to the guest
     pub virtio_consoles: Vec<VirtioConsoleConfigMode>,
+    /// Keeps the tail of the guest's console, `hvc0`, and spots kernel panics in it.
+    pub console_watch: Option<Arc<ConsoleWatch>>,
+    /// Where the VM records when it gets through each stage of its boot.
+    pub boot_timeline: Option<Arc<BootTimeline>>,
+    /// Where to wait for gdb to debug the first vCPU, which then halts before its first
+    /// instruction.
+    #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+    pub gdb_addr: Option<SocketAddr>,
 }
 
 impl VmResources {
@@ -405,6 +443,7 @@ mod tests {
     use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
     use crate::vmm_config::vsock::tests::{default_config, TempSockFile};
     use crate::vstate::VcpuConfig;
+    use std::collections::HashMap;
     use utils::tempfile::TempFile;
 
     fn default_kernel_cmdline() -> KernelCmdlineConfig {
@@ -425,7 +464,10 @@ mod tests {
             fs: Default::default(),
             #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
             custom_fs: Default::default(),
+            #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+            fs_hotplug_slots: Default::default(),
             vsock: Default::default(),
+            balloon: Default::default(),
             #[cfg(feature = "net")]
             net_builder: Default::default(),
             gpu_virgl_flags: None,
@@ -443,9 +485,18 @@ mod tests {
             nested_enabled: false,
             split_irqchip: false,
             request_vsock: false,
+            vsock_listeners: HashMap::new(),
+            clock_resync: cfg!(target_os = "macos"),
+            #[cfg(target_os = "linux")]
+            drop_privileges: None,
             disable_implicit_console: false,
+            stdio_as_pipes: false,
             serial_consoles: Vec::new(),
             virtio_consoles: Vec::new(),
+            console_watch: None,
+            boot_timeline: None,
+            #[cfg(all(feature = "gdbstub", target_os = "linux", target_arch = "x86_64"))]
+            gdb_addr: None,
             kernel_console: None,
         }
     }
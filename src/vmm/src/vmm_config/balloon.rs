@@ -0,0 +1,21 @@
+use std::sync::Arc;
+
+use devices::virtio::BalloonMetrics;
+
+/// Configuration of the balloon device.
+#[derive(Clone, Debug)]
+pub struct BalloonDeviceConfig {
+    /// Offer the guest free page reporting, so that the memory it frees goes back to the host.
+    pub free_page_reporting: bool,
+    /// Counts the pages the guest reports.
+    pub metrics: Arc<BalloonMetrics>,
+}
+
+impl Default for BalloonDeviceConfig {
+    fn default() -> Self {
+        Self {
+            free_page_reporting: true,
+            metrics: Arc::new(BalloonMetrics::new()),
+        }
+    }
+}
@@ -4,7 +4,7 @@ use std::sync::{Arc, Mutex};
 
 use devices::virtio::{
     block::{ImageType, SyncMode},
-    Block, CacheType,
+    Block, BlockSlot, CacheType, RateLimit,
 };
 
 #[derive(Debug)]
@@ -33,6 +33,7 @@ pub struct BlockDeviceConfig {
     pub is_disk_read_only: bool,
     pub direct_io: bool,
     pub sync_mode: SyncMode,
+    pub rate_limit: RateLimit,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -45,12 +46,15 @@ pub struct BlockRootConfig {
 #[derive(Default)]
 pub struct BlockBuilder {
     pub list: VecDeque<Arc<Mutex<Block>>>,
+    /// Empty slots for disks to be plugged into while the VM runs.
+    pub hotplug_slots: Vec<Arc<Mutex<BlockSlot>>>,
 }
 
 impl BlockBuilder {
     pub fn new() -> Self {
         Self {
             list: VecDeque::<Arc<Mutex<Block>>>::new(),
+            hotplug_slots: Vec::new(),
         }
     }
 
@@ -60,8 +64,14 @@ impl BlockBuilder {
         Ok(())
     }
 
+    /// Reserves an empty slot for a disk that shows up in the guest as `block_id` once plugged in.
+    pub fn add_hotplug_slot(&mut self, block_id: String) {
+        self.hotplug_slots
+            .push(Arc::new(Mutex::new(BlockSlot::new(block_id))));
+    }
+
     pub fn create_block(config: BlockDeviceConfig) -> Result<Block> {
-        devices::virtio::Block::new(
+        let block = devices::virtio::Block::new(
             config.block_id,
             None,
             config.cache_type,
@@ -71,6 +81,8 @@ impl BlockBuilder {
             config.direct_io,
             config.sync_mode,
         )
-        .map_err(BlockConfigError::CreateBlockDevice)
+        .map_err(BlockConfigError::CreateBlockDevice)?;
+        block.set_rate_limit(config.rate_limit);
+        Ok(block)
     }
 }
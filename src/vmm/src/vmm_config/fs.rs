@@ -1,8 +1,10 @@
-#[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
 use devices::virtio::fs::DynFileSystem;
+use devices::virtio::fs::{AtimePolicy, FsMetrics, IdMap, XattrRule};
 
 //--------------------------------------------------------------------------------------------------
 // Types
@@ -14,6 +16,31 @@ pub struct FsDeviceConfig {
     pub shared_dir: String,
     pub shm_size: Option<usize>,
     pub allow_root_dir_delete: bool,
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    pub atime: AtimePolicy,
+    pub metrics: Arc<FsMetrics>,
+    pub num_request_queues: usize,
+    /// Served as `init.krun` instead of the built-in init binary.
+    pub init_binary: Option<Vec<u8>>,
+    /// Paths, relative to `shared_dir`, that the guest can't see.
+    pub hidden: Vec<PathBuf>,
+    /// How extended attribute names are translated between the guest and the host.
+    pub xattr_map: Vec<XattrRule>,
+    /// Whether the guest is kept from going past host mount points in `shared_dir`.
+    pub one_filesystem: bool,
+    /// With `one_filesystem`, whether mount points show up as empty directories.
+    pub empty_mount_points: bool,
+    /// The largest read or write request, or `None` for the device's default.
+    pub max_io_size: Option<u32>,
+    /// Whether the workers confine themselves to `shared_dir` with Landlock.
+    pub landlock: bool,
+    /// Whether the guest is kept from changing anything in `shared_dir`.
+    pub read_only: bool,
+    /// Whether files the guest opens with `O_DIRECT` bypass the host's cache too.
+    pub direct_io: bool,
+    /// An open `/proc/self/fd` to serve the share through, for hosts without `/proc` mounted.
+    pub proc_fd: Option<Arc<OwnedFd>>,
 }
 
 #[cfg(not(any(feature = "tee", feature = "aws-nitro")))]
@@ -21,4 +48,5 @@ pub struct CustomFsDeviceConfig {
     pub fs_id: String,
     pub backend: Arc<dyn DynFileSystem>,
     pub shm_size: Option<usize>,
+    pub num_request_queues: usize,
 }
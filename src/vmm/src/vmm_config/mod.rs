@@ -1,6 +1,9 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+/// Wrapper for configuring the balloon device attached to the microVM.
+pub mod balloon;
+
 /// Wrapper for configuring the Block devices attached to the microVM.
 #[cfg(feature = "blk")]
 pub mod block;
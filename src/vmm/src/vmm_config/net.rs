@@ -7,7 +7,7 @@ use std::result;
 use std::sync::{Arc, Mutex};
 
 use devices::virtio::net::device::VirtioNetBackend;
-use devices::virtio::Net;
+use devices::virtio::{Net, RateLimit};
 
 pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
@@ -18,6 +18,10 @@ pub struct NetworkInterfaceConfig {
     pub mac: [u8; 6],
     /// virtio-net features for the network interface.
     pub features: u32,
+    /// Pairs of receive and transmit queues offered to the guest.
+    pub queue_pairs: u16,
+    /// Ceiling on the frames and bytes per second going each way.
+    pub rate_limit: RateLimit,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -65,7 +69,15 @@ impl NetBuilder {
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
         // Create and return the Net device
-        Net::new(cfg.iface_id, cfg.backend, cfg.mac, cfg.features)
-            .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let net = Net::new(
+            cfg.iface_id,
+            cfg.backend,
+            cfg.mac,
+            cfg.features,
+            cfg.queue_pairs,
+        )
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_rate_limit(cfg.rate_limit);
+        Ok(net)
     }
 }
@@ -1,7 +1,7 @@
 //! Common utilities used by multiple test
 
 use anyhow::Context;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fs;
 use std::fs::create_dir;
 use std::os::unix::ffi::OsStrExt;
@@ -28,6 +28,15 @@ fn copy_guest_agent(dir: &Path) -> anyhow::Result<()> {
 ///
 /// The returned object is used for deleting the temporary files.
 pub fn setup_fs_and_enter(ctx: u32, test_setup: TestSetup) -> anyhow::Result<()> {
+    setup_fs_and_enter_with_env(ctx, test_setup, &[])
+}
+
+/// Like [`setup_fs_and_enter`], but runs the guest agent with the environment variables `env`.
+pub fn setup_fs_and_enter_with_env(
+    ctx: u32,
+    test_setup: TestSetup,
+    env: &[&CStr],
+) -> anyhow::Result<()> {
     let root_dir = test_setup.tmp_dir.join("root");
     create_dir(&root_dir).context("Failed to create root directory")?;
 
@@ -39,7 +48,7 @@ pub fn setup_fs_and_enter(ctx: u32, test_setup: TestSetup) -> anyhow::Result<()>
         let test_case_cstr = CString::new(test_setup.test_case).context("CString::new")?;
         let argv = [test_case_cstr.as_ptr(), null()];
         //let envp = [c"RUST_BACKTRACE=1".as_ptr(), null()];
-        let envp = [null()];
+        let envp: Vec<_> = env.iter().map(|var| var.as_ptr()).chain([null()]).collect();
         krun_call!(krun_set_exec(
             ctx,
             c"/guest-agent".as_ptr(),
@@ -13,6 +13,18 @@ use test_tsi_tcp_guest_listen::TestTsiTcpGuestListen;
 mod test_multiport_console;
 use test_multiport_console::TestMultiportConsole;
 
+mod test_guest_hosts;
+use test_guest_hosts::TestGuestHosts;
+
+mod test_guest_dns;
+use test_guest_dns::TestGuestDns;
+
+mod test_guest_static_ip;
+use test_guest_static_ip::TestGuestStaticIp;
+
+mod test_tty;
+use test_tty::TestTty;
+
 pub fn test_cases() -> Vec<TestCase> {
     // Register your test here:
     vec![
@@ -40,6 +52,19 @@ pub fn test_cases() -> Vec<TestCase> {
             Box::new(TestTsiTcpGuestListen::new()),
         ),
         TestCase::new("multiport-console", Box::new(TestMultiportConsole)),
+        TestCase::new("guest-hosts", Box::new(TestGuestHosts)),
+        TestCase::new("guest-dns", Box::new(TestGuestDns)),
+        TestCase::new("guest-static-ip", Box::new(TestGuestStaticIp)),
+        TestCase::new("tty-auto", Box::new(TestTty::Auto)),
+        TestCase::new(
+            "tty-auto-console-output",
+            Box::new(TestTty::AutoConsoleOutput),
+        ),
+        TestCase::new("tty-force", Box::new(TestTty::Force)),
+        TestCase::new(
+            "tty-never-console-output",
+            Box::new(TestTty::NeverConsoleOutput),
+        ),
     ]
 }
 
@@ -0,0 +1,52 @@
+use macros::{guest, host};
+
+pub struct TestGuestDns;
+
+#[host]
+mod host {
+    use super::*;
+
+    use crate::common::setup_fs_and_enter_with_env;
+    use crate::{krun_call, krun_call_u32};
+    use crate::{Test, TestSetup};
+    use krun_sys::*;
+
+    impl Test for TestGuestDns {
+        fn start_vm(self: Box<Self>, test_setup: TestSetup) -> anyhow::Result<()> {
+            unsafe {
+                krun_call!(krun_set_log_level(KRUN_LOG_LEVEL_WARN))?;
+                let ctx = krun_call_u32!(krun_create_ctx())?;
+                krun_call!(krun_set_vm_config(ctx, 1, 512))?;
+                // What `NetBuilder::dns` passes to the init.
+                setup_fs_and_enter_with_env(
+                    ctx,
+                    test_setup,
+                    &[
+                        c"KRUN_NAMESERVERS=10.0.0.53,fd00::53",
+                        c"KRUN_DNS_SEARCH=corp.example.com,lab.example.com",
+                    ],
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[guest]
+mod guest {
+    use super::*;
+    use crate::Test;
+    use std::fs;
+
+    impl Test for TestGuestDns {
+        fn in_guest(self: Box<Self>) {
+            assert_eq!(
+                fs::read_to_string("/etc/resolv.conf").unwrap(),
+                "nameserver 10.0.0.53\n\
+                 nameserver fd00::53\n\
+                 search corp.example.com lab.example.com\n"
+            );
+            println!("OK");
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use macros::{guest, host};
+
+pub struct TestGuestHosts;
+
+#[host]
+mod host {
+    use super::*;
+
+    use crate::common::setup_fs_and_enter_with_env;
+    use crate::{krun_call, krun_call_u32};
+    use crate::{Test, TestSetup};
+    use krun_sys::*;
+
+    impl Test for TestGuestHosts {
+        fn start_vm(self: Box<Self>, test_setup: TestSetup) -> anyhow::Result<()> {
+            unsafe {
+                krun_call!(krun_set_log_level(KRUN_LOG_LEVEL_WARN))?;
+                let ctx = krun_call_u32!(krun_create_ctx())?;
+                krun_call!(krun_set_vm_config(ctx, 1, 512))?;
+                // What `VmBuilder::hostname` and `VmBuilder::host_entry` pass to the init.
+                setup_fs_and_enter_with_env(
+                    ctx,
+                    test_setup,
+                    &[
+                        c"KRUN_HOSTNAME=krun-test",
+                        c"KRUN_HOSTS=10.0.0.2=db,fd00::3=cache.internal",
+                    ],
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[guest]
+mod guest {
+    use super::*;
+    use crate::Test;
+    use std::fs;
+
+    impl Test for TestGuestHosts {
+        fn in_guest(self: Box<Self>) {
+            let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+            assert_eq!(hostname.trim_end(), "krun-test");
+            assert_eq!(std::env::var("HOSTNAME").unwrap(), "krun-test");
+            assert_eq!(fs::read_to_string("/etc/hostname").unwrap(), "krun-test\n");
+
+            let hosts = fs::read_to_string("/etc/hosts").unwrap();
+            let entries: Vec<Vec<&str>> = hosts
+                .lines()
+                .map(|line| line.split_whitespace().collect())
+                .collect();
+            for entry in [
+                ["127.0.0.1", "localhost"],
+                ["127.0.1.1", "krun-test"],
+                ["10.0.0.2", "db"],
+                ["fd00::3", "cache.internal"],
+            ] {
+                assert!(entries.contains(&entry.to_vec()), "{entry:?} missing");
+            }
+            println!("OK");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use macros::{guest, host};
+
+const HOST_ADDR: &str = "10.0.0.1";
+const PORT: u16 = 8001;
+
+pub struct TestGuestStaticIp;
+
+#[host]
+mod host {
+    use super::*;
+
+    use crate::common::setup_fs_and_enter_with_env;
+    use crate::{krun_call, krun_call_u32};
+    use crate::{Test, TestSetup};
+    use anyhow::{bail, Context};
+    use krun_sys::*;
+    use std::ffi::CString;
+    use std::io::Write;
+    use std::mem;
+    use std::net::TcpListener;
+    use std::process::Command;
+    use std::thread;
+
+    const TAP_NAME: &str = "krun-tap0";
+
+    fn ip(args: &[&str]) -> anyhow::Result<()> {
+        let status = Command::new("ip")
+            .args(args)
+            .status()
+            .context("Failed to run ip")?;
+        if !status.success() {
+            bail!("ip {args:?} failed: {status}");
+        }
+        Ok(())
+    }
+
+    impl Test for TestGuestStaticIp {
+        fn start_vm(self: Box<Self>, test_setup: TestSetup) -> anyhow::Result<()> {
+            ip(&["tuntap", "add", "dev", TAP_NAME, "mode", "tap"])?;
+            ip(&["addr", "add", &format!("{HOST_ADDR}/24"), "dev", TAP_NAME])?;
+            ip(&["link", "set", TAP_NAME, "up"])?;
+
+            let listener = TcpListener::bind((HOST_ADDR, PORT)).context("bind")?;
+            thread::spawn(move || {
+                let (mut stream, _addr) = listener.accept().unwrap();
+                stream.write_all(b"ping!").unwrap();
+                // We leak the file descriptor for now, since there is no easy way to close it on libkrun exit
+                mem::forget(listener);
+            });
+
+            unsafe {
+                krun_call!(krun_set_log_level(KRUN_LOG_LEVEL_WARN))?;
+                let ctx = krun_call_u32!(krun_create_ctx())?;
+                krun_call!(krun_set_vm_config(ctx, 1, 512))?;
+                let tap_name = CString::new(TAP_NAME).context("CString::new")?;
+                let mut mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+                krun_call!(krun_add_net_tap(
+                    ctx,
+                    tap_name.as_ptr() as *mut _,
+                    mac.as_mut_ptr(),
+                    0,
+                    0,
+                ))?;
+                // What `NetBuilder::static_ip` passes to the init.
+                setup_fs_and_enter_with_env(
+                    ctx,
+                    test_setup,
+                    &[c"KRUN_IPS=eth0,10.0.0.2/24,10.0.0.1"],
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[guest]
+mod guest {
+    use super::*;
+    use crate::Test;
+    use std::fs;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    impl Test for TestGuestStaticIp {
+        fn in_guest(self: Box<Self>) {
+            // The default route, with the gateway in network byte order.
+            let routes = fs::read_to_string("/proc/net/route").unwrap();
+            assert!(routes
+                .lines()
+                .any(|route| route.starts_with("eth0\t00000000\t0100000A\t")));
+
+            let mut tries = 0;
+            let mut stream = loop {
+                match TcpStream::connect((HOST_ADDR, PORT)) {
+                    Ok(stream) => break stream,
+                    Err(err) if tries == 5 => {
+                        panic!("Couldn't connect to the host after 5 attempts: {err}")
+                    }
+                    Err(_) => {
+                        tries += 1;
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            };
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping!");
+            println!("OK");
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use macros::{guest, host};
+
+/// Prints whether the workload's stdin, stdout and stderr are terminals, and
+/// whether it has a controlling one. The host's stdio are pipes, and the
+/// console's output, when it goes to a file, leaves the guest's stdio on the
+/// console, which is a terminal.
+pub enum TestTty {
+    Auto,
+    AutoConsoleOutput,
+    Force,
+    NeverConsoleOutput,
+}
+
+#[host]
+mod host {
+    use super::*;
+
+    use crate::common::setup_fs_and_enter_with_env;
+    use crate::{krun_call, krun_call_u32};
+    use crate::{Test, TestSetup};
+    use krun_sys::*;
+    use std::ffi::CStr;
+    use std::process::Child;
+
+    impl TestTty {
+        /// What `ExecBuilder::tty` passes to the init, whether the console's
+        /// output goes to the host's stdout, and what the guest prints.
+        fn config(&self) -> (&'static [&'static CStr], bool, &'static str) {
+            match self {
+                TestTty::Auto => (&[], false, "pipe pipe pipe ctty"),
+                TestTty::AutoConsoleOutput => (&[], true, "tty tty tty ctty"),
+                TestTty::Force => (&[c"KRUN_TTY=force"], false, "tty tty tty ctty"),
+                TestTty::NeverConsoleOutput => (&[c"KRUN_TTY=never"], true, "pipe pipe pipe none"),
+            }
+        }
+    }
+
+    impl Test for TestTty {
+        fn start_vm(self: Box<Self>, test_setup: TestSetup) -> anyhow::Result<()> {
+            let (env, console_output, _) = self.config();
+            unsafe {
+                krun_call!(krun_set_log_level(KRUN_LOG_LEVEL_WARN))?;
+                let ctx = krun_call_u32!(krun_create_ctx())?;
+                krun_call!(krun_set_vm_config(ctx, 1, 512))?;
+                if console_output {
+                    krun_call!(krun_set_console_output(ctx, c"/dev/stdout".as_ptr()))?;
+                }
+                setup_fs_and_enter_with_env(ctx, test_setup, env)?;
+            }
+            Ok(())
+        }
+
+        fn check(self: Box<Self>, child: Child) {
+            let output = child.wait_with_output().unwrap();
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            assert_eq!(stdout.lines().last(), Some(self.config().2));
+        }
+    }
+}
+
+#[guest]
+mod guest {
+    use super::*;
+    use crate::Test;
+    use std::fs::File;
+    use std::io::{self, IsTerminal};
+
+    fn kind(is_terminal: bool) -> &'static str {
+        if is_terminal {
+            "tty"
+        } else {
+            "pipe"
+        }
+    }
+
+    impl Test for TestTty {
+        fn in_guest(self: Box<Self>) {
+            println!(
+                "{} {} {} {}",
+                kind(io::stdin().is_terminal()),
+                kind(io::stdout().is_terminal()),
+                kind(io::stderr().is_terminal()),
+                if File::open("/dev/tty").is_ok() {
+                    "ctty"
+                } else {
+                    "none"
+                },
+            );
+        }
+    }
+}